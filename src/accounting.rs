@@ -0,0 +1,283 @@
+use crate::bitcoind_client::BitcoindClient;
+use crate::channel_history::ChannelHistory;
+use crate::forwarding::{ForwardedHtlcs, HtlcTracker};
+use crate::hex_utils;
+use crate::{time_now_secs, HTLCStatus, PaymentInfoStorage};
+use chrono::NaiveDateTime;
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const LIGHTNING: &str = "Assets:Lightning";
+const ONCHAIN: &str = "Assets:OnChain";
+const PAYMENTS_RECEIVED: &str = "Income:Lightning:Payments";
+const ROUTING_INCOME: &str = "Income:Lightning:RoutingFees";
+const PAYMENTS_SENT: &str = "Expenses:Lightning:Payments";
+const ROUTING_EXPENSES: &str = "Expenses:Lightning:RoutingFees";
+const ONCHAIN_FEES: &str = "Expenses:OnChain:Fees";
+
+#[derive(Clone, Copy)]
+pub(crate) struct Forward {
+	pub(crate) timestamp: u64,
+	pub(crate) fee_earned_msat: u64,
+	/// The HTLCs we forwarded between, unless we lost track of them, e.g. as the node restarted
+	/// or the next hop's claim came from the chain.
+	pub(crate) htlcs: Option<ForwardedHtlcs>,
+}
+
+/// The payments we forwarded and the fees we earned doing so, which LDK only tells us about once,
+/// as it happens. Each forward is appended to a log on disk as a line of
+/// `<timestamp> <fee_earned_msat>`, followed by
+/// `<inbound_channel_id> <inbound_amt_msat> <outbound_channel_id> <outbound_amt_msat>` if we know
+/// which channels it went through.
+pub(crate) struct ForwardLog {
+	path: String,
+	htlc_tracker: Arc<HtlcTracker>,
+	forwards: Mutex<Vec<Forward>>,
+}
+
+impl ForwardLog {
+	pub(crate) fn new(path: String, htlc_tracker: Arc<HtlcTracker>) -> Self {
+		let forwards = Mutex::new(read_forwards(Path::new(&path)));
+		Self { path, htlc_tracker, forwards }
+	}
+
+	/// Records a forward, called on LDK's `PaymentForwarded` event.
+	pub(crate) fn record(&self, fee_earned_msat: u64, claim_from_onchain_tx: bool) {
+		// The next hop's claim only goes through the tracker if it came over the wire.
+		let htlcs = if claim_from_onchain_tx {
+			None
+		} else {
+			self.htlc_tracker.take_settled(fee_earned_msat)
+		};
+		let forward = Forward { timestamp: time_now_secs(), fee_earned_msat, htlcs };
+		let appended = fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)
+			.and_then(|mut file| file.write_all(format_forward(&forward).as_bytes()));
+		if appended.is_err() {
+			eprintln!(
+				"Warning: Failed to persist forwarding fees, check your disk and permissions"
+			);
+		}
+		self.forwards.lock().unwrap().push(forward);
+	}
+
+	/// The forwards between `from` and `to`, in seconds since the UNIX epoch, oldest first.
+	pub(crate) fn forwards_between(&self, from: u64, to: u64) -> Vec<Forward> {
+		let forwards = self.forwards.lock().unwrap();
+		forwards.iter().filter(|f| f.timestamp >= from && f.timestamp < to).copied().collect()
+	}
+}
+
+fn format_forward(forward: &Forward) -> String {
+	match &forward.htlcs {
+		Some(htlcs) => format!(
+			"{} {} {} {} {} {}\n",
+			forward.timestamp,
+			forward.fee_earned_msat,
+			hex_utils::hex_str(&htlcs.inbound_channel_id),
+			htlcs.inbound_amt_msat,
+			hex_utils::hex_str(&htlcs.outbound_channel_id),
+			htlcs.outbound_amt_msat
+		),
+		None => format!("{} {}\n", forward.timestamp, forward.fee_earned_msat),
+	}
+}
+
+fn parse_channel_id(channel_id: &str) -> Option<[u8; 32]> {
+	let channel_id = hex_utils::to_vec(channel_id).filter(|id| id.len() == 32)?;
+	let mut id = [0; 32];
+	id.copy_from_slice(&channel_id);
+	Some(id)
+}
+
+fn parse_forward(line: &str) -> Option<Forward> {
+	let parts: Vec<&str> = line.split(' ').collect();
+	let htlcs = match parts.len() {
+		2 => None,
+		6 => Some(ForwardedHtlcs {
+			inbound_channel_id: parse_channel_id(parts[2])?,
+			inbound_amt_msat: parts[3].parse().ok()?,
+			outbound_channel_id: parse_channel_id(parts[4])?,
+			outbound_amt_msat: parts[5].parse().ok()?,
+		}),
+		_ => return None,
+	};
+	Some(Forward {
+		timestamp: parts[0].parse().ok()?,
+		fee_earned_msat: parts[1].parse().ok()?,
+		htlcs,
+	})
+}
+
+fn read_forwards(path: &Path) -> Vec<Forward> {
+	let mut forwards = Vec::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return forwards,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		match parse_forward(&line) {
+			Some(forward) => forwards.push(forward),
+			None => eprintln!("Warning: Skipping malformed forward in {:?}", path),
+		}
+	}
+	forwards
+}
+
+/// The plaintext accounting formats we can export to. They differ only in how a transaction's
+/// first line is written, and beancount needs accounts opened before they're used.
+#[derive(Clone, Copy)]
+pub(crate) enum LedgerFormat {
+	Beancount,
+	Ledger,
+}
+
+impl LedgerFormat {
+	pub(crate) fn from_str(format: &str) -> Option<Self> {
+		match format {
+			"beancount" => Some(LedgerFormat::Beancount),
+			"ledger" => Some(LedgerFormat::Ledger),
+			_ => None,
+		}
+	}
+}
+
+/// A balanced transaction, its postings in millisatoshis.
+struct Entry {
+	timestamp: u64,
+	narration: String,
+	postings: Vec<(&'static str, i64)>,
+}
+
+fn date(timestamp: u64, format: &str) -> String {
+	NaiveDateTime::from_timestamp(timestamp as i64, 0).format(format).to_string()
+}
+
+/// Formats millisatoshis as bitcoin, keeping every millisatoshi so transactions balance exactly.
+fn btc(amt_msat: i64) -> String {
+	let sign = if amt_msat < 0 { "-" } else { "" };
+	let amt_msat = amt_msat.abs() as u64;
+	format!("{}{}.{:011} BTC", sign, amt_msat / 100_000_000_000, amt_msat % 100_000_000_000)
+}
+
+/// Writes the node's on-chain and off-chain flows between `from` and `to`, in seconds since the
+/// UNIX epoch, to `path` as double-entry transactions, returning how many were written.
+///
+/// Payments count once they succeed. Channels we opened count as transfers from our on-chain funds
+/// to Lightning once funded, with the funding transaction's fee as an expense if our bitcoind
+/// wallet funded it. Closes and sweeps aren't recorded, as we don't keep track of how much of a
+/// channel came back to us.
+pub(crate) async fn export_ledger(
+	path: &Path, format: LedgerFormat, from: u64, to: u64, inbound_payments: &PaymentInfoStorage,
+	outbound_payments: &PaymentInfoStorage, forward_log: &ForwardLog,
+	channel_history: &ChannelHistory, bitcoind_client: &BitcoindClient,
+) -> Result<usize, String> {
+	let in_range = |timestamp: u64| timestamp >= from && timestamp < to;
+	let mut entries = Vec::new();
+	for (payment_hash, payment) in inbound_payments.lock().unwrap().iter() {
+		match (&payment.status, payment.amt_msat.0) {
+			(HTLCStatus::Succeeded, Some(amt_msat)) if in_range(payment.updated_at) => {
+				entries.push(Entry {
+					timestamp: payment.updated_at,
+					narration: format!("Received payment {}", hex_utils::hex_str(&payment_hash.0)),
+					postings: vec![
+						(LIGHTNING, amt_msat as i64),
+						(PAYMENTS_RECEIVED, -(amt_msat as i64)),
+					],
+				});
+			}
+			_ => {}
+		}
+	}
+	for (payment_hash, payment) in outbound_payments.lock().unwrap().iter() {
+		match (&payment.status, payment.amt_msat.0) {
+			(HTLCStatus::Succeeded, Some(amt_msat)) if in_range(payment.updated_at) => {
+				let fee_paid_msat = payment.fee_paid_msat.unwrap_or(0);
+				let mut postings = vec![(PAYMENTS_SENT, amt_msat as i64)];
+				if fee_paid_msat > 0 {
+					postings.push((ROUTING_EXPENSES, fee_paid_msat as i64));
+				}
+				postings.push((LIGHTNING, -((amt_msat + fee_paid_msat) as i64)));
+				entries.push(Entry {
+					timestamp: payment.updated_at,
+					narration: format!("Sent payment {}", hex_utils::hex_str(&payment_hash.0)),
+					postings,
+				});
+			}
+			_ => {}
+		}
+	}
+	for forward in forward_log.forwards_between(from, to) {
+		entries.push(Entry {
+			timestamp: forward.timestamp,
+			narration: "Forwarded payment".to_string(),
+			postings: vec![
+				(LIGHTNING, forward.fee_earned_msat as i64),
+				(ROUTING_INCOME, -(forward.fee_earned_msat as i64)),
+			],
+		});
+	}
+	for open in channel_history.channel_opens().into_iter().filter(|o| in_range(o.funded_at)) {
+		let amt_msat = open.channel_value_sat as i64 * 1000;
+		entries.push(Entry {
+			timestamp: open.funded_at,
+			narration: format!("Opened channel {}", hex_utils::hex_str(&open.channel_id)),
+			postings: vec![(LIGHTNING, amt_msat), (ONCHAIN, -amt_msat)],
+		});
+		let fee_sat =
+			bitcoind_client.get_transaction_fee(&open.funding_txo.txid).await.map_err(|e| {
+				format!("failed to look up funding transaction {}: {}", open.funding_txo.txid, e)
+			})?;
+		if let Some(fee_sat) = fee_sat.filter(|fee_sat| *fee_sat > 0) {
+			entries.push(Entry {
+				timestamp: open.funded_at,
+				narration: format!("Funding transaction {} fee", open.funding_txo.txid),
+				postings: vec![
+					(ONCHAIN_FEES, fee_sat as i64 * 1000),
+					(ONCHAIN, -(fee_sat as i64 * 1000)),
+				],
+			});
+		}
+	}
+	entries.sort_by_key(|entry| entry.timestamp);
+
+	let mut out = String::new();
+	if let LedgerFormat::Beancount = format {
+		let accounts: BTreeSet<&str> =
+			entries.iter().flat_map(|e| e.postings.iter().map(|(account, _)| *account)).collect();
+		for account in accounts {
+			out.push_str(&format!("{} open {} BTC\n", date(from, "%Y-%m-%d"), account));
+		}
+		out.push('\n');
+	}
+	for entry in entries.iter() {
+		match format {
+			LedgerFormat::Beancount => out.push_str(&format!(
+				"{} * \"{}\"\n",
+				date(entry.timestamp, "%Y-%m-%d"),
+				entry.narration
+			)),
+			LedgerFormat::Ledger => out.push_str(&format!(
+				"{} {}\n",
+				date(entry.timestamp, "%Y/%m/%d"),
+				entry.narration
+			)),
+		}
+		for (account, amt_msat) in entry.postings.iter() {
+			out.push_str(&format!("  {:<32}{:>24}\n", account, btc(*amt_msat)));
+		}
+		out.push('\n');
+	}
+	fs::write(path, out).map_err(|e| format!("failed to write {:?}: {}", path, e))?;
+	Ok(entries.len())
+}
@@ -0,0 +1,141 @@
+use crate::config;
+use crate::config::ConfigFile;
+use crate::disk;
+use crate::disk::FilesystemLogger;
+use crate::disk_space::DiskMonitor;
+use crate::pause::PauseSwitch;
+use crate::{ChannelManager, PeerManager};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Administers a running node over a unix socket in its storage directory, which only the user
+/// running the node can connect to. Unlike the RPC server it can't move funds, so it's always
+/// there, even on servers where nothing listens on TCP.
+///
+/// Each line sent is a command, answered with a line of JSON: `status`, `pause`, `resume`,
+/// `setloglevel <level>` and `reloadconfig`.
+pub(crate) struct AdminSocket {
+	pub(crate) channel_manager: Arc<ChannelManager>,
+	pub(crate) peer_manager: Arc<PeerManager>,
+	pub(crate) pause_switch: Arc<PauseSwitch>,
+	pub(crate) disk_monitor: Arc<DiskMonitor>,
+	pub(crate) logger: Arc<FilesystemLogger>,
+	pub(crate) config_path: String,
+	/// The config file as it was last loaded, to tell which settings `reloadconfig` can't apply.
+	pub(crate) config: Mutex<ConfigFile>,
+}
+
+fn log_level_str(admin: &AdminSocket) -> String {
+	admin.logger.min_level().map_or("all".to_string(), |level| level.to_string().to_lowercase())
+}
+
+impl AdminSocket {
+	fn handle_command(&self, line: &str) -> Value {
+		let words: Vec<&str> = line.split_whitespace().collect();
+		match words.as_slice() {
+			["status"] => json!({
+				"node_pubkey": self.channel_manager.get_our_node_id().to_string(),
+				"num_channels": self.channel_manager.list_channels().len(),
+				"num_usable_channels": self.channel_manager.list_usable_channels().len(),
+				"num_peers": self.peer_manager.get_peer_node_ids().len(),
+				"paused": self.pause_switch.is_paused(),
+				"disk_status": self.disk_monitor.status().as_str(),
+				"disk_free_bytes": self.disk_monitor.free_bytes(),
+				"log_level": log_level_str(self),
+			}),
+			["pause"] => match self.pause_switch.pause() {
+				Ok(()) => json!({ "paused": true }),
+				Err(e) => json!({ "error": format!("failed to pause the node: {}", e) }),
+			},
+			["resume"] => match self.pause_switch.resume() {
+				Ok(()) => {
+					if !self.pause_switch.holds_forwards() {
+						// Release any HTLCs we held on to while paused.
+						self.channel_manager.process_pending_htlc_forwards();
+					}
+					json!({ "paused": self.pause_switch.is_paused() })
+				}
+				Err(e) => json!({ "error": format!("failed to resume the node: {}", e) }),
+			},
+			["setloglevel", level] => match disk::parse_log_level(level) {
+				Ok(min_level) => {
+					self.logger.set_min_level(min_level);
+					json!({ "log_level": log_level_str(self) })
+				}
+				Err(e) => json!({ "error": e }),
+			},
+			["reloadconfig"] => self.reload_config(),
+			_ => json!({
+				"error": "unknown command, expected one of status, pause, resume, setloglevel <level> or reloadconfig"
+			}),
+		}
+	}
+
+	/// Applies the settings which can change while the node runs, currently only the log level,
+	/// and says whether any others changed, which only take effect after a restart.
+	fn reload_config(&self) -> Value {
+		let new_config = match config::read_config(Path::new(&self.config_path)) {
+			Ok(config) => config,
+			Err(e) => return json!({ "error": e }),
+		};
+		let min_level = match new_config.log_level.as_deref().map(disk::parse_log_level) {
+			Some(Ok(min_level)) => min_level,
+			Some(Err(e)) => return json!({ "error": e }),
+			None => None,
+		};
+		self.logger.set_min_level(min_level);
+		let mut config = self.config.lock().unwrap();
+		let mut unapplied = new_config.clone();
+		unapplied.log_level = config.log_level.clone();
+		let restart_required = unapplied != *config;
+		*config = new_config;
+		json!({ "log_level": log_level_str(self), "restart_required": restart_required })
+	}
+}
+
+async fn handle_connection(stream: UnixStream, admin: Arc<AdminSocket>) -> io::Result<()> {
+	let (reader, mut writer) = stream.into_split();
+	let mut lines = BufReader::new(reader).lines();
+	while let Some(line) = lines.next_line().await? {
+		let response = admin.handle_command(&line);
+		writer.write_all(format!("{}\n", response).as_bytes()).await?;
+	}
+	Ok(())
+}
+
+/// Listens on the unix socket at `path`, replacing any left over from an earlier run.
+pub(crate) fn start_admin_socket(path: String, admin: AdminSocket) -> io::Result<()> {
+	match fs::remove_file(&path) {
+		Ok(()) => {}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+		Err(e) => return Err(e),
+	}
+	let listener = UnixListener::bind(&path)?;
+	// Anyone who can connect can pause the node, so only its own user may. Connections made before
+	// the permissions were narrowed are turned away too.
+	fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+	let owner_uid = fs::metadata(&path)?.uid();
+	let admin = Arc::new(admin);
+	tokio::spawn(async move {
+		loop {
+			let stream = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(_) => continue,
+			};
+			if stream.peer_cred().map_or(true, |cred| cred.uid() != owner_uid) {
+				continue;
+			}
+			let admin = admin.clone();
+			tokio::spawn(async move {
+				let _ = handle_connection(stream, admin).await;
+			});
+		}
+	});
+	Ok(())
+}
@@ -0,0 +1,237 @@
+use crate::balances;
+use crate::uptime::PeerUptimeStorage;
+use crate::ChannelManager;
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::routing::network_graph::{
+	DirectionalChannelInfo, NetworkGraph, NodeId, RoutingFees,
+};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Fees are compared as the effective rate charged to forward this much, so that base fees count.
+const REFERENCE_AMOUNT_MSAT: u64 = 100_000_000;
+/// How many of the best-connected nodes we consider opening channels to. Each costs a walk of the
+/// whole graph.
+const MAX_OPEN_CANDIDATES: usize = 30;
+/// Counterparties online less than this share of the last week are worth closing channels with.
+const MIN_UPTIME_PCT: f64 = 50.0;
+/// Our fee is uncompetitive if it's more than this many times what others charge to reach the same
+/// counterparty.
+const MAX_FEE_RATIO: u64 = 2;
+/// Channels with less than this share of their capacity on our side are depleted.
+const DEPLETED_LOCAL_RATIO: f64 = 0.2;
+
+pub(crate) enum Action {
+	Open,
+	Close,
+	SetFee { proportional_millionths: u64 },
+}
+
+pub(crate) struct Suggestion {
+	pub(crate) action: Action,
+	pub(crate) node_id: PublicKey,
+	pub(crate) channel_id: Option<[u8; 32]>,
+	/// How strongly we suggest this, from 0 to 1, for ranking.
+	pub(crate) score: f64,
+	pub(crate) reason: String,
+}
+
+fn effective_fee_ppm(fees: &RoutingFees) -> u64 {
+	fees.proportional_millionths as u64 + fees.base_msat as u64 * 1_000_000 / REFERENCE_AMOUNT_MSAT
+}
+
+fn median(mut values: Vec<u64>) -> Option<u64> {
+	if values.is_empty() {
+		return None;
+	}
+	values.sort_unstable();
+	Some(values[values.len() / 2])
+}
+
+/// Hop counts from `source` to every node it can reach.
+fn hop_distances(adjacency: &HashMap<NodeId, Vec<NodeId>>, source: NodeId) -> HashMap<NodeId, u32> {
+	let mut distances = HashMap::new();
+	distances.insert(source, 0);
+	let mut queue = VecDeque::new();
+	queue.push_back(source);
+	while let Some(node) = queue.pop_front() {
+		let distance = distances[&node];
+		for neighbor in adjacency.get(&node).into_iter().flatten() {
+			if !distances.contains_key(neighbor) {
+				distances.insert(*neighbor, distance + 1);
+				queue.push_back(*neighbor);
+			}
+		}
+	}
+	distances
+}
+
+fn to_pubkey(node_id: &NodeId) -> Option<PublicKey> {
+	PublicKey::from_slice(node_id.as_slice()).ok()
+}
+
+/// Analyzes our channels against the network graph and returns suggestions, best first, of
+/// channels to open, channels to close and fees to change. Nothing is acted on.
+///
+/// Opens are ranked by how many nodes a channel to the candidate would bring closer to us, among
+/// the best-connected nodes we don't have a channel with. Closes are suggested for counterparties
+/// which are mostly offline, or which have no other channels to forward our payments over. Fee
+/// changes are suggested where our fee is far from what other nodes charge to forward to the same
+/// counterparty, or where we're cheap and the channel is depleted.
+pub(crate) fn suggestions(
+	channel_manager: &ChannelManager, network_graph: &NetworkGraph,
+	peer_uptime: &PeerUptimeStorage, our_fees: RoutingFees,
+) -> Vec<Suggestion> {
+	let our_node_id = NodeId::from_pubkey(&channel_manager.get_our_node_id());
+	let channels = channel_manager.list_channels();
+	let graph = network_graph.read_only();
+
+	// Only count channels which can forward in at least one direction.
+	let mut adjacency: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+	for chan in graph.channels().values() {
+		let enabled = |info: &Option<DirectionalChannelInfo>| {
+			info.as_ref().map_or(false, |info| info.enabled)
+		};
+		if !enabled(&chan.one_to_two) && !enabled(&chan.two_to_one) {
+			continue;
+		}
+		adjacency.entry(chan.node_one).or_default().push(chan.node_two);
+		adjacency.entry(chan.node_two).or_default().push(chan.node_one);
+	}
+	// Our unannounced channels aren't in the graph but still reach the rest of it.
+	let mut counterparties = HashSet::new();
+	for chan_info in channels.iter() {
+		let counterparty = NodeId::from_pubkey(&chan_info.counterparty.node_id);
+		counterparties.insert(counterparty);
+		adjacency.entry(our_node_id).or_default().push(counterparty);
+		adjacency.entry(counterparty).or_default().push(our_node_id);
+	}
+
+	let mut suggestions = Vec::new();
+	let node_count = graph.nodes().len();
+	let our_distances = hop_distances(&adjacency, our_node_id);
+	let mut candidates: Vec<(&NodeId, usize)> = graph
+		.nodes()
+		.iter()
+		.filter(|(node_id, _)| **node_id != our_node_id && !counterparties.contains(*node_id))
+		.map(|(node_id, node)| (node_id, node.channels.len()))
+		.collect();
+	candidates.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+	for (candidate, degree) in candidates.into_iter().take(MAX_OPEN_CANDIDATES) {
+		// A channel to the candidate puts us one hop further away from everything than it is.
+		let improved = hop_distances(&adjacency, *candidate)
+			.iter()
+			.filter(|(node_id, distance)| match our_distances.get(*node_id) {
+				Some(our_distance) => **distance + 1 < *our_distance,
+				None => true,
+			})
+			.count();
+		let node_id = match to_pubkey(candidate) {
+			Some(node_id) => node_id,
+			None => continue,
+		};
+		if improved == 0 || node_count == 0 {
+			continue;
+		}
+		suggestions.push(Suggestion {
+			action: Action::Open,
+			node_id,
+			channel_id: None,
+			score: improved as f64 / node_count as f64,
+			reason: format!(
+				"brings {} of {} known nodes closer, has {} channels",
+				improved, node_count, degree
+			),
+		});
+	}
+
+	let our_fee_ppm = effective_fee_ppm(&our_fees);
+	let peer_uptime = peer_uptime.lock().unwrap();
+	for chan_info in channels.iter() {
+		let counterparty = NodeId::from_pubkey(&chan_info.counterparty.node_id);
+		let local_ratio = balances::channel_local_balance_msat(chan_info) as f64
+			/ (chan_info.channel_value_satoshis * 1000).max(1) as f64;
+
+		let uptime =
+			peer_uptime.get(&chan_info.counterparty.node_id).and_then(|u| u.uptime_pct(24 * 7));
+		let other_channels = adjacency
+			.get(&counterparty)
+			.map_or(0, |neighbors| neighbors.iter().filter(|n| **n != our_node_id).count());
+		if let Some(uptime) = uptime.filter(|uptime| *uptime < MIN_UPTIME_PCT) {
+			suggestions.push(Suggestion {
+				action: Action::Close,
+				node_id: chan_info.counterparty.node_id,
+				channel_id: Some(chan_info.channel_id),
+				score: 1.0 - uptime / 100.0,
+				reason: format!("counterparty was online {:.1}% of the last week", uptime),
+			});
+		} else if other_channels == 0 && local_ratio > 1.0 - DEPLETED_LOCAL_RATIO {
+			// Our liquidity here can only pay the counterparty itself.
+			suggestions.push(Suggestion {
+				action: Action::Close,
+				node_id: chan_info.counterparty.node_id,
+				channel_id: Some(chan_info.channel_id),
+				score: local_ratio / 2.0,
+				reason: format!(
+					"counterparty has no other channels to forward over, {:.0}% of the channel is ours",
+					local_ratio * 100.0
+				),
+			});
+		}
+
+		// What other nodes charge to forward to the counterparty.
+		let competitor_fees: Vec<u64> = graph
+			.nodes()
+			.get(&counterparty)
+			.map(|node| node.channels.as_slice())
+			.unwrap_or(&[])
+			.iter()
+			.filter_map(|short_channel_id| graph.channels().get(short_channel_id))
+			.filter_map(|chan| {
+				let towards_counterparty = if chan.node_two == counterparty {
+					(chan.node_one, &chan.one_to_two)
+				} else {
+					(chan.node_two, &chan.two_to_one)
+				};
+				match towards_counterparty {
+					(node, _) if node == our_node_id => None,
+					(_, Some(info)) if info.enabled => Some(effective_fee_ppm(&info.fees)),
+					_ => None,
+				}
+			})
+			.collect();
+		let median_fee_ppm = match median(competitor_fees) {
+			Some(median_fee_ppm) => median_fee_ppm,
+			None => continue,
+		};
+		if our_fee_ppm > median_fee_ppm.max(1) * MAX_FEE_RATIO {
+			suggestions.push(Suggestion {
+				action: Action::SetFee { proportional_millionths: median_fee_ppm },
+				node_id: chan_info.counterparty.node_id,
+				channel_id: Some(chan_info.channel_id),
+				score: 1.0 - median_fee_ppm as f64 / our_fee_ppm as f64,
+				reason: format!(
+					"we charge {} ppm to forward to the counterparty, others charge a median of {} ppm",
+					our_fee_ppm, median_fee_ppm
+				),
+			});
+		} else if our_fee_ppm < median_fee_ppm && local_ratio < DEPLETED_LOCAL_RATIO {
+			suggestions.push(Suggestion {
+				action: Action::SetFee { proportional_millionths: median_fee_ppm },
+				node_id: chan_info.counterparty.node_id,
+				channel_id: Some(chan_info.channel_id),
+				score: 1.0 - local_ratio / DEPLETED_LOCAL_RATIO,
+				reason: format!(
+					"only {:.0}% of the channel is ours and we charge {} ppm, below the median of {} ppm",
+					local_ratio * 100.0,
+					our_fee_ppm,
+					median_fee_ppm
+				),
+			});
+		}
+	}
+
+	// Degenerate channel or graph data may make a score NaN, which mustn't bring the node down.
+	suggestions.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+	suggestions
+}
@@ -0,0 +1,151 @@
+use crate::bitcoind_client::BitcoindClient;
+use crate::hex_utils;
+use crate::ChannelManager;
+use lightning::chain::chaininterface::{ConfirmationTarget, FeeEstimator};
+use lightning::ln::channelmanager::ChannelDetails;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often we snapshot our balances.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// The weight of a commitment transaction with no HTLC outputs, and what each HTLC output adds to
+/// it, per BOLT 3.
+const COMMITMENT_TX_BASE_WEIGHT: u64 = 724;
+const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
+
+pub(crate) struct BalanceSnapshot {
+	pub(crate) timestamp: u64,
+	pub(crate) onchain_sat: u64,
+	/// Our balance in each open channel, keyed by hex channel id.
+	pub(crate) channel_balances_msat: Vec<(String, u64)>,
+}
+
+impl BalanceSnapshot {
+	pub(crate) fn lightning_balance_msat(&self) -> u64 {
+		self.channel_balances_msat.iter().map(|(_, balance)| balance).sum()
+	}
+
+	/// Serializes the snapshot as a single line of the form
+	/// `<timestamp> <onchain_sat> [<channel_id>:<balance_msat>,...]`.
+	fn to_line(&self) -> String {
+		let channels: Vec<String> = self
+			.channel_balances_msat
+			.iter()
+			.map(|(channel_id, balance)| format!("{}:{}", channel_id, balance))
+			.collect();
+		format!("{} {} {}", self.timestamp, self.onchain_sat, channels.join(","))
+	}
+
+	fn from_line(line: &str) -> Option<Self> {
+		let mut parts = line.split(' ');
+		let timestamp = parts.next()?.parse().ok()?;
+		let onchain_sat = parts.next()?.parse().ok()?;
+		let mut channel_balances_msat = Vec::new();
+		for channel in parts.next().unwrap_or("").split(',').filter(|c| !c.is_empty()) {
+			let mut channel_parts = channel.split(':');
+			let channel_id = channel_parts.next()?.to_string();
+			let balance = channel_parts.next()?.parse().ok()?;
+			channel_balances_msat.push((channel_id, balance));
+		}
+		Some(Self { timestamp, onchain_sat, channel_balances_msat })
+	}
+}
+
+/// Our balance in a channel, including the reserve we can't currently spend.
+pub(crate) fn channel_local_balance_msat(chan_info: &ChannelDetails) -> u64 {
+	chan_info.outbound_capacity_msat + chan_info.unspendable_punishment_reserve.unwrap_or(0) * 1000
+}
+
+/// Our counterparty's balance in a channel, including the reserve we require them to keep.
+pub(crate) fn channel_remote_balance_msat(chan_info: &ChannelDetails) -> u64 {
+	chan_info.inbound_capacity_msat + chan_info.counterparty.unspendable_punishment_reserve * 1000
+}
+
+/// The feerate LDK keeps our commitment transactions at. LDK 0.0.103 doesn't expose a channel's
+/// current feerate, but updates it to its `Normal` estimate.
+pub(crate) fn commitment_feerate(bitcoind_client: &BitcoindClient) -> u32 {
+	bitcoind_client.get_est_sat_per_1000_weight(ConfirmationTarget::Normal)
+}
+
+/// How much we could send over a channel right now, as a single HTLC.
+///
+/// LDK's outbound capacity already leaves out the reserve our counterparty requires us to keep,
+/// which is never below the dust limit, and the HTLCs we have pending. If we opened the channel we
+/// also pay its commitment transaction's fee, which grows by an HTLC output with the HTLC we'd
+/// add, so that's left out too. Pending HTLCs add outputs as well, but LDK 0.0.103 doesn't tell us
+/// how many there are, so the fee is a lower bound while any are pending. LDK 0.0.103 doesn't
+/// support anchor outputs, so there's no anchor reserve to keep.
+pub(crate) fn channel_spendable_msat(chan_info: &ChannelDetails, commitment_feerate: u32) -> u64 {
+	if !chan_info.is_usable {
+		return 0;
+	}
+	if !chan_info.is_outbound {
+		return chan_info.outbound_capacity_msat;
+	}
+	let commitment_weight = COMMITMENT_TX_BASE_WEIGHT + COMMITMENT_TX_WEIGHT_PER_HTLC;
+	let commitment_fee_msat = commitment_feerate as u64 * commitment_weight / 1000 * 1000;
+	chan_info.outbound_capacity_msat.saturating_sub(commitment_fee_msat)
+}
+
+fn append_snapshot(path: &Path, snapshot: &BalanceSnapshot) -> std::io::Result<()> {
+	let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+	file.write_all(format!("{}\n", snapshot.to_line()).as_bytes())
+}
+
+pub(crate) fn read_balance_history(path: &Path) -> Vec<BalanceSnapshot> {
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return Vec::new(),
+	};
+	BufReader::new(file)
+		.lines()
+		.filter_map(|line| line.ok())
+		.filter_map(|line| BalanceSnapshot::from_line(&line))
+		.collect()
+}
+
+/// Regularly appends a snapshot of our on-chain and per-channel balances to the file at `path`.
+pub(crate) fn start_balance_snapshots(
+	path: String, bitcoind_client: Arc<BitcoindClient>, channel_manager: Arc<ChannelManager>,
+) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+		loop {
+			interval.tick().await;
+			let onchain_sat = match bitcoind_client.get_balance().await {
+				Ok(balance) => balance,
+				Err(e) => {
+					eprintln!("Warning: Failed to fetch on-chain balance for snapshot: {}", e);
+					continue;
+				}
+			};
+			let channel_balances_msat = channel_manager
+				.list_channels()
+				.iter()
+				.map(|chan_info| {
+					(
+						hex_utils::hex_str(&chan_info.channel_id),
+						channel_local_balance_msat(chan_info),
+					)
+				})
+				.collect();
+			let snapshot = BalanceSnapshot {
+				timestamp: SystemTime::now()
+					.duration_since(SystemTime::UNIX_EPOCH)
+					.unwrap()
+					.as_secs(),
+				onchain_sat,
+				channel_balances_msat,
+			};
+			if append_snapshot(Path::new(&path), &snapshot).is_err() {
+				eprintln!(
+					"Warning: Failed to persist balance snapshot, check your disk and permissions"
+				);
+			}
+		}
+	});
+}
@@ -0,0 +1,72 @@
+use crate::bitcoind_client::BitcoindClient;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::BlockHash;
+use lightning::chain::BestBlock;
+use lightning_block_sync::BlockSource;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Records the block we've synced the chain to as `<block_hash> <height>`, so that at startup we
+/// can tell whether bitcoind's chain still extends it.
+pub(crate) fn persist_best_block(path: &Path, best_block: &BestBlock) -> std::io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	file.write_all(format!("{} {}\n", best_block.block_hash(), best_block.height()).as_bytes())?;
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+pub(crate) fn read_best_block(path: &Path) -> Option<(BlockHash, u32)> {
+	let contents = fs::read_to_string(path).ok()?;
+	let mut parts = contents.trim().split(' ');
+	let block_hash = BlockHash::from_hex(parts.next()?).ok()?;
+	let height = parts.next()?.parse().ok()?;
+	Some((block_hash, height))
+}
+
+/// Checks that our chain source (bitcoind or Esplora) knows about the blocks we last synced to, both
+/// overall and for each of our chain listeners, before we try to sync them to its tip. If bitcoind
+/// was reindexed, re-synced from scratch, switched to another network or pruned past our blocks,
+/// syncing would otherwise fail without saying why, or worse, appear to work against a chain which
+/// is still catching up.
+pub(crate) async fn verify_chain(
+	bitcoind_client: &BitcoindClient, last_synced: Option<(BlockHash, u32)>,
+	listeners: &[(String, BlockHash)],
+) -> Result<(), String> {
+	let mut block_source = bitcoind_client;
+	let source = bitcoind_client.chain_source_name();
+	if let Some((block_hash, height)) = last_synced {
+		let source_height = match block_source.get_best_block().await {
+			Ok((_, Some(source_height))) => source_height,
+			Ok((_, None)) => return Err(format!("{} didn't tell us its block height", source)),
+			Err(e) => return Err(format!("failed to get the best block from {}: {:?}", source, e)),
+		};
+		if source_height < height {
+			return Err(format!(
+				"{} is at block height {} but we last synced to height {}. It may have been \
+				 reindexed or be re-syncing the chain; wait for it to catch up before starting",
+				source, source_height, height
+			));
+		}
+		if block_source.get_header(&block_hash, Some(height)).await.is_err() {
+			return Err(format!(
+				"{} doesn't know block {} at height {}, which we last synced to. It may have \
+				 been reindexed onto a different chain, be on the wrong network or have pruned it",
+				source, block_hash, height
+			));
+		}
+	}
+	for (listener, block_hash) in listeners.iter() {
+		if block_source.get_header(block_hash, None).await.is_err() {
+			return Err(format!(
+				"{} doesn't know block {}, which our {} was last synced to. It may have been \
+				 reindexed, be on the wrong network or have pruned the block",
+				source, block_hash, listener
+			));
+		}
+	}
+	Ok(())
+}
@@ -1,32 +1,407 @@
-use crate::convert::{BlockchainInfo, FeeResponse, FundedTx, NewAddress, RawTx, SignedTx};
+use crate::channel_history::ChannelHistory;
+use crate::convert::{
+	BlockchainInfo, FeeResponse, FinalizedPsbt, FundedTx, GeneratedBlocks, ListUnspent,
+	MempoolMinFee, NewAddress, Psbt, RawTx, SignedTx, UnspentOutput, WalletBalance, WalletBalances,
+	WalletInfo, WalletTransaction, WalletUtxo,
+};
+use crate::esplora::EsploraClient;
+use crate::fee_snapshots::{BroadcastPurpose, FeeSnapshotLog};
+use crate::hex_utils;
+use crate::onchain_wallet::OnchainWallet;
+use crate::rpc_tls::{self, RpcTls};
 use base64;
 use bitcoin::blockdata::block::Block;
-use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxOut};
 use bitcoin::consensus::encode;
 use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::network::constants::Network;
 use bitcoin::util::address::Address;
 use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
-use lightning_block_sync::http::HttpEndpoint;
+use lightning_block_sync::http::{HttpEndpoint, JsonResponse};
 use lightning_block_sync::rpc::RpcClient;
 use lightning_block_sync::{AsyncBlockSourceResult, BlockHeaderData, BlockSource};
 use serde_json;
 use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Notify};
+
+/// How long we wait for bitcoind to answer an RPC call before giving up on it, by default.
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long we wait to connect to bitcoind over TLS, by default.
+pub const DEFAULT_RPC_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many times we retry an RPC call which failed because bitcoind couldn't be reached, e.g.
+/// while it restarts, waiting twice as long before each retry as before the last.
+const RPC_RETRIES: u32 = 5;
+const RPC_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Whether an RPC call failed because we couldn't reach bitcoind, rather than bitcoind rejecting it.
+fn is_transient(e: &io::Error) -> bool {
+	match e.kind() {
+		io::ErrorKind::ConnectionRefused
+		| io::ErrorKind::ConnectionReset
+		| io::ErrorKind::ConnectionAborted
+		| io::ErrorKind::NotConnected
+		| io::ErrorKind::BrokenPipe
+		| io::ErrorKind::TimedOut
+		| io::ErrorKind::UnexpectedEof => true,
+		_ => false,
+	}
+}
+
+/// How we authenticate to bitcoind's RPC server.
+#[derive(Clone)]
+pub enum RpcAuth {
+	/// The `rpcuser` and `rpcpassword` (or `rpcauth`) set in bitcoind's config.
+	UserPass { user: String, password: String },
+	/// The `.cookie` file bitcoind writes to its data directory when no password is configured.
+	/// bitcoind writes a new cookie each time it starts, so it's read again on every reconnect.
+	Cookie(PathBuf),
+}
+
+impl RpcAuth {
+	/// The base64-encoded `user:password` bitcoind's RPC server expects.
+	fn credentials(&self) -> io::Result<String> {
+		let user_and_password = match self {
+			RpcAuth::UserPass { user, password } => format!("{}:{}", user, password),
+			RpcAuth::Cookie(path) => std::fs::read_to_string(path)
+				.map_err(|e| {
+					io::Error::new(
+						e.kind(),
+						format!("Failed to read bitcoind cookie file {:?}: {}", path, e),
+					)
+				})?
+				.trim()
+				.to_string(),
+		};
+		Ok(base64::encode(user_and_password))
+	}
+}
+
+/// How we reach bitcoind's RPC server, beyond its address and credentials.
+#[derive(Clone)]
+pub struct RpcTransport {
+	/// How long we wait for bitcoind to answer an RPC call before giving up on it.
+	pub timeout: Duration,
+	/// If set, we connect to bitcoind over TLS, e.g. to reach it through stunnel on another machine.
+	pub tls: Option<RpcTls>,
+}
+
+/// Whether we can reach `host` without leaving this machine.
+pub fn is_local_host(host: &str) -> bool {
+	match host.parse::<std::net::IpAddr>() {
+		Ok(ip) => ip.is_loopback(),
+		Err(_) => host == "localhost",
+	}
+}
+
+/// Where bitcoind writes its cookie file for `network` when run with its default data directory.
+pub fn default_cookie_path(network: Network) -> Option<PathBuf> {
+	let home = PathBuf::from(std::env::var_os("HOME")?);
+	let mut path = if cfg!(target_os = "macos") {
+		home.join("Library/Application Support/Bitcoin")
+	} else {
+		home.join(".bitcoin")
+	};
+	match network {
+		Network::Bitcoin => {}
+		Network::Testnet => path.push("testnet3"),
+		Network::Regtest => path.push("regtest"),
+		Network::Signet => path.push("signet"),
+	}
+	path.push(".cookie");
+	Some(path)
+}
+
+/// The port bitcoind serves RPC on for `network` unless configured otherwise.
+pub fn default_rpc_port(network: Network) -> u16 {
+	match network {
+		Network::Bitcoin => 8332,
+		Network::Testnet => 18332,
+		Network::Regtest => 18443,
+		Network::Signet => 38332,
+	}
+}
+
+/// A connection to one of bitcoind's RPC endpoints, either the node's or a wallet's, which is
+/// re-established whenever bitcoind can't be reached over it.
+struct RpcConnection {
+	client: Mutex<RpcClient>,
+	host: String,
+	port: u16,
+	auth: RpcAuth,
+	wallet: Option<String>,
+	timeout: Duration,
+}
+
+impl RpcConnection {
+	fn new(
+		host: &str, port: u16, auth: &RpcAuth, wallet: Option<&str>, timeout: Duration,
+	) -> io::Result<Self> {
+		let client = RpcConnection::connect_to(host, port, auth, wallet)?;
+		Ok(Self {
+			client: Mutex::new(client),
+			host: host.to_string(),
+			port,
+			auth: auth.clone(),
+			wallet: wallet.map(|w| w.to_string()),
+			timeout,
+		})
+	}
+
+	fn connect_to(
+		host: &str, port: u16, auth: &RpcAuth, wallet: Option<&str>,
+	) -> io::Result<RpcClient> {
+		let mut http_endpoint = HttpEndpoint::for_host(host.to_string()).with_port(port);
+		if let Some(wallet) = wallet {
+			http_endpoint = http_endpoint.with_path(format!("/wallet/{}", wallet));
+		}
+		RpcClient::new(&auth.credentials()?, http_endpoint)
+	}
+
+	/// Replaces the connection with a new one, in case bitcoind restarted and dropped it, which
+	/// also picks up the new cookie bitcoind wrote if it restarted.
+	async fn reconnect(&self) {
+		let wallet = self.wallet.as_ref().map(|w| w.as_str());
+		if let Ok(client) = RpcConnection::connect_to(&self.host, self.port, &self.auth, wallet) {
+			*self.client.lock().await = client;
+		}
+	}
+
+	/// Calls `method`, retrying with backoff while bitcoind can't be reached. A call which timed
+	/// out may still have gone through, so this is only for calls which are safe to repeat.
+	async fn call<T>(&self, method: &str, params: &[serde_json::Value]) -> io::Result<T>
+	where
+		JsonResponse: TryFrom<Vec<u8>, Error = io::Error> + TryInto<T, Error = io::Error>,
+	{
+		let mut backoff = RPC_RETRY_BACKOFF;
+		let mut retries = 0;
+		loop {
+			match self.call_once(method, params).await {
+				Err(e) if is_transient(&e) && retries < RPC_RETRIES => {
+					retries += 1;
+					tokio::time::sleep(backoff).await;
+					backoff *= 2;
+				}
+				res => return res,
+			}
+		}
+	}
+
+	/// Calls `method` once, for calls which mustn't be repeated, such as sending funds.
+	async fn call_once<T>(&self, method: &str, params: &[serde_json::Value]) -> io::Result<T>
+	where
+		JsonResponse: TryFrom<Vec<u8>, Error = io::Error> + TryInto<T, Error = io::Error>,
+	{
+		let res = {
+			let mut rpc = self.client.lock().await;
+			match tokio::time::timeout(self.timeout, rpc.call_method::<T>(method, params)).await {
+				Ok(res) => res,
+				Err(_) => Err(io::Error::new(
+					io::ErrorKind::TimedOut,
+					format!("bitcoind didn't answer {} within {:?}", method, self.timeout),
+				)),
+			}
+		};
+		if let Err(e) = &res {
+			if is_transient(e) {
+				self.reconnect().await;
+			}
+		}
+		res
+	}
+}
+
+/// How often we poll bitcoind for new blocks without ZMQ notifications.
+const BLOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often we still poll with ZMQ notifications, as ZMQ drops messages under load.
+const ZMQ_BLOCK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// How long we wait before reconnecting to bitcoind's ZMQ publisher after losing it.
+const ZMQ_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Reads a single ZMTP frame, returning its flags and body.
+async fn read_zmtp_frame(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+	let flags = stream.read_u8().await?;
+	// Bit 1 marks a frame with an 8 byte length rather than a 1 byte one.
+	let len = match flags & 0x02 {
+		0 => stream.read_u8().await? as u64,
+		_ => stream.read_u64().await?,
+	};
+	if len > 16 * 1024 * 1024 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "ZMQ frame too large"));
+	}
+	let mut body = vec![0; len as usize];
+	stream.read_exact(&mut body).await?;
+	Ok((flags, body))
+}
+
+async fn write_zmtp_frame(stream: &mut TcpStream, flags: u8, body: &[u8]) -> io::Result<()> {
+	let mut frame = vec![flags, body.len() as u8];
+	frame.extend_from_slice(body);
+	stream.write_all(&frame).await
+}
+
+/// Connects to a ZMQ publisher as a SUB socket, speaking just enough ZMTP 3.0 (with the NULL
+/// security mechanism, which is all bitcoind offers) to subscribe to `topics`.
+async fn zmq_subscribe(addr: SocketAddr, topics: &[&str]) -> io::Result<TcpStream> {
+	let mut stream = TcpStream::connect(addr).await?;
+	// The greeting: signature, version 3.0, the NULL mechanism padded to 20 bytes, as-server
+	// false and padding to 64 bytes.
+	let mut greeting = vec![0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0x7f, 3, 0];
+	greeting.extend_from_slice(b"NULL");
+	greeting.resize(64, 0);
+	stream.write_all(&greeting).await?;
+	let mut peer_greeting = [0; 64];
+	stream.read_exact(&mut peer_greeting).await?;
+	if peer_greeting[0] != 0xff || peer_greeting[9] != 0x7f || peer_greeting[10] < 3 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "not a ZMTP 3 publisher"));
+	}
+
+	let mut ready = vec![5];
+	ready.extend_from_slice(b"READY");
+	ready.push(11);
+	ready.extend_from_slice(b"Socket-Type");
+	ready.extend_from_slice(&3u32.to_be_bytes());
+	ready.extend_from_slice(b"SUB");
+	write_zmtp_frame(&mut stream, 0x04, &ready).await?;
+	let (flags, body) = read_zmtp_frame(&mut stream).await?;
+	if flags & 0x04 == 0 || !body.starts_with(b"\x05READY") {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "ZMQ publisher didn't send READY"));
+	}
+
+	for topic in topics {
+		// In ZMTP 3.0 a subscription is a message of 1 followed by the topic.
+		let mut subscription = vec![1];
+		subscription.extend_from_slice(topic.as_bytes());
+		write_zmtp_frame(&mut stream, 0, &subscription).await?;
+	}
+	Ok(stream)
+}
+
+/// Wakes chain polling as soon as bitcoind announces a new block over ZMQ, rather than waiting for
+/// the next timed poll. bitcoind must be run with `-zmqpubhashblock` or `-zmqpubrawblock` set to
+/// the address we subscribe to.
+pub struct BlockNotifications {
+	new_block: Notify,
+	/// Whether we're currently subscribed, so that we poll often while we can't be notified.
+	subscribed: AtomicBool,
+}
+
+impl BlockNotifications {
+	pub fn start(zmq_addr: SocketAddr) -> Arc<Self> {
+		let notifications =
+			Arc::new(Self { new_block: Notify::new(), subscribed: AtomicBool::new(false) });
+		let listener = notifications.clone();
+		tokio::spawn(async move {
+			let mut failing = false;
+			loop {
+				let res = listener.listen(zmq_addr, &mut failing).await;
+				listener.subscribed.store(false, Ordering::Release);
+				if let Err(e) = res {
+					if !failing {
+						failing = true;
+						eprintln!(
+							"Warning: Lost bitcoind's ZMQ block notifications at {}, polling for blocks until they're back: {}",
+							zmq_addr, e
+						);
+					}
+				}
+				tokio::time::sleep(ZMQ_RECONNECT_DELAY).await;
+			}
+		});
+		notifications
+	}
+
+	async fn listen(&self, zmq_addr: SocketAddr, failing: &mut bool) -> io::Result<()> {
+		let mut stream = zmq_subscribe(zmq_addr, &["hashblock", "rawblock"]).await?;
+		self.subscribed.store(true, Ordering::Release);
+		if *failing {
+			*failing = false;
+			eprintln!("Receiving block notifications from bitcoind over ZMQ again");
+		}
+		loop {
+			// Each notification is a multi-part message of topic, body and sequence number, any
+			// part of which tells us there's a new block.
+			let (flags, _) = read_zmtp_frame(&mut stream).await?;
+			if flags & 0x04 == 0 {
+				self.new_block.notify_one();
+			}
+		}
+	}
+
+	/// Waits until it's time to poll for new blocks: as soon as bitcoind announces one, or after a
+	/// while in case we missed an announcement.
+	pub async fn wait(&self) {
+		let poll_interval = match self.subscribed.load(Ordering::Acquire) {
+			true => ZMQ_BLOCK_POLL_INTERVAL,
+			false => BLOCK_POLL_INTERVAL,
+		};
+		let _ = tokio::time::timeout(poll_interval, self.new_block.notified()).await;
+	}
+}
+
+/// Waits until it's time to poll bitcoind for new blocks again, using ZMQ notifications if we
+/// have them and a short timer otherwise.
+pub async fn wait_for_next_block_poll(notifications: Option<&BlockNotifications>) {
+	match notifications {
+		Some(notifications) => notifications.wait().await,
+		None => tokio::time::sleep(BLOCK_POLL_INTERVAL).await,
+	}
+}
 
 pub struct BitcoindClient {
-	bitcoind_rpc_client: Arc<Mutex<RpcClient>>,
+	bitcoind_rpc_client: Arc<RpcConnection>,
+	/// The wallet used to fund channel opens. Defaults to bitcoind's default wallet.
+	funding_wallet_client: Arc<RpcConnection>,
+	/// The wallet used for everything else, e.g. as the destination for sweeps of our on-chain
+	/// outputs. Defaults to bitcoind's default wallet.
+	operating_wallet_client: Arc<RpcConnection>,
+	funding_wallet: Option<String>,
+	/// Whether the funding wallet is watch-only, in which case funding transactions are signed
+	/// externally from a PSBT.
+	funding_wallet_watch_only: bool,
+	/// Whether funding transactions may only spend confirmed outputs, so that a channel open can't
+	/// get stuck behind an unconfirmed, possibly RBF-able, parent.
+	fund_from_confirmed_only: bool,
+	operating_wallet: Option<String>,
+	/// Where we make RPC calls to, which is a local port forwarding to bitcoind when we reach it
+	/// over TLS.
 	host: String,
 	port: u16,
-	rpc_user: String,
-	rpc_password: String,
-	fees: Arc<HashMap<Target, AtomicU32>>,
+	auth: RpcAuth,
+	fees: Arc<FeeCache>,
+	/// Channels the user asked to close, by funding outpoint, whose closing transaction we've yet to
+	/// report.
+	closing_channels: std::sync::Mutex<HashMap<OutPoint, [u8; 32]>>,
+	/// Where we record the closing and sweeping transactions we broadcast for our channels.
+	channel_history: Arc<ChannelHistory>,
+	/// Where we record the fee estimates behind each transaction we broadcast.
+	fee_snapshots: FeeSnapshotLog,
+	/// If set, blocks, fee estimates and broadcasts go through Esplora rather than bitcoind, which
+	/// is then only used for its wallet.
+	esplora: Option<Arc<EsploraClient>>,
+	/// If set, our on-chain funds are held in this wallet rather than in bitcoind's, which is then
+	/// only used for the chain. It's set once synced, as it's derived from the node's seed.
+	onchain_wallet: std::sync::Mutex<Option<Arc<OnchainWallet>>>,
 	handle: tokio::runtime::Handle,
 }
 
+/// Which of our bitcoind wallets to use, as the funding and operating wallets may differ.
+#[derive(Clone, Copy)]
+pub enum WalletRole {
+	Funding,
+	Operating,
+}
+
 #[derive(Clone, Eq, Hash, PartialEq)]
 pub enum Target {
 	Background,
@@ -34,12 +409,119 @@ pub enum Target {
 	HighPriority,
 }
 
+impl Target {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Target::Background => "background",
+			Target::Normal => "normal",
+			Target::HighPriority => "high_priority",
+		}
+	}
+
+	pub fn from_str(target: &str) -> Option<Self> {
+		match target {
+			"background" => Some(Target::Background),
+			"normal" => Some(Target::Normal),
+			"high_priority" => Some(Target::HighPriority),
+			_ => None,
+		}
+	}
+}
+
+/// Each target's `estimatesmartfee` confirmation target and estimate mode.
+const FEE_TARGETS: [(Target, u16, &str); 3] = [
+	(Target::Background, 144, "ECONOMICAL"),
+	(Target::Normal, 18, "ECONOMICAL"),
+	(Target::HighPriority, 6, "CONSERVATIVE"),
+];
+
+/// How often we refresh our cached fee estimates from bitcoind.
+const FEE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The feerates, in satoshis per 1000 weight, we use for each target when bitcoind has no estimate
+/// for it, e.g. on a fresh regtest chain.
+#[derive(Clone, Copy)]
+pub struct FeeFloors {
+	pub background: u32,
+	pub normal: u32,
+	pub high_priority: u32,
+}
+
+impl Default for FeeFloors {
+	fn default() -> Self {
+		Self { background: MIN_FEERATE, normal: 2000, high_priority: 5000 }
+	}
+}
+
+/// Where a cached feerate came from.
+#[derive(Clone, Copy)]
+pub enum FeeSource {
+	/// bitcoind's `estimatesmartfee`.
+	Estimate,
+	/// bitcoind's mempool minimum fee, as the estimate was below it or missing.
+	MempoolMinFee,
+	/// The target's configured floor, as bitcoind had no estimate.
+	Floor,
+	/// Esplora's `fee-estimates`.
+	Esplora,
+}
+
+impl FeeSource {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			FeeSource::Estimate => "estimatesmartfee",
+			FeeSource::MempoolMinFee => "mempoolminfee",
+			FeeSource::Floor => "floor",
+			FeeSource::Esplora => "esplora",
+		}
+	}
+
+	pub fn from_str(source: &str) -> Option<Self> {
+		match source {
+			"estimatesmartfee" => Some(FeeSource::Estimate),
+			"mempoolminfee" => Some(FeeSource::MempoolMinFee),
+			"floor" => Some(FeeSource::Floor),
+			"esplora" => Some(FeeSource::Esplora),
+			_ => None,
+		}
+	}
+}
+
+/// Our fee estimates, refreshed in the background so that LDK never waits on bitcoind for them.
+struct FeeCache {
+	feerates: HashMap<Target, AtomicU32>,
+	details: std::sync::Mutex<FeeDetails>,
+}
+
+struct FeeDetails {
+	sources: HashMap<Target, FeeSource>,
+	mempool_min_feerate: Option<u32>,
+	last_refresh: Option<Instant>,
+}
+
+pub struct CachedFee {
+	pub target: Target,
+	pub conf_target: u16,
+	pub feerate_sat_per_kw: u32,
+	pub source: FeeSource,
+}
+
+pub struct CachedFees {
+	pub feerates: Vec<CachedFee>,
+	pub mempool_min_feerate: Option<u32>,
+	/// How long ago we last refreshed the feerates, `None` if we never have.
+	pub age: Option<Duration>,
+}
+
 impl BlockSource for &BitcoindClient {
 	fn get_header<'a>(
 		&'a mut self, header_hash: &'a BlockHash, height_hint: Option<u32>,
 	) -> AsyncBlockSourceResult<'a, BlockHeaderData> {
 		Box::pin(async move {
-			let mut rpc = self.bitcoind_rpc_client.lock().await;
+			if let Some(esplora) = &self.esplora {
+				return esplora.get_header(header_hash).await;
+			}
+			let mut rpc = self.bitcoind_rpc_client.client.lock().await;
 			rpc.get_header(header_hash, height_hint).await
 		})
 	}
@@ -48,14 +530,20 @@ impl BlockSource for &BitcoindClient {
 		&'a mut self, header_hash: &'a BlockHash,
 	) -> AsyncBlockSourceResult<'a, Block> {
 		Box::pin(async move {
-			let mut rpc = self.bitcoind_rpc_client.lock().await;
+			if let Some(esplora) = &self.esplora {
+				return esplora.get_block(header_hash).await;
+			}
+			let mut rpc = self.bitcoind_rpc_client.client.lock().await;
 			rpc.get_block(header_hash).await
 		})
 	}
 
 	fn get_best_block<'a>(&'a mut self) -> AsyncBlockSourceResult<(BlockHash, Option<u32>)> {
 		Box::pin(async move {
-			let mut rpc = self.bitcoind_rpc_client.lock().await;
+			if let Some(esplora) = &self.esplora {
+				return esplora.get_best_block().await;
+			}
+			let mut rpc = self.bitcoind_rpc_client.client.lock().await;
 			rpc.get_best_block().await
 		})
 	}
@@ -66,132 +554,310 @@ const MIN_FEERATE: u32 = 253;
 
 impl BitcoindClient {
 	pub async fn new(
-		host: String, port: u16, rpc_user: String, rpc_password: String,
+		host: String, port: u16, auth: RpcAuth, transport: RpcTransport,
+		funding_wallet: Option<String>, operating_wallet: Option<String>,
+		fund_from_confirmed_only: bool, fee_floors: FeeFloors, esplora: Option<EsploraClient>,
+		channel_history: Arc<ChannelHistory>, fee_snapshots: FeeSnapshotLog,
 		handle: tokio::runtime::Handle,
 	) -> std::io::Result<Self> {
-		let http_endpoint = HttpEndpoint::for_host(host.clone()).with_port(port);
-		let rpc_credentials =
-			base64::encode(format!("{}:{}", rpc_user.clone(), rpc_password.clone()));
-		let mut bitcoind_rpc_client = RpcClient::new(&rpc_credentials, http_endpoint)?;
+		let (host, port) = match &transport.tls {
+			Some(tls) => {
+				let local_addr = rpc_tls::start_tunnel(&host, port, tls)
+					.await
+					.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+				(local_addr.ip().to_string(), local_addr.port())
+			}
+			None => (host, port),
+		};
+		let rpc_timeout = transport.timeout;
+		let bitcoind_rpc_client = RpcConnection::new(&host, port, &auth, None, rpc_timeout)?;
 		let _dummy = bitcoind_rpc_client
-			.call_method::<BlockchainInfo>("getblockchaininfo", &vec![])
+			.call_once::<BlockchainInfo>("getblockchaininfo", &vec![])
 			.await
 			.map_err(|_| {
 				std::io::Error::new(std::io::ErrorKind::PermissionDenied,
-				"Failed to make initial call to bitcoind - please check your RPC user/password and access settings")
+				"Failed to make initial call to bitcoind - please check your RPC user/password or cookie file and access settings")
 			})?;
-		let mut fees: HashMap<Target, AtomicU32> = HashMap::new();
-		fees.insert(Target::Background, AtomicU32::new(MIN_FEERATE));
-		fees.insert(Target::Normal, AtomicU32::new(2000));
-		fees.insert(Target::HighPriority, AtomicU32::new(5000));
+		let bitcoind_rpc_client = Arc::new(bitcoind_rpc_client);
+		let funding_wallet_client = match &funding_wallet {
+			Some(wallet) => Arc::new(
+				BitcoindClient::new_wallet_rpc_client(&host, port, &auth, wallet, rpc_timeout)
+					.await?,
+			),
+			None => bitcoind_rpc_client.clone(),
+		};
+		// If there's no wallet loaded at all we'll find out when we first try to use it.
+		let funding_wallet_watch_only = funding_wallet_client
+			.call_once::<WalletInfo>("getwalletinfo", &vec![])
+			.await
+			.map_or(false, |info| !info.private_keys_enabled);
+		let operating_wallet_client = match &operating_wallet {
+			Some(wallet) => Arc::new(
+				BitcoindClient::new_wallet_rpc_client(&host, port, &auth, wallet, rpc_timeout)
+					.await?,
+			),
+			None => bitcoind_rpc_client.clone(),
+		};
+		let mut feerates: HashMap<Target, AtomicU32> = HashMap::new();
+		feerates.insert(Target::Background, AtomicU32::new(fee_floors.background));
+		feerates.insert(Target::Normal, AtomicU32::new(fee_floors.normal));
+		feerates.insert(Target::HighPriority, AtomicU32::new(fee_floors.high_priority));
+		let fees = FeeCache {
+			feerates,
+			details: std::sync::Mutex::new(FeeDetails {
+				sources: HashMap::new(),
+				mempool_min_feerate: None,
+				last_refresh: None,
+			}),
+		};
 		let client = Self {
-			bitcoind_rpc_client: Arc::new(Mutex::new(bitcoind_rpc_client)),
+			bitcoind_rpc_client,
+			funding_wallet_client,
+			operating_wallet_client,
+			funding_wallet,
+			funding_wallet_watch_only,
+			fund_from_confirmed_only,
+			operating_wallet,
 			host,
 			port,
-			rpc_user,
-			rpc_password,
+			auth,
 			fees: Arc::new(fees),
+			closing_channels: std::sync::Mutex::new(HashMap::new()),
+			channel_history,
+			fee_snapshots,
+			esplora: esplora.map(Arc::new),
+			onchain_wallet: std::sync::Mutex::new(None),
 			handle: handle.clone(),
 		};
+		// Start out with real estimates if we can, the floors otherwise.
+		if let Err(e) = BitcoindClient::refresh_fee_estimates(
+			&client.fees,
+			&client.bitcoind_rpc_client,
+			client.esplora.as_deref(),
+			fee_floors,
+		)
+		.await
+		{
+			eprintln!(
+				"Warning: Failed to get fee estimates from {}, using floors: {}",
+				client.chain_source_name(),
+				e
+			);
+		}
 		BitcoindClient::poll_for_fee_estimates(
 			client.fees.clone(),
 			client.bitcoind_rpc_client.clone(),
+			client.esplora.clone(),
+			fee_floors,
 			handle,
 		);
 		Ok(client)
 	}
 
-	fn poll_for_fee_estimates(
-		fees: Arc<HashMap<Target, AtomicU32>>, rpc_client: Arc<Mutex<RpcClient>>,
-		handle: tokio::runtime::Handle,
-	) {
-		handle.spawn(async move {
-			loop {
-				let background_estimate = {
-					let mut rpc = rpc_client.lock().await;
-					let background_conf_target = serde_json::json!(144);
-					let background_estimate_mode = serde_json::json!("ECONOMICAL");
-					let resp = rpc
-						.call_method::<FeeResponse>(
-							"estimatesmartfee",
-							&vec![background_conf_target, background_estimate_mode],
-						)
-						.await
-						.unwrap();
-					match resp.feerate_sat_per_kw {
-						Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
-						None => MIN_FEERATE,
-					}
-				};
+	/// Connects to a specific wallet loaded in bitcoind, checking that it exists.
+	async fn new_wallet_rpc_client(
+		host: &str, port: u16, auth: &RpcAuth, wallet: &str, timeout: Duration,
+	) -> std::io::Result<RpcConnection> {
+		let rpc_client = RpcConnection::new(host, port, auth, Some(wallet), timeout)?;
+		rpc_client.call_once::<WalletBalance>("getbalance", &vec![]).await.map_err(|_| {
+			std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				format!("Failed to access bitcoind wallet {} - is it loaded?", wallet),
+			)
+		})?;
+		Ok(rpc_client)
+	}
 
-				let normal_estimate = {
-					let mut rpc = rpc_client.lock().await;
-					let normal_conf_target = serde_json::json!(18);
-					let normal_estimate_mode = serde_json::json!("ECONOMICAL");
-					let resp = rpc
-						.call_method::<FeeResponse>(
-							"estimatesmartfee",
-							&vec![normal_conf_target, normal_estimate_mode],
-						)
-						.await
-						.unwrap();
-					match resp.feerate_sat_per_kw {
-						Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
-						None => 2000,
-					}
-				};
+	/// Where we get blocks and fee estimates from and broadcast transactions to, for messages.
+	pub fn chain_source_name(&self) -> &'static str {
+		match self.esplora {
+			Some(_) => "Esplora",
+			None => "bitcoind",
+		}
+	}
+
+	/// Uses our internal wallet, rather than bitcoind's, for all our on-chain funds from now on.
+	pub fn use_onchain_wallet(&self, wallet: Arc<OnchainWallet>) {
+		*self.onchain_wallet.lock().unwrap() = Some(wallet);
+	}
+
+	/// Our internal wallet, if our on-chain funds are held in it rather than in bitcoind's.
+	pub fn onchain_wallet(&self) -> Option<Arc<OnchainWallet>> {
+		self.onchain_wallet.lock().unwrap().clone()
+	}
+
+	/// Whether funding transactions may only spend confirmed outputs.
+	pub fn fund_from_confirmed_only(&self) -> bool {
+		self.fund_from_confirmed_only
+	}
+
+	/// The name of the dedicated channel funding wallet, if one was configured.
+	pub fn funding_wallet(&self) -> Option<&str> {
+		self.funding_wallet.as_ref().map(|w| w.as_str())
+	}
 
-				let high_prio_estimate = {
-					let mut rpc = rpc_client.lock().await;
-					let high_prio_conf_target = serde_json::json!(6);
-					let high_prio_estimate_mode = serde_json::json!("CONSERVATIVE");
+	/// Whether the funding and operating wallets are one and the same.
+	pub fn single_wallet(&self) -> bool {
+		self.funding_wallet == self.operating_wallet
+	}
+
+	/// The name of the wallet with the given role, `None` for bitcoind's default wallet.
+	pub fn wallet_name(&self, role: WalletRole) -> Option<&str> {
+		match role {
+			WalletRole::Funding => self.funding_wallet.as_ref().map(|w| w.as_str()),
+			WalletRole::Operating => self.operating_wallet.as_ref().map(|w| w.as_str()),
+		}
+	}
+
+	fn wallet_client(&self, role: WalletRole) -> &Arc<RpcConnection> {
+		match role {
+			WalletRole::Funding => &self.funding_wallet_client,
+			WalletRole::Operating => &self.operating_wallet_client,
+		}
+	}
+
+	/// Whether funding transactions need to be signed externally as the funding wallet holds no
+	/// private keys.
+	pub fn funding_wallet_watch_only(&self) -> bool {
+		self.funding_wallet_watch_only && self.onchain_wallet().is_none()
+	}
+
+	/// Prints the txid of the transaction spending `funding_outpoint`, i.e. the channel's closing or
+	/// commitment transaction, once we've broadcast it.
+	pub fn report_close_broadcast(&self, funding_outpoint: OutPoint, channel_id: [u8; 32]) {
+		self.closing_channels.lock().unwrap().insert(funding_outpoint, channel_id);
+	}
+
+	pub fn cancel_close_report(&self, funding_outpoint: &OutPoint) {
+		self.closing_channels.lock().unwrap().remove(funding_outpoint);
+	}
+
+	/// Refreshes the cached feerate of every target from bitcoind, or Esplora if given. If there's
+	/// no estimate for a target, e.g. on a fresh regtest chain, we fall back to the target's floor
+	/// or bitcoind's mempool minimum fee, whichever is higher. If the estimates can't be fetched at
+	/// all, the previous feerates are kept.
+	async fn refresh_fee_estimates(
+		fees: &FeeCache, rpc: &RpcConnection, esplora: Option<&EsploraClient>, floors: FeeFloors,
+	) -> std::io::Result<()> {
+		// Nothing below this feerate would even be relayed. Esplora doesn't tell us bitcoind's.
+		let mempool_min_feerate = match esplora {
+			Some(_) => None,
+			None => Some(rpc.call::<MempoolMinFee>("getmempoolinfo", &vec![]).await?.0),
+		};
+		let min_feerate = mempool_min_feerate.unwrap_or(0);
+		let mut estimates = Vec::new();
+		for (target, conf_target, estimate_mode) in FEE_TARGETS.iter() {
+			let (feerate, source) = match esplora {
+				Some(esplora) => (esplora.estimate_fee(*conf_target).await?, FeeSource::Esplora),
+				None => {
 					let resp = rpc
-						.call_method::<FeeResponse>(
+						.call::<FeeResponse>(
 							"estimatesmartfee",
-							&vec![high_prio_conf_target, high_prio_estimate_mode],
+							&vec![serde_json::json!(conf_target), serde_json::json!(estimate_mode)],
 						)
-						.await
-						.unwrap();
+						.await?;
+					(resp.feerate_sat_per_kw, FeeSource::Estimate)
+				}
+			};
+			let floor = match target {
+				Target::Background => floors.background,
+				Target::Normal => floors.normal,
+				Target::HighPriority => floors.high_priority,
+			};
+			let estimate = match feerate {
+				Some(feerate) if feerate >= min_feerate => (feerate, source),
+				Some(_) => (min_feerate, FeeSource::MempoolMinFee),
+				None if min_feerate > floor => (min_feerate, FeeSource::MempoolMinFee),
+				None => (floor, FeeSource::Floor),
+			};
+			estimates.push((target.clone(), estimate));
+		}
 
-					match resp.feerate_sat_per_kw {
-						Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
-						None => 5000,
-					}
-				};
+		let mut details = fees.details.lock().unwrap();
+		for (target, (feerate, source)) in estimates {
+			let feerate = std::cmp::max(feerate, MIN_FEERATE);
+			fees.feerates.get(&target).unwrap().store(feerate, Ordering::Release);
+			details.sources.insert(target, source);
+		}
+		details.mempool_min_feerate = mempool_min_feerate;
+		details.last_refresh = Some(Instant::now());
+		Ok(())
+	}
 
-				fees.get(&Target::Background)
-					.unwrap()
-					.store(background_estimate, Ordering::Release);
-				fees.get(&Target::Normal).unwrap().store(normal_estimate, Ordering::Release);
-				fees.get(&Target::HighPriority)
-					.unwrap()
-					.store(high_prio_estimate, Ordering::Release);
-				tokio::time::sleep(Duration::from_secs(60)).await;
+	fn poll_for_fee_estimates(
+		fees: Arc<FeeCache>, rpc_client: Arc<RpcConnection>, esplora: Option<Arc<EsploraClient>>,
+		floors: FeeFloors, handle: tokio::runtime::Handle,
+	) {
+		handle.spawn(async move {
+			let mut failing = false;
+			loop {
+				tokio::time::sleep(FEE_REFRESH_INTERVAL).await;
+				// Only warn when refreshes start failing, rather than every time.
+				let refresh = BitcoindClient::refresh_fee_estimates(
+					&fees,
+					&rpc_client,
+					esplora.as_deref(),
+					floors,
+				);
+				match refresh.await {
+					Ok(()) => failing = false,
+					Err(e) if !failing => {
+						failing = true;
+						let source = if esplora.is_some() { "Esplora" } else { "bitcoind" };
+						eprintln!(
+							"Warning: Failed to refresh fee estimates from {}, using cached ones: {}",
+							source, e
+						);
+					}
+					Err(_) => {}
+				}
 			}
 		});
 	}
 
+	/// The feerates we currently hand LDK, with where each came from.
+	pub fn cached_fees(&self) -> CachedFees {
+		let details = self.fees.details.lock().unwrap();
+		let feerates = FEE_TARGETS
+			.iter()
+			.map(|(target, conf_target, _)| CachedFee {
+				target: target.clone(),
+				conf_target: *conf_target,
+				feerate_sat_per_kw: self.fees.feerates.get(target).unwrap().load(Ordering::Acquire),
+				source: *details.sources.get(target).unwrap_or(&FeeSource::Floor),
+			})
+			.collect();
+		CachedFees {
+			feerates,
+			mempool_min_feerate: details.mempool_min_feerate,
+			age: details.last_refresh.map(|refreshed| refreshed.elapsed()),
+		}
+	}
+
+	/// The fee estimates behind the transactions we've broadcast.
+	pub fn fee_snapshots(&self) -> &FeeSnapshotLog {
+		&self.fee_snapshots
+	}
+
 	pub fn get_new_rpc_client(&self) -> std::io::Result<RpcClient> {
 		let http_endpoint = HttpEndpoint::for_host(self.host.clone()).with_port(self.port);
-		let rpc_credentials =
-			base64::encode(format!("{}:{}", self.rpc_user.clone(), self.rpc_password.clone()));
-		RpcClient::new(&rpc_credentials, http_endpoint)
+		RpcClient::new(&self.auth.credentials()?, http_endpoint)
 	}
 
-	pub async fn create_raw_transaction(&self, outputs: Vec<HashMap<String, f64>>) -> RawTx {
-		let mut rpc = self.bitcoind_rpc_client.lock().await;
+	pub async fn create_raw_transaction(
+		&self, outputs: Vec<HashMap<String, f64>>,
+	) -> std::io::Result<RawTx> {
+		let rpc = &self.bitcoind_rpc_client;
 
 		let outputs_json = serde_json::json!(outputs);
-		rpc.call_method::<RawTx>("createrawtransaction", &vec![serde_json::json!([]), outputs_json])
-			.await
-			.unwrap()
+		rpc.call::<RawTx>("createrawtransaction", &vec![serde_json::json!([]), outputs_json]).await
 	}
 
-	pub async fn fund_raw_transaction(&self, raw_tx: RawTx) -> FundedTx {
-		let mut rpc = self.bitcoind_rpc_client.lock().await;
+	pub async fn fund_raw_transaction(&self, raw_tx: RawTx) -> std::io::Result<FundedTx> {
+		let rpc = &self.funding_wallet_client;
 
 		let raw_tx_json = serde_json::json!(raw_tx.0);
-		let options = serde_json::json!({
+		let mut options = serde_json::json!({
 			// LDK gives us feerates in satoshis per KW but Bitcoin Core here expects fees
 			// denominated in satoshis per vB. First we need to multiply by 4 to convert weight
 			// units to virtual bytes, then divide by 1000 to convert KvB to vB.
@@ -203,65 +869,370 @@ impl BitcoindClient {
 			// change address or to a new channel output negotiated with the same node.
 			"replaceable": false,
 		});
-		rpc.call_method("fundrawtransaction", &[raw_tx_json, options]).await.unwrap()
+		if self.fund_from_confirmed_only {
+			// Requires Bitcoin Core 25.0 or later, older versions reject the option.
+			options["minconf"] = serde_json::json!(1);
+		}
+		rpc.call("fundrawtransaction", &[raw_tx_json, options]).await
 	}
 
-	pub async fn send_raw_transaction(&self, raw_tx: RawTx) {
-		let mut rpc = self.bitcoind_rpc_client.lock().await;
+	/// Creates a PSBT paying `outputs` with inputs from the funding wallet, to be signed elsewhere.
+	pub async fn wallet_create_funded_psbt(
+		&self, outputs: Vec<HashMap<String, f64>>,
+	) -> std::io::Result<Psbt> {
+		let rpc = &self.funding_wallet_client;
+
+		let outputs_json = serde_json::json!(outputs);
+		let mut options = serde_json::json!({
+			// See `fund_raw_transaction` for the choice of feerate and why we disable RBF.
+			"fee_rate": self.get_est_sat_per_1000_weight(ConfirmationTarget::Normal) as f64 / 250.0,
+			"replaceable": false,
+		});
+		if self.fund_from_confirmed_only {
+			options["minconf"] = serde_json::json!(1);
+		}
+		rpc.call(
+			"walletcreatefundedpsbt",
+			&[serde_json::json!([]), outputs_json, serde_json::json!(0), options],
+		)
+		.await
+	}
+
+	pub async fn finalize_psbt(&self, psbt: &str) -> std::io::Result<FinalizedPsbt> {
+		let rpc = &self.bitcoind_rpc_client;
+
+		let psbt_json = serde_json::json!(psbt);
+		rpc.call("finalizepsbt", &[psbt_json]).await
+	}
+
+	pub async fn send_raw_transaction(&self, raw_tx: RawTx) -> std::io::Result<Txid> {
+		let rpc = &self.bitcoind_rpc_client;
 
 		let raw_tx_json = serde_json::json!(raw_tx.0);
-		rpc.call_method::<Txid>("sendrawtransaction", &[raw_tx_json]).await.unwrap();
+		rpc.call::<Txid>("sendrawtransaction", &[raw_tx_json]).await
 	}
 
-	pub async fn sign_raw_transaction_with_wallet(&self, tx_hex: String) -> SignedTx {
-		let mut rpc = self.bitcoind_rpc_client.lock().await;
+	pub async fn sign_raw_transaction_with_wallet(
+		&self, tx_hex: String,
+	) -> std::io::Result<SignedTx> {
+		let rpc = &self.funding_wallet_client;
 
 		let tx_hex_json = serde_json::json!(tx_hex);
-		rpc.call_method("signrawtransactionwithwallet", &vec![tx_hex_json]).await.unwrap()
+		rpc.call("signrawtransactionwithwallet", &vec![tx_hex_json]).await
 	}
 
-	pub async fn get_new_address(&self) -> Address {
-		let mut rpc = self.bitcoind_rpc_client.lock().await;
+	pub async fn get_new_address(&self) -> std::io::Result<Address> {
+		if let Some(wallet) = self.onchain_wallet() {
+			return Ok(wallet.new_address());
+		}
+		let rpc = &self.operating_wallet_client;
 
 		let addr_args = vec![serde_json::json!("LDK output address")];
-		let addr = rpc.call_method::<NewAddress>("getnewaddress", &addr_args).await.unwrap();
-		Address::from_str(addr.0.as_str()).unwrap()
+		let addr = rpc.call::<NewAddress>("getnewaddress", &addr_args).await?;
+		Address::from_str(addr.0.as_str())
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
 	}
 
-	pub async fn get_blockchain_info(&self) -> BlockchainInfo {
-		let mut rpc = self.bitcoind_rpc_client.lock().await;
-		rpc.call_method::<BlockchainInfo>("getblockchaininfo", &vec![]).await.unwrap()
+	pub async fn get_blockchain_info(&self) -> std::io::Result<BlockchainInfo> {
+		let rpc = &self.bitcoind_rpc_client;
+		rpc.call::<BlockchainInfo>("getblockchaininfo", &vec![]).await
 	}
-}
 
-impl FeeEstimator for BitcoindClient {
-	fn get_est_sat_per_1000_weight(&self, confirmation_target: ConfirmationTarget) -> u32 {
-		match confirmation_target {
-			ConfirmationTarget::Background => {
-				self.fees.get(&Target::Background).unwrap().load(Ordering::Acquire)
-			}
-			ConfirmationTarget::Normal => {
-				self.fees.get(&Target::Normal).unwrap().load(Ordering::Acquire)
+	/// Reconnects to bitcoind after it couldn't be reached, e.g. because it restarted. Our RPC
+	/// calls do so themselves, this is for the chain sync which talks to bitcoind directly.
+	pub async fn reconnect(&self) {
+		self.bitcoind_rpc_client.reconnect().await;
+	}
+
+	/// Returns the confirmed balance of our wallet(s), in satoshis.
+	pub async fn get_balance(&self) -> std::io::Result<u64> {
+		if let Some(wallet) = self.onchain_wallet() {
+			return Ok(wallet.balances().confirmed_sat);
+		}
+		let operating_balance = {
+			let rpc = &self.operating_wallet_client;
+			rpc.call::<WalletBalance>("getbalance", &vec![]).await?.0
+		};
+		if self.funding_wallet == self.operating_wallet {
+			return Ok(operating_balance);
+		}
+		let rpc = &self.funding_wallet_client;
+		let funding_balance = rpc.call::<WalletBalance>("getbalance", &vec![]).await?.0;
+		Ok(operating_balance + funding_balance)
+	}
+
+	pub async fn get_wallet_balances(&self, role: WalletRole) -> std::io::Result<WalletBalances> {
+		if let Some(wallet) = self.onchain_wallet() {
+			return Ok(wallet.balances());
+		}
+		let rpc = self.wallet_client(role);
+		rpc.call::<WalletBalances>("getbalances", &vec![]).await
+	}
+
+	/// Lists the wallet's spendable outputs, including unconfirmed ones.
+	pub async fn list_unspent(&self, role: WalletRole) -> std::io::Result<Vec<WalletUtxo>> {
+		if let Some(wallet) = self.onchain_wallet() {
+			return Ok(wallet.list_unspent());
+		}
+		let rpc = self.wallet_client(role);
+		rpc.call::<ListUnspent>("listunspent", &[serde_json::json!(0)]).await.map(|l| l.0)
+	}
+
+	pub async fn get_wallet_address(&self, role: WalletRole) -> std::io::Result<Address> {
+		if let Some(wallet) = self.onchain_wallet() {
+			return Ok(wallet.new_address());
+		}
+		let rpc = self.wallet_client(role);
+
+		let addr_args = vec![serde_json::json!("LDK wallet address")];
+		let addr = rpc.call::<NewAddress>("getnewaddress", &addr_args).await?;
+		Address::from_str(addr.0.as_str())
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+	}
+
+	/// Whether `outpoint` has been spent by a confirmed transaction, ignoring the mempool.
+	pub async fn is_spent_on_chain(&self, outpoint: &OutPoint) -> std::io::Result<bool> {
+		if let Some(esplora) = &self.esplora {
+			return esplora.is_spent_on_chain(outpoint).await;
+		}
+		let rpc = &self.bitcoind_rpc_client;
+
+		let txid_json = serde_json::json!(outpoint.txid.to_string());
+		let vout_json = serde_json::json!(outpoint.vout);
+		let include_mempool_json = serde_json::json!(false);
+		let output = rpc
+			.call::<UnspentOutput>("gettxout", &[txid_json, vout_json, include_mempool_json])
+			.await?;
+		Ok(output.0.is_none())
+	}
+
+	/// Whether `outpoint` has been spent, by a confirmed transaction or one in the mempool. An
+	/// output of a transaction bitcoind doesn't know of counts as spent.
+	pub async fn is_spent(&self, outpoint: &OutPoint) -> std::io::Result<bool> {
+		if let Some(esplora) = &self.esplora {
+			return esplora.is_spent(outpoint).await;
+		}
+		Ok(self.get_output_confirmations(outpoint, true).await?.is_none())
+	}
+
+	/// How many confirmations `outpoint`'s transaction has if the output is unspent, counting
+	/// spends and transactions in the mempool only if `include_mempool` is set. This works on a
+	/// pruned bitcoind, as it only looks at the UTXO set.
+	pub async fn get_output_confirmations(
+		&self, outpoint: &OutPoint, include_mempool: bool,
+	) -> std::io::Result<Option<u32>> {
+		let rpc = &self.bitcoind_rpc_client;
+
+		let txid_json = serde_json::json!(outpoint.txid.to_string());
+		let vout_json = serde_json::json!(outpoint.vout);
+		let include_mempool_json = serde_json::json!(include_mempool);
+		let output = rpc
+			.call::<UnspentOutput>("gettxout", &[txid_json, vout_json, include_mempool_json])
+			.await?;
+		Ok(output.0.map(|output| output.confirmations))
+	}
+
+	/// Looks `txid` up in our wallets, returning the transaction and, if it's confirmed, the hash
+	/// and height of its block and its index in the block. Unlike fetching the block, this works
+	/// on a pruned bitcoind, but only for transactions our wallets were involved in.
+	pub async fn get_wallet_transaction(
+		&self, txid: &Txid,
+	) -> std::io::Result<Option<(Transaction, Option<(BlockHash, u32, usize)>)>> {
+		let wallet_tx = match self.look_up_wallet_transaction(txid).await? {
+			Some(wallet_tx) => wallet_tx,
+			None => return Ok(None),
+		};
+		let tx = hex_utils::to_vec(&wallet_tx.hex)
+			.and_then(|bytes| encode::deserialize(&bytes).ok())
+			.ok_or_else(|| {
+				std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					"invalid transaction from bitcoind",
+				)
+			})?;
+		Ok(Some((tx, wallet_tx.confirmed_in)))
+	}
+
+	/// The fee paid by a transaction our wallets funded, `None` if they didn't or it's unknown. The
+	/// internal wallet doesn't keep the transactions it creates, so it never knows.
+	pub async fn get_transaction_fee(&self, txid: &Txid) -> std::io::Result<Option<u64>> {
+		if self.onchain_wallet().is_some() {
+			return Ok(None);
+		}
+		Ok(self.look_up_wallet_transaction(txid).await?.and_then(|wallet_tx| wallet_tx.fee_sat))
+	}
+
+	async fn look_up_wallet_transaction(
+		&self, txid: &Txid,
+	) -> std::io::Result<Option<WalletTransaction>> {
+		let mut wallets = vec![&self.funding_wallet_client];
+		if self.funding_wallet != self.operating_wallet {
+			wallets.push(&self.operating_wallet_client);
+		}
+		for rpc in wallets {
+			let txid_json = serde_json::json!(txid.to_string());
+			match rpc.call::<WalletTransaction>("gettransaction", &[txid_json]).await {
+				Ok(wallet_tx) => return Ok(Some(wallet_tx)),
+				// bitcoind rejects transactions the wallet doesn't know about.
+				Err(e) if !is_transient(&e) => {}
+				Err(e) => return Err(e),
 			}
-			ConfirmationTarget::HighPriority => {
-				self.fees.get(&Target::HighPriority).unwrap().load(Ordering::Acquire)
+		}
+		Ok(None)
+	}
+
+	/// Broadcasts `tx`, unlike `broadcast_transaction` reporting whether it was accepted. `built_at`
+	/// is the estimate its feerate was taken from, recorded with the estimates once it's accepted.
+	pub async fn send_transaction(
+		&self, tx: &Transaction, purpose: BroadcastPurpose, built_at: Option<Target>,
+	) -> std::io::Result<Txid> {
+		let rpc = &self.bitcoind_rpc_client;
+
+		self.channel_history.transaction_broadcast(tx);
+		let txid = match &self.esplora {
+			Some(esplora) => esplora.broadcast(tx).await?,
+			None => {
+				let tx_json = serde_json::json!(encode::serialize_hex(tx));
+				rpc.call::<Txid>("sendrawtransaction", &[tx_json]).await?
 			}
+		};
+		self.fee_snapshots.record(Some(tx), txid, purpose, built_at, self.cached_fees());
+		Ok(txid)
+	}
+
+	/// Mines `num_blocks` blocks paying to `address`. Only usable on regtest.
+	pub async fn generate_to_address(
+		&self, num_blocks: u64, address: &Address,
+	) -> std::io::Result<Vec<BlockHash>> {
+		let rpc = &self.bitcoind_rpc_client;
+
+		let num_blocks_json = serde_json::json!(num_blocks);
+		let address_json = serde_json::json!(address.to_string());
+		rpc.call_once::<GeneratedBlocks>("generatetoaddress", &[num_blocks_json, address_json])
+			.await
+			.map(|blocks| blocks.0)
+	}
+
+	/// Pays `amt_sat` from our operating wallet to `address`.
+	pub async fn send_to_address(&self, address: &Address, amt_sat: u64) -> std::io::Result<Txid> {
+		if let Some(wallet) = self.onchain_wallet() {
+			let output = TxOut { value: amt_sat, script_pubkey: address.script_pubkey() };
+			let feerate = self.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
+			let tx = wallet
+				.create_transaction(vec![output], feerate, false)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+			return self
+				.send_transaction(&tx, BroadcastPurpose::Send, Some(Target::Normal))
+				.await
+				.map_err(|e| {
+					wallet.cancel_transaction(&tx);
+					e
+				});
 		}
+		let rpc = &self.operating_wallet_client;
+
+		let address_json = serde_json::json!(address.to_string());
+		let amt_json = serde_json::json!(amt_sat as f64 / 100_000_000.0);
+		let txid = rpc.call_once::<Txid>("sendtoaddress", &[address_json, amt_json]).await?;
+		// bitcoind's wallet picked the feerate, but what ours were still tells whether it was sane.
+		self.fee_snapshots.record(None, txid, BroadcastPurpose::Send, None, self.cached_fees());
+		Ok(txid)
 	}
 }
 
+impl FeeEstimator for BitcoindClient {
+	fn get_est_sat_per_1000_weight(&self, confirmation_target: ConfirmationTarget) -> u32 {
+		// LDK calls this on its hot paths, so it only ever reads the cache.
+		let target = match confirmation_target {
+			ConfirmationTarget::Background => Target::Background,
+			ConfirmationTarget::Normal => Target::Normal,
+			ConfirmationTarget::HighPriority => Target::HighPriority,
+		};
+		self.fees.feerates.get(&target).unwrap().load(Ordering::Acquire)
+	}
+}
+
+/// Cooperative closing transactions spend only the channel's 2-of-2 funding output and, unlike
+/// commitment transactions, are final with no locktime.
+fn is_cooperative_close_candidate(tx: &Transaction) -> bool {
+	tx.input.len() == 1 && tx.input[0].sequence == 0xFFFFFFFF && tx.lock_time == 0
+}
+
 impl BroadcasterInterface for BitcoindClient {
 	fn broadcast_transaction(&self, tx: &Transaction) {
+		self.channel_history.transaction_broadcast(tx);
+		let purpose = match self.channel_history.spends_funding_output(tx) {
+			true => BroadcastPurpose::Close,
+			false => BroadcastPurpose::Channel,
+		};
+		self.fee_snapshots.record(Some(tx), tx.txid(), purpose, None, self.cached_fees());
 		let bitcoind_rpc_client = self.bitcoind_rpc_client.clone();
-		let tx_serialized = serde_json::json!(encode::serialize_hex(tx));
+		let esplora = self.esplora.clone();
+		let tx = tx.clone();
+		let close_candidate = if is_cooperative_close_candidate(tx) {
+			let outputs_sat: u64 = tx.output.iter().map(|output| output.value).sum();
+			Some((tx.txid(), tx.input[0].previous_output, outputs_sat))
+		} else {
+			None
+		};
+		let closed_channel = {
+			let mut closing_channels = self.closing_channels.lock().unwrap();
+			tx.input.iter().find_map(|input| closing_channels.remove(&input.previous_output))
+		};
+		let txid = tx.txid();
 		self.handle.spawn(async move {
-			let mut rpc = bitcoind_rpc_client.lock().await;
+			let rpc = bitcoind_rpc_client;
+			// Print the fee a cooperative close settled on while the funding output, and thus the
+			// channel value, can still be looked up.
+			if let Some((txid, funding_outpoint, outputs_sat)) = close_candidate {
+				let funding_output = match &esplora {
+					Some(esplora) => match esplora.get_output(&funding_outpoint).await {
+						Ok(Some(output)) => {
+							Some((output.script_pubkey.is_v0_p2wsh(), output.value))
+						}
+						_ => None,
+					},
+					None => {
+						let txid_json = serde_json::json!(funding_outpoint.txid.to_string());
+						let vout_json = serde_json::json!(funding_outpoint.vout);
+						match rpc.call::<UnspentOutput>("gettxout", &[txid_json, vout_json]).await {
+							Ok(UnspentOutput(Some(output))) => {
+								Some((output.is_p2wsh, output.value_sat))
+							}
+							_ => None,
+						}
+					}
+				};
+				if let Some((true, funding_value_sat)) = funding_output {
+					if funding_value_sat >= outputs_sat {
+						println!(
+							"\nEVENT: Cooperative close transaction {} pays a fee of {} sat",
+							txid,
+							funding_value_sat - outputs_sat
+						);
+						print!("> ");
+						io::stdout().flush().unwrap();
+					}
+				}
+			}
 			// This may error due to RL calling `broadcast_transaction` with the same transaction
 			// multiple times, but the error is safe to ignore.
-			match rpc.call_method::<Txid>("sendrawtransaction", &vec![tx_serialized]).await {
+			let res = match &esplora {
+				Some(esplora) => esplora.broadcast(&tx).await,
+				None => {
+					let tx_serialized = serde_json::json!(encode::serialize_hex(&tx));
+					rpc.call::<Txid>("sendrawtransaction", &vec![tx_serialized]).await
+				}
+			};
+			match res {
 				Ok(_) => {}
+				// LDK rebroadcasts its transactions as blocks come in, so if bitcoind stays
+				// unreachable for longer than we retry we'll still get them out eventually.
+				Err(e) if is_transient(&e) => {
+					eprintln!("Warning: Failed to broadcast transaction {}: {}", txid, e);
+				}
 				Err(e) => {
-					let err_str = e.get_ref().unwrap().to_string();
+					let err_str = e.get_ref().map_or(e.to_string(), |e| e.to_string());
 					if !err_str.contains("Transaction already in block chain")
 						&& !err_str.contains("Inputs missing or spent")
 						&& !err_str.contains("bad-txns-inputs-missingorspent")
@@ -272,6 +1243,15 @@ impl BroadcasterInterface for BitcoindClient {
 					}
 				}
 			}
+			if let Some(channel_id) = closed_channel {
+				println!(
+					"\nEVENT: Broadcast transaction {} closing channel {}",
+					txid,
+					hex_utils::hex_str(&channel_id)
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+			}
 		});
 	}
 }
@@ -0,0 +1,180 @@
+use crate::hex_utils;
+use crate::{time_now_secs, HTLCStatus, PaymentInfoStore};
+use lightning::ln::PaymentHash;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A set of invoices meant to be paid together, e.g. each payer's share of a split bill. The
+/// invoices themselves live in the inbound payment store, a bundle only records which ones belong
+/// together.
+pub(crate) struct InvoiceBundle {
+	/// When the bundle was created, in seconds since the UNIX epoch.
+	pub(crate) created_at: u64,
+	pub(crate) payment_hashes: Vec<PaymentHash>,
+}
+
+/// The settlement state of a bundle as a whole.
+pub(crate) enum BundleStatus {
+	/// None of the bundle's invoices have been paid yet.
+	Unpaid,
+	/// Some, but not all, of the bundle's invoices have been paid.
+	PartiallyPaid,
+	/// Every invoice in the bundle has been paid.
+	Paid,
+	/// At least one invoice can no longer be paid, e.g. because it expired, so the bundle will
+	/// never settle in full.
+	Failed,
+}
+
+impl BundleStatus {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			BundleStatus::Unpaid => "unpaid",
+			BundleStatus::PartiallyPaid => "partially_paid",
+			BundleStatus::Paid => "paid",
+			BundleStatus::Failed => "failed",
+		}
+	}
+}
+
+impl InvoiceBundle {
+	/// Works out the bundle's settlement state from its invoices in the inbound payment store.
+	/// Invoices which have already been pruned from the store are treated as failed, as we can no
+	/// longer tell whether they were paid.
+	pub(crate) fn status(&self, inbound_payments: &PaymentInfoStore) -> BundleStatus {
+		let mut num_paid = 0;
+		for payment_hash in self.payment_hashes.iter() {
+			match inbound_payments.get(payment_hash).map(|payment| &payment.status) {
+				Some(HTLCStatus::Succeeded) => num_paid += 1,
+				Some(HTLCStatus::Pending) | Some(HTLCStatus::TimedOut) => {}
+				Some(HTLCStatus::Failed) | None => return BundleStatus::Failed,
+			}
+		}
+		if num_paid == self.payment_hashes.len() {
+			BundleStatus::Paid
+		} else if num_paid > 0 {
+			BundleStatus::PartiallyPaid
+		} else {
+			BundleStatus::Unpaid
+		}
+	}
+}
+
+/// Invoice bundles by id, written to disk as each bundle is created.
+pub(crate) struct BundleStore {
+	path: String,
+	bundles: HashMap<u64, InvoiceBundle>,
+}
+
+pub(crate) type BundleStorage = Arc<Mutex<BundleStore>>;
+
+impl BundleStore {
+	pub(crate) fn new(path: String) -> Self {
+		let bundles = read_bundles(Path::new(&path));
+		Self { path, bundles }
+	}
+
+	pub(crate) fn get(&self, bundle_id: u64) -> Option<&InvoiceBundle> {
+		self.bundles.get(&bundle_id)
+	}
+
+	/// The id and bundle which the invoice with the given payment hash belongs to, if any.
+	pub(crate) fn bundle_of(&self, payment_hash: &PaymentHash) -> Option<(u64, &InvoiceBundle)> {
+		self.bundles
+			.iter()
+			.find(|(_, bundle)| bundle.payment_hashes.contains(payment_hash))
+			.map(|(bundle_id, bundle)| (*bundle_id, bundle))
+	}
+
+	/// Puts the invoice with payment hash `new` in place of `old` in the bundle `old` belongs to, if
+	/// any, e.g. once `old` has been re-issued.
+	pub(crate) fn replace(&mut self, old: &PaymentHash, new: PaymentHash) {
+		let mut replaced = false;
+		for bundle in self.bundles.values_mut() {
+			for payment_hash in bundle.payment_hashes.iter_mut() {
+				if payment_hash == old {
+					*payment_hash = new;
+					replaced = true;
+				}
+			}
+		}
+		if replaced && persist_bundles(Path::new(&self.path), &self.bundles).is_err() {
+			eprintln!(
+				"Warning: Failed to persist invoice bundles, check your disk and permissions"
+			);
+		}
+	}
+
+	/// Records a new bundle of the given invoices, returning its id.
+	pub(crate) fn insert(&mut self, payment_hashes: Vec<PaymentHash>) -> u64 {
+		let bundle_id = self.bundles.keys().max().map_or(1, |id| id + 1);
+		self.bundles
+			.insert(bundle_id, InvoiceBundle { created_at: time_now_secs(), payment_hashes });
+		if persist_bundles(Path::new(&self.path), &self.bundles).is_err() {
+			eprintln!(
+				"Warning: Failed to persist invoice bundles, check your disk and permissions"
+			);
+		}
+		bundle_id
+	}
+}
+
+/// Each bundle is stored as a line of the form `<id> <created_at> <payment_hash>,...`.
+fn persist_bundles(path: &Path, bundles: &HashMap<u64, InvoiceBundle>) -> std::io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for (bundle_id, bundle) in bundles.iter() {
+		let payment_hashes: Vec<String> =
+			bundle.payment_hashes.iter().map(|hash| hex_utils::hex_str(&hash.0)).collect();
+		file.write_all(
+			format!("{} {} {}\n", bundle_id, bundle.created_at, payment_hashes.join(","))
+				.as_bytes(),
+		)?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+fn read_bundles(path: &Path) -> HashMap<u64, InvoiceBundle> {
+	let mut bundles = HashMap::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return bundles,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		let parts: Vec<&str> = line.split(' ').collect();
+		if parts.len() != 3 {
+			eprintln!("Warning: Skipping malformed invoice bundle entry in {:?}", path);
+			continue;
+		}
+		let payment_hashes: Option<Vec<PaymentHash>> = parts[2]
+			.split(',')
+			.map(|hash| {
+				let bytes = hex_utils::to_vec(hash)?;
+				if bytes.len() != 32 {
+					return None;
+				}
+				let mut payment_hash = [0; 32];
+				payment_hash.copy_from_slice(&bytes);
+				Some(PaymentHash(payment_hash))
+			})
+			.collect();
+		match (parts[0].parse(), parts[1].parse(), payment_hashes) {
+			(Ok(bundle_id), Ok(created_at), Some(payment_hashes)) => {
+				bundles.insert(bundle_id, InvoiceBundle { created_at, payment_hashes });
+			}
+			_ => eprintln!("Warning: Skipping malformed invoice bundle entry in {:?}", path),
+		}
+	}
+	bundles
+}
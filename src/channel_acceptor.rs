@@ -0,0 +1,211 @@
+use crate::pause::PauseSwitch;
+use crate::wire_capture::WireCapture;
+use crate::ChannelManager;
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::ln::channelmanager::ChannelDetails;
+use lightning::ln::features::InitFeatures;
+use lightning::ln::msgs::{
+	AcceptChannel, AnnouncementSignatures, ChannelMessageHandler, ChannelReestablish,
+	ChannelUpdate, ClosingSigned, CommitmentSigned, ErrorMessage, FundingCreated, FundingLocked,
+	FundingSigned, Init, OpenChannel, RevokeAndACK, Shutdown, UpdateAddHTLC, UpdateFailHTLC,
+	UpdateFailMalformedHTLC, UpdateFee, UpdateFulfillHTLC,
+};
+use lightning::util::events::{MessageSendEvent, MessageSendEventsProvider};
+use std::collections::HashSet;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Which channels other nodes may open to us. LDK 0.0.103 can't be asked whether to accept an
+/// inbound channel, so each `open_channel` is checked against the policy before the channel
+/// manager sees it, and refused with an error otherwise.
+///
+/// There's no setting for zero-conf channels: LDK 0.0.103 always waits for the funding
+/// transaction of an inbound channel to confirm before using it, whatever the opener asks.
+#[derive(Clone, Default)]
+pub(crate) struct AcceptorPolicy {
+	pub(crate) min_channel_sat: Option<u64>,
+	pub(crate) max_channel_sat: Option<u64>,
+	/// Counts every channel we have with the peer, whoever opened it, including ones not yet
+	/// funded.
+	pub(crate) max_channels_per_peer: Option<usize>,
+	/// If not empty, only these nodes may open channels to us.
+	pub(crate) allowed_peers: HashSet<PublicKey>,
+	pub(crate) denied_peers: HashSet<PublicKey>,
+}
+
+impl AcceptorPolicy {
+	/// Checks a channel `their_node_id` wants to open to us, given our current `channels`,
+	/// returning why it's refused if it is. The reason is sent to the peer.
+	pub(crate) fn check(
+		&self, their_node_id: &PublicKey, msg: &OpenChannel, channels: &[ChannelDetails],
+	) -> Result<(), String> {
+		if self.denied_peers.contains(their_node_id)
+			|| (!self.allowed_peers.is_empty() && !self.allowed_peers.contains(their_node_id))
+		{
+			return Err("we don't accept channels from your node".to_string());
+		}
+		if let Some(min_sat) = self.min_channel_sat.filter(|min| msg.funding_satoshis < *min) {
+			return Err(format!(
+				"channel of {} sat is smaller than our minimum of {} sat",
+				msg.funding_satoshis, min_sat
+			));
+		}
+		if let Some(max_sat) = self.max_channel_sat.filter(|max| msg.funding_satoshis > *max) {
+			return Err(format!(
+				"channel of {} sat is larger than our maximum of {} sat",
+				msg.funding_satoshis, max_sat
+			));
+		}
+		if let Some(max_channels) = self.max_channels_per_peer {
+			let num_channels = channels
+				.iter()
+				.filter(|chan_info| chan_info.counterparty.node_id == *their_node_id)
+				.count();
+			if num_channels >= max_channels {
+				return Err(format!(
+					"we already have {} channel(s) with your node, our maximum",
+					num_channels
+				));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Sits in front of everything else handling channel messages, so that opens the policy refuses
+/// never reach the channel manager whatever else is configured, such as wire capture. Refusals go
+/// out through the `WireCapture`, so they show up in its dumps, but the refused `open_channel`
+/// itself doesn't. Every open is refused while the node is paused, or in a maintenance window which
+/// refuses channels.
+pub(crate) struct ChannelAcceptor {
+	inner: Arc<WireCapture>,
+	channel_manager: Arc<ChannelManager>,
+	policy: AcceptorPolicy,
+	pause_switch: Arc<PauseSwitch>,
+}
+
+impl ChannelAcceptor {
+	pub(crate) fn new(
+		inner: Arc<WireCapture>, channel_manager: Arc<ChannelManager>, policy: AcceptorPolicy,
+		pause_switch: Arc<PauseSwitch>,
+	) -> Self {
+		Self { inner, channel_manager, policy, pause_switch }
+	}
+
+	/// Why we won't accept the channel `their_node_id` wants to open, if we won't.
+	fn refusal(&self, their_node_id: &PublicKey, msg: &OpenChannel) -> Result<(), String> {
+		if self.pause_switch.refuses_channels() {
+			return Err("we aren't accepting channels right now, try again later".to_string());
+		}
+		self.policy.check(their_node_id, msg, &self.channel_manager.list_channels())
+	}
+}
+
+impl MessageSendEventsProvider for ChannelAcceptor {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+		self.inner.get_and_clear_pending_msg_events()
+	}
+}
+
+impl ChannelMessageHandler for ChannelAcceptor {
+	fn handle_open_channel(
+		&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &OpenChannel,
+	) {
+		if let Err(reason) = self.refusal(their_node_id, msg) {
+			println!(
+				"\nEVENT: Refused a {} sat channel from {}: {}",
+				msg.funding_satoshis, their_node_id, reason
+			);
+			print!("> ");
+			io::stdout().flush().unwrap();
+			self.inner.send_error(*their_node_id, msg.temporary_channel_id, reason);
+			return;
+		}
+		self.inner.handle_open_channel(their_node_id, their_features, msg)
+	}
+
+	fn handle_accept_channel(
+		&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &AcceptChannel,
+	) {
+		self.inner.handle_accept_channel(their_node_id, their_features, msg)
+	}
+
+	fn handle_funding_created(&self, their_node_id: &PublicKey, msg: &FundingCreated) {
+		self.inner.handle_funding_created(their_node_id, msg)
+	}
+
+	fn handle_funding_signed(&self, their_node_id: &PublicKey, msg: &FundingSigned) {
+		self.inner.handle_funding_signed(their_node_id, msg)
+	}
+
+	fn handle_funding_locked(&self, their_node_id: &PublicKey, msg: &FundingLocked) {
+		self.inner.handle_funding_locked(their_node_id, msg)
+	}
+
+	fn handle_shutdown(
+		&self, their_node_id: &PublicKey, their_features: &InitFeatures, msg: &Shutdown,
+	) {
+		self.inner.handle_shutdown(their_node_id, their_features, msg)
+	}
+
+	fn handle_closing_signed(&self, their_node_id: &PublicKey, msg: &ClosingSigned) {
+		self.inner.handle_closing_signed(their_node_id, msg)
+	}
+
+	fn handle_update_add_htlc(&self, their_node_id: &PublicKey, msg: &UpdateAddHTLC) {
+		self.inner.handle_update_add_htlc(their_node_id, msg)
+	}
+
+	fn handle_update_fulfill_htlc(&self, their_node_id: &PublicKey, msg: &UpdateFulfillHTLC) {
+		self.inner.handle_update_fulfill_htlc(their_node_id, msg)
+	}
+
+	fn handle_update_fail_htlc(&self, their_node_id: &PublicKey, msg: &UpdateFailHTLC) {
+		self.inner.handle_update_fail_htlc(their_node_id, msg)
+	}
+
+	fn handle_update_fail_malformed_htlc(
+		&self, their_node_id: &PublicKey, msg: &UpdateFailMalformedHTLC,
+	) {
+		self.inner.handle_update_fail_malformed_htlc(their_node_id, msg)
+	}
+
+	fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &CommitmentSigned) {
+		self.inner.handle_commitment_signed(their_node_id, msg)
+	}
+
+	fn handle_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &RevokeAndACK) {
+		self.inner.handle_revoke_and_ack(their_node_id, msg)
+	}
+
+	fn handle_update_fee(&self, their_node_id: &PublicKey, msg: &UpdateFee) {
+		self.inner.handle_update_fee(their_node_id, msg)
+	}
+
+	fn handle_announcement_signatures(
+		&self, their_node_id: &PublicKey, msg: &AnnouncementSignatures,
+	) {
+		self.inner.handle_announcement_signatures(their_node_id, msg)
+	}
+
+	fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool) {
+		self.inner.peer_disconnected(their_node_id, no_connection_possible)
+	}
+
+	fn peer_connected(&self, their_node_id: &PublicKey, msg: &Init) {
+		self.inner.peer_connected(their_node_id, msg)
+	}
+
+	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &ChannelReestablish) {
+		self.inner.handle_channel_reestablish(their_node_id, msg)
+	}
+
+	fn handle_channel_update(&self, their_node_id: &PublicKey, msg: &ChannelUpdate) {
+		self.inner.handle_channel_update(their_node_id, msg)
+	}
+
+	fn handle_error(&self, their_node_id: &PublicKey, msg: &ErrorMessage) {
+		self.inner.handle_error(their_node_id, msg)
+	}
+}
@@ -0,0 +1,426 @@
+use crate::cli;
+use crate::disk;
+use crate::hex_utils;
+use crate::storage::Storage;
+use crate::sweeper::OutputSweeper;
+use crate::wire_capture::WireCapture;
+use crate::{ChannelManager, PeerManager};
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::script::Script;
+use bitcoin::hash_types::{Txid, WPubkeyHash};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+use lightning::chain;
+use lightning::chain::keysinterface::{
+	BaseSign, KeysManager, SpendableOutputDescriptor, StaticPaymentOutputDescriptor,
+};
+use lightning::chain::transaction::OutPoint;
+use rand::{thread_rng, Rng};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Starts every backup file, followed by the nonce and the encrypted channels. Bump the version
+/// whenever the format changes, so that older backups can still be read.
+const MAGIC: &[u8; 8] = b"ldkscb01";
+
+/// Keeps the backup key apart from anything else derived from the node secret.
+const KEY_TAG: &[u8] = b"ldk-sample channel backup";
+
+/// How often we ask the peers of channels being recovered to force-close them, and hand the
+/// outputs their closes paid us to the sweeper.
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The channel keys ids LDK gave our channels' signers. `KeysManager` derives them from the time it
+/// was started at, so unlike our other keys they can't be derived from the seed again and must be
+/// backed up along with the channels. Each id is appended to a log on disk as a line of hex.
+pub(crate) struct ChannelKeysLog {
+	path: String,
+	ids: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl ChannelKeysLog {
+	pub(crate) fn new(path: String) -> Self {
+		let ids = Mutex::new(read_channel_keys_ids(Path::new(&path)));
+		Self { path, ids }
+	}
+
+	pub(crate) fn record(&self, channel_keys_id: [u8; 32]) {
+		let mut ids = self.ids.lock().unwrap();
+		if !ids.insert(channel_keys_id) {
+			return;
+		}
+		let appended = fs::OpenOptions::new().create(true).append(true).open(&self.path).and_then(
+			|mut file| {
+				file.write_all(format!("{}\n", hex_utils::hex_str(&channel_keys_id)).as_bytes())
+			},
+		);
+		if appended.is_err() {
+			eprintln!(
+				"Warning: Failed to persist channel keys ids, check your disk and permissions"
+			);
+		}
+	}
+
+	pub(crate) fn ids(&self) -> Vec<[u8; 32]> {
+		self.ids.lock().unwrap().iter().cloned().collect()
+	}
+}
+
+fn parse_id(hex: &str) -> Option<[u8; 32]> {
+	match hex_utils::to_vec(hex) {
+		Some(bytes) if bytes.len() == 32 => {
+			let mut id = [0; 32];
+			id.copy_from_slice(&bytes);
+			Some(id)
+		}
+		_ => None,
+	}
+}
+
+fn read_channel_keys_ids(path: &Path) -> HashSet<[u8; 32]> {
+	let mut ids = HashSet::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return ids,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		match parse_id(&line) {
+			Some(id) => {
+				ids.insert(id);
+			}
+			None => eprintln!("Warning: Skipping malformed channel keys id in {:?}", path),
+		}
+	}
+	ids
+}
+
+/// What we need to know about a channel to get our funds back from it once its state is lost.
+#[derive(Clone)]
+pub(crate) struct BackupChannel {
+	pub(crate) channel_id: [u8; 32],
+	pub(crate) counterparty: PublicKey,
+	/// The address we last connected to the peer at, `host:port`.
+	pub(crate) peer_addr: Option<String>,
+	pub(crate) funding_txo: OutPoint,
+	pub(crate) channel_value_sat: u64,
+}
+
+/// A static channel backup: enough to ask each peer to force-close its channel with us and to
+/// claim what the close pays us, but not to keep using the channels. Since the keys ids we have
+/// can't be matched to channels, all of them are backed up.
+pub(crate) struct ChannelBackup {
+	pub(crate) channels: Vec<BackupChannel>,
+	pub(crate) channel_keys_ids: Vec<[u8; 32]>,
+}
+
+/// Backups are encrypted under a key derived from the node secret, so the seed alone is enough to
+/// read one.
+fn backup_key(node_secret: &SecretKey) -> LessSafeKey {
+	let mut engine = Sha256::engine();
+	engine.input(KEY_TAG);
+	engine.input(&node_secret[..]);
+	let key = Sha256::from_engine(engine).into_inner();
+	LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+}
+
+/// The backup's plaintext is one `channel <channel_id> <pubkey> <host:port|-> <txid>:<index>
+/// <value_sat>` line per channel and one `keys <channel_keys_id>` line per keys id.
+fn encode_backup(backup: &ChannelBackup) -> String {
+	let mut out = String::new();
+	for channel in backup.channels.iter() {
+		out.push_str(&format!(
+			"channel {} {} {} {}:{} {}\n",
+			hex_utils::hex_str(&channel.channel_id),
+			channel.counterparty,
+			channel.peer_addr.as_deref().unwrap_or("-"),
+			channel.funding_txo.txid,
+			channel.funding_txo.index,
+			channel.channel_value_sat
+		));
+	}
+	for channel_keys_id in backup.channel_keys_ids.iter() {
+		out.push_str(&format!("keys {}\n", hex_utils::hex_str(channel_keys_id)));
+	}
+	out
+}
+
+fn parse_channel(parts: &[&str]) -> Option<BackupChannel> {
+	match parts {
+		[channel_id, counterparty, peer_addr, funding_txo, channel_value_sat] => {
+			let mut funding_txo = funding_txo.split(':');
+			let funding_txo = OutPoint {
+				txid: Txid::from_str(funding_txo.next()?).ok()?,
+				index: funding_txo.next()?.parse().ok()?,
+			};
+			Some(BackupChannel {
+				channel_id: parse_id(channel_id)?,
+				counterparty: hex_utils::to_compressed_pubkey(counterparty)?,
+				peer_addr: if *peer_addr == "-" { None } else { Some(peer_addr.to_string()) },
+				funding_txo,
+				channel_value_sat: channel_value_sat.parse().ok()?,
+			})
+		}
+		_ => None,
+	}
+}
+
+fn decode_backup(plaintext: &str) -> Result<ChannelBackup, String> {
+	let mut backup = ChannelBackup { channels: Vec::new(), channel_keys_ids: Vec::new() };
+	for line in plaintext.lines() {
+		let parts: Vec<&str> = line.split(' ').collect();
+		match parts.as_slice() {
+			["channel", rest @ ..] => backup
+				.channels
+				.push(parse_channel(rest).ok_or_else(|| "the backup is malformed".to_string())?),
+			["keys", channel_keys_id] => backup.channel_keys_ids.push(
+				parse_id(channel_keys_id).ok_or_else(|| "the backup is malformed".to_string())?,
+			),
+			_ => return Err("the backup is malformed".to_string()),
+		}
+	}
+	Ok(backup)
+}
+
+/// Reads the address we last opened a channel to each peer at from the channel peer data channel
+/// opens append them to, keeping them as given so that onion addresses stay unresolved.
+fn read_peer_addrs(storage: &dyn Storage) -> HashMap<PublicKey, String> {
+	let mut peer_addrs = HashMap::new();
+	for line in disk::read_channel_peer_lines(storage).unwrap_or_default() {
+		let mut pubkey_and_addr = line.splitn(2, '@');
+		if let (Some(pubkey), Some(addr)) = (pubkey_and_addr.next(), pubkey_and_addr.next()) {
+			if let Some(pubkey) = hex_utils::to_compressed_pubkey(pubkey) {
+				peer_addrs.insert(pubkey, addr.to_string());
+			}
+		}
+	}
+	peer_addrs
+}
+
+/// Writes an encrypted backup of our funded channels to `path`, returning how many it holds.
+pub(crate) fn export_backup(
+	path: &Path, channel_manager: &ChannelManager, channel_keys_log: &ChannelKeysLog,
+	storage: &dyn Storage, node_secret: &SecretKey,
+) -> Result<usize, String> {
+	let peer_addrs = read_peer_addrs(storage);
+	let channels: Vec<BackupChannel> = channel_manager
+		.list_channels()
+		.into_iter()
+		.filter_map(|details| {
+			Some(BackupChannel {
+				channel_id: details.channel_id,
+				counterparty: details.counterparty.node_id,
+				peer_addr: peer_addrs.get(&details.counterparty.node_id).cloned(),
+				funding_txo: details.funding_txo?,
+				channel_value_sat: details.channel_value_satoshis,
+			})
+		})
+		.collect();
+	let backup = ChannelBackup { channels, channel_keys_ids: channel_keys_log.ids() };
+
+	let mut nonce = [0; NONCE_LEN];
+	thread_rng().fill_bytes(&mut nonce);
+	let mut file = MAGIC.to_vec();
+	file.extend_from_slice(&nonce);
+	let mut ciphertext = encode_backup(&backup).into_bytes();
+	backup_key(node_secret)
+		.seal_in_place_append_tag(
+			Nonce::assume_unique_for_key(nonce),
+			Aad::from(&MAGIC[..]),
+			&mut ciphertext,
+		)
+		.unwrap();
+	file.extend_from_slice(&ciphertext);
+
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	fs::write(&tmp_path, &file)
+		.and_then(|_| fs::rename(&tmp_path, path))
+		.map_err(|e| format!("failed to write {:?}: {}", path, e))?;
+	Ok(backup.channels.len())
+}
+
+/// Reads and decrypts the backup at `path`, which only the node it was exported from can do.
+pub(crate) fn read_backup(path: &str, node_secret: &SecretKey) -> Result<ChannelBackup, String> {
+	let file = fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+	if file.len() < MAGIC.len() + NONCE_LEN || !file.starts_with(MAGIC) {
+		return Err(format!("{} is not a channel backup", path));
+	}
+	let mut nonce = [0; NONCE_LEN];
+	nonce.copy_from_slice(&file[MAGIC.len()..MAGIC.len() + NONCE_LEN]);
+	let mut ciphertext = file[MAGIC.len() + NONCE_LEN..].to_vec();
+	let plaintext = backup_key(node_secret)
+		.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(&MAGIC[..]), &mut ciphertext)
+		.map_err(|_| format!("{} wasn't exported by this node, or is corrupted", path))?;
+	let plaintext = std::str::from_utf8(plaintext)
+		.map_err(|_| "the backup is malformed".to_string())?
+		.to_string();
+	decode_backup(&plaintext)
+}
+
+struct RecoveryState {
+	/// The funding outputs of the channels we've seen closed.
+	closed: HashSet<OutPoint>,
+	/// Outputs closes paid us which haven't been handed to the sweeper yet.
+	found: Vec<SpendableOutputDescriptor>,
+}
+
+/// Recovers the funds in channels we lost the state of. We can't publish our own commitment
+/// transactions without it, so instead we ask each peer to force-close, which pays our balance to
+/// a key of ours straight away, as all our channels use `option_static_remotekey`.
+///
+/// Closes are noticed in the blocks we sync once the node is running, so a channel which was
+/// closed earlier isn't recovered.
+pub(crate) struct ChannelRecovery {
+	channels: Vec<BackupChannel>,
+	/// The script the peer's commitment transactions pay our balance to, for each backed up keys
+	/// id, as we don't know which belongs to which channel.
+	our_scripts: HashMap<Script, [u8; 32]>,
+	state: Mutex<RecoveryState>,
+}
+
+impl ChannelRecovery {
+	/// Sets up recovery of the backed up channels which the channel manager no longer has.
+	pub(crate) fn new(
+		backup: ChannelBackup, keys_manager: &KeysManager, channel_manager: &ChannelManager,
+	) -> Self {
+		let open: HashSet<[u8; 32]> =
+			channel_manager.list_channels().iter().map(|details| details.channel_id).collect();
+		let channels = backup
+			.channels
+			.into_iter()
+			.filter(|channel| !open.contains(&channel.channel_id))
+			.collect();
+		let our_scripts = backup
+			.channel_keys_ids
+			.iter()
+			.map(|channel_keys_id| {
+				// The channel value doesn't go into the keys, so any will do.
+				let signer = keys_manager.derive_channel_keys(0, channel_keys_id);
+				let payment_point = signer.pubkeys().payment_point;
+				let script =
+					Script::new_v0_wpkh(&WPubkeyHash::hash(&payment_point.serialize()[..]));
+				(script, *channel_keys_id)
+			})
+			.collect();
+		let state = Mutex::new(RecoveryState { closed: HashSet::new(), found: Vec::new() });
+		Self { channels, our_scripts, state }
+	}
+
+	/// The channels being recovered which we haven't seen closed yet.
+	fn open_channels(&self) -> Vec<BackupChannel> {
+		let state = self.state.lock().unwrap();
+		self.channels
+			.iter()
+			.filter(|channel| !state.closed.contains(&channel.funding_txo))
+			.cloned()
+			.collect()
+	}
+}
+
+impl chain::Listen for ChannelRecovery {
+	fn block_connected(&self, block: &Block, _height: u32) {
+		let mut state = self.state.lock().unwrap();
+		for tx in block.txdata.iter() {
+			let channel = self.channels.iter().find(|channel| {
+				tx.input.iter().any(|input| {
+					input.previous_output.txid == channel.funding_txo.txid
+						&& input.previous_output.vout == channel.funding_txo.index as u32
+				})
+			});
+			let channel = match channel {
+				Some(channel) => channel,
+				None => continue,
+			};
+			if !state.closed.insert(channel.funding_txo) {
+				continue;
+			}
+			println!(
+				"\nEVENT: Recovered channel {} was closed by {}",
+				hex_utils::hex_str(&channel.channel_id),
+				tx.txid()
+			);
+			let txid = tx.txid();
+			for (vout, output) in tx.output.iter().enumerate() {
+				if let Some(channel_keys_id) = self.our_scripts.get(&output.script_pubkey) {
+					state.found.push(SpendableOutputDescriptor::StaticPaymentOutput(
+						StaticPaymentOutputDescriptor {
+							outpoint: OutPoint { txid, index: vout as u16 },
+							output: output.clone(),
+							channel_keys_id: *channel_keys_id,
+							channel_value_satoshis: channel.channel_value_sat,
+						},
+					));
+				}
+			}
+		}
+	}
+
+	// A close which gets reorged out is confirmed again soon enough, and its output stays ours.
+	fn block_disconnected(&self, _header: &BlockHeader, _height: u32) {}
+}
+
+/// Regularly asks the peers of the channels being recovered to force-close them, connecting to
+/// each at the address it was backed up with, and sweeps what their closes pay us, until every
+/// channel is closed.
+pub(crate) fn start_recovery(
+	recovery: Arc<ChannelRecovery>, peer_manager: Arc<PeerManager>, wire_capture: Arc<WireCapture>,
+	output_sweeper: Arc<OutputSweeper>, proxy_addr: Option<SocketAddr>,
+) {
+	println!("Recovering {} channels from the backup", recovery.channels.len());
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(RECOVERY_INTERVAL);
+		loop {
+			interval.tick().await;
+			let open = recovery.open_channels();
+			let found = std::mem::take(&mut recovery.state.lock().unwrap().found);
+			if !found.is_empty() {
+				output_sweeper.track_outputs(&found);
+				OutputSweeper::outputs_added(&output_sweeper).await;
+			}
+			if open.is_empty() {
+				println!("\nEVENT: All backed up channels are closed, recovery is complete");
+				return;
+			}
+			for channel in open {
+				let peer_addr = match &channel.peer_addr {
+					Some(peer_addr) => peer_addr,
+					None => continue,
+				};
+				let (pubkey, peer_addr) =
+					match cli::parse_peer_info(format!("{}@{}", channel.counterparty, peer_addr)) {
+						Ok(info) => info,
+						Err(_) => continue,
+					};
+				if cli::connect_peer_if_necessary(
+					pubkey,
+					peer_addr,
+					peer_manager.clone(),
+					proxy_addr,
+				)
+				.await
+				.is_ok()
+				{
+					wire_capture.send_error(
+						pubkey,
+						channel.channel_id,
+						"Lost channel state, please force-close".to_string(),
+					);
+					peer_manager.process_events();
+				}
+			}
+		}
+	});
+}
@@ -0,0 +1,478 @@
+use crate::balances;
+use crate::hex_utils;
+use crate::{time_now_secs, ChannelManager};
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin::hash_types::Txid;
+use bitcoin::secp256k1::key::PublicKey;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often we check our channels for changes worth recording.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ChannelEventKind {
+	/// We first saw the channel, while its opening was being negotiated or just after.
+	Negotiated,
+	/// The channel was given its final id once funded. Its events under its temporary id are
+	/// carried over.
+	Renamed,
+	Funded,
+	/// Both sides consider the funding transaction confirmed deeply enough to use the channel.
+	Confirmed,
+	/// Our balance in the channel changed, e.g. as payments were sent, received or forwarded.
+	BalanceUpdate,
+	/// The channel stopped being usable, because the peer disconnected or the channel is closing.
+	Disconnected,
+	Reconnected,
+	Closed,
+	/// We broadcast a transaction spending the channel's funding output.
+	ClosingTxBroadcast,
+	/// We broadcast a transaction sweeping outputs of the channel's closing transaction.
+	Swept,
+}
+
+impl ChannelEventKind {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			ChannelEventKind::Negotiated => "negotiated",
+			ChannelEventKind::Renamed => "renamed",
+			ChannelEventKind::Funded => "funded",
+			ChannelEventKind::Confirmed => "confirmed",
+			ChannelEventKind::BalanceUpdate => "balance_update",
+			ChannelEventKind::Disconnected => "disconnected",
+			ChannelEventKind::Reconnected => "reconnected",
+			ChannelEventKind::Closed => "closed",
+			ChannelEventKind::ClosingTxBroadcast => "closing_tx_broadcast",
+			ChannelEventKind::Swept => "swept",
+		}
+	}
+
+	fn from_str(kind: &str) -> Option<Self> {
+		match kind {
+			"negotiated" => Some(ChannelEventKind::Negotiated),
+			"renamed" => Some(ChannelEventKind::Renamed),
+			"funded" => Some(ChannelEventKind::Funded),
+			"confirmed" => Some(ChannelEventKind::Confirmed),
+			"balance_update" => Some(ChannelEventKind::BalanceUpdate),
+			"disconnected" => Some(ChannelEventKind::Disconnected),
+			"reconnected" => Some(ChannelEventKind::Reconnected),
+			"closed" => Some(ChannelEventKind::Closed),
+			"closing_tx_broadcast" => Some(ChannelEventKind::ClosingTxBroadcast),
+			"swept" => Some(ChannelEventKind::Swept),
+			_ => None,
+		}
+	}
+}
+
+pub(crate) struct ChannelEvent {
+	/// When the event happened, or when we noticed it, in seconds since the UNIX epoch.
+	pub(crate) timestamp: u64,
+	pub(crate) kind: ChannelEventKind,
+	pub(crate) detail: String,
+}
+
+#[derive(Default)]
+struct Timelines {
+	events: HashMap<[u8; 32], Vec<ChannelEvent>>,
+	/// Lets us recognize transactions spending a channel's funding output.
+	funding_outpoints: HashMap<OutPoint, [u8; 32]>,
+	/// Lets us recognize transactions sweeping a channel's closing transaction.
+	closing_txids: HashMap<Txid, [u8; 32]>,
+}
+
+impl Timelines {
+	/// Adds an event, updating the indexes of our funding outpoints and closing transactions.
+	fn add(&mut self, channel_id: [u8; 32], event: ChannelEvent) {
+		match event.kind {
+			ChannelEventKind::Renamed => {
+				// Events under the temporary id belong to the channel's final id.
+				let temporary_channel_id = hex_utils::to_vec(&event.detail);
+				if let Some(temporary_channel_id) = temporary_channel_id.filter(|id| id.len() == 32)
+				{
+					let mut old_id = [0; 32];
+					old_id.copy_from_slice(&temporary_channel_id);
+					if let Some(old_events) = self.events.remove(&old_id) {
+						let events = self.events.entry(channel_id).or_default();
+						let mut moved = old_events;
+						moved.append(events);
+						*events = moved;
+					}
+				}
+			}
+			ChannelEventKind::Funded => {
+				if let Some(outpoint) = parse_outpoint(&event.detail) {
+					self.funding_outpoints.insert(outpoint, channel_id);
+				}
+			}
+			ChannelEventKind::ClosingTxBroadcast => {
+				if let Ok(txid) = Txid::from_str(&event.detail) {
+					self.closing_txids.insert(txid, channel_id);
+				}
+			}
+			_ => {}
+		}
+		self.events.entry(channel_id).or_default().push(event);
+	}
+
+	fn has_event(&self, channel_id: &[u8; 32], kind: ChannelEventKind) -> bool {
+		self.events.get(channel_id).map_or(false, |events| events.iter().any(|e| e.kind == kind))
+	}
+}
+
+/// A channel we opened and funded, as told by its timeline.
+pub(crate) struct ChannelOpen {
+	pub(crate) channel_id: [u8; 32],
+	/// When we noticed the channel was funded, in seconds since the UNIX epoch.
+	pub(crate) funded_at: u64,
+	pub(crate) funding_txo: OutPoint,
+	pub(crate) channel_value_sat: u64,
+}
+
+impl ChannelOpen {
+	fn from_timeline(channel_id: [u8; 32], events: &[ChannelEvent]) -> Option<Self> {
+		let negotiated = events.iter().find(|e| e.kind == ChannelEventKind::Negotiated)?;
+		// Matches the detail we record for outbound channels: "outbound channel of <value> sat
+		// with <counterparty>".
+		let channel_value_sat = negotiated
+			.detail
+			.strip_prefix("outbound channel of ")?
+			.split(' ')
+			.next()?
+			.parse()
+			.ok()?;
+		let funded = events.iter().find(|e| e.kind == ChannelEventKind::Funded)?;
+		let funding_txo = parse_outpoint(&funded.detail)?;
+		Some(Self { channel_id, funded_at: funded.timestamp, funding_txo, channel_value_sat })
+	}
+}
+
+/// The ways of identifying a channel which its timeline tells us, as far as it got.
+pub(crate) struct ChannelIdentifiers {
+	pub(crate) channel_id: [u8; 32],
+	pub(crate) counterparty: Option<PublicKey>,
+	pub(crate) funding_txo: Option<OutPoint>,
+	pub(crate) short_channel_id: Option<u64>,
+	pub(crate) closed: bool,
+}
+
+impl ChannelIdentifiers {
+	fn from_timeline(channel_id: [u8; 32], events: &[ChannelEvent]) -> Self {
+		let detail = |kind| events.iter().find(|e| e.kind == kind).map(|e| e.detail.as_str());
+		Self {
+			channel_id,
+			// Matches the detail we record on negotiation: "... with <counterparty>".
+			counterparty: detail(ChannelEventKind::Negotiated)
+				.and_then(|detail| detail.rsplit(' ').next())
+				.and_then(|pubkey| PublicKey::from_str(pubkey).ok()),
+			funding_txo: detail(ChannelEventKind::Funded).and_then(parse_outpoint),
+			// Matches the detail we record on confirmation: "short channel id <scid>".
+			short_channel_id: detail(ChannelEventKind::Confirmed)
+				.and_then(|detail| detail.strip_prefix("short channel id "))
+				.and_then(|scid| scid.parse().ok()),
+			closed: detail(ChannelEventKind::Closed).is_some(),
+		}
+	}
+}
+
+fn parse_outpoint(outpoint: &str) -> Option<OutPoint> {
+	let mut parts = outpoint.splitn(2, ':');
+	let txid = Txid::from_str(parts.next()?).ok()?;
+	let vout = parts.next()?.parse().ok()?;
+	Some(OutPoint { txid, vout })
+}
+
+/// A timeline of each channel's lifecycle, from negotiation through to its funds being swept
+/// after it closed, for post-mortems of channels which misbehaved. Events are appended to a log on
+/// disk as they happen and kept even once a channel is gone.
+pub(crate) struct ChannelHistory {
+	path: String,
+	timelines: Mutex<Timelines>,
+}
+
+impl ChannelHistory {
+	pub(crate) fn new(path: String) -> Self {
+		let timelines = Mutex::new(read_history(Path::new(&path)));
+		Self { path, timelines }
+	}
+
+	pub(crate) fn record(&self, channel_id: [u8; 32], kind: ChannelEventKind, detail: String) {
+		let event = ChannelEvent { timestamp: time_now_secs(), kind, detail };
+		if append_event(Path::new(&self.path), &channel_id, &event).is_err() {
+			eprintln!(
+				"Warning: Failed to persist channel history, check your disk and permissions"
+			);
+		}
+		self.timelines.lock().unwrap().add(channel_id, event);
+	}
+
+	/// Records an event only if the channel doesn't have one of its kind yet.
+	fn record_once(&self, channel_id: [u8; 32], kind: ChannelEventKind, detail: String) {
+		if !self.timelines.lock().unwrap().has_event(&channel_id, kind) {
+			self.record(channel_id, kind, detail);
+		}
+	}
+
+	/// Calls `f` with the channel's events, oldest first, if we know of the channel.
+	pub(crate) fn with_timeline<F: FnOnce(&[ChannelEvent])>(
+		&self, channel_id: &[u8; 32], f: F,
+	) -> bool {
+		match self.timelines.lock().unwrap().events.get(channel_id) {
+			Some(events) => {
+				f(events);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// The channels we opened which got funded, as inbound channels are funded by the peer.
+	pub(crate) fn channel_opens(&self) -> Vec<ChannelOpen> {
+		let timelines = self.timelines.lock().unwrap();
+		timelines
+			.events
+			.iter()
+			.filter_map(|(channel_id, events)| ChannelOpen::from_timeline(*channel_id, events))
+			.collect()
+	}
+
+	/// How to identify each channel we have a timeline for, including channels long closed.
+	pub(crate) fn channel_identifiers(&self) -> Vec<ChannelIdentifiers> {
+		let timelines = self.timelines.lock().unwrap();
+		timelines
+			.events
+			.iter()
+			.map(|(channel_id, events)| ChannelIdentifiers::from_timeline(*channel_id, events))
+			.collect()
+	}
+
+	/// Whether `tx` spends the funding output of one of our channels.
+	pub(crate) fn spends_funding_output(&self, tx: &Transaction) -> bool {
+		let timelines = self.timelines.lock().unwrap();
+		tx.input
+			.iter()
+			.any(|input| timelines.funding_outpoints.contains_key(&input.previous_output))
+	}
+
+	/// Records transactions we broadcast which close one of our channels or sweep the outputs of
+	/// a closing transaction. Closing transactions broadcast by our counterparties aren't seen
+	/// here, so sweeps of their outputs aren't recorded either.
+	pub(crate) fn transaction_broadcast(&self, tx: &Transaction) {
+		let txid = tx.txid();
+		let (closed, swept) = {
+			let timelines = self.timelines.lock().unwrap();
+			let closed = tx
+				.input
+				.iter()
+				.find_map(|input| timelines.funding_outpoints.get(&input.previous_output))
+				.copied();
+			let mut swept: Vec<[u8; 32]> = tx
+				.input
+				.iter()
+				.filter_map(|input| timelines.closing_txids.get(&input.previous_output.txid))
+				.copied()
+				.collect();
+			swept.sort_unstable();
+			swept.dedup();
+			(closed, swept)
+		};
+		if let Some(channel_id) = closed {
+			// LDK rebroadcasts closing transactions until they confirm.
+			let already_recorded = self.timelines.lock().unwrap().closing_txids.contains_key(&txid);
+			if !already_recorded {
+				self.record(channel_id, ChannelEventKind::ClosingTxBroadcast, txid.to_string());
+			}
+		}
+		for channel_id in swept {
+			let already_recorded = self.timelines.lock().unwrap().events[&channel_id]
+				.iter()
+				.any(|e| e.kind == ChannelEventKind::Swept && e.detail == txid.to_string());
+			if !already_recorded {
+				self.record(channel_id, ChannelEventKind::Swept, txid.to_string());
+			}
+		}
+	}
+
+	/// Regularly compares our channels against what we last saw of them, recording how they
+	/// changed. LDK has no events for most of these changes, so they're timestamped to within a
+	/// few seconds.
+	pub(crate) fn start_tracking(history: Arc<Self>, channel_manager: Arc<ChannelManager>) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+			let mut last_seen: HashMap<[u8; 32], ChannelState> = HashMap::new();
+			loop {
+				interval.tick().await;
+				let channels = channel_manager.list_channels();
+				let mut seen = HashMap::with_capacity(channels.len());
+				for chan_info in channels.iter() {
+					let state = ChannelState {
+						counterparty: chan_info.counterparty.node_id,
+						user_channel_id: chan_info.user_channel_id,
+						funded: chan_info.funding_txo.is_some(),
+						is_usable: chan_info.is_usable,
+						local_balance_msat: balances::channel_local_balance_msat(chan_info),
+					};
+					let channel_id = chan_info.channel_id;
+					let previous = last_seen.get(&channel_id);
+
+					// An outbound channel is renamed once funded. Its temporary id disappears at
+					// the same time as its final id appears.
+					if previous.is_none() && state.funded && chan_info.is_outbound {
+						let temporary_channel_id = last_seen.iter().find_map(|(id, old)| {
+							let renamed = !old.funded
+								&& old.counterparty == state.counterparty
+								&& old.user_channel_id == state.user_channel_id
+								&& !channels.iter().any(|c| c.channel_id == *id);
+							if renamed {
+								Some(*id)
+							} else {
+								None
+							}
+						});
+						if let Some(temporary_channel_id) = temporary_channel_id {
+							history.record(
+								channel_id,
+								ChannelEventKind::Renamed,
+								hex_utils::hex_str(&temporary_channel_id),
+							);
+						}
+					}
+					history.record_once(
+						channel_id,
+						ChannelEventKind::Negotiated,
+						format!(
+							"{} channel of {} sat with {}",
+							if chan_info.is_outbound { "outbound" } else { "inbound" },
+							chan_info.channel_value_satoshis,
+							chan_info.counterparty.node_id
+						),
+					);
+					if let Some(funding_txo) = chan_info.funding_txo {
+						history.record_once(
+							channel_id,
+							ChannelEventKind::Funded,
+							format!("{}:{}", funding_txo.txid, funding_txo.index),
+						);
+					}
+					if chan_info.is_funding_locked {
+						let short_channel_id = chan_info
+							.short_channel_id
+							.map_or("unknown".to_string(), |scid| scid.to_string());
+						history.record_once(
+							channel_id,
+							ChannelEventKind::Confirmed,
+							format!("short channel id {}", short_channel_id),
+						);
+					}
+					// Whether the channel is usable or how much of it is ours only changes once
+					// we've seen it before, which keeps restarts from looking like changes.
+					if let Some(previous) = previous {
+						if previous.is_usable && !state.is_usable {
+							history.record(
+								channel_id,
+								ChannelEventKind::Disconnected,
+								String::new(),
+							);
+						} else if !previous.is_usable
+							&& state.is_usable && chan_info.is_funding_locked
+						{
+							history.record(
+								channel_id,
+								ChannelEventKind::Reconnected,
+								String::new(),
+							);
+						}
+						if previous.local_balance_msat != state.local_balance_msat {
+							history.record(
+								channel_id,
+								ChannelEventKind::BalanceUpdate,
+								format!(
+									"our balance went from {} to {} msat",
+									previous.local_balance_msat, state.local_balance_msat
+								),
+							);
+						}
+					}
+					seen.insert(channel_id, state);
+				}
+				last_seen = seen;
+			}
+		});
+	}
+}
+
+struct ChannelState {
+	counterparty: PublicKey,
+	user_channel_id: u64,
+	funded: bool,
+	is_usable: bool,
+	local_balance_msat: u64,
+}
+
+/// Each event is stored as a line of the form `<channel_id> <timestamp> <kind> <detail>`, where the
+/// detail is hex-encoded, or `-` if empty.
+fn append_event(path: &Path, channel_id: &[u8; 32], event: &ChannelEvent) -> std::io::Result<()> {
+	let detail = match event.detail.is_empty() {
+		true => "-".to_string(),
+		false => hex_utils::hex_str(event.detail.as_bytes()),
+	};
+	let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+	file.write_all(
+		format!(
+			"{} {} {} {}\n",
+			hex_utils::hex_str(channel_id),
+			event.timestamp,
+			event.kind.as_str(),
+			detail
+		)
+		.as_bytes(),
+	)?;
+	file.sync_all()
+}
+
+fn read_history(path: &Path) -> Timelines {
+	let mut timelines = Timelines::default();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return timelines,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		let parts: Vec<&str> = line.split(' ').collect();
+		if parts.len() != 4 {
+			eprintln!("Warning: Skipping malformed channel history entry in {:?}", path);
+			continue;
+		}
+		let channel_id = match hex_utils::to_vec(parts[0]) {
+			Some(id) if id.len() == 32 => {
+				let mut channel_id = [0; 32];
+				channel_id.copy_from_slice(&id);
+				channel_id
+			}
+			_ => {
+				eprintln!("Warning: Skipping malformed channel history entry in {:?}", path);
+				continue;
+			}
+		};
+		let detail = match parts[3] {
+			"-" => Some(String::new()),
+			detail => hex_utils::to_vec(detail).and_then(|d| String::from_utf8(d).ok()),
+		};
+		match (parts[1].parse(), ChannelEventKind::from_str(parts[2]), detail) {
+			(Ok(timestamp), Some(kind), Some(detail)) => {
+				timelines.add(channel_id, ChannelEvent { timestamp, kind, detail })
+			}
+			_ => eprintln!("Warning: Skipping malformed channel history entry in {:?}", path),
+		}
+	}
+	timelines
+}
@@ -0,0 +1,154 @@
+use crate::channel_history::ChannelHistory;
+use crate::hex_utils;
+use crate::ChannelManager;
+use bitcoin::blockdata::transaction::OutPoint;
+use bitcoin::hash_types::Txid;
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::chain::transaction::OutPoint as LdkOutPoint;
+use lightning::routing::network_graph::NetworkGraph;
+use std::str::FromStr;
+
+/// A channel as given by the user, in whichever form they have it.
+pub(crate) enum ChannelQuery {
+	/// Either as a number or as `<block>x<tx>x<output>`.
+	ShortChannelId(u64),
+	FundingOutpoint(OutPoint),
+	ChannelId([u8; 32]),
+}
+
+impl ChannelQuery {
+	pub(crate) fn parse(query: &str) -> Result<Self, String> {
+		if let Ok(scid) = query.parse::<u64>() {
+			return Ok(ChannelQuery::ShortChannelId(scid));
+		}
+		let parts: Vec<&str> = query.split('x').collect();
+		if parts.len() == 3 {
+			let block: u64 = parts[0].parse().map_err(|_| "invalid block height")?;
+			let tx_index: u64 = parts[1].parse().map_err(|_| "invalid transaction index")?;
+			let vout: u64 = parts[2].parse().map_err(|_| "invalid output index")?;
+			if block >= 1 << 24 || tx_index >= 1 << 24 || vout >= 1 << 16 {
+				return Err("short channel id out of range".to_string());
+			}
+			return Ok(ChannelQuery::ShortChannelId(block << 40 | tx_index << 16 | vout));
+		}
+		let parts: Vec<&str> = query.splitn(2, ':').collect();
+		if parts.len() == 2 {
+			let txid = Txid::from_str(parts[0]).map_err(|_| "invalid txid")?;
+			let vout = parts[1].parse().map_err(|_| "invalid output index")?;
+			return Ok(ChannelQuery::FundingOutpoint(OutPoint { txid, vout }));
+		}
+		match hex_utils::to_vec(query).filter(|id| id.len() == 32) {
+			Some(id) => {
+				let mut channel_id = [0; 32];
+				channel_id.copy_from_slice(&id);
+				Ok(ChannelQuery::ChannelId(channel_id))
+			}
+			None => {
+				Err("expected a short channel id, a funding outpoint <txid>:<vout> or a channel id"
+					.to_string())
+			}
+		}
+	}
+}
+
+/// Formats a short channel id as `<block>x<tx>x<output>`, the form block explorers use.
+pub(crate) fn format_scid(scid: u64) -> String {
+	format!("{}x{}x{}", scid >> 40, (scid >> 16) & 0xff_ffff, scid & 0xffff)
+}
+
+/// Everything we know about a channel, from our open channels, the timelines of our past ones and
+/// the network graph.
+#[derive(Default)]
+pub(crate) struct ResolvedChannel {
+	pub(crate) channel_id: Option<[u8; 32]>,
+	pub(crate) funding_txo: Option<OutPoint>,
+	pub(crate) short_channel_id: Option<u64>,
+	/// Our counterparty, for our own channels.
+	pub(crate) counterparty: Option<PublicKey>,
+	/// Both ends of the channel as announced, for public channels.
+	pub(crate) announced_nodes: Option<(String, String)>,
+	pub(crate) capacity_sat: Option<u64>,
+	pub(crate) status: &'static str,
+}
+
+fn channel_id_of(funding_txo: &OutPoint) -> [u8; 32] {
+	LdkOutPoint { txid: funding_txo.txid, index: funding_txo.vout as u16 }.to_channel_id()
+}
+
+/// Looks a channel up by any of its identifiers, filling in the others from wherever we know them.
+/// Channels which aren't ours can only be found by their short channel id, as announcements
+/// don't carry funding outpoints.
+pub(crate) fn resolve(
+	query: &ChannelQuery, channel_manager: &ChannelManager, network_graph: &NetworkGraph,
+	channel_history: &ChannelHistory,
+) -> Option<ResolvedChannel> {
+	let mut resolved = ResolvedChannel::default();
+	match query {
+		ChannelQuery::ShortChannelId(scid) => resolved.short_channel_id = Some(*scid),
+		ChannelQuery::FundingOutpoint(outpoint) => {
+			resolved.funding_txo = Some(*outpoint);
+			resolved.channel_id = Some(channel_id_of(outpoint));
+		}
+		ChannelQuery::ChannelId(channel_id) => resolved.channel_id = Some(*channel_id),
+	}
+
+	let query_channel_id = resolved.channel_id;
+	let matches = |channel_id: &[u8; 32], scid: Option<u64>| match query {
+		ChannelQuery::ShortChannelId(query_scid) => scid == Some(*query_scid),
+		_ => query_channel_id.as_ref() == Some(channel_id),
+	};
+	let mut found = false;
+	if let Some(chan_info) = channel_manager
+		.list_channels()
+		.into_iter()
+		.find(|chan_info| matches(&chan_info.channel_id, chan_info.short_channel_id))
+	{
+		found = true;
+		resolved.channel_id = Some(chan_info.channel_id);
+		resolved.funding_txo = chan_info
+			.funding_txo
+			.map(|txo| OutPoint { txid: txo.txid, vout: txo.index as u32 })
+			.or(resolved.funding_txo);
+		resolved.short_channel_id = chan_info.short_channel_id.or(resolved.short_channel_id);
+		resolved.counterparty = Some(chan_info.counterparty.node_id);
+		resolved.capacity_sat = Some(chan_info.channel_value_satoshis);
+		resolved.status = if chan_info.is_usable {
+			"open, usable"
+		} else if chan_info.is_funding_locked {
+			"open, peer offline or channel closing"
+		} else {
+			"awaiting funding confirmation"
+		};
+	} else if let Some(ids) = channel_history
+		.channel_identifiers()
+		.into_iter()
+		.find(|ids| matches(&ids.channel_id, ids.short_channel_id))
+	{
+		found = true;
+		resolved.channel_id = Some(ids.channel_id);
+		resolved.funding_txo = ids.funding_txo.or(resolved.funding_txo);
+		resolved.short_channel_id = ids.short_channel_id.or(resolved.short_channel_id);
+		resolved.counterparty = ids.counterparty;
+		resolved.status = if ids.closed { "closed" } else { "no longer open" };
+	}
+
+	if let Some(scid) = resolved.short_channel_id {
+		let graph = network_graph.read_only();
+		if let Some(chan) = graph.channels().get(&scid) {
+			resolved.announced_nodes = Some((
+				hex_utils::hex_str(chan.node_one.as_slice()),
+				hex_utils::hex_str(chan.node_two.as_slice()),
+			));
+			resolved.capacity_sat = resolved.capacity_sat.or(chan.capacity_sats);
+			if !found {
+				found = true;
+				resolved.status = "public channel of other nodes";
+			}
+		}
+	}
+	if found {
+		Some(resolved)
+	} else {
+		None
+	}
+}
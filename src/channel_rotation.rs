@@ -0,0 +1,244 @@
+use crate::cli;
+use crate::hex_utils;
+use crate::pause::PauseSwitch;
+use crate::peer_limits::PeerLimitsStorage;
+use crate::wal_persister::WalPersister;
+use crate::{ChannelManager, PeerManager};
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::ln::channelmanager::ChannelDetails;
+use lightning::util::config::UserConfig;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How many commitment updates a channel may take before we warn about it, unless configured.
+pub(crate) const DEFAULT_MAX_COMMITMENT_UPDATES: u64 = 1_000_000;
+
+/// We start warning once a channel is this far towards its limit, in percent.
+const WARN_AT_PERCENT: u64 = 90;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+pub(crate) struct RotationLimits {
+	pub(crate) max_updates: u64,
+	/// Whether outbound channels which reach `max_updates` are closed and reopened.
+	pub(crate) rotate: bool,
+}
+
+/// Keeps an eye on how many commitment updates each channel has taken over its lifetime. Each
+/// revoked commitment with HTLCs on it leaves data in the channel's monitor for good, so a busy
+/// channel's monitor keeps growing, slowing down every update persisted. Channels nearing the
+/// limit are warned about, and if enabled, our outbound ones are cooperatively closed and, once
+/// closed, reopened with the same peer and capacity once we're connected to the peer.
+///
+/// Channels being rotated are persisted next to the monitor WAL, so one which closes across a
+/// restart is still reopened. Nothing is closed or reopened while the node is paused.
+pub(crate) struct ChannelRotation {
+	limits: RotationLimits,
+	persister: Arc<WalPersister>,
+	warned: Mutex<HashSet<[u8; 32]>>,
+	/// Channels we're closing to reopen, with the peer, capacity and announcement to reopen them
+	/// with.
+	rotating: Mutex<HashMap<[u8; 32], (PublicKey, u64, bool)>>,
+	path: String,
+}
+
+impl ChannelRotation {
+	pub(crate) fn new(
+		limits: RotationLimits, persister: Arc<WalPersister>, ldk_data_dir: &str,
+	) -> Self {
+		let path = format!("{}/rotating_channels", ldk_data_dir);
+		let rotating = read_rotating(Path::new(&path));
+		Self {
+			limits,
+			persister,
+			warned: Mutex::new(HashSet::new()),
+			rotating: Mutex::new(rotating),
+			path,
+		}
+	}
+
+	/// How many commitment updates the channel has taken since it was opened.
+	pub(crate) fn update_count(&self, chan_info: &ChannelDetails) -> Option<u64> {
+		chan_info.funding_txo.and_then(|funding_txo| self.persister.update_count(&funding_txo))
+	}
+
+	fn persist(&self, rotating: &HashMap<[u8; 32], (PublicKey, u64, bool)>) {
+		if persist_rotating(Path::new(&self.path), rotating).is_err() {
+			eprintln!(
+				"Warning: Failed to persist the channels being rotated, check your disk and permissions"
+			);
+		}
+	}
+
+	/// Reopens rotated channels which closed, and closes channels which reached the limit, unless
+	/// the node is `paused`. Channels are only reopened once we're connected to their peer, e.g.
+	/// after a restart.
+	fn check(
+		&self, channel_manager: &Arc<ChannelManager>, user_config: UserConfig,
+		peer_limits: &PeerLimitsStorage, connected_peers: &[PublicKey], paused: bool,
+	) {
+		let channels = channel_manager.list_channels();
+		let mut rotating = self.rotating.lock().unwrap();
+		let closed: Vec<[u8; 32]> = rotating
+			.iter()
+			.filter(|(channel_id, (peer, _, _))| {
+				!paused
+					&& !channels.iter().any(|c| c.channel_id == **channel_id)
+					&& connected_peers.contains(peer)
+			})
+			.map(|(channel_id, _)| *channel_id)
+			.collect();
+		for channel_id in closed {
+			let (peer, channel_value_sat, announced) = rotating.remove(&channel_id).unwrap();
+			self.persist(&rotating);
+			println!(
+				"\nEVENT: Reopening rotated channel {} with {}",
+				hex_utils::hex_str(&channel_id),
+				peer
+			);
+			let _ = cli::open_channel(
+				peer,
+				channel_value_sat,
+				announced,
+				user_config,
+				channel_manager.clone(),
+				peer_limits,
+				None,
+			);
+		}
+
+		let warn_at = self.limits.max_updates / 100 * WARN_AT_PERCENT;
+		for chan_info in channels.iter() {
+			let updates = match self.update_count(chan_info) {
+				Some(updates) => updates,
+				None => continue,
+			};
+			let rotatable =
+				self.limits.rotate && chan_info.is_outbound && chan_info.is_usable && !paused;
+			if updates >= self.limits.max_updates && rotatable {
+				if rotating.contains_key(&chan_info.channel_id) {
+					continue;
+				}
+				match channel_manager.close_channel(&chan_info.channel_id) {
+					Ok(()) => {
+						println!(
+							"\nEVENT: Closing channel {} after {} commitment updates, to reopen it",
+							hex_utils::hex_str(&chan_info.channel_id),
+							updates
+						);
+						rotating.insert(
+							chan_info.channel_id,
+							(
+								chan_info.counterparty.node_id,
+								chan_info.channel_value_satoshis,
+								chan_info.is_public,
+							),
+						);
+						self.persist(&rotating);
+					}
+					Err(e) => eprintln!(
+						"Warning: Failed to close channel {} for rotation: {:?}",
+						hex_utils::hex_str(&chan_info.channel_id),
+						e
+					),
+				}
+			} else if updates >= warn_at && self.warned.lock().unwrap().insert(chan_info.channel_id)
+			{
+				eprintln!(
+					"Warning: Channel {} has taken {} commitment updates, nearing the limit of {}. Consider closing and reopening it.",
+					hex_utils::hex_str(&chan_info.channel_id),
+					updates,
+					self.limits.max_updates
+				);
+			}
+		}
+	}
+
+	pub(crate) fn start_checking(
+		rotation: Arc<Self>, channel_manager: Arc<ChannelManager>, peer_manager: Arc<PeerManager>,
+		user_config: UserConfig, peer_limits: PeerLimitsStorage, pause_switch: Arc<PauseSwitch>,
+	) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(CHECK_INTERVAL);
+			loop {
+				interval.tick().await;
+				rotation.check(
+					&channel_manager,
+					user_config,
+					&peer_limits,
+					&peer_manager.get_peer_node_ids(),
+					pause_switch.is_paused(),
+				);
+			}
+		});
+	}
+}
+
+/// Each channel being rotated is stored as a line of the form `<channel_id> <peer_pubkey>
+/// <channel_value_sat> <announced>`, the last being 0 or 1.
+fn persist_rotating(
+	path: &Path, rotating: &HashMap<[u8; 32], (PublicKey, u64, bool)>,
+) -> std::io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for (channel_id, (peer, channel_value_sat, announced)) in rotating.iter() {
+		file.write_all(
+			format!(
+				"{} {} {} {}\n",
+				hex_utils::hex_str(channel_id),
+				peer,
+				channel_value_sat,
+				*announced as u8
+			)
+			.as_bytes(),
+		)?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+fn rotating_from_line(line: &str) -> Option<([u8; 32], (PublicKey, u64, bool))> {
+	let parts: Vec<&str> = line.split(' ').collect();
+	if parts.len() != 4 || parts[1].len() != 66 {
+		return None;
+	}
+	let mut channel_id = [0; 32];
+	let channel_id_bytes = hex_utils::to_vec(parts[0]).filter(|bytes| bytes.len() == 32)?;
+	channel_id.copy_from_slice(&channel_id_bytes);
+	let peer = hex_utils::to_compressed_pubkey(parts[1])?;
+	let announced = match parts[3] {
+		"0" => false,
+		"1" => true,
+		_ => return None,
+	};
+	Some((channel_id, (peer, parts[2].parse().ok()?, announced)))
+}
+
+fn read_rotating(path: &Path) -> HashMap<[u8; 32], (PublicKey, u64, bool)> {
+	let mut rotating = HashMap::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return rotating,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		match rotating_from_line(&line) {
+			Some((channel_id, channel)) => {
+				rotating.insert(channel_id, channel);
+			}
+			None => eprintln!("Warning: Skipping malformed rotating channel entry in {:?}", path),
+		}
+	}
+	rotating
+}
@@ -1,105 +1,745 @@
+use crate::accounting;
+use crate::accounting::{ForwardLog, LedgerFormat};
+use crate::advisor;
+use crate::advisor::Action;
+use crate::balances;
+use crate::bitcoind_client::{
+	default_cookie_path, default_rpc_port, is_local_host, BitcoindClient, FeeFloors, RpcAuth,
+	RpcTransport, WalletRole, DEFAULT_RPC_CONNECT_TIMEOUT, DEFAULT_RPC_TIMEOUT,
+};
+use crate::bundles::BundleStorage;
+use crate::channel_acceptor::AcceptorPolicy;
+use crate::channel_backup;
+use crate::channel_history::ChannelHistory;
+use crate::channel_lookup;
+use crate::channel_lookup::ChannelQuery;
+use crate::channel_rotation::{ChannelRotation, RotationLimits, DEFAULT_MAX_COMMITMENT_UPDATES};
+use crate::close_address::ShutdownKeysManager;
+use crate::config;
+use crate::connection_guard;
+use crate::connection_guard::ConnectionGuard;
+use crate::convert::FinalizedPsbt;
 use crate::disk;
+use crate::disk::{LogFormat, LogRotation, DEFAULT_LOG_KEEP_FILES};
+use crate::disk_space::DiskMonitor;
+use crate::esplora;
+use crate::export;
+use crate::export::{ExportSections, NodeState};
+use crate::external_funding::ExternalFundingStorage;
+use crate::fee_budget::FeeBudget;
+use crate::fee_snapshots::BroadcastPurpose;
 use crate::hex_utils;
+use crate::maintenance::{MaintenanceSchedule, MaintenanceWindow};
+use crate::memory;
+use crate::monitor_replica::MonitorReplica;
+use crate::notifier;
+use crate::notifier::NotifyTarget;
+use crate::open_queue::{OpenQueue, OpenState, DEFAULT_MAX_CONCURRENT_OPENS};
+use crate::path_privacy::{PathPrivacy, PrivacyOverrides};
+use crate::pause::PauseSwitch;
+use crate::payer::{RetryLimits, RouteRecordingPayer};
+use crate::payer_auth;
+use crate::peer_limits::PeerLimitsStorage;
+use crate::receipt;
+use crate::route_exclusions::{ExcludingScorer, RouteExclusionsStorage};
+use crate::rpc_tls;
+use crate::rpc_tls::RpcTls;
+use crate::scheduler::ScheduleStorage;
+use crate::storage::{Storage, StorageBackend};
+use crate::sweeper::OutputSweeper;
+use crate::tor;
+use crate::tor::PeerAddr;
+use crate::uptime;
+use crate::uptime::PeerUptimeStorage;
+use crate::wire_capture::WireCapture;
 use crate::{
-	ChannelManager, FilesystemLogger, HTLCStatus, InvoicePayer, MillisatAmount, PaymentInfo,
-	PaymentInfoStorage, PeerManager,
+	time_now_secs, ChannelManager, FilesystemLogger, HTLCStatus, InvoicePayer, MillisatAmount,
+	PaymentInfo, PaymentInfoStorage, PeerManager,
 };
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::network::constants::Network;
 use bitcoin::secp256k1::key::PublicKey;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::address::Address;
 use lightning::chain::keysinterface::{KeysInterface, KeysManager};
+use lightning::ln::channelmanager::{ChannelDetails, PaymentSendFailure, MIN_FINAL_CLTV_EXPIRY};
 use lightning::ln::msgs::NetAddress;
-use lightning::ln::PaymentHash;
-use lightning::routing::network_graph::NetworkGraph;
+use lightning::ln::script::ShutdownScript;
+use lightning::ln::{PaymentHash, PaymentPreimage};
+use lightning::routing::network_graph::{NetworkGraph, RoutingFees};
 use lightning::routing::router;
-use lightning::routing::router::{Payee, RouteParameters};
+use lightning::routing::router::{Payee, Route, RouteHint, RouteHintHop, RouteParameters};
 use lightning::routing::scorer::Scorer;
 use lightning::util::config::{ChannelConfig, ChannelHandshakeLimits, UserConfig};
 use lightning::util::events::EventHandler;
+use lightning::util::logger::Level;
+use lightning::util::ser::Writeable;
 use lightning_invoice::payment::PaymentError;
-use lightning_invoice::{utils, Currency, Invoice};
+use lightning_invoice::{CreationError, Currency, Invoice, InvoiceBuilder, DEFAULT_EXPIRY_TIME};
+use rand::{thread_rng, Rng};
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::env;
+use std::future::Future;
 use std::io;
 use std::io::{BufRead, Write};
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
-use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a non-interactive command waits for its payments to complete before shutting down.
+const PENDING_PAYMENT_WAIT_SECS: u64 = 60;
+
+/// Commands which move funds, and so are refused while the node is paused.
+const PAUSED_COMMANDS: &[&str] = &["openchannel", "openqueue", "sendpayment", "keysend", "refund"];
+
+/// How many times failed payment paths are retried unless configured otherwise.
+const DEFAULT_MAX_PAYMENT_RETRIES: usize = 5;
+
+/// Unless configured otherwise, we'll pay up to 5 sats plus 1% of a payment in routing fees.
+const DEFAULT_MAX_FEE_BASE_MSAT: u64 = 5000;
+const DEFAULT_MAX_FEE_PROPORTIONAL_MILLIONTHS: u64 = 10_000;
+
+/// The rolling window over which fees paid to reach each counterparty are budgeted, if they are.
+const DEFAULT_COUNTERPARTY_FEE_BUDGET_WINDOW_HOURS: u64 = 24;
+
+/// Payments to us are only claimed with at least two hours left before they expire.
+const DEFAULT_MIN_CLAIM_CLTV_BLOCKS: u32 = 12;
+
+const DEFAULT_INVOICE_DESCRIPTION: &str = "ldk-tutorial-node";
+
+/// How often heartbeats are sent to a configured monitor unless configured otherwise.
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 5 * 60;
+
+/// Completed payments are pruned from the payment stores once they're this many days old, unless
+/// configured otherwise.
+const DEFAULT_PAYMENT_RETENTION_DAYS: u64 = 90;
+
+/// How many of the most recent block headers are cached unless configured otherwise.
+const DEFAULT_HEADER_CACHE_SIZE: usize = 1000;
+
+/// Environment variables earlier versions read settings from, and where in the config file those
+/// settings went. The node refuses to start with one set rather than silently ignore it.
+const MOVED_TO_CONFIG: &[(&str, &str)] = &[
+	("LDK_WEBHOOK_ENDPOINT", "settlement_webhook in [notify]"),
+	("LDK_WEBHOOK_SECRET", "settlement_secret in [notify]"),
+	("LDK_TELEMETRY_ENDPOINT", "telemetry_endpoint in [monitoring]"),
+	("LDK_HEARTBEAT_ENDPOINT", "heartbeat_endpoint in [monitoring]"),
+	("LDK_HEARTBEAT_INTERVAL_SECS", "heartbeat_interval_secs in [monitoring]"),
+	("LDK_PAYMENT_RETENTION_DAYS", "retention_days in [payments]"),
+	("LDK_MIN_FINAL_CLTV_EXPIRY", "min_final_cltv_expiry in [payments]"),
+	("LDK_PAYER_SECRET", "payer_secret in [payments]"),
+	("LDK_FUNDING_WALLET", "funding_wallet in [wallet]"),
+	("LDK_OPERATING_WALLET", "operating_wallet in [wallet]"),
+	("LDK_HEADER_CACHE_SIZE", "header_cache_size in [memory]"),
+	("LDK_MAX_GRAPH_NODES", "max_graph_nodes in [memory]"),
+];
+
+/// What an invoice tells payers it's for: either a description, or the SHA-256 hash of a longer one
+/// handed to the payer some other way.
+pub(crate) enum InvoiceDescription {
+	Direct(String),
+	Hash(Sha256),
+}
+
+/// How an invoice we create describes itself and how long it can be paid for.
+pub(crate) struct InvoiceOptions {
+	pub(crate) description: InvoiceDescription,
+	pub(crate) expiry_secs: u32,
+}
+
+impl Default for InvoiceOptions {
+	fn default() -> Self {
+		Self {
+			description: InvoiceDescription::Direct(DEFAULT_INVOICE_DESCRIPTION.to_string()),
+			expiry_secs: DEFAULT_EXPIRY_TIME as u32,
+		}
+	}
+}
 
 pub(crate) struct LdkUserInfo {
-	pub(crate) bitcoind_rpc_username: String,
-	pub(crate) bitcoind_rpc_password: String,
+	pub(crate) bitcoind_rpc_auth: RpcAuth,
 	pub(crate) bitcoind_rpc_port: u16,
 	pub(crate) bitcoind_rpc_host: String,
+	pub(crate) bitcoind_rpc_transport: RpcTransport,
 	pub(crate) ldk_storage_dir_path: String,
 	pub(crate) ldk_peer_listening_port: u16,
+	/// The addresses we accept inbound peer connections on.
+	pub(crate) ldk_peer_bind_addrs: Vec<SocketAddr>,
 	pub(crate) ldk_announced_listen_addr: Vec<NetAddress>,
 	pub(crate) ldk_announced_node_name: [u8; 32],
+	/// Whether to accept inbound peer connections. If not, we only connect out to peers and never
+	/// announce any addresses.
+	pub(crate) accept_inbound: bool,
+	/// Whether to record the channel messages exchanged with each peer, for `dumpwire`.
+	pub(crate) debug_wire: bool,
 	pub(crate) network: Network,
+	/// Whether to run on mainnet even though the data directory previously ran on another network.
+	pub(crate) allow_mainnet_switch: bool,
+	pub(crate) user_config: UserConfig,
+	pub(crate) telemetry_endpoint: Option<String>,
+	/// If set, signed heartbeats are posted here every `heartbeat_interval`.
+	pub(crate) heartbeat_endpoint: Option<String>,
+	pub(crate) heartbeat_interval: Duration,
+	/// If set, every inbound payment we claim is posted here, signed with `webhook_secret`.
+	pub(crate) webhook_endpoint: Option<String>,
+	pub(crate) webhook_secret: String,
+	pub(crate) payment_retention_days: u64,
+	pub(crate) funding_wallet: Option<String>,
+	pub(crate) operating_wallet: Option<String>,
+	/// Whether on-chain funds are held in our own wallet rather than in bitcoind's.
+	pub(crate) internal_wallet: bool,
+	/// Whether channel funding transactions may only spend confirmed outputs.
+	pub(crate) fund_from_confirmed_only: bool,
+	/// The feerates we fall back to when bitcoind has no fee estimate.
+	pub(crate) fee_floors: FeeFloors,
+	/// If set, on-chain funds are swept to addresses derived from this xpub rather than to bitcoind.
+	pub(crate) sweep_xpub: Option<String>,
+	/// If set, on-chain funds are swept to this address rather than to bitcoind.
+	pub(crate) sweep_address: Option<String>,
+	/// How long we wait for more spendable outputs to sweep along with a new one.
+	pub(crate) sweep_batch_window: Duration,
+	pub(crate) min_final_cltv_expiry: u32,
+	/// Payments to us which would expire within this many blocks are failed back, not claimed.
+	pub(crate) min_claim_cltv_blocks: u32,
+	/// The most we'll pay in routing fees for any one payment.
+	pub(crate) fee_budget: FeeBudget,
+	/// When channels are warned about, or rotated, for having taken too many commitment updates.
+	pub(crate) rotation_limits: RotationLimits,
+	/// How many channels queued with `openqueue add` are opened at once, at most.
+	pub(crate) max_concurrent_opens: usize,
+	/// If set, the operator is alerted when more than this is in flight through our channels.
+	pub(crate) max_htlc_exposure_msat: Option<u64>,
+	/// Which channels other nodes may open to us.
+	pub(crate) acceptor_policy: AcceptorPolicy,
+	/// When the node holds forwards and refuses channels for maintenance.
+	pub(crate) maintenance: MaintenanceSchedule,
+	/// How many times failed payment paths are retried, at most.
+	pub(crate) max_payment_retries: usize,
+	/// How many times an invoice which expires unpaid is re-issued automatically, at most.
+	pub(crate) auto_reissue_invoices: u32,
+	/// How outbound payments are padded by default, unless a payment says otherwise.
+	pub(crate) path_privacy: PathPrivacy,
+	pub(crate) rpc_listen_addr: Option<SocketAddr>,
+	/// If set, our listening port is exposed as an onion service via this Tor control port.
+	pub(crate) tor_control_addr: Option<SocketAddr>,
+	/// If set, all outbound peer connections go through this SOCKS5 proxy.
+	pub(crate) proxy_addr: Option<SocketAddr>,
+	/// If set, we poll for new blocks when bitcoind announces them here over ZMQ, rather than
+	/// every second.
+	pub(crate) bitcoind_zmq_addr: Option<SocketAddr>,
+	/// If set, blocks, fee estimates and broadcasts go through this Esplora server rather than
+	/// bitcoind.
+	pub(crate) esplora_url: Option<String>,
+	/// If set, the chain is synced using the compact block filters this peer serves.
+	pub(crate) filter_peer_addr: Option<SocketAddr>,
+	/// Peers whose filter headers the filter peer's are checked against.
+	pub(crate) filter_check_peer_addrs: Vec<SocketAddr>,
+	pub(crate) header_cache_size: usize,
+	pub(crate) max_graph_nodes: Option<usize>,
+	/// If set, keysend payments are only claimed if their payer knows this secret.
+	pub(crate) payer_secret: Option<[u8; 32]>,
+	/// Whether to keep the key seed encrypted under a passphrase.
+	pub(crate) encrypt_seed: bool,
+	/// If set, the seed passphrase is read from this file rather than prompted for.
+	pub(crate) password_file: Option<String>,
+	/// If set, the channels in this backup are recovered by asking their peers to force-close.
+	pub(crate) recover_backup: Option<String>,
+	/// Where copies of our channel monitors are kept, besides the monitors directory.
+	pub(crate) monitor_replicas: Vec<MonitorReplica>,
+	/// LDK's log records below this level aren't logged, or none if `None`.
+	pub(crate) log_level: Option<Level>,
+	pub(crate) log_format: LogFormat,
+	pub(crate) log_rotation: LogRotation,
+	/// Where key events are notified of, if anywhere.
+	pub(crate) notify_targets: Vec<NotifyTarget>,
+	/// Which events are notified of, or all of them if empty.
+	pub(crate) notify_events: Vec<String>,
+	/// Where the ChannelManager, channel peers, payments and network graph are kept.
+	pub(crate) storage_backend: StorageBackend,
+	/// A single command to run non-interactively, after which the node shuts down.
+	pub(crate) command: Option<String>,
 }
 
-pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
-	if env::args().len() < 3 {
-		println!("ldk-tutorial-node requires 3 arguments: `cargo run <bitcoind-rpc-username>:<bitcoind-rpc-password>@<bitcoind-rpc-host>:<bitcoind-rpc-port> ldk_storage_directory_path [<ldk-incoming-peer-listening-port>] [bitcoin-network] [announced-node-name announced-listen-addr*]`");
-		return Err(());
+/// A timeout given in seconds on the command line, or else in the config file.
+fn parse_timeout(
+	arg: Option<String>, config_secs: Option<u64>, flag: &str,
+) -> Result<Option<Duration>, ()> {
+	let secs = match arg.map(|secs| secs.parse::<u64>()) {
+		Some(Ok(secs)) => Some(secs),
+		Some(Err(_)) => {
+			println!("ERROR: {} requires a number of seconds", flag);
+			return Err(());
+		}
+		None => config_secs,
+	};
+	match secs {
+		Some(0) => {
+			println!("ERROR: {} must be at least a second", flag);
+			Err(())
+		}
+		secs => Ok(secs.map(Duration::from_secs)),
 	}
-	let bitcoind_rpc_info = env::args().skip(1).next().unwrap();
-	let bitcoind_rpc_info_parts: Vec<&str> = bitcoind_rpc_info.rsplitn(2, "@").collect();
-	if bitcoind_rpc_info_parts.len() != 2 {
-		println!("ERROR: bad bitcoind RPC URL provided");
-		return Err(());
+}
+
+/// Returns the storage directory to inspect if we were started with `--observe <dir>`.
+pub(crate) fn parse_observe_arg() -> Option<String> {
+	let mut args = env::args();
+	while let Some(arg) = args.next() {
+		if arg == "--observe" {
+			return args.next();
+		}
 	}
-	let rpc_user_and_password: Vec<&str> = bitcoind_rpc_info_parts[1].split(":").collect();
-	if rpc_user_and_password.len() != 2 {
-		println!("ERROR: bad bitcoind RPC username/password combo provided");
-		return Err(());
+	None
+}
+
+pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
+	// Pull out any flags first so that the remaining arguments can be parsed positionally.
+	let mut args = Vec::new();
+	let mut rpc_listen_addr = None;
+	let mut tor_control_addr = None;
+	let mut proxy_addr = None;
+	let mut bitcoind_zmq_addr = None;
+	let mut filter_peer_addr = None;
+	let mut filter_check_peer_args = Vec::new();
+	let mut bind_addr_args = Vec::new();
+	let mut command = None;
+	let mut accept_inbound = true;
+	let mut debug_wire = false;
+	let mut allow_mainnet_switch = false;
+	let mut cookie_path_arg = None;
+	let mut chain_backend_arg = None;
+	let mut esplora_url_arg = None;
+	let mut rpc_tls_arg = false;
+	let mut rpc_tls_ca_arg = None;
+	let mut rpc_tls_pin_arg = None;
+	let mut rpc_timeout_arg = None;
+	let mut rpc_connect_timeout_arg = None;
+	let mut encrypt_seed = false;
+	let mut password_file = None;
+	let mut recover_backup = None;
+	let mut storage_arg = None;
+	let mut raw_args = env::args();
+	while let Some(arg) = raw_args.next() {
+		// Everything after `--` is a single command to run instead of reading commands from stdin.
+		if arg == "--" {
+			command = Some(raw_args.by_ref().collect::<Vec<_>>().join(" "));
+			break;
+		}
+		if arg == "--no-listen" {
+			accept_inbound = false;
+			continue;
+		}
+		if arg == "--debug-wire" {
+			debug_wire = true;
+			continue;
+		}
+		if arg == "--bitcoind-rpc-tls" {
+			rpc_tls_arg = true;
+			continue;
+		}
+		if arg == "--encrypt-seed" {
+			encrypt_seed = true;
+			continue;
+		}
+		if arg == "--i-really-want-mainnet" {
+			allow_mainnet_switch = true;
+			continue;
+		}
+		if arg.starts_with("--bitcoind-cookie-path=") {
+			cookie_path_arg = Some(arg["--bitcoind-cookie-path=".len()..].to_string());
+			continue;
+		}
+		if arg == "--bitcoind-cookie-path" {
+			match raw_args.next() {
+				Some(path) => cookie_path_arg = Some(path),
+				None => {
+					println!("ERROR: --bitcoind-cookie-path requires the path to bitcoind's .cookie file");
+					return Err(());
+				}
+			}
+			continue;
+		}
+		// Flags which take a value other than an address.
+		let value_flag = [
+			"--chain-backend",
+			"--esplora-url",
+			"--bitcoind-rpc-tls-ca",
+			"--bitcoind-rpc-tls-pin",
+			"--bitcoind-rpc-timeout",
+			"--bitcoind-connect-timeout",
+			"--password-file",
+			"--recover",
+			"--storage",
+		]
+		.iter()
+		.find_map(|flag| {
+			if arg == *flag {
+				Some((*flag, raw_args.next()))
+			} else if arg.starts_with(&format!("{}=", flag)) {
+				Some((*flag, Some(arg[flag.len() + 1..].to_string())))
+			} else {
+				None
+			}
+		});
+		match value_flag {
+			Some((flag, None)) => {
+				println!("ERROR: {} requires a value", flag);
+				return Err(());
+			}
+			Some((flag, Some(value))) => {
+				match flag {
+					"--chain-backend" => chain_backend_arg = Some(value),
+					"--esplora-url" => esplora_url_arg = Some(value),
+					"--bitcoind-rpc-tls-ca" => rpc_tls_ca_arg = Some(value),
+					"--bitcoind-rpc-tls-pin" => rpc_tls_pin_arg = Some(value),
+					"--bitcoind-rpc-timeout" => rpc_timeout_arg = Some(value),
+					"--password-file" => password_file = Some(value),
+					"--recover" => recover_backup = Some(value),
+					"--storage" => storage_arg = Some(value),
+					_ => rpc_connect_timeout_arg = Some(value),
+				}
+				continue;
+			}
+			None => {}
+		}
+		// Unlike the other address flags, --bind may be given more than once.
+		if arg.starts_with("--bind=") {
+			bind_addr_args.push(arg["--bind=".len()..].to_string());
+			continue;
+		}
+		if arg == "--bind" {
+			match raw_args.next() {
+				Some(addr) => bind_addr_args.push(addr),
+				None => {
+					println!(
+						"ERROR: --bind requires an address to listen on, e.g. `--bind 0.0.0.0`"
+					);
+					return Err(());
+				}
+			}
+			continue;
+		}
+		// Flags which take an address, along with an example address for each.
+		let addr_flags = [
+			("--rpc-listen", "127.0.0.1:9736"),
+			("--tor-control", "127.0.0.1:9051"),
+			("--proxy", "127.0.0.1:9050"),
+			("--bitcoind-zmq", "127.0.0.1:28332"),
+			("--filter-peer", "127.0.0.1:8333"),
+			("--filter-check-peer", "203.0.113.1:8333"),
+		];
+		let (flag, example, addr) = match addr_flags.iter().find_map(|(flag, example)| {
+			if arg == *flag {
+				Some((*flag, *example, raw_args.next()))
+			} else if arg.starts_with(&format!("{}=", flag)) {
+				Some((*flag, *example, Some(arg[flag.len() + 1..].to_string())))
+			} else {
+				None
+			}
+		}) {
+			Some(flag_and_addr) => flag_and_addr,
+			None => {
+				args.push(arg);
+				continue;
+			}
+		};
+		let addr = match addr.as_ref().map(|a| a.to_socket_addrs().map(|mut r| r.next())) {
+			Some(Ok(Some(addr))) => addr,
+			_ => {
+				println!("ERROR: {} requires an address, e.g. `{} {}`", flag, flag, example);
+				return Err(());
+			}
+		};
+		match flag {
+			"--rpc-listen" => rpc_listen_addr = Some(addr),
+			"--tor-control" => tor_control_addr = Some(addr),
+			"--bitcoind-zmq" => bitcoind_zmq_addr = Some(addr),
+			"--filter-peer" => filter_peer_addr = Some(addr),
+			"--filter-check-peer" => filter_check_peer_args.push(addr),
+			_ => proxy_addr = Some(addr),
+		}
 	}
-	let bitcoind_rpc_username = rpc_user_and_password[0].to_string();
-	let bitcoind_rpc_password = rpc_user_and_password[1].to_string();
-	let bitcoind_rpc_path: Vec<&str> = bitcoind_rpc_info_parts[0].split(":").collect();
-	if bitcoind_rpc_path.len() != 2 {
-		println!("ERROR: bad bitcoind RPC path provided");
-		return Err(());
+
+	// The bitcoind RPC info may instead come from the config file, in which case the storage
+	// directory is the first argument.
+	let (bitcoind_rpc_info, mut arg_idx) = match args.get(1) {
+		Some(arg) if arg.contains('@') => (Some(arg.clone()), 2),
+		_ => (None, 1),
+	};
+	let ldk_storage_dir_path = match args.get(arg_idx) {
+		Some(path) => path.clone(),
+		None => {
+			println!("ldk-tutorial-node requires 3 arguments: `cargo run <bitcoind-rpc-username>:<bitcoind-rpc-password>@<bitcoind-rpc-host>:<bitcoind-rpc-port> ldk_storage_directory_path [<ldk-incoming-peer-listening-port>] [bitcoin-network] [announced-node-name announced-listen-addr*] [--rpc-listen <host:port>] [--bind <ip[:port]>]* [--tor-control <host:port>] [--proxy <host:port>] [--bitcoind-cookie-path <path>] [--bitcoind-zmq <host:port>] [--bitcoind-rpc-tls] [--bitcoind-rpc-tls-ca <pem-file>] [--bitcoind-rpc-tls-pin <sha256>] [--bitcoind-rpc-timeout <secs>] [--bitcoind-connect-timeout <secs>] [--chain-backend <bitcoind|esplora|filters>] [--esplora-url <url>] [--filter-peer <host:port>] [--filter-check-peer <host:port>]* [--storage <files|sqlite>] [--no-listen] [--debug-wire] [--i-really-want-mainnet] [-- <command>]`, though the bitcoind RPC info may be given in ldk_storage_directory_path/ldk.conf instead, or left out to use bitcoind's cookie file");
+			return Err(());
+		}
+	};
+	let config_path = format!("{}/ldk.conf", ldk_storage_dir_path);
+	let config = match config::read_config(Path::new(&config_path)) {
+		Ok(config) => config,
+		Err(e) => {
+			println!("ERROR: {}", e);
+			return Err(());
+		}
+	};
+	for (var, setting) in MOVED_TO_CONFIG.iter() {
+		if env::var_os(var).is_some() {
+			println!(
+				"ERROR: {} is no longer read, set {} in {} instead",
+				var, setting, config_path
+			);
+			return Err(());
+		}
 	}
-	let bitcoind_rpc_host = bitcoind_rpc_path[0].to_string();
-	let bitcoind_rpc_port = bitcoind_rpc_path[1].parse::<u16>().unwrap();
 
-	let ldk_storage_dir_path = env::args().skip(2).next().unwrap();
+	let (bitcoind_rpc_user_pass, bitcoind_rpc_host, bitcoind_rpc_port) = match bitcoind_rpc_info {
+		Some(bitcoind_rpc_info) => {
+			let bitcoind_rpc_info_parts: Vec<&str> = bitcoind_rpc_info.rsplitn(2, "@").collect();
+			if bitcoind_rpc_info_parts.len() != 2 {
+				println!("ERROR: bad bitcoind RPC URL provided");
+				return Err(());
+			}
+			let rpc_user_and_password: Vec<&str> = bitcoind_rpc_info_parts[1].split(":").collect();
+			if rpc_user_and_password.len() != 2 {
+				println!("ERROR: bad bitcoind RPC username/password combo provided");
+				return Err(());
+			}
+			let bitcoind_rpc_path: Vec<&str> = bitcoind_rpc_info_parts[0].split(":").collect();
+			if bitcoind_rpc_path.len() != 2 {
+				println!("ERROR: bad bitcoind RPC path provided");
+				return Err(());
+			}
+			(
+				Some((rpc_user_and_password[0].to_string(), rpc_user_and_password[1].to_string())),
+				Some(bitcoind_rpc_path[0].to_string()),
+				Some(bitcoind_rpc_path[1].parse::<u16>().unwrap()),
+			)
+		}
+		// Without a username and password we fall back to bitcoind's cookie file, and without a
+		// host and port to bitcoind's defaults, once we know the network.
+		None => {
+			match (config.bitcoind_rpc_username.clone(), config.bitcoind_rpc_password.clone()) {
+				(Some(username), Some(password)) => (
+					Some((username, password)),
+					config.bitcoind_rpc_host.clone(),
+					config.bitcoind_rpc_port,
+				),
+				(None, None) => (None, config.bitcoind_rpc_host.clone(), config.bitcoind_rpc_port),
+				_ => {
+					println!("ERROR: rpc_username and rpc_password must be given together in the [bitcoind] section of {}", config_path);
+					return Err(());
+				}
+			}
+		}
+	};
 
 	let mut ldk_peer_port_set = true;
-	let ldk_peer_listening_port: u16 = match env::args().skip(3).next().map(|p| p.parse()) {
+	let ldk_peer_listening_port: u16 = match args.get(arg_idx + 1).map(|p| p.parse()) {
 		Some(Ok(p)) => p,
 		Some(Err(_)) => {
 			ldk_peer_port_set = false;
-			9735
+			config.listening_port.unwrap_or(9735)
 		}
 		None => {
 			ldk_peer_port_set = false;
-			9735
+			config.listening_port.unwrap_or(9735)
+		}
+	};
+
+	// Bind addresses given on the command line replace those in the config file. Each may give
+	// just an IP, in which case we listen on it on our listening port.
+	let bind_addr_strs = if bind_addr_args.is_empty() {
+		if config.bind_addrs.is_empty() {
+			vec!["0.0.0.0".to_string()]
+		} else {
+			config.bind_addrs.clone()
 		}
+	} else {
+		bind_addr_args
 	};
+	let mut ldk_peer_bind_addrs = Vec::new();
+	for s in bind_addr_strs {
+		match (IpAddr::from_str(&s), SocketAddr::from_str(&s)) {
+			(Ok(ip), _) => ldk_peer_bind_addrs.push(SocketAddr::new(ip, ldk_peer_listening_port)),
+			(_, Ok(addr)) => ldk_peer_bind_addrs.push(addr),
+			_ => {
+				println!("ERROR: Failed to parse bind address {}, expected an IP or IP:port", s);
+				return Err(());
+			}
+		}
+	}
 
-	let mut arg_idx = match ldk_peer_port_set {
-		true => 4,
-		false => 3,
+	arg_idx += match ldk_peer_port_set {
+		true => 2,
+		false => 1,
 	};
-	let network: Network = match env::args().skip(arg_idx).next().as_ref().map(String::as_str) {
+	let network_arg = args.get(arg_idx).or(config.network.as_ref());
+	let network: Network = match network_arg.map(String::as_str) {
 		Some("testnet") => Network::Testnet,
 		Some("regtest") => Network::Regtest,
+		Some("signet") => Network::Signet,
+		Some("mainnet") => Network::Bitcoin,
 		Some(net) => {
-			panic!("Unsupported network provided. Options are: `regtest`, `testnet`. Got {}", net);
+			panic!(
+				"Unsupported network provided. Options are: `regtest`, `testnet`, `signet`, `mainnet`. Got {}",
+				net
+			);
 		}
 		None => Network::Testnet,
 	};
 
-	let ldk_announced_node_name = match env::args().skip(arg_idx + 1).next().as_ref() {
+	let bitcoind_zmq_addr = match (bitcoind_zmq_addr, &config.bitcoind_zmq_addr) {
+		(Some(addr), _) => Some(addr),
+		(None, Some(addr)) => match addr.to_socket_addrs().map(|mut r| r.next()) {
+			Ok(Some(addr)) => Some(addr),
+			_ => {
+				println!("ERROR: Failed to parse zmq_block_addr {}, expected host:port", addr);
+				return Err(());
+			}
+		},
+		(None, None) => None,
+	};
+
+	let chain_backend = chain_backend_arg
+		.or_else(|| config.chain_backend.clone())
+		.unwrap_or_else(|| "bitcoind".to_string());
+	let (esplora_url, filter_peer_addr) = match chain_backend.as_str() {
+		"bitcoind" => (None, None),
+		"esplora" => match esplora_url_arg.or_else(|| config.esplora_url.clone()) {
+			Some(url) => {
+				if let Err(e) = esplora::parse_url(&url) {
+					println!("ERROR: Invalid Esplora URL: {}", e);
+					return Err(());
+				}
+				(Some(url), None)
+			}
+			None => {
+				println!("ERROR: The esplora chain backend requires --esplora-url, or esplora_url in the config file's [chain] section");
+				return Err(());
+			}
+		},
+		"filters" => match (filter_peer_addr, &config.filter_peer) {
+			(Some(addr), _) => (None, Some(addr)),
+			(None, Some(addr)) => match addr.to_socket_addrs().map(|mut r| r.next()) {
+				Ok(Some(addr)) => (None, Some(addr)),
+				_ => {
+					println!("ERROR: Failed to parse filter_peer {}, expected host:port", addr);
+					return Err(());
+				}
+			},
+			(None, None) => {
+				println!("ERROR: The filters chain backend requires --filter-peer, or filter_peer in the config file's [chain] section");
+				return Err(());
+			}
+		},
+		"electrum" => {
+			println!("ERROR: The electrum chain backend isn't supported, as Electrum servers don't serve the full blocks LDK needs to sync the chain. Use esplora instead");
+			return Err(());
+		}
+		backend => {
+			println!(
+				"ERROR: Unknown chain backend {}, expected bitcoind, esplora or filters",
+				backend
+			);
+			return Err(());
+		}
+	};
+	let mut filter_check_peer_addrs = filter_check_peer_args;
+	if filter_check_peer_addrs.is_empty() {
+		for addr in config.filter_check_peers.iter() {
+			match addr.to_socket_addrs().map(|mut r| r.next()) {
+				Ok(Some(addr)) => filter_check_peer_addrs.push(addr),
+				_ => {
+					println!(
+						"ERROR: Failed to parse filter_check_peers entry {}, expected host:port",
+						addr
+					);
+					return Err(());
+				}
+			}
+		}
+	}
+
+	let bitcoind_rpc_host = bitcoind_rpc_host.unwrap_or_else(|| "127.0.0.1".to_string());
+	let bitcoind_rpc_port = bitcoind_rpc_port.unwrap_or_else(|| default_rpc_port(network));
+
+	// A CA file or pinned certificate only makes sense over TLS, so either implies it.
+	let rpc_tls_ca = rpc_tls_ca_arg.or_else(|| config.bitcoind_rpc_tls_ca_file.clone());
+	let rpc_tls_pin = match rpc_tls_pin_arg.or_else(|| config.bitcoind_rpc_tls_cert_sha256.clone())
+	{
+		Some(pin) => match rpc_tls::parse_fingerprint(&pin) {
+			Some(fingerprint) => Some(fingerprint),
+			None => {
+				println!("ERROR: bitcoind's pinned certificate must be given as its SHA256 fingerprint in hex, got {}", pin);
+				return Err(());
+			}
+		},
+		None => None,
+	};
+	let rpc_tls_enabled = rpc_tls_arg
+		|| config.bitcoind_rpc_tls.unwrap_or(false)
+		|| rpc_tls_ca.is_some()
+		|| rpc_tls_pin.is_some();
+	let rpc_timeout =
+		parse_timeout(rpc_timeout_arg, config.bitcoind_rpc_timeout_secs, "--bitcoind-rpc-timeout")?
+			.unwrap_or(DEFAULT_RPC_TIMEOUT);
+	let rpc_connect_timeout = parse_timeout(
+		rpc_connect_timeout_arg,
+		config.bitcoind_rpc_connect_timeout_secs,
+		"--bitcoind-connect-timeout",
+	)?
+	.unwrap_or(DEFAULT_RPC_CONNECT_TIMEOUT);
+	let bitcoind_rpc_transport = RpcTransport {
+		timeout: rpc_timeout,
+		tls: match rpc_tls_enabled {
+			true => Some(RpcTls {
+				ca_file: rpc_tls_ca.map(PathBuf::from),
+				cert_sha256: rpc_tls_pin,
+				connect_timeout: rpc_connect_timeout,
+			}),
+			false => None,
+		},
+	};
+	// bitcoind's RPC server authenticates us with a password sent in the clear, so anyone on the
+	// network path to a remote bitcoind could take over its wallet.
+	if !rpc_tls_enabled && !is_local_host(&bitcoind_rpc_host) {
+		eprintln!("Warning: Connecting to bitcoind at {} without TLS, so our RPC credentials and bitcoind's answers can be read and tampered with on the way. Use --bitcoind-rpc-tls with stunnel or a TLS-terminating proxy in front of bitcoind", bitcoind_rpc_host);
+	}
+
+	let cookie_path = cookie_path_arg.or_else(|| match bitcoind_rpc_user_pass {
+		Some(_) => None,
+		None => config.bitcoind_cookie_path.clone(),
+	});
+	let bitcoind_rpc_auth = match (cookie_path, bitcoind_rpc_user_pass) {
+		(Some(path), _) => RpcAuth::Cookie(PathBuf::from(path)),
+		(None, Some((user, password))) => RpcAuth::UserPass { user, password },
+		(None, None) => match default_cookie_path(network) {
+			Some(path) if path.exists() => RpcAuth::Cookie(path),
+			path => {
+				println!(
+					"ERROR: no bitcoind RPC credentials were given and there's no bitcoind cookie file at {}. Give them on the command line, in the [bitcoind] section of {}, or with --bitcoind-cookie-path",
+					path.map_or("the default location".to_string(), |p| p.display().to_string()),
+					config_path
+				);
+				return Err(());
+			}
+		},
+	};
+
+	let node_name_arg = args.get(arg_idx + 1);
+	let ldk_announced_node_name = match node_name_arg.or(config.announced_node_name.as_ref()) {
 		Some(s) => {
 			if s.len() > 32 {
 				panic!("Node Alias can not be longer than 32 bytes");
 			}
-			arg_idx += 1;
 			let mut bytes = [0; 32];
 			bytes[..s.len()].copy_from_slice(s.as_bytes());
 			bytes
@@ -107,45 +747,380 @@ pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
 		None => [0; 32],
 	};
 
+	// Announced addresses given on the command line replace those in the config file.
+	let listen_addr_args = match node_name_arg {
+		Some(_) if args.len() > arg_idx + 2 => &args[arg_idx + 2..],
+		_ => &config.announced_listen_addrs[..],
+	};
 	let mut ldk_announced_listen_addr = Vec::new();
-	loop {
-		match env::args().skip(arg_idx + 1).next().as_ref() {
-			Some(s) => match IpAddr::from_str(s) {
-				Ok(IpAddr::V4(a)) => {
-					ldk_announced_listen_addr
-						.push(NetAddress::IPv4 { addr: a.octets(), port: ldk_peer_listening_port });
-					arg_idx += 1;
-				}
-				Ok(IpAddr::V6(a)) => {
-					ldk_announced_listen_addr
-						.push(NetAddress::IPv6 { addr: a.octets(), port: ldk_peer_listening_port });
-					arg_idx += 1;
-				}
-				Err(_) => panic!("Failed to parse announced-listen-addr into an IP address"),
+	for s in listen_addr_args {
+		match IpAddr::from_str(s) {
+			Ok(IpAddr::V4(a)) => {
+				ldk_announced_listen_addr
+					.push(NetAddress::IPv4 { addr: a.octets(), port: ldk_peer_listening_port });
+			}
+			Ok(IpAddr::V6(a)) => {
+				ldk_announced_listen_addr
+					.push(NetAddress::IPv6 { addr: a.octets(), port: ldk_peer_listening_port });
+			}
+			Err(_) => panic!("Failed to parse announced-listen-addr into an IP address"),
+		}
+	}
+
+	// There's no point announcing addresses we won't accept connections on.
+	if !accept_inbound && !ldk_announced_listen_addr.is_empty() {
+		eprintln!("Warning: Not announcing listen addresses as --no-listen was given");
+		ldk_announced_listen_addr.clear();
+	}
+	if !accept_inbound && tor_control_addr.is_some() {
+		eprintln!("Warning: Not creating an onion service as --no-listen was given");
+		tor_control_addr = None;
+	}
+
+	// Default configuration for our channels, used both for channels we open and those opened to
+	// us.
+	let mut user_config = UserConfig::default();
+	user_config.peer_channel_config_limits.force_announced_channel_preference = false;
+	if let Some(announced) = config.channel_announced {
+		user_config.channel_options.announced_channel = announced;
+	}
+	if let Some(fee_base_msat) = config.forwarding_fee_base_msat {
+		user_config.channel_options.forwarding_fee_base_msat = fee_base_msat;
+	}
+	if let Some(fee_proportional_millionths) = config.forwarding_fee_proportional_millionths {
+		user_config.channel_options.forwarding_fee_proportional_millionths =
+			fee_proportional_millionths;
+	}
+	if let Some(cltv_expiry_delta) = config.cltv_expiry_delta {
+		user_config.channel_options.cltv_expiry_delta = cltv_expiry_delta;
+	}
+	if let Some(htlc_minimum_msat) = config.htlc_minimum_msat {
+		user_config.own_channel_config.our_htlc_minimum_msat = htlc_minimum_msat;
+	}
+	if let Some(max_fee_sat) = config.force_close_avoidance_max_fee_satoshis {
+		user_config.channel_options.force_close_avoidance_max_fee_satoshis = max_fee_sat;
+	}
+	// Channels which commit to their closing script when opened can't later be closed to an
+	// address given to `closechannel`.
+	if let Some(commit_upfront) = config.commit_upfront_shutdown_pubkey {
+		user_config.own_channel_config.commit_upfront_shutdown_pubkey = commit_upfront;
+	}
+
+	let rotation_limits = RotationLimits {
+		max_updates: config.max_commitment_updates.unwrap_or(DEFAULT_MAX_COMMITMENT_UPDATES),
+		rotate: config.rotate_channels.unwrap_or(false),
+	};
+	let max_concurrent_opens =
+		config.max_concurrent_opens.unwrap_or(DEFAULT_MAX_CONCURRENT_OPENS).max(1);
+	let parse_peers = |peers: &[String]| {
+		peers
+			.iter()
+			.map(|peer| {
+				hex_utils::to_compressed_pubkey(peer)
+					.ok_or_else(|| format!("invalid node id {} in [inbound_channels]", peer))
+			})
+			.collect::<Result<HashSet<_>, _>>()
+	};
+	let acceptor_policy =
+		match (parse_peers(&config.inbound_allow_peers), parse_peers(&config.inbound_deny_peers)) {
+			(Ok(allowed_peers), Ok(denied_peers)) => AcceptorPolicy {
+				min_channel_sat: config.inbound_min_channel_sat,
+				max_channel_sat: config.inbound_max_channel_sat,
+				max_channels_per_peer: config.inbound_max_channels_per_peer,
+				allowed_peers,
+				denied_peers,
 			},
-			None => break,
+			(Err(e), _) | (_, Err(e)) => {
+				println!("ERROR: {}", e);
+				return Err(());
+			}
+		};
+	let maintenance = match config
+		.maintenance_windows
+		.iter()
+		.map(|window| MaintenanceWindow::parse(window))
+		.collect::<Result<Vec<_>, _>>()
+	{
+		Ok(windows) => MaintenanceSchedule {
+			windows,
+			hold_forwards: config.maintenance_hold_forwards.unwrap_or(true),
+			refuse_channels: config.maintenance_refuse_channels.unwrap_or(true),
+		},
+		Err(e) => {
+			println!("ERROR: {}", e);
+			return Err(());
+		}
+	};
+
+	let fee_budget = FeeBudget {
+		base_msat: config.max_fee_base_msat.unwrap_or(DEFAULT_MAX_FEE_BASE_MSAT),
+		proportional_millionths: config
+			.max_fee_proportional_millionths
+			.unwrap_or(DEFAULT_MAX_FEE_PROPORTIONAL_MILLIONTHS),
+		counterparty_budget_msat: config.counterparty_fee_budget_msat,
+		counterparty_window_secs: config
+			.counterparty_fee_budget_window_hours
+			.unwrap_or(DEFAULT_COUNTERPARTY_FEE_BUDGET_WINDOW_HOURS)
+			* 3600,
+	};
+	let max_payment_retries = config.max_retries.unwrap_or(DEFAULT_MAX_PAYMENT_RETRIES);
+	// Merchants whose customers come back to an expired invoice may want a fresh one waiting.
+	let auto_reissue_invoices = config.auto_reissue_invoices.unwrap_or(0);
+	// Padding costs a little in fees and overpayment, so it's opt-in.
+	let path_privacy = PathPrivacy {
+		shadow_route: config.shadow_route.unwrap_or(false),
+		randomize_final_cltv: config.randomize_final_cltv.unwrap_or(false),
+	};
+
+	// Telemetry is strictly opt-in: nothing is reported unless an endpoint is configured.
+	let telemetry_endpoint = config.telemetry_endpoint.clone();
+
+	// Unlike telemetry, heartbeats identify the node, so that a monitor can tell it's still up.
+	let heartbeat_endpoint = config.heartbeat_endpoint.clone();
+	let heartbeat_interval = match config.heartbeat_interval_secs {
+		Some(0) => {
+			println!("ERROR: heartbeat_interval_secs must be a positive number of seconds");
+			return Err(());
+		}
+		Some(secs) => Duration::from_secs(secs),
+		None => Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+	};
+
+	// Merchant backends can't tell a forged settlement from a real one without the shared secret.
+	let webhook_endpoint = config.settlement_webhook.clone();
+	let webhook_secret = config.settlement_secret.clone().unwrap_or_default();
+	if webhook_endpoint.is_some() && webhook_secret.is_empty() {
+		println!("ERROR: settlement_secret must be set in [notify] to sign the settlement webhook's deliveries");
+		return Err(());
+	}
+
+	// Completed payments are pruned from the payment stores once they're this many days old.
+	let payment_retention_days =
+		config.payment_retention_days.unwrap_or(DEFAULT_PAYMENT_RETENTION_DAYS);
+
+	// Optionally split bitcoind wallet usage between a wallet which only funds channel opens and
+	// a wallet used for everything else, such as sweeping our on-chain outputs.
+	let funding_wallet = config.funding_wallet.clone();
+	let operating_wallet = config.operating_wallet.clone();
+	let fund_from_confirmed_only = config.fund_from_confirmed_only.unwrap_or(false);
+	// Or hold funds in a wallet of our own, so that bitcoind doesn't need one at all.
+	let internal_wallet = config.internal_wallet.unwrap_or(false);
+	if internal_wallet && (funding_wallet.is_some() || operating_wallet.is_some()) {
+		println!(
+			"ERROR: funding_wallet and operating_wallet can't be used with the internal wallet"
+		);
+		return Err(());
+	}
+	// Blocks fetched for their compact filters only have what LDK asked us to watch for.
+	if internal_wallet && filter_peer_addr.is_some() {
+		println!("ERROR: the internal wallet can't be used with the filters chain backend");
+		return Err(());
+	}
+
+	// A fresh regtest chain has no fee estimates, nor may a node which has just started.
+	let mut fee_floors = FeeFloors::default();
+	if let Some(floor) = config.background_floor_sat_per_kw {
+		fee_floors.background = floor;
+	}
+	if let Some(floor) = config.normal_floor_sat_per_kw {
+		fee_floors.normal = floor;
+	}
+	if let Some(floor) = config.high_priority_floor_sat_per_kw {
+		fee_floors.high_priority = floor;
+	}
+	let monitor_replicas = match config
+		.monitor_replicas
+		.iter()
+		.map(|replica| MonitorReplica::parse(replica))
+		.collect::<Result<Vec<_>, _>>()
+	{
+		Ok(monitor_replicas) => monitor_replicas,
+		Err(e) => {
+			println!("ERROR: {}", e);
+			return Err(());
+		}
+	};
+	let log_level = match config.log_level.as_deref().map(disk::parse_log_level) {
+		Some(Ok(log_level)) => log_level,
+		Some(Err(e)) => {
+			println!("ERROR: {}", e);
+			return Err(());
+		}
+		None => None,
+	};
+	let log_format = match config.log_format.as_deref().map(LogFormat::parse) {
+		Some(Ok(log_format)) => log_format,
+		Some(Err(e)) => {
+			println!("ERROR: {}", e);
+			return Err(());
+		}
+		None => LogFormat::Text,
+	};
+	let mut notify_targets = Vec::new();
+	if let Some(endpoint) = config.notify_webhook.clone() {
+		notify_targets.push(NotifyTarget::Webhook(endpoint));
+	}
+	if let Some(path) = config.notify_command.clone() {
+		notify_targets.push(NotifyTarget::Command(path));
+	}
+	if let Some(event) =
+		config.notify_events.iter().find(|event| !notifier::EVENTS.contains(&event.as_str()))
+	{
+		println!(
+			"ERROR: unknown notify event {}, expected one of {}",
+			event,
+			notifier::EVENTS.join(", ")
+		);
+		return Err(());
+	}
+	let log_rotation = LogRotation {
+		max_size_bytes: config.log_rotate_size_mb.map(|mb| mb * 1024 * 1024),
+		max_age_secs: config.log_rotate_age_hours.map(|hours| hours * 60 * 60),
+		keep_files: config.log_keep_files.unwrap_or(DEFAULT_LOG_KEEP_FILES),
+	};
+	let storage_backend = match storage_arg
+		.as_deref()
+		.or(config.storage_backend.as_deref())
+		.map(StorageBackend::parse)
+	{
+		Some(Ok(backend)) => backend,
+		Some(Err(e)) => {
+			println!("ERROR: {}", e);
+			return Err(());
 		}
+		None => StorageBackend::Files,
+	};
+	// Funds can instead be swept out of bitcoind entirely, to a hardware wallet or cold storage.
+	let sweep_xpub = config.sweep_xpub;
+	let sweep_address = config.sweep_address;
+	if sweep_xpub.is_some() && sweep_address.is_some() {
+		println!("ERROR: only one of sweep_xpub and sweep_address may be set");
+		return Err(());
 	}
+	// Sweeping outputs which become spendable around the same time in one transaction saves fees.
+	let sweep_batch_window = Duration::from_secs(config.sweep_batch_secs.unwrap_or(0));
+
+	// Merchants may want payers to leave us more blocks than LDK's minimum to claim an HTLC
+	// on-chain, e.g. if they may be offline for a while after being paid.
+	let min_final_cltv_expiry = match config.min_final_cltv_expiry {
+		Some(blocks) if blocks < MIN_FINAL_CLTV_EXPIRY => {
+			println!(
+				"ERROR: min_final_cltv_expiry must be a number of blocks no smaller than {}",
+				MIN_FINAL_CLTV_EXPIRY
+			);
+			return Err(());
+		}
+		Some(blocks) => blocks,
+		None => MIN_FINAL_CLTV_EXPIRY,
+	};
+	// Claiming a payment which expires soon could leave us claiming it on-chain, at a loss for a
+	// small payment, if the payer's node doesn't take the preimage in time.
+	let min_claim_cltv_blocks =
+		config.min_claim_cltv_blocks.unwrap_or(DEFAULT_MIN_CLAIM_CLTV_BLOCKS);
+
+	// Memory usage controls for small nodes: how many block headers to cache and, optionally, how
+	// many nodes to keep in the network graph.
+	let header_cache_size = config.header_cache_size.unwrap_or(DEFAULT_HEADER_CACHE_SIZE);
+	let max_graph_nodes = config.max_graph_nodes;
+
+	// Closed-group deployments can share a secret which keysend payers must prove knowledge of.
+	let payer_secret = match &config.payer_secret {
+		Some(hex) => match hex_utils::to_vec(hex) {
+			Some(bytes) if bytes.len() == 32 => {
+				let mut secret = [0; 32];
+				secret.copy_from_slice(&bytes);
+				Some(secret)
+			}
+			_ => {
+				println!("ERROR: payer_secret must be 32 bytes of hex");
+				return Err(());
+			}
+		},
+		None => None,
+	};
 
 	Ok(LdkUserInfo {
-		bitcoind_rpc_username,
-		bitcoind_rpc_password,
+		bitcoind_rpc_auth,
 		bitcoind_rpc_host,
 		bitcoind_rpc_port,
+		bitcoind_rpc_transport,
 		ldk_storage_dir_path,
 		ldk_peer_listening_port,
+		ldk_peer_bind_addrs,
 		ldk_announced_listen_addr,
 		ldk_announced_node_name,
+		accept_inbound,
+		debug_wire,
 		network,
+		allow_mainnet_switch,
+		user_config,
+		telemetry_endpoint,
+		heartbeat_endpoint,
+		heartbeat_interval,
+		webhook_endpoint,
+		webhook_secret,
+		payment_retention_days,
+		funding_wallet,
+		operating_wallet,
+		fund_from_confirmed_only,
+		internal_wallet,
+		fee_floors,
+		sweep_xpub,
+		sweep_address,
+		sweep_batch_window,
+		min_final_cltv_expiry,
+		min_claim_cltv_blocks,
+		fee_budget,
+		rotation_limits,
+		max_concurrent_opens,
+		max_htlc_exposure_msat: config.max_htlc_exposure_msat,
+		acceptor_policy,
+		maintenance,
+		max_payment_retries,
+		auto_reissue_invoices,
+		path_privacy,
+		rpc_listen_addr,
+		tor_control_addr,
+		proxy_addr,
+		bitcoind_zmq_addr,
+		esplora_url,
+		filter_peer_addr,
+		filter_check_peer_addrs,
+		header_cache_size,
+		max_graph_nodes,
+		payer_secret,
+		encrypt_seed,
+		password_file,
+		recover_backup,
+		monitor_replicas,
+		log_level,
+		log_format,
+		log_rotation,
+		notify_targets,
+		notify_events: config.notify_events.clone(),
+		storage_backend,
+		command,
 	})
 }
 
 pub(crate) async fn poll_for_user_input<E: EventHandler>(
-	invoice_payer: Arc<InvoicePayer<E>>, peer_manager: Arc<PeerManager>,
-	channel_manager: Arc<ChannelManager>, keys_manager: Arc<KeysManager>,
-	network_graph: Arc<NetworkGraph>, scorer: Arc<Mutex<Scorer>>,
-	inbound_payments: PaymentInfoStorage, outbound_payments: PaymentInfoStorage,
-	ldk_data_dir: String, logger: Arc<FilesystemLogger>, network: Network,
+	invoice_payer: Arc<InvoicePayer<E>>, payer: Arc<RouteRecordingPayer>,
+	peer_manager: Arc<PeerManager>, channel_manager: Arc<ChannelManager>,
+	keys_manager: Arc<KeysManager>, shutdown_keys: Arc<ShutdownKeysManager>,
+	output_sweeper: Arc<OutputSweeper>, network_graph: Arc<NetworkGraph>,
+	scorer: Arc<Mutex<Scorer>>, inbound_payments: PaymentInfoStorage,
+	outbound_payments: PaymentInfoStorage, invoice_bundles: BundleStorage,
+	scheduled_payments: ScheduleStorage, peer_uptime: PeerUptimeStorage,
+	channel_history: Arc<ChannelHistory>, forward_log: Arc<ForwardLog>,
+	wire_capture: Arc<WireCapture>, ldk_data_dir: String, storage: Arc<dyn Storage>,
+	logger: Arc<FilesystemLogger>, bitcoind_client: Arc<BitcoindClient>, network: Network,
+	min_final_cltv_expiry: u32, user_config: UserConfig, header_cache_len: Arc<AtomicUsize>,
+	fee_budget: FeeBudget, payer_secret: Option<[u8; 32]>, pause_switch: Arc<PauseSwitch>,
+	disk_monitor: Arc<DiskMonitor>, peer_limits: PeerLimitsStorage,
+	external_funding: ExternalFundingStorage, route_exclusions: RouteExclusionsStorage,
+	channel_rotation: Arc<ChannelRotation>, open_queue: Arc<OpenQueue>,
+	connection_guard: Arc<ConnectionGuard>, proxy_addr: Option<SocketAddr>,
+	command: Option<String>,
 ) {
 	println!("LDK startup successful. To view available commands: \"help\".");
 	println!("LDK logs are available at <your-supplied-ldk-data-dir-path>/.ldk/logs");
@@ -153,17 +1128,58 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 	let stdin = io::stdin();
 	print!("> ");
 	io::stdout().flush().unwrap(); // Without flushing, the `>` doesn't print
-	for line in stdin.lock().lines() {
+								// In non-interactive mode we only run the given command. Anything which would prompt for
+								// confirmation, such as opening a channel from a dedicated funding wallet, is aborted.
+	let mut lines: Box<dyn Iterator<Item = io::Result<String>>> = match command {
+		Some(command) => Box::new(std::iter::once(Ok(command))),
+		None => Box::new(stdin.lock().lines()),
+	};
+	while let Some(line) = lines.next() {
 		let line = line.unwrap();
 		let mut words = line.split_whitespace();
 		if let Some(word) = words.next() {
+			if pause_switch.is_held_for_disk() && PAUSED_COMMANDS.contains(&word) {
+				println!(
+					"ERROR: the node's disk is {}, free up space or fix it before using `{}`",
+					disk_monitor.status().as_str(),
+					word
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				continue;
+			}
+			if pause_switch.is_paused() && PAUSED_COMMANDS.contains(&word) {
+				println!("ERROR: the node is paused, `resume` it before using `{}`", word);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				continue;
+			}
 			match word {
 				"help" => help(),
+				"pause" => match pause_switch.pause() {
+					Ok(()) => println!("SUCCESS: paused the node"),
+					Err(e) => println!("ERROR: failed to pause the node: {}", e),
+				},
+				"resume" => match pause_switch.resume() {
+					Ok(()) if pause_switch.is_held_for_disk() => println!(
+						"SUCCESS: resumed the node, though it's still held as its disk is {}",
+						disk_monitor.status().as_str()
+					),
+					Ok(()) => {
+						// Release any HTLCs we held on to while paused, unless a maintenance
+						// window still holds them.
+						if !pause_switch.holds_forwards() {
+							channel_manager.process_pending_htlc_forwards();
+						}
+						println!("SUCCESS: resumed the node");
+					}
+					Err(e) => println!("ERROR: failed to resume the node: {}", e),
+				},
 				"openchannel" => {
 					let peer_pubkey_and_ip_addr = words.next();
 					let channel_value_sat = words.next();
 					if peer_pubkey_and_ip_addr.is_none() || channel_value_sat.is_none() {
-						println!("ERROR: openchannel has 2 required arguments: `openchannel pubkey@host:port channel_amt_satoshis` [--public]");
+						println!("ERROR: openchannel has 2 required arguments: `openchannel pubkey@host:port channel_amt_satoshis` [--public|--private] [--psbt]");
 						print!("> ");
 						io::stdout().flush().unwrap();
 						continue;
@@ -188,46 +1204,82 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 						continue;
 					}
 
-					if connect_peer_if_necessary(pubkey, peer_addr, peer_manager.clone())
-						.await
-						.is_err()
+					if connect_peer_if_necessary(
+						pubkey,
+						peer_addr,
+						peer_manager.clone(),
+						proxy_addr,
+					)
+					.await
+					.is_err()
 					{
 						print!("> ");
 						io::stdout().flush().unwrap();
 						continue;
 					};
 
-					let announce_channel = match words.next() {
-						Some("--public") | Some("--public=true") => true,
-						Some("--public=false") => false,
-						Some(_) => {
-							println!("ERROR: invalid `--public` command format. Valid formats: `--public`, `--public=true` `--public=false`");
-							print!("> ");
-							io::stdout().flush().unwrap();
-							continue;
+					let mut announce_channel = user_config.channel_options.announced_channel;
+					let mut fund_externally = false;
+					let mut invalid_flag = false;
+					for flag in words.by_ref() {
+						match flag {
+							"--public" | "--public=true" => announce_channel = true,
+							"--private" | "--public=false" => announce_channel = false,
+							"--psbt" => fund_externally = true,
+							_ => {
+								println!("ERROR: invalid openchannel flag {}. Valid flags: `--public`, `--public=true`, `--public=false`, `--private`, `--psbt`", flag);
+								invalid_flag = true;
+								break;
+							}
 						}
-						None => false,
-					};
+					}
+					if invalid_flag {
+						print!("> ");
+						io::stdout().flush().unwrap();
+						continue;
+					}
+
+					// Opening a channel spends from the funding wallet, which operators who set one
+					// up explicitly presumably want to guard more carefully. Externally funded opens
+					// don't touch it.
+					if let Some(wallet) =
+						bitcoind_client.funding_wallet().filter(|_| !fund_externally)
+					{
+						print!(
+							"Open a {} sat channel funded from bitcoind wallet {}? [y/N] ",
+							chan_amt_sat.as_ref().unwrap(),
+							wallet
+						);
+						io::stdout().flush().unwrap();
+						match lines.next() {
+							Some(Ok(answer)) if answer.trim() == "y" || answer.trim() == "yes" => {}
+							_ => {
+								println!("Aborted channel open");
+								print!("> ");
+								io::stdout().flush().unwrap();
+								continue;
+							}
+						}
+					}
 
 					if open_channel(
 						pubkey,
 						chan_amt_sat.unwrap(),
 						announce_channel,
+						user_config,
 						channel_manager.clone(),
+						&peer_limits,
+						if fund_externally { Some(&external_funding) } else { None },
 					)
 					.is_ok()
 					{
-						let peer_data_path = format!("{}/channel_peer_data", ldk_data_dir.clone());
-						let _ = disk::persist_channel_peer(
-							Path::new(&peer_data_path),
-							peer_pubkey_and_ip_addr,
-						);
+						let _ = disk::persist_channel_peer(&*storage, peer_pubkey_and_ip_addr);
 					}
 				}
 				"sendpayment" => {
 					let invoice_str = words.next();
 					if invoice_str.is_none() {
-						println!("ERROR: sendpayment requires an invoice: `sendpayment <invoice>`");
+						println!("ERROR: sendpayment requires an invoice: `sendpayment <invoice> [<amt_millisatoshis>]`");
 						print!("> ");
 						io::stdout().flush().unwrap();
 						continue;
@@ -242,9 +1294,100 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 							continue;
 						}
 					};
+					if let Err(e) = check_invoice_network(&invoice, network) {
+						println!("ERROR: {}", e);
+						print!("> ");
+						io::stdout().flush().unwrap();
+						continue;
+					}
+					// An amount may follow the invoice, for invoices which leave it to the payer.
+					let mut args = words.by_ref().peekable();
+					let amt_override =
+						match args.peek() {
+							Some(amt) if !amt.starts_with("--") => match amt.parse::<u64>() {
+								Ok(amt_msat) => {
+									args.next();
+									Some(amt_msat)
+								}
+								Err(_) => {
+									println!("ERROR: sendpayment provided payment amount was not a number");
+									print!("> ");
+									io::stdout().flush().unwrap();
+									continue;
+								}
+							},
+							_ => None,
+						};
+					let amt_msat = match invoice_amount_msat(&invoice, amt_override) {
+						Ok(amt_msat) => amt_msat,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					let (limits, privacy) = match parse_send_flags(args, amt_msat) {
+						Ok(flags) => flags,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
 
-					send_payment(&*invoice_payer, &invoice, outbound_payments.clone());
-				}
+					let _ = send_payment(
+						&*invoice_payer,
+						&payer,
+						&invoice,
+						amt_msat,
+						limits,
+						privacy,
+						outbound_payments.clone(),
+					);
+				}
+				"refund" => {
+					let (payment_hash, invoice_str) =
+						match (words.next().and_then(hex_utils::to_vec), words.next()) {
+							(Some(hash), Some(invoice_str)) if hash.len() == 32 => {
+								let mut payment_hash = [0; 32];
+								payment_hash.copy_from_slice(&hash);
+								(PaymentHash(payment_hash), invoice_str)
+							}
+							_ => {
+								println!("ERROR: refund usage: `refund <payment_hash> <invoice>`");
+								print!("> ");
+								io::stdout().flush().unwrap();
+								continue;
+							}
+						};
+					let invoice = match Invoice::from_str(invoice_str) {
+						Ok(inv) => inv,
+						Err(e) => {
+							println!("ERROR: invalid invoice: {:?}", e);
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					if let Err(e) = check_invoice_network(&invoice, network) {
+						println!("ERROR: {}", e);
+						print!("> ");
+						io::stdout().flush().unwrap();
+						continue;
+					}
+					if let Err(e) = refund(
+						&payment_hash,
+						&invoice,
+						&*invoice_payer,
+						&payer,
+						&inbound_payments,
+						outbound_payments.clone(),
+					) {
+						println!("ERROR: can't refund payment: {}", e);
+					}
+				}
 				"keysend" => {
 					let dest_pubkey = match words.next() {
 						Some(dest) => match hex_utils::to_compressed_pubkey(dest) {
@@ -290,8 +1433,107 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 						outbound_payments.clone(),
 						logger.clone(),
 						scorer.clone(),
+						fee_budget,
+						payer_secret,
+						route_exclusions.clone(),
 					);
 				}
+				"schedulepayment" => {
+					let args: Vec<&str> = words.by_ref().collect();
+					let parsed = match args.as_slice() {
+						[dest, amt, interval] => match (
+							hex_utils::to_compressed_pubkey(dest),
+							amt.parse::<u64>(),
+							interval.parse::<u64>(),
+						) {
+							(Some(dest), Ok(amt), Ok(interval)) if interval > 0 => {
+								Some((dest, amt, interval))
+							}
+							_ => None,
+						},
+						_ => None,
+					};
+					match parsed {
+						Some((dest_pubkey, amt_msat, interval_secs)) => {
+							let schedule_id = scheduled_payments.lock().unwrap().insert(
+								dest_pubkey,
+								amt_msat,
+								interval_secs,
+							);
+							println!("SUCCESS: scheduled payment {}, first due in {} seconds", schedule_id, interval_secs);
+						}
+						None => println!("ERROR: schedulepayment usage: `schedulepayment <dest_pubkey> <amt_msat> <interval_secs>`"),
+					}
+				}
+				"excludenode" => {
+					let args: Vec<&str> = words.by_ref().collect();
+					let parsed = match args.as_slice() {
+						[node] => hex_utils::to_compressed_pubkey(node).map(|node| (node, None)),
+						[node, duration] => {
+							match (hex_utils::to_compressed_pubkey(node), duration.parse::<u64>()) {
+								(Some(node), Ok(duration)) => Some((node, Some(duration))),
+								_ => None,
+							}
+						}
+						_ => None,
+					};
+					match parsed {
+						Some((node, duration_secs)) => {
+							route_exclusions.lock().unwrap().exclude_node(node, duration_secs);
+							println!("SUCCESS: excluded node {} from payment routes", node);
+						}
+						None => println!(
+							"ERROR: excludenode usage: `excludenode <pubkey> [<duration_secs>]`"
+						),
+					}
+				}
+				"excludechannel" => {
+					let args: Vec<&str> = words.by_ref().collect();
+					let parsed = match args.as_slice() {
+						[scid] => scid.parse::<u64>().ok().map(|scid| (scid, None)),
+						[scid, duration] => match (scid.parse::<u64>(), duration.parse::<u64>()) {
+							(Ok(scid), Ok(duration)) => Some((scid, Some(duration))),
+							_ => None,
+						},
+						_ => None,
+					};
+					match parsed {
+						Some((scid, duration_secs)) => {
+							route_exclusions.lock().unwrap().exclude_channel(scid, duration_secs);
+							println!("SUCCESS: excluded channel {} from payment routes", scid);
+						}
+						None => println!("ERROR: excludechannel usage: `excludechannel <short_channel_id> [<duration_secs>]`"),
+					}
+				}
+				"unexclude" => {
+					let mut exclusions = route_exclusions.lock().unwrap();
+					let removed = match words.next() {
+						Some(id) => match hex_utils::to_compressed_pubkey(id) {
+							Some(node) => Some(exclusions.remove_node(&node)),
+							None => id.parse().ok().map(|scid| exclusions.remove_channel(scid)),
+						},
+						None => None,
+					};
+					match removed {
+						Some(true) => println!("SUCCESS: lifted the exclusion"),
+						Some(false) => println!("ERROR: that node or channel isn't excluded"),
+						None => println!(
+							"ERROR: unexclude usage: `unexclude <pubkey|short_channel_id>`"
+						),
+					}
+				}
+				"listexclusions" => list_exclusions(&route_exclusions),
+				"listschedules" => list_schedules(scheduled_payments.clone()),
+				"cancelschedule" => match words.next().map(str::parse) {
+					Some(Ok(schedule_id)) => {
+						if scheduled_payments.lock().unwrap().remove(schedule_id) {
+							println!("SUCCESS: cancelled scheduled payment {}", schedule_id);
+						} else {
+							println!("ERROR: unknown scheduled payment {}", schedule_id);
+						}
+					}
+					_ => println!("ERROR: cancelschedule usage: `cancelschedule <schedule_id>`"),
+				},
 				"getinvoice" => {
 					let amt_str = words.next();
 					if amt_str.is_none() {
@@ -308,14 +1550,78 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 						io::stdout().flush().unwrap();
 						continue;
 					}
-					get_invoice(
+					let options = match parse_invoice_options(words.by_ref()) {
+						Ok(options) => options,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					let _ = get_invoice(
 						amt_msat.unwrap(),
+						options,
 						inbound_payments.clone(),
 						channel_manager.clone(),
 						keys_manager.clone(),
 						network,
+						min_final_cltv_expiry,
 					);
 				}
+				"reissueinvoice" => {
+					let payment_hash = match words.next().and_then(hex_utils::to_vec) {
+						Some(hash) if hash.len() == 32 => {
+							let mut payment_hash = [0; 32];
+							payment_hash.copy_from_slice(&hash);
+							PaymentHash(payment_hash)
+						}
+						_ => {
+							println!(
+								"ERROR: reissueinvoice usage: `reissueinvoice <payment_hash>`"
+							);
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					match reissue_invoice(
+						&payment_hash,
+						&inbound_payments,
+						&invoice_bundles,
+						&channel_manager,
+						&keys_manager,
+						network,
+						min_final_cltv_expiry,
+					) {
+						Ok(invoice) => println!("SUCCESS: re-issued invoice: {}", invoice),
+						Err(e) => println!("ERROR: can't re-issue invoice: {}", e),
+					}
+				}
+				"createbundle" => {
+					let amounts_msat: Result<Vec<u64>, _> =
+						words.by_ref().map(str::parse).collect();
+					match amounts_msat {
+						Ok(amounts_msat) if !amounts_msat.is_empty() => {
+							let _ = create_bundle(
+								&amounts_msat,
+								invoice_bundles.clone(),
+								inbound_payments.clone(),
+								channel_manager.clone(),
+								keys_manager.clone(),
+								network,
+								min_final_cltv_expiry,
+							);
+						}
+						_ => println!("ERROR: createbundle usage: `createbundle <amt_millisatoshis> [<amt_millisatoshis>...]`"),
+					}
+				}
+				"bundlestatus" => match words.next().map(str::parse) {
+					Some(Ok(bundle_id)) => {
+						bundle_status(bundle_id, invoice_bundles.clone(), inbound_payments.clone())
+					}
+					_ => println!("ERROR: bundlestatus usage: `bundlestatus <bundle_id>`"),
+				},
 				"connectpeer" => {
 					let peer_pubkey_and_ip_addr = words.next();
 					if peer_pubkey_and_ip_addr.is_none() {
@@ -334,21 +1640,127 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 								continue;
 							}
 						};
-					if connect_peer_if_necessary(pubkey, peer_addr, peer_manager.clone())
-						.await
-						.is_ok()
+					if connect_peer_if_necessary(
+						pubkey,
+						peer_addr,
+						peer_manager.clone(),
+						proxy_addr,
+					)
+					.await
+					.is_ok()
 					{
 						println!("SUCCESS: connected to peer {}", pubkey);
 					}
 				}
-				"listchannels" => list_channels(channel_manager.clone()),
-				"listpayments" => {
-					list_payments(inbound_payments.clone(), outbound_payments.clone())
+				"listchannels" => {
+					let verbose = match words.next() {
+						Some("--verbose") => true,
+						Some(_) => {
+							println!("ERROR: listchannels usage: `listchannels [--verbose]`");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+						None => false,
+					};
+					list_channels(
+						channel_manager.list_channels(),
+						channel_manager.current_best_block().height(),
+						Some(balances::commitment_feerate(&bitcoind_client)),
+						if verbose { Some(&channel_rotation) } else { None },
+					)
+				}
+				"getbalance" => get_balance(&channel_manager, &bitcoind_client).await,
+				"getfees" => get_fees(&bitcoind_client),
+				"gettransaction" => match words.next().map(Txid::from_str) {
+					Some(Ok(txid)) => get_transaction(&txid, &bitcoind_client).await,
+					_ => println!("ERROR: gettransaction usage: `gettransaction <txid>`"),
+				},
+				"listpayments" | "paymenthistory" => {
+					let verbose = match words.next() {
+						Some("--verbose") => true,
+						Some(_) => {
+							println!("ERROR: listpayments usage: `listpayments [--verbose]`");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+						None => false,
+					};
+					list_payments(inbound_payments.clone(), outbound_payments.clone(), verbose)
+				}
+				"trackpayment" => {
+					let payment_hash = match words.next().and_then(hex_utils::to_vec) {
+						Some(hash) if hash.len() == 32 => {
+							let mut payment_hash = [0; 32];
+							payment_hash.copy_from_slice(&hash);
+							PaymentHash(payment_hash)
+						}
+						_ => {
+							println!("ERROR: trackpayment usage: `trackpayment <payment_hash>`");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					track_payment(
+						&payment_hash,
+						inbound_payments.clone(),
+						outbound_payments.clone(),
+					)
+				}
+				"getreceipt" => {
+					let payment_hash = match words.next().and_then(hex_utils::to_vec) {
+						Some(hash) if hash.len() == 32 => {
+							let mut payment_hash = [0; 32];
+							payment_hash.copy_from_slice(&hash);
+							PaymentHash(payment_hash)
+						}
+						_ => {
+							println!("ERROR: getreceipt usage: `getreceipt <payment_hash>`");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					get_receipt(&payment_hash, &inbound_payments, &outbound_payments, &keys_manager)
+				}
+				"fundchannel" => {
+					let (channel_id_str, psbt) = match (words.next(), words.next()) {
+						(Some(channel_id_str), Some(psbt)) => (channel_id_str, psbt),
+						_ => {
+							println!("ERROR: fundchannel usage: `fundchannel <temporary_channel_id> <signed_psbt_or_raw_tx>`");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					let channel_id = match hex_utils::to_vec(channel_id_str) {
+						Some(channel_id_vec) if channel_id_vec.len() == 32 => {
+							let mut channel_id = [0; 32];
+							channel_id.copy_from_slice(&channel_id_vec);
+							channel_id
+						}
+						_ => {
+							println!("ERROR: couldn't parse channel_id as hex");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					fund_channel(
+						channel_id,
+						psbt,
+						&bitcoind_client,
+						&channel_manager,
+						&external_funding,
+					)
+					.await;
 				}
 				"closechannel" => {
 					let channel_id_str = words.next();
 					if channel_id_str.is_none() {
-						println!("ERROR: closechannel requires a channel ID: `closechannel <channel_id>`");
+						println!("ERROR: closechannel requires a channel ID: `closechannel <channel_id> [<address>]`");
 						print!("> ");
 						io::stdout().flush().unwrap();
 						continue;
@@ -362,7 +1774,25 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 					}
 					let mut channel_id = [0; 32];
 					channel_id.copy_from_slice(&channel_id_vec.unwrap());
-					close_channel(channel_id, channel_manager.clone());
+					// Optionally pay our balance somewhere other than our wallet.
+					let close_script =
+						match words.next().map(|addr| parse_close_address(addr, network)) {
+							Some(Ok(script)) => Some(script),
+							Some(Err(e)) => {
+								println!("ERROR: {}", e);
+								print!("> ");
+								io::stdout().flush().unwrap();
+								continue;
+							}
+							None => None,
+						};
+					close_channel(
+						channel_id,
+						close_script,
+						channel_manager.clone(),
+						&shutdown_keys,
+						&bitcoind_client,
+					);
 				}
 				"forceclosechannel" => {
 					let channel_id_str = words.next();
@@ -381,10 +1811,364 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 					}
 					let mut channel_id = [0; 32];
 					channel_id.copy_from_slice(&channel_id_vec.unwrap());
-					force_close_channel(channel_id, channel_manager.clone());
+					force_close_channel(channel_id, channel_manager.clone(), &bitcoind_client);
+				}
+				"walletbalance" => wallet_balance(&bitcoind_client).await,
+				"listunspent" | "newaddress" => {
+					let role = match words.next() {
+						None => WalletRole::Funding,
+						Some("--operating") => WalletRole::Operating,
+						Some(_) => {
+							println!("ERROR: {} usage: `{} [--operating]`", word, word);
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					if word == "listunspent" {
+						list_unspent(role, &bitcoind_client).await;
+					} else {
+						match bitcoind_client.get_wallet_address(role).await {
+							Ok(address) => println!("{}", address),
+							Err(e) => println!("ERROR: failed to get a new address: {}", e),
+						}
+					}
+				}
+				"listspendableoutputs" => list_spendable_outputs(&output_sweeper),
+				"mine" => {
+					if network != Network::Regtest {
+						println!("ERROR: mine is only available on regtest");
+						print!("> ");
+						io::stdout().flush().unwrap();
+						continue;
+					}
+					let num_blocks: u64 = match words.next().map(|n| n.parse()) {
+						Some(Ok(n)) => n,
+						Some(Err(_)) => {
+							println!("ERROR: number of blocks must be a number");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+						None => 1,
+					};
+					mine(num_blocks, bitcoind_client.clone()).await;
+				}
+				"faucet" => {
+					if network != Network::Regtest {
+						println!("ERROR: faucet is only available on regtest");
+						print!("> ");
+						io::stdout().flush().unwrap();
+						continue;
+					}
+					let amt_sat: u64 = match words.next().map(|amt| amt.parse()) {
+						Some(Ok(amt)) => amt,
+						Some(Err(_)) => {
+							println!("ERROR: faucet amount must be a number");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+						None => {
+							println!("ERROR: faucet requires an amount in satoshis: `faucet <amt_satoshis> [address]`");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					let address = match words.next().map(Address::from_str) {
+						Some(Ok(addr)) => Some(addr),
+						Some(Err(e)) => {
+							println!("ERROR: couldn't parse address: {}", e);
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+						None => None,
+					};
+					faucet(amt_sat, address, bitcoind_client.clone()).await;
+				}
+				"balancehistory" => {
+					let mut num_snapshots = 24;
+					let mut show_channels = false;
+					let mut parse_err = false;
+					for arg in words.by_ref() {
+						match arg {
+							"--channels" => show_channels = true,
+							n => match n.parse() {
+								Ok(n) => num_snapshots = n,
+								Err(_) => parse_err = true,
+							},
+						}
+					}
+					if parse_err {
+						println!("ERROR: balancehistory usage: `balancehistory [<num_snapshots>] [--channels]`");
+						print!("> ");
+						io::stdout().flush().unwrap();
+						continue;
+					}
+					let balance_history_path = format!("{}/balance_history", ldk_data_dir.clone());
+					balance_history(Path::new(&balance_history_path), num_snapshots, show_channels);
+				}
+				"nodeinfo" => node_info(
+					channel_manager.clone(),
+					peer_manager.clone(),
+					&pause_switch,
+					&disk_monitor,
+				),
+				"channelhistory" => {
+					let channel_id = match words.next().and_then(hex_utils::to_vec) {
+						Some(id) if id.len() == 32 => {
+							let mut channel_id = [0; 32];
+							channel_id.copy_from_slice(&id);
+							channel_id
+						}
+						_ => {
+							println!("ERROR: channelhistory usage: `channelhistory <channel_id>`");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+					};
+					print_channel_history(&channel_id, &channel_history);
+				}
+				"resolvechannel" => match words.next().map(ChannelQuery::parse) {
+					Some(Ok(query)) => {
+						resolve_channel(&query, &channel_manager, &network_graph, &channel_history)
+					}
+					Some(Err(e)) => println!("ERROR: {}", e),
+					None => println!(
+						"ERROR: resolvechannel usage: `resolvechannel <short_channel_id|txid:vout|channel_id>`"
+					),
+				},
+				"listpeers" => list_peers(peer_manager.get_peer_node_ids(), peer_uptime.clone()),
+				"dumpwire" => match words.next().and_then(hex_utils::to_compressed_pubkey) {
+					Some(pubkey) => dump_wire(&pubkey, &wire_capture),
+					None => println!("ERROR: dumpwire usage: `dumpwire <pubkey>`"),
+				},
+				"advisor" => advise(&channel_manager, &network_graph, &peer_uptime, &user_config),
+				"openqueue" => match words.next() {
+					Some("status") => list_open_queue(&open_queue),
+					Some("add") => {
+						let usage = "ERROR: openqueue add usage: `openqueue add pubkey@host:port <amt_satoshis> [--public|--private]`";
+						let (peer_pubkey_and_ip_addr, chan_amt_sat) =
+							match (words.next(), words.next().map(str::parse::<u64>)) {
+								(Some(peer), Some(Ok(amt))) => (peer, amt),
+								_ => {
+									println!("{}", usage);
+									print!("> ");
+									io::stdout().flush().unwrap();
+									continue;
+								}
+							};
+						let (pubkey, peer_addr) =
+							match parse_peer_info(peer_pubkey_and_ip_addr.to_string()) {
+								Ok(info) => info,
+								Err(e) => {
+									println!("{:?}", e.into_inner().unwrap());
+									print!("> ");
+									io::stdout().flush().unwrap();
+									continue;
+								}
+							};
+						let mut announce_channel = user_config.channel_options.announced_channel;
+						let mut invalid_flag = false;
+						for flag in words.by_ref() {
+							match flag {
+								"--public" | "--public=true" => announce_channel = true,
+								"--private" | "--public=false" => announce_channel = false,
+								_ => invalid_flag = true,
+							}
+						}
+						if invalid_flag {
+							println!("{}", usage);
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+						// Queued opens are funded in the background, so there's no one to hand a
+						// PSBT to.
+						if bitcoind_client.funding_wallet_watch_only() {
+							println!("ERROR: the funding wallet can't sign, use `openchannel` to fund channels externally");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+						if let Some(wallet) = bitcoind_client.funding_wallet() {
+							print!(
+								"Queue a {} sat channel funded from bitcoind wallet {}? [y/N] ",
+								chan_amt_sat, wallet
+							);
+							io::stdout().flush().unwrap();
+							match lines.next() {
+								Some(Ok(answer)) if answer.trim() == "y" || answer.trim() == "yes" => {}
+								_ => {
+									println!("Aborted channel open");
+									print!("> ");
+									io::stdout().flush().unwrap();
+									continue;
+								}
+							}
+						}
+						let id = open_queue.add(
+							pubkey,
+							peer_pubkey_and_ip_addr.to_string(),
+							peer_addr,
+							chan_amt_sat,
+							announce_channel,
+						);
+						println!("SUCCESS: queued open #{} with {}", id, pubkey);
+					}
+					_ => println!(
+						"ERROR: openqueue usage: `openqueue add pubkey@host:port <amt_satoshis> [--public|--private]` or `openqueue status`"
+					),
+				},
+				"setloglevel" => match words.next().map(disk::parse_log_level) {
+					Some(Ok(min_level)) => {
+						logger.set_min_level(min_level);
+						println!(
+							"SUCCESS: logging {}",
+							min_level.map_or("all records".to_string(), |level| format!(
+								"records at level {} and above",
+								level.to_string().to_lowercase()
+							))
+						);
+					}
+					Some(Err(e)) => println!("ERROR: {}", e),
+					None => println!(
+						"ERROR: setloglevel usage: `setloglevel <all|trace|debug|info|warn|error>`"
+					),
+				},
+				"peerlimits" => match words.next().and_then(hex_utils::to_compressed_pubkey) {
+					Some(pubkey) => {
+						peer_limits_info(pubkey, channel_manager.list_channels(), &peer_limits)
+					}
+					None => println!("ERROR: peerlimits usage: `peerlimits <pubkey>`"),
+				},
+				"feebudget" => match words.next().and_then(hex_utils::to_compressed_pubkey) {
+					Some(pubkey) => {
+						counterparty_fee_budget_info(pubkey, fee_budget, &outbound_payments)
+					}
+					None => println!("ERROR: feebudget usage: `feebudget <pubkey>`"),
+				},
+				"exportstate" => {
+					let mut sections =
+						ExportSections { payments: false, invoices: false, peers: false };
+					let mut path = None;
+					let mut valid = true;
+					for arg in words.by_ref() {
+						match arg {
+							"--payments" => sections.payments = true,
+							"--invoices" => sections.invoices = true,
+							"--peers" => sections.peers = true,
+							_ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+							_ => valid = false,
+						}
+					}
+					match path {
+						Some(path) if valid => {
+							// With no sections picked, export everything we can.
+							if !(sections.payments || sections.invoices || sections.peers) {
+								sections =
+									ExportSections { payments: true, invoices: true, peers: true };
+							}
+							export_state(
+								Path::new(path),
+								&sections,
+								channel_manager.get_our_node_id(),
+								&node_state(
+									&inbound_payments,
+									&outbound_payments,
+									&peer_limits,
+									&*storage,
+								),
+							)
+						}
+						_ => println!(
+							"ERROR: exportstate usage: `exportstate [--payments] [--invoices] [--peers] <file>`"
+						),
+					}
+				}
+				"exportledger" => {
+					let args: Vec<&str> = words.by_ref().collect();
+					let parsed = match args.as_slice() {
+						[format, from, to, path] => {
+							match (LedgerFormat::from_str(format), parse_date(from), parse_date(to))
+							{
+								(Some(format), Some(from), Some(to)) if from <= to => {
+									Some((format, from, to, path))
+								}
+								_ => None,
+							}
+						}
+						_ => None,
+					};
+					match parsed {
+						// The range takes in all of its last day.
+						Some((format, from, to, path)) => match accounting::export_ledger(
+							Path::new(path),
+							format,
+							from,
+							to + 24 * 60 * 60,
+							&inbound_payments,
+							&outbound_payments,
+							&forward_log,
+							&channel_history,
+							&bitcoind_client,
+						)
+						.await
+						{
+							Ok(num_entries) => println!(
+								"SUCCESS: exported {} accounting entries to {}",
+								num_entries, path
+							),
+							Err(e) => println!("ERROR: failed to export accounting entries: {}", e),
+						},
+						None => println!("ERROR: exportledger usage: `exportledger <beancount|ledger> <from_date> <to_date> <file>`, with dates as YYYY-MM-DD"),
+					}
+				}
+				"fwdinghistory" => {
+					let from = words.next().map(parse_date);
+					let to = words.next().map(parse_date);
+					match (from, to) {
+						(Some(None), _) | (_, Some(None)) => println!("ERROR: fwdinghistory usage: `fwdinghistory [<from_date> [<to_date>]]`, with dates as YYYY-MM-DD"),
+						// The range takes in all of its last day.
+						(from, to) => forwarding_history(
+							&forward_log,
+							from.flatten().unwrap_or(0),
+							to.flatten().map_or(u64::max_value(), |to| to + 24 * 60 * 60),
+						),
+					}
 				}
-				"nodeinfo" => node_info(channel_manager.clone(), peer_manager.clone()),
-				"listpeers" => list_peers(peer_manager.clone()),
+				"exportbackup" => match words.next() {
+					Some(path) => match channel_backup::export_backup(
+						Path::new(path),
+						&channel_manager,
+						shutdown_keys.channel_keys_log(),
+						&*storage,
+						&keys_manager.get_node_secret(),
+					) {
+						Ok(num_channels) => {
+							println!("SUCCESS: backed up {} channels to {}", num_channels, path)
+						}
+						Err(e) => println!("ERROR: failed to export the channel backup: {}", e),
+					},
+					None => println!("ERROR: exportbackup usage: `exportbackup <file>`"),
+				},
+				"importstate" => match words.next() {
+					Some(path) => import_state(
+						Path::new(path),
+						&node_state(&inbound_payments, &outbound_payments, &peer_limits, &*storage),
+					),
+					None => println!("ERROR: importstate usage: `importstate <file>`"),
+				},
+				"debugstats" => debug_stats(
+					&network_graph,
+					&header_cache_len,
+					&inbound_payments,
+					&outbound_payments,
+					&connection_guard,
+				),
 				"signmessage" => {
 					const MSG_STARTPOS: usize = "signmsg".len() + 1;
 					if line.as_bytes().len() <= MSG_STARTPOS {
@@ -411,125 +2195,770 @@ pub(crate) async fn poll_for_user_input<E: EventHandler>(
 	}
 }
 
+/// Waits for outbound payments which are still pending to either succeed or fail, so that a
+/// non-interactive `sendpayment` or `keysend` reports its outcome before the node shuts down.
+pub(crate) async fn wait_for_pending_payments(outbound_payments: PaymentInfoStorage) {
+	for _ in 0..PENDING_PAYMENT_WAIT_SECS {
+		let any_pending =
+			outbound_payments.lock().unwrap().values().any(|payment| match payment.status {
+				HTLCStatus::Pending => true,
+				_ => false,
+			});
+		if !any_pending {
+			return;
+		}
+		tokio::time::sleep(Duration::from_secs(1)).await;
+	}
+	println!("Warning: Shutting down with outbound payments still pending");
+}
+
 fn help() {
-	println!("openchannel pubkey@host:port <amt_satoshis>");
-	println!("sendpayment <invoice>");
-	println!("getinvoice <amt_millisatoshis>");
+	println!("openchannel pubkey@host:port <amt_satoshis> [--public|--private] [--psbt]");
+	println!("openqueue add pubkey@host:port <amt_satoshis> [--public|--private]");
+	println!("openqueue status");
+	println!(
+		"sendpayment <invoice> [<amt_millisatoshis>] [--max-retries <n>] [--max-fee-msat <msat>] [--max-fee-percent <percent>] [--timeout <secs>] [--deadline <secs>] [--[no-]shadow-route] [--[no-]randomize-cltv]"
+	);
+	println!("keysend <dest_pubkey> <amt_millisatoshis>");
+	println!("refund <payment_hash> <invoice>");
+	println!(
+		"getinvoice <amt_millisatoshis> [--expiry <secs>] [--description-hash <hex>] [--description <text...>]"
+	);
+	println!("reissueinvoice <payment_hash>");
+	println!("schedulepayment <dest_pubkey> <amt_msat> <interval_secs>");
+	println!("excludenode <pubkey> [<duration_secs>]");
+	println!("excludechannel <short_channel_id> [<duration_secs>]");
+	println!("unexclude <pubkey|short_channel_id>");
+	println!("listexclusions");
+	println!("listschedules");
+	println!("cancelschedule <schedule_id>");
+	println!("createbundle <amt_millisatoshis> [<amt_millisatoshis>...]");
+	println!("bundlestatus <bundle_id>");
 	println!("connectpeer pubkey@host:port");
-	println!("listchannels");
-	println!("listpayments");
-	println!("closechannel <channel_id>");
+	println!("listchannels [--verbose]");
+	println!("channelhistory <channel_id>");
+	println!("resolvechannel <short_channel_id|txid:vout|channel_id>");
+	println!("dumpwire <pubkey> (with --debug-wire)");
+	println!("getbalance");
+	println!("listpayments [--verbose] (alias: paymenthistory)");
+	println!("trackpayment <payment_hash>");
+	println!("getreceipt <payment_hash>");
+	println!("fundchannel <temporary_channel_id> <signed_psbt_or_raw_tx>");
+	println!("closechannel <channel_id> [<address>]");
 	println!("forceclosechannel <channel_id>");
 	println!("nodeinfo");
+	println!("pause");
+	println!("resume");
+	println!("setloglevel <all|trace|debug|info|warn|error>");
 	println!("listpeers");
+	println!("peerlimits <pubkey>");
+	println!("feebudget <pubkey>");
+	println!("advisor");
+	println!("exportstate [--payments] [--invoices] [--peers] <file>");
+	println!("importstate <file>");
+	println!("exportledger <beancount|ledger> <from_date> <to_date> <file>");
+	println!("fwdinghistory [<from_date> [<to_date>]]");
+	println!("exportbackup <file>");
+	println!("debugstats");
 	println!("signmessage <message>");
+	println!("balancehistory [<num_snapshots>] [--channels]");
+	println!("walletbalance");
+	println!("getfees");
+	println!("gettransaction <txid>");
+	println!("listunspent [--operating]");
+	println!("newaddress [--operating]");
+	println!("listspendableoutputs");
+	println!("mine [<num_blocks>] (regtest only)");
+	println!("faucet <amt_satoshis> [address] (regtest only)");
+}
+
+fn print_channel_history(channel_id: &[u8; 32], channel_history: &ChannelHistory) {
+	let known = channel_history.with_timeline(channel_id, |events| {
+		print!("[");
+		for event in events {
+			println!("");
+			println!("\t{{");
+			println!("\t\ttimestamp: {},", event.timestamp);
+			println!("\t\tevent: {},", event.kind.as_str());
+			if !event.detail.is_empty() {
+				println!("\t\tdetail: {},", event.detail);
+			}
+			print!("\t}},");
+		}
+		println!("]");
+	});
+	if !known {
+		println!("ERROR: no history for channel {}", hex_utils::hex_str(channel_id));
+	}
+}
+
+fn resolve_channel(
+	query: &ChannelQuery, channel_manager: &ChannelManager, network_graph: &NetworkGraph,
+	channel_history: &ChannelHistory,
+) {
+	let resolved =
+		match channel_lookup::resolve(query, channel_manager, network_graph, channel_history) {
+			Some(resolved) => resolved,
+			None => {
+				println!("ERROR: no channel of ours or in the network graph matches");
+				return;
+			}
+		};
+	println!("\t{{");
+	if let Some(channel_id) = resolved.channel_id {
+		println!("\t\tchannel_id: {},", hex_utils::hex_str(&channel_id));
+	}
+	if let Some(funding_txo) = resolved.funding_txo {
+		println!("\t\tfunding_outpoint: {},", funding_txo);
+	}
+	if let Some(scid) = resolved.short_channel_id {
+		println!("\t\tshort_channel_id: {} ({}),", scid, channel_lookup::format_scid(scid));
+	}
+	if let Some(counterparty) = resolved.counterparty {
+		println!("\t\tpeer_pubkey: {},", counterparty);
+	}
+	if let Some((node_one, node_two)) = resolved.announced_nodes {
+		println!("\t\tannounced_node_one: {},", node_one);
+		println!("\t\tannounced_node_two: {},", node_two);
+	}
+	if let Some(capacity_sat) = resolved.capacity_sat {
+		println!("\t\tcapacity_sat: {},", capacity_sat);
+	}
+	println!("\t\tstatus: {},", resolved.status);
+	println!("\t}},");
+}
+
+fn dump_wire(node_id: &PublicKey, wire_capture: &WireCapture) {
+	if !wire_capture.enabled() {
+		println!("ERROR: Messages aren't being captured, start the node with --debug-wire");
+		return;
+	}
+	let known = wire_capture.with_records(node_id, |records| {
+		print!("[");
+		for record in records {
+			println!("");
+			println!("\t{{");
+			println!("\t\ttimestamp: {},", record.timestamp);
+			println!("\t\tdirection: {},", record.direction.as_str());
+			println!("\t\ttype: {},", record.msg_type);
+			println!("\t\tlen: {},", record.len);
+			if !record.fields.is_empty() {
+				println!("\t\tfields: {},", record.fields);
+			}
+			print!("\t}},");
+		}
+		println!("]");
+	});
+	if !known {
+		println!("ERROR: No messages captured for peer {}", node_id);
+	}
 }
 
-fn node_info(channel_manager: Arc<ChannelManager>, peer_manager: Arc<PeerManager>) {
+fn node_info(
+	channel_manager: Arc<ChannelManager>, peer_manager: Arc<PeerManager>,
+	pause_switch: &PauseSwitch, disk_monitor: &DiskMonitor,
+) {
 	println!("\t{{");
 	println!("\t\t node_pubkey: {}", channel_manager.get_our_node_id());
 	println!("\t\t num_channels: {}", channel_manager.list_channels().len());
 	println!("\t\t num_usable_channels: {}", channel_manager.list_usable_channels().len());
 	println!("\t\t num_peers: {}", peer_manager.get_peer_node_ids().len());
+	println!("\t\t paused: {}", pause_switch.is_paused());
+	println!("\t\t disk_status: {}", disk_monitor.status().as_str());
+	match disk_monitor.free_bytes() {
+		Some(bytes) => println!("\t\t disk_free_bytes: {}", bytes),
+		None => println!("\t\t disk_free_bytes: unknown"),
+	}
 	println!("\t}},");
 }
 
-fn list_peers(peer_manager: Arc<PeerManager>) {
+fn debug_stats(
+	network_graph: &NetworkGraph, header_cache_len: &AtomicUsize,
+	inbound_payments: &PaymentInfoStorage, outbound_payments: &PaymentInfoStorage,
+	connection_guard: &ConnectionGuard,
+) {
+	let (num_nodes, num_channels) = {
+		let graph = network_graph.read_only();
+		(graph.nodes().len(), graph.channels().len())
+	};
 	println!("\t{{");
-	for pubkey in peer_manager.get_peer_node_ids() {
-		println!("\t\t pubkey: {}", pubkey);
+	match memory::resident_size_kb() {
+		Some(kb) => println!("\t\t resident_size_kb: {}", kb),
+		None => println!("\t\t resident_size_kb: unknown"),
 	}
+	println!("\t\t header_cache_entries: {}", header_cache_len.load(Ordering::Relaxed));
+	println!("\t\t network_graph_nodes: {}", num_nodes);
+	println!("\t\t network_graph_channels: {}", num_channels);
+	println!("\t\t network_graph_serialized_bytes: {}", network_graph.encode().len());
+	println!("\t\t inbound_payments: {}", inbound_payments.lock().unwrap().len());
+	println!("\t\t outbound_payments: {}", outbound_payments.lock().unwrap().len());
+	match connection_guard::fd_usage() {
+		Some(usage) => println!("\t\t open_fds: {} of {}", usage.open, usage.limit),
+		None => println!("\t\t open_fds: unknown"),
+	}
+	let (channel_peers, gossip_peers) = connection_guard.connected_peers();
+	let (evicted_peers, refused_inbound) = connection_guard.counters();
+	println!("\t\t connected_channel_peers: {}", channel_peers);
+	println!("\t\t connected_gossip_peers: {}", gossip_peers);
+	println!("\t\t evicted_gossip_peers: {}", evicted_peers);
+	println!("\t\t refused_inbound_connections: {}", refused_inbound);
 	println!("\t}},");
 }
 
-fn list_channels(channel_manager: Arc<ChannelManager>) {
+pub(crate) fn list_peers(connected_peers: Vec<PublicKey>, peer_uptime: PeerUptimeStorage) {
+	let peer_uptime = peer_uptime.lock().unwrap();
+	let mut pubkeys: Vec<PublicKey> = connected_peers.clone();
+	// Also list channel counterparties we're tracking but which are currently offline.
+	for pubkey in peer_uptime.keys() {
+		if !pubkeys.contains(pubkey) {
+			pubkeys.push(*pubkey);
+		}
+	}
 	print!("[");
-	for chan_info in channel_manager.list_channels() {
+	for pubkey in pubkeys {
 		println!("");
 		println!("\t{{");
-		println!("\t\tchannel_id: {},", hex_utils::hex_str(&chan_info.channel_id[..]));
-		if let Some(funding_txo) = chan_info.funding_txo {
-			println!("\t\tfunding_txid: {},", funding_txo.txid);
+		println!("\t\tpubkey: {},", pubkey);
+		println!("\t\tconnected: {},", connected_peers.contains(&pubkey));
+		if let Some(uptime) = peer_uptime.get(&pubkey) {
+			println!("\t\tuptime_24h: {},", uptime::format_uptime(uptime.uptime_pct(24)));
+			println!("\t\tuptime_7d: {},", uptime::format_uptime(uptime.uptime_pct(24 * 7)));
 		}
-		println!(
-			"\t\tpeer_pubkey: {},",
+		println!("\t}},");
+	}
+	println!("]");
+}
+
+fn advise(
+	channel_manager: &ChannelManager, network_graph: &NetworkGraph,
+	peer_uptime: &PeerUptimeStorage, user_config: &UserConfig,
+) {
+	let our_fees = RoutingFees {
+		base_msat: user_config.channel_options.forwarding_fee_base_msat,
+		proportional_millionths: user_config.channel_options.forwarding_fee_proportional_millionths,
+	};
+	let suggestions = advisor::suggestions(channel_manager, network_graph, peer_uptime, our_fees);
+	print!("[");
+	for suggestion in suggestions {
+		println!("");
+		println!("\t{{");
+		match suggestion.action {
+			Action::Open => println!("\t\taction: open,"),
+			Action::Close => println!("\t\taction: close,"),
+			Action::SetFee { proportional_millionths } => {
+				println!("\t\taction: set_fee,");
+				println!("\t\tsuggested_fee_ppm: {},", proportional_millionths);
+			}
+		}
+		println!("\t\tnode_id: {},", suggestion.node_id);
+		if let Some(channel_id) = suggestion.channel_id {
+			println!("\t\tchannel_id: {},", hex_utils::hex_str(&channel_id));
+		}
+		println!("\t\tscore: {:.3},", suggestion.score);
+		println!("\t\treason: {},", suggestion.reason);
+		println!("\t}},");
+	}
+	println!("]");
+}
+
+fn counterparty_fee_budget_info(
+	counterparty: PublicKey, fee_budget: FeeBudget, outbound_payments: &PaymentInfoStorage,
+) {
+	let spent_msat =
+		fee_budget.counterparty_fees_msat(&outbound_payments.lock().unwrap(), &counterparty);
+	println!("\t{{");
+	println!("\t\tcounterparty: {},", counterparty);
+	println!("\t\twindow_hours: {},", fee_budget.counterparty_window_secs / 3600);
+	println!("\t\tfees_paid_msat: {},", spent_msat);
+	if let Some(budget_msat) = fee_budget.counterparty_budget_msat {
+		println!("\t\tbudget_msat: {},", budget_msat);
+		println!("\t\tremaining_msat: {},", budget_msat.saturating_sub(spent_msat));
+	}
+	println!("\t}},");
+}
+
+/// How many confirmations a channel's funding transaction has, given the height of our best block.
+/// LDK 0.0.103 doesn't report this directly, but a channel's short channel id encodes the height of
+/// the block its funding transaction confirmed in.
+pub(crate) fn funding_confirmations(
+	chan_info: &ChannelDetails, best_block_height: u32,
+) -> Option<u32> {
+	let funding_height = (chan_info.short_channel_id? >> 40) as u32;
+	Some((best_block_height + 1).saturating_sub(funding_height))
+}
+
+/// Lists our channels. How much can be spent over each right now is only shown if we have a
+/// `commitment_feerate` to work it out with.
+/// Lists our channels, along with how many commitment updates each has taken if `rotation` is
+/// given.
+pub(crate) fn list_channels(
+	channels: Vec<ChannelDetails>, best_block_height: u32, commitment_feerate: Option<u32>,
+	rotation: Option<&ChannelRotation>,
+) {
+	print!("[");
+	for chan_info in channels {
+		println!("");
+		println!("\t{{");
+		println!("\t\tchannel_id: {},", hex_utils::hex_str(&chan_info.channel_id[..]));
+		if let Some(funding_txo) = chan_info.funding_txo {
+			println!("\t\tfunding_txid: {},", funding_txo.txid);
+			println!("\t\tfunding_outpoint: {}:{},", funding_txo.txid, funding_txo.index);
+		}
+		println!(
+			"\t\tpeer_pubkey: {},",
 			hex_utils::hex_str(&chan_info.counterparty.node_id.serialize())
 		);
+		println!("\t\tis_outbound: {},", chan_info.is_outbound);
 		if let Some(id) = chan_info.short_channel_id {
 			println!("\t\tshort_channel_id: {},", id);
 		}
 		println!("\t\tis_confirmed_onchain: {},", chan_info.is_funding_locked);
-		println!("\t\tchannel_value_satoshis: {},", chan_info.channel_value_satoshis);
 		println!(
-			"\t\tlocal_balance_msat: {},",
-			chan_info.outbound_capacity_msat
-				+ chan_info.unspendable_punishment_reserve.unwrap_or(0) * 1000
+			"\t\tconfirmations: {},",
+			funding_confirmations(&chan_info, best_block_height).unwrap_or(0)
 		);
-		if chan_info.is_usable {
-			println!("\t\tavailable_balance_for_send_msat: {},", chan_info.outbound_capacity_msat);
-			println!("\t\tavailable_balance_for_recv_msat: {},", chan_info.inbound_capacity_msat);
+		if let Some(required) = chan_info.confirmations_required {
+			println!("\t\tconfirmations_required: {},", required);
+		}
+		println!("\t\tchannel_value_satoshis: {},", chan_info.channel_value_satoshis);
+		println!("\t\tlocal_balance_msat: {},", balances::channel_local_balance_msat(&chan_info));
+		println!("\t\tremote_balance_msat: {},", balances::channel_remote_balance_msat(&chan_info));
+		println!("\t\tavailable_balance_for_send_msat: {},", chan_info.outbound_capacity_msat);
+		println!("\t\tavailable_balance_for_recv_msat: {},", chan_info.inbound_capacity_msat);
+		if let Some(feerate) = commitment_feerate {
+			println!(
+				"\t\tspendable_now_msat: {},",
+				balances::channel_spendable_msat(&chan_info, feerate)
+			);
 		}
 		println!("\t\tchannel_can_send_payments: {},", chan_info.is_usable);
 		println!("\t\tpublic: {},", chan_info.is_public);
+		if let Some(updates) = rotation.and_then(|rotation| rotation.update_count(&chan_info)) {
+			println!("\t\tcommitment_updates: {},", updates);
+		}
 		println!("\t}},");
 	}
 	println!("]");
 }
 
-fn list_payments(inbound_payments: PaymentInfoStorage, outbound_payments: PaymentInfoStorage) {
+fn print_route(route: &Route) {
+	println!("\t\troute: [");
+	for path in route.paths.iter() {
+		// Each hop's fee is paid to that hop's node for forwarding to the next hop, while the last
+		// hop's "fee" is the amount delivered to the recipient.
+		let fees_msat: u64 = path.iter().rev().skip(1).map(|hop| hop.fee_msat).sum();
+		println!("\t\t\t{{");
+		println!("\t\t\t\ttotal_fee_msat: {},", fees_msat);
+		for (idx, hop) in path.iter().enumerate() {
+			if idx == path.len() - 1 {
+				println!(
+					"\t\t\t\tdestination: {} via channel {}, amount_msat: {}, final_cltv_expiry_delta: {},",
+					hop.pubkey, hop.short_channel_id, hop.fee_msat, hop.cltv_expiry_delta
+				);
+			} else {
+				println!(
+					"\t\t\t\thop: {} via channel {}, fee_msat: {}, cltv_expiry_delta: {},",
+					hop.pubkey, hop.short_channel_id, hop.fee_msat, hop.cltv_expiry_delta
+				);
+			}
+		}
+		println!("\t\t\t}},");
+	}
+	println!("\t\t],");
+}
+
+fn print_payment(
+	payment_hash: &PaymentHash, payment_info: &PaymentInfo, direction: &str, verbose: bool,
+) {
+	println!("");
+	println!("\t{{");
+	println!("\t\tamount_millisatoshis: {},", payment_info.amt_msat);
+	println!("\t\tpayment_hash: {},", hex_utils::hex_str(&payment_hash.0));
+	if let Some(preimage) = payment_info.preimage {
+		println!("\t\tpayment_preimage: {},", hex_utils::hex_str(&preimage.0));
+	}
+	println!("\t\thtlc_direction: {},", direction);
+	println!(
+		"\t\thtlc_status: {},",
+		match payment_info.status {
+			HTLCStatus::Pending => "pending",
+			HTLCStatus::Succeeded => "succeeded",
+			HTLCStatus::Failed => "failed",
+			HTLCStatus::TimedOut => "timed out, parts still in flight",
+		}
+	);
+	println!("\t\tcreated_at: {},", payment_info.created_at);
+	println!("\t\tupdated_at: {},", payment_info.updated_at);
+	if let Some(expires_at) = payment_info.expires_at {
+		println!("\t\texpires_at: {},", expires_at);
+	}
+	if let Some(route) = &payment_info.route {
+		println!("\t\tnum_parts: {},", route.paths.len());
+	}
+	if let Some(fee_paid_msat) = payment_info.fee_paid_msat {
+		println!("\t\tfee_paid_msat: {},", fee_paid_msat);
+	}
+	if payment_info.failed_parts > 0 {
+		println!("\t\tfailed_parts: {},", payment_info.failed_parts);
+	}
+	if let Some(failure) = &payment_info.failure {
+		println!("\t\tfailure_reason: {},", failure);
+	}
+	if let Some(refund_of) = payment_info.refund_of {
+		println!("\t\trefund_of: {},", hex_utils::hex_str(&refund_of.0));
+	}
+	if let Some(reissue_of) = payment_info.reissue_of {
+		println!("\t\treissue_of: {},", hex_utils::hex_str(&reissue_of.0));
+	}
+	if verbose {
+		if let Some(route) = &payment_info.route {
+			print_route(route);
+		}
+	}
+	println!("\t}},");
+}
+
+pub(crate) fn list_payments(
+	inbound_payments: PaymentInfoStorage, outbound_payments: PaymentInfoStorage, verbose: bool,
+) {
 	let inbound = inbound_payments.lock().unwrap();
 	let outbound = outbound_payments.lock().unwrap();
+	let mut payments: Vec<(&PaymentHash, &PaymentInfo, &str)> = inbound
+		.iter()
+		.map(|(hash, info)| (hash, info, "inbound"))
+		.chain(outbound.iter().map(|(hash, info)| (hash, info, "outbound")))
+		.collect();
+	// Show the most recent payments last, closest to the prompt.
+	payments.sort_by_key(|(_, info, _)| info.created_at);
 	print!("[");
-	for (payment_hash, payment_info) in inbound.deref() {
+	for (payment_hash, payment_info, direction) in payments {
+		print_payment(payment_hash, payment_info, direction, verbose);
+	}
+	println!("]");
+}
+
+/// Shows everything we know about a single payment, including its route and why it failed.
+fn track_payment(
+	payment_hash: &PaymentHash, inbound_payments: PaymentInfoStorage,
+	outbound_payments: PaymentInfoStorage,
+) {
+	let inbound = inbound_payments.lock().unwrap();
+	let outbound = outbound_payments.lock().unwrap();
+	let payment = match outbound.get(payment_hash) {
+		Some(payment_info) => (payment_info, "outbound"),
+		None => match inbound.get(payment_hash) {
+			Some(payment_info) => (payment_info, "inbound"),
+			None => {
+				println!("ERROR: no payment with hash {}", hex_utils::hex_str(&payment_hash.0));
+				return;
+			}
+		},
+	};
+	print_payment(payment_hash, payment.0, payment.1, true);
+}
+
+/// Prints a signed receipt for a payment we received or sent, for bookkeeping or to settle a
+/// dispute over whether it was paid.
+fn get_receipt(
+	payment_hash: &PaymentHash, inbound_payments: &PaymentInfoStorage,
+	outbound_payments: &PaymentInfoStorage, keys_manager: &KeysManager,
+) {
+	let inbound = inbound_payments.lock().unwrap();
+	let outbound = outbound_payments.lock().unwrap();
+	let (payment, direction) = match inbound.get(payment_hash) {
+		Some(payment_info) => (payment_info, "received"),
+		None => match outbound.get(payment_hash) {
+			Some(payment_info) => (payment_info, "sent"),
+			None => {
+				println!("ERROR: no payment with hash {}", hex_utils::hex_str(&payment_hash.0));
+				return;
+			}
+		},
+	};
+	match receipt::payment_receipt(
+		payment_hash,
+		payment,
+		direction,
+		&keys_manager.get_node_secret(),
+	) {
+		Ok(receipt) => println!("{}", serde_json::to_string_pretty(&receipt).unwrap()),
+		Err(e) => println!("ERROR: can't issue a receipt: {}", e),
+	}
+}
+
+/// Shows what we know of a peer's limits on the channels it'll accept from us, from our channels
+/// with it and from past opens it accepted or rejected.
+/// Parses a YYYY-MM-DD date into the UNIX timestamp of its start, in UTC.
+fn forwarding_history(forward_log: &ForwardLog, from: u64, to: u64) {
+	let forwards = forward_log.forwards_between(from, to);
+	print!("[");
+	for forward in forwards.iter() {
 		println!("");
 		println!("\t{{");
-		println!("\t\tamount_millisatoshis: {},", payment_info.amt_msat);
-		println!("\t\tpayment_hash: {},", hex_utils::hex_str(&payment_hash.0));
-		println!("\t\thtlc_direction: inbound,");
-		println!(
-			"\t\thtlc_status: {},",
-			match payment_info.status {
-				HTLCStatus::Pending => "pending",
-				HTLCStatus::Succeeded => "succeeded",
-				HTLCStatus::Failed => "failed",
+		println!("\t\ttimestamp: {},", forward.timestamp);
+		match &forward.htlcs {
+			Some(htlcs) => {
+				println!(
+					"\t\tinbound_channel_id: {},",
+					hex_utils::hex_str(&htlcs.inbound_channel_id)
+				);
+				println!("\t\tinbound_amount_msat: {},", htlcs.inbound_amt_msat);
+				println!(
+					"\t\toutbound_channel_id: {},",
+					hex_utils::hex_str(&htlcs.outbound_channel_id)
+				);
+				println!("\t\toutbound_amount_msat: {},", htlcs.outbound_amt_msat);
+			}
+			None => println!("\t\tchannels: unknown,"),
+		}
+		println!("\t\tfee_earned_msat: {},", forward.fee_earned_msat);
+		print!("\t}},");
+	}
+	println!("]");
+	let forwarded_msat: u64 =
+		forwards.iter().filter_map(|f| f.htlcs.as_ref()).map(|h| h.outbound_amt_msat).sum();
+	let fees_msat: u64 = forwards.iter().map(|f| f.fee_earned_msat).sum();
+	println!("\t{{");
+	println!("\t\tnum_forwards: {},", forwards.len());
+	println!("\t\ttotal_forwarded_msat: {},", forwarded_msat);
+	println!("\t\ttotal_fees_earned_msat: {},", fees_msat);
+	println!("\t}},");
+}
+
+fn parse_date(date: &str) -> Option<u64> {
+	let date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+	Some(date.and_hms(0, 0, 0).timestamp() as u64)
+}
+
+fn node_state<'a>(
+	inbound_payments: &'a PaymentInfoStorage, outbound_payments: &'a PaymentInfoStorage,
+	peer_limits: &'a PeerLimitsStorage, storage: &'a dyn Storage,
+) -> NodeState<'a> {
+	NodeState { inbound_payments, outbound_payments, peer_limits, storage }
+}
+
+fn export_state(path: &Path, sections: &ExportSections, our_node_id: PublicKey, state: &NodeState) {
+	match export::export_state(path, sections, our_node_id, state) {
+		Ok(counts) => {
+			println!("SUCCESS: exported node state to {:?}", path);
+			for (section, count) in counts {
+				println!("\t{}: {} entries", section, count);
+			}
+		}
+		Err(e) => println!("ERROR: failed to export node state: {}", e),
+	}
+}
+
+fn import_state(path: &Path, state: &NodeState) {
+	match export::import_state(path, state) {
+		Ok((node_id, counts)) => {
+			println!("SUCCESS: imported node state exported by {}", node_id);
+			for (section, count) in counts {
+				println!("\t{}: {} new entries", section, count);
 			}
+		}
+		Err(e) => println!("ERROR: failed to import node state: {}", e),
+	}
+}
+
+fn peer_limits_info(
+	peer: PublicKey, channels: Vec<ChannelDetails>, peer_limits: &PeerLimitsStorage,
+) {
+	let store = peer_limits.lock().unwrap();
+	println!("\t{{");
+	println!("\t\tpeer_pubkey: {},", peer);
+	match store.get(&peer) {
+		Some(limits) => {
+			if let Some(min_accepted_sat) = limits.min_accepted_sat {
+				println!("\t\tsmallest_accepted_channel_sat: {},", min_accepted_sat);
+			}
+			if let Some(max_accepted_sat) = limits.max_accepted_sat {
+				println!("\t\tlargest_accepted_channel_sat: {},", max_accepted_sat);
+			}
+			if let Some(rejection) = &limits.last_rejection {
+				println!("\t\tlast_failed_open_sat: {},", rejection.channel_value_sat);
+				println!("\t\tlast_failed_open_at: {},", rejection.rejected_at);
+				println!("\t\tlast_failed_open_reason: {},", rejection.reason);
+			}
+		}
+		None => println!("\t\tno channel opens with this peer recorded"),
+	}
+	for chan_info in channels.iter().filter(|chan| chan.counterparty.node_id == peer) {
+		println!("\t\tchannel {{");
+		println!("\t\t\tchannel_id: {},", hex_utils::hex_str(&chan_info.channel_id[..]));
+		println!("\t\t\tchannel_value_satoshis: {},", chan_info.channel_value_satoshis);
+		if let Some(reserve) = chan_info.unspendable_punishment_reserve {
+			println!("\t\t\treserve_required_of_us_sat: {},", reserve);
+		}
+		println!(
+			"\t\t\treserve_required_of_peer_sat: {},",
+			chan_info.counterparty.unspendable_punishment_reserve
 		);
+		if let Some(delay) = chan_info.force_close_spend_delay {
+			println!("\t\t\tto_self_delay_required_of_us: {},", delay);
+		}
+		println!("\t\t}},");
+	}
+	println!("\t}},");
+}
+
+fn list_open_queue(open_queue: &OpenQueue) {
+	let now = Instant::now();
+	print!("[");
+	open_queue.with_opens(|opens| {
+		for open in opens {
+			println!("");
+			println!("\t{{");
+			println!("\t\tid: {},", open.id);
+			println!("\t\tpeer: {},", open.peer_pubkey_and_addr);
+			println!("\t\tchannel_value_satoshis: {},", open.channel_amt_sat);
+			println!("\t\tpublic: {},", open.announced);
+			match &open.state {
+				OpenState::Queued { retry_at: None } => println!("\t\tstatus: queued,"),
+				OpenState::Queued { retry_at: Some(retry_at) } => println!(
+					"\t\tstatus: retrying in {}s,",
+					retry_at.saturating_duration_since(now).as_secs()
+				),
+				OpenState::Connecting => println!("\t\tstatus: connecting,"),
+				OpenState::Opening { .. } => println!("\t\tstatus: opening,"),
+				OpenState::Opened { channel_id } => {
+					println!("\t\tstatus: opened,");
+					println!("\t\tchannel_id: {},", hex_utils::hex_str(channel_id));
+				}
+				OpenState::Failed => println!("\t\tstatus: failed,"),
+			}
+			println!("\t\tattempts: {},", open.attempts);
+			if let Some(error) = &open.last_error {
+				println!("\t\tlast_error: {},", error);
+			}
+			println!("\t}},");
+		}
+	});
+	println!("]");
+}
 
+fn list_exclusions(route_exclusions: &RouteExclusionsStorage) {
+	let mut exclusions = route_exclusions.lock().unwrap();
+	exclusions.remove_expired();
+	let until_str = |until: &Option<u64>| until.map_or("none".to_string(), |t| t.to_string());
+	print!("[");
+	for (node, until) in exclusions.nodes.iter() {
+		println!("");
+		println!("\t{{");
+		println!("\t\tnode_pubkey: {},", node);
+		println!("\t\texcluded_until: {},", until_str(until));
 		println!("\t}},");
 	}
+	for (short_channel_id, until) in exclusions.channels.iter() {
+		println!("");
+		println!("\t{{");
+		println!("\t\tshort_channel_id: {},", short_channel_id);
+		println!("\t\texcluded_until: {},", until_str(until));
+		println!("\t}},");
+	}
+	println!("]");
+}
 
-	for (payment_hash, payment_info) in outbound.deref() {
+fn list_schedules(scheduled_payments: ScheduleStorage) {
+	let store = scheduled_payments.lock().unwrap();
+	let mut schedules: Vec<_> = store.schedules().iter().collect();
+	schedules.sort_by_key(|(schedule_id, _)| **schedule_id);
+	print!("[");
+	for (schedule_id, schedule) in schedules {
 		println!("");
 		println!("\t{{");
-		println!("\t\tamount_millisatoshis: {},", payment_info.amt_msat);
-		println!("\t\tpayment_hash: {},", hex_utils::hex_str(&payment_hash.0));
-		println!("\t\thtlc_direction: outbound,");
-		println!(
-			"\t\thtlc_status: {},",
-			match payment_info.status {
-				HTLCStatus::Pending => "pending",
-				HTLCStatus::Succeeded => "succeeded",
-				HTLCStatus::Failed => "failed",
-			}
-		);
+		println!("\t\tschedule_id: {},", schedule_id);
+		println!("\t\tdest_pubkey: {},", schedule.payee);
+		println!("\t\tamount_millisatoshis: {},", schedule.amt_msat);
+		println!("\t\tinterval_secs: {},", schedule.interval_secs);
+		println!("\t\tnext_run: {},", schedule.next_run);
+		println!("\t}},");
+	}
+	println!("]");
+}
 
+async fn get_balance(channel_manager: &ChannelManager, bitcoind_client: &BitcoindClient) {
+	let channels = channel_manager.list_channels();
+	let feerate = balances::commitment_feerate(bitcoind_client);
+	let local_balance_msat: u64 = channels.iter().map(balances::channel_local_balance_msat).sum();
+	let reserve_msat: u64 = channels
+		.iter()
+		.map(|chan_info| chan_info.unspendable_punishment_reserve.unwrap_or(0) * 1000)
+		.sum();
+	let spendable_msat: u64 =
+		channels.iter().map(|chan_info| balances::channel_spendable_msat(chan_info, feerate)).sum();
+	println!("\t{{");
+	match bitcoind_client.get_balance().await {
+		Ok(onchain_sat) => println!("\t\tonchain_balance_sat: {},", onchain_sat),
+		Err(e) => println!("\t\tonchain_balance_sat: unknown ({}),", e),
+	}
+	println!("\t\tlightning_balance_msat: {},", local_balance_msat);
+	println!("\t\tlightning_reserve_msat: {},", reserve_msat);
+	println!("\t\tlightning_spendable_now_msat: {},", spendable_msat);
+	println!("\t}},");
+}
+
+pub(crate) fn balance_history(path: &Path, num_snapshots: usize, show_channels: bool) {
+	let history = balances::read_balance_history(path);
+	let skip = history.len().saturating_sub(num_snapshots);
+	print!("[");
+	for snapshot in history.iter().skip(skip) {
+		println!("");
+		println!("\t{{");
+		println!("\t\ttimestamp: {},", snapshot.timestamp);
+		println!("\t\tonchain_balance_sat: {},", snapshot.onchain_sat);
+		println!("\t\tlightning_balance_msat: {},", snapshot.lightning_balance_msat());
+		if show_channels {
+			for (channel_id, balance) in snapshot.channel_balances_msat.iter() {
+				println!("\t\tchannel {}: {} msat,", channel_id, balance);
+			}
+		}
 		println!("\t}},");
 	}
 	println!("]");
 }
 
+/// Connects to the peer unless we're already connected, going through the SOCKS5 proxy at
+/// `proxy_addr` if one is given.
 pub(crate) async fn connect_peer_if_necessary(
-	pubkey: PublicKey, peer_addr: SocketAddr, peer_manager: Arc<PeerManager>,
+	pubkey: PublicKey, peer_addr: PeerAddr, peer_manager: Arc<PeerManager>,
+	proxy_addr: Option<SocketAddr>,
 ) -> Result<(), ()> {
 	for node_pubkey in peer_manager.get_peer_node_ids() {
 		if node_pubkey == pubkey {
 			return Ok(());
 		}
 	}
-	match lightning_net_tokio::connect_outbound(Arc::clone(&peer_manager), pubkey, peer_addr).await
-	{
-		Some(connection_closed_future) => {
-			let mut connection_closed_future = Box::pin(connection_closed_future);
+	let connection_closed_future: Option<Pin<Box<dyn Future<Output = ()> + Send>>> =
+		match (proxy_addr, peer_addr) {
+			(Some(proxy_addr), peer_addr) => {
+				match tor::socks5_connect(proxy_addr, &peer_addr).await {
+					Ok(stream) => match stream.into_std() {
+						Ok(stream) => Some(Box::pin(lightning_net_tokio::setup_outbound(
+							Arc::clone(&peer_manager),
+							pubkey,
+							stream,
+						))),
+						Err(_) => None,
+					},
+					Err(e) => {
+						println!("ERROR: failed to connect via proxy: {}", e);
+						None
+					}
+				}
+			}
+			(None, PeerAddr::Socket(addr)) => {
+				lightning_net_tokio::connect_outbound(Arc::clone(&peer_manager), pubkey, addr)
+					.await
+					.map(|future| Box::pin(future) as Pin<Box<dyn Future<Output = ()> + Send>>)
+			}
+			(None, PeerAddr::Onion(..)) => {
+				println!("ERROR: connecting to onion addresses requires a Tor proxy, see --proxy");
+				return Err(());
+			}
+		};
+	match connection_closed_future {
+		Some(mut connection_closed_future) => {
 			loop {
 				match futures::poll!(&mut connection_closed_future) {
 					std::task::Poll::Ready(_) => {
@@ -553,24 +2982,98 @@ pub(crate) async fn connect_peer_if_necessary(
 	Ok(())
 }
 
-fn open_channel(
+/// Funds a channel from an externally signed PSBT or raw transaction, for when our funding wallet
+/// is watch-only or the channel was opened with `openchannel --psbt`.
+async fn fund_channel(
+	temporary_channel_id: [u8; 32], psbt_or_tx: &str, bitcoind_client: &BitcoindClient,
+	channel_manager: &ChannelManager, external_funding: &ExternalFundingStorage,
+) {
+	let raw_tx: Option<Transaction> =
+		hex_utils::to_vec(psbt_or_tx).and_then(|tx| encode::deserialize(&tx).ok());
+	let funding_tx = match raw_tx {
+		Some(tx) => tx,
+		None => {
+			let tx_hex = match bitcoind_client.finalize_psbt(psbt_or_tx).await {
+				Ok(FinalizedPsbt { complete: true, hex: Some(hex) }) => hex,
+				Ok(_) => {
+					println!("ERROR: the PSBT isn't fully signed");
+					return;
+				}
+				Err(e) => {
+					println!("ERROR: failed to finalize PSBT: {}", e);
+					return;
+				}
+			};
+			match hex_utils::to_vec(&tx_hex).map(|tx| encode::deserialize(&tx)) {
+				Some(Ok(tx)) => tx,
+				_ => {
+					println!("ERROR: bitcoind returned an invalid transaction");
+					return;
+				}
+			}
+		}
+	};
+	// LDK only checks that some output pays the funding script, so catch a wrong amount or a
+	// transaction which couldn't be broadcast before the peer signs for it.
+	if let Err(e) =
+		external_funding.lock().unwrap().check_funding_tx(&temporary_channel_id, &funding_tx)
+	{
+		println!("ERROR: can't fund the channel with this transaction: {}", e);
+		return;
+	}
+	let user_channel_id = channel_manager
+		.list_channels()
+		.iter()
+		.find(|channel| channel.channel_id == temporary_channel_id)
+		.map(|channel| channel.user_channel_id);
+	// Whoever signed the transaction picked its feerate.
+	bitcoind_client.fee_snapshots().expect(funding_tx.txid(), BroadcastPurpose::Funding, None);
+	match channel_manager.funding_transaction_generated(&temporary_channel_id, funding_tx) {
+		Ok(()) => {
+			if let Some(user_channel_id) = user_channel_id {
+				external_funding
+					.lock()
+					.unwrap()
+					.channel_done(&temporary_channel_id, user_channel_id);
+			}
+			println!("SUCCESS: funding transaction handed to the channel")
+		}
+		Err(e) => println!("ERROR: failed to fund channel: {:?}", e),
+	}
+}
+
+pub(crate) fn open_channel(
 	peer_pubkey: PublicKey, channel_amt_sat: u64, announced_channel: bool,
-	channel_manager: Arc<ChannelManager>,
-) -> Result<(), ()> {
+	default_config: UserConfig, channel_manager: Arc<ChannelManager>,
+	peer_limits: &PeerLimitsStorage, external_funding: Option<&ExternalFundingStorage>,
+) -> Result<u64, ()> {
 	let config = UserConfig {
 		peer_channel_config_limits: ChannelHandshakeLimits {
 			// lnd's max to_self_delay is 2016, so we want to be compatible.
 			their_to_self_delay: 2016,
-			..Default::default()
+			..default_config.peer_channel_config_limits
 		},
-		channel_options: ChannelConfig { announced_channel, ..Default::default() },
-		..Default::default()
+		channel_options: ChannelConfig { announced_channel, ..default_config.channel_options },
+		..default_config
 	};
 
-	match channel_manager.create_channel(peer_pubkey, channel_amt_sat, 0, 0, Some(config)) {
+	// The id lets us match up the events telling us how the open went with the peer. Channels
+	// opened before we last restarted may still close, so pick one which won't collide with theirs.
+	let user_channel_id = thread_rng().gen();
+	match channel_manager.create_channel(
+		peer_pubkey,
+		channel_amt_sat,
+		0,
+		user_channel_id,
+		Some(config),
+	) {
 		Ok(_) => {
+			peer_limits.lock().unwrap().open_started(user_channel_id, peer_pubkey, channel_amt_sat);
+			if let Some(external_funding) = external_funding {
+				external_funding.lock().unwrap().request(user_channel_id);
+			}
 			println!("EVENT: initiated channel with peer {}. ", peer_pubkey);
-			return Ok(());
+			return Ok(user_channel_id);
 		}
 		Err(e) => {
 			println!("ERROR: failed to open channel: {:?}", e);
@@ -579,35 +3082,241 @@ fn open_channel(
 	}
 }
 
-fn send_payment<E: EventHandler>(
-	invoice_payer: &InvoicePayer<E>, invoice: &Invoice, payment_storage: PaymentInfoStorage,
-) {
-	let status = match invoice_payer.pay_invoice(invoice) {
+fn invoice_expires_at(invoice: &Invoice) -> u64 {
+	(invoice.timestamp().duration_since(SystemTime::UNIX_EPOCH).unwrap() + invoice.expiry_time())
+		.as_secs()
+}
+
+/// Parses `getinvoice`'s optional `--expiry <secs>`, `--description-hash <hex>` and
+/// `--description <text>` flags. As descriptions may contain spaces, `--description` takes the rest
+/// of the line.
+fn parse_invoice_options<'a, I: Iterator<Item = &'a str>>(
+	mut args: I,
+) -> Result<InvoiceOptions, String> {
+	let mut options = InvoiceOptions::default();
+	let mut has_description = false;
+	while let Some(flag) = args.next() {
+		match flag {
+			"--expiry" => match args.next().and_then(|v| v.parse::<u32>().ok()) {
+				Some(secs) if secs > 0 => options.expiry_secs = secs,
+				_ => return Err("--expiry must be a positive number of seconds".to_string()),
+			},
+			"--description-hash" if !has_description => {
+				let hash = args.next().and_then(hex_utils::to_vec).filter(|h| h.len() == 32);
+				match hash {
+					Some(hash) => {
+						options.description =
+							InvoiceDescription::Hash(Sha256::from_slice(&hash).unwrap())
+					}
+					None => {
+						return Err("--description-hash must be 32 hex-encoded bytes".to_string())
+					}
+				}
+				has_description = true;
+			}
+			"--description" if !has_description => {
+				let description = args.by_ref().collect::<Vec<_>>().join(" ");
+				if description.is_empty() {
+					return Err("--description requires some text".to_string());
+				}
+				options.description = InvoiceDescription::Direct(description);
+				has_description = true;
+			}
+			"--description" | "--description-hash" => {
+				return Err(
+					"only one of --description and --description-hash may be given".to_string()
+				)
+			}
+			_ => {
+				return Err("getinvoice usage: `getinvoice <amt_millisatoshis> [--expiry <secs>] \
+					 [--description-hash <hex>] [--description <text...>]`"
+					.to_string())
+			}
+		}
+	}
+	Ok(options)
+}
+
+/// Parses `sendpayment`'s optional `--max-retries <n>`, `--max-fee-msat <msat>`,
+/// `--max-fee-percent <percent>`, `--timeout <secs>` and `--deadline <secs>` flags for a payment of
+/// `amt_msat`, and its `--[no-]shadow-route` and `--[no-]randomize-cltv` privacy flags. If both fee
+/// limits are given, the lower one applies.
+fn parse_send_flags<'a, I: Iterator<Item = &'a str>>(
+	mut args: I, amt_msat: u64,
+) -> Result<(RetryLimits, PrivacyOverrides), String> {
+	let mut limits = RetryLimits::default();
+	let mut privacy = PrivacyOverrides::default();
+	let mut fee_limits = Vec::new();
+	while let Some(flag) = args.next() {
+		// The privacy flags take no value.
+		let privacy_flag = match flag {
+			"--shadow-route" => Some((&mut privacy.shadow_route, true)),
+			"--no-shadow-route" => Some((&mut privacy.shadow_route, false)),
+			"--randomize-cltv" => Some((&mut privacy.randomize_final_cltv, true)),
+			"--no-randomize-cltv" => Some((&mut privacy.randomize_final_cltv, false)),
+			_ => None,
+		};
+		if let Some((setting, enabled)) = privacy_flag {
+			*setting = Some(enabled);
+			continue;
+		}
+		let value = args.next();
+		match (flag, value.and_then(|v| v.parse::<u64>().ok())) {
+			("--max-retries", Some(n)) => limits.max_retries = Some(n as usize),
+			("--max-fee-msat", Some(msat)) => fee_limits.push(msat),
+			("--timeout", Some(secs)) => limits.retry_until = Some(time_now_secs() + secs),
+			("--deadline", Some(secs)) => limits.deadline = Some(time_now_secs() + secs),
+			("--max-fee-percent", _) => match value.and_then(|v| v.parse::<f64>().ok()) {
+				Some(percent) if percent >= 0.0 => {
+					fee_limits.push((amt_msat as f64 * percent / 100.0) as u64)
+				}
+				_ => return Err("--max-fee-percent must be a non-negative number".to_string()),
+			},
+			_ => {
+				return Err("sendpayment usage: `sendpayment <invoice> [<amt_millisatoshis>] \
+					 [--max-retries <n>] \
+					 [--max-fee-msat <msat>] [--max-fee-percent <percent>] [--timeout <secs>] \
+					 [--deadline <secs>] [--[no-]shadow-route] [--[no-]randomize-cltv]`"
+					.to_string())
+			}
+		}
+	}
+	limits.max_fee_msat = fee_limits.into_iter().min();
+	Ok((limits, privacy))
+}
+
+/// The amount to pay `invoice`, which is either the amount it asks for or, if it leaves the amount
+/// to the payer, `amt_override`. Giving an amount for an invoice which has one is an error, as the
+/// user likely has the wrong invoice.
+pub(crate) fn invoice_amount_msat(
+	invoice: &Invoice, amt_override: Option<u64>,
+) -> Result<u64, String> {
+	match (invoice.amount_milli_satoshis(), amt_override) {
+		(Some(amt_msat), None) => Ok(amt_msat),
+		(Some(amt_msat), Some(_)) => Err(format!(
+			"invoice is for {} millisatoshis, an amount can only be given for invoices without one",
+			amt_msat
+		)),
+		(None, Some(amt_msat)) if amt_msat > 0 => Ok(amt_msat),
+		(None, Some(_)) => Err("payment amount must be positive".to_string()),
+		(None, None) => {
+			Err("invoice has no amount, so the amount to pay must be given".to_string())
+		}
+	}
+}
+
+/// Pays `invoice`, sending `amt_msat` if it leaves the amount to us.
+/// Pays `invoice` to refund the inbound payment `payment_hash`, and records which payment it
+/// refunds. A payment may be refunded in parts, but never by more in total than we received. An
+/// invoice without an amount is paid whatever remains to be refunded.
+fn refund<E: EventHandler>(
+	payment_hash: &PaymentHash, invoice: &Invoice, invoice_payer: &InvoicePayer<E>,
+	payer: &Arc<RouteRecordingPayer>, inbound_payments: &PaymentInfoStorage,
+	outbound_payments: PaymentInfoStorage,
+) -> Result<(), String> {
+	let received_msat = {
+		let inbound = inbound_payments.lock().unwrap();
+		let payment = inbound.get(payment_hash).ok_or_else(|| {
+			format!("no inbound payment with hash {}", hex_utils::hex_str(&payment_hash.0))
+		})?;
+		match payment.status {
+			HTLCStatus::Succeeded => {}
+			_ => return Err("the payment hasn't been received".to_string()),
+		}
+		payment.amt_msat.0.ok_or("the amount received is unknown")?
+	};
+	let refund_hash = PaymentHash(invoice.payment_hash().clone().into_inner());
+	let refunded_msat: u64 = {
+		let outbound = outbound_payments.lock().unwrap();
+		if outbound.contains_key(&refund_hash) {
+			return Err("the refund invoice has already been paid".to_string());
+		}
+		outbound
+			.values()
+			.filter(|payment| payment.refund_of == Some(*payment_hash))
+			.filter(|payment| match payment.status {
+				HTLCStatus::Failed => false,
+				_ => true,
+			})
+			.filter_map(|payment| payment.amt_msat.0)
+			.sum()
+	};
+	let remaining_msat = received_msat.saturating_sub(refunded_msat);
+	if remaining_msat == 0 {
+		return Err("the payment has already been refunded in full".to_string());
+	}
+	let amt_msat = invoice.amount_milli_satoshis().unwrap_or(remaining_msat);
+	if amt_msat > remaining_msat {
+		return Err(format!(
+			"the refund invoice is for {} msat, but only {} msat of the {} msat received remains to be refunded",
+			amt_msat, remaining_msat, received_msat
+		));
+	}
+
+	let res = send_payment(
+		invoice_payer,
+		payer,
+		invoice,
+		amt_msat,
+		RetryLimits::default(),
+		PrivacyOverrides::default(),
+		outbound_payments.clone(),
+	);
+	// Link the refund even if it failed to send, so that its history shows what it was for.
+	let mut outbound = outbound_payments.lock().unwrap();
+	if let Some(refund) = outbound.get_mut(&refund_hash) {
+		refund.refund_of = Some(*payment_hash);
+		outbound.persist();
+	}
+	res.map(|_| ())
+}
+
+pub(crate) fn send_payment<E: EventHandler>(
+	invoice_payer: &InvoicePayer<E>, payer: &Arc<RouteRecordingPayer>, invoice: &Invoice,
+	amt_msat: u64, limits: RetryLimits, privacy: PrivacyOverrides,
+	payment_storage: PaymentInfoStorage,
+) -> Result<PaymentHash, String> {
+	let payment_hash = PaymentHash(invoice.payment_hash().clone().into_inner());
+	payer.set_retry_limits(payment_hash, limits);
+	payer.set_privacy(payment_hash, privacy);
+	let pay_res = match invoice.amount_milli_satoshis() {
+		Some(_) => invoice_payer.pay_invoice(invoice),
+		None => invoice_payer.pay_zero_value_invoice(invoice, amt_msat),
+	};
+	let (status, res) = match pay_res {
 		Ok(_payment_id) => {
 			let payee_pubkey = invoice.recover_payee_pub_key();
-			let amt_msat = invoice.amount_milli_satoshis().unwrap();
 			println!("EVENT: initiated sending {} msats to {}", amt_msat, payee_pubkey);
 			print!("> ");
-			HTLCStatus::Pending
+			(HTLCStatus::Pending, Ok(()))
 		}
 		Err(PaymentError::Invoice(e)) => {
-			println!("ERROR: invalid invoice: {}", e);
+			let err = format!("invalid invoice: {}", e);
+			println!("ERROR: {}", err);
 			print!("> ");
-			return;
+			payer.payment_failed(&payment_hash);
+			return Err(err);
 		}
 		Err(PaymentError::Routing(e)) => {
-			println!("ERROR: failed to find route: {}", e.err);
+			let err = format!("failed to find route: {}", e.err);
+			println!("ERROR: {}", err);
 			print!("> ");
-			return;
+			payer.payment_failed(&payment_hash);
+			return Err(err);
 		}
 		Err(PaymentError::Sending(e)) => {
-			println!("ERROR: failed to send payment: {:?}", e);
+			let err = format!("failed to send payment: {:?}", e);
+			println!("ERROR: {}", err);
 			print!("> ");
-			HTLCStatus::Failed
+			payer.payment_failed(&payment_hash);
+			(HTLCStatus::Failed, Err(err))
 		}
 	};
-	let payment_hash = PaymentHash(invoice.payment_hash().clone().into_inner());
 	let payment_secret = Some(invoice.payment_secret().clone());
+	let is_pending = match status {
+		HTLCStatus::Pending => true,
+		_ => false,
+	};
 
 	let mut payments = payment_storage.lock().unwrap();
 	payments.insert(
@@ -616,75 +3325,240 @@ fn send_payment<E: EventHandler>(
 			preimage: None,
 			secret: payment_secret,
 			status,
-			amt_msat: MillisatAmount(invoice.amount_milli_satoshis()),
+			amt_msat: MillisatAmount(Some(amt_msat)),
+			created_at: time_now_secs(),
+			updated_at: time_now_secs(),
+			expires_at: Some(invoice_expires_at(invoice)),
+			route: None,
+			failure: None,
+			failed_parts: 0,
+			fee_paid_msat: None,
+			invoice: Some(invoice.to_string()),
+			refund_of: None,
+			reissue_of: None,
 		},
 	);
+	payments.persist();
+	if is_pending {
+		if let Some(timeout) = limits.retry_until {
+			enforce_payment_limit(
+				payer.clone(),
+				payment_hash,
+				timeout,
+				false,
+				payment_storage.clone(),
+			)
+		}
+		if let Some(deadline) = limits.deadline {
+			enforce_payment_limit(
+				payer.clone(),
+				payment_hash,
+				deadline,
+				true,
+				payment_storage.clone(),
+			)
+		}
+	}
+	res.map(|()| payment_hash)
+}
+
+/// Gives up on a payment which hasn't completed by its timeout or, if `abandon` is set, its
+/// deadline, `at`. Retries already stop then, but parts in flight can take far longer to resolve,
+/// so rather than leaving the payment pending we report how far it got. At the timeout it's
+/// reported timed out, and only failed once its parts in flight have all failed. At the deadline
+/// it's abandoned and reported failed right away. LDK 0.0.103 can't cancel parts in flight, so
+/// should one still succeed, the payment is updated to succeeded once we learn of it.
+fn enforce_payment_limit(
+	payer: Arc<RouteRecordingPayer>, payment_hash: PaymentHash, at: u64, abandon: bool,
+	payment_storage: PaymentInfoStorage,
+) {
+	tokio::spawn(async move {
+		tokio::time::sleep(Duration::from_secs(at.saturating_sub(time_now_secs()))).await;
+		let mut payments = payment_storage.lock().unwrap();
+		let payment = match payments.get_mut(&payment_hash) {
+			Some(payment) => payment,
+			None => return,
+		};
+		match payment.status {
+			HTLCStatus::Pending => {}
+			HTLCStatus::TimedOut if abandon => {}
+			_ => return,
+		}
+		let in_flight = payer.in_flight_parts(&payment_hash);
+		let failure = format!(
+			"{} with {} failed parts and {} still in flight{}",
+			if abandon { "deadline passed" } else { "timed out" },
+			payment.failed_parts,
+			in_flight,
+			match &payment.failure {
+				Some(last_failure) => format!(", last failure: {}", last_failure),
+				None => String::new(),
+			}
+		);
+		if abandon {
+			println!(
+				"\nEVENT: Abandoned payment to payment hash {} at its deadline: {}",
+				hex_utils::hex_str(&payment_hash.0),
+				failure
+			);
+		} else {
+			println!(
+				"\nEVENT: Gave up waiting on payment to payment hash {}: {}",
+				hex_utils::hex_str(&payment_hash.0),
+				failure
+			);
+		}
+		print!("> ");
+		io::stdout().flush().unwrap();
+		payment.status = if abandon { HTLCStatus::Failed } else { HTLCStatus::TimedOut };
+		payment.updated_at = time_now_secs();
+		payment.failure = Some(failure);
+		payments.persist();
+	});
 }
 
-fn keysend(
+/// Sends a keysend payment, returning its payment hash if we got as far as attempting it.
+pub(crate) fn keysend(
 	payee_pubkey: PublicKey, amt_msat: u64, network_graph: Arc<NetworkGraph>,
 	channel_manager: Arc<ChannelManager>, payment_storage: PaymentInfoStorage,
-	logger: Arc<FilesystemLogger>, scorer: Arc<Mutex<Scorer>>,
-) {
+	logger: Arc<FilesystemLogger>, scorer: Arc<Mutex<Scorer>>, fee_budget: FeeBudget,
+	payer_secret: Option<[u8; 32]>, route_exclusions: RouteExclusionsStorage,
+) -> Option<PaymentHash> {
 	let first_hops = channel_manager.list_usable_channels();
 	let payer_pubkey = channel_manager.get_our_node_id();
 
 	let payee = Payee::for_keysend(payee_pubkey);
 	let params = RouteParameters { payee, final_value_msat: amt_msat, final_cltv_expiry_delta: 40 };
 
+	let exclusions = route_exclusions.lock().unwrap();
 	let route = match router::find_route(
 		&payer_pubkey,
 		&params,
 		&network_graph,
 		Some(&first_hops.iter().collect::<Vec<_>>()),
 		logger,
-		&scorer.lock().unwrap(),
-	) {
+		&ExcludingScorer {
+			inner: &*scorer.lock().unwrap(),
+			exclusions: &exclusions,
+			now: time_now_secs(),
+		},
+	)
+	.and_then(|route| exclusions.check_route(&route).map(|_| route))
+	.and_then(|route| fee_budget.check_route(&route, amt_msat).map(|_| route))
+	.and_then(|route| {
+		fee_budget.check_counterparty(&route, &payment_storage.lock().unwrap()).map(|_| route)
+	}) {
 		Ok(r) => r,
 		Err(e) => {
 			println!("ERROR: failed to find route: {}", e.err);
-			return;
+			return None;
+		}
+	};
+	drop(exclusions);
+
+	// The preimage is sent to the recipient in the onion, so we pick it ourselves. Members of a
+	// closed group prove they know the group's secret through their choice of preimage.
+	let payment_preimage = match payer_secret {
+		Some(secret) => payer_auth::authenticated_keysend_preimage(&secret),
+		None => {
+			let mut preimage_bytes = [0; 32];
+			thread_rng().fill_bytes(&mut preimage_bytes);
+			PaymentPreimage(preimage_bytes)
+		}
+	};
+	let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
+
+	let status = match channel_manager.send_spontaneous_payment(&route, Some(payment_preimage)) {
+		Ok(_) => {
+			println!("EVENT: initiated sending {} msats to {}", amt_msat, payee_pubkey);
+			HTLCStatus::Pending
+		}
+		// Some paths are in flight, so the payment may still succeed.
+		Err(PaymentSendFailure::PartialFailure { .. }) => {
+			println!(
+				"EVENT: initiated sending {} msats to {}, though some paths failed",
+				amt_msat, payee_pubkey
+			);
+			HTLCStatus::Pending
+		}
+		Err(e) => {
+			println!("ERROR: failed to send payment: {:?}", e);
+			HTLCStatus::Failed
 		}
 	};
 
 	let mut payments = payment_storage.lock().unwrap();
-	let payment_hash = channel_manager.send_spontaneous_payment(&route, None).unwrap().0;
 	payments.insert(
 		payment_hash,
 		PaymentInfo {
-			preimage: None,
+			preimage: Some(payment_preimage),
 			secret: None,
-			status: HTLCStatus::Pending,
+			status,
 			amt_msat: MillisatAmount(Some(amt_msat)),
+			created_at: time_now_secs(),
+			updated_at: time_now_secs(),
+			expires_at: None,
+			route: Some(route),
+			failure: None,
+			failed_parts: 0,
+			fee_paid_msat: None,
+			invoice: None,
+			refund_of: None,
+			reissue_of: None,
 		},
 	);
+	payments.persist();
+	Some(payment_hash)
 }
 
-fn get_invoice(
-	amt_msat: u64, payment_storage: PaymentInfoStorage, channel_manager: Arc<ChannelManager>,
-	keys_manager: Arc<KeysManager>, network: Network,
-) {
-	let mut payments = payment_storage.lock().unwrap();
-	let currency = match network {
+fn network_currency(network: Network) -> Currency {
+	match network {
 		Network::Bitcoin => Currency::Bitcoin,
 		Network::Testnet => Currency::BitcoinTestnet,
 		Network::Regtest => Currency::Regtest,
-		Network::Signet => panic!("Signet unsupported"),
+		Network::Signet => Currency::Signet,
+	}
+}
+
+/// Refuses invoices for a network other than the one we're running on. Paying one couldn't succeed
+/// anyway, but a mainnet invoice handed to a test node, or vice versa, usually means the user has
+/// mixed up their nodes, which is worth saying plainly.
+pub(crate) fn check_invoice_network(invoice: &Invoice, network: Network) -> Result<(), String> {
+	if invoice.currency() == network_currency(network) {
+		return Ok(());
+	}
+	let invoice_network = match invoice.currency() {
+		Currency::Bitcoin => "mainnet",
+		Currency::BitcoinTestnet => "testnet",
+		Currency::Regtest => "regtest",
+		Currency::Simnet => "simnet",
+		Currency::Signet => "signet",
 	};
-	let invoice = match utils::create_invoice_from_channelmanager(
+	Err(format!("invoice is for {}, but this node is running on {}", invoice_network, network))
+}
+
+pub(crate) fn get_invoice(
+	amt_msat: u64, options: InvoiceOptions, payment_storage: PaymentInfoStorage,
+	channel_manager: Arc<ChannelManager>, keys_manager: Arc<KeysManager>, network: Network,
+	min_final_cltv_expiry: u32,
+) -> Result<Invoice, String> {
+	let mut payments = payment_storage.lock().unwrap();
+	let invoice = match create_invoice(
 		&channel_manager,
-		keys_manager,
-		currency,
-		Some(amt_msat),
-		"ldk-tutorial-node".to_string(),
+		&keys_manager,
+		network_currency(network),
+		amt_msat,
+		options,
+		min_final_cltv_expiry,
 	) {
 		Ok(inv) => {
 			println!("SUCCESS: generated invoice: {}", inv);
 			inv
 		}
 		Err(e) => {
-			println!("ERROR: failed to create invoice: {:?}", e);
-			return;
+			let err = format!("failed to create invoice: {:?}", e);
+			println!("ERROR: {}", err);
+			return Err(err);
 		}
 	};
 
@@ -696,27 +3570,477 @@ fn get_invoice(
 			secret: Some(invoice.payment_secret().clone()),
 			status: HTLCStatus::Pending,
 			amt_msat: MillisatAmount(Some(amt_msat)),
+			created_at: time_now_secs(),
+			updated_at: time_now_secs(),
+			expires_at: Some(invoice_expires_at(&invoice)),
+			route: None,
+			failure: None,
+			failed_parts: 0,
+			fee_paid_msat: None,
+			invoice: Some(invoice.to_string()),
+			refund_of: None,
+			reissue_of: None,
 		},
 	);
+	payments.persist();
+	Ok(invoice)
+}
+
+/// Creates a successor to an expired, unpaid invoice, for the same amount and with the same
+/// description and expiry, linked to it by `reissue_of`. If the invoice belongs to a bundle, the
+/// successor takes its place so that the bundle can still be settled.
+pub(crate) fn reissue_invoice(
+	payment_hash: &PaymentHash, inbound_payments: &PaymentInfoStorage,
+	invoice_bundles: &BundleStorage, channel_manager: &ChannelManager, keys_manager: &KeysManager,
+	network: Network, min_final_cltv_expiry: u32,
+) -> Result<Invoice, String> {
+	let mut payments = inbound_payments.lock().unwrap();
+	let (amt_msat, options) = {
+		let original = payments.get(payment_hash).ok_or("no invoice with that payment hash")?;
+		if let HTLCStatus::Succeeded = original.status {
+			return Err("the invoice has been paid".to_string());
+		}
+		// Re-issuing an invoice which can still be paid would let the payer pay twice.
+		if original.expires_at.map_or(true, |expires_at| expires_at >= time_now_secs()) {
+			return Err("the invoice hasn't expired".to_string());
+		}
+		if let Some((successor_hash, _)) =
+			payments.iter().find(|(_, payment)| payment.reissue_of == Some(*payment_hash))
+		{
+			return Err(format!(
+				"the invoice was already re-issued with payment hash {}",
+				hex_utils::hex_str(&successor_hash.0)
+			));
+		}
+		let invoice = original
+			.invoice
+			.as_ref()
+			.and_then(|invoice| Invoice::from_str(invoice).ok())
+			.ok_or("we don't have the invoice itself")?;
+		let amt_msat = original.amt_msat.0.ok_or("the invoice has no amount")?;
+		let description = match invoice.description() {
+			lightning_invoice::InvoiceDescription::Direct(description) => {
+				InvoiceDescription::Direct(String::from(&**description))
+			}
+			lightning_invoice::InvoiceDescription::Hash(hash) => InvoiceDescription::Hash(hash.0),
+		};
+		let expiry_secs = invoice.expiry_time().as_secs() as u32;
+		(amt_msat, InvoiceOptions { description, expiry_secs })
+	};
+
+	let invoice = create_invoice(
+		channel_manager,
+		keys_manager,
+		network_currency(network),
+		amt_msat,
+		options,
+		min_final_cltv_expiry,
+	)
+	.map_err(|e| format!("failed to create invoice: {:?}", e))?;
+	let successor_hash = PaymentHash(invoice.payment_hash().clone().into_inner());
+	payments.insert(
+		successor_hash,
+		PaymentInfo {
+			preimage: None,
+			secret: Some(invoice.payment_secret().clone()),
+			status: HTLCStatus::Pending,
+			amt_msat: MillisatAmount(Some(amt_msat)),
+			created_at: time_now_secs(),
+			updated_at: time_now_secs(),
+			expires_at: Some(invoice_expires_at(&invoice)),
+			route: None,
+			failure: None,
+			failed_parts: 0,
+			fee_paid_msat: None,
+			invoice: Some(invoice.to_string()),
+			refund_of: None,
+			reissue_of: Some(*payment_hash),
+		},
+	);
+	payments.persist();
+	drop(payments);
+	invoice_bundles.lock().unwrap().replace(payment_hash, successor_hash);
+	Ok(invoice)
+}
+
+/// Creates an invoice for each of `amounts_msat` and records them as a bundle which is settled once
+/// they've all been paid, returning the bundle's id and invoices.
+pub(crate) fn create_bundle(
+	amounts_msat: &[u64], invoice_bundles: BundleStorage, inbound_payments: PaymentInfoStorage,
+	channel_manager: Arc<ChannelManager>, keys_manager: Arc<KeysManager>, network: Network,
+	min_final_cltv_expiry: u32,
+) -> Result<(u64, Vec<Invoice>), String> {
+	let mut invoices = Vec::with_capacity(amounts_msat.len());
+	for amt_msat in amounts_msat {
+		invoices.push(get_invoice(
+			*amt_msat,
+			InvoiceOptions::default(),
+			inbound_payments.clone(),
+			channel_manager.clone(),
+			keys_manager.clone(),
+			network,
+			min_final_cltv_expiry,
+		)?);
+	}
+	let payment_hashes = invoices
+		.iter()
+		.map(|invoice| PaymentHash(invoice.payment_hash().clone().into_inner()))
+		.collect();
+	let bundle_id = invoice_bundles.lock().unwrap().insert(payment_hashes);
+	println!("SUCCESS: created invoice bundle {}", bundle_id);
+	Ok((bundle_id, invoices))
+}
+
+fn bundle_status(
+	bundle_id: u64, invoice_bundles: BundleStorage, inbound_payments: PaymentInfoStorage,
+) {
+	let bundles = invoice_bundles.lock().unwrap();
+	let bundle = match bundles.get(bundle_id) {
+		Some(bundle) => bundle,
+		None => {
+			println!("ERROR: unknown invoice bundle {}", bundle_id);
+			return;
+		}
+	};
+	let inbound = inbound_payments.lock().unwrap();
+	println!("\t{{");
+	println!("\t\tbundle_id: {},", bundle_id);
+	println!("\t\tstatus: {},", bundle.status(&inbound).as_str());
+	println!("\t\tcreated_at: {},", bundle.created_at);
+	print!("\t\tinvoices: [");
+	for payment_hash in bundle.payment_hashes.iter() {
+		match inbound.get(payment_hash) {
+			Some(payment_info) => print_payment(payment_hash, payment_info, "inbound", false),
+			None => {
+				println!("");
+				println!("\t{{");
+				println!("\t\tpayment_hash: {},", hex_utils::hex_str(&payment_hash.0));
+				println!("\t\thtlc_status: unknown,");
+				println!("\t}},");
+			}
+		}
+	}
+	println!("],");
+	println!("\t}},");
+}
+
+/// Like lightning-invoice's `create_invoice_from_channelmanager`, but requiring payers to leave
+/// us at least `min_final_cltv_expiry` blocks to claim the payment rather than LDK's minimum.
+fn create_invoice(
+	channel_manager: &ChannelManager, keys_manager: &KeysManager, currency: Currency,
+	amt_msat: u64, options: InvoiceOptions, min_final_cltv_expiry: u32,
+) -> Result<Invoice, CreationError> {
+	let (payment_hash, payment_secret) =
+		channel_manager.create_inbound_payment(Some(amt_msat), options.expiry_secs, 0);
+	let mut invoice = InvoiceBuilder::new(currency)
+		.current_timestamp()
+		.expiry_time(Duration::from_secs(options.expiry_secs as u64))
+		.payee_pub_key(channel_manager.get_our_node_id())
+		.payment_hash(Sha256::from_slice(&payment_hash.0).unwrap())
+		.payment_secret(payment_secret)
+		.basic_mpp()
+		.min_final_cltv_expiry(min_final_cltv_expiry as u64)
+		.amount_milli_satoshis(amt_msat);
+
+	// Include route hints for our private channels so that we can be paid over them. Payers can
+	// already find our public channels in the network graph.
+	for channel in channel_manager.list_usable_channels() {
+		if channel.is_public {
+			continue;
+		}
+		let short_channel_id = match channel.short_channel_id {
+			Some(id) => id,
+			None => continue,
+		};
+		let forwarding_info = match channel.counterparty.forwarding_info {
+			Some(info) => info,
+			None => continue,
+		};
+		invoice = invoice.private_route(RouteHint(vec![RouteHintHop {
+			src_node_id: channel.counterparty.node_id,
+			short_channel_id,
+			fees: RoutingFees {
+				base_msat: forwarding_info.fee_base_msat,
+				proportional_millionths: forwarding_info.fee_proportional_millionths,
+			},
+			cltv_expiry_delta: forwarding_info.cltv_expiry_delta,
+			htlc_minimum_msat: None,
+			htlc_maximum_msat: None,
+		}]));
+	}
+
+	let invoice = match options.description {
+		InvoiceDescription::Direct(description) => invoice.description(description),
+		InvoiceDescription::Hash(hash) => invoice.description_hash(hash),
+	};
+
+	let node_secret = keys_manager.get_node_secret();
+	let secp_ctx = Secp256k1::signing_only();
+	invoice.build_signed(|msg_hash| secp_ctx.sign_recoverable(msg_hash, &node_secret))
+}
+
+fn wallet_label(role: WalletRole, bitcoind_client: &BitcoindClient) -> &str {
+	if bitcoind_client.onchain_wallet().is_some() {
+		return "internal";
+	}
+	bitcoind_client.wallet_name(role).unwrap_or("default")
+}
+
+fn get_fees(bitcoind_client: &BitcoindClient) {
+	let fees = bitcoind_client.cached_fees();
+	println!("\t{{");
+	for fee in fees.feerates {
+		println!("\t\t{}: {{", fee.target.as_str());
+		println!("\t\t\tconf_target_blocks: {},", fee.conf_target);
+		println!("\t\t\tsat_per_kw: {},", fee.feerate_sat_per_kw);
+		println!("\t\t\tsat_per_vbyte: {:.2},", fee.feerate_sat_per_kw as f64 / 250.0);
+		println!("\t\t\tsource: {},", fee.source.as_str());
+		println!("\t\t}},");
+	}
+	if let Some(feerate) = fees.mempool_min_feerate {
+		println!("\t\tmempool_min_sat_per_kw: {},", feerate);
+	}
+	match fees.age {
+		Some(age) => println!("\t\tupdated_secs_ago: {},", age.as_secs()),
+		None => println!("\t\tupdated_secs_ago: never,"),
+	}
+	println!("\t}},");
+}
+
+/// Prints the fee estimates we had when we broadcast `txid`, and the fee it paid if our wallet
+/// knows it.
+async fn get_transaction(txid: &Txid, bitcoind_client: &BitcoindClient) {
+	let fee_sat = bitcoind_client.get_transaction_fee(txid).await.unwrap_or(None);
+	let known = bitcoind_client.fee_snapshots().with_snapshot(txid, |snapshot| {
+		println!("\t{{");
+		println!("\t\ttxid: {},", txid);
+		println!("\t\tpurpose: {},", snapshot.purpose.as_str());
+		println!("\t\tbroadcast_at: {},", snapshot.timestamp);
+		if let Some(weight) = snapshot.weight {
+			println!("\t\tweight: {},", weight);
+		}
+		if let Some(fee_sat) = fee_sat {
+			println!("\t\tfee_sat: {},", fee_sat);
+			if let Some(weight) = snapshot.weight {
+				println!("\t\tsat_per_kw: {},", fee_sat * 1000 / weight as u64);
+			}
+		}
+		match &snapshot.built_at {
+			Some(target) => println!("\t\tbuilt_at_estimate: {},", target.as_str()),
+			None => println!("\t\tbuilt_at_estimate: none,"),
+		}
+		println!("\t\tfee_estimates: {{");
+		for fee in snapshot.fees.feerates.iter() {
+			println!("\t\t\t{}: {{", fee.target.as_str());
+			println!("\t\t\t\tconf_target_blocks: {},", fee.conf_target);
+			println!("\t\t\t\tsat_per_kw: {},", fee.feerate_sat_per_kw);
+			println!("\t\t\t\tsource: {},", fee.source.as_str());
+			println!("\t\t\t}},");
+		}
+		if let Some(feerate) = snapshot.fees.mempool_min_feerate {
+			println!("\t\t\tmempool_min_sat_per_kw: {},", feerate);
+		}
+		match snapshot.fees.age {
+			Some(age) => println!("\t\t\tupdated_secs_before: {},", age.as_secs()),
+			None => println!("\t\t\tupdated_secs_before: never,"),
+		}
+		println!("\t\t}},");
+		println!("\t}},");
+	});
+	if !known {
+		println!("ERROR: no fee snapshot for transaction {}, we didn't broadcast it", txid);
+	}
 }
 
-fn close_channel(channel_id: [u8; 32], channel_manager: Arc<ChannelManager>) {
-	match channel_manager.close_channel(&channel_id) {
-		Ok(()) => println!("EVENT: initiating channel close"),
-		Err(e) => println!("ERROR: failed to close channel: {:?}", e),
+async fn wallet_balance(bitcoind_client: &BitcoindClient) {
+	let roles: &[(WalletRole, &str)] = if bitcoind_client.single_wallet() {
+		&[(WalletRole::Funding, "funding and operating")]
+	} else {
+		&[(WalletRole::Funding, "funding"), (WalletRole::Operating, "operating")]
+	};
+	print!("[");
+	for (role, role_name) in roles {
+		let balances = match bitcoind_client.get_wallet_balances(*role).await {
+			Ok(balances) => balances,
+			Err(e) => {
+				println!("]");
+				println!("ERROR: failed to get the {} wallet's balance: {}", role_name, e);
+				return;
+			}
+		};
+		println!("");
+		println!("\t{{");
+		println!("\t\twallet: {},", wallet_label(*role, bitcoind_client));
+		println!("\t\trole: {},", role_name);
+		println!("\t\tconfirmed_sat: {},", balances.confirmed_sat);
+		println!("\t\tunconfirmed_sat: {},", balances.unconfirmed_sat);
+		println!("\t\timmature_sat: {},", balances.immature_sat);
+		println!("\t}},");
 	}
+	println!("]");
 }
 
-fn force_close_channel(channel_id: [u8; 32], channel_manager: Arc<ChannelManager>) {
+async fn list_unspent(role: WalletRole, bitcoind_client: &BitcoindClient) {
+	let utxos = match bitcoind_client.list_unspent(role).await {
+		Ok(utxos) => utxos,
+		Err(e) => {
+			println!("ERROR: failed to list unspent outputs: {}", e);
+			return;
+		}
+	};
+	println!("wallet: {}", wallet_label(role, bitcoind_client));
+	print!("[");
+	for utxo in utxos {
+		println!("");
+		println!("\t{{");
+		println!("\t\toutpoint: {}:{},", utxo.txid, utxo.vout);
+		println!("\t\tamount_sat: {},", utxo.amount_sat);
+		println!("\t\tconfirmations: {},", utxo.confirmations);
+		if let Some(address) = utxo.address {
+			println!("\t\taddress: {},", address);
+		}
+		println!("\t}},");
+	}
+	println!("]");
+}
+
+fn list_spendable_outputs(output_sweeper: &OutputSweeper) {
+	print!("[");
+	for output in output_sweeper.unswept_outputs() {
+		println!("");
+		println!("\t{{");
+		println!("\t\toutpoint: {}:{},", output.outpoint.txid, output.outpoint.index);
+		println!("\t\tvalue_sat: {},", output.value_sat);
+		println!("\t\tkind: {},", output.kind);
+		if let Some(txid) = output.sweep_txid {
+			println!("\t\tsweep_txid: {},", txid);
+		}
+		println!("\t\tsweep_attempts: {},", output.attempts);
+		println!("\t}},");
+	}
+	println!("]");
+}
+
+async fn mine(num_blocks: u64, bitcoind_client: Arc<BitcoindClient>) {
+	let address = match bitcoind_client.get_new_address().await {
+		Ok(address) => address,
+		Err(e) => {
+			println!("ERROR: failed to get an address to mine to: {}", e);
+			return;
+		}
+	};
+	match bitcoind_client.generate_to_address(num_blocks, &address).await {
+		Ok(blocks) => println!("SUCCESS: mined {} blocks to {}", blocks.len(), address),
+		Err(e) => println!("ERROR: failed to mine blocks: {}", e),
+	}
+}
+
+async fn faucet(amt_sat: u64, address: Option<Address>, bitcoind_client: Arc<BitcoindClient>) {
+	let address = match address {
+		Some(addr) => addr,
+		None => match bitcoind_client.get_new_address().await {
+			Ok(addr) => addr,
+			Err(e) => {
+				println!("ERROR: failed to get an address to send to: {}", e);
+				return;
+			}
+		},
+	};
+	let txid = match bitcoind_client.send_to_address(&address, amt_sat).await {
+		Ok(txid) => txid,
+		Err(e) => {
+			// Coinbase outputs need 100 confirmations before bitcoind's wallet can spend them.
+			println!("ERROR: failed to send funds, try `mine 101` first: {}", e);
+			return;
+		}
+	};
+	// Mine a block so the funds are confirmed and immediately usable for channel funding.
+	let mine_res = match bitcoind_client.get_new_address().await {
+		Ok(mine_address) => bitcoind_client.generate_to_address(1, &mine_address).await.map(|_| ()),
+		Err(e) => Err(e),
+	};
+	if let Err(e) = mine_res {
+		println!("ERROR: sent funds in {} but failed to confirm them: {}", txid, e);
+		return;
+	}
+	println!("SUCCESS: sent {} sats to {} in confirmed tx {}", amt_sat, address, txid);
+}
+
+/// The outpoint funding the channel with the given id, whose spend will close it. Channels which
+/// were never funded have none, and close without anything being broadcast.
+fn funding_outpoint(channel_id: &[u8; 32], channel_manager: &ChannelManager) -> Option<OutPoint> {
+	channel_manager
+		.list_channels()
+		.iter()
+		.find(|chan| chan.channel_id == *channel_id)
+		.and_then(|chan| chan.funding_txo)
+		.map(|txo| txo.into_bitcoin_outpoint())
+}
+
+fn parse_close_address(addr: &str, network: Network) -> Result<ShutdownScript, String> {
+	let address = Address::from_str(addr).map_err(|e| format!("invalid address: {}", e))?;
+	if address.network != network {
+		return Err(format!("{} is not a {} address", addr, network));
+	}
+	ShutdownScript::try_from(address.script_pubkey()).map_err(|_| {
+		format!("{} can't be closed to, as closing outputs must be segwit or P2SH/P2PKH", addr)
+	})
+}
+
+fn close_channel(
+	channel_id: [u8; 32], close_script: Option<ShutdownScript>,
+	channel_manager: Arc<ChannelManager>, shutdown_keys: &ShutdownKeysManager,
+	bitcoind_client: &BitcoindClient,
+) {
+	// Closing a channel only starts negotiating the closing transaction with our peer, so we
+	// report its txid when it's eventually broadcast.
+	let funding_outpoint = funding_outpoint(&channel_id, &channel_manager);
+	if let Some(outpoint) = funding_outpoint {
+		bitcoind_client.report_close_broadcast(outpoint, channel_id);
+	}
+	let res = match close_script {
+		Some(script) => shutdown_keys.close_channel_to(&channel_manager, &channel_id, script),
+		None => channel_manager
+			.close_channel(&channel_id)
+			.map(|()| true)
+			.map_err(|e| format!("{:?}", e)),
+	};
+	match res {
+		Ok(true) => println!("EVENT: initiating channel close"),
+		Ok(false) => println!(
+			"EVENT: initiating channel close. The channel committed to paying our wallet when it was opened, so it can't be closed to the given address"
+		),
+		Err(e) => {
+			if let Some(outpoint) = funding_outpoint {
+				bitcoind_client.cancel_close_report(&outpoint);
+			}
+			println!("ERROR: failed to close channel: {}", e)
+		}
+	}
+}
+
+fn force_close_channel(
+	channel_id: [u8; 32], channel_manager: Arc<ChannelManager>, bitcoind_client: &BitcoindClient,
+) {
+	// Our commitment transaction is broadcast while force-closing, so watch for it beforehand.
+	let funding_outpoint = funding_outpoint(&channel_id, &channel_manager);
+	if let Some(outpoint) = funding_outpoint {
+		bitcoind_client.report_close_broadcast(outpoint, channel_id);
+	}
 	match channel_manager.force_close_channel(&channel_id) {
 		Ok(()) => println!("EVENT: initiating channel force-close"),
-		Err(e) => println!("ERROR: failed to force-close channel: {:?}", e),
+		Err(e) => {
+			if let Some(outpoint) = funding_outpoint {
+				bitcoind_client.cancel_close_report(&outpoint);
+			}
+			println!("ERROR: failed to force-close channel: {:?}", e)
+		}
 	}
 }
 
 pub(crate) fn parse_peer_info(
 	peer_pubkey_and_ip_addr: String,
-) -> Result<(PublicKey, SocketAddr), std::io::Error> {
+) -> Result<(PublicKey, PeerAddr), std::io::Error> {
 	let mut pubkey_and_addr = peer_pubkey_and_ip_addr.split("@");
 	let pubkey = pubkey_and_addr.next();
 	let peer_addr_str = pubkey_and_addr.next();
@@ -727,7 +4051,13 @@ pub(crate) fn parse_peer_info(
 		));
 	}
 
-	let peer_addr = peer_addr_str.unwrap().to_socket_addrs().map(|mut r| r.next());
+	// Onion addresses can't be resolved locally, we leave that to the proxy.
+	let peer_addr = match peer_addr_str.unwrap().rsplitn(2, ':').collect::<Vec<_>>().as_slice() {
+		[port, host] if host.ends_with(".onion") => {
+			Ok(port.parse().ok().map(|port| PeerAddr::Onion(host.to_string(), port)))
+		}
+		_ => peer_addr_str.unwrap().to_socket_addrs().map(|mut r| r.next().map(PeerAddr::Socket)),
+	};
 	if peer_addr.is_err() || peer_addr.as_ref().unwrap().is_none() {
 		return Err(std::io::Error::new(
 			std::io::ErrorKind::Other,
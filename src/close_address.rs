@@ -0,0 +1,100 @@
+use crate::channel_backup::ChannelKeysLog;
+use crate::sweep_wallet::SweepWallet;
+use crate::ChannelManager;
+use bitcoin::blockdata::script::Script;
+use bitcoin::secp256k1::key::SecretKey;
+use bitcoin::secp256k1::recovery::RecoverableSignature;
+use lightning::chain::keysinterface::{BaseSign, InMemorySigner, KeysInterface, KeysManager};
+use lightning::ln::msgs::DecodeError;
+use lightning::ln::script::ShutdownScript;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+/// The keys we hand LDK: our `KeysManager`'s, except that a channel we're cooperatively closing
+/// can be given a shutdown script of the user's choosing, e.g. paying to a cold wallet. If a sweep
+/// wallet is configured, channels' closing and claimed funds are paid to it rather than to
+/// addresses derived from our seed.
+///
+/// LDK only asks for a shutdown script when a channel starts closing if it didn't commit to one
+/// when the channel was opened, so this only works for channels opened with
+/// `commit_upfront_shutdown_pubkey` disabled.
+///
+/// The keys ids of the channel signers we hand out or read back are logged for channel backups.
+pub(crate) struct ShutdownKeysManager {
+	inner: Arc<KeysManager>,
+	close_script: Mutex<Option<ShutdownScript>>,
+	sweep_wallet: Option<Arc<SweepWallet>>,
+	channel_keys_log: ChannelKeysLog,
+}
+
+impl ShutdownKeysManager {
+	pub(crate) fn new(
+		inner: Arc<KeysManager>, sweep_wallet: Option<Arc<SweepWallet>>,
+		channel_keys_log: ChannelKeysLog,
+	) -> Self {
+		Self { inner, close_script: Mutex::new(None), sweep_wallet, channel_keys_log }
+	}
+
+	pub(crate) fn channel_keys_log(&self) -> &ChannelKeysLog {
+		&self.channel_keys_log
+	}
+
+	/// Starts cooperatively closing a channel, paying our balance to `script`. Returns whether the
+	/// script is used, which it isn't if the channel committed to its shutdown script when it was
+	/// opened, in which case the channel still closes but pays to our wallet.
+	pub(crate) fn close_channel_to(
+		&self, channel_manager: &ChannelManager, channel_id: &[u8; 32], script: ShutdownScript,
+	) -> Result<bool, String> {
+		*self.close_script.lock().unwrap() = Some(script);
+		let res = channel_manager.close_channel(channel_id);
+		let script_used = self.close_script.lock().unwrap().take().is_none();
+		res.map(|()| script_used).map_err(|e| format!("{:?}", e))
+	}
+}
+
+impl KeysInterface for ShutdownKeysManager {
+	type Signer = InMemorySigner;
+
+	fn get_node_secret(&self) -> SecretKey {
+		self.inner.get_node_secret()
+	}
+
+	fn get_destination_script(&self) -> Script {
+		match &self.sweep_wallet {
+			Some(sweep_wallet) => sweep_wallet.next_script(),
+			None => self.inner.get_destination_script(),
+		}
+	}
+
+	fn get_shutdown_scriptpubkey(&self) -> ShutdownScript {
+		if let Some(script) = self.close_script.lock().unwrap().take() {
+			return script;
+		}
+		match &self.sweep_wallet {
+			Some(sweep_wallet) => ShutdownScript::try_from(sweep_wallet.next_script())
+				.expect("sweep wallet scripts are checked to be valid shutdown scripts"),
+			None => self.inner.get_shutdown_scriptpubkey(),
+		}
+	}
+
+	fn get_channel_signer(&self, inbound: bool, channel_value_satoshis: u64) -> InMemorySigner {
+		let signer = self.inner.get_channel_signer(inbound, channel_value_satoshis);
+		self.channel_keys_log.record(signer.channel_keys_id());
+		signer
+	}
+
+	fn get_secure_random_bytes(&self) -> [u8; 32] {
+		self.inner.get_secure_random_bytes()
+	}
+
+	fn read_chan_signer(&self, reader: &[u8]) -> Result<InMemorySigner, DecodeError> {
+		// Channels opened before we kept the log get into it when the channel manager is read.
+		let signer = self.inner.read_chan_signer(reader)?;
+		self.channel_keys_log.record(signer.channel_keys_id());
+		Ok(signer)
+	}
+
+	fn sign_invoice(&self, invoice_preimage: Vec<u8>) -> Result<RecoverableSignature, ()> {
+		self.inner.sign_invoice(invoice_preimage)
+	}
+}
@@ -0,0 +1,665 @@
+use crate::{time_now_secs, ChainMonitor, ChannelManager};
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction};
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::{BlockHash, FilterHash, FilterHeader, Txid};
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::network::message_filter::{GetCFHeaders, GetCFilters};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::util::uint::Uint256;
+use lightning::chain::transaction::TransactionData;
+use lightning::chain::{BestBlock, Confirm, Filter, WatchedOutput};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// How long we wait for the peer to answer a request before giving up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often we ask the peer for new headers if it hasn't announced a block.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// How long we wait before reconnecting to the peer after losing it.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Peers send at most this many headers at a time, so a full batch means there are more.
+const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+/// Anything longer isn't a valid message, and we shouldn't allocate for it.
+const MAX_MESSAGE_LEN: usize = 32 * 1024 * 1024;
+/// The BIP 158 basic filter, the only type there is.
+const BASIC_FILTER_TYPE: u8 = 0;
+/// Peers serve at most this many filter headers at a time.
+const MAX_CFHEADERS_PER_MESSAGE: u32 = 2000;
+/// The difficulty is retargeted every this many blocks, aiming for them to take two weeks.
+const RETARGET_INTERVAL: u32 = 2016;
+const TARGET_TIMESPAN_SECS: u32 = 14 * 24 * 60 * 60;
+const TARGET_SPACING_SECS: u32 = 10 * 60;
+/// How many of the most recent headers we keep: enough to work out the next difficulty from and
+/// to reorganize past any fork we can expect.
+const HEADER_WINDOW: usize = 2 * RETARGET_INTERVAL as usize;
+
+/// The scripts LDK asks us to watch the chain for, as outputs paying to them or inputs spending
+/// from them. BIP 158 basic filters cover both, so these are all we match blocks' filters against.
+/// We also keep the transactions and outputs themselves, which is all we can look up on a pruned
+/// bitcoind, see `pruned_sync`.
+pub(crate) struct WatchedScripts {
+	scripts: Mutex<HashSet<Script>>,
+	txids: Mutex<HashSet<Txid>>,
+	outputs: Mutex<HashSet<OutPoint>>,
+}
+
+impl WatchedScripts {
+	pub(crate) fn new() -> Self {
+		Self {
+			scripts: Mutex::new(HashSet::new()),
+			txids: Mutex::new(HashSet::new()),
+			outputs: Mutex::new(HashSet::new()),
+		}
+	}
+
+	fn scripts(&self) -> Vec<Script> {
+		self.scripts.lock().unwrap().iter().cloned().collect()
+	}
+
+	pub(crate) fn txids(&self) -> Vec<Txid> {
+		self.txids.lock().unwrap().iter().cloned().collect()
+	}
+
+	pub(crate) fn outputs(&self) -> Vec<OutPoint> {
+		self.outputs.lock().unwrap().iter().cloned().collect()
+	}
+}
+
+impl Filter for WatchedScripts {
+	fn register_tx(&self, txid: &Txid, script_pubkey: &Script) {
+		self.scripts.lock().unwrap().insert(script_pubkey.clone());
+		self.txids.lock().unwrap().insert(*txid);
+	}
+
+	fn register_output(&self, output: WatchedOutput) -> Option<(usize, Transaction)> {
+		self.outputs.lock().unwrap().insert(output.outpoint.into_bitcoin_outpoint());
+		self.scripts.lock().unwrap().insert(output.script_pubkey);
+		// We hand LDK every transaction of a block which matches, so it already sees spends of
+		// this output in the same block.
+		None
+	}
+}
+
+/// A connection to a peer serving BIP 157 compact block filters.
+struct FilterPeer {
+	stream: TcpStream,
+	network: Network,
+}
+
+impl FilterPeer {
+	async fn connect(addr: SocketAddr, network: Network) -> io::Result<Self> {
+		let stream = TcpStream::connect(addr).await?;
+		let mut peer = Self { stream, network };
+		let mut version = VersionMessage::new(
+			ServiceFlags::NONE,
+			time_now_secs() as i64,
+			Address::new(&addr, ServiceFlags::NONE),
+			Address::new(&SocketAddr::from(([0, 0, 0, 0], 0)), ServiceFlags::NONE),
+			rand::random(),
+			format!("/ldk-tutorial-node:{}/", env!("CARGO_PKG_VERSION")),
+			0,
+		);
+		// We only want what we ask for, not every transaction the peer sees.
+		version.relay = false;
+		peer.send(NetworkMessage::Version(version)).await?;
+		let services = peer
+			.expect(|msg| match msg {
+				NetworkMessage::Version(version) => Some(version.services),
+				_ => None,
+			})
+			.await?;
+		if !services.has(ServiceFlags::COMPACT_FILTERS) || !services.has(ServiceFlags::WITNESS) {
+			return Err(io::Error::new(
+				io::ErrorKind::Other,
+				"peer doesn't serve compact block filters, run bitcoind with -blockfilterindex=1 -peerblockfilters=1",
+			));
+		}
+		peer.send(NetworkMessage::Verack).await?;
+		peer.expect(|msg| match msg {
+			NetworkMessage::Verack => Some(()),
+			_ => None,
+		})
+		.await?;
+		Ok(peer)
+	}
+
+	async fn send(&mut self, payload: NetworkMessage) -> io::Result<()> {
+		let msg = RawNetworkMessage { magic: self.network.magic(), payload };
+		self.stream.write_all(&encode::serialize(&msg)).await
+	}
+
+	/// Reads the next message we understand, skipping any we don't.
+	async fn recv(&mut self) -> io::Result<NetworkMessage> {
+		loop {
+			// The header is the network magic, command, payload length and checksum.
+			let mut msg = vec![0; 24];
+			self.stream.read_exact(&mut msg).await?;
+			let mut len_bytes = [0; 4];
+			len_bytes.copy_from_slice(&msg[16..20]);
+			let len = u32::from_le_bytes(len_bytes) as usize;
+			if len > MAX_MESSAGE_LEN {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "oversized message"));
+			}
+			msg.resize(24 + len, 0);
+			self.stream.read_exact(&mut msg[24..]).await?;
+			match encode::deserialize::<RawNetworkMessage>(&msg) {
+				Ok(msg) if msg.magic == self.network.magic() => return Ok(msg.payload),
+				Ok(_) => {
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						"peer is on a different network",
+					))
+				}
+				Err(_) => continue,
+			}
+		}
+	}
+
+	/// Waits for the message `f` picks out, answering pings and ignoring anything else meanwhile.
+	async fn expect<T, F: FnMut(NetworkMessage) -> Option<T>>(
+		&mut self, mut f: F,
+	) -> io::Result<T> {
+		let wait = async {
+			loop {
+				match self.recv().await? {
+					NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce)).await?,
+					msg => {
+						if let Some(res) = f(msg) {
+							return Ok::<_, io::Error>(res);
+						}
+					}
+				}
+			}
+		};
+		match tokio::time::timeout(REQUEST_TIMEOUT, wait).await {
+			Ok(res) => res,
+			Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "peer didn't answer in time")),
+		}
+	}
+
+	/// Answers any pings the peer sent while we weren't listening, without waiting for more.
+	async fn answer_pings(&mut self) -> io::Result<()> {
+		while let Ok(readable) =
+			tokio::time::timeout(Duration::from_secs(0), self.stream.readable()).await
+		{
+			readable?;
+			if let NetworkMessage::Ping(nonce) = self.recv().await? {
+				self.send(NetworkMessage::Pong(nonce)).await?;
+			}
+		}
+		Ok(())
+	}
+}
+
+fn chain_error(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, msg)
+}
+
+/// The easiest difficulty allowed on `network`, in compact form.
+fn pow_limit_bits(network: Network) -> u32 {
+	match network {
+		Network::Bitcoin | Network::Testnet => 0x1d00ffff,
+		Network::Signet => 0x1e0377ae,
+		Network::Regtest => 0x207fffff,
+	}
+}
+
+/// The most recent headers of the best chain we know of, which we check the proof of work of all
+/// the way from the genesis block, so that a peer can't feed us a chain without mining it at the
+/// chain's actual difficulty. Older headers are forgotten, so memory stays bounded.
+struct HeaderChain {
+	network: Network,
+	/// Heights, hashes and headers, oldest first.
+	recent: VecDeque<(u32, BlockHash, BlockHeader)>,
+}
+
+impl HeaderChain {
+	fn new(network: Network) -> Self {
+		let genesis = genesis_block(network).header;
+		let mut recent = VecDeque::new();
+		recent.push_back((0, genesis.block_hash(), genesis));
+		Self { network, recent }
+	}
+
+	fn tip(&self) -> &(u32, BlockHash, BlockHeader) {
+		self.recent.back().unwrap()
+	}
+
+	fn tip_height(&self) -> u32 {
+		self.tip().0
+	}
+
+	fn entry_at(&self, height: u32) -> Option<&(u32, BlockHash, BlockHeader)> {
+		let oldest = self.recent.front().unwrap().0;
+		self.recent.get(height.checked_sub(oldest)? as usize)
+	}
+
+	fn hash_at(&self, height: u32) -> Option<BlockHash> {
+		self.entry_at(height).map(|(_, hash, _)| *hash)
+	}
+
+	fn header_at(&self, height: u32) -> Option<&BlockHeader> {
+		self.entry_at(height).map(|(_, _, header)| header)
+	}
+
+	fn height_of(&self, block_hash: &BlockHash) -> Option<u32> {
+		self.recent
+			.iter()
+			.rev()
+			.find(|(_, hash, _)| hash == block_hash)
+			.map(|(height, _, _)| *height)
+	}
+
+	/// The hashes of our most recent blocks, then exponentially fewer back to the oldest we know,
+	/// so the peer can tell where its chain forks from ours.
+	fn locator(&self) -> Vec<BlockHash> {
+		let mut locator = Vec::new();
+		let mut idx = self.recent.len() - 1;
+		let mut step = 1;
+		while idx > 0 {
+			locator.push(self.recent[idx].1);
+			if locator.len() >= 10 {
+				step *= 2;
+			}
+			idx = idx.saturating_sub(step);
+		}
+		locator.push(self.recent[0].1);
+		locator
+	}
+
+	/// The difficulty the block after our tip must have, as Bitcoin Core works it out, or `None`
+	/// if that needs headers we no longer have.
+	fn next_bits(&self, header: &BlockHeader) -> Option<u32> {
+		let (tip_height, _, tip) = self.tip();
+		let height = tip_height + 1;
+		let pow_limit = pow_limit_bits(self.network);
+		if self.network == Network::Regtest {
+			return Some(tip.bits);
+		}
+		if height % RETARGET_INTERVAL != 0 {
+			if self.network != Network::Testnet {
+				return Some(tip.bits);
+			}
+			// Testnet allows a minimum difficulty block once a block has taken 20 minutes, and
+			// otherwise wants the difficulty of the last block which wasn't such a block.
+			if header.time > tip.time + 2 * TARGET_SPACING_SECS {
+				return Some(pow_limit);
+			}
+			let mut height = *tip_height;
+			loop {
+				let bits = self.header_at(height)?.bits;
+				if height % RETARGET_INTERVAL == 0 || bits != pow_limit {
+					return Some(bits);
+				}
+				height -= 1;
+			}
+		}
+		let first = self.header_at(height - RETARGET_INTERVAL)?;
+		let timespan = tip
+			.time
+			.saturating_sub(first.time)
+			.max(TARGET_TIMESPAN_SECS / 4)
+			.min(TARGET_TIMESPAN_SECS * 4);
+		let target = BlockHeader::u256_from_compact_target(tip.bits).mul_u32(timespan)
+			/ Uint256::from_u64(TARGET_TIMESPAN_SECS as u64).unwrap();
+		let limit = BlockHeader::u256_from_compact_target(pow_limit);
+		Some(BlockHeader::compact_target_from_u256(if target > limit { &limit } else { &target }))
+	}
+
+	/// Adds `header` on top of our tip if it builds on it with the right difficulty and enough
+	/// work, returning the hash of the header we forgot to make room, if any.
+	fn connect(&mut self, header: BlockHeader) -> io::Result<Option<BlockHash>> {
+		let (tip_height, tip_hash, _) = *self.tip();
+		if header.prev_blockhash != tip_hash {
+			return Err(chain_error("peer sent headers which don't form a chain"));
+		}
+		let bits = self
+			.next_bits(&header)
+			.ok_or_else(|| chain_error("not enough headers to check the difficulty"))?;
+		if header.bits != bits || header.validate_pow(&header.target()).is_err() {
+			return Err(chain_error("peer sent a header without the required proof of work"));
+		}
+		self.recent.push_back((tip_height + 1, header.block_hash(), header));
+		if self.recent.len() > HEADER_WINDOW {
+			return Ok(self.recent.pop_front().map(|(_, hash, _)| hash));
+		}
+		Ok(None)
+	}
+
+	/// Forgets every header above `height`, returning their hashes, newest first.
+	fn disconnect_above(&mut self, height: u32) -> io::Result<Vec<BlockHash>> {
+		if self.entry_at(height).is_none() {
+			return Err(chain_error("the chain reorganized deeper than the headers we keep"));
+		}
+		let mut disconnected = Vec::new();
+		while self.tip_height() > height {
+			disconnected.push(self.recent.pop_back().unwrap().1);
+		}
+		Ok(disconnected)
+	}
+}
+
+/// The filter header committing to a block's filter, which BIP 157 chains from block to block.
+fn next_filter_header(filter_hash: &FilterHash, previous: &FilterHeader) -> FilterHeader {
+	let mut engine = FilterHeader::engine();
+	engine.input(&filter_hash[..]);
+	engine.input(&previous[..]);
+	FilterHeader::from_engine(engine)
+}
+
+/// Keeps LDK synced to the chain using compact block filters, only downloading the blocks whose
+/// filter matches one of the scripts we watch. Every block's header is passed to LDK, so that
+/// timeouts still happen on time.
+///
+/// Headers are checked from the genesis block on, and the filter headers committing to each
+/// block's filter have to be the same from every peer we sync from before a block is passed to
+/// LDK. Filters and blocks come from the first peer, and are checked against those commitments,
+/// so a single lying peer can't hide a block from us, though it can stall our sync.
+struct FilterSync {
+	/// The block LDK was last synced to. Blocks up to it are only checked, not passed to LDK.
+	start: BestBlock,
+	chain: HeaderChain,
+	/// The height of the last block passed to LDK, which may trail our chain's tip.
+	synced_height: u32,
+	/// The agreed filter headers of the blocks in `chain` past `start`.
+	filter_headers: HashMap<BlockHash, FilterHeader>,
+	/// The transactions LDK found relevant in each block, to unconfirm if the block is reorged out.
+	confirmed: HashMap<BlockHash, Vec<Txid>>,
+	watched_scripts: Arc<WatchedScripts>,
+	chain_monitor: Arc<ChainMonitor>,
+	channel_manager: Arc<ChannelManager>,
+}
+
+impl FilterSync {
+	/// Unconfirms the transactions of every block above `height`, which has been reorged out.
+	fn disconnect_above(&mut self, height: u32) -> io::Result<()> {
+		if height < self.start.height() {
+			return Err(chain_error(
+				"the chain reorganized below the block we started syncing from, restart to resync",
+			));
+		}
+		for block_hash in self.chain.disconnect_above(height)? {
+			self.filter_headers.remove(&block_hash);
+			for txid in self.confirmed.remove(&block_hash).unwrap_or_default() {
+				self.chain_monitor.transaction_unconfirmed(&txid);
+				self.channel_manager.transaction_unconfirmed(&txid);
+			}
+		}
+		self.synced_height = self.synced_height.min(height);
+		Ok(())
+	}
+
+	/// Asks every peer for the filter hashes of the blocks from `start_height` up to `stop_hash`,
+	/// returning them along with the filter header they build on if all peers agree.
+	async fn agreed_filter_hashes(
+		&self, peers: &mut [FilterPeer], start_height: u32, stop_hash: BlockHash,
+	) -> io::Result<(FilterHeader, Vec<FilterHash>)> {
+		let mut agreed: Option<(FilterHeader, Vec<FilterHash>)> = None;
+		for peer in peers.iter_mut() {
+			let get_cfheaders =
+				GetCFHeaders { filter_type: BASIC_FILTER_TYPE, start_height, stop_hash };
+			peer.send(NetworkMessage::GetCFHeaders(get_cfheaders)).await?;
+			let answer = peer
+				.expect(|msg| match msg {
+					NetworkMessage::CFHeaders(cfheaders) if cfheaders.stop_hash == stop_hash => {
+						Some((cfheaders.previous_filter_header, cfheaders.filter_hashes))
+					}
+					_ => None,
+				})
+				.await?;
+			if agreed.as_ref().map_or(false, |agreed| *agreed != answer) {
+				return Err(chain_error(&format!(
+					"filter peers disagree about the filters of blocks from height {}, not syncing past it",
+					start_height
+				)));
+			}
+			agreed = Some(answer);
+		}
+		Ok(agreed.unwrap())
+	}
+
+	async fn connect_block(
+		&mut self, peer: &mut FilterPeer, header: BlockHeader, height: u32, filter_hash: FilterHash,
+	) -> io::Result<()> {
+		let block_hash = header.block_hash();
+		let scripts = self.watched_scripts.scripts();
+		let matched = if scripts.is_empty() {
+			false
+		} else {
+			let get_filter = GetCFilters {
+				filter_type: BASIC_FILTER_TYPE,
+				start_height: height,
+				stop_hash: block_hash,
+			};
+			peer.send(NetworkMessage::GetCFilters(get_filter)).await?;
+			let filter = peer
+				.expect(|msg| match msg {
+					NetworkMessage::CFilter(filter) if filter.block_hash == block_hash => {
+						Some(filter.filter)
+					}
+					_ => None,
+				})
+				.await?;
+			if FilterHash::hash(&filter) != filter_hash {
+				return Err(chain_error(
+					"peer sent a block filter which doesn't match the agreed one",
+				));
+			}
+			BlockFilter::new(&filter)
+				.match_any(&block_hash, &mut scripts.iter().map(|script| script.as_bytes()))
+				.map_err(|_| chain_error("peer sent an invalid block filter"))?
+		};
+
+		if matched {
+			peer.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(block_hash)])).await?;
+			let block = peer
+				.expect(|msg| match msg {
+					NetworkMessage::Block(block) if block.block_hash() == block_hash => Some(block),
+					_ => None,
+				})
+				.await?;
+			if !block.check_merkle_root() || !block.check_witness_commitment() {
+				return Err(chain_error("peer sent a block which doesn't match its header"));
+			}
+			// LDK picks out what's relevant to it, including spends of outputs it only starts
+			// watching partway through the block.
+			let txdata: &TransactionData = &block.txdata.iter().enumerate().collect::<Vec<_>>();
+			self.chain_monitor.transactions_confirmed(&header, txdata, height);
+			self.channel_manager.transactions_confirmed(&header, txdata, height);
+			let relevant: HashSet<Txid> = self
+				.chain_monitor
+				.get_relevant_txids()
+				.into_iter()
+				.chain(self.channel_manager.get_relevant_txids())
+				.collect();
+			let confirmed: Vec<Txid> = block
+				.txdata
+				.iter()
+				.map(|tx| tx.txid())
+				.filter(|txid| relevant.contains(txid))
+				.collect();
+			if !confirmed.is_empty() {
+				self.confirmed.insert(block_hash, confirmed);
+			}
+		}
+		self.chain_monitor.best_block_updated(&header, height);
+		self.channel_manager.best_block_updated(&header, height);
+		self.synced_height = height;
+		Ok(())
+	}
+
+	/// Passes the blocks of our chain which LDK hasn't seen yet to it, in batches of blocks whose
+	/// filter headers all peers agree on.
+	async fn catch_up(&mut self, peers: &mut [FilterPeer]) -> io::Result<()> {
+		while self.synced_height < self.chain.tip_height() {
+			let start_height = self.synced_height + 1;
+			let stop_height =
+				self.chain.tip_height().min(self.synced_height + MAX_CFHEADERS_PER_MESSAGE);
+			let stop_hash = self.chain.hash_at(stop_height).unwrap();
+			let (previous_filter_header, filter_hashes) =
+				self.agreed_filter_hashes(peers, start_height, stop_hash).await?;
+			if filter_hashes.len() != (stop_height - self.synced_height) as usize {
+				return Err(chain_error("peers sent the wrong number of filter headers"));
+			}
+			let previous_hash = self.chain.hash_at(self.synced_height).unwrap();
+			match self.filter_headers.get(&previous_hash) {
+				Some(known) if *known != previous_filter_header => {
+					return Err(chain_error("peers' filter headers don't build on the agreed ones"))
+				}
+				_ => {}
+			}
+			let mut filter_header = previous_filter_header;
+			for (idx, filter_hash) in filter_hashes.into_iter().enumerate() {
+				let height = start_height + idx as u32;
+				let header = *self.chain.header_at(height).unwrap();
+				filter_header = next_filter_header(&filter_hash, &filter_header);
+				self.filter_headers.insert(header.block_hash(), filter_header);
+				self.connect_block(&mut peers[0], header, height, filter_hash).await?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Syncs to the first peer's best chain, reorganizing ours onto it if need be.
+	async fn sync(&mut self, peers: &mut [FilterPeer]) -> io::Result<()> {
+		loop {
+			self.catch_up(peers).await?;
+			let get_headers = GetHeadersMessage::new(self.chain.locator(), BlockHash::default());
+			peers[0].send(NetworkMessage::GetHeaders(get_headers)).await?;
+			let headers = peers[0]
+				.expect(|msg| match msg {
+					NetworkMessage::Headers(headers) => Some(headers),
+					_ => None,
+				})
+				.await?;
+			let first = match headers.first() {
+				Some(first) => first,
+				None => return Ok(()),
+			};
+			let fork_height = match self.chain.height_of(&first.prev_blockhash) {
+				Some(height) => height,
+				None => {
+					return Err(chain_error("peer sent headers which don't build on our chain"))
+				}
+			};
+			if fork_height < self.chain.tip_height() {
+				self.disconnect_above(fork_height)?;
+			}
+			let more = headers.len() >= MAX_HEADERS_PER_MESSAGE;
+			for header in headers {
+				if let Some(forgotten) = self.chain.connect(header)? {
+					self.filter_headers.remove(&forgotten);
+					self.confirmed.remove(&forgotten);
+				}
+				let start_height = self.start.height();
+				if self.chain.tip_height() == start_height
+					&& self.chain.hash_at(start_height) != Some(self.start.block_hash())
+				{
+					return Err(chain_error(
+						"the peer's chain doesn't include the block we started syncing from",
+					));
+				}
+			}
+			if !more {
+				return self.catch_up(peers).await;
+			}
+		}
+	}
+
+	/// Syncs from the peers until we lose one, syncing again whenever the first announces a new
+	/// block.
+	async fn run(
+		&mut self, peer_addrs: &[SocketAddr], network: Network, failing: &mut bool,
+	) -> io::Result<()> {
+		let mut peers = Vec::new();
+		for peer_addr in peer_addrs {
+			peers.push(FilterPeer::connect(*peer_addr, network).await?);
+		}
+		loop {
+			self.sync(&mut peers).await?;
+			if *failing {
+				*failing = false;
+				eprintln!("Syncing blocks from compact filter peers again");
+			}
+			// Wait for the peer to announce a block, or ask it for headers anyway after a while.
+			// Only waiting for the peer to send something can be cut short without losing part of
+			// a message.
+			let poll_at = tokio::time::Instant::now() + POLL_INTERVAL;
+			while let Ok(readable) =
+				tokio::time::timeout_at(poll_at, peers[0].stream.readable()).await
+			{
+				readable?;
+				let announced = match peers[0].recv().await? {
+					NetworkMessage::Ping(nonce) => {
+						peers[0].send(NetworkMessage::Pong(nonce)).await?;
+						false
+					}
+					NetworkMessage::Inv(inv) => inv.iter().any(|item| match item {
+						Inventory::Block(_) | Inventory::WitnessBlock(_) => true,
+						_ => false,
+					}),
+					NetworkMessage::Headers(_) => true,
+					_ => false,
+				};
+				if announced {
+					break;
+				}
+			}
+			// Keep the other peers from dropping us for not answering their pings.
+			for peer in peers[1..].iter_mut() {
+				peer.answer_pings().await?;
+			}
+		}
+	}
+}
+
+/// Keeps the chain monitor and channel manager synced from `start` using the compact block filters
+/// served by the peers at `peer_addrs`, reconnecting to them whenever one is lost. Blocks and
+/// filters come from the first peer, the others are only asked for filter headers to check the
+/// first peer's against.
+pub(crate) fn start_syncing(
+	peer_addrs: Vec<SocketAddr>, network: Network, start: BestBlock,
+	watched_scripts: Arc<WatchedScripts>, chain_monitor: Arc<ChainMonitor>,
+	channel_manager: Arc<ChannelManager>,
+) {
+	if peer_addrs.len() < 2 {
+		eprintln!("Warning: With a single compact filter peer, its filters can't be cross-checked, so it could hide blocks from us. Add others with --filter-check-peer");
+	}
+	tokio::spawn(async move {
+		let mut sync = FilterSync {
+			start,
+			chain: HeaderChain::new(network),
+			synced_height: start.height(),
+			filter_headers: HashMap::new(),
+			confirmed: HashMap::new(),
+			watched_scripts,
+			chain_monitor,
+			channel_manager,
+		};
+		let mut failing = false;
+		loop {
+			let res = sync.run(&peer_addrs, network, &mut failing).await;
+			// Only warn when we lose a peer, rather than on every failed reconnection.
+			if let Err(e) = res {
+				if !failing {
+					failing = true;
+					eprintln!("Warning: Lost a compact filter peer, reconnecting: {}", e);
+				}
+			}
+			tokio::time::sleep(RECONNECT_DELAY).await;
+		}
+	});
+}
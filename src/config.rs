@@ -0,0 +1,497 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use toml::value::Table;
+use toml::Value;
+
+/// Settings read from the optional `ldk.conf` TOML file in the storage directory. Anything given
+/// on the command line takes precedence over the file, e.g.:
+///
+/// ```toml
+/// [bitcoind]
+/// rpc_host = "127.0.0.1"
+/// rpc_port = 18332
+/// rpc_username = "user"
+/// rpc_password = "pass"
+/// # Or, instead of a username and password:
+/// cookie_path = "/home/user/.bitcoin/testnet3/.cookie"
+/// zmq_block_addr = "127.0.0.1:28332"
+/// rpc_timeout_secs = 30
+/// # For a remote bitcoind behind stunnel or another TLS-terminating proxy:
+/// rpc_tls = true
+/// rpc_tls_ca_file = "/etc/ldk/bitcoind-ca.pem"
+/// # Or, for a self-signed certificate:
+/// rpc_tls_cert_sha256 = "AB:CD:..."
+/// rpc_connect_timeout_secs = 10
+///
+/// [chain]
+/// backend = "esplora"
+/// esplora_url = "http://127.0.0.1:3002"
+/// # Or, with backend = "filters":
+/// filter_peer = "127.0.0.1:8333"
+/// filter_check_peers = ["203.0.113.1:8333", "198.51.100.1:8333"]
+///
+/// [node]
+/// network = "testnet"
+/// listening_port = 9735
+/// announced_node_name = "my-node"
+/// announced_listen_addrs = ["203.0.113.1"]
+/// bind_addrs = ["0.0.0.0", "[::]:9736"]
+///
+/// [channel]
+/// announced = false
+/// forwarding_fee_base_msat = 1000
+/// forwarding_fee_proportional_millionths = 1
+/// cltv_expiry_delta = 72
+/// htlc_minimum_msat = 1
+/// force_close_avoidance_max_fee_satoshis = 1000
+/// fund_from_confirmed_only = true
+/// commit_upfront_shutdown_pubkey = false
+/// max_commitment_updates = 1000000
+/// rotate_channels = false
+/// max_concurrent_opens = 1
+/// # Alert when more than this is in flight through our channels.
+/// max_htlc_exposure_msat = 500000000
+///
+/// [inbound_channels]
+/// # Which channels other nodes may open to us.
+/// min_channel_sat = 100000
+/// max_channel_sat = 16777215
+/// max_channels_per_peer = 2
+/// # If set, only these nodes may open channels to us.
+/// allow_peers = ["02abc..."]
+/// deny_peers = ["03def..."]
+///
+/// [maintenance]
+/// # Recurring windows, in UTC, during which incoming HTLCs are held and new channels refused.
+/// windows = ["sat 02:00-04:00", "23:30-00:15"]
+/// hold_forwards = true
+/// refuse_channels = true
+///
+/// [payments]
+/// # Payments to us are failed back rather than claimed if they'd expire within this many blocks.
+/// min_claim_cltv_blocks = 12
+/// max_fee_base_msat = 5000
+/// max_fee_proportional_millionths = 10000
+/// max_retries = 5
+/// counterparty_fee_budget_msat = 100000
+/// counterparty_fee_budget_window_hours = 24
+/// auto_reissue_invoices = 3
+/// shadow_route = true
+/// randomize_final_cltv = true
+/// # Completed payments are pruned once they're this many days old.
+/// retention_days = 90
+/// # Payers must leave us at least this many blocks to claim a payment to us on-chain.
+/// min_final_cltv_expiry = 24
+/// # Keysend payers must prove knowledge of this secret, shared among a closed group of nodes.
+/// payer_secret = "0101..."
+///
+/// [wallet]
+/// internal = false
+/// # Or, instead of the internal wallet, separate bitcoind wallets for channel opens and the rest.
+/// funding_wallet = "funding"
+/// operating_wallet = "operating"
+/// sweep_xpub = "xpub..."
+/// sweep_batch_secs = 3600
+///
+/// [fees]
+/// background_floor_sat_per_kw = 253
+/// normal_floor_sat_per_kw = 2000
+/// high_priority_floor_sat_per_kw = 5000
+///
+/// [memory]
+/// header_cache_size = 1000
+/// # Regularly prune the network graph down to this many nodes.
+/// max_graph_nodes = 5000
+///
+/// [monitoring]
+/// # Opt in to anonymous daily telemetry.
+/// telemetry_endpoint = "127.0.0.1:8080/telemetry"
+/// # Signed heartbeats identifying the node, for an external monitor.
+/// heartbeat_endpoint = "127.0.0.1:8080/heartbeat"
+/// heartbeat_interval_secs = 300
+///
+/// [monitors]
+/// # Copies of every channel monitor, each a directory or a command storing the monitor.
+/// replicas = ["dir:/mnt/backup/monitors", "cmd:/usr/local/bin/upload-monitor"]
+///
+/// [storage]
+/// # Or "files", the default. Nodes which moved to SQLite can't move back.
+/// backend = "sqlite"
+///
+/// [log]
+/// # One of all, trace, debug, info, warn or error. Can be changed with `reloadconfig`.
+/// level = "info"
+/// # Or "text", the default. JSON logs have a `time`, `level`, `module`, `line` and `message`.
+/// format = "json"
+/// # logs.txt is rotated once it reaches this size or age, keeping this many old logs.
+/// rotate_size_mb = 100
+/// rotate_age_hours = 24
+/// keep_files = 5
+///
+/// [notify]
+/// # Key events are POSTed as JSON to this host:port[/path], and/or piped to this command.
+/// webhook = "127.0.0.1:8080/alerts"
+/// command = "/usr/local/bin/ldk-alert"
+/// # Only these events are notified of, rather than all of them.
+/// events = ["payment_received", "channel_force_closed", "peer_disconnected"]
+/// # Every payment we claim is POSTed here, signed with the shared secret, until acknowledged.
+/// settlement_webhook = "127.0.0.1:8080/settlements"
+/// settlement_secret = "..."
+/// ```
+#[derive(Clone, Default, PartialEq)]
+pub(crate) struct ConfigFile {
+	pub(crate) bitcoind_rpc_host: Option<String>,
+	pub(crate) bitcoind_rpc_port: Option<u16>,
+	pub(crate) bitcoind_rpc_username: Option<String>,
+	pub(crate) bitcoind_rpc_password: Option<String>,
+	pub(crate) bitcoind_cookie_path: Option<String>,
+	pub(crate) bitcoind_zmq_addr: Option<String>,
+	pub(crate) bitcoind_rpc_timeout_secs: Option<u64>,
+	pub(crate) bitcoind_rpc_tls: Option<bool>,
+	pub(crate) bitcoind_rpc_tls_ca_file: Option<String>,
+	pub(crate) bitcoind_rpc_tls_cert_sha256: Option<String>,
+	pub(crate) bitcoind_rpc_connect_timeout_secs: Option<u64>,
+	pub(crate) chain_backend: Option<String>,
+	pub(crate) esplora_url: Option<String>,
+	pub(crate) filter_peer: Option<String>,
+	pub(crate) filter_check_peers: Vec<String>,
+	pub(crate) network: Option<String>,
+	pub(crate) listening_port: Option<u16>,
+	pub(crate) announced_node_name: Option<String>,
+	pub(crate) announced_listen_addrs: Vec<String>,
+	pub(crate) bind_addrs: Vec<String>,
+	pub(crate) channel_announced: Option<bool>,
+	pub(crate) forwarding_fee_base_msat: Option<u32>,
+	pub(crate) forwarding_fee_proportional_millionths: Option<u32>,
+	pub(crate) cltv_expiry_delta: Option<u16>,
+	pub(crate) htlc_minimum_msat: Option<u64>,
+	pub(crate) force_close_avoidance_max_fee_satoshis: Option<u64>,
+	pub(crate) fund_from_confirmed_only: Option<bool>,
+	pub(crate) commit_upfront_shutdown_pubkey: Option<bool>,
+	pub(crate) max_commitment_updates: Option<u64>,
+	pub(crate) rotate_channels: Option<bool>,
+	pub(crate) max_concurrent_opens: Option<usize>,
+	pub(crate) max_htlc_exposure_msat: Option<u64>,
+	pub(crate) inbound_min_channel_sat: Option<u64>,
+	pub(crate) inbound_max_channel_sat: Option<u64>,
+	pub(crate) inbound_max_channels_per_peer: Option<usize>,
+	pub(crate) inbound_allow_peers: Vec<String>,
+	pub(crate) inbound_deny_peers: Vec<String>,
+	pub(crate) maintenance_windows: Vec<String>,
+	pub(crate) maintenance_hold_forwards: Option<bool>,
+	pub(crate) maintenance_refuse_channels: Option<bool>,
+	pub(crate) min_claim_cltv_blocks: Option<u32>,
+	pub(crate) max_fee_base_msat: Option<u64>,
+	pub(crate) max_fee_proportional_millionths: Option<u64>,
+	pub(crate) max_retries: Option<usize>,
+	pub(crate) counterparty_fee_budget_msat: Option<u64>,
+	pub(crate) counterparty_fee_budget_window_hours: Option<u64>,
+	pub(crate) auto_reissue_invoices: Option<u32>,
+	pub(crate) shadow_route: Option<bool>,
+	pub(crate) randomize_final_cltv: Option<bool>,
+	pub(crate) payment_retention_days: Option<u64>,
+	pub(crate) min_final_cltv_expiry: Option<u32>,
+	pub(crate) payer_secret: Option<String>,
+	pub(crate) internal_wallet: Option<bool>,
+	pub(crate) funding_wallet: Option<String>,
+	pub(crate) operating_wallet: Option<String>,
+	pub(crate) sweep_xpub: Option<String>,
+	pub(crate) sweep_address: Option<String>,
+	pub(crate) sweep_batch_secs: Option<u64>,
+	pub(crate) background_floor_sat_per_kw: Option<u32>,
+	pub(crate) normal_floor_sat_per_kw: Option<u32>,
+	pub(crate) high_priority_floor_sat_per_kw: Option<u32>,
+	pub(crate) header_cache_size: Option<usize>,
+	pub(crate) max_graph_nodes: Option<usize>,
+	pub(crate) telemetry_endpoint: Option<String>,
+	pub(crate) heartbeat_endpoint: Option<String>,
+	pub(crate) heartbeat_interval_secs: Option<u64>,
+	pub(crate) monitor_replicas: Vec<String>,
+	pub(crate) storage_backend: Option<String>,
+	pub(crate) log_level: Option<String>,
+	pub(crate) log_format: Option<String>,
+	pub(crate) log_rotate_size_mb: Option<u64>,
+	pub(crate) log_rotate_age_hours: Option<u64>,
+	pub(crate) log_keep_files: Option<u32>,
+	pub(crate) notify_webhook: Option<String>,
+	pub(crate) notify_command: Option<String>,
+	pub(crate) notify_events: Vec<String>,
+	pub(crate) settlement_webhook: Option<String>,
+	pub(crate) settlement_secret: Option<String>,
+}
+
+const SECTIONS: &[(&str, &[&str])] = &[
+	(
+		"bitcoind",
+		&[
+			"rpc_host",
+			"rpc_port",
+			"rpc_username",
+			"rpc_password",
+			"cookie_path",
+			"zmq_block_addr",
+			"rpc_timeout_secs",
+			"rpc_tls",
+			"rpc_tls_ca_file",
+			"rpc_tls_cert_sha256",
+			"rpc_connect_timeout_secs",
+		],
+	),
+	("chain", &["backend", "esplora_url", "filter_peer", "filter_check_peers"]),
+	(
+		"node",
+		&[
+			"network",
+			"listening_port",
+			"announced_node_name",
+			"announced_listen_addrs",
+			"bind_addrs",
+		],
+	),
+	(
+		"channel",
+		&[
+			"announced",
+			"forwarding_fee_base_msat",
+			"forwarding_fee_proportional_millionths",
+			"cltv_expiry_delta",
+			"htlc_minimum_msat",
+			"force_close_avoidance_max_fee_satoshis",
+			"fund_from_confirmed_only",
+			"commit_upfront_shutdown_pubkey",
+			"max_commitment_updates",
+			"rotate_channels",
+			"max_concurrent_opens",
+			"max_htlc_exposure_msat",
+		],
+	),
+	(
+		"inbound_channels",
+		&[
+			"min_channel_sat",
+			"max_channel_sat",
+			"max_channels_per_peer",
+			"allow_peers",
+			"deny_peers",
+		],
+	),
+	("maintenance", &["windows", "hold_forwards", "refuse_channels"]),
+	(
+		"payments",
+		&[
+			"min_claim_cltv_blocks",
+			"max_fee_base_msat",
+			"max_fee_proportional_millionths",
+			"max_retries",
+			"counterparty_fee_budget_msat",
+			"counterparty_fee_budget_window_hours",
+			"auto_reissue_invoices",
+			"shadow_route",
+			"randomize_final_cltv",
+			"retention_days",
+			"min_final_cltv_expiry",
+			"payer_secret",
+		],
+	),
+	(
+		"wallet",
+		&[
+			"internal",
+			"funding_wallet",
+			"operating_wallet",
+			"sweep_xpub",
+			"sweep_address",
+			"sweep_batch_secs",
+		],
+	),
+	(
+		"fees",
+		&[
+			"background_floor_sat_per_kw",
+			"normal_floor_sat_per_kw",
+			"high_priority_floor_sat_per_kw",
+		],
+	),
+	("memory", &["header_cache_size", "max_graph_nodes"]),
+	("monitoring", &["telemetry_endpoint", "heartbeat_endpoint", "heartbeat_interval_secs"]),
+	("monitors", &["replicas"]),
+	("storage", &["backend"]),
+	("log", &["level", "format", "rotate_size_mb", "rotate_age_hours", "keep_files"]),
+	("notify", &["webhook", "command", "events", "settlement_webhook", "settlement_secret"]),
+];
+
+fn get_str(section: Option<&Table>, key: &str) -> Result<Option<String>, String> {
+	match section.and_then(|s| s.get(key)) {
+		None => Ok(None),
+		Some(Value::String(s)) => Ok(Some(s.clone())),
+		Some(_) => Err(format!("`{}` must be a string", key)),
+	}
+}
+
+fn get_int<T: std::convert::TryFrom<i64>>(
+	section: Option<&Table>, key: &str,
+) -> Result<Option<T>, String> {
+	match section.and_then(|s| s.get(key)) {
+		None => Ok(None),
+		Some(Value::Integer(i)) => {
+			T::try_from(*i).map(Some).map_err(|_| format!("`{}` is out of range", key))
+		}
+		Some(_) => Err(format!("`{}` must be an integer", key)),
+	}
+}
+
+fn get_bool(section: Option<&Table>, key: &str) -> Result<Option<bool>, String> {
+	match section.and_then(|s| s.get(key)) {
+		None => Ok(None),
+		Some(Value::Boolean(b)) => Ok(Some(*b)),
+		Some(_) => Err(format!("`{}` must be true or false", key)),
+	}
+}
+
+fn get_str_array(section: Option<&Table>, key: &str) -> Result<Vec<String>, String> {
+	match section.and_then(|s| s.get(key)) {
+		None => Ok(Vec::new()),
+		Some(Value::Array(values)) => values
+			.iter()
+			.map(|v| v.as_str().map(str::to_string))
+			.collect::<Option<Vec<_>>>()
+			.ok_or_else(|| format!("`{}` must be a list of strings", key)),
+		Some(_) => Err(format!("`{}` must be a list of strings", key)),
+	}
+}
+
+fn parse_config(contents: &str) -> Result<ConfigFile, String> {
+	let config = contents.parse::<Value>().map_err(|e| e.to_string())?;
+	let config = config.as_table().ok_or("expected a table of settings")?;
+	// Catch typos rather than silently ignoring settings we don't know about.
+	for (section_name, section) in config.iter() {
+		let known_keys = match SECTIONS.iter().find(|(name, _)| name == section_name) {
+			Some((_, keys)) => keys,
+			None => return Err(format!("unknown section [{}]", section_name)),
+		};
+		let section =
+			section.as_table().ok_or_else(|| format!("[{}] must be a section", section_name))?;
+		for key in section.keys() {
+			if !known_keys.contains(&key.as_str()) {
+				return Err(format!("unknown setting `{}` in [{}]", key, section_name));
+			}
+		}
+	}
+
+	let bitcoind = config.get("bitcoind").and_then(Value::as_table);
+	let chain = config.get("chain").and_then(Value::as_table);
+	let node = config.get("node").and_then(Value::as_table);
+	let channel = config.get("channel").and_then(Value::as_table);
+	let inbound_channels = config.get("inbound_channels").and_then(Value::as_table);
+	let maintenance = config.get("maintenance").and_then(Value::as_table);
+	let payments = config.get("payments").and_then(Value::as_table);
+	let wallet = config.get("wallet").and_then(Value::as_table);
+	let fees = config.get("fees").and_then(Value::as_table);
+	let memory = config.get("memory").and_then(Value::as_table);
+	let monitoring = config.get("monitoring").and_then(Value::as_table);
+	let monitors = config.get("monitors").and_then(Value::as_table);
+	let storage = config.get("storage").and_then(Value::as_table);
+	let log = config.get("log").and_then(Value::as_table);
+	let notify = config.get("notify").and_then(Value::as_table);
+	Ok(ConfigFile {
+		bitcoind_rpc_host: get_str(bitcoind, "rpc_host")?,
+		bitcoind_rpc_port: get_int(bitcoind, "rpc_port")?,
+		bitcoind_rpc_username: get_str(bitcoind, "rpc_username")?,
+		bitcoind_rpc_password: get_str(bitcoind, "rpc_password")?,
+		bitcoind_cookie_path: get_str(bitcoind, "cookie_path")?,
+		bitcoind_zmq_addr: get_str(bitcoind, "zmq_block_addr")?,
+		bitcoind_rpc_timeout_secs: get_int(bitcoind, "rpc_timeout_secs")?,
+		bitcoind_rpc_tls: get_bool(bitcoind, "rpc_tls")?,
+		bitcoind_rpc_tls_ca_file: get_str(bitcoind, "rpc_tls_ca_file")?,
+		bitcoind_rpc_tls_cert_sha256: get_str(bitcoind, "rpc_tls_cert_sha256")?,
+		bitcoind_rpc_connect_timeout_secs: get_int(bitcoind, "rpc_connect_timeout_secs")?,
+		chain_backend: get_str(chain, "backend")?,
+		esplora_url: get_str(chain, "esplora_url")?,
+		filter_peer: get_str(chain, "filter_peer")?,
+		filter_check_peers: get_str_array(chain, "filter_check_peers")?,
+		network: get_str(node, "network")?,
+		listening_port: get_int(node, "listening_port")?,
+		announced_node_name: get_str(node, "announced_node_name")?,
+		announced_listen_addrs: get_str_array(node, "announced_listen_addrs")?,
+		bind_addrs: get_str_array(node, "bind_addrs")?,
+		channel_announced: get_bool(channel, "announced")?,
+		forwarding_fee_base_msat: get_int(channel, "forwarding_fee_base_msat")?,
+		forwarding_fee_proportional_millionths: get_int(
+			channel,
+			"forwarding_fee_proportional_millionths",
+		)?,
+		cltv_expiry_delta: get_int(channel, "cltv_expiry_delta")?,
+		htlc_minimum_msat: get_int(channel, "htlc_minimum_msat")?,
+		force_close_avoidance_max_fee_satoshis: get_int(
+			channel,
+			"force_close_avoidance_max_fee_satoshis",
+		)?,
+		fund_from_confirmed_only: get_bool(channel, "fund_from_confirmed_only")?,
+		commit_upfront_shutdown_pubkey: get_bool(channel, "commit_upfront_shutdown_pubkey")?,
+		max_commitment_updates: get_int(channel, "max_commitment_updates")?,
+		rotate_channels: get_bool(channel, "rotate_channels")?,
+		max_concurrent_opens: get_int(channel, "max_concurrent_opens")?,
+		max_htlc_exposure_msat: get_int(channel, "max_htlc_exposure_msat")?,
+		inbound_min_channel_sat: get_int(inbound_channels, "min_channel_sat")?,
+		inbound_max_channel_sat: get_int(inbound_channels, "max_channel_sat")?,
+		inbound_max_channels_per_peer: get_int(inbound_channels, "max_channels_per_peer")?,
+		inbound_allow_peers: get_str_array(inbound_channels, "allow_peers")?,
+		inbound_deny_peers: get_str_array(inbound_channels, "deny_peers")?,
+		maintenance_windows: get_str_array(maintenance, "windows")?,
+		maintenance_hold_forwards: get_bool(maintenance, "hold_forwards")?,
+		maintenance_refuse_channels: get_bool(maintenance, "refuse_channels")?,
+		min_claim_cltv_blocks: get_int(payments, "min_claim_cltv_blocks")?,
+		max_fee_base_msat: get_int(payments, "max_fee_base_msat")?,
+		max_fee_proportional_millionths: get_int(payments, "max_fee_proportional_millionths")?,
+		max_retries: get_int(payments, "max_retries")?,
+		counterparty_fee_budget_msat: get_int(payments, "counterparty_fee_budget_msat")?,
+		counterparty_fee_budget_window_hours: get_int(
+			payments,
+			"counterparty_fee_budget_window_hours",
+		)?,
+		auto_reissue_invoices: get_int(payments, "auto_reissue_invoices")?,
+		shadow_route: get_bool(payments, "shadow_route")?,
+		randomize_final_cltv: get_bool(payments, "randomize_final_cltv")?,
+		payment_retention_days: get_int(payments, "retention_days")?,
+		min_final_cltv_expiry: get_int(payments, "min_final_cltv_expiry")?,
+		payer_secret: get_str(payments, "payer_secret")?,
+		internal_wallet: get_bool(wallet, "internal")?,
+		funding_wallet: get_str(wallet, "funding_wallet")?,
+		operating_wallet: get_str(wallet, "operating_wallet")?,
+		sweep_xpub: get_str(wallet, "sweep_xpub")?,
+		sweep_address: get_str(wallet, "sweep_address")?,
+		sweep_batch_secs: get_int(wallet, "sweep_batch_secs")?,
+		background_floor_sat_per_kw: get_int(fees, "background_floor_sat_per_kw")?,
+		normal_floor_sat_per_kw: get_int(fees, "normal_floor_sat_per_kw")?,
+		high_priority_floor_sat_per_kw: get_int(fees, "high_priority_floor_sat_per_kw")?,
+		header_cache_size: get_int(memory, "header_cache_size")?,
+		max_graph_nodes: get_int(memory, "max_graph_nodes")?,
+		telemetry_endpoint: get_str(monitoring, "telemetry_endpoint")?,
+		heartbeat_endpoint: get_str(monitoring, "heartbeat_endpoint")?,
+		heartbeat_interval_secs: get_int(monitoring, "heartbeat_interval_secs")?,
+		monitor_replicas: get_str_array(monitors, "replicas")?,
+		storage_backend: get_str(storage, "backend")?,
+		log_level: get_str(log, "level")?,
+		log_format: get_str(log, "format")?,
+		log_rotate_size_mb: get_int(log, "rotate_size_mb")?,
+		log_rotate_age_hours: get_int(log, "rotate_age_hours")?,
+		log_keep_files: get_int(log, "keep_files")?,
+		notify_webhook: get_str(notify, "webhook")?,
+		notify_command: get_str(notify, "command")?,
+		notify_events: get_str_array(notify, "events")?,
+		settlement_webhook: get_str(notify, "settlement_webhook")?,
+		settlement_secret: get_str(notify, "settlement_secret")?,
+	})
+}
+
+/// Reads the config file at `path`, returning an empty config if there is none.
+pub(crate) fn read_config(path: &Path) -> Result<ConfigFile, String> {
+	match fs::read_to_string(path) {
+		Ok(contents) => {
+			parse_config(&contents).map_err(|e| format!("invalid {}: {}", path.display(), e))
+		}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(ConfigFile::default()),
+		Err(e) => Err(format!("failed to read {}: {}", path.display(), e)),
+	}
+}
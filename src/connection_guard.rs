@@ -0,0 +1,167 @@
+use crate::{ChannelManager, PeerManager};
+use bitcoin::secp256k1::key::PublicKey;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Once this share of our file descriptor limit is in use, gossip-only peers get disconnected.
+const EVICT_PCT: u64 = 90;
+
+/// Once this share is in use and there are no gossip-only peers left to disconnect, new inbound
+/// connections are dropped, so that our own reconnections to channel peers and our storage still
+/// get descriptors.
+const REFUSE_PCT: u64 = 97;
+
+/// Evicting stops once usage is back below this share.
+const TARGET_PCT: u64 = 80;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many file descriptors the process has open, and how many it may have open.
+pub(crate) struct FdUsage {
+	pub(crate) open: u64,
+	pub(crate) limit: u64,
+}
+
+impl FdUsage {
+	fn above(&self, pct: u64) -> bool {
+		self.open * 100 >= self.limit * pct
+	}
+}
+
+/// Our file descriptor usage, if the platform tells us and the limit isn't unlimited.
+pub(crate) fn fd_usage() -> Option<FdUsage> {
+	let limits = fs::read_to_string("/proc/self/limits").ok()?;
+	let line = limits.lines().find(|line| line.starts_with("Max open files"))?;
+	// The soft limit, as in `Max open files  1024  1048576  files`.
+	let limit: u64 = line.split_whitespace().nth(3)?.parse().ok()?;
+	// Reading the directory takes a descriptor of its own.
+	let open = fs::read_dir("/proc/self/fd").ok()?.count().saturating_sub(1) as u64;
+	Some(FdUsage { open, limit })
+}
+
+/// Keeps the node connected to its channel peers when it runs low on file descriptors, e.g. as
+/// many gossip peers connect to a public node. Every peer connection takes a descriptor, so near
+/// the limit we disconnect peers we have no channels with, most recently connected first, and
+/// once none are left, drop new inbound connections before they take the last descriptors. Peers
+/// aren't known until their handshake completes, so inbound connections from channel peers can
+/// be dropped too, but we reconnect to those ourselves.
+pub(crate) struct ConnectionGuard {
+	channel_manager: Arc<ChannelManager>,
+	peer_manager: Arc<PeerManager>,
+	/// When each connected peer was first seen connected, to pick which gossip peers to evict.
+	connected_since: Mutex<HashMap<PublicKey, Instant>>,
+	evicted_peers: AtomicU64,
+	refused_inbound: AtomicU64,
+}
+
+impl ConnectionGuard {
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, peer_manager: Arc<PeerManager>,
+	) -> Self {
+		Self {
+			channel_manager,
+			peer_manager,
+			connected_since: Mutex::new(HashMap::new()),
+			evicted_peers: AtomicU64::new(0),
+			refused_inbound: AtomicU64::new(0),
+		}
+	}
+
+	fn channel_peers(&self) -> HashSet<PublicKey> {
+		self.channel_manager
+			.list_channels()
+			.iter()
+			.map(|chan_info| chan_info.counterparty.node_id)
+			.collect()
+	}
+
+	/// Connected peers we have no channels with, most recently connected first.
+	fn gossip_peers(&self) -> Vec<PublicKey> {
+		let channel_peers = self.channel_peers();
+		let connected = self.peer_manager.get_peer_node_ids();
+		let mut connected_since = self.connected_since.lock().unwrap();
+		let now = Instant::now();
+		connected_since.retain(|node_id, _| connected.contains(node_id));
+		let mut gossip_peers: Vec<(PublicKey, Instant)> = connected
+			.into_iter()
+			.filter(|node_id| !channel_peers.contains(node_id))
+			.map(|node_id| (node_id, *connected_since.entry(node_id).or_insert(now)))
+			.collect();
+		gossip_peers.sort_by(|a, b| b.1.cmp(&a.1));
+		gossip_peers.into_iter().map(|(node_id, _)| node_id).collect()
+	}
+
+	/// Disconnects up to `count` gossip-only peers, returning how many were disconnected.
+	fn evict_gossip_peers(&self, count: u64) -> u64 {
+		let mut evicted = 0;
+		for node_id in self.gossip_peers().into_iter().take(count as usize) {
+			self.peer_manager.disconnect_by_node_id(node_id, false);
+			evicted += 1;
+		}
+		if evicted > 0 {
+			self.evicted_peers.fetch_add(evicted, Ordering::Relaxed);
+			println!(
+				"\nEVENT: Disconnected {} gossip-only peer(s) as we're running low on file descriptors",
+				evicted
+			);
+		}
+		evicted
+	}
+
+	/// Called for each inbound connection, returning whether to keep it. Near the limit a gossip
+	/// peer makes room for it, and if there's none to make room it's dropped.
+	pub(crate) fn admit_inbound(&self) -> bool {
+		let usage = match fd_usage() {
+			Some(usage) => usage,
+			None => return true,
+		};
+		if !usage.above(EVICT_PCT) {
+			return true;
+		}
+		if self.evict_gossip_peers(1) == 0 && usage.above(REFUSE_PCT) {
+			self.refused_inbound.fetch_add(1, Ordering::Relaxed);
+			return false;
+		}
+		true
+	}
+
+	/// Regularly brings our descriptor usage back down if it crept up, e.g. through connections
+	/// we made ourselves.
+	pub(crate) fn start_monitoring(guard: Arc<Self>) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(CHECK_INTERVAL);
+			loop {
+				interval.tick().await;
+				let usage = match fd_usage() {
+					Some(usage) => usage,
+					None => continue,
+				};
+				if usage.above(EVICT_PCT) {
+					// Each peer takes one descriptor.
+					let excess = usage.open.saturating_sub(usage.limit * TARGET_PCT / 100);
+					guard.evict_gossip_peers(excess);
+				} else {
+					// Keep track of when peers connected even while there's no pressure.
+					guard.gossip_peers();
+				}
+			}
+		});
+	}
+
+	/// How many gossip-only peers were disconnected and inbound connections dropped since start.
+	pub(crate) fn counters(&self) -> (u64, u64) {
+		(self.evicted_peers.load(Ordering::Relaxed), self.refused_inbound.load(Ordering::Relaxed))
+	}
+
+	/// How many channel and gossip-only peers are connected.
+	pub(crate) fn connected_peers(&self) -> (usize, usize) {
+		let channel_peers = self.channel_peers();
+		let connected = self.peer_manager.get_peer_node_ids();
+		let num_channel_peers =
+			connected.iter().filter(|node_id| channel_peers.contains(node_id)).count();
+		(num_channel_peers, connected.len() - num_channel_peers)
+	}
+}
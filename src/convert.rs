@@ -42,6 +42,43 @@ impl TryInto<SignedTx> for JsonResponse {
 	}
 }
 
+/// A base64-encoded PSBT.
+pub struct Psbt(pub String);
+
+impl TryInto<Psbt> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<Psbt> {
+		Ok(Psbt(self.0["psbt"].as_str().unwrap().to_string()))
+	}
+}
+
+pub struct FinalizedPsbt {
+	/// The fully signed transaction, only present if `complete`.
+	pub hex: Option<String>,
+	pub complete: bool,
+}
+
+impl TryInto<FinalizedPsbt> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<FinalizedPsbt> {
+		Ok(FinalizedPsbt {
+			hex: self.0["hex"].as_str().map(|hex| hex.to_string()),
+			complete: self.0["complete"].as_bool().unwrap(),
+		})
+	}
+}
+
+pub struct WalletInfo {
+	pub private_keys_enabled: bool,
+}
+
+impl TryInto<WalletInfo> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<WalletInfo> {
+		Ok(WalletInfo { private_keys_enabled: self.0["private_keys_enabled"].as_bool().unwrap() })
+	}
+}
+
 pub struct NewAddress(pub String);
 impl TryInto<NewAddress> for JsonResponse {
 	type Error = std::io::Error;
@@ -74,10 +111,24 @@ impl TryInto<FeeResponse> for JsonResponse {
 	}
 }
 
+/// bitcoind's mempool minimum fee, in satoshis per 1000 weight.
+pub struct MempoolMinFee(pub u32);
+
+impl TryInto<MempoolMinFee> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<MempoolMinFee> {
+		// Like `estimatesmartfee`, this is in BTC/KvB.
+		let feerate_btc_per_kvbyte = self.0["mempoolminfee"].as_f64().unwrap();
+		Ok(MempoolMinFee((feerate_btc_per_kvbyte * 100_000_000.0 / 4.0).round() as u32))
+	}
+}
+
 pub struct BlockchainInfo {
 	pub latest_height: usize,
 	pub latest_blockhash: BlockHash,
 	pub chain: String,
+	/// If bitcoind is pruned, the height of the oldest block it still has.
+	pub prune_height: Option<u32>,
 }
 
 impl TryInto<BlockchainInfo> for JsonResponse {
@@ -88,6 +139,142 @@ impl TryInto<BlockchainInfo> for JsonResponse {
 			latest_blockhash: BlockHash::from_hex(self.0["bestblockhash"].as_str().unwrap())
 				.unwrap(),
 			chain: self.0["chain"].as_str().unwrap().to_string(),
+			prune_height: match self.0["pruned"].as_bool() {
+				Some(true) => self.0["pruneheight"].as_u64().map(|height| height as u32),
+				_ => None,
+			},
+		})
+	}
+}
+
+/// An output as reported by `gettxout`, which reports nothing for outputs that are already spent.
+pub struct UnspentOutput(pub Option<UnspentOutputInfo>);
+
+pub struct UnspentOutputInfo {
+	pub value_sat: u64,
+	pub is_p2wsh: bool,
+	/// 0 for the output of a transaction still in the mempool.
+	pub confirmations: u32,
+}
+
+impl TryInto<UnspentOutput> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<UnspentOutput> {
+		if self.0.is_null() {
+			return Ok(UnspentOutput(None));
+		}
+		Ok(UnspentOutput(Some(UnspentOutputInfo {
+			value_sat: (self.0["value"].as_f64().unwrap() * 100_000_000.0).round() as u64,
+			is_p2wsh: self.0["scriptPubKey"]["type"].as_str() == Some("witness_v0_scripthash"),
+			confirmations: self.0["confirmations"].as_u64().unwrap_or(0) as u32,
+		})))
+	}
+}
+
+/// A transaction in one of our wallets, as reported by `gettransaction`.
+pub struct WalletTransaction {
+	pub hex: String,
+	/// The hash and height of the block the transaction confirmed in, and its index in the block.
+	pub confirmed_in: Option<(BlockHash, u32, usize)>,
+	/// The fee the transaction paid, if our wallet funded it.
+	pub fee_sat: Option<u64>,
+}
+
+impl TryInto<WalletTransaction> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<WalletTransaction> {
+		let confirmed_in = match self.0["confirmations"].as_i64() {
+			Some(confirmations) if confirmations > 0 => Some((
+				BlockHash::from_hex(self.0["blockhash"].as_str().unwrap()).unwrap(),
+				self.0["blockheight"].as_u64().unwrap() as u32,
+				self.0["blockindex"].as_u64().unwrap() as usize,
+			)),
+			_ => None,
+		};
+		// bitcoind reports the fee as a negative amount, and only for transactions we sent.
+		let fee_sat = self.0["fee"].as_f64().map(|fee| (-fee * 100_000_000.0).round() as u64);
+		Ok(WalletTransaction {
+			hex: self.0["hex"].as_str().unwrap().to_string(),
+			confirmed_in,
+			fee_sat,
 		})
 	}
 }
+
+pub struct GeneratedBlocks(pub Vec<BlockHash>);
+
+impl TryInto<GeneratedBlocks> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<GeneratedBlocks> {
+		Ok(GeneratedBlocks(
+			self.0
+				.as_array()
+				.unwrap()
+				.iter()
+				.map(|hash| BlockHash::from_hex(hash.as_str().unwrap()).unwrap())
+				.collect(),
+		))
+	}
+}
+
+/// Bitcoin Core's `getbalances`, in satoshis.
+pub struct WalletBalances {
+	pub confirmed_sat: u64,
+	pub unconfirmed_sat: u64,
+	/// Coinbase outputs which aren't yet spendable.
+	pub immature_sat: u64,
+}
+
+impl TryInto<WalletBalances> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<WalletBalances> {
+		let to_sat = |key: &str| {
+			(self.0["mine"][key].as_f64().unwrap_or(0.0) * 100_000_000.0).round() as u64
+		};
+		Ok(WalletBalances {
+			confirmed_sat: to_sat("trusted"),
+			unconfirmed_sat: to_sat("untrusted_pending"),
+			immature_sat: to_sat("immature"),
+		})
+	}
+}
+
+pub struct WalletUtxo {
+	pub txid: String,
+	pub vout: u64,
+	pub amount_sat: u64,
+	pub confirmations: u64,
+	pub address: Option<String>,
+}
+
+pub struct ListUnspent(pub Vec<WalletUtxo>);
+
+impl TryInto<ListUnspent> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<ListUnspent> {
+		Ok(ListUnspent(
+			self.0
+				.as_array()
+				.unwrap()
+				.iter()
+				.map(|utxo| WalletUtxo {
+					txid: utxo["txid"].as_str().unwrap().to_string(),
+					vout: utxo["vout"].as_u64().unwrap(),
+					amount_sat: (utxo["amount"].as_f64().unwrap() * 100_000_000.0).round() as u64,
+					confirmations: utxo["confirmations"].as_u64().unwrap(),
+					address: utxo["address"].as_str().map(|addr| addr.to_string()),
+				})
+				.collect(),
+		))
+	}
+}
+
+pub struct WalletBalance(pub u64);
+
+impl TryInto<WalletBalance> for JsonResponse {
+	type Error = std::io::Error;
+	fn try_into(self) -> std::io::Result<WalletBalance> {
+		// Bitcoin Core reports balances in BTC, which we convert to satoshis.
+		Ok(WalletBalance((self.0.as_f64().unwrap() * 100_000_000.0).round() as u64))
+	}
+}
@@ -0,0 +1,65 @@
+use lightning::routing::network_graph::NetworkUpdate;
+use lightning::routing::router::RouteHop;
+
+/// Works out why a payment path failed, in terms of the BOLT 4 failure codes a user is likely to
+/// have seen elsewhere, and which hop is to blame.
+///
+/// LDK 0.0.103 decodes the onion error itself and only hands us what it learned from it, so we
+/// can't always name the exact failure code. Instead we name the codes consistent with what the
+/// failing node told us.
+pub(crate) fn describe_path_failure(
+	rejected_by_dest: bool, network_update: &Option<NetworkUpdate>, short_channel_id: Option<u64>,
+	path: &[RouteHop],
+) -> String {
+	let reason = if rejected_by_dest {
+		"the recipient rejected the payment (incorrect_or_unknown_payment_details, e.g. the invoice \
+		 was already paid, has expired or is unknown to them)"
+			.to_string()
+	} else {
+		match network_update {
+			Some(NetworkUpdate::ChannelUpdateMessage { msg }) => format!(
+				"channel {} rejected the HTLC under its current policy (e.g. fee_insufficient, \
+				 incorrect_cltv_expiry, amount_below_minimum or temporary_channel_failure)",
+				msg.contents.short_channel_id
+			),
+			Some(NetworkUpdate::ChannelClosed { short_channel_id, is_permanent: true }) => format!(
+				"channel {} can no longer be used (permanent_channel_failure or unknown_next_peer)",
+				short_channel_id
+			),
+			Some(NetworkUpdate::ChannelClosed { short_channel_id, is_permanent: false }) => {
+				format!(
+					"channel {} couldn't carry the HTLC (temporary_channel_failure, e.g. low liquidity)",
+					short_channel_id
+				)
+			}
+			Some(NetworkUpdate::NodeFailure { node_id, is_permanent: true }) => format!(
+				"node {} can no longer forward payments (permanent_node_failure or \
+				 required_node_feature_missing)",
+				node_id
+			),
+			Some(NetworkUpdate::NodeFailure { node_id, is_permanent: false }) => {
+				format!(
+					"node {} is temporarily unable to forward (temporary_node_failure)",
+					node_id
+				)
+			}
+			None => "an intermediate node returned an error we couldn't attribute".to_string(),
+		}
+	};
+
+	// The failing channel is the one leading out of the node which failed the HTLC, so blame the
+	// node at the start of that hop.
+	let hop_idx =
+		short_channel_id.and_then(|scid| path.iter().position(|hop| hop.short_channel_id == scid));
+	match hop_idx {
+		Some(0) => format!("{}, at hop 1 of {} (our own channel)", reason, path.len()),
+		Some(idx) => format!(
+			"{}, at hop {} of {} (node {})",
+			reason,
+			idx + 1,
+			path.len(),
+			path[idx - 1].pubkey
+		),
+		None => reason,
+	}
+}
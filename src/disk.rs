@@ -1,43 +1,168 @@
 use crate::cli;
+use crate::hex_utils;
+use crate::storage;
+use crate::storage::Storage;
+use crate::tor::PeerAddr;
+use crate::{time_now_secs, HTLCStatus, MillisatAmount, PaymentInfo};
+use bitcoin::network::constants::Network;
 use bitcoin::secp256k1::key::PublicKey;
 use bitcoin::BlockHash;
 use chrono::Utc;
+use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
 use lightning::routing::network_graph::NetworkGraph;
+use lightning::routing::router::Route;
 use lightning::routing::scorer::Scorer;
-use lightning::util::logger::{Logger, Record};
+use lightning::util::logger::{Level, Logger, Record};
 use lightning::util::ser::{Readable, Writeable, Writer};
+use serde_json::json;
 use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter};
-use std::net::SocketAddr;
+use std::io::{BufRead, BufReader, BufWriter, Cursor};
 use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum LogFormat {
+	/// `<time> <level> [<module>:<line>] <message>` lines.
+	Text,
+	/// A JSON object per line, with `time`, `level`, `module`, `line` and `message` fields.
+	Json,
+}
+
+impl LogFormat {
+	pub(crate) fn parse(format: &str) -> Result<Self, String> {
+		match format {
+			"text" => Ok(LogFormat::Text),
+			"json" => Ok(LogFormat::Json),
+			_ => Err(format!("unknown log format `{}`, expected text or json", format)),
+		}
+	}
+}
+
+/// When `logs.txt` is rotated to `logs.txt.1`, shifting older logs along, and how many of those
+/// are kept.
+#[derive(Clone, Copy)]
+pub(crate) struct LogRotation {
+	/// Rotate once the log reaches this size, if set.
+	pub(crate) max_size_bytes: Option<u64>,
+	/// Rotate once the log was started this long ago, if set.
+	pub(crate) max_age_secs: Option<u64>,
+	pub(crate) keep_files: u32,
+}
+
+/// How many rotated logs are kept, unless configured.
+pub(crate) const DEFAULT_LOG_KEEP_FILES: u32 = 5;
+
+/// The log being written to: how big it is and when it was started, in seconds since the UNIX
+/// epoch.
+struct LogFile {
+	size: u64,
+	started_at: u64,
+}
 
 pub(crate) struct FilesystemLogger {
 	data_dir: String,
+	/// Records below this level are dropped, or none if `None`. It can be changed while running.
+	min_level: Mutex<Option<Level>>,
+	format: LogFormat,
+	rotation: LogRotation,
+	file: Mutex<LogFile>,
 }
 impl FilesystemLogger {
-	pub(crate) fn new(data_dir: String) -> Self {
+	pub(crate) fn new(
+		data_dir: String, min_level: Option<Level>, format: LogFormat, rotation: LogRotation,
+	) -> Self {
 		let logs_path = format!("{}/logs", data_dir);
 		fs::create_dir_all(logs_path.clone()).unwrap();
-		Self { data_dir: logs_path }
+		// An existing log was started no later than it was last written to.
+		let file = match fs::metadata(format!("{}/logs.txt", logs_path)) {
+			Ok(metadata) => LogFile {
+				size: metadata.len(),
+				started_at: metadata
+					.created()
+					.or_else(|_| metadata.modified())
+					.ok()
+					.and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+					.map_or(time_now_secs(), |since_epoch| since_epoch.as_secs()),
+			},
+			Err(_) => LogFile { size: 0, started_at: time_now_secs() },
+		};
+		Self {
+			data_dir: logs_path,
+			min_level: Mutex::new(min_level),
+			format,
+			rotation,
+			file: Mutex::new(file),
+		}
+	}
+
+	pub(crate) fn min_level(&self) -> Option<Level> {
+		*self.min_level.lock().unwrap()
+	}
+
+	pub(crate) fn set_min_level(&self, min_level: Option<Level>) {
+		*self.min_level.lock().unwrap() = min_level;
 	}
 }
+
+/// Parses a log level as given in the config file or to `setloglevel`, where `all` keeps every
+/// record.
+pub(crate) fn parse_log_level(level: &str) -> Result<Option<Level>, String> {
+	match level {
+		"all" => Ok(None),
+		"trace" => Ok(Some(Level::Trace)),
+		"debug" => Ok(Some(Level::Debug)),
+		"info" => Ok(Some(Level::Info)),
+		"warn" => Ok(Some(Level::Warn)),
+		"error" => Ok(Some(Level::Error)),
+		_ => Err(format!(
+			"unknown log level `{}`, expected one of all, trace, debug, info, warn or error",
+			level
+		)),
+	}
+}
+
 impl Logger for FilesystemLogger {
 	fn log(&self, record: &Record) {
+		if self.min_level().map_or(false, |min_level| record.level < min_level) {
+			return;
+		}
 		let raw_log = record.args.to_string();
-		let log = format!(
-			"{} {:<5} [{}:{}] {}\n",
-			// Note that a "real" lightning node almost certainly does *not* want subsecond
-			// precision for message-receipt information as it makes log entries a target for
-			// deanonymization attacks. For testing, however, its quite useful.
-			Utc::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-			record.level.to_string(),
-			record.module_path,
-			record.line,
-			raw_log
-		);
+		// Note that a "real" lightning node almost certainly does *not* want subsecond
+		// precision for message-receipt information as it makes log entries a target for
+		// deanonymization attacks. For testing, however, its quite useful.
+		let time = Utc::now().format("%Y-%m-%d %H:%M:%S%.3f");
+		let log = match self.format {
+			LogFormat::Text => format!(
+				"{} {:<5} [{}:{}] {}\n",
+				time,
+				record.level.to_string(),
+				record.module_path,
+				record.line,
+				raw_log
+			),
+			LogFormat::Json => format!(
+				"{}\n",
+				json!({
+					"time": time.to_string(),
+					"level": record.level.to_string(),
+					"module": record.module_path,
+					"line": record.line,
+					"message": raw_log,
+				})
+			),
+		};
 		let logs_file_path = format!("{}/logs.txt", self.data_dir.clone());
+		let mut file = self.file.lock().unwrap();
+		if self.should_rotate(&file) {
+			// Failing to rotate isn't worth losing the record over, we'll try again next time.
+			if self.rotate().is_ok() {
+				*file = LogFile { size: 0, started_at: time_now_secs() };
+			}
+		}
 		fs::OpenOptions::new()
 			.create(true)
 			.append(true)
@@ -45,26 +170,55 @@ impl Logger for FilesystemLogger {
 			.unwrap()
 			.write_all(log.as_bytes())
 			.unwrap();
+		file.size += log.len() as u64;
+	}
+}
+
+impl FilesystemLogger {
+	fn should_rotate(&self, file: &LogFile) -> bool {
+		let too_big = self.rotation.max_size_bytes.map_or(false, |max| file.size >= max);
+		let too_old = self
+			.rotation
+			.max_age_secs
+			.map_or(false, |max| file.size > 0 && file.started_at + max <= time_now_secs());
+		too_big || too_old
+	}
+
+	/// Moves `logs.txt` to `logs.txt.1`, `logs.txt.1` to `logs.txt.2` and so on, dropping the
+	/// oldest once there are `keep_files` of them.
+	fn rotate(&self) -> std::io::Result<()> {
+		let path = format!("{}/logs.txt", self.data_dir);
+		if self.rotation.keep_files == 0 {
+			return fs::remove_file(&path);
+		}
+		let _ = fs::remove_file(format!("{}.{}", path, self.rotation.keep_files));
+		for n in (1..self.rotation.keep_files).rev() {
+			let rotated = format!("{}.{}", path, n);
+			if Path::new(&rotated).exists() {
+				fs::rename(&rotated, format!("{}.{}", path, n + 1))?;
+			}
+		}
+		fs::rename(&path, format!("{}.1", path))
 	}
 }
-pub(crate) fn persist_channel_peer(path: &Path, peer_info: &str) -> std::io::Result<()> {
-	let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
-	file.write_all(format!("{}\n", peer_info).as_bytes())
+
+pub(crate) fn persist_channel_peer(storage: &dyn Storage, peer_info: &str) -> std::io::Result<()> {
+	storage.add_channel_peer(peer_info)
+}
+
+/// The `<pubkey>@<host>:<port>` lines we stored for our channel peers, in the order stored.
+pub(crate) fn read_channel_peer_lines(storage: &dyn Storage) -> std::io::Result<Vec<String>> {
+	storage.channel_peers()
 }
 
 pub(crate) fn read_channel_peer_data(
-	path: &Path,
-) -> Result<HashMap<PublicKey, SocketAddr>, std::io::Error> {
+	storage: &dyn Storage,
+) -> Result<HashMap<PublicKey, PeerAddr>, std::io::Error> {
 	let mut peer_data = HashMap::new();
-	if !Path::new(&path).exists() {
-		return Ok(HashMap::new());
-	}
-	let file = File::open(path)?;
-	let reader = BufReader::new(file);
-	for line in reader.lines() {
-		match cli::parse_peer_info(line.unwrap()) {
-			Ok((pubkey, socket_addr)) => {
-				peer_data.insert(pubkey, socket_addr);
+	for line in read_channel_peer_lines(storage)? {
+		match cli::parse_peer_info(line) {
+			Ok((pubkey, peer_addr)) => {
+				peer_data.insert(pubkey, peer_addr);
 			}
 			Err(e) => return Err(e),
 		}
@@ -72,22 +226,15 @@ pub(crate) fn read_channel_peer_data(
 	Ok(peer_data)
 }
 
-pub(crate) fn persist_network(path: &Path, network_graph: &NetworkGraph) -> std::io::Result<()> {
-	let mut tmp_path = path.to_path_buf().into_os_string();
-	tmp_path.push(".tmp");
-	let file = fs::OpenOptions::new().write(true).create(true).open(&tmp_path)?;
-	let write_res = network_graph.write(&mut BufWriter::new(file));
-	if let Err(e) = write_res.and_then(|_| fs::rename(&tmp_path, path)) {
-		let _ = fs::remove_file(&tmp_path);
-		Err(e)
-	} else {
-		Ok(())
-	}
+pub(crate) fn persist_network(
+	storage: &dyn Storage, network_graph: &NetworkGraph,
+) -> std::io::Result<()> {
+	storage.write(storage::NETWORK_GRAPH, &network_graph.encode())
 }
 
-pub(crate) fn read_network(path: &Path, genesis_hash: BlockHash) -> NetworkGraph {
-	if let Ok(file) = File::open(path) {
-		if let Ok(graph) = NetworkGraph::read(&mut BufReader::new(file)) {
+pub(crate) fn read_network(storage: &dyn Storage, genesis_hash: BlockHash) -> NetworkGraph {
+	if let Ok(Some(graph)) = storage.read(storage::NETWORK_GRAPH) {
+		if let Ok(graph) = NetworkGraph::read(&mut Cursor::new(graph)) {
 			return graph;
 		}
 	}
@@ -97,8 +244,14 @@ pub(crate) fn read_network(path: &Path, genesis_hash: BlockHash) -> NetworkGraph
 pub(crate) fn persist_scorer(path: &Path, scorer: &Scorer) -> std::io::Result<()> {
 	let mut tmp_path = path.to_path_buf().into_os_string();
 	tmp_path.push(".tmp");
-	let file = fs::OpenOptions::new().write(true).create(true).open(&tmp_path)?;
-	let write_res = scorer.write(&mut BufWriter::new(file));
+	let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	let mut writer = BufWriter::new(file);
+	// Flush and sync before renaming so a crash can't leave us with a truncated scorer, which
+	// would be thrown away on the next start along with everything it learned.
+	let write_res = scorer
+		.write(&mut writer)
+		.and_then(|_| std::io::Write::flush(&mut writer))
+		.and_then(|_| writer.get_ref().sync_all());
 	if let Err(e) = write_res.and_then(|_| fs::rename(&tmp_path, path)) {
 		let _ = fs::remove_file(&tmp_path);
 		Err(e)
@@ -107,11 +260,319 @@ pub(crate) fn persist_scorer(path: &Path, scorer: &Scorer) -> std::io::Result<()
 	}
 }
 
+/// Reads the scorer persisted by a previous run, so that channels which recently failed our
+/// payments keep being avoided across restarts.
 pub(crate) fn read_scorer(path: &Path) -> Scorer {
 	if let Ok(file) = File::open(path) {
-		if let Ok(scorer) = Scorer::read(&mut BufReader::new(file)) {
-			return scorer;
+		match Scorer::read(&mut BufReader::new(file)) {
+			Ok(scorer) => return scorer,
+			Err(e) => eprintln!(
+				"Warning: Failed to read scorer from {:?}, starting with no payment history: {:?}",
+				path, e
+			),
 		}
 	}
 	Scorer::default()
 }
+
+fn hex_to_32_bytes(hex: &str) -> Option<[u8; 32]> {
+	let bytes = hex_utils::to_vec(hex)?;
+	if bytes.len() != 32 {
+		return None;
+	}
+	let mut res = [0; 32];
+	res.copy_from_slice(&bytes);
+	Some(res)
+}
+
+/// How a payment's status is written to storage.
+pub(crate) fn status_str(status: &HTLCStatus) -> &'static str {
+	match status {
+		HTLCStatus::Pending => "pending",
+		HTLCStatus::Succeeded => "succeeded",
+		HTLCStatus::Failed => "failed",
+		HTLCStatus::TimedOut => "timed_out",
+	}
+}
+
+pub(crate) fn parse_status(status: &str) -> Option<HTLCStatus> {
+	match status {
+		"pending" => Some(HTLCStatus::Pending),
+		"succeeded" => Some(HTLCStatus::Succeeded),
+		"failed" => Some(HTLCStatus::Failed),
+		"timed_out" => Some(HTLCStatus::TimedOut),
+		_ => None,
+	}
+}
+
+/// Serializes a payment as a single line of the form
+/// `<payment_hash> <preimage|-> <secret|-> <status> <amt_msat|-> <created_at> <updated_at>
+/// <expires_at|-> <route|-> <failure|-> <failed_parts> <fee_paid_msat|-> <invoice|-> <refund_of|->
+/// <reissue_of|->`,
+/// where the route and failure description are hex-encoded.
+pub(crate) fn payment_info_to_line(payment_hash: &PaymentHash, payment: &PaymentInfo) -> String {
+	format!(
+		"{} {} {} {} {} {} {} {} {} {} {} {} {} {} {}",
+		hex_utils::hex_str(&payment_hash.0),
+		payment.preimage.map_or("-".to_string(), |p| hex_utils::hex_str(&p.0)),
+		payment.secret.map_or("-".to_string(), |s| hex_utils::hex_str(&s.0)),
+		status_str(&payment.status),
+		payment.amt_msat.0.map_or("-".to_string(), |amt| amt.to_string()),
+		payment.created_at,
+		payment.updated_at,
+		payment.expires_at.map_or("-".to_string(), |expiry| expiry.to_string()),
+		payment.route.as_ref().map_or("-".to_string(), |route| hex_utils::hex_str(&route.encode())),
+		payment
+			.failure
+			.as_ref()
+			.map_or("-".to_string(), |failure| hex_utils::hex_str(failure.as_bytes())),
+		payment.failed_parts,
+		payment.fee_paid_msat.map_or("-".to_string(), |fee| fee.to_string()),
+		payment.invoice.as_ref().map_or("-", |invoice| invoice.as_str()),
+		payment.refund_of.map_or("-".to_string(), |hash| hex_utils::hex_str(&hash.0)),
+		payment.reissue_of.map_or("-".to_string(), |hash| hex_utils::hex_str(&hash.0)),
+	)
+}
+
+pub(crate) fn payment_info_from_line(line: &str) -> Option<(PaymentHash, PaymentInfo)> {
+	let parts: Vec<&str> = line.split(' ').collect();
+	// Entries written by older versions of the node lack the trailing timestamp fields.
+	if parts.len() != 5 && !(7..=15).contains(&parts.len()) {
+		return None;
+	}
+	let payment_hash = PaymentHash(hex_to_32_bytes(parts[0])?);
+	let preimage = match parts[1] {
+		"-" => None,
+		p => Some(PaymentPreimage(hex_to_32_bytes(p)?)),
+	};
+	let secret = match parts[2] {
+		"-" => None,
+		s => Some(PaymentSecret(hex_to_32_bytes(s)?)),
+	};
+	let status = parse_status(parts[3])?;
+	let amt_msat = match parts[4] {
+		"-" => MillisatAmount(None),
+		amt => MillisatAmount(Some(amt.parse().ok()?)),
+	};
+	let (created_at, updated_at) = match parts.len() {
+		5 => (0, 0),
+		_ => (parts[5].parse().ok()?, parts[6].parse().ok()?),
+	};
+	let expires_at = match parts.get(7) {
+		None | Some(&"-") => None,
+		Some(expiry) => Some(expiry.parse().ok()?),
+	};
+	let route = match parts.get(8) {
+		None | Some(&"-") => None,
+		Some(route) => Some(Route::read(&mut Cursor::new(hex_utils::to_vec(route)?)).ok()?),
+	};
+	let failure = match parts.get(9) {
+		None | Some(&"-") => None,
+		Some(failure) => Some(String::from_utf8(hex_utils::to_vec(failure)?).ok()?),
+	};
+	let failed_parts = match parts.get(10) {
+		None => 0,
+		Some(failed_parts) => failed_parts.parse().ok()?,
+	};
+	let fee_paid_msat = match parts.get(11) {
+		None | Some(&"-") => None,
+		Some(fee) => Some(fee.parse().ok()?),
+	};
+	let invoice = match parts.get(12) {
+		None | Some(&"-") => None,
+		Some(invoice) => Some(invoice.to_string()),
+	};
+	let refund_of = match parts.get(13) {
+		None | Some(&"-") => None,
+		Some(hash) => Some(PaymentHash(hex_to_32_bytes(hash)?)),
+	};
+	let reissue_of = match parts.get(14) {
+		None | Some(&"-") => None,
+		Some(hash) => Some(PaymentHash(hex_to_32_bytes(hash)?)),
+	};
+	Some((
+		payment_hash,
+		PaymentInfo {
+			preimage,
+			secret,
+			status,
+			amt_msat,
+			created_at,
+			updated_at,
+			expires_at,
+			route,
+			failure,
+			failed_parts,
+			fee_paid_msat,
+			invoice,
+			refund_of,
+			reissue_of,
+		},
+	))
+}
+
+pub(crate) fn persist_payment_info(
+	storage: &dyn Storage, key: &str, payments: &HashMap<PaymentHash, PaymentInfo>,
+) -> std::io::Result<()> {
+	storage.write_payments(key, payments)
+}
+
+pub(crate) fn read_payment_info(
+	storage: &dyn Storage, key: &str,
+) -> HashMap<PaymentHash, PaymentInfo> {
+	storage.read_payments(key).unwrap_or_default()
+}
+
+/// Whether a node has already run in the data directory, whichever storage it used.
+fn has_node_state(ldk_data_dir: &str) -> bool {
+	Path::new(&format!("{}/{}", ldk_data_dir, storage::MANAGER)).exists()
+		|| Path::new(&format!("{}/{}", ldk_data_dir, storage::SQLITE_FILE)).exists()
+}
+
+/// Checks that the data directory last ran on `network`, recording it for next time. Running a
+/// directory which held test coins on mainnet is far more likely a mistake than intended, so it
+/// takes `allow_mainnet_switch` to do so.
+pub(crate) fn check_data_dir_network(
+	ldk_data_dir: &str, network: Network, allow_mainnet_switch: bool,
+) -> Result<(), String> {
+	let network_path = format!("{}/network", ldk_data_dir);
+	let last_network = match fs::read_to_string(&network_path) {
+		Ok(last) if last.trim() == network.to_string() => return Ok(()),
+		Ok(last) => Some(last.trim().to_string()),
+		// Nodes created before we tracked their network couldn't run on mainnet.
+		Err(_) if has_node_state(ldk_data_dir) => Some("a test network".to_string()),
+		Err(_) => None,
+	};
+	if let Some(last_network) = last_network {
+		if network == Network::Bitcoin && !allow_mainnet_switch {
+			return Err(format!(
+				"This data directory previously ran on {}. Pass --i-really-want-mainnet to run it on mainnet anyway",
+				last_network
+			));
+		}
+		eprintln!(
+			"Warning: This data directory previously ran on {}, now running on {}",
+			last_network, network
+		);
+	}
+	fs::write(&network_path, network.to_string())
+		.map_err(|e| format!("Failed to record the data directory's network: {}", e))
+}
+
+fn run_tar(command: &mut Command) -> std::io::Result<()> {
+	let status = command.status()?;
+	if !status.success() {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::Other,
+			format!("tar exited with {}", status),
+		));
+	}
+	Ok(())
+}
+
+/// How many data directory backups we keep around before deleting the oldest.
+const MAX_DATA_DIR_BACKUPS: usize = 5;
+
+/// If the node was last run with a different version of this binary, snapshots the data directory
+/// into `backups/` before any state is read (and possibly migrated) by the new version.
+pub(crate) fn backup_data_dir_on_upgrade(ldk_data_dir: &str) -> std::io::Result<()> {
+	let version_path = format!("{}/version", ldk_data_dir);
+	let current_version = env!("CARGO_PKG_VERSION");
+	let last_version = match fs::read_to_string(&version_path) {
+		Ok(v) if v.trim() == current_version => return Ok(()),
+		Ok(v) => v.trim().to_string(),
+		// Nodes created before we tracked versions still have state worth backing up.
+		Err(_) if has_node_state(ldk_data_dir) => "unknown".to_string(),
+		// Otherwise this is a fresh node and there is nothing to back up.
+		Err(_) => return fs::write(&version_path, current_version),
+	};
+
+	let backups_dir = format!("{}/backups", ldk_data_dir);
+	fs::create_dir_all(&backups_dir)?;
+	let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+	let backup_path =
+		format!("{}/{}-{}-to-{}.tar", backups_dir, timestamp, last_version, current_version);
+	let tarred = run_tar(
+		Command::new("tar")
+			.arg("--exclude=./backups")
+			.arg("-cf")
+			.arg(&backup_path)
+			.arg("-C")
+			.arg(ldk_data_dir)
+			.arg("."),
+	);
+	if let Err(e) = tarred {
+		let _ = fs::remove_file(&backup_path);
+		return Err(e);
+	}
+	println!("Backed up data directory to {} before upgrading from {}", backup_path, last_version);
+
+	// Backup names start with their creation timestamp, so sorting them sorts by age.
+	let mut backups: Vec<_> = fs::read_dir(&backups_dir)?
+		.filter_map(|entry| entry.ok())
+		.map(|entry| entry.path())
+		.filter(|path| path.extension().map_or(false, |ext| ext == "tar"))
+		.collect();
+	backups.sort();
+	while backups.len() > MAX_DATA_DIR_BACKUPS {
+		fs::remove_file(backups.remove(0))?;
+	}
+
+	fs::write(&version_path, current_version)
+}
+
+/// Once the seed is encrypted, swaps the plaintext `keys_seed` in any data directory backup taken
+/// before for `keys_seed.enc`, so that the backups don't keep the seed in the clear. Each backup is
+/// rewritten as a copy which then replaces it, so a failure part way leaves it as it was.
+pub(crate) fn encrypt_seed_in_backups(ldk_data_dir: &str) -> std::io::Result<()> {
+	let backups_dir = format!("{}/backups", ldk_data_dir);
+	let entries = match fs::read_dir(&backups_dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e),
+	};
+	let backups = entries
+		.filter_map(|entry| entry.ok())
+		.map(|entry| entry.path())
+		.filter(|path| path.extension().map_or(false, |ext| ext == "tar"));
+	for backup in backups {
+		let listing = Command::new("tar").arg("-tf").arg(&backup).output()?;
+		if !listing.status.success() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::Other,
+				format!("tar failed to list {}", backup.display()),
+			));
+		}
+		let listing = String::from_utf8_lossy(&listing.stdout);
+		if !listing.lines().any(|name| name == "./keys_seed") {
+			continue;
+		}
+		let mut tmp_path = backup.clone().into_os_string();
+		tmp_path.push(".tmp");
+		fs::copy(&backup, &tmp_path)?;
+		let rewritten = run_tar(
+			Command::new("tar").arg("--delete").arg("-f").arg(&tmp_path).arg("./keys_seed"),
+		)
+		.and_then(|()| {
+			if listing.lines().any(|name| name == "./keys_seed.enc") {
+				return Ok(());
+			}
+			run_tar(
+				Command::new("tar")
+					.arg("-rf")
+					.arg(&tmp_path)
+					.arg("-C")
+					.arg(ldk_data_dir)
+					.arg("./keys_seed.enc"),
+			)
+		})
+		.and_then(|()| File::open(&tmp_path)?.sync_all());
+		if let Err(e) = rewritten {
+			let _ = fs::remove_file(&tmp_path);
+			return Err(e);
+		}
+		fs::rename(&tmp_path, &backup)?;
+		println!("Replaced the unencrypted keys seed in {}", backup.display());
+	}
+	Ok(())
+}
@@ -0,0 +1,158 @@
+use crate::pause::PauseSwitch;
+use crate::ChannelManager;
+use std::io;
+use std::io::Write;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Below this much free space in the data directory we warn that it's running out.
+const LOW_DISK_SPACE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Below this much free space we stop taking on new HTLCs, so that what's left goes to persisting
+/// updates to the channels we already have.
+const CRITICAL_DISK_SPACE_BYTES: u64 = 100 * 1024 * 1024;
+
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum DiskStatus {
+	Ok,
+	Low,
+	Critical,
+	WriteFailed,
+}
+
+impl DiskStatus {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			DiskStatus::Ok => "ok",
+			DiskStatus::Low => "low",
+			DiskStatus::Critical => "critical",
+			DiskStatus::WriteFailed => "write_failed",
+		}
+	}
+
+	fn holds_node(&self) -> bool {
+		*self == DiskStatus::Critical || *self == DiskStatus::WriteFailed
+	}
+}
+
+/// Watches the free space in the data directory, along with whether our channel monitor writes
+/// have started failing. If either puts channel state at risk we hold the node on the
+/// `PauseSwitch`, failing new payments rather than risking channel updates we can't persist.
+///
+/// Failed writes hold the node until it's restarted, as we can't tell when the disk has recovered.
+pub(crate) struct DiskMonitor {
+	data_dir: String,
+	/// Free bytes as of the last check, or `u64::MAX` if we couldn't tell.
+	free_bytes: AtomicU64,
+	write_failed: AtomicBool,
+	pause_switch: Arc<PauseSwitch>,
+}
+
+impl DiskMonitor {
+	pub(crate) fn new(data_dir: String, pause_switch: Arc<PauseSwitch>) -> Self {
+		let monitor = Self {
+			data_dir,
+			free_bytes: AtomicU64::new(u64::max_value()),
+			write_failed: AtomicBool::new(false),
+			pause_switch,
+		};
+		monitor.check();
+		if monitor.free_bytes().is_none() {
+			eprintln!("Warning: Failed to check free disk space in {}", monitor.data_dir);
+		}
+		monitor
+	}
+
+	pub(crate) fn free_bytes(&self) -> Option<u64> {
+		match self.free_bytes.load(Ordering::Acquire) {
+			bytes if bytes == u64::max_value() => None,
+			bytes => Some(bytes),
+		}
+	}
+
+	pub(crate) fn status(&self) -> DiskStatus {
+		if self.write_failed.load(Ordering::Acquire) {
+			return DiskStatus::WriteFailed;
+		}
+		match self.free_bytes.load(Ordering::Acquire) {
+			bytes if bytes < CRITICAL_DISK_SPACE_BYTES => DiskStatus::Critical,
+			bytes if bytes < LOW_DISK_SPACE_BYTES => DiskStatus::Low,
+			_ => DiskStatus::Ok,
+		}
+	}
+
+	/// Re-checks the free space, returning whether the node was held and is now released.
+	fn check(&self) -> bool {
+		let old_status = self.status();
+		if let Some(bytes) = available_bytes(&self.data_dir) {
+			self.free_bytes.store(bytes, Ordering::Release);
+		}
+		let status = self.status();
+		if status == old_status {
+			return false;
+		}
+		let free_mb = self.free_bytes().unwrap_or(0) / 1024 / 1024;
+		match status {
+			DiskStatus::Low => eprintln!(
+				"Warning: Only {} MB of disk space left for the node's data, free some up soon",
+				free_mb
+			),
+			DiskStatus::Critical => {
+				println!(
+					"\nEVENT: Only {} MB of disk space left for the node's data, no longer accepting payments or forwards until more is freed",
+					free_mb
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+			}
+			_ => {}
+		}
+		self.pause_switch.set_held_for_disk(status.holds_node());
+		let released = old_status.holds_node() && !status.holds_node();
+		if released {
+			println!("\nEVENT: Disk space recovered to {} MB, accepting payments again", free_mb);
+			print!("> ");
+			io::stdout().flush().unwrap();
+		}
+		released
+	}
+
+	/// Holds the node after a channel monitor update failed to be written.
+	pub(crate) fn write_failed(&self) {
+		if !self.write_failed.swap(true, Ordering::AcqRel) {
+			self.pause_switch.set_held_for_disk(true);
+			println!(
+				"\nEVENT: Failed to write a channel update to disk, no longer accepting payments or forwards. Fix the disk and restart the node"
+			);
+			print!("> ");
+			io::stdout().flush().unwrap();
+		}
+	}
+
+	/// Regularly re-checks the free space, processing any HTLCs we held on to once there's enough.
+	pub(crate) fn start_monitoring(monitor: Arc<Self>, channel_manager: Arc<ChannelManager>) {
+		thread::spawn(move || loop {
+			thread::sleep(DISK_CHECK_INTERVAL);
+			if monitor.check() && !monitor.pause_switch.holds_forwards() {
+				channel_manager.process_pending_htlc_forwards();
+			}
+		});
+	}
+}
+
+/// The bytes available to us on the filesystem holding `dir`, as reported by `df`.
+fn available_bytes(dir: &str) -> Option<u64> {
+	let output = Command::new("df").arg("-Pk").arg(dir).output().ok()?;
+	if !output.status.success() {
+		return None;
+	}
+	// The second line describes the filesystem, with the available 1K blocks in its fourth column.
+	let stdout = String::from_utf8(output.stdout).ok()?;
+	let available_kb: u64 = stdout.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()?;
+	Some(available_kb * 1024)
+}
@@ -0,0 +1,278 @@
+use crate::hex_utils;
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxOut};
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::util::uint::Uint256;
+use lightning_block_sync::{BlockHeaderData, BlockSourceError, BlockSourceResult};
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// How long we wait for the Esplora server to answer a request before giving up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Splits an Esplora base URL of the form `http://host[:port][/path]` into the address to connect
+/// to and the path prefix of its API, e.g. `/api`. TLS isn't supported.
+pub(crate) fn parse_url(url: &str) -> Result<(String, String), String> {
+	let rest = match url.strip_prefix("http://") {
+		Some(rest) => rest,
+		None if url.starts_with("https://") => {
+			return Err(format!(
+				"{} uses https, which isn't supported - use a local Esplora instance or a TLS-terminating proxy",
+				url
+			))
+		}
+		None => return Err(format!("{} isn't an http:// URL", url)),
+	};
+	let (host, path) = match rest.find('/') {
+		Some(idx) => (&rest[..idx], rest[idx..].trim_end_matches('/')),
+		None => (rest, ""),
+	};
+	if host.is_empty() {
+		return Err(format!("{} has no host", url));
+	}
+	let addr = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+	Ok((addr, path.to_string()))
+}
+
+fn malformed_response() -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response from Esplora")
+}
+
+fn find_crlf(bytes: &[u8]) -> Option<usize> {
+	bytes.windows(2).position(|w| w == b"\r\n")
+}
+
+/// Reassembles a body sent with `Transfer-Encoding: chunked`.
+fn dechunk(mut body: &[u8]) -> io::Result<Vec<u8>> {
+	let mut out = Vec::new();
+	loop {
+		let line_end = find_crlf(body).ok_or_else(malformed_response)?;
+		let size_line = std::str::from_utf8(&body[..line_end]).map_err(|_| malformed_response())?;
+		let size_hex = size_line.split(';').next().unwrap().trim();
+		let size = usize::from_str_radix(size_hex, 16).map_err(|_| malformed_response())?;
+		body = &body[line_end + 2..];
+		if size == 0 {
+			return Ok(out);
+		}
+		if body.len() < size + 2 {
+			return Err(malformed_response());
+		}
+		out.extend_from_slice(&body[..size]);
+		body = &body[size + 2..];
+	}
+}
+
+/// Splits a complete HTTP response into its body, failing with the body as the error message if
+/// the status isn't 2xx, as Esplora explains rejections in the body.
+fn parse_response(response: &[u8]) -> io::Result<Vec<u8>> {
+	let headers_end =
+		response.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(malformed_response)?;
+	let headers =
+		std::str::from_utf8(&response[..headers_end]).map_err(|_| malformed_response())?;
+	let body = &response[headers_end + 4..];
+	let chunked = headers.lines().skip(1).any(|line| {
+		let line = line.to_ascii_lowercase();
+		line.starts_with("transfer-encoding:") && line.contains("chunked")
+	});
+	let body = if chunked { dechunk(body)? } else { body.to_vec() };
+	// e.g. `HTTP/1.1 200 OK`
+	match headers.split(' ').nth(1) {
+		Some(status) if status.starts_with('2') && status.len() == 3 => Ok(body),
+		Some(status) => Err(io::Error::new(
+			// Overloaded or rate-limited servers didn't look at the request, so it's worth retrying
+			// rather than treating as a rejection.
+			if status == "429" || status.starts_with('5') {
+				io::ErrorKind::ConnectionAborted
+			} else {
+				io::ErrorKind::Other
+			},
+			format!("Esplora responded with {}: {}", status, String::from_utf8_lossy(&body)),
+		)),
+		None => Err(malformed_response()),
+	}
+}
+
+/// A client of an Esplora server's HTTP API, used as our chain source in place of bitcoind for
+/// syncing blocks, estimating fees and broadcasting transactions.
+pub(crate) struct EsploraClient {
+	addr: String,
+	path_prefix: String,
+	/// The chainwork of the blocks we've seen, see `get_header`.
+	chainworks: Mutex<HashMap<BlockHash, Uint256>>,
+}
+
+impl EsploraClient {
+	pub(crate) fn new(url: &str) -> Result<Self, String> {
+		let (addr, path_prefix) = parse_url(url)?;
+		Ok(Self { addr, path_prefix, chainworks: Mutex::new(HashMap::new()) })
+	}
+
+	async fn request(&self, method: &str, path: &str, body: Option<&str>) -> io::Result<Vec<u8>> {
+		let request = match body {
+			Some(body) => format!(
+				"{} {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+				method,
+				self.path_prefix,
+				path,
+				self.addr,
+				body.len(),
+				body
+			),
+			None => format!(
+				"{} {}{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+				method, self.path_prefix, path, self.addr
+			),
+		};
+		let exchange = async {
+			let mut stream = TcpStream::connect(&self.addr).await?;
+			stream.write_all(request.as_bytes()).await?;
+			let mut response = Vec::new();
+			stream.read_to_end(&mut response).await?;
+			Ok::<_, io::Error>(response)
+		};
+		let response = match tokio::time::timeout(REQUEST_TIMEOUT, exchange).await {
+			Ok(res) => res?,
+			Err(_) => {
+				return Err(io::Error::new(
+					io::ErrorKind::TimedOut,
+					format!("Esplora didn't answer {} within {:?}", path, REQUEST_TIMEOUT),
+				))
+			}
+		};
+		parse_response(&response)
+	}
+
+	async fn get_text(&self, path: &str) -> io::Result<String> {
+		let body = self.request("GET", path, None).await?;
+		String::from_utf8(body)
+			.map(|text| text.trim().to_string())
+			.map_err(|_| malformed_response())
+	}
+
+	async fn get_json(&self, path: &str) -> io::Result<serde_json::Value> {
+		let body = self.request("GET", path, None).await?;
+		serde_json::from_slice(&body).map_err(|_| malformed_response())
+	}
+
+	/// Esplora doesn't tell us a block's chainwork, which LDK only uses to compare blocks and
+	/// check that each builds on the last. We work it out from the chainwork of the block's parent
+	/// or child if we've seen either, and otherwise estimate it from the block's height and work.
+	/// Estimates are only ever compared to chainwork derived from them, so they stay consistent.
+	pub(crate) async fn get_header(
+		&self, header_hash: &BlockHash,
+	) -> BlockSourceResult<BlockHeaderData> {
+		let header_hex = self
+			.get_text(&format!("/block/{}/header", header_hash))
+			.await
+			.map_err(BlockSourceError::transient)?;
+		let header: BlockHeader = hex_utils::to_vec(&header_hex)
+			.and_then(|bytes| encode::deserialize(&bytes).ok())
+			.ok_or_else(|| BlockSourceError::persistent("invalid block header from Esplora"))?;
+		if header.block_hash() != *header_hash {
+			return Err(BlockSourceError::persistent("Esplora returned the wrong block header"));
+		}
+		let info = self
+			.get_json(&format!("/block/{}", header_hash))
+			.await
+			.map_err(BlockSourceError::transient)?;
+		let height = match info["height"].as_u64() {
+			Some(height) => height as u32,
+			None => {
+				return Err(BlockSourceError::persistent("block without a height from Esplora"))
+			}
+		};
+
+		let work = header.work();
+		let mut chainworks = self.chainworks.lock().unwrap();
+		let chainwork = match chainworks.get(header_hash) {
+			Some(chainwork) => *chainwork,
+			None => match chainworks.get(&header.prev_blockhash) {
+				Some(prev_chainwork) => *prev_chainwork + work,
+				None => work.mul_u32(height + 1),
+			},
+		};
+		chainworks.insert(*header_hash, chainwork);
+		chainworks.entry(header.prev_blockhash).or_insert(chainwork - work);
+		Ok(BlockHeaderData { header, height, chainwork })
+	}
+
+	pub(crate) async fn get_block(&self, header_hash: &BlockHash) -> BlockSourceResult<Block> {
+		let bytes = self
+			.request("GET", &format!("/block/{}/raw", header_hash), None)
+			.await
+			.map_err(BlockSourceError::transient)?;
+		let block: Block = encode::deserialize(&bytes)
+			.map_err(|_| BlockSourceError::persistent("invalid block from Esplora"))?;
+		if block.block_hash() != *header_hash {
+			return Err(BlockSourceError::persistent("Esplora returned the wrong block"));
+		}
+		Ok(block)
+	}
+
+	pub(crate) async fn get_best_block(&self) -> BlockSourceResult<(BlockHash, Option<u32>)> {
+		let hash = self.get_text("/blocks/tip/hash").await.map_err(BlockSourceError::transient)?;
+		let hash = BlockHash::from_str(&hash)
+			.map_err(|_| BlockSourceError::persistent("invalid block hash from Esplora"))?;
+		// The tip may have moved on in between, but the height is only a hint.
+		let height =
+			self.get_text("/blocks/tip/height").await.ok().and_then(|height| height.parse().ok());
+		Ok((hash, height))
+	}
+
+	/// Esplora's feerate, in satoshis per 1000 weight, for confirming within `conf_target` blocks,
+	/// or `None` if it has no estimate that far out.
+	pub(crate) async fn estimate_fee(&self, conf_target: u16) -> io::Result<Option<u32>> {
+		// e.g. `{"1": 87.882, "2": 87.882, ..., "144": 1.027}`, in satoshis per vbyte.
+		let estimates = self.get_json("/fee-estimates").await?;
+		let estimates = estimates.as_object().ok_or_else(malformed_response)?;
+		let sat_per_vbyte = estimates
+			.iter()
+			.filter_map(|(target, feerate)| Some((target.parse::<u16>().ok()?, feerate.as_f64()?)))
+			.filter(|(target, _)| *target <= conf_target)
+			.max_by_key(|(target, _)| *target)
+			.map(|(_, feerate)| feerate);
+		Ok(sat_per_vbyte.map(|feerate| (feerate * 250.0).round() as u32))
+	}
+
+	/// Broadcasts `tx`. Rejections carry bitcoind's reason, as Esplora relays it.
+	pub(crate) async fn broadcast(&self, tx: &Transaction) -> io::Result<Txid> {
+		let body = self.request("POST", "/tx", Some(&encode::serialize_hex(tx))).await?;
+		let txid = String::from_utf8(body).map_err(|_| malformed_response())?;
+		Txid::from_str(txid.trim()).map_err(|_| malformed_response())
+	}
+
+	/// The output `outpoint` refers to, whether or not it's been spent, `None` if there's no such
+	/// transaction or output.
+	pub(crate) async fn get_output(&self, outpoint: &OutPoint) -> io::Result<Option<TxOut>> {
+		let bytes = match self.request("GET", &format!("/tx/{}/raw", outpoint.txid), None).await {
+			Ok(bytes) => bytes,
+			Err(e) if e.to_string().contains(" 404:") => return Ok(None),
+			Err(e) => return Err(e),
+		};
+		let tx: Transaction = encode::deserialize(&bytes).map_err(|_| malformed_response())?;
+		Ok(tx.output.get(outpoint.vout as usize).cloned())
+	}
+
+	/// Whether `outpoint` has been spent by a confirmed transaction, ignoring the mempool.
+	pub(crate) async fn is_spent_on_chain(&self, outpoint: &OutPoint) -> io::Result<bool> {
+		// e.g. `{"spent": true, "txid": "...", "vin": 0, "status": {"confirmed": true, ...}}`
+		let outspend =
+			self.get_json(&format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout)).await?;
+		let spent = outspend["spent"].as_bool().unwrap_or(false);
+		let confirmed = outspend["status"]["confirmed"].as_bool().unwrap_or(false);
+		Ok(spent && confirmed)
+	}
+
+	/// Whether `outpoint` has been spent, by a confirmed transaction or one in the mempool.
+	pub(crate) async fn is_spent(&self, outpoint: &OutPoint) -> io::Result<bool> {
+		let outspend =
+			self.get_json(&format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout)).await?;
+		Ok(outspend["spent"].as_bool().unwrap_or(false))
+	}
+}
@@ -0,0 +1,222 @@
+use crate::hex_utils;
+use crate::{time_now_secs, ChannelManager};
+use bitcoin::BlockHash;
+use lightning::util::events::{Event, PaymentPurpose};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::broadcast;
+
+/// How many events a slow client may fall behind before it misses some.
+const BUFFER_LEN: usize = 1024;
+
+/// How often we look for channel state changes and new blocks, which LDK has no events for.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a stream may go quiet before we send a comment, so that proxies keep it open and we
+/// notice clients which went away.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone)]
+pub(crate) struct StreamedEvent {
+	id: u64,
+	event: &'static str,
+	data: String,
+}
+
+/// Pushes what happens on the node to clients of the RPC server's `GET /events` as it happens, as
+/// server-sent events, so that dashboards and bots don't have to poll. Each event's data is
+/// `{"timestamp": <secs>, ...}` with fields specific to the event.
+///
+/// Events are only kept in memory, so clients which connect late or fall too far behind miss
+/// some. A client which missed events gets a `lagged` event saying how many.
+pub(crate) struct EventStream {
+	sender: broadcast::Sender<StreamedEvent>,
+	next_id: AtomicU64,
+}
+
+impl EventStream {
+	pub(crate) fn new() -> Self {
+		let (sender, _) = broadcast::channel(BUFFER_LEN);
+		Self { sender, next_id: AtomicU64::new(1) }
+	}
+
+	pub(crate) fn subscribe(&self) -> broadcast::Receiver<StreamedEvent> {
+		self.sender.subscribe()
+	}
+
+	/// Sends `event` with `fields` added to its timestamp to every connected client.
+	pub(crate) fn publish(&self, event: &'static str, fields: Value) {
+		if self.sender.receiver_count() == 0 {
+			return;
+		}
+		let mut data = json!({ "timestamp": time_now_secs() });
+		if let (Some(data), Value::Object(fields)) = (data.as_object_mut(), fields) {
+			data.extend(fields);
+		}
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let _ = self.sender.send(StreamedEvent { id, event, data: data.to_string() });
+	}
+
+	/// Publishes an event LDK handed us. Preimages are left out, as the stream isn't authenticated.
+	pub(crate) fn ldk_event(&self, event: &Event) {
+		match event {
+			Event::FundingGenerationReady {
+				temporary_channel_id,
+				channel_value_satoshis,
+				user_channel_id,
+				..
+			} => self.publish(
+				"funding_generation_ready",
+				json!({
+					"temporary_channel_id": hex_utils::hex_str(temporary_channel_id),
+					"channel_value_satoshis": channel_value_satoshis,
+					"user_channel_id": user_channel_id,
+				}),
+			),
+			Event::PaymentReceived { payment_hash, purpose, amt, .. } => self.publish(
+				"payment_received",
+				json!({
+					"payment_hash": hex_utils::hex_str(&payment_hash.0),
+					"amount_msat": amt,
+					"spontaneous": match purpose {
+						PaymentPurpose::InvoicePayment { .. } => false,
+						PaymentPurpose::SpontaneousPayment(_) => true,
+					},
+				}),
+			),
+			Event::PaymentSent { payment_hash, fee_paid_msat, .. } => self.publish(
+				"payment_sent",
+				json!({
+					"payment_hash": hex_utils::hex_str(&payment_hash.0),
+					"fee_paid_msat": fee_paid_msat,
+				}),
+			),
+			Event::PaymentPathFailed {
+				payment_hash,
+				rejected_by_dest,
+				all_paths_failed,
+				short_channel_id,
+				..
+			} => self.publish(
+				"payment_path_failed",
+				json!({
+					"payment_hash": hex_utils::hex_str(&payment_hash.0),
+					"rejected_by_dest": rejected_by_dest,
+					"all_paths_failed": all_paths_failed,
+					"short_channel_id": short_channel_id,
+				}),
+			),
+			Event::PaymentForwarded { fee_earned_msat, claim_from_onchain_tx } => self.publish(
+				"payment_forwarded",
+				json!({
+					"fee_earned_msat": fee_earned_msat,
+					"claim_from_onchain_tx": claim_from_onchain_tx,
+				}),
+			),
+			Event::SpendableOutputs { outputs } => {
+				self.publish("spendable_outputs", json!({ "num_outputs": outputs.len() }))
+			}
+			Event::ChannelClosed { channel_id, reason, user_channel_id } => self.publish(
+				"channel_closed",
+				json!({
+					"channel_id": hex_utils::hex_str(channel_id),
+					"user_channel_id": user_channel_id,
+					"reason": format!("{:?}", reason),
+				}),
+			),
+			Event::DiscardFunding { transaction, .. } => self.publish(
+				"discard_funding",
+				json!({ "funding_txid": transaction.txid().to_string() }),
+			),
+			// Only tells us to forward HTLCs a little later, nothing a client can act on.
+			Event::PendingHTLCsForwardable { .. } => {}
+		}
+	}
+
+	/// Regularly publishes changes to our channels' states and new best blocks.
+	pub(crate) fn start_watching(stream: Arc<Self>, channel_manager: Arc<ChannelManager>) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(CHECK_INTERVAL);
+			let mut states: HashMap<[u8; 32], &'static str> = HashMap::new();
+			let mut best_block_hash: Option<BlockHash> = None;
+			loop {
+				interval.tick().await;
+				let best_block = channel_manager.current_best_block();
+				if best_block_hash != Some(best_block.block_hash()) {
+					if best_block_hash.is_some() {
+						stream.publish(
+							"best_block",
+							json!({
+								"height": best_block.height(),
+								"block_hash": best_block.block_hash().to_string(),
+							}),
+						);
+					}
+					best_block_hash = Some(best_block.block_hash());
+				}
+
+				let channels = channel_manager.list_channels();
+				let mut new_states = HashMap::new();
+				for chan_info in channels.iter() {
+					let state = if chan_info.is_usable {
+						"usable"
+					} else if chan_info.is_funding_locked {
+						"unusable"
+					} else if chan_info.funding_txo.is_some() {
+						"awaiting_confirmation"
+					} else {
+						"negotiating"
+					};
+					let previous_state = states.get(&chan_info.channel_id).copied();
+					if previous_state != Some(state) {
+						stream.publish(
+							"channel_state",
+							json!({
+								"channel_id": hex_utils::hex_str(&chan_info.channel_id),
+								"peer_pubkey": chan_info.counterparty.node_id.to_string(),
+								"state": state,
+								"previous_state": previous_state,
+							}),
+						);
+					}
+					new_states.insert(chan_info.channel_id, state);
+				}
+				// Closed channels are published from their `ChannelClosed` event.
+				states = new_states;
+			}
+		});
+	}
+}
+
+/// Streams events to an RPC client until it disconnects.
+pub(crate) async fn serve(
+	mut writer: OwnedWriteHalf, mut receiver: broadcast::Receiver<StreamedEvent>,
+) -> io::Result<()> {
+	writer
+		.write_all(
+			b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+		)
+		.await?;
+	loop {
+		let message = tokio::select! {
+			res = receiver.recv() => match res {
+				Ok(event) => {
+					format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.event, event.data)
+				}
+				Err(broadcast::error::RecvError::Lagged(missed)) => format!(
+					"event: lagged\ndata: {}\n\n",
+					json!({ "timestamp": time_now_secs(), "missed_events": missed })
+				),
+				Err(broadcast::error::RecvError::Closed) => return writer.shutdown().await,
+			},
+			_ = tokio::time::sleep(KEEPALIVE_INTERVAL) => ": keepalive\n\n".to_string(),
+		};
+		writer.write_all(message.as_bytes()).await?;
+	}
+}
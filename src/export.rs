@@ -0,0 +1,229 @@
+use crate::disk;
+use crate::hex_utils;
+use crate::peer_limits;
+use crate::peer_limits::PeerLimitsStorage;
+use crate::storage::Storage;
+use crate::{time_now_secs, HTLCStatus, PaymentInfoStorage};
+use bitcoin::secp256k1::key::PublicKey;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// The first line of every archive, followed by its format version. Bump the version whenever a
+/// section's line format changes incompatibly.
+const ARCHIVE_MAGIC: &str = "ldk-sample-state";
+const ARCHIVE_VERSION: u32 = 1;
+
+const OUTBOUND_PAYMENTS: &str = "outbound_payments";
+const INBOUND_PAYMENTS: &str = "inbound_payments";
+const PEER_ADDRS: &str = "peer_addrs";
+const PEER_LIMITS: &str = "peer_limits";
+
+/// Which parts of the node's state to export. Channel state is never exported, as it's only valid
+/// for the node it was created by.
+pub(crate) struct ExportSections {
+	pub(crate) payments: bool,
+	pub(crate) invoices: bool,
+	pub(crate) peers: bool,
+}
+
+/// Where the state an archive is exported from or imported into lives.
+pub(crate) struct NodeState<'a> {
+	pub(crate) inbound_payments: &'a PaymentInfoStorage,
+	pub(crate) outbound_payments: &'a PaymentInfoStorage,
+	pub(crate) peer_limits: &'a PeerLimitsStorage,
+	pub(crate) storage: &'a dyn Storage,
+}
+
+fn read_peer_addrs(storage: &dyn Storage) -> Vec<String> {
+	let mut seen = HashSet::new();
+	disk::read_channel_peer_lines(storage)
+		.unwrap_or_default()
+		.into_iter()
+		.filter(|line| !line.is_empty() && seen.insert(line.clone()))
+		.collect()
+}
+
+fn peer_addr_pubkey(line: &str) -> Option<PublicKey> {
+	let mut parts = line.splitn(2, '@');
+	let pubkey = hex_utils::to_compressed_pubkey(parts.next()?)?;
+	parts.next().filter(|addr| !addr.is_empty())?;
+	Some(pubkey)
+}
+
+/// Writes the chosen sections of the node's state to a portable archive at `path`, returning how
+/// many entries each section holds.
+///
+/// The archive is line-based: a `<magic> <version>` header, the exporting node's id, then each
+/// section as a `[<name>]` line followed by its entries in the same form as our data files.
+pub(crate) fn export_state(
+	path: &Path, sections: &ExportSections, our_node_id: PublicKey, state: &NodeState,
+) -> std::io::Result<Vec<(&'static str, usize)>> {
+	let mut archive = format!(
+		"{} {}\nnode_id {}\nexported_at {}\n",
+		ARCHIVE_MAGIC,
+		ARCHIVE_VERSION,
+		our_node_id,
+		time_now_secs()
+	);
+	let mut counts = Vec::new();
+	let mut add_section = |name: &'static str, lines: Vec<String>| {
+		archive.push_str(&format!("[{}]\n", name));
+		for line in lines.iter() {
+			archive.push_str(line);
+			archive.push('\n');
+		}
+		counts.push((name, lines.len()));
+	};
+	let payment_lines = |storage: &PaymentInfoStorage| {
+		let payments = storage.lock().unwrap();
+		payments
+			.iter()
+			.map(|(payment_hash, payment)| disk::payment_info_to_line(payment_hash, payment))
+			.collect::<Vec<_>>()
+	};
+	if sections.payments {
+		add_section(OUTBOUND_PAYMENTS, payment_lines(state.outbound_payments));
+	}
+	if sections.invoices {
+		add_section(INBOUND_PAYMENTS, payment_lines(state.inbound_payments));
+	}
+	if sections.peers {
+		add_section(PEER_ADDRS, read_peer_addrs(state.storage));
+		add_section(PEER_LIMITS, state.peer_limits.lock().unwrap().export_lines());
+	}
+
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	file.write_all(archive.as_bytes())?;
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)?;
+	Ok(counts)
+}
+
+/// Merges the archive at `path` into the node's state, returning the node it was exported from
+/// and how many new entries each section added. Entries we already have are left alone, and the
+/// whole archive is checked before anything is imported.
+///
+/// Payments which were still pending when exported can't complete on this node, so they're
+/// imported as failed.
+pub(crate) fn import_state(
+	path: &Path, state: &NodeState,
+) -> Result<(PublicKey, Vec<(&'static str, usize)>), String> {
+	let contents =
+		fs::read_to_string(path).map_err(|e| format!("failed to read {:?}: {}", path, e))?;
+	let mut lines = contents.lines();
+	match lines.next().map(|header| header.split(' ').collect::<Vec<_>>()).as_deref() {
+		Some([ARCHIVE_MAGIC, version]) => match version.parse::<u32>() {
+			Ok(ARCHIVE_VERSION) => {}
+			Ok(version) if version > ARCHIVE_VERSION => {
+				return Err(format!(
+					"archive is version {}, but we only understand up to version {}. Import it \
+					 with a newer version of the node",
+					version, ARCHIVE_VERSION
+				))
+			}
+			_ => return Err(format!("unsupported archive version {}", version)),
+		},
+		_ => return Err(format!("{:?} is not a state archive", path)),
+	}
+	let node_id = lines
+		.next()
+		.and_then(|line| line.strip_prefix("node_id "))
+		.and_then(hex_utils::to_compressed_pubkey)
+		.ok_or_else(|| "archive is missing the node it was exported from".to_string())?;
+	lines
+		.next()
+		.and_then(|line| line.strip_prefix("exported_at "))
+		.and_then(|secs| secs.parse::<u64>().ok())
+		.ok_or_else(|| "archive is missing when it was exported".to_string())?;
+
+	let mut sections: Vec<(&'static str, Vec<String>)> = Vec::new();
+	for line in lines {
+		if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+			let name = [OUTBOUND_PAYMENTS, INBOUND_PAYMENTS, PEER_ADDRS, PEER_LIMITS]
+				.iter()
+				.find(|known| **known == name)
+				.copied()
+				.ok_or_else(|| format!("archive has unknown section {}", name))?;
+			sections.push((name, Vec::new()));
+			continue;
+		}
+		match sections.last_mut() {
+			Some((_, entries)) => entries.push(line.to_string()),
+			None => return Err("archive has entries outside of any section".to_string()),
+		}
+	}
+
+	// Check every entry before touching any of our state.
+	for (name, entries) in sections.iter() {
+		let valid = match *name {
+			OUTBOUND_PAYMENTS | INBOUND_PAYMENTS => {
+				entries.iter().all(|line| disk::payment_info_from_line(line).is_some())
+			}
+			PEER_ADDRS => entries.iter().all(|line| peer_addr_pubkey(line).is_some()),
+			_ => entries.iter().all(|line| peer_limits::peer_limits_from_line(line).is_some()),
+		};
+		if !valid {
+			return Err(format!("archive has a malformed {} entry", name));
+		}
+	}
+
+	let mut counts = Vec::new();
+	for (name, entries) in sections.iter() {
+		let added = match *name {
+			OUTBOUND_PAYMENTS | INBOUND_PAYMENTS => {
+				let storage = if *name == OUTBOUND_PAYMENTS {
+					state.outbound_payments
+				} else {
+					state.inbound_payments
+				};
+				let mut payments = storage.lock().unwrap();
+				let mut added = 0;
+				for line in entries.iter() {
+					let (payment_hash, mut payment) = disk::payment_info_from_line(line).unwrap();
+					if payments.contains_key(&payment_hash) {
+						continue;
+					}
+					if let HTLCStatus::Pending | HTLCStatus::TimedOut = payment.status {
+						payment.status = HTLCStatus::Failed;
+						payment.updated_at = time_now_secs();
+						payment.failure =
+							Some(format!("still pending on node {} when exported", node_id));
+					}
+					payments.insert(payment_hash, payment);
+					added += 1;
+				}
+				payments.persist();
+				added
+			}
+			PEER_ADDRS => {
+				let mut known: HashSet<PublicKey> = read_peer_addrs(state.storage)
+					.iter()
+					.filter_map(|line| peer_addr_pubkey(line))
+					.collect();
+				let mut added = 0;
+				for line in entries.iter() {
+					if !known.insert(peer_addr_pubkey(line).unwrap()) {
+						continue;
+					}
+					disk::persist_channel_peer(state.storage, line)
+						.map_err(|e| format!("failed to persist peer address: {}", e))?;
+					added += 1;
+				}
+				added
+			}
+			_ => state
+				.peer_limits
+				.lock()
+				.unwrap()
+				.import_lines(entries)
+				.ok_or_else(|| format!("archive has a malformed {} entry", name))?,
+		};
+		counts.push((*name, added));
+	}
+	Ok((node_id, counts))
+}
@@ -0,0 +1,62 @@
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Channel opens whose funding transaction is built and signed outside the node, e.g. by a
+/// hardware wallet or a multisig treasury, rather than by our wallet. Unfunded channels don't
+/// survive a restart, so neither does this.
+#[derive(Default)]
+pub(crate) struct ExternalFunding {
+	/// Opens started with `openchannel --psbt` which LDK hasn't asked us to fund yet, by the
+	/// `user_channel_id` we gave them.
+	requested: HashSet<u64>,
+	/// The funding output each channel waiting for its funding transaction must pay, by temporary
+	/// channel id.
+	awaiting: HashMap<[u8; 32], TxOut>,
+}
+
+pub(crate) type ExternalFundingStorage = Arc<Mutex<ExternalFunding>>;
+
+impl ExternalFunding {
+	/// Notes that the open with the given `user_channel_id` is to be funded externally.
+	pub(crate) fn request(&mut self, user_channel_id: u64) {
+		self.requested.insert(user_channel_id);
+	}
+
+	/// Whether the open with the given `user_channel_id` is to be funded externally, forgetting it
+	/// as its funding output is now known.
+	pub(crate) fn take_request(&mut self, user_channel_id: u64) -> bool {
+		self.requested.remove(&user_channel_id)
+	}
+
+	/// Notes the funding output a channel's externally signed funding transaction must pay.
+	pub(crate) fn await_funding(&mut self, temporary_channel_id: [u8; 32], funding_output: TxOut) {
+		self.awaiting.insert(temporary_channel_id, funding_output);
+	}
+
+	/// Checks that `tx` can fund the channel: it must pay the funding output exactly and only
+	/// spend segwit outputs, as otherwise it could be malleated, and be fully signed, as we can't
+	/// sign it ourselves.
+	pub(crate) fn check_funding_tx(
+		&self, temporary_channel_id: &[u8; 32], tx: &Transaction,
+	) -> Result<(), String> {
+		if let Some(funding_output) = self.awaiting.get(temporary_channel_id) {
+			if !tx.output.iter().any(|output| output == funding_output) {
+				return Err(format!(
+					"the transaction doesn't pay exactly {} sat to the channel's funding address",
+					funding_output.value
+				));
+			}
+		}
+		if tx.input.iter().any(|input| input.witness.is_empty()) {
+			return Err("every input must be a signed segwit input".to_string());
+		}
+		Ok(())
+	}
+
+	/// Forgets a channel once its funding transaction was handed to LDK, or it closed.
+	pub(crate) fn channel_done(&mut self, temporary_channel_id: &[u8; 32], user_channel_id: u64) {
+		self.awaiting.remove(temporary_channel_id);
+		self.requested.remove(&user_channel_id);
+	}
+}
@@ -0,0 +1,171 @@
+use crate::disk::FilesystemLogger;
+use crate::route_exclusions::{ExcludingScorer, RouteExclusionsStorage};
+use crate::{time_now_secs, HTLCStatus, PaymentInfoStorage, PaymentInfoStore};
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::ln::channelmanager::ChannelDetails;
+use lightning::ln::msgs::{ErrorAction, LightningError};
+use lightning::routing::network_graph::NetworkGraph;
+use lightning::routing::router::{Route, RouteParameters};
+use lightning::routing::Score;
+use lightning_invoice::payment::Router;
+use lightning_invoice::utils::DefaultRouter;
+use std::sync::Arc;
+
+/// The most we're willing to pay in routing fees for a payment: a flat amount plus a share of the
+/// amount being sent. Optionally, the fees paid to reach any one counterparty over a rolling window
+/// are capped too, so that e.g. an automated rebalancer paying ourselves can't burn through funds.
+#[derive(Clone, Copy)]
+pub(crate) struct FeeBudget {
+	pub(crate) base_msat: u64,
+	pub(crate) proportional_millionths: u64,
+	pub(crate) counterparty_budget_msat: Option<u64>,
+	pub(crate) counterparty_window_secs: u64,
+}
+
+impl FeeBudget {
+	fn max_fee_msat(&self, amt_msat: u64) -> u64 {
+		self.base_msat
+			.saturating_add(amt_msat.saturating_mul(self.proportional_millionths) / 1_000_000)
+	}
+
+	/// Fails if sending `amt_msat` over `route` would cost more than the budget allows.
+	pub(crate) fn check_route(&self, route: &Route, amt_msat: u64) -> Result<(), LightningError> {
+		let fees_msat = route_fees_msat(route);
+		let max_fee_msat = self.max_fee_msat(amt_msat);
+		if fees_msat > max_fee_msat {
+			return Err(LightningError {
+				err: format!(
+					"cheapest route costs {} msat in fees, more than our budget of {} msat",
+					fees_msat, max_fee_msat
+				),
+				action: ErrorAction::IgnoreError,
+			});
+		}
+		Ok(())
+	}
+
+	/// The routing fees paid to reach `counterparty` within the rolling window, plus those of any
+	/// payments to it which are in flight over a known route.
+	pub(crate) fn counterparty_fees_msat(
+		&self, payments: &PaymentInfoStore, counterparty: &PublicKey,
+	) -> u64 {
+		let since = time_now_secs().saturating_sub(self.counterparty_window_secs);
+		payments
+			.values()
+			.filter_map(|payment| {
+				let route = payment.route.as_ref()?;
+				if route_destination(route) != Some(counterparty) {
+					return None;
+				}
+				match payment.status {
+					HTLCStatus::Succeeded if payment.updated_at >= since => {
+						Some(payment.fee_paid_msat.unwrap_or_else(|| route_fees_msat(route)))
+					}
+					HTLCStatus::Pending | HTLCStatus::TimedOut => Some(route_fees_msat(route)),
+					_ => None,
+				}
+			})
+			.sum()
+	}
+
+	/// How much more than `route`'s fees we could pay for sending `amt_msat` over it without going
+	/// over the budget, or the budget for its destination.
+	pub(crate) fn headroom_msat(
+		&self, route: &Route, amt_msat: u64, payments: &PaymentInfoStore,
+	) -> u64 {
+		let fees_msat = route_fees_msat(route);
+		let headroom_msat = self.max_fee_msat(amt_msat).saturating_sub(fees_msat);
+		match (self.counterparty_budget_msat, route_destination(route)) {
+			(Some(budget_msat), Some(destination)) => {
+				let spent_msat = self.counterparty_fees_msat(payments, destination);
+				headroom_msat.min(budget_msat.saturating_sub(spent_msat.saturating_add(fees_msat)))
+			}
+			_ => headroom_msat,
+		}
+	}
+
+	/// Fails if paying `route`'s fees would take us over the budget for its destination.
+	pub(crate) fn check_counterparty(
+		&self, route: &Route, payments: &PaymentInfoStore,
+	) -> Result<(), LightningError> {
+		let (budget_msat, destination) =
+			match (self.counterparty_budget_msat, route_destination(route)) {
+				(Some(budget_msat), Some(destination)) => (budget_msat, destination),
+				_ => return Ok(()),
+			};
+		let spent_msat = self.counterparty_fees_msat(payments, destination);
+		let fees_msat = route_fees_msat(route);
+		if spent_msat.saturating_add(fees_msat) > budget_msat {
+			return Err(LightningError {
+				err: format!(
+					"route costs {} msat in fees, but only {} msat is left of the {} msat budget for paying {} over the last {} hours",
+					fees_msat,
+					budget_msat.saturating_sub(spent_msat),
+					budget_msat,
+					destination,
+					self.counterparty_window_secs / 3600
+				),
+				action: ErrorAction::IgnoreError,
+			});
+		}
+		Ok(())
+	}
+}
+
+/// The node a route pays, which is ourselves for a circular rebalance.
+fn route_destination(route: &Route) -> Option<&PublicKey> {
+	route.paths.first()?.last().map(|hop| &hop.pubkey)
+}
+
+/// The total routing fees paid across all of a route's paths.
+pub(crate) fn route_fees_msat(route: &Route) -> u64 {
+	// The last hop's fee is the amount the recipient receives, not a fee.
+	route
+		.paths
+		.iter()
+		.map(|path| path.iter().rev().skip(1).map(|hop| hop.fee_msat).sum::<u64>())
+		.sum()
+}
+
+/// Finds routes as the `DefaultRouter` does, but around any nodes and channels the operator
+/// excluded, and refuses any which would exceed our fee budget.
+///
+/// When a hop fails a payment with fee_insufficient or a similar error carrying a channel update,
+/// the update is applied to the network graph before the `InvoicePayer` asks us for a new route,
+/// so retries pay the hop's new fees immediately, but only for as long as they stay within budget.
+pub(crate) struct FeeBudgetRouter {
+	inner: DefaultRouter<Arc<NetworkGraph>, Arc<FilesystemLogger>>,
+	fee_budget: FeeBudget,
+	outbound_payments: PaymentInfoStorage,
+	route_exclusions: RouteExclusionsStorage,
+}
+
+impl FeeBudgetRouter {
+	pub(crate) fn new(
+		network_graph: Arc<NetworkGraph>, logger: Arc<FilesystemLogger>, fee_budget: FeeBudget,
+		outbound_payments: PaymentInfoStorage, route_exclusions: RouteExclusionsStorage,
+	) -> Self {
+		Self {
+			inner: DefaultRouter::new(network_graph, logger),
+			fee_budget,
+			outbound_payments,
+			route_exclusions,
+		}
+	}
+}
+
+impl<S: Score> Router<S> for FeeBudgetRouter {
+	fn find_route(
+		&self, payer: &PublicKey, params: &RouteParameters, first_hops: Option<&[&ChannelDetails]>,
+		scorer: &S,
+	) -> Result<Route, LightningError> {
+		let exclusions = self.route_exclusions.lock().unwrap();
+		let scorer =
+			ExcludingScorer { inner: scorer, exclusions: &exclusions, now: time_now_secs() };
+		let route = self.inner.find_route(payer, params, first_hops, &scorer)?;
+		exclusions.check_route(&route)?;
+		self.fee_budget.check_route(&route, params.final_value_msat)?;
+		self.fee_budget.check_counterparty(&route, &self.outbound_payments.lock().unwrap())?;
+		Ok(route)
+	}
+}
@@ -0,0 +1,223 @@
+use crate::bitcoind_client::{CachedFee, CachedFees, FeeSource, Target};
+use crate::time_now_secs;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::hash_types::Txid;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Why we broadcast a transaction.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum BroadcastPurpose {
+	/// Funds one of our channels.
+	Funding,
+	/// Spends a channel's funding output, cooperatively or not.
+	Close,
+	/// Sweeps outputs we were paid by closing channels to our wallet.
+	Sweep,
+	/// Pays an address on the user's behalf.
+	Send,
+	/// Anything else LDK broadcast for a channel, e.g. claiming HTLCs or punishing a revoked
+	/// commitment.
+	Channel,
+}
+
+impl BroadcastPurpose {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			BroadcastPurpose::Funding => "funding",
+			BroadcastPurpose::Close => "close",
+			BroadcastPurpose::Sweep => "sweep",
+			BroadcastPurpose::Send => "send",
+			BroadcastPurpose::Channel => "channel",
+		}
+	}
+
+	fn from_str(purpose: &str) -> Option<Self> {
+		match purpose {
+			"funding" => Some(BroadcastPurpose::Funding),
+			"close" => Some(BroadcastPurpose::Close),
+			"sweep" => Some(BroadcastPurpose::Sweep),
+			"send" => Some(BroadcastPurpose::Send),
+			"channel" => Some(BroadcastPurpose::Channel),
+			_ => None,
+		}
+	}
+}
+
+/// What our fee estimates were when we first broadcast a transaction.
+pub(crate) struct FeeSnapshot {
+	/// When we first broadcast the transaction, in seconds since the UNIX epoch.
+	pub(crate) timestamp: u64,
+	pub(crate) purpose: BroadcastPurpose,
+	/// The estimate the transaction's feerate was taken from, `None` if it wasn't ours to pick,
+	/// e.g. bitcoind's wallet funded it or it pays a feerate negotiated with the channel's peer.
+	pub(crate) built_at: Option<Target>,
+	/// `None` if bitcoind built and broadcast the transaction itself.
+	pub(crate) weight: Option<usize>,
+	pub(crate) fees: CachedFees,
+}
+
+/// The fee estimates behind every transaction we broadcast, so that a fee which turned out too
+/// high or too low can be traced back to what the market looked like at the time. Snapshots are
+/// appended to a log on disk and kept for good.
+pub(crate) struct FeeSnapshotLog {
+	path: String,
+	snapshots: Mutex<HashMap<Txid, FeeSnapshot>>,
+	/// What callers told us about transactions they're about to have broadcast, as the broadcast
+	/// itself may happen elsewhere, e.g. LDK broadcasting our funding transactions.
+	expected: Mutex<HashMap<Txid, (BroadcastPurpose, Option<Target>)>>,
+}
+
+impl FeeSnapshotLog {
+	pub(crate) fn new(path: String) -> Self {
+		let snapshots = Mutex::new(read_snapshots(Path::new(&path)));
+		Self { path, snapshots, expected: Mutex::new(HashMap::new()) }
+	}
+
+	/// Tells us why `txid` will be broadcast and which estimate it was built at, for when it is.
+	pub(crate) fn expect(&self, txid: Txid, purpose: BroadcastPurpose, built_at: Option<Target>) {
+		self.expected.lock().unwrap().insert(txid, (purpose, built_at));
+	}
+
+	/// Records the estimates `tx` was broadcast under, unless it's a rebroadcast. `purpose` is
+	/// used if we weren't told to expect the transaction.
+	pub(crate) fn record(
+		&self, tx: Option<&Transaction>, txid: Txid, purpose: BroadcastPurpose,
+		built_at: Option<Target>, fees: CachedFees,
+	) {
+		let (purpose, built_at) =
+			self.expected.lock().unwrap().remove(&txid).unwrap_or((purpose, built_at));
+		let mut snapshots = self.snapshots.lock().unwrap();
+		if snapshots.contains_key(&txid) {
+			return;
+		}
+		let snapshot = FeeSnapshot {
+			timestamp: time_now_secs(),
+			purpose,
+			built_at,
+			weight: tx.map(|tx| tx.get_weight()),
+			fees,
+		};
+		if append_snapshot(Path::new(&self.path), &txid, &snapshot).is_err() {
+			eprintln!("Warning: Failed to persist fee snapshot, check your disk and permissions");
+		}
+		snapshots.insert(txid, snapshot);
+	}
+
+	/// Calls `f` with the snapshot taken when we broadcast `txid`, if we did.
+	pub(crate) fn with_snapshot<F: FnOnce(&FeeSnapshot)>(&self, txid: &Txid, f: F) -> bool {
+		match self.snapshots.lock().unwrap().get(txid) {
+			Some(snapshot) => {
+				f(snapshot);
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+	value.map_or("-".to_string(), |value| value.to_string())
+}
+
+/// Each snapshot is stored as a line of the form
+/// `<txid> <timestamp> <purpose> <built_at> <weight> <mempool_min_sat_per_kw> <estimates_age_secs>`
+/// followed by `<target>:<conf_target>:<sat_per_kw>:<source>` for each target. Missing values are
+/// `-`.
+fn append_snapshot(path: &Path, txid: &Txid, snapshot: &FeeSnapshot) -> std::io::Result<()> {
+	let mut line = format!(
+		"{} {} {} {} {} {} {}",
+		txid,
+		snapshot.timestamp,
+		snapshot.purpose.as_str(),
+		optional(snapshot.built_at.as_ref().map(Target::as_str)),
+		optional(snapshot.weight),
+		optional(snapshot.fees.mempool_min_feerate),
+		optional(snapshot.fees.age.map(|age| age.as_secs())),
+	);
+	for fee in snapshot.fees.feerates.iter() {
+		line.push_str(&format!(
+			" {}:{}:{}:{}",
+			fee.target.as_str(),
+			fee.conf_target,
+			fee.feerate_sat_per_kw,
+			fee.source.as_str()
+		));
+	}
+	line.push('\n');
+	let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+	file.write_all(line.as_bytes())?;
+	file.sync_all()
+}
+
+fn parse_optional<T: FromStr>(value: &str) -> Option<Option<T>> {
+	match value {
+		"-" => Some(None),
+		value => value.parse().ok().map(Some),
+	}
+}
+
+fn parse_fee(fee: &str) -> Option<CachedFee> {
+	let parts: Vec<&str> = fee.split(':').collect();
+	if parts.len() != 4 {
+		return None;
+	}
+	Some(CachedFee {
+		target: Target::from_str(parts[0])?,
+		conf_target: parts[1].parse().ok()?,
+		feerate_sat_per_kw: parts[2].parse().ok()?,
+		source: FeeSource::from_str(parts[3])?,
+	})
+}
+
+fn parse_snapshot(line: &str) -> Option<(Txid, FeeSnapshot)> {
+	let parts: Vec<&str> = line.split(' ').collect();
+	if parts.len() < 7 {
+		return None;
+	}
+	let built_at = match parts[3] {
+		"-" => None,
+		target => Some(Target::from_str(target)?),
+	};
+	let feerates = parts[7..].iter().map(|fee| parse_fee(fee)).collect::<Option<Vec<_>>>()?;
+	let fees = CachedFees {
+		feerates,
+		mempool_min_feerate: parse_optional(parts[5])?,
+		age: parse_optional(parts[6])?.map(Duration::from_secs),
+	};
+	let snapshot = FeeSnapshot {
+		timestamp: parts[1].parse().ok()?,
+		purpose: BroadcastPurpose::from_str(parts[2])?,
+		built_at,
+		weight: parse_optional(parts[4])?,
+		fees,
+	};
+	Some((Txid::from_str(parts[0]).ok()?, snapshot))
+}
+
+fn read_snapshots(path: &Path) -> HashMap<Txid, FeeSnapshot> {
+	let mut snapshots = HashMap::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return snapshots,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		match parse_snapshot(&line) {
+			Some((txid, snapshot)) => {
+				snapshots.entry(txid).or_insert(snapshot);
+			}
+			None => eprintln!("Warning: Skipping malformed fee snapshot in {:?}", path),
+		}
+	}
+	snapshots
+}
@@ -0,0 +1,231 @@
+use crate::notifier::Notifier;
+use crate::ChannelManager;
+use lightning::ln::msgs::{
+	UpdateAddHTLC, UpdateFailHTLC, UpdateFailMalformedHTLC, UpdateFulfillHTLC,
+};
+use lightning::ln::PaymentHash;
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How many HTLCs we keep track of at once, dropping the oldest first. HTLCs of channels which
+/// closed while they were in flight are never resolved over the wire, so they'd pile up otherwise.
+const MAX_TRACKED_HTLCS: usize = 10_000;
+
+/// How often we add up the value of the HTLCs in flight through us.
+const EXPOSURE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The two HTLCs of a payment we forwarded.
+#[derive(Clone, Copy)]
+pub(crate) struct ForwardedHtlcs {
+	pub(crate) inbound_channel_id: [u8; 32],
+	pub(crate) inbound_amt_msat: u64,
+	pub(crate) outbound_channel_id: [u8; 32],
+	pub(crate) outbound_amt_msat: u64,
+}
+
+struct InboundHtlc {
+	channel_id: [u8; 32],
+	htlc_id: u64,
+	amount_msat: u64,
+	payment_hash: PaymentHash,
+	cltv_expiry: u32,
+}
+
+struct OutboundHtlc {
+	htlc_id: u64,
+	htlcs: ForwardedHtlcs,
+}
+
+#[derive(Default)]
+struct Htlcs {
+	/// HTLCs our peers offered us which we haven't forwarded, claimed or failed yet.
+	inbound: VecDeque<InboundHtlc>,
+	/// HTLCs we forwarded which the next hop hasn't resolved yet.
+	outbound: VecDeque<OutboundHtlc>,
+	/// Forwards the next hop claimed, oldest first, waiting for their `PaymentForwarded` event.
+	settled: VecDeque<ForwardedHtlcs>,
+}
+
+/// Works out which channels a payment was forwarded between, which LDK 0.0.103's
+/// `PaymentForwarded` event doesn't tell us, from the HTLC messages we exchange with our peers. An
+/// HTLC we offer with the payment hash of one we were offered is taken to forward it, and once the
+/// next hop claims it the forward waits for LDK's event to record it with.
+///
+/// Nothing here is persisted, so forwards in flight while the node restarts are recorded without
+/// their channels.
+#[derive(Default)]
+pub(crate) struct HtlcTracker {
+	htlcs: Mutex<Htlcs>,
+}
+
+impl HtlcTracker {
+	pub(crate) fn add_received(&self, msg: &UpdateAddHTLC) {
+		let mut htlcs = self.htlcs.lock().unwrap();
+		if htlcs.inbound.len() >= MAX_TRACKED_HTLCS {
+			htlcs.inbound.pop_front();
+		}
+		htlcs.inbound.push_back(InboundHtlc {
+			channel_id: msg.channel_id,
+			htlc_id: msg.htlc_id,
+			amount_msat: msg.amount_msat,
+			payment_hash: msg.payment_hash,
+			cltv_expiry: msg.cltv_expiry,
+		});
+	}
+
+	/// The earliest expiry of the HTLCs paying `payment_hash` to us which we haven't claimed or
+	/// failed yet, or `None` if we didn't see them offered, e.g. as they were before a restart.
+	pub(crate) fn min_cltv_expiry(&self, payment_hash: &PaymentHash) -> Option<u32> {
+		let htlcs = self.htlcs.lock().unwrap();
+		htlcs
+			.inbound
+			.iter()
+			.filter(|htlc| htlc.payment_hash == *payment_hash)
+			.map(|htlc| htlc.cltv_expiry)
+			.min()
+	}
+
+	pub(crate) fn add_sent(&self, msg: &UpdateAddHTLC) {
+		let mut htlcs = self.htlcs.lock().unwrap();
+		// HTLCs of payments we send ourselves don't forward anything.
+		let inbound_idx = match htlcs.inbound.iter().position(|htlc| {
+			htlc.payment_hash == msg.payment_hash && htlc.channel_id != msg.channel_id
+		}) {
+			Some(idx) => idx,
+			None => return,
+		};
+		let inbound = htlcs.inbound.remove(inbound_idx).unwrap();
+		if htlcs.outbound.len() >= MAX_TRACKED_HTLCS {
+			htlcs.outbound.pop_front();
+		}
+		htlcs.outbound.push_back(OutboundHtlc {
+			htlc_id: msg.htlc_id,
+			htlcs: ForwardedHtlcs {
+				inbound_channel_id: inbound.channel_id,
+				inbound_amt_msat: inbound.amount_msat,
+				outbound_channel_id: msg.channel_id,
+				outbound_amt_msat: msg.amount_msat,
+			},
+		});
+	}
+
+	/// We claimed or failed an HTLC we were offered.
+	pub(crate) fn resolved_sent(&self, channel_id: &[u8; 32], htlc_id: u64) {
+		let mut htlcs = self.htlcs.lock().unwrap();
+		htlcs.inbound.retain(|htlc| !(htlc.channel_id == *channel_id && htlc.htlc_id == htlc_id));
+	}
+
+	/// The next hop claimed or failed an HTLC we offered, returning the forward it was part of.
+	fn take_outbound(&self, channel_id: &[u8; 32], htlc_id: u64) -> Option<ForwardedHtlcs> {
+		let mut htlcs = self.htlcs.lock().unwrap();
+		let idx = htlcs.outbound.iter().position(|htlc| {
+			htlc.htlcs.outbound_channel_id == *channel_id && htlc.htlc_id == htlc_id
+		})?;
+		htlcs.outbound.remove(idx).map(|htlc| htlc.htlcs)
+	}
+
+	pub(crate) fn fulfill_received(&self, msg: &UpdateFulfillHTLC) {
+		if let Some(forward) = self.take_outbound(&msg.channel_id, msg.htlc_id) {
+			let mut htlcs = self.htlcs.lock().unwrap();
+			if htlcs.settled.len() >= MAX_TRACKED_HTLCS {
+				htlcs.settled.pop_front();
+			}
+			htlcs.settled.push_back(forward);
+		}
+	}
+
+	pub(crate) fn fail_received(&self, msg: &UpdateFailHTLC) {
+		self.take_outbound(&msg.channel_id, msg.htlc_id);
+	}
+
+	pub(crate) fn fail_malformed_received(&self, msg: &UpdateFailMalformedHTLC) {
+		self.take_outbound(&msg.channel_id, msg.htlc_id);
+	}
+
+	/// The value of the HTLCs on `channel_ids` which we were offered and haven't forwarded, claimed
+	/// or failed yet, plus that of the HTLCs we forwarded which the next hop hasn't resolved yet.
+	pub(crate) fn in_flight_msat(&self, channel_ids: &HashSet<[u8; 32]>) -> u64 {
+		let htlcs = self.htlcs.lock().unwrap();
+		let inbound_msat: u64 = htlcs
+			.inbound
+			.iter()
+			.filter(|htlc| channel_ids.contains(&htlc.channel_id))
+			.map(|htlc| htlc.amount_msat)
+			.sum();
+		let outbound_msat: u64 = htlcs
+			.outbound
+			.iter()
+			.filter(|htlc| channel_ids.contains(&htlc.htlcs.outbound_channel_id))
+			.map(|htlc| htlc.htlcs.outbound_amt_msat)
+			.sum();
+		inbound_msat + outbound_msat
+	}
+
+	/// Takes the forward a `PaymentForwarded` event earning `fee_earned_msat` is about. LDK
+	/// generates the events in the order the next hops claimed, so this is the oldest claimed
+	/// forward, if its fee adds up. If it doesn't we lost track somewhere, so start over.
+	pub(crate) fn take_settled(&self, fee_earned_msat: u64) -> Option<ForwardedHtlcs> {
+		let mut htlcs = self.htlcs.lock().unwrap();
+		let forward = htlcs.settled.pop_front()?;
+		if forward.inbound_amt_msat.checked_sub(forward.outbound_amt_msat) != Some(fee_earned_msat)
+		{
+			htlcs.settled.clear();
+			return None;
+		}
+		Some(forward)
+	}
+}
+
+/// Regularly adds up the value in flight through us and alerts the operator, on the console and
+/// through the notifier if there is one, when it goes over `ceiling_msat`, and again once it's
+/// back under 90% of it.
+///
+/// Only HTLCs we were offered, i.e. payments to us and forwards, are counted, along with the
+/// HTLCs we forwarded them over: the HTLCs of payments we send are already limited by our own
+/// payment limits. HTLCs on channels which have closed are no longer counted. LDK 0.0.103 can't
+/// fail HTLCs back before it forwards them, so new forwards aren't refused while over the ceiling.
+pub(crate) fn start_exposure_alarm(
+	ceiling_msat: u64, htlc_tracker: Arc<HtlcTracker>, channel_manager: Arc<ChannelManager>,
+	notifier: Option<Arc<Notifier>>,
+) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(EXPOSURE_CHECK_INTERVAL);
+		let mut over_ceiling = false;
+		loop {
+			interval.tick().await;
+			let channels = channel_manager.list_channels();
+			let channel_ids: HashSet<[u8; 32]> =
+				channels.iter().map(|chan_info| chan_info.channel_id).collect();
+			let exposure_msat = htlc_tracker.in_flight_msat(&channel_ids);
+			let balance_msat: u64 = channels.iter().map(|chan_info| chan_info.balance_msat).sum();
+			let (event, alarm) = if !over_ceiling && exposure_msat > ceiling_msat {
+				("htlc_exposure_high", "over its ceiling of")
+			} else if over_ceiling && exposure_msat < ceiling_msat / 10 * 9 {
+				("htlc_exposure_normal", "back under its ceiling of")
+			} else {
+				continue;
+			};
+			over_ceiling = !over_ceiling;
+			println!(
+				"\nEVENT: {} msat in flight through our channels, {} {} msat, with {} msat of balance in our channels",
+				exposure_msat, alarm, ceiling_msat, balance_msat
+			);
+			print!("> ");
+			io::stdout().flush().unwrap();
+			if let Some(notifier) = &notifier {
+				notifier.notify(
+					event,
+					json!({
+						"exposure_msat": exposure_msat,
+						"ceiling_msat": ceiling_msat,
+						"channel_balance_msat": balance_msat,
+					}),
+				);
+			}
+		}
+	});
+}
@@ -0,0 +1,144 @@
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::chain;
+use lightning::ln::features::ChannelFeatures;
+use lightning::ln::msgs::{
+	LightningError, OptionalField, UnsignedChannelAnnouncement, UnsignedChannelUpdate,
+};
+use lightning::routing::network_graph::NetworkGraph;
+use std::sync::Arc;
+
+/// Inserts a synthetic, unsigned channel between `node_a` and `node_b` into `network_graph`, with
+/// channel updates in both directions charging the given fees, so that routing can be tested
+/// against crafted topologies without real peers. The nodes aren't announced, which the router
+/// doesn't need.
+pub(crate) fn inject_channel(
+	network_graph: &NetworkGraph, network: Network, node_a: PublicKey, node_b: PublicKey,
+	short_channel_id: u64, fee_base_msat: u32, fee_proportional_millionths: u32,
+) -> Result<(), LightningError> {
+	let chain_hash = genesis_block(network).header.block_hash();
+	// Gossip requires node_id_1 to be the lexicographically-lesser of the two node ids.
+	let (node_one, node_two) = if node_a.serialize()[..] < node_b.serialize()[..] {
+		(node_a, node_b)
+	} else {
+		(node_b, node_a)
+	};
+	let channel_announcement = UnsignedChannelAnnouncement {
+		features: ChannelFeatures::known(),
+		chain_hash,
+		short_channel_id,
+		node_id_1: node_one,
+		node_id_2: node_two,
+		bitcoin_key_1: node_one,
+		bitcoin_key_2: node_two,
+		excess_data: Vec::new(),
+	};
+	network_graph.update_channel_from_unsigned_announcement(
+		&channel_announcement,
+		&None::<Arc<dyn chain::Access + Send + Sync>>,
+	)?;
+
+	// The low bit of `flags` indicates the direction: 0 for node_one -> node_two, 1 for the
+	// reverse.
+	for direction in 0..2 {
+		let channel_update = UnsignedChannelUpdate {
+			chain_hash,
+			short_channel_id,
+			timestamp: 1,
+			flags: direction,
+			cltv_expiry_delta: 40,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: OptionalField::Absent,
+			fee_base_msat,
+			fee_proportional_millionths,
+			excess_data: Vec::new(),
+		};
+		network_graph.update_channel_unsigned(&channel_update)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::key::SecretKey;
+	use bitcoin::secp256k1::Secp256k1;
+	use lightning::routing::router::{find_route, Payee, Route, RouteParameters};
+	use lightning::routing::scorer::Scorer;
+	use lightning::util::logger::{Logger, Record};
+
+	struct TestLogger;
+
+	impl Logger for TestLogger {
+		fn log(&self, _record: &Record) {}
+	}
+
+	fn node(n: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[n; 32]).unwrap())
+	}
+
+	fn network_graph(channels: &[(u8, u8, u64, u32)]) -> NetworkGraph {
+		let network_graph = NetworkGraph::new(genesis_block(Network::Regtest).header.block_hash());
+		for (node_a, node_b, short_channel_id, fee_base_msat) in channels.iter() {
+			inject_channel(
+				&network_graph,
+				Network::Regtest,
+				node(*node_a),
+				node(*node_b),
+				*short_channel_id,
+				*fee_base_msat,
+				0,
+			)
+			.unwrap();
+		}
+		network_graph
+	}
+
+	fn route(
+		network_graph: &NetworkGraph, payer: u8, payee: u8, amt_msat: u64,
+	) -> Result<Route, LightningError> {
+		let params = RouteParameters {
+			payee: Payee::for_keysend(node(payee)),
+			final_value_msat: amt_msat,
+			final_cltv_expiry_delta: 40,
+		};
+		find_route(&node(payer), &params, network_graph, None, &TestLogger, &Scorer::default())
+	}
+
+	#[test]
+	fn routes_over_injected_channels() {
+		let network_graph = network_graph(&[(1, 2, 12, 1000), (2, 3, 23, 1000)]);
+		let route = route(&network_graph, 1, 3, 10_000).unwrap();
+		assert_eq!(route.paths.len(), 1);
+		let hops: Vec<_> =
+			route.paths[0].iter().map(|hop| (hop.pubkey, hop.short_channel_id)).collect();
+		assert_eq!(hops, vec![(node(2), 12), (node(3), 23)]);
+		// Node 2 charges its base fee for forwarding over the second channel.
+		assert_eq!(route.get_total_fees(), 1000);
+	}
+
+	#[test]
+	fn prefers_cheaper_path() {
+		let network_graph =
+			network_graph(&[(1, 2, 12, 0), (2, 4, 24, 5000), (1, 3, 13, 0), (3, 4, 34, 10)]);
+		let route = route(&network_graph, 1, 4, 10_000).unwrap();
+		assert_eq!(route.paths.len(), 1);
+		let channels: Vec<_> = route.paths[0].iter().map(|hop| hop.short_channel_id).collect();
+		assert_eq!(channels, vec![13, 34]);
+	}
+
+	#[test]
+	fn fails_without_path() {
+		let network_graph = network_graph(&[(1, 2, 12, 0), (3, 4, 34, 0)]);
+		assert!(route(&network_graph, 1, 4, 10_000).is_err());
+	}
+
+	#[test]
+	fn rejects_duplicate_channel() {
+		let network_graph = network_graph(&[(1, 2, 12, 0)]);
+		assert!(
+			inject_channel(&network_graph, Network::Regtest, node(2), node(3), 12, 0, 0).is_err()
+		);
+	}
+}
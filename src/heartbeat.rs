@@ -0,0 +1,73 @@
+use crate::telemetry;
+use crate::ChannelManager;
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+use bitcoin::secp256k1::Secp256k1;
+use lightning::util::message_signing;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// The message a heartbeat's signature commits to. Monitors verify it against the node id, e.g.
+/// with lnd's `verifymessage`, so that a heartbeat can't be forged for a node which has died.
+fn heartbeat_message(
+	node_id: &PublicKey, timestamp: u64, block_height: u32, channel_count: usize,
+) -> String {
+	format!(
+		"heartbeat {} {} {} {} {}",
+		node_id,
+		timestamp,
+		block_height,
+		channel_count,
+		env!("CARGO_PKG_VERSION")
+	)
+}
+
+/// Posts a signed heartbeat to `endpoint` every `interval`, so that an external monitor can tell
+/// when the node has silently stopped. Each heartbeat carries the block height we've synced to,
+/// which also lets the monitor notice a node that's up but stuck.
+pub(crate) fn start_heartbeats(
+	endpoint: String, interval: Duration, node_secret: SecretKey,
+	channel_manager: Arc<ChannelManager>,
+) {
+	let node_id = PublicKey::from_secret_key(&Secp256k1::new(), &node_secret);
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(interval);
+		let mut failing = false;
+		loop {
+			interval.tick().await;
+			let timestamp =
+				SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+			let block_height = channel_manager.current_best_block().height();
+			let channel_count = channel_manager.list_channels().len();
+			let message = heartbeat_message(&node_id, timestamp, block_height, channel_count);
+			let signature = match message_signing::sign(message.as_bytes(), &node_secret) {
+				Ok(signature) => signature,
+				Err(e) => {
+					eprintln!("Warning: Failed to sign heartbeat: {:?}", e);
+					continue;
+				}
+			};
+			let heartbeat = serde_json::json!({
+				"node_id": node_id.to_string(),
+				"timestamp": timestamp,
+				"block_height": block_height,
+				"channel_count": channel_count,
+				"version": env!("CARGO_PKG_VERSION"),
+				"message": message,
+				"signature": signature,
+			});
+			// Only warn when heartbeats start failing, rather than on every missed one.
+			match telemetry::post_json(&endpoint, &heartbeat.to_string()).await {
+				Ok(()) if failing => {
+					failing = false;
+					eprintln!("Heartbeats to {} are being delivered again", endpoint);
+				}
+				Ok(()) => {}
+				Err(e) if !failing => {
+					failing = true;
+					eprintln!("Warning: Failed to post heartbeat to {}: {}", endpoint, e);
+				}
+				Err(_) => {}
+			}
+		}
+	});
+}
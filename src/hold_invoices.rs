@@ -0,0 +1,233 @@
+use crate::hex_utils;
+use crate::notifier::SettlementWebhook;
+use crate::{time_now_secs, ChannelManager, HTLCStatus, PaymentInfoStorage};
+use lightning::ln::PaymentHash;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a payment is held for an external system to settle it, unless asked otherwise.
+pub(crate) const DEFAULT_HOLD_TIMEOUT_SECS: u64 = 600;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+struct HoldInvoice {
+	/// How long a payment to the invoice is held before it's cancelled.
+	timeout_secs: u64,
+	/// When the payment arrived, in seconds since the UNIX epoch, once it has.
+	held_at: Option<u64>,
+}
+
+/// Invoices whose payments aren't claimed when they arrive, but held until an external system,
+/// e.g. an order pipeline, settles or cancels them over the RPC server. A payment nobody settles
+/// in time is cancelled, failing it back to the payer. LDK also fails a held payment back on its
+/// own once its HTLCs get close to expiring, after which it can no longer be settled.
+///
+/// Invoices are forgotten once settled, cancelled or expired unpaid, and written to disk whenever
+/// they change, so payments held across a restart can still be settled.
+pub(crate) struct HoldInvoices {
+	path: String,
+	invoices: Mutex<HashMap<PaymentHash, HoldInvoice>>,
+}
+
+impl HoldInvoices {
+	pub(crate) fn new(path: String) -> Self {
+		let invoices = Mutex::new(read_hold_invoices(Path::new(&path)));
+		Self { path, invoices }
+	}
+
+	/// Holds payments to the invoice with `payment_hash` for up to `timeout_secs`.
+	pub(crate) fn add(&self, payment_hash: PaymentHash, timeout_secs: u64) {
+		let mut invoices = self.invoices.lock().unwrap();
+		invoices.insert(payment_hash, HoldInvoice { timeout_secs, held_at: None });
+		self.persist(&invoices);
+	}
+
+	/// Called when a payment arrives, returning when it will be cancelled if it's to be held.
+	pub(crate) fn hold(&self, payment_hash: &PaymentHash) -> Option<u64> {
+		let mut invoices = self.invoices.lock().unwrap();
+		let invoice = invoices.get_mut(payment_hash)?;
+		let held_at = *invoice.held_at.get_or_insert(time_now_secs());
+		let cancel_at = held_at + invoice.timeout_secs;
+		self.persist(&invoices);
+		Some(cancel_at)
+	}
+
+	fn take_held(&self, payment_hash: &PaymentHash) -> Result<(), String> {
+		let mut invoices = self.invoices.lock().unwrap();
+		match invoices.get(payment_hash) {
+			Some(HoldInvoice { held_at: Some(_), .. }) => {}
+			Some(_) => return Err("no payment to that invoice has arrived yet".to_string()),
+			None => return Err("no held invoice with that payment hash".to_string()),
+		}
+		invoices.remove(payment_hash);
+		self.persist(&invoices);
+		Ok(())
+	}
+
+	/// Claims a held payment, returning the amount received.
+	pub(crate) fn settle(
+		&self, payment_hash: &PaymentHash, channel_manager: &ChannelManager,
+		inbound_payments: &PaymentInfoStorage, settlement_webhook: Option<&SettlementWebhook>,
+	) -> Result<u64, String> {
+		self.take_held(payment_hash)?;
+		let mut payments = inbound_payments.lock().unwrap();
+		let payment = payments.get_mut(payment_hash).ok_or("no payment with that payment hash")?;
+		let preimage = payment.preimage.ok_or("we don't know the payment's preimage")?;
+		let claimed = channel_manager.claim_funds(preimage);
+		payment.status = if claimed { HTLCStatus::Succeeded } else { HTLCStatus::Failed };
+		payment.updated_at = time_now_secs();
+		let amt_msat = payment.amt_msat.0.unwrap_or(0);
+		payments.persist();
+		drop(payments);
+		if !claimed {
+			return Err(
+				"the payment is no longer claimable, its HTLCs may have timed out".to_string()
+			);
+		}
+		println!(
+			"\nEVENT: settled held payment from payment hash {} of {} millisatoshis",
+			hex_utils::hex_str(&payment_hash.0),
+			amt_msat
+		);
+		if let Some(webhook) = settlement_webhook {
+			webhook.settled(*payment_hash, amt_msat, inbound_payments);
+		}
+		Ok(amt_msat)
+	}
+
+	/// Fails a held payment back to the payer.
+	pub(crate) fn cancel(
+		&self, payment_hash: &PaymentHash, channel_manager: &ChannelManager,
+		inbound_payments: &PaymentInfoStorage,
+	) -> Result<(), String> {
+		self.take_held(payment_hash)?;
+		channel_manager.fail_htlc_backwards(payment_hash);
+		let mut payments = inbound_payments.lock().unwrap();
+		if let Some(payment) = payments.get_mut(payment_hash) {
+			payment.status = HTLCStatus::Failed;
+			payment.updated_at = time_now_secs();
+			payments.persist();
+		}
+		Ok(())
+	}
+
+	/// Cancels held payments which weren't settled in time, and forgets invoices which expired
+	/// unpaid.
+	fn check(&self, channel_manager: &ChannelManager, inbound_payments: &PaymentInfoStorage) {
+		let now = time_now_secs();
+		let timed_out: Vec<PaymentHash> = {
+			let mut invoices = self.invoices.lock().unwrap();
+			let num_invoices = invoices.len();
+			let payments = inbound_payments.lock().unwrap();
+			invoices.retain(|payment_hash, invoice| {
+				invoice.held_at.is_some()
+					|| payments
+						.get(payment_hash)
+						.and_then(|payment| payment.expires_at)
+						.map_or(false, |expires_at| expires_at >= now)
+			});
+			if invoices.len() != num_invoices {
+				self.persist(&invoices);
+			}
+			invoices
+				.iter()
+				.filter(|(_, invoice)| {
+					invoice.held_at.map_or(false, |held_at| held_at + invoice.timeout_secs <= now)
+				})
+				.map(|(payment_hash, _)| *payment_hash)
+				.collect()
+		};
+		for payment_hash in timed_out {
+			if self.cancel(&payment_hash, channel_manager, inbound_payments).is_ok() {
+				println!(
+					"\nEVENT: cancelled held payment from payment hash {} as it wasn't settled in time",
+					hex_utils::hex_str(&payment_hash.0)
+				);
+			}
+		}
+	}
+
+	pub(crate) fn start_expiring(
+		hold_invoices: Arc<Self>, channel_manager: Arc<ChannelManager>,
+		inbound_payments: PaymentInfoStorage,
+	) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(CHECK_INTERVAL);
+			loop {
+				interval.tick().await;
+				hold_invoices.check(&channel_manager, &inbound_payments);
+			}
+		});
+	}
+
+	fn persist(&self, invoices: &HashMap<PaymentHash, HoldInvoice>) {
+		if persist_hold_invoices(Path::new(&self.path), invoices).is_err() {
+			eprintln!("Warning: Failed to persist hold invoices, check your disk and permissions");
+		}
+	}
+}
+
+/// Invoices are stored one per line, as `<payment_hash> <timeout_secs> <held_at|->`.
+fn persist_hold_invoices(
+	path: &Path, invoices: &HashMap<PaymentHash, HoldInvoice>,
+) -> io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for (payment_hash, invoice) in invoices.iter() {
+		file.write_all(
+			format!(
+				"{} {} {}\n",
+				hex_utils::hex_str(&payment_hash.0),
+				invoice.timeout_secs,
+				invoice.held_at.map_or("-".to_string(), |held_at| held_at.to_string())
+			)
+			.as_bytes(),
+		)?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+fn parse_hold_invoice(line: &str) -> Option<(PaymentHash, HoldInvoice)> {
+	let parts: Vec<&str> = line.split(' ').collect();
+	if parts.len() != 3 {
+		return None;
+	}
+	let hash = hex_utils::to_vec(parts[0]).filter(|hash| hash.len() == 32)?;
+	let mut payment_hash = [0; 32];
+	payment_hash.copy_from_slice(&hash);
+	let held_at = match parts[2] {
+		"-" => None,
+		held_at => Some(held_at.parse().ok()?),
+	};
+	Some((PaymentHash(payment_hash), HoldInvoice { timeout_secs: parts[1].parse().ok()?, held_at }))
+}
+
+fn read_hold_invoices(path: &Path) -> HashMap<PaymentHash, HoldInvoice> {
+	let mut invoices = HashMap::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return invoices,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		match parse_hold_invoice(&line) {
+			Some((payment_hash, invoice)) => {
+				invoices.insert(payment_hash, invoice);
+			}
+			None => eprintln!("Warning: Skipping malformed hold invoice in {:?}", path),
+		}
+	}
+	invoices
+}
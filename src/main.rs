@@ -1,60 +1,154 @@
+mod accounting;
+mod admin_socket;
+mod advisor;
+mod balances;
+mod best_block;
 pub mod bitcoind_client;
+mod bundles;
+mod channel_acceptor;
+mod channel_backup;
+mod channel_history;
+mod channel_lookup;
+mod channel_rotation;
 mod cli;
+mod close_address;
+mod compact_filters;
+mod config;
+mod connection_guard;
 mod convert;
+mod diagnostics;
 mod disk;
+mod disk_space;
+mod esplora;
+mod event_stream;
+mod export;
+mod external_funding;
+mod fee_budget;
+mod fee_snapshots;
+mod forwarding;
+#[cfg(test)]
+mod gossip_injection;
+mod heartbeat;
 mod hex_utils;
+mod hold_invoices;
+mod maintenance;
+mod memory;
+mod monitor_replica;
+mod notifier;
+mod observer;
+mod onchain_wallet;
+mod open_queue;
+mod path_privacy;
+mod pause;
+mod payer;
+mod payer_auth;
+mod payment_gc;
+mod peer_limits;
+mod pruned_sync;
+mod receipt;
+mod reissue;
+mod route_exclusions;
+mod rpc_server;
+mod rpc_tls;
+mod scheduler;
+mod seed_encryption;
+mod storage;
+mod sweep_wallet;
+mod sweeper;
+mod systemd;
+mod telemetry;
+mod tor;
+mod uptime;
+mod wal_persister;
+mod wire_capture;
 
-use crate::bitcoind_client::BitcoindClient;
+use crate::accounting::ForwardLog;
+use crate::bitcoind_client::{BitcoindClient, BlockNotifications, Target};
+use crate::bundles::{BundleStorage, BundleStore};
+use crate::channel_acceptor::ChannelAcceptor;
+use crate::channel_backup::{ChannelKeysLog, ChannelRecovery};
+use crate::channel_history::{ChannelEventKind, ChannelHistory};
+use crate::channel_rotation::ChannelRotation;
+use crate::close_address::ShutdownKeysManager;
+use crate::compact_filters::WatchedScripts;
+use crate::connection_guard::ConnectionGuard;
 use crate::disk::FilesystemLogger;
+use crate::disk_space::DiskMonitor;
+use crate::esplora::EsploraClient;
+use crate::event_stream::EventStream;
+use crate::external_funding::{ExternalFunding, ExternalFundingStorage};
+use crate::fee_budget::FeeBudgetRouter;
+use crate::fee_snapshots::{BroadcastPurpose, FeeSnapshotLog};
+use crate::forwarding::HtlcTracker;
+use crate::hold_invoices::HoldInvoices;
+use crate::notifier::{Notifier, SettlementWebhook};
+use crate::onchain_wallet::OnchainWallet;
+use crate::open_queue::OpenQueue;
+use crate::path_privacy::PathPadder;
+use crate::pause::PauseSwitch;
+use crate::payer::RouteRecordingPayer;
+use crate::payer_auth::SeenProofs;
+use crate::peer_limits::{PeerLimitsStorage, PeerLimitsStore};
+use crate::route_exclusions::{RouteExclusions, RouteExclusionsStorage};
+use crate::scheduler::{ScheduleStorage, ScheduleStore};
+use crate::storage::Storage;
+use crate::sweep_wallet::SweepWallet;
+use crate::sweeper::OutputSweeper;
+use crate::uptime::PeerUptimeStorage;
+use crate::wal_persister::WalPersister;
+use crate::wire_capture::WireCapture;
+use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::blockdata::constants::genesis_block;
-use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
 use bitcoin::consensus::encode;
 use bitcoin::network::constants::Network;
-use bitcoin::secp256k1::Secp256k1;
 use bitcoin::BlockHash;
 use bitcoin_bech32::WitnessProgram;
 use lightning::chain;
-use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use lightning::chain::chaininterface::{ConfirmationTarget, FeeEstimator};
 use lightning::chain::chainmonitor;
 use lightning::chain::keysinterface::{InMemorySigner, KeysInterface, KeysManager};
-use lightning::chain::{BestBlock, Filter, Watch};
+use lightning::chain::{BestBlock, Filter, Listen, Watch};
 use lightning::ln::channelmanager;
-use lightning::ln::channelmanager::{
-	ChainParameters, ChannelManagerReadArgs, SimpleArcChannelManager,
-};
-use lightning::ln::peer_handler::{IgnoringMessageHandler, MessageHandler, SimpleArcPeerManager};
+use lightning::ln::channelmanager::{ChainParameters, ChannelManagerReadArgs};
+use lightning::ln::peer_handler;
+use lightning::ln::peer_handler::{IgnoringMessageHandler, MessageHandler};
 use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
 use lightning::routing::network_graph::{NetGraphMsgHandler, NetworkGraph};
+use lightning::routing::router::Route;
 use lightning::routing::scorer::Scorer;
-use lightning::util::config::UserConfig;
-use lightning::util::events::{Event, PaymentPurpose};
-use lightning::util::ser::ReadableArgs;
+use lightning::util::events::{Event, EventHandler, PaymentPurpose};
+use lightning::util::ser::{ReadableArgs, Writeable};
 use lightning_background_processor::BackgroundProcessor;
 use lightning_block_sync::init;
 use lightning_block_sync::poll;
+use lightning_block_sync::BlockSource;
 use lightning_block_sync::SpvClient;
-use lightning_block_sync::UnboundedCache;
 use lightning_invoice::payment;
-use lightning_invoice::utils::DefaultRouter;
 use lightning_net_tokio::SocketDescriptor;
 use lightning_persister::FilesystemPersister;
 use rand::{thread_rng, Rng};
+use serde_json::json;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::Write;
-use std::ops::Deref;
+use std::io::{Cursor, Write};
+use std::ops::{Deref, DerefMut};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
 
 pub(crate) enum HTLCStatus {
 	Pending,
 	Succeeded,
 	Failed,
+	/// We gave up waiting for an outbound payment, but parts of it are still in flight and may yet
+	/// succeed. It's only failed once they've all failed.
+	TimedOut,
 }
 
 pub(crate) struct MillisatAmount(Option<u64>);
@@ -73,9 +167,70 @@ pub(crate) struct PaymentInfo {
 	secret: Option<PaymentSecret>,
 	status: HTLCStatus,
 	amt_msat: MillisatAmount,
+	/// When we first learned of the payment, in seconds since the UNIX epoch.
+	created_at: u64,
+	/// When the payment's status last changed, in seconds since the UNIX epoch.
+	updated_at: u64,
+	/// When the invoice for this payment expires, in seconds since the UNIX epoch, if known.
+	expires_at: Option<u64>,
+	/// The paths an outbound payment took, once it has succeeded.
+	route: Option<Route>,
+	/// Why an outbound payment's most recent path failed, if it did.
+	failure: Option<String>,
+	/// How many parts of an outbound payment failed along the way, including any which were
+	/// retried over other paths.
+	failed_parts: u32,
+	/// The routing fees an outbound payment paid, once it has succeeded.
+	fee_paid_msat: Option<u64>,
+	/// The BOLT 11 invoice the payment was made to, if there was one.
+	invoice: Option<String>,
+	/// For an outbound payment refunding an inbound one, the inbound payment's hash.
+	refund_of: Option<PaymentHash>,
+	/// For an invoice re-issued in place of one which expired unpaid, the expired invoice's hash.
+	reissue_of: Option<PaymentHash>,
 }
 
-pub(crate) type PaymentInfoStorage = Arc<Mutex<HashMap<PaymentHash, PaymentInfo>>>;
+pub(crate) fn time_now_secs() -> u64 {
+	SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Payment information which is written to storage whenever `persist` is called, so that payment
+/// history survives restarts.
+pub(crate) struct PaymentInfoStore {
+	storage: Arc<dyn Storage>,
+	key: &'static str,
+	payments: HashMap<PaymentHash, PaymentInfo>,
+}
+
+impl PaymentInfoStore {
+	fn new(storage: Arc<dyn Storage>, key: &'static str) -> Self {
+		let payments = disk::read_payment_info(&*storage, key);
+		Self { storage, key, payments }
+	}
+
+	pub(crate) fn persist(&self) {
+		if disk::persist_payment_info(&*self.storage, self.key, &self.payments).is_err() {
+			// Persistence errors here are non-fatal as LDK itself doesn't rely on this data, but
+			// they may indicate a disk error which could be fatal elsewhere.
+			eprintln!("Warning: Failed to persist payment info, check your disk and permissions");
+		}
+	}
+}
+
+impl Deref for PaymentInfoStore {
+	type Target = HashMap<PaymentHash, PaymentInfo>;
+	fn deref(&self) -> &Self::Target {
+		&self.payments
+	}
+}
+
+impl DerefMut for PaymentInfoStore {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.payments
+	}
+}
+
+pub(crate) type PaymentInfoStorage = Arc<Mutex<PaymentInfoStore>>;
 
 type ChainMonitor = chainmonitor::ChainMonitor<
 	InMemorySigner,
@@ -83,43 +238,107 @@ type ChainMonitor = chainmonitor::ChainMonitor<
 	Arc<BitcoindClient>,
 	Arc<BitcoindClient>,
 	Arc<FilesystemLogger>,
-	Arc<FilesystemPersister>,
+	Arc<WalPersister>,
 >;
 
-pub(crate) type PeerManager = SimpleArcPeerManager<
+pub(crate) type PeerManager = peer_handler::PeerManager<
 	SocketDescriptor,
-	ChainMonitor,
-	BitcoindClient,
-	BitcoindClient,
-	dyn chain::Access + Send + Sync,
-	FilesystemLogger,
+	Arc<ChannelAcceptor>,
+	Arc<NetGraphMsgHandler<Arc<dyn chain::Access + Send + Sync>, Arc<FilesystemLogger>>>,
+	Arc<FilesystemLogger>,
+	Arc<IgnoringMessageHandler>,
 >;
 
-pub(crate) type ChannelManager =
-	SimpleArcChannelManager<ChainMonitor, BitcoindClient, BitcoindClient, FilesystemLogger>;
+pub(crate) type ChannelManager = channelmanager::ChannelManager<
+	InMemorySigner,
+	Arc<ChainMonitor>,
+	Arc<BitcoindClient>,
+	Arc<ShutdownKeysManager>,
+	Arc<BitcoindClient>,
+	Arc<FilesystemLogger>,
+>;
 
 pub(crate) type InvoicePayer<E> = payment::InvoicePayer<
-	Arc<ChannelManager>,
+	Arc<RouteRecordingPayer>,
 	Router,
 	Arc<Mutex<Scorer>>,
 	Arc<FilesystemLogger>,
 	E,
 >;
 
-type Router = DefaultRouter<Arc<NetworkGraph>, Arc<FilesystemLogger>>;
+type Router = FeeBudgetRouter;
+
+/// Passes the blocks we sync on to the chain monitor and channel manager, and to our internal
+/// wallet if we have one.
+struct ChainListener {
+	chain_monitor: Arc<ChainMonitor>,
+	channel_manager: Arc<ChannelManager>,
+	onchain_wallet: Option<Arc<OnchainWallet>>,
+	recovery: Option<Arc<ChannelRecovery>>,
+}
+
+impl Listen for ChainListener {
+	fn block_connected(&self, block: &Block, height: u32) {
+		self.chain_monitor.block_connected(block, height);
+		self.channel_manager.block_connected(block, height);
+		if let Some(wallet) = &self.onchain_wallet {
+			wallet.block_connected(block, height);
+		}
+		if let Some(recovery) = &self.recovery {
+			recovery.block_connected(block, height);
+		}
+	}
+
+	fn block_disconnected(&self, header: &BlockHeader, height: u32) {
+		self.chain_monitor.block_disconnected(header, height);
+		self.channel_manager.block_disconnected(header, height);
+		if let Some(wallet) = &self.onchain_wallet {
+			wallet.block_disconnected(header, height);
+		}
+	}
+}
+
+async fn fund_and_sign_transaction(
+	bitcoind_client: &BitcoindClient, outputs: Vec<HashMap<String, f64>>, funding_output: TxOut,
+) -> io::Result<Transaction> {
+	if let Some(wallet) = bitcoind_client.onchain_wallet() {
+		let feerate = bitcoind_client.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
+		return wallet
+			.create_transaction(
+				vec![funding_output],
+				feerate,
+				bitcoind_client.fund_from_confirmed_only(),
+			)
+			.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+	}
+	let raw_tx = bitcoind_client.create_raw_transaction(outputs).await?;
+	let funded_tx = bitcoind_client.fund_raw_transaction(raw_tx).await?;
+	let signed_tx = bitcoind_client.sign_raw_transaction_with_wallet(funded_tx.hex).await?;
+	assert_eq!(signed_tx.complete, true);
+	Ok(encode::deserialize(&hex_utils::to_vec(&signed_tx.hex).unwrap()).unwrap())
+}
 
 async fn handle_ldk_events(
 	channel_manager: Arc<ChannelManager>, bitcoind_client: Arc<BitcoindClient>,
-	keys_manager: Arc<KeysManager>, inbound_payments: PaymentInfoStorage,
-	outbound_payments: PaymentInfoStorage, network: Network, event: &Event,
+	output_sweeper: Arc<OutputSweeper>, payer: Arc<RouteRecordingPayer>,
+	inbound_payments: PaymentInfoStorage, outbound_payments: PaymentInfoStorage, network: Network,
+	payer_secret: Option<[u8; 32]>, seen_proofs: Arc<SeenProofs>, pause_switch: Arc<PauseSwitch>,
+	peer_limits: PeerLimitsStorage, external_funding: ExternalFundingStorage,
+	settlement_webhook: Option<Arc<SettlementWebhook>>, hold_invoices: Arc<HoldInvoices>,
+	notifier: Option<Arc<Notifier>>, event_stream: Arc<EventStream>,
+	channel_history: Arc<ChannelHistory>, forward_log: Arc<ForwardLog>,
+	htlc_tracker: Arc<HtlcTracker>, min_claim_cltv_blocks: u32, event: &Event,
 ) {
+	event_stream.ldk_event(event);
 	match event {
 		Event::FundingGenerationReady {
 			temporary_channel_id,
 			channel_value_satoshis,
 			output_script,
-			..
+			user_channel_id,
 		} => {
+			peer_limits.lock().unwrap().open_accepted(*user_channel_id);
+
 			// Construct the raw transaction with one output, that is paid the amount of the
 			// channel.
 			let addr = WitnessProgram::from_scriptpubkey(
@@ -128,29 +347,83 @@ async fn handle_ldk_events(
 					Network::Bitcoin => bitcoin_bech32::constants::Network::Bitcoin,
 					Network::Testnet => bitcoin_bech32::constants::Network::Testnet,
 					Network::Regtest => bitcoin_bech32::constants::Network::Regtest,
-					Network::Signet => panic!("Signet unsupported"),
+					Network::Signet => bitcoin_bech32::constants::Network::Signet,
 				},
 			)
 			.expect("Lightning funding tx should always be to a SegWit output")
 			.to_address();
+			let funding_output =
+				TxOut { value: *channel_value_satoshis, script_pubkey: output_script.clone() };
+
+			// For opens funded from elsewhere we only need to tell the user what to pay.
+			let mut funding = external_funding.lock().unwrap();
+			if funding.take_request(*user_channel_id) {
+				funding.await_funding(*temporary_channel_id, funding_output);
+				let channel_id = hex_utils::hex_str(temporary_channel_id);
+				println!(
+					"\nEVENT: Channel {} is ready to be funded. Sign a transaction paying exactly {} sat to {}, but don't broadcast it, then run `fundchannel {} <signed_psbt_or_raw_tx>`",
+					channel_id, channel_value_satoshis, addr, channel_id
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				return;
+			}
+			drop(funding);
 			let mut outputs = vec![HashMap::with_capacity(1)];
 			outputs[0].insert(addr, *channel_value_satoshis as f64 / 100_000_000.0);
-			let raw_tx = bitcoind_client.create_raw_transaction(outputs).await;
 
+			// A watch-only wallet can't sign, so hand the user a PSBT to sign elsewhere. They
+			// give the signed PSBT back to us with `fundchannel`.
+			if bitcoind_client.funding_wallet_watch_only() {
+				match bitcoind_client.wallet_create_funded_psbt(outputs).await {
+					Ok(psbt) => {
+						external_funding
+							.lock()
+							.unwrap()
+							.await_funding(*temporary_channel_id, funding_output);
+						let channel_id = hex_utils::hex_str(temporary_channel_id);
+						println!(
+							"\nEVENT: Channel {} needs its funding transaction signed externally. Sign this PSBT, then run `fundchannel {} <signed_psbt>`:\n{}",
+							channel_id, channel_id, psbt.0
+						);
+					}
+					Err(e) => {
+						println!("\nERROR: Failed to create a funding PSBT: {}", e);
+						let _ = channel_manager.force_close_channel(temporary_channel_id);
+					}
+				}
+				print!("> ");
+				io::stdout().flush().unwrap();
+				return;
+			}
 			// Have your wallet put the inputs into the transaction such that the output is
-			// satisfied.
-			let funded_tx = bitcoind_client.fund_raw_transaction(raw_tx).await;
-
-			// Sign the final funding transaction and broadcast it.
-			let signed_tx = bitcoind_client.sign_raw_transaction_with_wallet(funded_tx.hex).await;
-			assert_eq!(signed_tx.complete, true);
-			let final_tx: Transaction =
-				encode::deserialize(&hex_utils::to_vec(&signed_tx.hex).unwrap()).unwrap();
+			// satisfied, then sign it.
+			let final_tx =
+				match fund_and_sign_transaction(&bitcoind_client, outputs, funding_output).await {
+					Ok(final_tx) => final_tx,
+					Err(e) => {
+						println!("\nERROR: Failed to fund channel: {}", e);
+						let _ = channel_manager.force_close_channel(temporary_channel_id);
+						print!("> ");
+						io::stdout().flush().unwrap();
+						return;
+					}
+				};
+			// LDK broadcasts the funding transaction once the peer signed for it.
+			let built_at = bitcoind_client.onchain_wallet().map(|_| Target::Normal);
+			bitcoind_client.fee_snapshots().expect(
+				final_tx.txid(),
+				BroadcastPurpose::Funding,
+				built_at,
+			);
 			// Give the funding transaction back to LDK for opening the channel.
 			if channel_manager
-				.funding_transaction_generated(&temporary_channel_id, final_tx)
+				.funding_transaction_generated(&temporary_channel_id, final_tx.clone())
 				.is_err()
 			{
+				if let Some(wallet) = bitcoind_client.onchain_wallet() {
+					wallet.cancel_transaction(&final_tx);
+				}
 				println!(
 					"\nERROR: Channel went away before we could fund it. The peer disconnected or refused the channel.");
 				print!("> ");
@@ -165,23 +438,139 @@ async fn handle_ldk_events(
 				}
 				PaymentPurpose::SpontaneousPayment(preimage) => (Some(*preimage), None),
 			};
-			let status = match channel_manager.claim_funds(payment_preimage.unwrap()) {
-				true => {
-					println!(
-						"\nEVENT: received payment from payment hash {} of {} millisatoshis",
-						hex_utils::hex_str(&payment_hash.0),
-						amt
-					);
-					print!("> ");
-					io::stdout().flush().unwrap();
-					HTLCStatus::Succeeded
+			// In a closed group, keysend payers must prove they know the group's secret. Invoice
+			// payments are already limited to those we handed an invoice to.
+			let unauthenticated = match (purpose, payer_secret) {
+				(PaymentPurpose::SpontaneousPayment(preimage), Some(secret)) => {
+					!payer_auth::verify_keysend_preimage(&secret, preimage)
+				}
+				_ => false,
+			};
+			// Any node which forwarded a payment we claimed knows its proof, so each is only
+			// accepted once.
+			let replayed = match (purpose, payer_secret) {
+				(PaymentPurpose::SpontaneousPayment(preimage), Some(_)) => {
+					!unauthenticated && seen_proofs.contains(preimage)
+				}
+				_ => false,
+			};
+			// Recorded only once we claim, so that the payer may retry a payment we failed back.
+			let record_proof = || match (purpose, payer_secret) {
+				(PaymentPurpose::SpontaneousPayment(preimage), Some(_)) => {
+					seen_proofs.insert(preimage)
+				}
+				_ => Ok(()),
+			};
+			// LDK only times out inbound payments by block timestamps, which can lag by hours, so
+			// hold payers to the expiry we put in the invoice ourselves.
+			let expired = match purpose {
+				PaymentPurpose::InvoicePayment { .. } => payments
+					.get(payment_hash)
+					.and_then(|payment| payment.expires_at)
+					.map_or(false, |expires_at| expires_at < time_now_secs()),
+				PaymentPurpose::SpontaneousPayment(_) => false,
+			};
+			// Claiming an HTLC about to expire could leave us racing the payer on-chain for it.
+			let cur_height = channel_manager.current_best_block().height();
+			let expiring = htlc_tracker
+				.min_cltv_expiry(payment_hash)
+				.map_or(false, |expiry| expiry < cur_height + min_claim_cltv_blocks);
+			let status = if pause_switch.is_paused() {
+				channel_manager.fail_htlc_backwards(payment_hash);
+				println!(
+					"\nEVENT: failed back payment from payment hash {} of {} millisatoshis as the node is paused",
+					hex_utils::hex_str(&payment_hash.0),
+					amt
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				HTLCStatus::Failed
+			} else if unauthenticated {
+				channel_manager.fail_htlc_backwards(payment_hash);
+				println!(
+					"\nEVENT: failed back keysend payment from payment hash {} of {} millisatoshis from an unknown payer",
+					hex_utils::hex_str(&payment_hash.0),
+					amt
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				HTLCStatus::Failed
+			} else if replayed {
+				channel_manager.fail_htlc_backwards(payment_hash);
+				println!(
+					"\nEVENT: failed back keysend payment from payment hash {} of {} millisatoshis replaying a payer proof we already accepted",
+					hex_utils::hex_str(&payment_hash.0),
+					amt
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				HTLCStatus::Failed
+			} else if expired {
+				channel_manager.fail_htlc_backwards(payment_hash);
+				println!(
+					"\nEVENT: failed back payment from payment hash {} of {} millisatoshis as its invoice has expired",
+					hex_utils::hex_str(&payment_hash.0),
+					amt
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				HTLCStatus::Failed
+			} else if expiring {
+				channel_manager.fail_htlc_backwards(payment_hash);
+				println!(
+					"\nEVENT: failed back payment from payment hash {} of {} millisatoshis as it expires within {} blocks",
+					hex_utils::hex_str(&payment_hash.0),
+					amt,
+					min_claim_cltv_blocks
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				HTLCStatus::Failed
+			} else if let Some(cancel_at) = hold_invoices.hold(payment_hash) {
+				println!(
+					"\nEVENT: holding payment from payment hash {} of {} millisatoshis until it's settled or cancelled, or until {}",
+					hex_utils::hex_str(&payment_hash.0),
+					amt,
+					cancel_at
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				HTLCStatus::Pending
+			} else if let Err(e) = record_proof() {
+				channel_manager.fail_htlc_backwards(payment_hash);
+				println!(
+					"\nERROR: failed back keysend payment from payment hash {} of {} millisatoshis as its payer proof couldn't be recorded: {}",
+					hex_utils::hex_str(&payment_hash.0),
+					amt,
+					e
+				);
+				print!("> ");
+				io::stdout().flush().unwrap();
+				HTLCStatus::Failed
+			} else {
+				match channel_manager.claim_funds(payment_preimage.unwrap()) {
+					true => {
+						println!(
+							"\nEVENT: received payment from payment hash {} of {} millisatoshis",
+							hex_utils::hex_str(&payment_hash.0),
+							amt
+						);
+						print!("> ");
+						io::stdout().flush().unwrap();
+						HTLCStatus::Succeeded
+					}
+					_ => HTLCStatus::Failed,
 				}
-				_ => HTLCStatus::Failed,
+			};
+			let settled = match status {
+				HTLCStatus::Succeeded => true,
+				_ => false,
 			};
 			match payments.entry(*payment_hash) {
 				Entry::Occupied(mut e) => {
 					let payment = e.get_mut();
 					payment.status = status;
+					payment.updated_at = time_now_secs();
 					payment.preimage = payment_preimage;
 					payment.secret = payment_secret;
 				}
@@ -191,20 +580,60 @@ async fn handle_ldk_events(
 						secret: payment_secret,
 						status,
 						amt_msat: MillisatAmount(Some(*amt)),
+						created_at: time_now_secs(),
+						updated_at: time_now_secs(),
+						expires_at: None,
+						route: None,
+						failure: None,
+						failed_parts: 0,
+						fee_paid_msat: None,
+						invoice: None,
+						refund_of: None,
+						reissue_of: None,
 					});
 				}
 			}
+			payments.persist();
+			drop(payments);
+			if let Some(webhook) = settlement_webhook.filter(|_| settled) {
+				webhook.settled(*payment_hash, *amt, &inbound_payments);
+			}
+			if let Some(notifier) = notifier.filter(|_| settled) {
+				notifier.notify(
+					"payment_received",
+					json!({ "payment_hash": hex_utils::hex_str(&payment_hash.0), "amount_msat": amt }),
+				);
+			}
 		}
-		Event::PaymentSent { payment_preimage, payment_hash, .. } => {
+		Event::PaymentSent { payment_preimage, payment_hash, fee_paid_msat, .. } => {
 			let mut payments = outbound_payments.lock().unwrap();
 			for (hash, payment) in payments.iter_mut() {
 				if *hash == *payment_hash {
+					if let Some(notifier) = &notifier {
+						notifier.notify(
+							"payment_sent",
+							json!({
+								"payment_hash": hex_utils::hex_str(&payment_hash.0),
+								"amount_msat": payment.amt_msat.0,
+								"fee_paid_msat": fee_paid_msat,
+							}),
+						);
+					}
 					payment.preimage = Some(*payment_preimage);
 					payment.status = HTLCStatus::Succeeded;
+					payment.updated_at = time_now_secs();
+					payment.fee_paid_msat = *fee_paid_msat;
+					if let Some(route) = payer.take_route(payment_hash) {
+						payment.route = Some(route);
+					}
 					println!(
-						"\nEVENT: successfully sent payment of {} millisatoshis from \
+						"\nEVENT: successfully sent payment of {} millisatoshis{} from \
 								 payment hash {:?} with preimage {:?}",
 						payment.amt_msat,
+						match fee_paid_msat {
+							Some(fee) => format!(" (paying {} millisatoshis in fees)", fee),
+							None => "".to_string(),
+						},
 						hex_utils::hex_str(&payment_hash.0),
 						hex_utils::hex_str(&payment_preimage.0)
 					);
@@ -212,34 +641,61 @@ async fn handle_ldk_events(
 					io::stdout().flush().unwrap();
 				}
 			}
+			payments.persist();
 		}
 		Event::PaymentPathFailed {
 			payment_hash,
 			rejected_by_dest,
+			network_update,
 			all_paths_failed,
+			path,
 			short_channel_id,
 			..
 		} => {
+			let failure = diagnostics::describe_path_failure(
+				*rejected_by_dest,
+				network_update,
+				*short_channel_id,
+				path,
+			);
 			print!(
 				"\nEVENT: Failed to send payment{} to payment hash {:?}",
 				if *all_paths_failed { "" } else { " along MPP path" },
 				hex_utils::hex_str(&payment_hash.0)
 			);
-			if let Some(scid) = short_channel_id {
-				print!(" because of failure at channel {}", scid);
-			}
 			if *rejected_by_dest {
 				println!(": re-attempting the payment will not succeed");
 			} else {
 				println!(": exhausted payment retry attempts");
 			}
+			println!("\tdiagnosis: {}", failure);
 			print!("> ");
 			io::stdout().flush().unwrap();
 
+			if *all_paths_failed {
+				payer.payment_failed(payment_hash);
+				if let Some(notifier) = &notifier {
+					notifier.notify(
+						"payment_failed",
+						json!({
+							"payment_hash": hex_utils::hex_str(&payment_hash.0),
+							"rejected_by_dest": rejected_by_dest,
+							"diagnosis": failure,
+						}),
+					);
+				}
+			}
+
 			let mut payments = outbound_payments.lock().unwrap();
 			if payments.contains_key(&payment_hash) {
 				let payment = payments.get_mut(&payment_hash).unwrap();
-				payment.status = HTLCStatus::Failed;
+				// Other parts of a multi-path payment may still succeed.
+				if *all_paths_failed {
+					payment.status = HTLCStatus::Failed;
+				}
+				payment.failure = Some(failure);
+				payment.updated_at = time_now_secs();
+				payments.persist();
 			}
 		}
 		Event::PaymentForwarded { fee_earned_msat, claim_from_onchain_tx } => {
@@ -249,6 +705,7 @@ async fn handle_ldk_events(
 				"from HTLC fulfill message"
 			};
 			if let Some(fee_earned) = fee_earned_msat {
+				forward_log.record(*fee_earned, *claim_from_onchain_tx);
 				println!(
 					"\nEVENT: Forwarded payment, earning {} msat {}",
 					fee_earned, from_onchain_str
@@ -265,26 +722,26 @@ async fn handle_ldk_events(
 			tokio::spawn(async move {
 				let millis_to_sleep = thread_rng().gen_range(min, min * 5) as u64;
 				tokio::time::sleep(Duration::from_millis(millis_to_sleep)).await;
-				forwarding_channel_manager.process_pending_htlc_forwards();
+				// LDK 0.0.103 can't fail pending HTLCs back without processing them, so while
+				// paused or in a maintenance window we hold them until we're released.
+				if !pause_switch.holds_forwards() {
+					forwarding_channel_manager.process_pending_htlc_forwards();
+				}
 			});
 		}
 		Event::SpendableOutputs { outputs } => {
-			let destination_address = bitcoind_client.get_new_address().await;
-			let output_descriptors = &outputs.iter().map(|a| a).collect::<Vec<_>>();
-			let tx_feerate =
-				bitcoind_client.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
-			let spending_tx = keys_manager
-				.spend_spendable_outputs(
-					output_descriptors,
-					Vec::new(),
-					destination_address.script_pubkey(),
-					tx_feerate,
-					&Secp256k1::new(),
-				)
-				.unwrap();
-			bitcoind_client.broadcast_transaction(&spending_tx);
-		}
-		Event::ChannelClosed { channel_id, reason, user_channel_id: _ } => {
+			// Persist the outputs before trying to sweep them, so that a failed sweep is retried
+			// rather than forgotten.
+			output_sweeper.track_outputs(outputs);
+			OutputSweeper::outputs_added(&output_sweeper).await;
+		}
+		Event::ChannelClosed { channel_id, reason, user_channel_id } => {
+			peer_limits.lock().unwrap().channel_closed(*user_channel_id, format!("{:?}", reason));
+			external_funding.lock().unwrap().channel_done(channel_id, *user_channel_id);
+			channel_history.record(*channel_id, ChannelEventKind::Closed, format!("{:?}", reason));
+			if let Some(notifier) = &notifier {
+				notifier.channel_closed(channel_id, reason);
+			}
 			println!(
 				"\nEVENT: Channel {} closed due to: {:?}",
 				hex_utils::hex_str(channel_id),
@@ -293,29 +750,70 @@ async fn handle_ldk_events(
 			print!("> ");
 			io::stdout().flush().unwrap();
 		}
-		Event::DiscardFunding { .. } => {
+		Event::DiscardFunding { transaction, .. } => {
 			// A "real" node should probably "lock" the UTXOs spent in funding transactions until
-			// the funding transaction either confirms, or this event is generated.
+			// the funding transaction either confirms, or this event is generated. Our internal
+			// wallet does, so it can spend them again now.
+			if let Some(wallet) = bitcoind_client.onchain_wallet() {
+				wallet.cancel_transaction(transaction);
+			}
 		}
 	}
 }
 
 async fn start_ldk() {
+	if let Some(storage_dir) = cli::parse_observe_arg() {
+		observer::observe(storage_dir);
+		return;
+	}
+
 	let args = match cli::parse_startup_args() {
 		Ok(user_args) => user_args,
 		Err(()) => return,
 	};
+	let mut activated_sockets = systemd::take_activated_sockets();
 
 	// Initialize the LDK data directory if necessary.
 	let ldk_data_dir = format!("{}/.ldk", args.ldk_storage_dir_path);
 	fs::create_dir_all(ldk_data_dir.clone()).unwrap();
 
+	if let Err(e) =
+		disk::check_data_dir_network(&ldk_data_dir, args.network, args.allow_mainnet_switch)
+	{
+		println!("ERROR: {}", e);
+		return;
+	}
+
+	// Snapshot our existing state before a new version of the node gets a chance to migrate it.
+	if let Err(e) = disk::backup_data_dir_on_upgrade(&ldk_data_dir) {
+		println!("ERROR: Failed to back up data directory before upgrading: {}", e);
+		return;
+	}
+
+	// Open wherever the node keeps its state, moving it into SQLite if it's switching over.
+	let storage = match storage::open_storage(&ldk_data_dir, args.storage_backend) {
+		Ok(storage) => storage,
+		Err(e) => {
+			println!("ERROR: {}", e);
+			return;
+		}
+	};
+
 	// Initialize our bitcoind client.
+	let channel_history =
+		Arc::new(ChannelHistory::new(format!("{}/channel_history", ldk_data_dir.clone())));
 	let bitcoind_client = match BitcoindClient::new(
 		args.bitcoind_rpc_host.clone(),
 		args.bitcoind_rpc_port,
-		args.bitcoind_rpc_username.clone(),
-		args.bitcoind_rpc_password.clone(),
+		args.bitcoind_rpc_auth.clone(),
+		args.bitcoind_rpc_transport.clone(),
+		args.funding_wallet.clone(),
+		args.operating_wallet.clone(),
+		args.fund_from_confirmed_only,
+		args.fee_floors,
+		args.esplora_url.as_ref().map(|url| EsploraClient::new(url).unwrap()),
+		channel_history.clone(),
+		FeeSnapshotLog::new(format!("{}/fee_snapshots", ldk_data_dir.clone())),
 		tokio::runtime::Handle::current(),
 	)
 	.await
@@ -328,7 +826,19 @@ async fn start_ldk() {
 	};
 
 	// Check that the bitcoind we've connected to is running the network we expect
-	let bitcoind_chain = bitcoind_client.get_blockchain_info().await.chain;
+	let (bitcoind_chain, bitcoind_prune_height) = match bitcoind_client.get_blockchain_info().await
+	{
+		Ok(info) => (info.chain, info.prune_height),
+		Err(e) => {
+			println!("Failed to get blockchain info from bitcoind: {}", e);
+			return;
+		}
+	};
+	// Blocks only come from bitcoind if we aren't using Esplora or compact block filters.
+	let bitcoind_prune_height = match (&args.esplora_url, args.filter_peer_addr) {
+		(None, None) => bitcoind_prune_height,
+		_ => None,
+	};
 	if bitcoind_chain
 		!= match args.network {
 			bitcoin::Network::Bitcoin => "main",
@@ -350,7 +860,12 @@ async fn start_ldk() {
 	let fee_estimator = bitcoind_client.clone();
 
 	// Step 2: Initialize the Logger
-	let logger = Arc::new(FilesystemLogger::new(ldk_data_dir.clone()));
+	let logger = Arc::new(FilesystemLogger::new(
+		ldk_data_dir.clone(),
+		args.log_level,
+		args.log_format,
+		args.log_rotation,
+	));
 
 	// Step 3: Initialize the BroadcasterInterface
 
@@ -359,15 +874,43 @@ async fn start_ldk() {
 	let broadcaster = bitcoind_client.clone();
 
 	// Step 4: Initialize Persist
+	let pause_switch = Arc::new(PauseSwitch::new(format!("{}/paused", ldk_data_dir.clone())));
+	if pause_switch.is_paused() {
+		eprintln!("Warning: The node is paused, use `resume` to send and receive payments again");
+	}
+	// If the disk fills up or starts failing writes, stop taking on new HTLCs rather than risk
+	// channel updates we can't persist.
+	let disk_monitor = Arc::new(DiskMonitor::new(ldk_data_dir.clone(), pause_switch.clone()));
 	let persister = Arc::new(FilesystemPersister::new(ldk_data_dir.clone()));
+	// Monitor updates are appended to a write-ahead log which is synced in batches, rather than
+	// rewriting the whole monitor on every update, and copied to any replicas.
+	let monitor_persister = match WalPersister::new(
+		persister.clone(),
+		&ldk_data_dir,
+		disk_monitor.clone(),
+		args.monitor_replicas,
+	) {
+		Ok(p) => Arc::new(p),
+		Err(e) => {
+			println!("ERROR: Unable to open channel monitor WAL: {}", e);
+			return;
+		}
+	};
 
 	// Step 5: Initialize the ChainMonitor
+	// When syncing with compact block filters, the ChainMonitor tells us which scripts to match
+	// blocks' filters against. With a pruned bitcoind, it tells us which transactions and outputs to
+	// look up if we need to catch up past blocks bitcoind no longer has.
+	let watched_scripts = match (args.filter_peer_addr, bitcoind_prune_height) {
+		(None, None) => None,
+		_ => Some(Arc::new(WatchedScripts::new())),
+	};
 	let chain_monitor: Arc<ChainMonitor> = Arc::new(chainmonitor::ChainMonitor::new(
-		None,
+		watched_scripts.clone().map(|w| w as Arc<dyn Filter + Send + Sync>),
 		broadcaster.clone(),
 		logger.clone(),
 		fee_estimator.clone(),
-		persister.clone(),
+		monitor_persister.clone(),
 	));
 
 	// Step 6: Initialize the KeysManager
@@ -375,7 +918,19 @@ async fn start_ldk() {
 	// The key seed that we use to derive the node privkey (that corresponds to the node pubkey) and
 	// other secret key material.
 	let keys_seed_path = format!("{}/keys_seed", ldk_data_dir.clone());
-	let keys_seed = if let Ok(seed) = fs::read(keys_seed_path.clone()) {
+	// If the seed is encrypted, it's unlocked with a passphrase before anything uses it.
+	let encrypted_seed_path = format!("{}/keys_seed.enc", ldk_data_dir.clone());
+	let keys_seed = if Path::new(&encrypted_seed_path).exists() {
+		match seed_encryption::read_passphrase(args.password_file.as_deref(), false)
+			.and_then(|passphrase| seed_encryption::unlock_seed(&encrypted_seed_path, &passphrase))
+		{
+			Ok(seed) => seed,
+			Err(e) => {
+				println!("ERROR: Failed to unlock the keys seed: {}", e);
+				return;
+			}
+		}
+	} else if let Ok(seed) = fs::read(keys_seed_path.clone()) {
 		assert_eq!(seed.len(), 32);
 		let mut key = [0; 32];
 		key.copy_from_slice(&seed);
@@ -383,36 +938,127 @@ async fn start_ldk() {
 	} else {
 		let mut key = [0; 32];
 		thread_rng().fill_bytes(&mut key);
-		match File::create(keys_seed_path.clone()) {
-			Ok(mut f) => {
-				f.write_all(&key).expect("Failed to write node keys seed to disk");
-				f.sync_all().expect("Failed to sync node keys seed to disk");
+		if !args.encrypt_seed {
+			match File::create(keys_seed_path.clone()) {
+				Ok(mut f) => {
+					f.write_all(&key).expect("Failed to write node keys seed to disk");
+					f.sync_all().expect("Failed to sync node keys seed to disk");
+				}
+				Err(e) => {
+					println!("ERROR: Unable to create keys seed file {}: {}", keys_seed_path, e);
+					return;
+				}
 			}
+		}
+		key
+	};
+	// Encrypt a new seed, or one which was stored in the clear until now, removing the plaintext
+	// once the encrypted copy is safely on disk.
+	if args.encrypt_seed && !Path::new(&encrypted_seed_path).exists() {
+		let passphrase = match seed_encryption::read_passphrase(args.password_file.as_deref(), true)
+		{
+			Ok(passphrase) => passphrase,
 			Err(e) => {
-				println!("ERROR: Unable to create keys seed file {}: {}", keys_seed_path, e);
+				println!("ERROR: Failed to encrypt the keys seed: {}", e);
 				return;
 			}
+		};
+		if let Err(e) =
+			seed_encryption::write_encrypted_seed(&encrypted_seed_path, &keys_seed, &passphrase)
+		{
+			println!(
+				"ERROR: Unable to create encrypted keys seed file {}: {}",
+				encrypted_seed_path, e
+			);
+			return;
 		}
-		key
-	};
+		if Path::new(&keys_seed_path).exists() {
+			if let Err(e) = fs::remove_file(&keys_seed_path) {
+				println!(
+					"ERROR: Failed to remove the unencrypted keys seed {}: {}",
+					keys_seed_path, e
+				);
+				return;
+			}
+		}
+	}
+	// Backups taken before the seed was encrypted still hold it in the clear. This is checked on
+	// every start, in case we stopped part way through last time.
+	if Path::new(&encrypted_seed_path).exists() {
+		if let Err(e) = disk::encrypt_seed_in_backups(&ldk_data_dir) {
+			println!("ERROR: Failed to remove the unencrypted keys seed from backups: {}", e);
+			return;
+		}
+	}
 	let cur = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
 	let keys_manager = Arc::new(KeysManager::new(&keys_seed, cur.as_secs(), cur.subsec_nanos()));
+	// A backup to recover from is read up front, so that a wrong file fails before anything else.
+	let recovery_backup = match &args.recover_backup {
+		Some(_) if args.filter_peer_addr.is_some() => {
+			println!("ERROR: --recover doesn't work with the filters chain backend");
+			return;
+		}
+		Some(path) => match channel_backup::read_backup(path, &keys_manager.get_node_secret()) {
+			Ok(backup) => Some(backup),
+			Err(e) => {
+				println!("ERROR: Failed to read the channel backup: {}", e);
+				return;
+			}
+		},
+		None => None,
+	};
+	let sweep_wallet = match (&args.sweep_xpub, &args.sweep_address) {
+		(Some(xpub), _) => {
+			let index_path = format!("{}/sweep_wallet_index", ldk_data_dir.clone());
+			Some(SweepWallet::new(xpub, args.network, index_path))
+		}
+		(None, Some(address)) => Some(SweepWallet::from_address(address, args.network)),
+		(None, None) => None,
+	};
+	let sweep_wallet = match sweep_wallet {
+		Some(Ok(sweep_wallet)) => Some(Arc::new(sweep_wallet)),
+		Some(Err(e)) => {
+			println!("ERROR: {}", e);
+			return;
+		}
+		None => None,
+	};
+	// The ChannelManager gets our keys through a wrapper which lets us pick where a channel's
+	// cooperative close pays to.
+	let shutdown_keys = Arc::new(ShutdownKeysManager::new(
+		keys_manager.clone(),
+		sweep_wallet.clone(),
+		ChannelKeysLog::new(format!("{}/channel_keys", ldk_data_dir.clone())),
+	));
 
 	// Step 7: Read ChannelMonitor state from disk
 	let mut channelmonitors = persister.read_channelmonitors(keys_manager.clone()).unwrap();
+	if let Err(e) =
+		monitor_persister.replay_wal(&mut channelmonitors, &broadcaster, &fee_estimator, &logger)
+	{
+		println!("ERROR: Failed to restore channel monitors: {}", e);
+		return;
+	}
+	monitor_persister.note_update_ids(&channelmonitors);
 
 	// Step 8: Initialize the ChannelManager
-	let mut user_config = UserConfig::default();
-	user_config.peer_channel_config_limits.force_announced_channel_preference = false;
+	let user_config = args.user_config;
 	let mut restarting_node = true;
 	let (channel_manager_blockhash, mut channel_manager) = {
-		if let Ok(mut f) = fs::File::open(format!("{}/manager", ldk_data_dir.clone())) {
+		let manager = match storage.read(storage::MANAGER) {
+			Ok(manager) => manager,
+			Err(e) => {
+				println!("ERROR: Failed to read the ChannelManager: {}", e);
+				return;
+			}
+		};
+		if let Some(manager) = manager {
 			let mut channel_monitor_mut_references = Vec::new();
 			for (_, channel_monitor) in channelmonitors.iter_mut() {
 				channel_monitor_mut_references.push(channel_monitor);
 			}
 			let read_args = ChannelManagerReadArgs::new(
-				keys_manager.clone(),
+				shutdown_keys.clone(),
 				fee_estimator.clone(),
 				chain_monitor.clone(),
 				broadcaster.clone(),
@@ -420,37 +1066,130 @@ async fn start_ldk() {
 				user_config,
 				channel_monitor_mut_references,
 			);
-			<(BlockHash, ChannelManager)>::read(&mut f, read_args).unwrap()
+			<(BlockHash, ChannelManager)>::read(&mut Cursor::new(manager), read_args).unwrap()
 		} else {
 			// We're starting a fresh node.
 			restarting_node = false;
-			let getinfo_resp = bitcoind_client.get_blockchain_info().await;
+			let mut block_source = bitcoind_client.deref();
+			let (best_block_hash, best_block_height) = match block_source.get_best_block().await {
+				Ok((block_hash, Some(height))) => (block_hash, height),
+				res => {
+					println!(
+						"Failed to get the best block from {}: {:?}",
+						bitcoind_client.chain_source_name(),
+						res
+					);
+					return;
+				}
+			};
 
 			let chain_params = ChainParameters {
 				network: args.network,
-				best_block: BestBlock::new(
-					getinfo_resp.latest_blockhash,
-					getinfo_resp.latest_height as u32,
-				),
+				best_block: BestBlock::new(best_block_hash, best_block_height),
 			};
 			let fresh_channel_manager = channelmanager::ChannelManager::new(
 				fee_estimator.clone(),
 				chain_monitor.clone(),
 				broadcaster.clone(),
 				logger.clone(),
-				keys_manager.clone(),
+				shutdown_keys.clone(),
 				user_config,
 				chain_params,
 			);
-			(getinfo_resp.latest_blockhash, fresh_channel_manager)
+			(best_block_hash, fresh_channel_manager)
 		}
 	};
 
+	// Our own on-chain wallet, if we don't use bitcoind's, is synced along with them. A new one
+	// starts from where the channel manager is.
+	let mut onchain_wallet = None;
+	if args.internal_wallet {
+		match OnchainWallet::new(
+			&keys_seed,
+			args.network,
+			format!("{}/onchain_wallet", ldk_data_dir.clone()),
+			channel_manager.current_best_block(),
+		) {
+			Ok(wallet) => onchain_wallet = Some(wallet),
+			Err(e) => {
+				println!("ERROR: Failed to open the internal wallet: {}", e);
+				return;
+			}
+		}
+	}
+
 	// Step 9: Sync ChannelMonitors and ChannelManager to chain tip
 	let mut chain_listener_channel_monitors = Vec::new();
-	let mut cache = UnboundedCache::new();
+	let mut cache = memory::BoundedHeaderCache::new(args.header_cache_size);
+	let header_cache_len = cache.len_handle();
 	let mut chain_tip: Option<poll::ValidatedBlockHeader> = None;
-	if restarting_node {
+	let best_block_path = format!("{}/best_block", ldk_data_dir.clone());
+	// Compact block filter sync starts from whichever of the channel manager and monitors is
+	// furthest behind, once the monitors are watched and their scripts registered.
+	let mut filter_sync_start = channel_manager.current_best_block();
+	for (_, channel_monitor) in channelmonitors.iter() {
+		let monitor_best_block = channel_monitor.current_best_block();
+		if monitor_best_block.height() < filter_sync_start.height() {
+			filter_sync_start = monitor_best_block;
+		}
+	}
+	// If bitcoind has pruned blocks we'd need to sync block by block, we catch up on what LDK
+	// registered through the Filter interface instead, once the monitors are watched.
+	let pruned_gap = match bitcoind_prune_height {
+		Some(prune_height) => restarting_node && filter_sync_start.height() + 1 < prune_height,
+		None => false,
+	};
+	if restarting_node && args.filter_peer_addr.is_none() {
+		// Before syncing, make sure bitcoind is still on the chain we left off on, so that a
+		// reindexed or re-synced bitcoind is reported as such rather than failing obscurely.
+		let mut listener_blocks = vec![("channel manager".to_string(), channel_manager_blockhash)];
+		for (blockhash, channel_monitor) in channelmonitors.iter() {
+			let outpoint = channel_monitor.get_funding_txo().0;
+			listener_blocks.push((
+				format!("channel monitor for {}:{}", outpoint.txid, outpoint.index),
+				*blockhash,
+			));
+		}
+		if let Some(wallet) = &onchain_wallet {
+			listener_blocks.push(("internal wallet".to_string(), wallet.best_block_hash()));
+		}
+		let last_synced = best_block::read_best_block(Path::new(&best_block_path));
+		if let Err(e) =
+			best_block::verify_chain(bitcoind_client.deref(), last_synced, &listener_blocks).await
+		{
+			println!("ERROR: {}", e);
+			return;
+		}
+	}
+	if restarting_node && (args.filter_peer_addr.is_some() || pruned_gap) {
+		for (blockhash, channel_monitor) in channelmonitors.drain(..) {
+			let outpoint = channel_monitor.get_funding_txo().0;
+			chain_listener_channel_monitors.push((
+				blockhash,
+				(channel_monitor, broadcaster.clone(), fee_estimator.clone(), logger.clone()),
+				outpoint,
+			));
+		}
+		// Unlike the channel manager and monitors, the internal wallet can't catch up on what it
+		// registered through the Filter interface, it needs the blocks.
+		if let Some(wallet) = onchain_wallet.as_mut() {
+			let wallet_blockhash = wallet.best_block_hash();
+			if let Err(e) = init::synchronize_listeners(
+				&mut bitcoind_client.deref(),
+				args.network,
+				&mut cache,
+				vec![(wallet_blockhash, wallet as &mut dyn chain::Listen)],
+			)
+			.await
+			{
+				println!(
+					"ERROR: Failed to sync the internal wallet, bitcoind may have pruned blocks it needs: {:?}",
+					e
+				);
+				return;
+			}
+		}
+	} else if restarting_node {
 		let mut chain_listeners =
 			vec![(channel_manager_blockhash, &mut channel_manager as &mut dyn chain::Listen)];
 
@@ -469,16 +1208,28 @@ async fn start_ldk() {
 				&mut monitor_listener_info.1 as &mut dyn chain::Listen,
 			));
 		}
-		chain_tip = Some(
-			init::synchronize_listeners(
-				&mut bitcoind_client.deref(),
-				args.network,
-				&mut cache,
-				chain_listeners,
-			)
-			.await
-			.unwrap(),
-		);
+		if let Some(wallet) = onchain_wallet.as_mut() {
+			let wallet_blockhash = wallet.best_block_hash();
+			chain_listeners.push((wallet_blockhash, wallet as &mut dyn chain::Listen));
+		}
+		match init::synchronize_listeners(
+			&mut bitcoind_client.deref(),
+			args.network,
+			&mut cache,
+			chain_listeners,
+		)
+		.await
+		{
+			Ok(tip) => chain_tip = Some(tip),
+			Err(e) => {
+				println!("ERROR: Failed to sync to bitcoind's chain tip: {:?}", e);
+				// bitcoind may have pruned the blocks we needed while we were syncing.
+				if bitcoind_prune_height.is_some() {
+					println!("{}", pruned_sync::RECOVERY_HINT);
+				}
+				return;
+			}
+		}
 	}
 
 	// Step 10: Give ChannelMonitors to ChainMonitor
@@ -487,24 +1238,58 @@ async fn start_ldk() {
 		let funding_outpoint = item.2;
 		chain_monitor.watch_channel(funding_outpoint, channel_monitor).unwrap();
 	}
+	if pruned_gap {
+		let tip = match init::validate_best_block_header(&mut bitcoind_client.deref()).await {
+			Ok(tip) => tip,
+			Err(e) => {
+				println!("ERROR: Failed to get bitcoind's chain tip: {:?}", e);
+				return;
+			}
+		};
+		if let Err(e) = pruned_sync::catch_up(
+			bitcoind_client.deref(),
+			watched_scripts.as_ref().unwrap(),
+			&chain_monitor,
+			&channel_manager,
+			filter_sync_start,
+			&tip.header,
+			tip.height,
+		)
+		.await
+		{
+			println!(
+				"ERROR: bitcoind has pruned the blocks before height {}, but we last synced to height {}, and we can't catch up without them: {}",
+				bitcoind_prune_height.unwrap(),
+				filter_sync_start.height(),
+				e
+			);
+			println!("{}", pruned_sync::RECOVERY_HINT);
+			return;
+		}
+		chain_tip = Some(tip);
+	}
+
+	let onchain_wallet = onchain_wallet.map(Arc::new);
+	if let Some(wallet) = &onchain_wallet {
+		bitcoind_client.use_onchain_wallet(wallet.clone());
+		wallet.clone().watch_spends(bitcoind_client.clone());
+	}
 
 	// Step 11: Optional: Initialize the NetGraphMsgHandler
 	let genesis = genesis_block(args.network).header.block_hash();
-	let network_graph_path = format!("{}/network_graph", ldk_data_dir.clone());
-	let network_graph = Arc::new(disk::read_network(Path::new(&network_graph_path), genesis));
+	let network_graph = Arc::new(disk::read_network(&*storage, genesis));
 	let network_gossip = Arc::new(NetGraphMsgHandler::new(
 		Arc::clone(&network_graph),
 		None::<Arc<dyn chain::Access + Send + Sync>>,
 		logger.clone(),
 	));
 	let network_graph_persist = Arc::clone(&network_graph);
+	let network_graph_storage = storage.clone();
 	tokio::spawn(async move {
 		let mut interval = tokio::time::interval(Duration::from_secs(600));
 		loop {
 			interval.tick().await;
-			if disk::persist_network(Path::new(&network_graph_path), &network_graph_persist)
-				.is_err()
-			{
+			if disk::persist_network(&*network_graph_storage, &network_graph_persist).is_err() {
 				// Persistence errors here are non-fatal as we can just fetch the routing graph
 				// again later, but they may indicate a disk error which could be fatal elsewhere.
 				eprintln!(
@@ -516,12 +1301,35 @@ async fn start_ldk() {
 
 	// Step 12: Initialize the PeerManager
 	let channel_manager: Arc<ChannelManager> = Arc::new(channel_manager);
+	let recovery = recovery_backup
+		.map(|backup| Arc::new(ChannelRecovery::new(backup, &keys_manager, &channel_manager)));
+	WalPersister::start_wal_sync(monitor_persister.clone(), channel_manager.clone());
+	DiskMonitor::start_monitoring(disk_monitor.clone(), channel_manager.clone());
+	args.maintenance.clone().start(pause_switch.clone(), channel_manager.clone());
+
+	// Optionally keep the network graph from growing unboundedly as we learn gossip.
+	if let Some(max_graph_nodes) = args.max_graph_nodes {
+		memory::start_graph_pruning(
+			network_graph.clone(),
+			channel_manager.clone(),
+			max_graph_nodes,
+		);
+	}
 	let mut ephemeral_bytes = [0; 32];
 	rand::thread_rng().fill_bytes(&mut ephemeral_bytes);
-	let lightning_msg_handler = MessageHandler {
-		chan_handler: channel_manager.clone(),
-		route_handler: network_gossip.clone(),
-	};
+	let htlc_tracker = Arc::new(HtlcTracker::default());
+	let wire_capture =
+		Arc::new(WireCapture::new(channel_manager.clone(), htlc_tracker.clone(), args.debug_wire));
+	// Inbound channel opens are checked first, independently of whether wire capture is enabled,
+	// and all refused while paused.
+	let channel_acceptor = Arc::new(ChannelAcceptor::new(
+		wire_capture.clone(),
+		channel_manager.clone(),
+		args.acceptor_policy.clone(),
+		pause_switch.clone(),
+	));
+	let lightning_msg_handler =
+		MessageHandler { chan_handler: channel_acceptor, route_handler: network_gossip.clone() };
 	let peer_manager: Arc<PeerManager> = Arc::new(PeerManager::new(
 		lightning_msg_handler,
 		keys_manager.get_node_secret(),
@@ -533,65 +1341,289 @@ async fn start_ldk() {
 	// ## Running LDK
 	// Step 13: Initialize networking
 
-	let peer_manager_connection_handler = peer_manager.clone();
-	let listening_port = args.ldk_peer_listening_port;
-	tokio::spawn(async move {
-		let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", listening_port))
-			.await
-			.expect("Failed to bind to listen port - is something else already listening on it?");
-		loop {
-			let peer_mgr = peer_manager_connection_handler.clone();
-			let tcp_stream = listener.accept().await.unwrap().0;
+	// Near the file descriptor limit, keep room for channel peers at the expense of gossip peers.
+	let connection_guard =
+		Arc::new(ConnectionGuard::new(channel_manager.clone(), peer_manager.clone()));
+	ConnectionGuard::start_monitoring(connection_guard.clone());
+
+	// In client-only mode we never accept inbound connections, only connecting out to peers.
+	if args.accept_inbound {
+		let mut listeners = Vec::new();
+		// Under socket activation systemd already bound our listening sockets.
+		for listener in activated_sockets.peer_listeners.drain(..) {
+			match listener
+				.set_nonblocking(true)
+				.and_then(|_| tokio::net::TcpListener::from_std(listener))
+			{
+				Ok(listener) => listeners.push(listener),
+				Err(e) => {
+					println!("ERROR: Failed to use socket passed by systemd: {}", e);
+					return;
+				}
+			}
+		}
+		if listeners.is_empty() {
+			for bind_addr in args.ldk_peer_bind_addrs.iter() {
+				match tokio::net::TcpListener::bind(bind_addr).await {
+					Ok(listener) => listeners.push(listener),
+					Err(e) => {
+						println!(
+							"ERROR: Failed to bind to {} - is something else already listening on it? {}",
+							bind_addr, e
+						);
+						return;
+					}
+				}
+			}
+		}
+		for listener in listeners {
+			let peer_manager_connection_handler = peer_manager.clone();
+			let connection_guard = connection_guard.clone();
 			tokio::spawn(async move {
-				lightning_net_tokio::setup_inbound(
-					peer_mgr.clone(),
-					tcp_stream.into_std().unwrap(),
-				)
-				.await;
+				loop {
+					let peer_mgr = peer_manager_connection_handler.clone();
+					let tcp_stream = match listener.accept().await {
+						Ok((tcp_stream, _)) => tcp_stream,
+						// Errors here are per-connection, e.g. running out of file descriptors.
+						Err(_) => continue,
+					};
+					if !connection_guard.admit_inbound() {
+						continue;
+					}
+					tokio::spawn(async move {
+						lightning_net_tokio::setup_inbound(
+							peer_mgr.clone(),
+							tcp_stream.into_std().unwrap(),
+						)
+						.await;
+					});
+				}
 			});
 		}
-	});
+	}
 
-	// Step 14: Connect and Disconnect Blocks
-	if chain_tip.is_none() {
-		chain_tip =
-			Some(init::validate_best_block_header(&mut bitcoind_client.deref()).await.unwrap());
+	// Optionally make our listening port reachable as an onion service too.
+	let mut announced_listen_addr = args.ldk_announced_listen_addr.clone();
+	if let Some(tor_control_addr) = args.tor_control_addr {
+		let onion_key_path = format!("{}/tor_onion_key", ldk_data_dir.clone());
+		match tor::create_onion_service(
+			tor_control_addr,
+			Path::new(&onion_key_path),
+			args.ldk_peer_listening_port,
+		)
+		.await
+		{
+			Ok((onion_addr, net_address)) => {
+				println!("Onion service listening at {}", onion_addr);
+				announced_listen_addr.push(net_address);
+			}
+			Err(e) => {
+				println!("ERROR: Failed to create onion service: {}", e);
+				return;
+			}
+		}
 	}
-	let channel_manager_listener = channel_manager.clone();
-	let chain_monitor_listener = chain_monitor.clone();
-	let bitcoind_block_source = bitcoind_client.clone();
-	let network = args.network;
-	tokio::spawn(async move {
-		let mut derefed = bitcoind_block_source.deref();
-		let chain_poller = poll::ChainPoller::new(&mut derefed, network);
-		let chain_listener = (chain_monitor_listener, channel_manager_listener);
-		let mut spv_client =
-			SpvClient::new(chain_tip.unwrap(), chain_poller, &mut cache, &chain_listener);
-		loop {
-			spv_client.poll_best_tip().await.unwrap();
-			tokio::time::sleep(Duration::from_secs(1)).await;
+
+	// Step 14: Connect and Disconnect Blocks
+	if let (Some(peer_addr), Some(watched_scripts)) =
+		(args.filter_peer_addr, watched_scripts.clone())
+	{
+		let mut peer_addrs = vec![peer_addr];
+		peer_addrs.extend(args.filter_check_peer_addrs.iter().cloned());
+		compact_filters::start_syncing(
+			peer_addrs,
+			args.network,
+			filter_sync_start,
+			watched_scripts,
+			chain_monitor.clone(),
+			channel_manager.clone(),
+		);
+	} else {
+		if chain_tip.is_none() {
+			match init::validate_best_block_header(&mut bitcoind_client.deref()).await {
+				Ok(tip) => chain_tip = Some(tip),
+				Err(e) => {
+					println!(
+						"ERROR: Failed to get {}'s chain tip: {:?}",
+						bitcoind_client.chain_source_name(),
+						e
+					);
+					return;
+				}
+			}
 		}
-	});
+		let channel_manager_listener = channel_manager.clone();
+		let chain_monitor_listener = chain_monitor.clone();
+		let channel_manager_best_block = channel_manager.clone();
+		let bitcoind_block_source = bitcoind_client.clone();
+		let block_notifications = args.bitcoind_zmq_addr.map(BlockNotifications::start);
+		let recovery_listener = recovery.clone();
+		let network = args.network;
+		tokio::spawn(async move {
+			let mut derefed = bitcoind_block_source.deref();
+			let chain_poller = poll::ChainPoller::new(&mut derefed, network);
+			let chain_listener = ChainListener {
+				chain_monitor: chain_monitor_listener,
+				channel_manager: channel_manager_listener,
+				onchain_wallet,
+				recovery: recovery_listener,
+			};
+			let mut spv_client =
+				SpvClient::new(chain_tip.unwrap(), chain_poller, &mut cache, &chain_listener);
+			let mut persisted_block_hash = None;
+			let mut polling_failed = false;
+			loop {
+				// Keep polling through bitcoind restarts, only warning when polling starts failing.
+				let source = bitcoind_block_source.chain_source_name();
+				match spv_client.poll_best_tip().await {
+					Ok(_) if polling_failed => {
+						polling_failed = false;
+						eprintln!("Syncing blocks from {} again", source);
+					}
+					Ok(_) => {}
+					Err(e) => {
+						if !polling_failed {
+							polling_failed = true;
+							eprintln!(
+								"Warning: Failed to poll {} for new blocks, retrying: {:?}",
+								source, e
+							);
+						}
+						bitcoind_block_source.reconnect().await;
+					}
+				}
+				let best_block = channel_manager_best_block.current_best_block();
+				if persisted_block_hash != Some(best_block.block_hash()) {
+					match best_block::persist_best_block(Path::new(&best_block_path), &best_block) {
+						Ok(()) => persisted_block_hash = Some(best_block.block_hash()),
+						Err(e) => eprintln!("Warning: Failed to persist best block: {}", e),
+					}
+				}
+				bitcoind_client::wait_for_next_block_poll(block_notifications.as_deref()).await;
+			}
+		});
+	}
 
 	// Step 15: Handle LDK Events
 	let channel_manager_event_listener = channel_manager.clone();
-	let keys_manager_listener = keys_manager.clone();
-	// TODO: persist payment info to disk
-	let inbound_payments: PaymentInfoStorage = Arc::new(Mutex::new(HashMap::new()));
-	let outbound_payments: PaymentInfoStorage = Arc::new(Mutex::new(HashMap::new()));
+	let inbound_payments: PaymentInfoStorage =
+		Arc::new(Mutex::new(PaymentInfoStore::new(storage.clone(), storage::INBOUND_PAYMENTS)));
+	let outbound_payments: PaymentInfoStorage =
+		Arc::new(Mutex::new(PaymentInfoStore::new(storage.clone(), storage::OUTBOUND_PAYMENTS)));
+	let invoice_bundles: BundleStorage =
+		Arc::new(Mutex::new(BundleStore::new(format!("{}/invoice_bundles", ldk_data_dir.clone()))));
 	let inbound_pmts_for_events = inbound_payments.clone();
 	let outbound_pmts_for_events = outbound_payments.clone();
 	let network = args.network;
 	let bitcoind_rpc = bitcoind_client.clone();
+	let payer_secret = args.payer_secret;
+	let seen_proofs = match SeenProofs::new(storage.clone()) {
+		Ok(seen_proofs) => Arc::new(seen_proofs),
+		Err(e) => {
+			println!("ERROR: Failed to read the payer proofs we've accepted: {}", e);
+			return;
+		}
+	};
+	let pause_switch_listener = pause_switch.clone();
+	let peer_limits: PeerLimitsStorage =
+		Arc::new(Mutex::new(PeerLimitsStore::new(format!("{}/peer_limits", ldk_data_dir.clone()))));
+	let peer_limits_listener = peer_limits.clone();
+	let external_funding: ExternalFundingStorage = Arc::new(Mutex::new(ExternalFunding::default()));
+	let external_funding_listener = external_funding.clone();
+	let payer = Arc::new(RouteRecordingPayer::new(
+		channel_manager.clone(),
+		PathPadder::new(network_graph.clone(), args.path_privacy),
+		args.fee_budget,
+		outbound_payments.clone(),
+	));
+	let payer_listener = payer.clone();
+	let output_sweeper = Arc::new(OutputSweeper::new(
+		format!("{}/spendable_outputs", ldk_data_dir.clone()),
+		keys_manager.clone(),
+		bitcoind_client.clone(),
+		sweep_wallet.clone(),
+		args.sweep_batch_window,
+	));
+	// Retry sweeps of outputs left over from before a restart, and any which haven't confirmed.
+	OutputSweeper::start_sweeping(output_sweeper.clone());
+	if let Some(recovery) = recovery {
+		channel_backup::start_recovery(
+			recovery,
+			peer_manager.clone(),
+			wire_capture.clone(),
+			output_sweeper.clone(),
+			args.proxy_addr,
+		);
+	}
+	let output_sweeper_listener = output_sweeper.clone();
+	ChannelHistory::start_tracking(channel_history.clone(), channel_manager.clone());
+	let channel_history_listener = channel_history.clone();
+	let forward_log = Arc::new(ForwardLog::new(
+		format!("{}/forwards", ldk_data_dir.clone()),
+		htlc_tracker.clone(),
+	));
+	let forward_log_listener = forward_log.clone();
+	let settlement_webhook = args.webhook_endpoint.clone().map(|endpoint| {
+		Arc::new(SettlementWebhook::new(
+			endpoint,
+			args.webhook_secret.clone(),
+			format!("{}/webhook_outbox", ldk_data_dir.clone()),
+			invoice_bundles.clone(),
+		))
+	});
+	if let Some(webhook) = settlement_webhook.clone() {
+		// Deliver settlements left over from before a restart, and any queued from now on.
+		SettlementWebhook::start_delivering(webhook);
+	}
+	let reissue_webhook = settlement_webhook.clone();
+	let rpc_webhook = settlement_webhook.clone();
+	let hold_invoices =
+		Arc::new(HoldInvoices::new(format!("{}/hold_invoices", ldk_data_dir.clone())));
+	let hold_invoices_listener = hold_invoices.clone();
+	let notifier = if args.notify_targets.is_empty() {
+		None
+	} else {
+		let notifier = Arc::new(Notifier::start(args.notify_targets, args.notify_events.clone()));
+		Notifier::start_watching(notifier.clone(), channel_manager.clone(), peer_manager.clone());
+		Some(notifier)
+	};
+	if let Some(ceiling_msat) = args.max_htlc_exposure_msat {
+		forwarding::start_exposure_alarm(
+			ceiling_msat,
+			htlc_tracker.clone(),
+			channel_manager.clone(),
+			notifier.clone(),
+		);
+	}
+	// Fed to clients of the RPC server's event stream.
+	let event_stream = Arc::new(EventStream::new());
+	EventStream::start_watching(event_stream.clone(), channel_manager.clone());
+	let event_stream_listener = event_stream.clone();
+	let htlc_tracker_listener = htlc_tracker.clone();
+	let min_claim_cltv_blocks = args.min_claim_cltv_blocks;
 	let handle = tokio::runtime::Handle::current();
 	let event_handler = move |event: &Event| {
 		handle.block_on(handle_ldk_events(
 			channel_manager_event_listener.clone(),
 			bitcoind_rpc.clone(),
-			keys_manager_listener.clone(),
+			output_sweeper_listener.clone(),
+			payer_listener.clone(),
 			inbound_pmts_for_events.clone(),
 			outbound_pmts_for_events.clone(),
 			network,
+			payer_secret,
+			seen_proofs.clone(),
+			pause_switch_listener.clone(),
+			peer_limits_listener.clone(),
+			external_funding_listener.clone(),
+			settlement_webhook.clone(),
+			hold_invoices_listener.clone(),
+			notifier.clone(),
+			event_stream_listener.clone(),
+			channel_history_listener.clone(),
+			forward_log_listener.clone(),
+			htlc_tracker_listener.clone(),
+			min_claim_cltv_blocks,
 			event,
 		));
 	};
@@ -600,6 +1632,7 @@ async fn start_ldk() {
 	let scorer_path = format!("{}/scorer", ldk_data_dir.clone());
 	let scorer = Arc::new(Mutex::new(disk::read_scorer(Path::new(&scorer_path))));
 	let scorer_persist = Arc::clone(&scorer);
+	let scorer_shutdown_path = scorer_path.clone();
 	tokio::spawn(async move {
 		let mut interval = tokio::time::interval(Duration::from_secs(600));
 		loop {
@@ -615,42 +1648,253 @@ async fn start_ldk() {
 	});
 
 	// Step 17: Create InvoicePayer
-	let router = DefaultRouter::new(network_graph.clone(), logger.clone());
+	let route_exclusions: RouteExclusionsStorage = Arc::new(Mutex::new(RouteExclusions::new(
+		format!("{}/route_exclusions", ldk_data_dir.clone()),
+	)));
+	let router = FeeBudgetRouter::new(
+		network_graph.clone(),
+		logger.clone(),
+		args.fee_budget,
+		outbound_payments.clone(),
+		route_exclusions.clone(),
+	);
 	let invoice_payer = Arc::new(InvoicePayer::new(
-		channel_manager.clone(),
+		payer.clone(),
 		router,
 		scorer.clone(),
 		logger.clone(),
 		event_handler,
-		payment::RetryAttempts(5),
+		payment::RetryAttempts(args.max_payment_retries),
 	));
 
+	// Regularly expire stale invoices and prune old payments from the payment stores.
+	payment_gc::start_payment_gc(
+		inbound_payments.clone(),
+		outbound_payments.clone(),
+		invoice_payer.clone(),
+		args.payment_retention_days,
+	);
+
+	// Cancel held payments which the external system didn't settle in time.
+	HoldInvoices::start_expiring(
+		hold_invoices.clone(),
+		channel_manager.clone(),
+		inbound_payments.clone(),
+	);
+
+	// Re-issue invoices which expire unpaid, if the merchant wants them replaced.
+	if args.auto_reissue_invoices > 0 {
+		reissue::start_auto_reissue(
+			inbound_payments.clone(),
+			invoice_bundles.clone(),
+			channel_manager.clone(),
+			keys_manager.clone(),
+			network,
+			args.min_final_cltv_expiry,
+			args.auto_reissue_invoices,
+			reissue_webhook,
+		);
+	}
+
+	// Send any recurring payments as they come due.
+	let scheduled_payments: ScheduleStorage = Arc::new(Mutex::new(ScheduleStore::new(format!(
+		"{}/scheduled_payments",
+		ldk_data_dir.clone()
+	))));
+	scheduler::start_payment_scheduler(
+		scheduled_payments.clone(),
+		network_graph.clone(),
+		channel_manager.clone(),
+		outbound_payments.clone(),
+		logger.clone(),
+		scorer.clone(),
+		args.fee_budget,
+		args.payer_secret,
+		pause_switch.clone(),
+		route_exclusions.clone(),
+	);
+
 	// Step 18: Persist ChannelManager
-	let data_dir = ldk_data_dir.clone();
+	let manager_storage = storage.clone();
 	let persist_channel_manager_callback =
-		move |node: &ChannelManager| FilesystemPersister::persist_manager(data_dir.clone(), &*node);
+		move |node: &ChannelManager| manager_storage.write(storage::MANAGER, &node.encode());
 
 	// Step 19: Background Processing
-	let background_processor = BackgroundProcessor::start(
+
+	// The InvoicePayer doesn't pass on failures of paths it retries, so we have to see them before
+	// it does to know which paths didn't end up being part of a payment's route, and to count every
+	// part of a multi-path payment which failed.
+	let invoice_payer_event_handler = invoice_payer.clone();
+	let payer_path_listener = payer.clone();
+	let outbound_parts_listener = outbound_payments.clone();
+	let background_event_handler = move |event: &Event| {
+		if let Event::PaymentPathFailed { payment_hash, path, .. } = event {
+			payer_path_listener.path_failed(payment_hash, path);
+			let mut payments = outbound_parts_listener.lock().unwrap();
+			if let Some(payment) = payments.get_mut(payment_hash) {
+				payment.failed_parts += 1;
+				payments.persist();
+			}
+		}
+		invoice_payer_event_handler.handle_event(event);
+	};
+	let background_processor = Arc::new(Mutex::new(Some(BackgroundProcessor::start(
 		persist_channel_manager_callback,
-		invoice_payer.clone(),
+		background_event_handler,
 		chain_monitor.clone(),
 		channel_manager.clone(),
 		Some(network_gossip.clone()),
 		peer_manager.clone(),
 		logger.clone(),
+	))));
+
+	// Track how reliably each of our channel counterparties stays connected.
+	let uptime_path = format!("{}/peer_uptime", ldk_data_dir.clone());
+	let peer_uptime: PeerUptimeStorage =
+		Arc::new(Mutex::new(uptime::read_uptime(Path::new(&uptime_path))));
+	uptime::start_uptime_tracking(
+		uptime_path,
+		peer_uptime.clone(),
+		channel_manager.clone(),
+		peer_manager.clone(),
 	);
 
+	// Periodically record our balances so operators can chart the node's growth.
+	balances::start_balance_snapshots(
+		format!("{}/balance_history", ldk_data_dir.clone()),
+		bitcoind_client.clone(),
+		channel_manager.clone(),
+	);
+
+	// Optionally allow the node to be driven over HTTP alongside the interactive CLI, on the socket
+	// systemd passed us if there is one.
+	let rpc_listener = match (activated_sockets.rpc_listener.take(), args.rpc_listen_addr) {
+		(Some(listener), _) => Some(listener),
+		(None, Some(rpc_listen_addr)) => match std::net::TcpListener::bind(rpc_listen_addr) {
+			Ok(listener) => Some(listener),
+			Err(e) => {
+				println!(
+					"ERROR: Failed to bind to RPC listen address {} - is something else already listening on it? {}",
+					rpc_listen_addr, e
+				);
+				return;
+			}
+		},
+		(None, None) => None,
+	};
+	if let Some(rpc_listener) = rpc_listener {
+		let cookie_path = format!("{}/{}", ldk_data_dir, rpc_server::AUTH_COOKIE_FILE);
+		let auth_token = match rpc_server::write_auth_cookie(&cookie_path) {
+			Ok(auth_token) => auth_token,
+			Err(e) => {
+				println!("ERROR: Failed to write RPC auth cookie to {}: {}", cookie_path, e);
+				return;
+			}
+		};
+		rpc_server::start_rpc_server(
+			rpc_listener,
+			rpc_server::RpcServer {
+				invoice_payer: invoice_payer.clone(),
+				payer: payer.clone(),
+				peer_manager: peer_manager.clone(),
+				channel_manager: channel_manager.clone(),
+				keys_manager: keys_manager.clone(),
+				bitcoind_client: bitcoind_client.clone(),
+				inbound_payments: inbound_payments.clone(),
+				outbound_payments: outbound_payments.clone(),
+				invoice_bundles: invoice_bundles.clone(),
+				peer_uptime: peer_uptime.clone(),
+				storage: storage.clone(),
+				network: args.network,
+				user_config: args.user_config,
+				min_final_cltv_expiry: args.min_final_cltv_expiry,
+				proxy_addr: args.proxy_addr,
+				pause_switch: pause_switch.clone(),
+				disk_monitor: disk_monitor.clone(),
+				peer_limits: peer_limits.clone(),
+				hold_invoices: hold_invoices.clone(),
+				settlement_webhook: rpc_webhook,
+				connection_guard: connection_guard.clone(),
+				event_stream: event_stream.clone(),
+				auth_token,
+			},
+		);
+	}
+
+	// Keep an eye on channels which have taken many commitment updates.
+	let channel_rotation = Arc::new(ChannelRotation::new(
+		args.rotation_limits,
+		monitor_persister.clone(),
+		&ldk_data_dir,
+	));
+	ChannelRotation::start_checking(
+		channel_rotation.clone(),
+		channel_manager.clone(),
+		peer_manager.clone(),
+		args.user_config,
+		peer_limits.clone(),
+		pause_switch.clone(),
+	);
+
+	// Open channels queued with `openqueue add` a few at a time.
+	let open_queue = Arc::new(OpenQueue::new(args.max_concurrent_opens));
+	OpenQueue::start_processing(
+		open_queue.clone(),
+		channel_manager.clone(),
+		peer_manager.clone(),
+		bitcoind_client.clone(),
+		storage.clone(),
+		args.user_config,
+		peer_limits.clone(),
+		args.proxy_addr,
+		pause_switch.clone(),
+	);
+
+	// Let the node be administered over a unix socket, whether or not the RPC server is enabled.
+	let config_path = format!("{}/ldk.conf", args.ldk_storage_dir_path);
+	let admin_socket = admin_socket::AdminSocket {
+		channel_manager: channel_manager.clone(),
+		peer_manager: peer_manager.clone(),
+		pause_switch: pause_switch.clone(),
+		disk_monitor: disk_monitor.clone(),
+		logger: logger.clone(),
+		config: Mutex::new(config::read_config(Path::new(&config_path)).unwrap_or_default()),
+		config_path,
+	};
+	if let Err(e) =
+		admin_socket::start_admin_socket(format!("{}/admin.sock", ldk_data_dir), admin_socket)
+	{
+		eprintln!("Warning: Failed to open the admin socket: {}", e);
+	}
+
+	// Optionally report anonymized node statistics.
+	if let Some(endpoint) = args.telemetry_endpoint.clone() {
+		telemetry::start_telemetry(endpoint, channel_manager.clone(), args.network);
+	}
+
+	// Optionally let an external monitor know we're still alive.
+	if let Some(endpoint) = args.heartbeat_endpoint.clone() {
+		heartbeat::start_heartbeats(
+			endpoint,
+			args.heartbeat_interval,
+			keys_manager.get_node_secret(),
+			channel_manager.clone(),
+		);
+	}
+
 	// Reconnect to channel peers if possible.
-	let peer_data_path = format!("{}/channel_peer_data", ldk_data_dir.clone());
-	match disk::read_channel_peer_data(Path::new(&peer_data_path)) {
+	match disk::read_channel_peer_data(&*storage) {
 		Ok(mut info) => {
 			for (pubkey, peer_addr) in info.drain() {
 				for chan_info in channel_manager.list_channels() {
 					if pubkey == chan_info.counterparty.node_id {
-						let _ =
-							cli::connect_peer_if_necessary(pubkey, peer_addr, peer_manager.clone())
-								.await;
+						let _ = cli::connect_peer_if_necessary(
+							pubkey,
+							peer_addr.clone(),
+							peer_manager.clone(),
+							args.proxy_addr,
+						)
+						.await;
 					}
 				}
 			}
@@ -664,38 +1908,136 @@ async fn start_ldk() {
 	// to avoid churn in the global network graph.
 	let chan_manager = Arc::clone(&channel_manager);
 	let network = args.network;
-	if !args.ldk_announced_listen_addr.is_empty() {
+	let announced_node_name = args.ldk_announced_node_name;
+	if !announced_listen_addr.is_empty() {
 		tokio::spawn(async move {
 			let mut interval = tokio::time::interval(Duration::from_secs(60));
 			loop {
 				interval.tick().await;
 				chan_manager.broadcast_node_announcement(
 					[0; 3],
-					args.ldk_announced_node_name,
-					args.ldk_announced_listen_addr.clone(),
+					announced_node_name,
+					announced_listen_addr.clone(),
 				);
 			}
 		});
 	}
 
+	// Shut down cleanly if we're asked to stop via SIGINT or SIGTERM.
+	let shutdown_state = ShutdownState {
+		background_processor,
+		peer_manager: peer_manager.clone(),
+		network_graph: network_graph.clone(),
+		storage: storage.clone(),
+		scorer: scorer.clone(),
+		scorer_path: scorer_shutdown_path,
+		inbound_payments: inbound_payments.clone(),
+		outbound_payments: outbound_payments.clone(),
+	};
+	let signal_shutdown_state = shutdown_state.clone();
+	tokio::spawn(async move {
+		let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => {}
+			_ = sigterm.recv() => {}
+		}
+		println!("\nReceived shutdown signal, shutting down...");
+		signal_shutdown_state.shutdown();
+		std::process::exit(0);
+	});
+
+	// Chain sync is done and everything is running, so let systemd know we're up.
+	systemd::notify("READY=1");
+	systemd::start_watchdog();
+
 	// Start the CLI.
 	cli::poll_for_user_input(
 		invoice_payer.clone(),
+		payer.clone(),
 		peer_manager.clone(),
 		channel_manager.clone(),
 		keys_manager.clone(),
+		shutdown_keys.clone(),
+		output_sweeper.clone(),
 		network_graph.clone(),
 		scorer.clone(),
 		inbound_payments,
 		outbound_payments,
+		invoice_bundles,
+		scheduled_payments,
+		peer_uptime,
+		channel_history,
+		forward_log,
+		wire_capture,
 		ldk_data_dir.clone(),
+		storage.clone(),
 		logger.clone(),
+		bitcoind_client.clone(),
 		network,
+		args.min_final_cltv_expiry,
+		args.user_config,
+		header_cache_len,
+		args.fee_budget,
+		args.payer_secret,
+		pause_switch,
+		disk_monitor,
+		peer_limits,
+		external_funding,
+		route_exclusions,
+		channel_rotation,
+		open_queue,
+		connection_guard,
+		args.proxy_addr,
+		args.command.clone(),
 	)
 	.await;
 
-	// Stop the background processor.
-	background_processor.stop().unwrap();
+	if args.command.is_some() {
+		cli::wait_for_pending_payments(shutdown_state.outbound_payments.clone()).await;
+	} else if systemd::is_service() {
+		// A service's stdin is closed from the start, so keep running until we're told to stop.
+		futures::future::pending::<()>().await;
+	}
+	shutdown_state.shutdown();
+}
+
+/// Everything we need to flush to disk when the node shuts down.
+#[derive(Clone)]
+struct ShutdownState {
+	background_processor: Arc<Mutex<Option<BackgroundProcessor>>>,
+	peer_manager: Arc<PeerManager>,
+	network_graph: Arc<NetworkGraph>,
+	storage: Arc<dyn Storage>,
+	scorer: Arc<Mutex<Scorer>>,
+	scorer_path: String,
+	inbound_payments: PaymentInfoStorage,
+	outbound_payments: PaymentInfoStorage,
+}
+
+impl ShutdownState {
+	/// Stops background processing, flushes all node state to disk and disconnects our peers. Safe
+	/// to call more than once.
+	fn shutdown(&self) {
+		systemd::notify("STOPPING=1");
+		// Stopping the background processor persists the ChannelManager one final time.
+		if let Some(background_processor) = self.background_processor.lock().unwrap().take() {
+			if let Err(e) = background_processor.stop() {
+				eprintln!("ERROR: Failed to persist ChannelManager on shutdown: {}", e);
+			}
+		}
+		if disk::persist_network(&*self.storage, &self.network_graph).is_err() {
+			eprintln!("Warning: Failed to persist network graph on shutdown");
+		}
+		if disk::persist_scorer(Path::new(&self.scorer_path), &self.scorer.lock().unwrap()).is_err()
+		{
+			eprintln!("Warning: Failed to persist scorer on shutdown");
+		}
+		self.inbound_payments.lock().unwrap().persist();
+		self.outbound_payments.lock().unwrap().persist();
+		for node_id in self.peer_manager.get_peer_node_ids() {
+			self.peer_manager.disconnect_by_node_id(node_id, false);
+		}
+	}
 }
 
 #[tokio::main]
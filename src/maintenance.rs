@@ -0,0 +1,130 @@
+use crate::pause::PauseSwitch;
+use crate::{time_now_secs, ChannelManager};
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often we check whether a maintenance window has started or ended.
+const WINDOW_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// A recurring stretch of time, in UTC, during which the node holds forwards and/or refuses new
+/// channels, e.g. while its operator upgrades the machine it runs on.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MaintenanceWindow {
+	/// The day of the week the window starts on, Monday being 0, or `None` for every day.
+	day: Option<u64>,
+	/// Minutes past midnight. A window ending before it starts runs past midnight.
+	start_min: u64,
+	end_min: u64,
+}
+
+fn parse_time(time: &str) -> Option<u64> {
+	let mut parts = time.splitn(2, ':');
+	let hours: u64 = parts.next()?.parse().ok()?;
+	let mins: u64 = parts.next()?.parse().ok()?;
+	if hours > 23 || mins > 59 {
+		return None;
+	}
+	Some(hours * 60 + mins)
+}
+
+impl MaintenanceWindow {
+	/// Parses a window such as "sat 02:00-04:00", or "03:00-03:30" for one every day.
+	pub(crate) fn parse(window: &str) -> Result<Self, String> {
+		let invalid = || {
+			format!("invalid maintenance window {}, expected e.g. \"sat 02:00-04:00\" or \"03:00-03:30\"", window)
+		};
+		let words: Vec<&str> = window.split_whitespace().collect();
+		let (day, times) = match words.as_slice() {
+			[times] => (None, *times),
+			[day, times] => {
+				let day =
+					DAYS.iter().position(|d| day.eq_ignore_ascii_case(d)).ok_or_else(invalid)?;
+				(Some(day as u64), *times)
+			}
+			_ => return Err(invalid()),
+		};
+		let mut times = times.splitn(2, '-');
+		let start_min = times.next().and_then(parse_time).ok_or_else(invalid)?;
+		let end_min = times.next().and_then(parse_time).ok_or_else(invalid)?;
+		if start_min == end_min {
+			return Err(invalid());
+		}
+		Ok(Self { day, start_min, end_min })
+	}
+
+	/// Whether the window covers `now`, in seconds since the UNIX epoch.
+	fn contains(&self, now: u64) -> bool {
+		let days = now / 86400;
+		let min = now % 86400 / 60;
+		// The UNIX epoch was a Thursday.
+		let weekday = (days + 3) % 7;
+		let starts_on = |day: u64| self.day.map_or(true, |d| d == day);
+		if self.start_min < self.end_min {
+			starts_on(weekday) && min >= self.start_min && min < self.end_min
+		} else {
+			// Either we're past the start today, or before the end of a window started yesterday.
+			(starts_on(weekday) && min >= self.start_min)
+				|| (starts_on((weekday + 6) % 7) && min < self.end_min)
+		}
+	}
+}
+
+/// When the node goes into maintenance, and what it stops doing while it is.
+#[derive(Clone, Default)]
+pub(crate) struct MaintenanceSchedule {
+	pub(crate) windows: Vec<MaintenanceWindow>,
+	pub(crate) hold_forwards: bool,
+	pub(crate) refuse_channels: bool,
+}
+
+impl MaintenanceSchedule {
+	fn in_window(&self, now: u64) -> bool {
+		self.windows.iter().any(|window| window.contains(now))
+	}
+
+	/// Regularly checks whether a maintenance window has started or ended, holding the node
+	/// through the `PauseSwitch` while one is on and processing any HTLCs we held on to after.
+	pub(crate) fn start(
+		self, pause_switch: Arc<PauseSwitch>, channel_manager: Arc<ChannelManager>,
+	) {
+		if self.windows.is_empty() {
+			return;
+		}
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(WINDOW_CHECK_INTERVAL);
+			let mut in_window = false;
+			loop {
+				interval.tick().await;
+				let now_in_window = self.in_window(time_now_secs());
+				if now_in_window == in_window {
+					continue;
+				}
+				in_window = now_in_window;
+				pause_switch.set_held_for_maintenance(
+					in_window && self.hold_forwards,
+					in_window && self.refuse_channels,
+				);
+				if in_window {
+					let held = match (self.hold_forwards, self.refuse_channels) {
+						(true, true) => "holding incoming HTLCs and refusing new channels",
+						(true, false) => "holding incoming HTLCs",
+						(false, true) => "refusing new channels",
+						(false, false) => "carrying on as usual",
+					};
+					println!("\nEVENT: Maintenance window started, {} until it ends", held);
+				} else {
+					println!("\nEVENT: Maintenance window ended");
+					if !pause_switch.holds_forwards() {
+						channel_manager.process_pending_htlc_forwards();
+					}
+				}
+				print!("> ");
+				io::stdout().flush().unwrap();
+			}
+		});
+	}
+}
@@ -0,0 +1,131 @@
+use crate::ChannelManager;
+use bitcoin::BlockHash;
+use lightning::routing::network_graph::{NetworkGraph, NodeId};
+use lightning_block_sync::poll::ValidatedBlockHeader;
+use lightning_block_sync::Cache;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often we prune the network graph back down to its configured size.
+const GRAPH_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A block header cache which evicts the oldest headers once it holds `max_headers`. Headers which
+/// were evicted are simply re-fetched from bitcoind if we ever need them again, e.g. on a deep
+/// reorg.
+pub(crate) struct BoundedHeaderCache {
+	headers: HashMap<BlockHash, ValidatedBlockHeader>,
+	insertion_order: VecDeque<BlockHash>,
+	max_headers: usize,
+	/// Shared with `debugstats` as the cache itself is owned by the chain poller.
+	len: Arc<AtomicUsize>,
+}
+
+impl BoundedHeaderCache {
+	pub(crate) fn new(max_headers: usize) -> Self {
+		Self {
+			headers: HashMap::new(),
+			insertion_order: VecDeque::new(),
+			max_headers,
+			len: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	/// A handle to the number of headers currently cached.
+	pub(crate) fn len_handle(&self) -> Arc<AtomicUsize> {
+		self.len.clone()
+	}
+}
+
+impl Cache for BoundedHeaderCache {
+	fn look_up(&self, block_hash: &BlockHash) -> Option<&ValidatedBlockHeader> {
+		self.headers.get(block_hash)
+	}
+
+	fn block_connected(&mut self, block_hash: BlockHash, block_header: ValidatedBlockHeader) {
+		if self.headers.insert(block_hash, block_header).is_none() {
+			self.insertion_order.push_back(block_hash);
+		}
+		while self.headers.len() > self.max_headers {
+			match self.insertion_order.pop_front() {
+				Some(oldest) => {
+					self.headers.remove(&oldest);
+				}
+				None => break,
+			}
+		}
+		self.len.store(self.headers.len(), Ordering::Relaxed);
+	}
+
+	fn block_disconnected(&mut self, block_hash: &BlockHash) -> Option<ValidatedBlockHeader> {
+		let header = self.headers.remove(block_hash);
+		if header.is_some() {
+			self.insertion_order.retain(|hash| hash != block_hash);
+		}
+		self.len.store(self.headers.len(), Ordering::Relaxed);
+		header
+	}
+}
+
+/// Drops all but the `max_nodes` best-connected nodes from the network graph, along with any
+/// channels to or from the dropped nodes. Ourselves and our channel counterparties are always kept
+/// so that we can still route through our own channels.
+pub(crate) fn prune_network_graph(
+	network_graph: &NetworkGraph, channel_manager: &ChannelManager, max_nodes: usize,
+) -> usize {
+	let mut keep: HashSet<NodeId> = HashSet::new();
+	keep.insert(NodeId::from_pubkey(&channel_manager.get_our_node_id()));
+	for chan_info in channel_manager.list_channels() {
+		keep.insert(NodeId::from_pubkey(&chan_info.counterparty.node_id));
+	}
+
+	let channels_to_remove: Vec<u64> = {
+		let graph = network_graph.read_only();
+		if graph.nodes().len() <= max_nodes {
+			return 0;
+		}
+		// Small nodes mostly route through the best-connected nodes, so rank by channel count.
+		let mut nodes: Vec<(&NodeId, usize)> =
+			graph.nodes().iter().map(|(node_id, node)| (node_id, node.channels.len())).collect();
+		nodes.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+		for (node_id, _) in nodes {
+			if keep.len() >= max_nodes {
+				break;
+			}
+			keep.insert(*node_id);
+		}
+		graph
+			.channels()
+			.iter()
+			.filter(|(_, chan)| !keep.contains(&chan.node_one) || !keep.contains(&chan.node_two))
+			.map(|(short_channel_id, _)| *short_channel_id)
+			.collect()
+	};
+	// Removing a node's last channel also removes the node from the graph.
+	for short_channel_id in channels_to_remove.iter() {
+		network_graph.close_channel_from_update(*short_channel_id, true);
+	}
+	channels_to_remove.len()
+}
+
+/// Regularly prunes the network graph, which otherwise keeps growing as we learn gossip.
+pub(crate) fn start_graph_pruning(
+	network_graph: Arc<NetworkGraph>, channel_manager: Arc<ChannelManager>, max_nodes: usize,
+) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(GRAPH_PRUNE_INTERVAL);
+		loop {
+			interval.tick().await;
+			prune_network_graph(&network_graph, &channel_manager, max_nodes);
+		}
+	});
+}
+
+/// The resident set size of this process in kilobytes, if the platform tells us.
+pub(crate) fn resident_size_kb() -> Option<u64> {
+	let status = fs::read_to_string("/proc/self/status").ok()?;
+	let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+	line.split_whitespace().nth(1)?.parse().ok()
+}
@@ -0,0 +1,70 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Somewhere other than our monitors directory that a copy of every channel monitor is kept, so
+/// that losing the node's disk doesn't lose the channels' funds. Monitors are stored under the
+/// same names as in the monitors directory, `<funding_txid>_<funding_index>`.
+pub(crate) enum MonitorReplica {
+	/// A directory, e.g. on another disk or a network mount, which can be copied back in place of
+	/// the monitors directory.
+	Directory(String),
+	/// A command run with the monitor's name as its only argument and the monitor on stdin, which
+	/// must exit successfully once the monitor is stored, e.g. a script uploading it over SFTP or
+	/// to S3.
+	Command(String),
+}
+
+impl MonitorReplica {
+	/// Parses a replica as given in the config file, `dir:<path>` or `cmd:<path>`.
+	pub(crate) fn parse(replica: &str) -> Result<Self, String> {
+		if let Some(path) = replica.strip_prefix("dir:") {
+			return Ok(MonitorReplica::Directory(path.to_string()));
+		}
+		if let Some(path) = replica.strip_prefix("cmd:") {
+			return Ok(MonitorReplica::Command(path.to_string()));
+		}
+		Err(format!("monitor replica `{}` must start with dir: or cmd:", replica))
+	}
+
+	pub(crate) fn describe(&self) -> String {
+		match self {
+			MonitorReplica::Directory(path) => format!("directory {}", path),
+			MonitorReplica::Command(path) => format!("command {}", path),
+		}
+	}
+
+	/// Stores `monitor` under `name`, only returning once it's durably stored.
+	pub(crate) fn write_monitor(&self, name: &str, monitor: &[u8]) -> io::Result<()> {
+		match self {
+			MonitorReplica::Directory(dir) => {
+				fs::create_dir_all(dir)?;
+				let tmp_path = format!("{}/{}.tmp", dir, name);
+				let mut tmp_file = File::create(&tmp_path)?;
+				tmp_file.write_all(monitor)?;
+				tmp_file.sync_all()?;
+				fs::rename(&tmp_path, format!("{}/{}", dir, name))?;
+				File::open(dir)?.sync_all()
+			}
+			MonitorReplica::Command(path) => {
+				let mut child = Command::new(path)
+					.arg(name)
+					.stdin(Stdio::piped())
+					.stdout(Stdio::null())
+					.spawn()?;
+				let written = child.stdin.take().unwrap().write_all(monitor);
+				let status = child.wait()?;
+				written?;
+				if !status.success() {
+					return Err(io::Error::new(
+						io::ErrorKind::Other,
+						format!("{} exited with {}", path, status),
+					));
+				}
+				Ok(())
+			}
+		}
+	}
+}
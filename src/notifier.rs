@@ -0,0 +1,587 @@
+use crate::bundles::BundleStorage;
+use crate::hex_utils;
+use crate::telemetry;
+use crate::{time_now_secs, ChannelManager, PaymentInfoStorage, PeerManager};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::ln::PaymentHash;
+use lightning::util::events::ClosureReason;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+
+/// Every event we notify of, as named in notifications and the config file's `events` list.
+pub(crate) const EVENTS: [&str; 9] = [
+	"payment_received",
+	"payment_sent",
+	"payment_failed",
+	"channel_opened",
+	"channel_closed",
+	"channel_force_closed",
+	"peer_disconnected",
+	"htlc_exposure_high",
+	"htlc_exposure_normal",
+];
+
+/// How often we look for channels which opened and channel peers which disconnected, which LDK
+/// has no events for.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a webhook or command has to take a notification.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where notifications are sent.
+pub(crate) enum NotifyTarget {
+	/// An endpoint of the form `host:port[/path]`, which each notification is POSTed to.
+	Webhook(String),
+	/// A command run with the event's name as its only argument and the notification on stdin.
+	Command(String),
+}
+
+impl NotifyTarget {
+	fn describe(&self) -> String {
+		match self {
+			NotifyTarget::Webhook(endpoint) => format!("webhook {}", endpoint),
+			NotifyTarget::Command(path) => format!("command {}", path),
+		}
+	}
+
+	async fn deliver(&self, event: &str, body: &str) -> io::Result<()> {
+		match self {
+			NotifyTarget::Webhook(endpoint) => {
+				let delivery = telemetry::post_json_acknowledged(endpoint, body, &[]);
+				match tokio::time::timeout(DELIVERY_TIMEOUT, delivery).await {
+					Ok(res) => res,
+					Err(_) => {
+						Err(io::Error::new(io::ErrorKind::TimedOut, "endpoint didn't respond"))
+					}
+				}
+			}
+			NotifyTarget::Command(path) => {
+				let (path, event, body) = (path.clone(), event.to_string(), body.to_string());
+				let run = tokio::task::spawn_blocking(move || -> io::Result<()> {
+					let mut child = Command::new(&path)
+						.arg(event)
+						.stdin(Stdio::piped())
+						.stdout(Stdio::null())
+						.spawn()?;
+					let written = child.stdin.take().unwrap().write_all(body.as_bytes());
+					let status = child.wait()?;
+					written?;
+					if !status.success() {
+						return Err(io::Error::new(
+							io::ErrorKind::Other,
+							format!("{} exited with {}", path, status),
+						));
+					}
+					Ok(())
+				});
+				match tokio::time::timeout(DELIVERY_TIMEOUT, run).await {
+					Ok(Ok(res)) => res,
+					Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, e)),
+					Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "command didn't exit")),
+				}
+			}
+		}
+	}
+}
+
+/// Tells operators' alerting about key events as they happen, by POSTing a JSON notification to
+/// a webhook or running a command with it, so that nobody has to scrape the log. Each notification
+/// is `{"event": <name>, "timestamp": <secs>, ...}` with fields specific to the event.
+///
+/// Notifications are best effort: they're delivered in order, but one which can't be delivered is
+/// dropped rather than retried, and any still queued when the node stops are lost. Settlements
+/// which must not be missed belong on the settlement webhook instead.
+pub(crate) struct Notifier {
+	events: HashSet<String>,
+	sender: mpsc::UnboundedSender<(&'static str, Value)>,
+}
+
+impl Notifier {
+	/// Starts delivering notifications of `events`, or of every event if it's empty, to `targets`.
+	pub(crate) fn start(targets: Vec<NotifyTarget>, events: Vec<String>) -> Self {
+		let (sender, mut receiver) = mpsc::unbounded_channel::<(&'static str, Value)>();
+		tokio::spawn(async move {
+			let mut failing = vec![false; targets.len()];
+			while let Some((event, notification)) = receiver.recv().await {
+				let body = notification.to_string();
+				for (target, failing) in targets.iter().zip(failing.iter_mut()) {
+					// Only warn when deliveries start failing, rather than on every notification.
+					match target.deliver(event, &body).await {
+						Ok(()) if *failing => {
+							*failing = false;
+							eprintln!(
+								"Notifications are being delivered to {} again",
+								target.describe()
+							);
+						}
+						Ok(()) => {}
+						Err(e) => {
+							if !*failing {
+								*failing = true;
+								eprintln!(
+									"Warning: Failed to deliver {} notification to {}: {}",
+									event,
+									target.describe(),
+									e
+								);
+							}
+						}
+					}
+				}
+			}
+		});
+		let events = if events.is_empty() {
+			EVENTS.iter().map(|e| e.to_string()).collect()
+		} else {
+			events.into_iter().collect()
+		};
+		Self { events, sender }
+	}
+
+	/// Queues a notification of `event`, with `fields` added to its name and timestamp.
+	pub(crate) fn notify(&self, event: &'static str, fields: Value) {
+		if !self.events.contains(event) {
+			return;
+		}
+		let mut notification = json!({ "event": event, "timestamp": time_now_secs() });
+		if let (Some(notification), Value::Object(fields)) = (notification.as_object_mut(), fields)
+		{
+			notification.extend(fields);
+		}
+		let _ = self.sender.send((event, notification));
+	}
+
+	/// Notifies of a channel closing, as a force close unless it closed cooperatively or was never
+	/// funded.
+	pub(crate) fn channel_closed(&self, channel_id: &[u8; 32], reason: &ClosureReason) {
+		let event = match reason {
+			ClosureReason::CooperativeClosure | ClosureReason::DisconnectedPeer => "channel_closed",
+			_ => "channel_force_closed",
+		};
+		self.notify(
+			event,
+			json!({ "channel_id": hex_utils::hex_str(channel_id), "reason": format!("{:?}", reason) }),
+		);
+	}
+
+	/// Regularly looks for channels whose funding confirmed and channel peers which disconnected.
+	/// Whatever was already the case when the node started isn't notified of.
+	pub(crate) fn start_watching(
+		notifier: Arc<Self>, channel_manager: Arc<ChannelManager>, peer_manager: Arc<PeerManager>,
+	) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(CHECK_INTERVAL);
+			let mut confirmed: Option<HashSet<[u8; 32]>> = None;
+			let mut connected: Option<HashSet<PublicKey>> = None;
+			loop {
+				interval.tick().await;
+				let channels = channel_manager.list_channels();
+				let now_confirmed: HashSet<[u8; 32]> = channels
+					.iter()
+					.filter(|chan_info| chan_info.is_funding_locked)
+					.map(|chan_info| chan_info.channel_id)
+					.collect();
+				if let Some(confirmed) = &confirmed {
+					for chan_info in channels.iter() {
+						if now_confirmed.contains(&chan_info.channel_id)
+							&& !confirmed.contains(&chan_info.channel_id)
+						{
+							notifier.notify(
+								"channel_opened",
+								json!({
+									"channel_id": hex_utils::hex_str(&chan_info.channel_id),
+									"peer_pubkey": chan_info.counterparty.node_id.to_string(),
+									"channel_value_satoshis": chan_info.channel_value_satoshis,
+									"short_channel_id": chan_info.short_channel_id,
+									"is_outbound": chan_info.is_outbound,
+								}),
+							);
+						}
+					}
+				}
+				confirmed = Some(now_confirmed);
+
+				// Only channel peers are worth alerting about, gossip peers come and go.
+				let channel_peers: HashSet<PublicKey> =
+					channels.iter().map(|chan_info| chan_info.counterparty.node_id).collect();
+				let now_connected: HashSet<PublicKey> = peer_manager
+					.get_peer_node_ids()
+					.into_iter()
+					.filter(|node_id| channel_peers.contains(node_id))
+					.collect();
+				if let Some(connected) = &connected {
+					for node_id in connected.difference(&now_connected) {
+						if channel_peers.contains(node_id) {
+							notifier.notify(
+								"peer_disconnected",
+								json!({ "peer_pubkey": node_id.to_string() }),
+							);
+						}
+					}
+				}
+				connected = Some(now_connected);
+			}
+		});
+	}
+}
+
+/// How long we wait after a settlement for more to arrive, so that a burst is delivered together.
+const SETTLEMENT_BATCH_WINDOW: Duration = Duration::from_secs(1);
+/// The most settlements delivered in a single request.
+const MAX_SETTLEMENT_BATCH_SIZE: usize = 100;
+/// How often settlements which couldn't be delivered are retried.
+const SETTLEMENT_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How long the endpoint has to acknowledge a delivery before we retry it.
+const SETTLEMENT_DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An inbound payment we've claimed, waiting to be delivered to the webhook.
+struct Settlement {
+	payment_hash: PaymentHash,
+	amount_msat: u64,
+	/// When we claimed the payment, in seconds since the UNIX epoch.
+	settled_at: u64,
+	/// The bundle the invoice belongs to, and the bundle's state once this invoice was paid.
+	bundle: Option<(u64, String)>,
+	/// If the invoice was re-issued in place of an expired one, the first invoice it replaces.
+	original_payment_hash: Option<PaymentHash>,
+}
+
+impl Settlement {
+	fn to_json(&self) -> serde_json::Value {
+		json!({
+			"payment_hash": hex_utils::hex_str(&self.payment_hash.0),
+			"amount_msat": self.amount_msat,
+			"settled_at": self.settled_at,
+			"bundle_id": self.bundle.as_ref().map(|(bundle_id, _)| bundle_id),
+			"bundle_status": self.bundle.as_ref().map(|(_, status)| status),
+			"original_payment_hash": self.original_payment_hash.map(|hash| hex_utils::hex_str(&hash.0)),
+		})
+	}
+}
+
+/// An invoice we re-issued automatically as the invoice it replaces expired unpaid, waiting to be
+/// delivered to the webhook so that the merchant can show the payer the new invoice.
+struct Reissue {
+	payment_hash: PaymentHash,
+	reissue_of: PaymentHash,
+	/// The first invoice in the chain of re-issues, i.e. the one the merchant originally handed out.
+	original_payment_hash: PaymentHash,
+	invoice: String,
+}
+
+impl Reissue {
+	fn to_json(&self) -> serde_json::Value {
+		json!({
+			"payment_hash": hex_utils::hex_str(&self.payment_hash.0),
+			"reissue_of": hex_utils::hex_str(&self.reissue_of.0),
+			"original_payment_hash": hex_utils::hex_str(&self.original_payment_hash.0),
+			"invoice": self.invoice,
+		})
+	}
+}
+
+enum OutboxEntry {
+	Settlement(Settlement),
+	Reissue(Reissue),
+}
+
+/// The first invoice in the chain of re-issues leading to `payment_hash`, if it is a re-issue. The
+/// chain stops early at invoices which have already been pruned from the store.
+fn original_payment_hash(
+	payment_hash: &PaymentHash, inbound_payments: &PaymentInfoStorage,
+) -> Option<PaymentHash> {
+	let inbound = inbound_payments.lock().unwrap();
+	let mut original = None;
+	let mut hash = *payment_hash;
+	while let Some(reissue_of) = inbound.get(&hash).and_then(|payment| payment.reissue_of) {
+		original = Some(reissue_of);
+		hash = reissue_of;
+	}
+	original
+}
+
+/// Notifies a merchant backend of every inbound payment we claim, and of invoices we re-issue
+/// automatically. Notifications are written to an outbox on disk before delivery and only removed
+/// once the endpoint acknowledges them with a 2xx response, so none are lost to an unreachable
+/// endpoint or a restart. Delivery is at-least-once: the endpoint may see a notification more than
+/// once and should deduplicate by payment hash, which is unique per settlement and per re-issue.
+pub(crate) struct SettlementWebhook {
+	endpoint: String,
+	secret: String,
+	outbox_path: String,
+	outbox: Mutex<Vec<OutboxEntry>>,
+	invoice_bundles: BundleStorage,
+	new_settlement: Notify,
+}
+
+impl SettlementWebhook {
+	pub(crate) fn new(
+		endpoint: String, secret: String, outbox_path: String, invoice_bundles: BundleStorage,
+	) -> Self {
+		let outbox = Mutex::new(read_outbox(Path::new(&outbox_path)));
+		Self {
+			endpoint,
+			secret,
+			outbox_path,
+			outbox,
+			invoice_bundles,
+			new_settlement: Notify::new(),
+		}
+	}
+
+	/// Queues a claimed payment for delivery. Must not be called with `inbound_payments` locked.
+	pub(crate) fn settled(
+		&self, payment_hash: PaymentHash, amount_msat: u64, inbound_payments: &PaymentInfoStorage,
+	) {
+		let bundle = {
+			let bundles = self.invoice_bundles.lock().unwrap();
+			bundles.bundle_of(&payment_hash).map(|(bundle_id, bundle)| {
+				let status = bundle.status(&inbound_payments.lock().unwrap());
+				(bundle_id, status.as_str().to_string())
+			})
+		};
+		let original_payment_hash = original_payment_hash(&payment_hash, inbound_payments);
+		self.queue(OutboxEntry::Settlement(Settlement {
+			payment_hash,
+			amount_msat,
+			settled_at: time_now_secs(),
+			bundle,
+			original_payment_hash,
+		}));
+	}
+
+	/// Queues an invoice re-issued in place of `reissue_of` for delivery. Must not be called with
+	/// `inbound_payments` locked.
+	pub(crate) fn reissued(
+		&self, payment_hash: PaymentHash, reissue_of: PaymentHash, invoice: String,
+		inbound_payments: &PaymentInfoStorage,
+	) {
+		let original_payment_hash =
+			original_payment_hash(&reissue_of, inbound_payments).unwrap_or(reissue_of);
+		self.queue(OutboxEntry::Reissue(Reissue {
+			payment_hash,
+			reissue_of,
+			original_payment_hash,
+			invoice,
+		}));
+	}
+
+	fn queue(&self, entry: OutboxEntry) {
+		let mut outbox = self.outbox.lock().unwrap();
+		outbox.push(entry);
+		if persist_outbox(Path::new(&self.outbox_path), &outbox).is_err() {
+			eprintln!(
+				"Warning: Failed to persist the webhook outbox, check your disk and permissions"
+			);
+		}
+		self.new_settlement.notify_one();
+	}
+
+	/// The hex HMAC-SHA256 of a request body under the shared secret, which the endpoint recomputes
+	/// to check that a delivery came from us.
+	fn signature(&self, body: &str) -> String {
+		let mut engine = HmacEngine::<Sha256>::new(self.secret.as_bytes());
+		engine.input(body.as_bytes());
+		hex_utils::hex_str(&Hmac::from_engine(engine)[..])
+	}
+
+	/// Delivers the oldest queued notifications in one request, returning how many were delivered.
+	async fn deliver_batch(&self) -> io::Result<usize> {
+		let mut settlements = Vec::new();
+		let mut reissues = Vec::new();
+		{
+			let outbox = self.outbox.lock().unwrap();
+			for entry in outbox.iter().take(MAX_SETTLEMENT_BATCH_SIZE) {
+				match entry {
+					OutboxEntry::Settlement(settlement) => settlements.push(settlement.to_json()),
+					OutboxEntry::Reissue(reissue) => reissues.push(reissue.to_json()),
+				}
+			}
+		}
+		let num_delivered = settlements.len() + reissues.len();
+		if num_delivered == 0 {
+			return Ok(0);
+		}
+		let body = json!({
+			"sent_at": time_now_secs(),
+			"settlements": settlements,
+			"reissues": reissues,
+		})
+		.to_string();
+		let headers = [("X-LDK-Signature", format!("sha256={}", self.signature(&body)))];
+		let delivery = telemetry::post_json_acknowledged(&self.endpoint, &body, &headers);
+		match tokio::time::timeout(SETTLEMENT_DELIVERY_TIMEOUT, delivery).await {
+			Ok(res) => res?,
+			Err(_) => {
+				return Err(io::Error::new(io::ErrorKind::TimedOut, "endpoint didn't respond"))
+			}
+		}
+		// More may have been queued while we were delivering, after those we sent, as only we
+		// remove entries from the outbox.
+		let mut outbox = self.outbox.lock().unwrap();
+		outbox.drain(..num_delivered);
+		if persist_outbox(Path::new(&self.outbox_path), &outbox).is_err() {
+			eprintln!(
+				"Warning: Failed to persist the webhook outbox, check your disk and permissions"
+			);
+		}
+		Ok(num_delivered)
+	}
+
+	/// Delivers queued settlements shortly after they're queued, and retries those which couldn't
+	/// be delivered, including any left over from before a restart.
+	pub(crate) fn start_delivering(webhook: Arc<Self>) {
+		tokio::spawn(async move {
+			let mut failing = false;
+			loop {
+				let _ = tokio::time::timeout(
+					SETTLEMENT_RETRY_INTERVAL,
+					webhook.new_settlement.notified(),
+				)
+				.await;
+				tokio::time::sleep(SETTLEMENT_BATCH_WINDOW).await;
+				loop {
+					// Only warn when deliveries start failing, rather than on every retry.
+					match webhook.deliver_batch().await {
+						Ok(0) => break,
+						Ok(_) if failing => {
+							failing = false;
+							eprintln!(
+								"Settlements are being delivered to {} again",
+								webhook.endpoint
+							);
+						}
+						Ok(_) => {}
+						Err(e) => {
+							if !failing {
+								failing = true;
+								eprintln!(
+									"Warning: Failed to deliver settlements to {}, will retry: {}",
+									webhook.endpoint, e
+								);
+							}
+							break;
+						}
+					}
+				}
+			}
+		});
+	}
+}
+
+fn parse_payment_hash(s: &str) -> Option<PaymentHash> {
+	match hex_utils::to_vec(s) {
+		Some(bytes) if bytes.len() == 32 => {
+			let mut payment_hash = [0; 32];
+			payment_hash.copy_from_slice(&bytes);
+			Some(PaymentHash(payment_hash))
+		}
+		_ => None,
+	}
+}
+
+/// Writes the outbox, one entry per line. Settlements are stored as
+/// `<payment_hash> <amount_msat> <settled_at> <bundle_id|-> <bundle_status|-> <original_payment_hash|->`
+/// and re-issues as `reissue <payment_hash> <reissue_of> <original_payment_hash> <invoice>`.
+fn persist_outbox(path: &Path, outbox: &[OutboxEntry]) -> io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for entry in outbox.iter() {
+		let line = match entry {
+			OutboxEntry::Settlement(settlement) => {
+				let (bundle_id, bundle_status) = match &settlement.bundle {
+					Some((bundle_id, status)) => (bundle_id.to_string(), status.as_str()),
+					None => ("-".to_string(), "-"),
+				};
+				format!(
+					"{} {} {} {} {} {}\n",
+					hex_utils::hex_str(&settlement.payment_hash.0),
+					settlement.amount_msat,
+					settlement.settled_at,
+					bundle_id,
+					bundle_status,
+					settlement
+						.original_payment_hash
+						.map_or("-".to_string(), |hash| hex_utils::hex_str(&hash.0))
+				)
+			}
+			OutboxEntry::Reissue(reissue) => format!(
+				"reissue {} {} {} {}\n",
+				hex_utils::hex_str(&reissue.payment_hash.0),
+				hex_utils::hex_str(&reissue.reissue_of.0),
+				hex_utils::hex_str(&reissue.original_payment_hash.0),
+				reissue.invoice
+			),
+		};
+		file.write_all(line.as_bytes())?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+fn parse_outbox_entry(parts: &[&str]) -> Option<OutboxEntry> {
+	if parts[0] == "reissue" {
+		if parts.len() != 5 {
+			return None;
+		}
+		return Some(OutboxEntry::Reissue(Reissue {
+			payment_hash: parse_payment_hash(parts[1])?,
+			reissue_of: parse_payment_hash(parts[2])?,
+			original_payment_hash: parse_payment_hash(parts[3])?,
+			invoice: parts[4].to_string(),
+		}));
+	}
+	// Outboxes written before invoices could be re-issued lack the original payment hash.
+	if parts.len() != 5 && parts.len() != 6 {
+		return None;
+	}
+	let bundle = match (parts[3], parts[4]) {
+		("-", "-") => None,
+		(bundle_id, status) => Some((bundle_id.parse().ok()?, status.to_string())),
+	};
+	let original_payment_hash = match parts.get(5) {
+		None | Some(&"-") => None,
+		Some(hash) => Some(parse_payment_hash(hash)?),
+	};
+	Some(OutboxEntry::Settlement(Settlement {
+		payment_hash: parse_payment_hash(parts[0])?,
+		amount_msat: parts[1].parse().ok()?,
+		settled_at: parts[2].parse().ok()?,
+		bundle,
+		original_payment_hash,
+	}))
+}
+
+fn read_outbox(path: &Path) -> Vec<OutboxEntry> {
+	let mut outbox = Vec::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return outbox,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		let parts: Vec<&str> = line.split(' ').collect();
+		match parse_outbox_entry(&parts) {
+			Some(entry) => outbox.push(entry),
+			None => eprintln!("Warning: Skipping malformed webhook outbox entry in {:?}", path),
+		}
+	}
+	outbox
+}
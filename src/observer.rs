@@ -0,0 +1,265 @@
+use crate::cli;
+use crate::seed_encryption;
+use crate::storage;
+use crate::storage::Storage;
+use crate::uptime;
+use crate::uptime::PeerUptimeStorage;
+use crate::wal_persister;
+use crate::{PaymentInfoStorage, PaymentInfoStore};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::BlockHash;
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use lightning::chain::chainmonitor;
+use lightning::chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate};
+use lightning::chain::keysinterface::{InMemorySigner, KeysManager};
+use lightning::chain::transaction::OutPoint;
+use lightning::chain::{ChannelMonitorUpdateErr, Filter};
+use lightning::ln::channelmanager;
+use lightning::ln::channelmanager::ChannelManagerReadArgs;
+use lightning::util::config::UserConfig;
+use lightning::util::logger::{Logger, Record};
+use lightning::util::ser::ReadableArgs;
+use lightning_persister::FilesystemPersister;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Cursor, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Stands in for every component which could act on the node's state: transactions are never
+/// broadcast, log lines and monitor updates are never written, and fee estimates are never fetched.
+/// This lets an observer load a data directory while the node which owns it keeps running.
+struct ReadOnly;
+
+impl BroadcasterInterface for ReadOnly {
+	fn broadcast_transaction(&self, _tx: &Transaction) {}
+}
+
+impl FeeEstimator for ReadOnly {
+	fn get_est_sat_per_1000_weight(&self, _confirmation_target: ConfirmationTarget) -> u32 {
+		253
+	}
+}
+
+impl Logger for ReadOnly {
+	fn log(&self, _record: &Record) {}
+}
+
+impl chainmonitor::Persist<InMemorySigner> for ReadOnly {
+	fn persist_new_channel(
+		&self, _funding_txo: OutPoint, _monitor: &ChannelMonitor<InMemorySigner>,
+	) -> Result<(), ChannelMonitorUpdateErr> {
+		Err(ChannelMonitorUpdateErr::TemporaryFailure)
+	}
+
+	fn update_persisted_channel(
+		&self, _funding_txo: OutPoint, _update: &ChannelMonitorUpdate,
+		_monitor: &ChannelMonitor<InMemorySigner>,
+	) -> Result<(), ChannelMonitorUpdateErr> {
+		Err(ChannelMonitorUpdateErr::TemporaryFailure)
+	}
+}
+
+type ChainMonitor = chainmonitor::ChainMonitor<
+	InMemorySigner,
+	Arc<dyn Filter + Send + Sync>,
+	Arc<ReadOnly>,
+	Arc<ReadOnly>,
+	Arc<ReadOnly>,
+	Arc<ReadOnly>,
+>;
+
+type ChannelManager = channelmanager::ChannelManager<
+	InMemorySigner,
+	Arc<ChainMonitor>,
+	Arc<ReadOnly>,
+	Arc<KeysManager>,
+	Arc<ReadOnly>,
+	Arc<ReadOnly>,
+>;
+
+fn read_channel_manager(
+	ldk_data_dir: &str, storage: &dyn Storage,
+) -> Result<ChannelManager, String> {
+	let encrypted_seed_path = format!("{}/keys_seed.enc", ldk_data_dir);
+	let keys_seed = if Path::new(&encrypted_seed_path).exists() {
+		let passphrase = seed_encryption::read_passphrase(None, false)?;
+		seed_encryption::unlock_seed(&encrypted_seed_path, &passphrase)
+			.map_err(|e| format!("failed to unlock the keys seed: {}", e))?
+	} else {
+		let keys_seed_path = format!("{}/keys_seed", ldk_data_dir);
+		let seed = fs::read(&keys_seed_path)
+			.map_err(|e| format!("failed to read keys seed {}: {}", keys_seed_path, e))?;
+		if seed.len() != 32 {
+			return Err(format!("invalid keys seed {}", keys_seed_path));
+		}
+		let mut keys_seed = [0; 32];
+		keys_seed.copy_from_slice(&seed);
+		keys_seed
+	};
+	let cur = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+	let keys_manager = Arc::new(KeysManager::new(&keys_seed, cur.as_secs(), cur.subsec_nanos()));
+
+	let read_only = Arc::new(ReadOnly);
+	let chain_monitor: Arc<ChainMonitor> = Arc::new(chainmonitor::ChainMonitor::new(
+		None,
+		read_only.clone(),
+		read_only.clone(),
+		read_only.clone(),
+		read_only.clone(),
+	));
+
+	// Monitor updates the node hasn't compacted yet are only in the WAL, so apply them in memory
+	// without rewriting anything on disk.
+	let persister = FilesystemPersister::new(ldk_data_dir.to_string());
+	let mut channelmonitors = persister
+		.read_channelmonitors(keys_manager.clone())
+		.map_err(|e| format!("failed to read channel monitors: {}", e))?;
+	let wal_path = format!("{}/monitor_wal", ldk_data_dir);
+	if Path::new(&wal_path).exists() {
+		wal_persister::apply_wal(
+			Path::new(&wal_path),
+			&mut channelmonitors,
+			&read_only,
+			&read_only,
+			&read_only,
+		)
+		.map_err(|e| format!("failed to read channel monitor WAL: {}", e))?;
+	}
+
+	let manager = match storage.read(storage::MANAGER) {
+		Ok(Some(manager)) => manager,
+		Ok(None) => return Err("the node has no ChannelManager".to_string()),
+		Err(e) => return Err(format!("failed to read the ChannelManager: {}", e)),
+	};
+	let mut channel_monitor_mut_references = Vec::new();
+	for (_, channel_monitor) in channelmonitors.iter_mut() {
+		channel_monitor_mut_references.push(channel_monitor);
+	}
+	let read_args = ChannelManagerReadArgs::new(
+		keys_manager,
+		read_only.clone(),
+		chain_monitor,
+		read_only.clone(),
+		read_only,
+		UserConfig::default(),
+		channel_monitor_mut_references,
+	);
+	let (_, channel_manager) =
+		<(BlockHash, ChannelManager)>::read(&mut Cursor::new(manager), read_args)
+			.map_err(|e| format!("failed to read the ChannelManager: {:?}", e))?;
+	Ok(channel_manager)
+}
+
+/// Loads the node state in `<storage_dir>/.ldk` without writing to it, connecting to anyone or
+/// touching any funds, and serves the commands which only read that state.
+pub(crate) fn observe(storage_dir: String) {
+	let ldk_data_dir = format!("{}/.ldk", storage_dir);
+	let storage = match storage::open_storage_read_only(&ldk_data_dir) {
+		Ok(storage) => storage,
+		Err(e) => {
+			println!("ERROR: Unable to open the storage in {}: {}", ldk_data_dir, e);
+			return;
+		}
+	};
+	let channel_manager = match read_channel_manager(&ldk_data_dir, &*storage) {
+		Ok(channel_manager) => channel_manager,
+		Err(e) => {
+			println!("ERROR: Unable to load {}: {}", ldk_data_dir, e);
+			return;
+		}
+	};
+	let inbound_payments: PaymentInfoStorage =
+		Arc::new(Mutex::new(PaymentInfoStore::new(storage.clone(), storage::INBOUND_PAYMENTS)));
+	let outbound_payments: PaymentInfoStorage =
+		Arc::new(Mutex::new(PaymentInfoStore::new(storage.clone(), storage::OUTBOUND_PAYMENTS)));
+	let uptime_path = format!("{}/peer_uptime", ldk_data_dir);
+	let peer_uptime: PeerUptimeStorage =
+		Arc::new(Mutex::new(uptime::read_uptime(Path::new(&uptime_path))));
+
+	println!(
+		"Observing node {} read-only. Enter \"help\" to view available commands. Press Ctrl-D to quit.",
+		channel_manager.get_our_node_id()
+	);
+	print!("> ");
+	io::stdout().flush().unwrap();
+	let stdin = io::stdin();
+	for line in stdin.lock().lines() {
+		let line = line.unwrap();
+		let mut words = line.split_whitespace();
+		if let Some(word) = words.next() {
+			match word {
+				"help" => help(),
+				"nodeinfo" => {
+					println!("\t{{");
+					println!("\t\t node_pubkey: {}", channel_manager.get_our_node_id());
+					println!("\t\t num_channels: {}", channel_manager.list_channels().len());
+					println!("\t}},");
+				}
+				// We don't fetch fee estimates, so can't work out what's spendable right now.
+				"listchannels" => cli::list_channels(
+					channel_manager.list_channels(),
+					channel_manager.current_best_block().height(),
+					None,
+					None,
+				),
+				"listpayments" | "paymenthistory" => {
+					let verbose = match words.next() {
+						Some("--verbose") => true,
+						Some(_) => {
+							println!("ERROR: listpayments usage: `listpayments [--verbose]`");
+							print!("> ");
+							io::stdout().flush().unwrap();
+							continue;
+						}
+						None => false,
+					};
+					cli::list_payments(inbound_payments.clone(), outbound_payments.clone(), verbose)
+				}
+				// We never connect to anyone, so this only shows recorded uptime.
+				"listpeers" => cli::list_peers(Vec::new(), peer_uptime.clone()),
+				"balancehistory" => {
+					let mut num_snapshots = 24;
+					let mut show_channels = false;
+					let mut parse_err = false;
+					for arg in words.by_ref() {
+						match arg {
+							"--channels" => show_channels = true,
+							n => match n.parse() {
+								Ok(n) => num_snapshots = n,
+								Err(_) => parse_err = true,
+							},
+						}
+					}
+					if parse_err {
+						println!("ERROR: balancehistory usage: `balancehistory [<num_snapshots>] [--channels]`");
+						print!("> ");
+						io::stdout().flush().unwrap();
+						continue;
+					}
+					let balance_history_path = format!("{}/balance_history", ldk_data_dir);
+					cli::balance_history(
+						Path::new(&balance_history_path),
+						num_snapshots,
+						show_channels,
+					);
+				}
+				_ => println!(
+					"ERROR: `{}` is not available in observer mode. Use `help` to see what is.",
+					word
+				),
+			}
+		}
+		print!("> ");
+		io::stdout().flush().unwrap();
+	}
+}
+
+fn help() {
+	println!("nodeinfo");
+	println!("listchannels");
+	println!("listpayments [--verbose]");
+	println!("listpeers");
+	println!("balancehistory [<num_snapshots>] [--channels]");
+}
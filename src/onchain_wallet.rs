@@ -0,0 +1,629 @@
+use crate::bitcoind_client::BitcoindClient;
+use crate::convert::{WalletBalances, WalletUtxo};
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{OutPoint, SigHashType, Transaction, TxIn, TxOut};
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::{All, Message, Secp256k1};
+use bitcoin::util::address::Address;
+use bitcoin::util::bip143::SigHashCache;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPrivKey};
+use lightning::chain;
+use lightning::chain::BestBlock;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many unused addresses past the last one we handed out or saw paid we watch on each chain.
+const GAP_LIMIT: u32 = 20;
+
+/// Coinbase outputs can only be spent once they have this many confirmations.
+const COINBASE_MATURITY: u32 = 100;
+
+/// Change smaller than this is left to the fee rather than paid to an output which would cost
+/// about as much to spend as it's worth.
+const DUST_LIMIT_SAT: u64 = 546;
+
+/// The weight of a transaction with no inputs or outputs, including the segwit marker and flag.
+const BASE_TX_WEIGHT: u64 = 4 * (4 + 1 + 1 + 4) + 2;
+
+/// The weight of a P2WPKH input with a signature of the largest size.
+const P2WPKH_INPUT_WEIGHT: u64 = 4 * (32 + 4 + 1 + 4) + 1 + 1 + 72 + 1 + 33;
+
+/// The weight of a P2WPKH output, i.e. of our change.
+const P2WPKH_OUTPUT_WEIGHT: u64 = 4 * (8 + 1 + 22);
+
+/// How often we check that the unconfirmed transactions spending our outputs are still around.
+const SPEND_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long an unconfirmed transaction spending our outputs may be missing from the mempool before
+/// we give up on it and spend its outputs again, e.g. after it was evicted for paying too little or
+/// its broadcast failed. This leaves time for a block we haven't synced yet to confirm it.
+const DROPPED_SPEND_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Keychain {
+	/// The chain of addresses we hand out.
+	Receive = 0,
+	Change = 1,
+}
+
+struct Utxo {
+	value_sat: u64,
+	keychain: Keychain,
+	index: u32,
+	/// The height of the block the output confirmed in, `None` if it hasn't confirmed.
+	confirmed_at: Option<u32>,
+	is_coinbase: bool,
+	/// The transaction we created which spends the output, if any. It hasn't confirmed yet.
+	spent_by: Option<Txid>,
+}
+
+impl Utxo {
+	fn is_mature(&self, tip_height: u32) -> bool {
+		!self.is_coinbase
+			|| self
+				.confirmed_at
+				.map_or(false, |height| tip_height + 1 - height >= COINBASE_MATURITY)
+	}
+}
+
+struct WalletState {
+	best_block: BestBlock,
+	/// The index of the next address to use on each chain.
+	next_index: [u32; 2],
+	/// The scripts of the addresses we watch, `GAP_LIMIT` past `next_index` on each chain.
+	scripts: HashMap<Script, (Keychain, u32)>,
+	/// How many addresses of each chain are in `scripts`.
+	watched: [u32; 2],
+	utxos: HashMap<OutPoint, Utxo>,
+}
+
+/// A P2WPKH wallet derived from the node's seed, which funds channels and receives our swept funds
+/// in place of bitcoind's wallet, so that bitcoind can run with -disablewallet.
+///
+/// Like a BIP84 descriptor wallet, its keys are derived at `m/84'/<coin>'/0'/<chain>/<index>`,
+/// with chain 0 for the addresses we hand out and chain 1 for change. Rather than asking bitcoind
+/// about our outputs, we find them in the blocks we sync along with LDK, so deposits only show up
+/// once they confirm. Change is tracked from when we create the transaction paying it.
+pub(crate) struct OnchainWallet {
+	network: Network,
+	secp_ctx: Secp256k1<All>,
+	/// The extended private keys of the receive and change chains.
+	chains: [ExtendedPrivKey; 2],
+	path: String,
+	state: Mutex<WalletState>,
+}
+
+impl OnchainWallet {
+	/// Opens the wallet stored at `path`, or creates it, starting at `best_block`, if there is
+	/// none. A new wallet has no earlier history to look for, as its keys have never been used.
+	pub(crate) fn new(
+		seed: &[u8; 32], network: Network, path: String, best_block: BestBlock,
+	) -> Result<Self, String> {
+		let secp_ctx = Secp256k1::new();
+		let coin = if network == Network::Bitcoin { 0 } else { 1 };
+		let account_path = [
+			ChildNumber::from_hardened_idx(84).unwrap(),
+			ChildNumber::from_hardened_idx(coin).unwrap(),
+			ChildNumber::from_hardened_idx(0).unwrap(),
+		];
+		let derive_chain = |chain: u32| {
+			ExtendedPrivKey::new_master(network, seed)
+				.and_then(|master| master.derive_priv(&secp_ctx, &account_path))
+				.and_then(|account| {
+					account.ckd_priv(&secp_ctx, ChildNumber::from_normal_idx(chain).unwrap())
+				})
+				.map_err(|e| format!("failed to derive the wallet's keys: {}", e))
+		};
+		let chains = [derive_chain(0)?, derive_chain(1)?];
+		let state = match File::open(&path) {
+			Ok(file) => read_state(file).map_err(|e| format!("failed to read {}: {}", path, e))?,
+			Err(_) => WalletState {
+				best_block,
+				next_index: [0, 0],
+				scripts: HashMap::new(),
+				watched: [0, 0],
+				utxos: HashMap::new(),
+			},
+		};
+		let wallet = Self { network, secp_ctx, chains, path, state: Mutex::new(state) };
+		{
+			let mut state = wallet.state.lock().unwrap();
+			wallet.watch_scripts(&mut state);
+			wallet.persist(&state);
+		}
+		Ok(wallet)
+	}
+
+	fn derive(&self, keychain: Keychain, index: u32) -> ExtendedPrivKey {
+		self.chains[keychain as usize]
+			.ckd_priv(&self.secp_ctx, ChildNumber::from_normal_idx(index).unwrap())
+			.expect(
+				"normal derivation can only fail for an invalid key, with negligible probability",
+			)
+	}
+
+	fn address(&self, keychain: Keychain, index: u32) -> Address {
+		let pubkey = self.derive(keychain, index).private_key.public_key(&self.secp_ctx);
+		Address::p2wpkh(&pubkey, self.network).expect("derived keys are compressed")
+	}
+
+	/// Derives the scripts we need to watch after `next_index` moved on.
+	fn watch_scripts(&self, state: &mut WalletState) {
+		for keychain in [Keychain::Receive, Keychain::Change].iter() {
+			let chain = *keychain as usize;
+			while state.watched[chain] < state.next_index[chain] + GAP_LIMIT {
+				let index = state.watched[chain];
+				state
+					.scripts
+					.insert(self.address(*keychain, index).script_pubkey(), (*keychain, index));
+				state.watched[chain] += 1;
+			}
+		}
+	}
+
+	/// The hash of the block we last synced to.
+	pub(crate) fn best_block_hash(&self) -> BlockHash {
+		self.state.lock().unwrap().best_block.block_hash()
+	}
+
+	/// Hands out a new address to receive funds to. The index is persisted before the address is
+	/// handed out so that we never reuse one, even across restarts.
+	pub(crate) fn new_address(&self) -> Address {
+		let mut state = self.state.lock().unwrap();
+		let index = state.next_index[Keychain::Receive as usize];
+		state.next_index[Keychain::Receive as usize] += 1;
+		self.watch_scripts(&mut state);
+		self.persist(&state);
+		self.address(Keychain::Receive, index)
+	}
+
+	pub(crate) fn balances(&self) -> WalletBalances {
+		let state = self.state.lock().unwrap();
+		let tip_height = state.best_block.height();
+		let mut balances = WalletBalances { confirmed_sat: 0, unconfirmed_sat: 0, immature_sat: 0 };
+		for utxo in state.utxos.values().filter(|utxo| utxo.spent_by.is_none()) {
+			if !utxo.is_mature(tip_height) {
+				balances.immature_sat += utxo.value_sat;
+			} else if utxo.confirmed_at.is_some() {
+				balances.confirmed_sat += utxo.value_sat;
+			} else {
+				balances.unconfirmed_sat += utxo.value_sat;
+			}
+		}
+		balances
+	}
+
+	/// Lists the outputs we can spend, including unconfirmed change.
+	pub(crate) fn list_unspent(&self) -> Vec<WalletUtxo> {
+		let state = self.state.lock().unwrap();
+		let tip_height = state.best_block.height();
+		state
+			.utxos
+			.iter()
+			.filter(|(_, utxo)| utxo.spent_by.is_none() && utxo.is_mature(tip_height))
+			.map(|(outpoint, utxo)| WalletUtxo {
+				txid: outpoint.txid.to_string(),
+				vout: outpoint.vout as u64,
+				amount_sat: utxo.value_sat,
+				confirmations: utxo
+					.confirmed_at
+					.map_or(0, |height| (tip_height + 1 - height) as u64),
+				address: Some(self.address(utxo.keychain, utxo.index).to_string()),
+			})
+			.collect()
+	}
+
+	/// Creates and signs a transaction paying `outputs`, spending our largest outputs first and
+	/// paying any change back to us. The outputs it spends aren't spent again unless it's cancelled
+	/// with `cancel_transaction`, is double-spent or drops out of the mempool without confirming
+	/// (see `watch_spends`). Like the funding transactions bitcoind's wallet creates for us,
+	/// it doesn't signal RBF.
+	pub(crate) fn create_transaction(
+		&self, outputs: Vec<TxOut>, feerate_sat_per_kw: u32, confirmed_only: bool,
+	) -> Result<Transaction, String> {
+		let mut state = self.state.lock().unwrap();
+		let tip_height = state.best_block.height();
+		let mut candidates: Vec<(OutPoint, u64)> = state
+			.utxos
+			.iter()
+			.filter(|(_, utxo)| {
+				utxo.spent_by.is_none()
+					&& utxo.is_mature(tip_height)
+					&& (utxo.confirmed_at.is_some() || !confirmed_only)
+			})
+			.map(|(outpoint, utxo)| (*outpoint, utxo.value_sat))
+			.collect();
+		candidates.sort_by_key(|(_, value_sat)| std::cmp::Reverse(*value_sat));
+		let available_sat: u64 = candidates.iter().map(|(_, value_sat)| value_sat).sum();
+
+		let fee = |weight: u64| (weight * feerate_sat_per_kw as u64 + 999) / 1000;
+		let amount_sat: u64 = outputs.iter().map(|output| output.value).sum();
+		let mut weight = BASE_TX_WEIGHT
+			+ outputs
+				.iter()
+				.map(|output| 4 * (8 + 1 + output.script_pubkey.len() as u64))
+				.sum::<u64>();
+		let mut inputs = Vec::new();
+		let mut selected_sat = 0;
+		let mut change_sat = None;
+		for (outpoint, value_sat) in candidates {
+			inputs.push(outpoint);
+			selected_sat += value_sat;
+			weight += P2WPKH_INPUT_WEIGHT;
+			if selected_sat >= amount_sat + fee(weight) {
+				change_sat = Some(
+					(selected_sat - amount_sat)
+						.checked_sub(fee(weight + P2WPKH_OUTPUT_WEIGHT))
+						.filter(|change_sat| *change_sat >= DUST_LIMIT_SAT),
+				);
+				break;
+			}
+		}
+		let change_sat = match change_sat {
+			Some(change_sat) => change_sat,
+			None => {
+				return Err(format!(
+					"insufficient funds: need {} sat plus fees, but only {} sat{} is available",
+					amount_sat,
+					available_sat,
+					if confirmed_only { " of confirmed outputs" } else { "" }
+				))
+			}
+		};
+
+		let mut tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: inputs
+				.iter()
+				.map(|outpoint| TxIn {
+					previous_output: *outpoint,
+					script_sig: Script::new(),
+					sequence: 0xFFFFFFFE,
+					witness: Vec::new(),
+				})
+				.collect(),
+			output: outputs,
+		};
+		let change_index = state.next_index[Keychain::Change as usize];
+		if let Some(change_sat) = change_sat {
+			state.next_index[Keychain::Change as usize] += 1;
+			self.watch_scripts(&mut state);
+			tx.output.push(TxOut {
+				value: change_sat,
+				script_pubkey: self.address(Keychain::Change, change_index).script_pubkey(),
+			});
+		}
+
+		let mut witnesses = Vec::with_capacity(inputs.len());
+		{
+			let mut sighash_cache = SigHashCache::new(&tx);
+			for (input_idx, outpoint) in inputs.iter().enumerate() {
+				let utxo = &state.utxos[outpoint];
+				let key = self.derive(utxo.keychain, utxo.index);
+				let pubkey = key.private_key.public_key(&self.secp_ctx);
+				// The script code of a P2WPKH input is the corresponding P2PKH script.
+				let script_code = Address::p2pkh(&pubkey, self.network).script_pubkey();
+				let sighash = sighash_cache.signature_hash(
+					input_idx,
+					&script_code,
+					utxo.value_sat,
+					SigHashType::All,
+				);
+				let sig = self
+					.secp_ctx
+					.sign(&Message::from_slice(&sighash[..]).unwrap(), &key.private_key.key);
+				let mut sig = sig.serialize_der().to_vec();
+				sig.push(SigHashType::All as u8);
+				witnesses.push(vec![sig, pubkey.to_bytes()]);
+			}
+		}
+		for (input, witness) in tx.input.iter_mut().zip(witnesses) {
+			input.witness = witness;
+		}
+
+		let txid = tx.txid();
+		for outpoint in inputs.iter() {
+			state.utxos.get_mut(outpoint).unwrap().spent_by = Some(txid);
+		}
+		if let Some(change_sat) = change_sat {
+			let change_outpoint = OutPoint { txid, vout: tx.output.len() as u32 - 1 };
+			state.utxos.insert(
+				change_outpoint,
+				Utxo {
+					value_sat: change_sat,
+					keychain: Keychain::Change,
+					index: change_index,
+					confirmed_at: None,
+					is_coinbase: false,
+					spent_by: None,
+				},
+			);
+		}
+		self.persist(&state);
+		Ok(tx)
+	}
+
+	/// Releases the outputs a transaction from `create_transaction` spends, and forgets its change,
+	/// after it turned out it won't be broadcast.
+	pub(crate) fn cancel_transaction(&self, tx: &Transaction) {
+		let mut state = self.state.lock().unwrap();
+		release_spend(&mut state, tx.txid());
+		self.persist(&state);
+	}
+
+	/// One output of ours each unconfirmed transaction of ours spends, by the transaction's txid.
+	fn pending_spends(&self) -> HashMap<Txid, OutPoint> {
+		let state = self.state.lock().unwrap();
+		state
+			.utxos
+			.iter()
+			.filter_map(|(outpoint, utxo)| utxo.spent_by.map(|txid| (txid, *outpoint)))
+			.collect()
+	}
+
+	/// Regularly checks that the unconfirmed transactions spending our outputs are in the mempool,
+	/// and releases the outputs of one which has been missing for `DROPPED_SPEND_TIMEOUT`, as it
+	/// will likely never confirm. Otherwise its outputs would stay spent, and out of our balance,
+	/// forever.
+	pub(crate) fn watch_spends(self: Arc<Self>, bitcoind_client: Arc<BitcoindClient>) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(SPEND_CHECK_INTERVAL);
+			// When we first found each transaction missing. A restart starts the clock over.
+			let mut missing_since: HashMap<Txid, Instant> = HashMap::new();
+			let mut failing = false;
+			loop {
+				interval.tick().await;
+				// An output of ours which isn't spent in the mempool or on chain means the
+				// transaction of ours spending it is neither.
+				let check = async {
+					let mut missing = HashMap::new();
+					for (txid, outpoint) in self.pending_spends() {
+						if !bitcoind_client.is_spent(&outpoint).await? {
+							let since = missing_since.get(&txid).copied();
+							missing.insert(txid, since.unwrap_or_else(Instant::now));
+						}
+					}
+					Ok::<_, io::Error>(missing)
+				};
+				match check.await {
+					Ok(missing) => {
+						failing = false;
+						missing_since = missing;
+					}
+					// Only warn when checks start failing, rather than every time.
+					Err(e) if !failing => {
+						failing = true;
+						eprintln!(
+							"Warning: Failed to check with {} whether our unconfirmed on-chain transactions are still in the mempool: {}",
+							bitcoind_client.chain_source_name(),
+							e
+						);
+						continue;
+					}
+					Err(_) => continue,
+				}
+				let dropped: Vec<Txid> = missing_since
+					.iter()
+					.filter(|(_, since)| since.elapsed() >= DROPPED_SPEND_TIMEOUT)
+					.map(|(txid, _)| *txid)
+					.collect();
+				for txid in dropped {
+					missing_since.remove(&txid);
+					let released = {
+						let mut state = self.state.lock().unwrap();
+						let released = release_spend(&mut state, txid);
+						self.persist(&state);
+						released
+					};
+					// It may have confirmed since we checked, leaving nothing to release.
+					if released > 0 {
+						println!(
+							"\nEVENT: Our transaction {} dropped out of the mempool without confirming, {} output(s) it spent can be spent again",
+							txid, released
+						);
+						print!("> ");
+						io::stdout().flush().unwrap();
+					}
+				}
+			}
+		});
+	}
+
+	fn persist(&self, state: &WalletState) {
+		if persist_state(Path::new(&self.path), state).is_err() {
+			eprintln!(
+				"Warning: Failed to persist the on-chain wallet, check your disk and permissions"
+			);
+		}
+	}
+}
+
+impl chain::Listen for OnchainWallet {
+	fn block_connected(&self, block: &Block, height: u32) {
+		let mut state = self.state.lock().unwrap();
+		// Our unconfirmed transactions which spend the same outputs as one in the block.
+		let mut conflicted = Vec::new();
+		for tx in block.txdata.iter() {
+			let txid = tx.txid();
+			for input in tx.input.iter() {
+				let spent_by =
+					state.utxos.remove(&input.previous_output).and_then(|utxo| utxo.spent_by);
+				if let Some(spent_by) = spent_by.filter(|spent_by| *spent_by != txid) {
+					conflicted.push(spent_by);
+				}
+			}
+			for (vout, output) in tx.output.iter().enumerate() {
+				let (keychain, index) = match state.scripts.get(&output.script_pubkey) {
+					Some(script) => *script,
+					None => continue,
+				};
+				let outpoint = OutPoint { txid, vout: vout as u32 };
+				// Our unconfirmed change may already be spent by another transaction of ours.
+				let spent_by = state.utxos.get(&outpoint).and_then(|utxo| utxo.spent_by);
+				state.utxos.insert(
+					outpoint,
+					Utxo {
+						value_sat: output.value,
+						keychain,
+						index,
+						confirmed_at: Some(height),
+						is_coinbase: tx.is_coin_base(),
+						spent_by,
+					},
+				);
+				if index >= state.next_index[keychain as usize] {
+					state.next_index[keychain as usize] = index + 1;
+					self.watch_scripts(&mut state);
+				}
+			}
+		}
+		for txid in conflicted {
+			release_spend(&mut state, txid);
+		}
+		state.best_block = BestBlock::new(block.block_hash(), height);
+		self.persist(&state);
+	}
+
+	fn block_disconnected(&self, header: &BlockHeader, height: u32) {
+		let mut state = self.state.lock().unwrap();
+		// A reorg may confirm our transactions again in another block, but the coinbase outputs of
+		// a disconnected block are gone for good. Outputs spent in the block stay forgotten, as we
+		// no longer have them to put back, so a reorg may hide funds until the wallet is restored.
+		state.utxos.retain(|_, utxo| !(utxo.is_coinbase && utxo.confirmed_at == Some(height)));
+		for utxo in state.utxos.values_mut() {
+			if utxo.confirmed_at == Some(height) {
+				utxo.confirmed_at = None;
+			}
+		}
+		state.best_block = BestBlock::new(header.prev_blockhash, height - 1);
+		self.persist(&state);
+	}
+}
+
+/// Releases the outputs our transaction `txid` spends, and forgets its unconfirmed outputs, after
+/// it turned out it won't confirm. The same goes for any transaction of ours spending those in turn.
+/// Returns how many outputs were released.
+fn release_spend(state: &mut WalletState, txid: Txid) -> usize {
+	let mut released = 0;
+	let mut dropped = vec![txid];
+	while let Some(txid) = dropped.pop() {
+		for (outpoint, utxo) in state.utxos.iter_mut() {
+			if utxo.spent_by == Some(txid) {
+				utxo.spent_by = None;
+				released += 1;
+			} else if outpoint.txid == txid && utxo.confirmed_at.is_none() {
+				dropped.extend(utxo.spent_by);
+			}
+		}
+		state.utxos.retain(|outpoint, utxo| outpoint.txid != txid || utxo.confirmed_at.is_some());
+	}
+	released
+}
+
+/// Writes the wallet's state: `best_block <hash> <height>`, `next_index <receive> <change>`, then
+/// one `utxo <txid>:<vout> <value_sat> <chain> <index> <height|-> <coinbase> <spent_by|->` line per
+/// output, `coinbase` being 0 or 1 and `spent_by` the txid of our transaction spending it.
+fn persist_state(path: &Path, state: &WalletState) -> io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	file.write_all(
+		format!(
+			"best_block {} {}\nnext_index {} {}\n",
+			state.best_block.block_hash(),
+			state.best_block.height(),
+			state.next_index[0],
+			state.next_index[1]
+		)
+		.as_bytes(),
+	)?;
+	for (outpoint, utxo) in state.utxos.iter() {
+		file.write_all(
+			format!(
+				"utxo {} {} {} {} {} {} {}\n",
+				outpoint,
+				utxo.value_sat,
+				utxo.keychain as u8,
+				utxo.index,
+				utxo.confirmed_at.map_or("-".to_string(), |height| height.to_string()),
+				utxo.is_coinbase as u8,
+				utxo.spent_by.map_or("-".to_string(), |txid| txid.to_string())
+			)
+			.as_bytes(),
+		)?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+/// Reads the state written by `persist_state`. Unlike most of our stores, a malformed line fails
+/// the whole read rather than being skipped, as skipping one would lose track of funds.
+fn read_state(file: File) -> io::Result<WalletState> {
+	let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed wallet state");
+	let mut best_block = None;
+	let mut next_index = None;
+	let mut utxos = HashMap::new();
+	for line in BufReader::new(file).lines() {
+		let line = line?;
+		let parts: Vec<&str> = line.split(' ').collect();
+		match (parts[0], parts.len()) {
+			("best_block", 3) => {
+				let block_hash = BlockHash::from_str(parts[1]).map_err(|_| malformed())?;
+				let height = parts[2].parse().map_err(|_| malformed())?;
+				best_block = Some(BestBlock::new(block_hash, height));
+			}
+			("next_index", 3) => {
+				next_index = Some([
+					parts[1].parse().map_err(|_| malformed())?,
+					parts[2].parse().map_err(|_| malformed())?,
+				]);
+			}
+			("utxo", 8) => {
+				let outpoint = OutPoint::from_str(parts[1]).map_err(|_| malformed())?;
+				let keychain = match parts[3] {
+					"0" => Keychain::Receive,
+					"1" => Keychain::Change,
+					_ => return Err(malformed()),
+				};
+				let confirmed_at = match parts[5] {
+					"-" => None,
+					height => Some(height.parse().map_err(|_| malformed())?),
+				};
+				let spent_by = match parts[7] {
+					"-" => None,
+					txid => Some(Txid::from_str(txid).map_err(|_| malformed())?),
+				};
+				utxos.insert(
+					outpoint,
+					Utxo {
+						value_sat: parts[2].parse().map_err(|_| malformed())?,
+						keychain,
+						index: parts[4].parse().map_err(|_| malformed())?,
+						confirmed_at,
+						is_coinbase: parts[6] == "1",
+						spent_by,
+					},
+				);
+			}
+			_ => return Err(malformed()),
+		}
+	}
+	Ok(WalletState {
+		best_block: best_block.ok_or_else(malformed)?,
+		next_index: next_index.ok_or_else(malformed)?,
+		scripts: HashMap::new(),
+		watched: [0, 0],
+		utxos,
+	})
+}
@@ -0,0 +1,253 @@
+use crate::bitcoind_client::BitcoindClient;
+use crate::cli;
+use crate::disk;
+use crate::hex_utils;
+use crate::pause::PauseSwitch;
+use crate::peer_limits::PeerLimitsStorage;
+use crate::storage::Storage;
+use crate::tor::PeerAddr;
+use crate::{ChannelManager, PeerManager};
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::util::config::UserConfig;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many queued opens are in flight at once, unless configured.
+pub(crate) const DEFAULT_MAX_CONCURRENT_OPENS: usize = 1;
+
+/// How many times an open is tried before we give up on it.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How long we wait before trying a failed open again, multiplied by the attempts made so far.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub(crate) enum OpenState {
+	/// Waiting for a free slot, or, if it failed before, for `retry_at`.
+	Queued { retry_at: Option<Instant> },
+	/// Connecting to the peer.
+	Connecting,
+	/// The channel is being negotiated and funded.
+	Opening { user_channel_id: u64 },
+	/// The channel's funding transaction was broadcast.
+	Opened { channel_id: [u8; 32] },
+	/// Every attempt failed, see `last_error`.
+	Failed,
+}
+
+pub(crate) struct QueuedOpen {
+	pub(crate) id: u64,
+	pub(crate) peer_pubkey: PublicKey,
+	/// The peer as given to `openqueue add`, `pubkey@host:port`.
+	pub(crate) peer_pubkey_and_addr: String,
+	peer_addr: PeerAddr,
+	pub(crate) channel_amt_sat: u64,
+	pub(crate) announced: bool,
+	pub(crate) attempts: u32,
+	pub(crate) state: OpenState,
+	pub(crate) last_error: Option<String>,
+}
+
+impl QueuedOpen {
+	fn in_flight(&self) -> bool {
+		match self.state {
+			OpenState::Connecting | OpenState::Opening { .. } => true,
+			_ => false,
+		}
+	}
+
+	fn failed(&mut self, error: String) {
+		self.attempts += 1;
+		println!(
+			"\nEVENT: Queued open #{} with {} failed (attempt {} of {}): {}",
+			self.id, self.peer_pubkey, self.attempts, MAX_ATTEMPTS, error
+		);
+		self.state = if self.attempts >= MAX_ATTEMPTS {
+			OpenState::Failed
+		} else {
+			OpenState::Queued { retry_at: Some(Instant::now() + RETRY_DELAY * self.attempts) }
+		};
+		self.last_error = Some(error);
+	}
+}
+
+struct QueueState {
+	next_id: u64,
+	opens: Vec<QueuedOpen>,
+}
+
+/// Opens channels one after another, or a few at a time if configured, so that scripted bulk opens
+/// don't race each other for the funding wallet's UTXOs. An open stays in flight until its funding
+/// transaction is broadcast, as that's when the wallet knows its inputs are spent. Opens which fail,
+/// e.g. because the peer was unreachable or the channel was rejected, are retried with a growing
+/// delay.
+///
+/// No open is started while the node is paused, opens waiting for a slot stay queued until it's
+/// resumed.
+///
+/// The queue is only kept while the node runs, so opens still queued at shutdown are forgotten.
+pub(crate) struct OpenQueue {
+	max_concurrent: usize,
+	state: Mutex<QueueState>,
+}
+
+impl OpenQueue {
+	pub(crate) fn new(max_concurrent: usize) -> Self {
+		Self { max_concurrent, state: Mutex::new(QueueState { next_id: 1, opens: Vec::new() }) }
+	}
+
+	/// Queues an open, returning its id.
+	pub(crate) fn add(
+		&self, peer_pubkey: PublicKey, peer_pubkey_and_addr: String, peer_addr: PeerAddr,
+		channel_amt_sat: u64, announced: bool,
+	) -> u64 {
+		let mut state = self.state.lock().unwrap();
+		let id = state.next_id;
+		state.next_id += 1;
+		state.opens.push(QueuedOpen {
+			id,
+			peer_pubkey,
+			peer_pubkey_and_addr,
+			peer_addr,
+			channel_amt_sat,
+			announced,
+			attempts: 0,
+			state: OpenState::Queued { retry_at: None },
+			last_error: None,
+		});
+		id
+	}
+
+	/// Calls `f` with every open queued since the node started, oldest first.
+	pub(crate) fn with_opens<F: FnOnce(&[QueuedOpen])>(&self, f: F) {
+		f(&self.state.lock().unwrap().opens)
+	}
+
+	/// Moves opens whose channel was funded or went away along, and returns those to start now,
+	/// none if the node is `paused`.
+	fn update(
+		&self, channel_manager: &ChannelManager, bitcoind_client: &BitcoindClient,
+		storage: &dyn Storage, paused: bool,
+	) -> Vec<(u64, PublicKey, PeerAddr)> {
+		let channels = channel_manager.list_channels();
+		let mut state = self.state.lock().unwrap();
+		for open in state.opens.iter_mut() {
+			let user_channel_id = match open.state {
+				OpenState::Opening { user_channel_id } => user_channel_id,
+				_ => continue,
+			};
+			match channels.iter().find(|c| c.user_channel_id == user_channel_id) {
+				Some(chan_info) => {
+					let broadcast = chan_info.funding_txo.map_or(false, |funding_txo| {
+						bitcoind_client.fee_snapshots().with_snapshot(&funding_txo.txid, |_| {})
+					});
+					if broadcast {
+						println!(
+							"\nEVENT: Queued open #{} funded channel {}",
+							open.id,
+							hex_utils::hex_str(&chan_info.channel_id)
+						);
+						open.state = OpenState::Opened { channel_id: chan_info.channel_id };
+						let _ = disk::persist_channel_peer(storage, &open.peer_pubkey_and_addr);
+					}
+				}
+				None => open.failed("the channel closed before it was funded".to_string()),
+			}
+		}
+
+		let now = Instant::now();
+		let mut free_slots = self
+			.max_concurrent
+			.saturating_sub(state.opens.iter().filter(|o| o.in_flight()).count());
+		let mut to_start = Vec::new();
+		if paused {
+			return to_start;
+		}
+		for open in state.opens.iter_mut() {
+			if free_slots == 0 {
+				break;
+			}
+			match open.state {
+				OpenState::Queued { retry_at } if retry_at.map_or(true, |at| at <= now) => {}
+				_ => continue,
+			}
+			open.state = OpenState::Connecting;
+			to_start.push((open.id, open.peer_pubkey, open.peer_addr.clone()));
+			free_slots -= 1;
+		}
+		to_start
+	}
+
+	/// Opens the channel for `id` once we're connected to its peer, unless the node was paused
+	/// while we connected, in which case it goes back in the queue.
+	fn start(
+		&self, id: u64, connected: bool, paused: bool, channel_manager: &Arc<ChannelManager>,
+		user_config: UserConfig, peer_limits: &PeerLimitsStorage,
+	) {
+		let mut state = self.state.lock().unwrap();
+		let open = match state.opens.iter_mut().find(|o| o.id == id) {
+			Some(open) => open,
+			None => return,
+		};
+		if paused {
+			open.state = OpenState::Queued { retry_at: None };
+			return;
+		}
+		if !connected {
+			open.failed("failed to connect to the peer".to_string());
+			return;
+		}
+		match cli::open_channel(
+			open.peer_pubkey,
+			open.channel_amt_sat,
+			open.announced,
+			user_config,
+			channel_manager.clone(),
+			peer_limits,
+			None,
+		) {
+			Ok(user_channel_id) => open.state = OpenState::Opening { user_channel_id },
+			Err(()) => open.failed("the channel couldn't be created, see the log".to_string()),
+		}
+	}
+
+	pub(crate) fn start_processing(
+		queue: Arc<Self>, channel_manager: Arc<ChannelManager>, peer_manager: Arc<PeerManager>,
+		bitcoind_client: Arc<BitcoindClient>, storage: Arc<dyn Storage>, user_config: UserConfig,
+		peer_limits: PeerLimitsStorage, proxy_addr: Option<SocketAddr>,
+		pause_switch: Arc<PauseSwitch>,
+	) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(CHECK_INTERVAL);
+			loop {
+				interval.tick().await;
+				let to_start = queue.update(
+					&channel_manager,
+					&bitcoind_client,
+					&*storage,
+					pause_switch.is_paused(),
+				);
+				for (id, peer_pubkey, peer_addr) in to_start {
+					let connected = cli::connect_peer_if_necessary(
+						peer_pubkey,
+						peer_addr,
+						peer_manager.clone(),
+						proxy_addr,
+					)
+					.await
+					.is_ok();
+					queue.start(
+						id,
+						connected,
+						pause_switch.is_paused(),
+						&channel_manager,
+						user_config,
+						&peer_limits,
+					);
+				}
+			}
+		});
+	}
+}
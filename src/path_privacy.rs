@@ -0,0 +1,188 @@
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::routing::network_graph::{
+	DirectionalChannelInfo, NetworkGraph, NodeId, ReadOnlyNetworkGraph,
+};
+use lightning::routing::router::{Route, RouteHop};
+use rand::{thread_rng, Rng};
+use std::sync::Arc;
+
+/// The most blocks the final CLTV is randomly raised by.
+const MAX_FINAL_CLTV_JITTER: u32 = 72;
+
+/// How many hops past the recipient a shadow route may pretend to go.
+const MAX_SHADOW_HOPS: usize = 3;
+
+/// A shadow route stops before its fees would exceed this share of the amount, in millionths, so
+/// that it never costs more than a little.
+const MAX_SHADOW_FEE_PROPORTIONAL_MILLIONTHS: u64 = 5_000;
+
+/// Nodes refuse HTLCs which expire further out than this, a week, so padding never takes a path
+/// past it.
+const MAX_TOTAL_CLTV_EXPIRY_DELTA: u32 = 1008;
+
+/// How an outbound payment hides that we're its sender and who its recipient is from the hops
+/// along its path, in particular the last one.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct PathPrivacy {
+	/// Pads the payment as if it continued past the recipient along a random path: the path's CLTV
+	/// deltas are added to the final CLTV, and its fees are overpaid to the recipient.
+	pub(crate) shadow_route: bool,
+	/// Raises the final CLTV by a random number of blocks, so that it doesn't give away that the
+	/// next hop is the recipient.
+	pub(crate) randomize_final_cltv: bool,
+}
+
+/// A payment's own choices, overriding the node's defaults where set.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct PrivacyOverrides {
+	pub(crate) shadow_route: Option<bool>,
+	pub(crate) randomize_final_cltv: Option<bool>,
+}
+
+impl PathPrivacy {
+	pub(crate) fn with(self, overrides: PrivacyOverrides) -> Self {
+		Self {
+			shadow_route: overrides.shadow_route.unwrap_or(self.shadow_route),
+			randomize_final_cltv: overrides
+				.randomize_final_cltv
+				.unwrap_or(self.randomize_final_cltv),
+		}
+	}
+}
+
+/// The direction of channel `short_channel_id` going out from `node_id`, if the graph knows it.
+fn channel_from<'a>(
+	graph: &'a ReadOnlyNetworkGraph, short_channel_id: u64, node_id: &NodeId,
+) -> Option<(&'a DirectionalChannelInfo, NodeId)> {
+	let chan = graph.channels().get(&short_channel_id)?;
+	if chan.node_one == *node_id {
+		Some((chan.one_to_two.as_ref()?, chan.node_two))
+	} else if chan.node_two == *node_id {
+		Some((chan.two_to_one.as_ref()?, chan.node_one))
+	} else {
+		None
+	}
+}
+
+/// Pads routes we send payments over as their `PathPrivacy` asks.
+pub(crate) struct PathPadder {
+	network_graph: Arc<NetworkGraph>,
+	defaults: PathPrivacy,
+}
+
+impl PathPadder {
+	pub(crate) fn new(network_graph: Arc<NetworkGraph>, defaults: PathPrivacy) -> Self {
+		Self { network_graph, defaults }
+	}
+
+	pub(crate) fn defaults(&self) -> PathPrivacy {
+		self.defaults
+	}
+
+	/// Walks randomly from `recipient` over enabled public channels, returning the CLTV deltas and
+	/// fees for `amt_msat` a payment continuing along the walk would add up.
+	fn shadow_walk(&self, recipient: &PublicKey, amt_msat: u64) -> (u32, u64) {
+		let graph = self.network_graph.read_only();
+		let max_fee_msat = amt_msat * MAX_SHADOW_FEE_PROPORTIONAL_MILLIONTHS / 1_000_000;
+		let mut rng = thread_rng();
+		let mut node_id = NodeId::from_pubkey(recipient);
+		let (mut cltv, mut fee_msat) = (0, 0);
+		for _ in 0..MAX_SHADOW_HOPS {
+			let next_hops: Vec<(&DirectionalChannelInfo, NodeId)> =
+				match graph.nodes().get(&node_id) {
+					Some(node) => node
+						.channels
+						.iter()
+						.filter_map(|scid| channel_from(&graph, *scid, &node_id))
+						.filter(|(info, _)| info.enabled)
+						.collect(),
+					None => break,
+				};
+			if next_hops.is_empty() {
+				break;
+			}
+			let (info, next_node) = next_hops[rng.gen_range(0, next_hops.len())];
+			let hop_fee_msat = info.fees.base_msat as u64
+				+ amt_msat * info.fees.proportional_millionths as u64 / 1_000_000;
+			if fee_msat + hop_fee_msat > max_fee_msat {
+				break;
+			}
+			cltv += info.cltv_expiry_delta as u32;
+			fee_msat += hop_fee_msat;
+			node_id = next_node;
+			// Walk on to a further hop only half the time, so shadow routes vary in length.
+			if rng.gen() {
+				break;
+			}
+		}
+		(cltv, fee_msat)
+	}
+
+	/// Raises what `path` delivers by `extra_msat`, raising each hop's fee for forwarding the
+	/// larger amount. Fails if a hop's fees aren't in the graph, e.g. over an invoice's private
+	/// route hints, a channel can't carry the larger amount, or the extra amount and fees together
+	/// come to more than `max_cost_msat`.
+	fn pad_amount(
+		&self, path: &mut Vec<RouteHop>, extra_msat: u64, max_cost_msat: u64,
+	) -> Option<()> {
+		let graph = self.network_graph.read_only();
+		let mut padded = path.clone();
+		padded.last_mut()?.fee_msat += extra_msat;
+		let mut extra_msat = extra_msat;
+		for idx in (0..padded.len() - 1).rev() {
+			let node_id = NodeId::from_pubkey(&padded[idx].pubkey);
+			let (info, _) = channel_from(&graph, padded[idx + 1].short_channel_id, &node_id)?;
+			let forwarded_msat: u64 = padded[idx + 1..].iter().map(|hop| hop.fee_msat).sum();
+			if info.htlc_maximum_msat.map_or(false, |max| forwarded_msat > max) {
+				return None;
+			}
+			let extra_fee_msat =
+				(extra_msat * info.fees.proportional_millionths as u64 + 999_999) / 1_000_000;
+			padded[idx].fee_msat += extra_fee_msat;
+			extra_msat += extra_fee_msat;
+		}
+		if extra_msat > max_cost_msat {
+			return None;
+		}
+		*path = padded;
+		Some(())
+	}
+
+	/// Returns `route` padded as `privacy` asks. The amount is only padded on a payment's first
+	/// attempt and only if it's sent over a single path, as retries and multi-path payments must
+	/// add up to what the first attempt committed to, and only if padding it costs at most
+	/// `max_cost_msat`, what's left of the payment's fee limits once `route`'s own fees are paid.
+	pub(crate) fn pad_route(
+		&self, route: &Route, privacy: PathPrivacy, first_attempt: bool, max_cost_msat: u64,
+	) -> Route {
+		let mut route = route.clone();
+		if !privacy.shadow_route && !privacy.randomize_final_cltv {
+			return route;
+		}
+		let single_path = route.paths.len() == 1;
+		for path in route.paths.iter_mut() {
+			let (recipient, amt_msat) = match path.last() {
+				Some(hop) => (hop.pubkey, hop.fee_msat),
+				None => continue,
+			};
+			let mut extra_cltv = 0;
+			if privacy.shadow_route {
+				let (shadow_cltv, shadow_fee_msat) = self.shadow_walk(&recipient, amt_msat);
+				extra_cltv += shadow_cltv;
+				if first_attempt && single_path && shadow_fee_msat > 0 {
+					// Without fees to pad with, the CLTV padding alone still helps.
+					let _ = self.pad_amount(path, shadow_fee_msat, max_cost_msat);
+				}
+			}
+			if privacy.randomize_final_cltv {
+				extra_cltv += thread_rng().gen_range(0, MAX_FINAL_CLTV_JITTER + 1);
+			}
+			let total_cltv: u32 = path.iter().map(|hop| hop.cltv_expiry_delta).sum();
+			let extra_cltv = extra_cltv.min(MAX_TOTAL_CLTV_EXPIRY_DELTA.saturating_sub(total_cltv));
+			if let Some(last_hop) = path.last_mut() {
+				last_hop.cltv_expiry_delta += extra_cltv;
+			}
+		}
+		route
+	}
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A node-wide switch which stops us from moving funds, e.g. during maintenance or while
+/// responding to an incident. While paused we don't send payments, open or accept channels or
+/// process incoming HTLCs, though we keep syncing the chain and watching our channels.
+///
+/// The switch is backed by a marker file so that a paused node stays paused across restarts.
+///
+/// The node is also held, independently of the switch, while its disk can't safely take more
+/// channel updates, see `DiskMonitor`, and may hold forwards or refuse new channels during a
+/// maintenance window, see `MaintenanceSchedule`.
+pub(crate) struct PauseSwitch {
+	path: String,
+	paused: AtomicBool,
+	held_for_disk: AtomicBool,
+	forwards_held_for_maintenance: AtomicBool,
+	channels_refused_for_maintenance: AtomicBool,
+}
+
+impl PauseSwitch {
+	pub(crate) fn new(path: String) -> Self {
+		let paused = AtomicBool::new(Path::new(&path).exists());
+		Self {
+			path,
+			paused,
+			held_for_disk: AtomicBool::new(false),
+			forwards_held_for_maintenance: AtomicBool::new(false),
+			channels_refused_for_maintenance: AtomicBool::new(false),
+		}
+	}
+
+	pub(crate) fn is_paused(&self) -> bool {
+		self.paused.load(Ordering::Acquire) || self.is_held_for_disk()
+	}
+
+	pub(crate) fn is_held_for_disk(&self) -> bool {
+		self.held_for_disk.load(Ordering::Acquire)
+	}
+
+	pub(crate) fn set_held_for_disk(&self, held: bool) {
+		self.held_for_disk.store(held, Ordering::Release);
+	}
+
+	/// Whether incoming HTLCs, both payments to us and forwards, are held rather than processed.
+	pub(crate) fn holds_forwards(&self) -> bool {
+		self.is_paused() || self.forwards_held_for_maintenance.load(Ordering::Acquire)
+	}
+
+	/// Whether inbound channel opens are refused.
+	pub(crate) fn refuses_channels(&self) -> bool {
+		self.is_paused() || self.channels_refused_for_maintenance.load(Ordering::Acquire)
+	}
+
+	pub(crate) fn set_held_for_maintenance(&self, hold_forwards: bool, refuse_channels: bool) {
+		self.forwards_held_for_maintenance.store(hold_forwards, Ordering::Release);
+		self.channels_refused_for_maintenance.store(refuse_channels, Ordering::Release);
+	}
+
+	pub(crate) fn pause(&self) -> std::io::Result<()> {
+		fs::write(&self.path, b"")?;
+		self.paused.store(true, Ordering::Release);
+		Ok(())
+	}
+
+	pub(crate) fn resume(&self) -> std::io::Result<()> {
+		match fs::remove_file(&self.path) {
+			Ok(()) => {}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+			Err(e) => return Err(e),
+		}
+		self.paused.store(false, Ordering::Release);
+		Ok(())
+	}
+}
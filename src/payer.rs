@@ -0,0 +1,272 @@
+use crate::fee_budget;
+use crate::fee_budget::FeeBudget;
+use crate::path_privacy::{PathPadder, PrivacyOverrides};
+use crate::{time_now_secs, ChannelManager, PaymentInfoStorage};
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::ln::channelmanager::{ChannelDetails, PaymentId, PaymentSendFailure};
+use lightning::ln::{PaymentHash, PaymentSecret};
+use lightning::routing::router::{Route, RouteHop};
+use lightning::util::errors::APIError;
+use lightning_invoice::payment::Payer;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Paths which were handed off to the ChannelManager for a single payment.
+struct PendingRoute {
+	payment_hash: PaymentHash,
+	paths: Vec<Vec<RouteHop>>,
+}
+
+/// Per-payment limits on how hard we try to complete a payment. These can only tighten the
+/// node-wide limits: the `InvoicePayer` still stops after its configured number of retries, and
+/// the router still refuses routes over the node's fee budget.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct RetryLimits {
+	/// How many times failed paths may be retried.
+	pub(crate) max_retries: Option<usize>,
+	/// The most any single attempt may pay in routing fees.
+	pub(crate) max_fee_msat: Option<u64>,
+	/// When to stop retrying, in seconds since the UNIX epoch. If the payment hasn't completed by
+	/// then it's reported as timed out until its parts in flight resolve.
+	pub(crate) retry_until: Option<u64>,
+	/// When to abandon the payment, in seconds since the UNIX epoch. Retries stop at the deadline,
+	/// and if the payment hasn't completed by then it's reported as failed.
+	pub(crate) deadline: Option<u64>,
+}
+
+struct RetryState {
+	limits: RetryLimits,
+	retries: usize,
+}
+
+fn limit_exceeded(err: String) -> PaymentSendFailure {
+	PaymentSendFailure::ParameterError(APIError::APIMisuseError { err })
+}
+
+/// A [`Payer`] which sends payments via our ChannelManager while remembering the paths each
+/// payment was sent over, so that we can tell the user which route a payment took once it
+/// succeeds. It also enforces any [`RetryLimits`] set for a payment, by refusing to send attempts
+/// beyond them, which makes the `InvoicePayer` give up and report the failure, and pads the routes
+/// it sends over as the payment's privacy options ask. Padding only spends what the payment's fee
+/// limit and the node's fee budgets leave over after the route's own fees.
+pub(crate) struct RouteRecordingPayer {
+	channel_manager: Arc<ChannelManager>,
+	path_padder: PathPadder,
+	fee_budget: FeeBudget,
+	outbound_payments: PaymentInfoStorage,
+	pending_routes: Mutex<HashMap<PaymentId, PendingRoute>>,
+	retry_limits: Mutex<HashMap<PaymentHash, RetryState>>,
+	privacy: Mutex<HashMap<PaymentHash, PrivacyOverrides>>,
+}
+
+impl RouteRecordingPayer {
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, path_padder: PathPadder, fee_budget: FeeBudget,
+		outbound_payments: PaymentInfoStorage,
+	) -> Self {
+		Self {
+			channel_manager,
+			path_padder,
+			fee_budget,
+			outbound_payments,
+			pending_routes: Mutex::new(HashMap::new()),
+			retry_limits: Mutex::new(HashMap::new()),
+			privacy: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Sets how a payment we're about to send differs from the node's default privacy options.
+	pub(crate) fn set_privacy(&self, payment_hash: PaymentHash, overrides: PrivacyOverrides) {
+		self.privacy.lock().unwrap().insert(payment_hash, overrides);
+	}
+
+	fn pad_route(&self, payment_hash: &PaymentHash, route: &Route, first_attempt: bool) -> Route {
+		let overrides = self.privacy.lock().unwrap().get(payment_hash).copied().unwrap_or_default();
+		let privacy = self.path_padder.defaults().with(overrides);
+		// Only first attempts have their amount padded.
+		let max_cost_msat =
+			if first_attempt { self.padding_budget_msat(payment_hash, route) } else { 0 };
+		self.path_padder.pad_route(route, privacy, first_attempt, max_cost_msat)
+	}
+
+	/// How much padding may add to what `route` costs, so that the padded route still keeps to the
+	/// payment's `max_fee_msat` and the node's fee budgets.
+	fn padding_budget_msat(&self, payment_hash: &PaymentHash, route: &Route) -> u64 {
+		let amt_msat: u64 =
+			route.paths.iter().filter_map(|path| path.last()).map(|hop| hop.fee_msat).sum();
+		let headroom_msat =
+			self.fee_budget.headroom_msat(route, amt_msat, &self.outbound_payments.lock().unwrap());
+		let max_fee_msat = self
+			.retry_limits
+			.lock()
+			.unwrap()
+			.get(payment_hash)
+			.and_then(|state| state.limits.max_fee_msat);
+		match max_fee_msat {
+			Some(max_fee_msat) => {
+				headroom_msat.min(max_fee_msat.saturating_sub(fee_budget::route_fees_msat(route)))
+			}
+			None => headroom_msat,
+		}
+	}
+
+	/// Sets the limits for a payment we're about to send.
+	pub(crate) fn set_retry_limits(&self, payment_hash: PaymentHash, limits: RetryLimits) {
+		self.retry_limits.lock().unwrap().insert(payment_hash, RetryState { limits, retries: 0 });
+	}
+
+	/// Checks an attempt at a payment against its limits, counting it if it's a retry.
+	fn check_retry_limits(
+		&self, payment_hash: &PaymentHash, route: &Route, is_retry: bool,
+	) -> Result<(), PaymentSendFailure> {
+		let mut retry_limits = self.retry_limits.lock().unwrap();
+		let state = match retry_limits.get_mut(payment_hash) {
+			Some(state) => state,
+			None => return Ok(()),
+		};
+		if is_retry {
+			if state.limits.max_retries.map_or(false, |max| state.retries >= max) {
+				return Err(limit_exceeded(format!(
+					"gave up after {} retries, the payment's limit",
+					state.retries
+				)));
+			}
+			if state.limits.deadline.map_or(false, |deadline| time_now_secs() >= deadline) {
+				return Err(limit_exceeded("the payment's deadline passed".to_string()));
+			}
+			if state.limits.retry_until.map_or(false, |until| time_now_secs() >= until) {
+				return Err(limit_exceeded("ran out of time to retry the payment".to_string()));
+			}
+		}
+		if let Some(max_fee_msat) = state.limits.max_fee_msat {
+			let fees_msat = fee_budget::route_fees_msat(route);
+			if fees_msat > max_fee_msat {
+				return Err(limit_exceeded(format!(
+					"route fees of {} msat exceed the payment's limit of {} msat",
+					fees_msat, max_fee_msat
+				)));
+			}
+		}
+		if is_retry {
+			state.retries += 1;
+		}
+		Ok(())
+	}
+
+	fn payment_hash(&self, payment_id: &PaymentId) -> Option<PaymentHash> {
+		self.pending_routes.lock().unwrap().get(payment_id).map(|pending| pending.payment_hash)
+	}
+
+	/// Records the paths of `route` which were sent. `path_results` is `None` if every path was
+	/// sent successfully.
+	fn record_paths(
+		&self, payment_id: PaymentId, payment_hash: Option<PaymentHash>, route: &Route,
+		path_results: Option<&Vec<Result<(), APIError>>>,
+	) {
+		let sent_paths: Vec<Vec<RouteHop>> = match path_results {
+			None => route.paths.clone(),
+			Some(results) => route
+				.paths
+				.iter()
+				.zip(results.iter())
+				.filter(|(_, res)| res.is_ok())
+				.map(|(path, _)| path.clone())
+				.collect(),
+		};
+		let mut pending_routes = self.pending_routes.lock().unwrap();
+		if let Some(pending) = pending_routes.get_mut(&payment_id) {
+			pending.paths.extend(sent_paths);
+		} else if let Some(payment_hash) = payment_hash {
+			pending_routes.insert(payment_id, PendingRoute { payment_hash, paths: sent_paths });
+		}
+	}
+
+	/// Forgets a path which failed, as it didn't end up being part of the payment's route.
+	pub(crate) fn path_failed(&self, payment_hash: &PaymentHash, path: &[RouteHop]) {
+		let mut pending_routes = self.pending_routes.lock().unwrap();
+		for pending in pending_routes.values_mut().filter(|p| p.payment_hash == *payment_hash) {
+			if let Some(idx) = pending.paths.iter().position(|p| &p[..] == path) {
+				pending.paths.remove(idx);
+			}
+		}
+	}
+
+	/// Returns the route a payment took once it has succeeded, forgetting about the payment.
+	pub(crate) fn take_route(&self, payment_hash: &PaymentHash) -> Option<Route> {
+		let mut pending_routes = self.pending_routes.lock().unwrap();
+		let payment_id = pending_routes
+			.iter()
+			.find(|(_, pending)| pending.payment_hash == *payment_hash)
+			.map(|(payment_id, _)| *payment_id)?;
+		let pending = pending_routes.remove(&payment_id)?;
+		self.retry_limits.lock().unwrap().remove(payment_hash);
+		self.privacy.lock().unwrap().remove(payment_hash);
+		Some(Route { paths: pending.paths, payee: None })
+	}
+
+	/// How many parts of a payment are currently in flight.
+	pub(crate) fn in_flight_parts(&self, payment_hash: &PaymentHash) -> usize {
+		let pending_routes = self.pending_routes.lock().unwrap();
+		pending_routes
+			.values()
+			.filter(|pending| pending.payment_hash == *payment_hash)
+			.map(|pending| pending.paths.len())
+			.sum()
+	}
+
+	/// Forgets about a payment which has failed entirely.
+	pub(crate) fn payment_failed(&self, payment_hash: &PaymentHash) {
+		let mut pending_routes = self.pending_routes.lock().unwrap();
+		pending_routes.retain(|_, pending| pending.payment_hash != *payment_hash);
+		self.retry_limits.lock().unwrap().remove(payment_hash);
+		self.privacy.lock().unwrap().remove(payment_hash);
+	}
+}
+
+impl Payer for RouteRecordingPayer {
+	fn node_id(&self) -> PublicKey {
+		self.channel_manager.get_our_node_id()
+	}
+
+	fn first_hops(&self) -> Vec<ChannelDetails> {
+		self.channel_manager.list_usable_channels()
+	}
+
+	fn send_payment(
+		&self, route: &Route, payment_hash: PaymentHash, payment_secret: &Option<PaymentSecret>,
+	) -> Result<PaymentId, PaymentSendFailure> {
+		self.check_retry_limits(&payment_hash, route, false)?;
+		let route = self.pad_route(&payment_hash, route, true);
+		let res = self.channel_manager.send_payment(&route, payment_hash, payment_secret);
+		match &res {
+			Ok(payment_id) => self.record_paths(*payment_id, Some(payment_hash), &route, None),
+			// Only the paths which didn't fail immediately are in flight.
+			Err(PaymentSendFailure::PartialFailure { results, payment_id, .. }) => {
+				self.record_paths(*payment_id, Some(payment_hash), &route, Some(results))
+			}
+			Err(_) => {}
+		}
+		res
+	}
+
+	fn retry_payment(
+		&self, route: &Route, payment_id: PaymentId,
+	) -> Result<(), PaymentSendFailure> {
+		let route = match self.payment_hash(&payment_id) {
+			Some(payment_hash) => {
+				self.check_retry_limits(&payment_hash, route, true)?;
+				self.pad_route(&payment_hash, route, false)
+			}
+			None => route.clone(),
+		};
+		let res = self.channel_manager.retry_payment(&route, payment_id);
+		match &res {
+			Ok(()) => self.record_paths(payment_id, None, &route, None),
+			Err(PaymentSendFailure::PartialFailure { results, .. }) => {
+				self.record_paths(payment_id, None, &route, Some(results))
+			}
+			Err(_) => {}
+		}
+		res
+	}
+}
@@ -0,0 +1,92 @@
+//! Closed-group deployments can require keysend payers to prove they know a shared secret before
+//! we claim their payment. LDK 0.0.103 can't send or read custom onion TLVs, so instead the payer
+//! picks the keysend preimage as a random nonce followed by a MAC of that nonce under the shared
+//! secret, which we check when the payment arrives.
+//!
+//! This only works for keysend, where the payer chooses the preimage. Invoice payments can't carry
+//! the proof, as we choose their preimage, so they can't be restricted this way.
+//!
+//! Every node forwarding a payment learns its preimage once we claim it, and with it the proof, so
+//! we remember the proofs we've accepted in `SeenProofs` and refuse them when they come again.
+
+use crate::storage::{self, Storage};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use lightning::ln::PaymentPreimage;
+use rand::{thread_rng, Rng};
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+fn preimage_tag(secret: &[u8; 32], nonce: &[u8]) -> [u8; 16] {
+	let mut engine = HmacEngine::<Sha256>::new(secret);
+	engine.input(nonce);
+	let mut tag = [0; 16];
+	tag.copy_from_slice(&Hmac::from_engine(engine)[..16]);
+	tag
+}
+
+/// A keysend preimage proving knowledge of `secret`.
+pub(crate) fn authenticated_keysend_preimage(secret: &[u8; 32]) -> PaymentPreimage {
+	let mut preimage = [0; 32];
+	thread_rng().fill_bytes(&mut preimage[..16]);
+	let tag = preimage_tag(secret, &preimage[..16]);
+	preimage[16..].copy_from_slice(&tag);
+	PaymentPreimage(preimage)
+}
+
+/// Whether a keysend preimage was generated by someone who knows `secret`. Meaningless for the
+/// preimages of invoice payments, which we generate ourselves.
+pub(crate) fn verify_keysend_preimage(secret: &[u8; 32], preimage: &PaymentPreimage) -> bool {
+	let tag = preimage_tag(secret, &preimage.0[..16]);
+	// Compare in constant time so that the tag can't be guessed byte by byte.
+	tag.iter().zip(preimage.0[16..].iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The tags of the keysend preimages we've accepted, kept under `storage::PAYER_PROOFS` as one
+/// 16-byte tag after another. They're never pruned, as a proof could be replayed at any time.
+pub(crate) struct SeenProofs {
+	storage: Arc<dyn Storage>,
+	tags: Mutex<HashSet<[u8; 16]>>,
+}
+
+impl SeenProofs {
+	pub(crate) fn new(storage: Arc<dyn Storage>) -> io::Result<Self> {
+		let stored = storage.read(storage::PAYER_PROOFS)?.unwrap_or_default();
+		let tags = stored
+			.chunks_exact(16)
+			.map(|chunk| {
+				let mut tag = [0; 16];
+				tag.copy_from_slice(chunk);
+				tag
+			})
+			.collect();
+		Ok(Self { storage, tags: Mutex::new(tags) })
+	}
+
+	/// Whether we already accepted a payment with `preimage`'s proof.
+	pub(crate) fn contains(&self, preimage: &PaymentPreimage) -> bool {
+		self.tags.lock().unwrap().contains(&preimage_tag_of(preimage))
+	}
+
+	/// Records that we're accepting a payment with `preimage`'s proof, only returning once it's
+	/// durably stored, so that the proof is refused from then on even if we crash.
+	pub(crate) fn insert(&self, preimage: &PaymentPreimage) -> io::Result<()> {
+		let mut tags = self.tags.lock().unwrap();
+		if !tags.insert(preimage_tag_of(preimage)) {
+			return Ok(());
+		}
+		let stored: Vec<u8> = tags.iter().flat_map(|tag| tag.iter().cloned()).collect();
+		self.storage.write(storage::PAYER_PROOFS, &stored).map_err(|e| {
+			tags.remove(&preimage_tag_of(preimage));
+			e
+		})
+	}
+}
+
+fn preimage_tag_of(preimage: &PaymentPreimage) -> [u8; 16] {
+	let mut tag = [0; 16];
+	tag.copy_from_slice(&preimage.0[16..]);
+	tag
+}
@@ -0,0 +1,62 @@
+use crate::{time_now_secs, HTLCStatus, InvoicePayer, PaymentInfoStorage};
+use lightning::util::events::EventHandler;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often we sweep the payment stores.
+const GC_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Marks expired invoices as failed, stops retrying outbound payments whose invoice has expired and
+/// drops completed payments which haven't been updated in `retention_days` days.
+pub(crate) fn start_payment_gc<E: EventHandler + Send + Sync + 'static>(
+	inbound_payments: PaymentInfoStorage, outbound_payments: PaymentInfoStorage,
+	invoice_payer: Arc<InvoicePayer<E>>, retention_days: u64,
+) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(GC_INTERVAL);
+		loop {
+			interval.tick().await;
+			let now = time_now_secs();
+			let retention_cutoff = now.saturating_sub(retention_days * 24 * 60 * 60);
+			let is_expired = |expires_at: Option<u64>| expires_at.map_or(false, |e| e < now);
+
+			{
+				let mut inbound = inbound_payments.lock().unwrap();
+				// ChannelManager fails any HTLCs paying an expired invoice, so a still-pending
+				// expired invoice can never be paid. Payments held for an external system to
+				// settle, which we know the preimage of, already arrived though.
+				for payment in inbound.values_mut() {
+					if let HTLCStatus::Pending = payment.status {
+						if is_expired(payment.expires_at) && payment.preimage.is_none() {
+							payment.status = HTLCStatus::Failed;
+							payment.updated_at = now;
+						}
+					}
+				}
+				inbound.retain(|_, payment| match payment.status {
+					HTLCStatus::Pending => true,
+					_ => payment.updated_at >= retention_cutoff,
+				});
+				inbound.persist();
+			}
+
+			{
+				let mut outbound = outbound_payments.lock().unwrap();
+				for (payment_hash, payment) in outbound.iter() {
+					// HTLCs which are already in flight may still succeed or fail, which we'll
+					// learn about via events, but we shouldn't retry paying an expired invoice.
+					if let HTLCStatus::Pending = payment.status {
+						if is_expired(payment.expires_at) {
+							invoice_payer.remove_cached_payment(payment_hash);
+						}
+					}
+				}
+				outbound.retain(|_, payment| match payment.status {
+					HTLCStatus::Pending | HTLCStatus::TimedOut => true,
+					_ => payment.updated_at >= retention_cutoff,
+				});
+				outbound.persist();
+			}
+		}
+	});
+}
@@ -0,0 +1,192 @@
+use crate::hex_utils;
+use crate::time_now_secs;
+use bitcoin::secp256k1::key::PublicKey;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// What we've learned about a peer's channel open limits from our past attempts to open channels
+/// with them.
+#[derive(Default)]
+pub(crate) struct PeerLimits {
+	/// The smallest and largest channels the peer has accepted from us.
+	pub(crate) min_accepted_sat: Option<u64>,
+	pub(crate) max_accepted_sat: Option<u64>,
+	/// Our most recent open which didn't make it to funding: its size, when it failed, and why.
+	pub(crate) last_rejection: Option<OpenRejection>,
+}
+
+pub(crate) struct OpenRejection {
+	pub(crate) channel_value_sat: u64,
+	pub(crate) rejected_at: u64,
+	pub(crate) reason: String,
+}
+
+/// Negotiation outcomes per peer, written to disk whenever they change. Opens in flight are
+/// tracked by the `user_channel_id` we give them, which LDK hands back in the events telling us
+/// how the negotiation went.
+pub(crate) struct PeerLimitsStore {
+	path: String,
+	peers: HashMap<PublicKey, PeerLimits>,
+	pending_opens: HashMap<u64, (PublicKey, u64)>,
+}
+
+pub(crate) type PeerLimitsStorage = Arc<Mutex<PeerLimitsStore>>;
+
+impl PeerLimitsStore {
+	pub(crate) fn new(path: String) -> Self {
+		let peers = read_peer_limits(Path::new(&path));
+		Self { path, peers, pending_opens: HashMap::new() }
+	}
+
+	pub(crate) fn get(&self, peer: &PublicKey) -> Option<&PeerLimits> {
+		self.peers.get(peer)
+	}
+
+	/// Notes that we've started opening a channel with the given `user_channel_id`.
+	pub(crate) fn open_started(
+		&mut self, user_channel_id: u64, peer: PublicKey, channel_value_sat: u64,
+	) {
+		self.pending_opens.insert(user_channel_id, (peer, channel_value_sat));
+	}
+
+	/// The peer accepted our open and we're about to fund it.
+	pub(crate) fn open_accepted(&mut self, user_channel_id: u64) {
+		let (peer, channel_value_sat) = match self.pending_opens.remove(&user_channel_id) {
+			Some(open) => open,
+			None => return,
+		};
+		let limits = self.peers.entry(peer).or_insert_with(PeerLimits::default);
+		limits.min_accepted_sat = Some(
+			limits.min_accepted_sat.map_or(channel_value_sat, |min| min.min(channel_value_sat)),
+		);
+		limits.max_accepted_sat = Some(
+			limits.max_accepted_sat.map_or(channel_value_sat, |max| max.max(channel_value_sat)),
+		);
+		self.persist();
+	}
+
+	/// A channel closed. If it was one of our opens which never got funded, the peer or we
+	/// rejected its terms, so remember why.
+	pub(crate) fn channel_closed(&mut self, user_channel_id: u64, reason: String) {
+		let (peer, channel_value_sat) = match self.pending_opens.remove(&user_channel_id) {
+			Some(open) => open,
+			None => return,
+		};
+		let limits = self.peers.entry(peer).or_insert_with(PeerLimits::default);
+		limits.last_rejection =
+			Some(OpenRejection { channel_value_sat, rejected_at: time_now_secs(), reason });
+		self.persist();
+	}
+
+	/// Each peer's limits in their on-disk form, e.g. for exporting them to another node.
+	pub(crate) fn export_lines(&self) -> Vec<String> {
+		self.peers.iter().map(|(peer, limits)| peer_limits_to_line(peer, limits)).collect()
+	}
+
+	/// Adds the limits in `lines` for peers we don't know anything about yet, returning how many
+	/// were added, or `None` if any line is malformed.
+	pub(crate) fn import_lines(&mut self, lines: &[String]) -> Option<usize> {
+		let imported =
+			lines.iter().map(|line| peer_limits_from_line(line)).collect::<Option<Vec<_>>>()?;
+		let mut added = 0;
+		for (peer, limits) in imported {
+			if !self.peers.contains_key(&peer) {
+				self.peers.insert(peer, limits);
+				added += 1;
+			}
+		}
+		self.persist();
+		Some(added)
+	}
+
+	fn persist(&self) {
+		if persist_peer_limits(Path::new(&self.path), &self.peers).is_err() {
+			eprintln!("Warning: Failed to persist peer limits, check your disk and permissions");
+		}
+	}
+}
+
+fn opt_to_str(value: Option<u64>) -> String {
+	value.map_or("-".to_string(), |v| v.to_string())
+}
+
+fn str_to_opt(s: &str) -> Option<Option<u64>> {
+	match s {
+		"-" => Some(None),
+		s => s.parse().ok().map(Some),
+	}
+}
+
+/// Each peer is stored as a line of the form `<pubkey> <min_accepted_sat|-> <max_accepted_sat|->
+/// <rejected_value_sat|-> <rejected_at|-> <rejection_reason|->`, where the reason is hex-encoded.
+fn peer_limits_to_line(peer: &PublicKey, limits: &PeerLimits) -> String {
+	let rejection = &limits.last_rejection;
+	format!(
+		"{} {} {} {} {} {}",
+		peer,
+		opt_to_str(limits.min_accepted_sat),
+		opt_to_str(limits.max_accepted_sat),
+		opt_to_str(rejection.as_ref().map(|r| r.channel_value_sat)),
+		opt_to_str(rejection.as_ref().map(|r| r.rejected_at)),
+		rejection.as_ref().map_or("-".to_string(), |r| hex_utils::hex_str(r.reason.as_bytes())),
+	)
+}
+
+fn persist_peer_limits(path: &Path, peers: &HashMap<PublicKey, PeerLimits>) -> std::io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for (peer, limits) in peers.iter() {
+		file.write_all(format!("{}\n", peer_limits_to_line(peer, limits)).as_bytes())?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+pub(crate) fn peer_limits_from_line(line: &str) -> Option<(PublicKey, PeerLimits)> {
+	let parts: Vec<&str> = line.split(' ').collect();
+	if parts.len() != 6 {
+		return None;
+	}
+	let peer = hex_utils::to_compressed_pubkey(parts[0])?;
+	let last_rejection = match (str_to_opt(parts[3])?, str_to_opt(parts[4])?, parts[5]) {
+		(Some(channel_value_sat), Some(rejected_at), reason) if reason != "-" => {
+			let reason = String::from_utf8(hex_utils::to_vec(reason)?).ok()?;
+			Some(OpenRejection { channel_value_sat, rejected_at, reason })
+		}
+		(None, None, "-") => None,
+		_ => return None,
+	};
+	let limits = PeerLimits {
+		min_accepted_sat: str_to_opt(parts[1])?,
+		max_accepted_sat: str_to_opt(parts[2])?,
+		last_rejection,
+	};
+	Some((peer, limits))
+}
+
+fn read_peer_limits(path: &Path) -> HashMap<PublicKey, PeerLimits> {
+	let mut peers = HashMap::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return peers,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		match peer_limits_from_line(&line) {
+			Some((peer, limits)) => {
+				peers.insert(peer, limits);
+			}
+			None => eprintln!("Warning: Skipping malformed peer limits entry in {:?}", path),
+		}
+	}
+	peers
+}
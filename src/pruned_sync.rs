@@ -0,0 +1,113 @@
+use crate::bitcoind_client::BitcoindClient;
+use crate::compact_filters::WatchedScripts;
+use crate::{ChainMonitor, ChannelManager};
+use bitcoin::blockdata::block::BlockHeader;
+use lightning::chain::{BestBlock, Confirm};
+use lightning_block_sync::BlockSource;
+use std::collections::HashMap;
+
+/// What to do when bitcoind has pruned blocks we can't do without.
+pub(crate) const RECOVERY_HINT: &str = "To recover, sync from a source which still has the blocks, either a peer serving compact block filters with `--chain-backend filters --filter-peer <host:port>` or an Esplora server with `--chain-backend esplora --esplora-url <url>`, or restart bitcoind with -reindex and a -prune target large enough to keep the blocks since we last synced, so it downloads them again.";
+
+/// Catches our channel manager and monitors up from `start` to bitcoind's tip when bitcoind has
+/// pruned some of the blocks in between, so that they can't be synced block by block.
+///
+/// Instead we look up what LDK registered through the `Filter` interface. Everything which happens
+/// to a channel on-chain spends one of the outputs we watch, so if they're all still unspent we
+/// only need to know which of the transactions we watch confirmed since `start`. bitcoind can tell
+/// us where in its block a transaction confirmed if it's in one of our wallets, which covers the
+/// funding transactions of channels we opened. Anything else can't be worked out without the
+/// pruned blocks, in which case we give up, leaving the channel manager and monitors untouched.
+pub(crate) async fn catch_up(
+	bitcoind_client: &BitcoindClient, watched: &WatchedScripts, chain_monitor: &ChainMonitor,
+	channel_manager: &ChannelManager, start: BestBlock, tip: &BlockHeader, tip_height: u32,
+) -> Result<(), String> {
+	// How many confirmations the transactions of the outputs we watch have, 0 if unconfirmed.
+	let mut output_confirmations = HashMap::new();
+	for outpoint in watched.outputs() {
+		let lookup_failed = |e| format!("failed to look up output {} in bitcoind: {}", outpoint, e);
+		let confirmations = match bitcoind_client
+			.get_output_confirmations(&outpoint, false)
+			.await
+			.map_err(lookup_failed)?
+		{
+			Some(confirmations) => confirmations,
+			// The output may just not be confirmed yet, rather than spent.
+			None => match bitcoind_client
+				.get_output_confirmations(&outpoint, true)
+				.await
+				.map_err(lookup_failed)?
+			{
+				Some(0) => 0,
+				_ => {
+					return Err(format!(
+						"output {} which we watch for our channels has been spent, and the spend may be in one of the pruned blocks",
+						outpoint
+					))
+				}
+			},
+		};
+		output_confirmations.insert(outpoint.txid, confirmations);
+	}
+
+	let mut confirmed = Vec::new();
+	for txid in watched.txids() {
+		let wallet_tx = bitcoind_client
+			.get_wallet_transaction(&txid)
+			.await
+			.map_err(|e| format!("failed to look up transaction {} in bitcoind: {}", txid, e))?;
+		match wallet_tx {
+			Some((tx, Some((block_hash, height, index)))) => {
+				if height > start.height() {
+					confirmed.push((height, block_hash, index, tx));
+				}
+			}
+			// Not confirmed yet, so we'll see it confirm as we sync new blocks.
+			Some((_, None)) => {}
+			None => {
+				// All we can tell about a transaction which isn't ours is when the outputs of it we
+				// watch confirmed, which we checked above are unspent.
+				match output_confirmations.get(&txid) {
+					Some(0) => {}
+					Some(confirmations) if tip_height + 1 - confirmations <= start.height() => {}
+					_ => {
+						return Err(format!(
+							"transaction {} may have confirmed in one of the pruned blocks, and as it isn't in our wallet we can't tell where in the block without it",
+							txid
+						))
+					}
+				}
+			}
+		}
+	}
+
+	// Confirmations are counted from bitcoind's current tip, so they're only right for `tip` if
+	// bitcoind hasn't found a block since.
+	let mut block_source = bitcoind_client;
+	match block_source.get_best_block().await {
+		Ok((block_hash, _)) if block_hash == tip.block_hash() => {}
+		Ok(_) => {
+			return Err(
+				"bitcoind found a new block while we were catching up, try again".to_string()
+			)
+		}
+		Err(e) => return Err(format!("failed to get bitcoind's best block: {:?}", e)),
+	}
+	confirmed.sort_by_key(|(height, _, _, _)| *height);
+	for (height, block_hash, index, tx) in confirmed.iter() {
+		let header = match block_source.get_header(block_hash, Some(*height)).await {
+			Ok(header_data) => header_data.header,
+			Err(e) => {
+				return Err(format!("failed to get block {} from bitcoind: {:?}", block_hash, e))
+			}
+		};
+		let txdata = [(*index, tx)];
+		chain_monitor.transactions_confirmed(&header, &txdata, *height);
+		if *height > channel_manager.current_best_block().height() {
+			channel_manager.transactions_confirmed(&header, &txdata, *height);
+		}
+	}
+	chain_monitor.best_block_updated(tip, tip_height);
+	channel_manager.best_block_updated(tip, tip_height);
+	Ok(())
+}
@@ -0,0 +1,62 @@
+use crate::hex_utils;
+use crate::{HTLCStatus, PaymentInfo};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+use bitcoin::secp256k1::Secp256k1;
+use lightning::ln::PaymentHash;
+use lightning::util::message_signing;
+
+/// The message a receipt's signature commits to, covering every other field of the receipt.
+fn receipt_message(
+	direction: &str, payment_hash: &str, preimage: &str, amount_msat: &str, settled_at: u64,
+	invoice: &str,
+) -> String {
+	format!(
+		"receipt {} {} {} {} {} {}",
+		direction, payment_hash, preimage, amount_msat, settled_at, invoice
+	)
+}
+
+/// Builds a receipt for a payment which has succeeded, signed by our node. It can be checked
+/// offline: the preimage hashes to the payment hash, which only the payee could have revealed, the
+/// invoice (if any) is signed by the payee and commits to the same payment hash, and `signature`
+/// is our node's signature of `message`, in the same format as `signmessage`.
+pub(crate) fn payment_receipt(
+	payment_hash: &PaymentHash, payment: &PaymentInfo, direction: &str, node_secret: &SecretKey,
+) -> Result<serde_json::Value, String> {
+	match payment.status {
+		HTLCStatus::Succeeded => {}
+		_ => return Err("the payment hasn't succeeded".to_string()),
+	}
+	let preimage = payment.preimage.ok_or("we don't know the payment's preimage")?;
+	if Sha256::hash(&preimage.0).into_inner() != payment_hash.0 {
+		return Err("the payment's preimage doesn't match its hash".to_string());
+	}
+
+	let payment_hash = hex_utils::hex_str(&payment_hash.0);
+	let preimage = hex_utils::hex_str(&preimage.0);
+	let amount_msat = payment.amt_msat.to_string();
+	let invoice = payment.invoice.as_ref().map_or("-", |invoice| invoice.as_str());
+	let message = receipt_message(
+		direction,
+		&payment_hash,
+		&preimage,
+		&amount_msat,
+		payment.updated_at,
+		invoice,
+	);
+	let signature = message_signing::sign(message.as_bytes(), node_secret)
+		.map_err(|e| format!("failed to sign receipt: {:?}", e))?;
+	Ok(serde_json::json!({
+		"direction": direction,
+		"payment_hash": payment_hash,
+		"preimage": preimage,
+		"amount_msat": payment.amt_msat.0,
+		"settled_at": payment.updated_at,
+		"invoice": payment.invoice,
+		"node_id": PublicKey::from_secret_key(&Secp256k1::new(), node_secret).to_string(),
+		"message": message,
+		"signature": signature,
+	}))
+}
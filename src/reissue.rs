@@ -0,0 +1,117 @@
+use crate::bundles::BundleStorage;
+use crate::cli;
+use crate::hex_utils;
+use crate::notifier::SettlementWebhook;
+use crate::{time_now_secs, ChannelManager, HTLCStatus, PaymentInfoStorage};
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::Network;
+use lightning::chain::keysinterface::KeysManager;
+use lightning::ln::PaymentHash;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often we look for invoices which have expired unpaid.
+const REISSUE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long after an invoice expired we'll still re-issue it, so that turning automatic re-issue
+/// on doesn't re-issue every invoice which ever went unpaid.
+const REISSUE_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// Re-issues invoices shortly after they expire unpaid, linking each successor to the invoice it
+/// replaces, until an invoice has been re-issued `max_reissues` times. Each re-issue is delivered to
+/// the settlement webhook, if there is one, so that the merchant can hand out the new invoice.
+pub(crate) fn start_auto_reissue(
+	inbound_payments: PaymentInfoStorage, invoice_bundles: BundleStorage,
+	channel_manager: Arc<ChannelManager>, keys_manager: Arc<KeysManager>, network: Network,
+	min_final_cltv_expiry: u32, max_reissues: u32,
+	settlement_webhook: Option<Arc<SettlementWebhook>>,
+) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(REISSUE_INTERVAL);
+		let mut failing = false;
+		loop {
+			interval.tick().await;
+			let now = time_now_secs();
+			let expired: Vec<PaymentHash> = {
+				let inbound = inbound_payments.lock().unwrap();
+				let reissued: HashSet<PaymentHash> =
+					inbound.values().filter_map(|payment| payment.reissue_of).collect();
+				let times_reissued = |payment_hash: &PaymentHash| {
+					let mut count = 0;
+					let mut hash = *payment_hash;
+					while let Some(reissue_of) = inbound.get(&hash).and_then(|p| p.reissue_of) {
+						count += 1;
+						hash = reissue_of;
+					}
+					count
+				};
+				inbound
+					.iter()
+					.filter(|(payment_hash, payment)| {
+						let recently_expired = payment.expires_at.map_or(false, |expires_at| {
+							expires_at < now && expires_at + REISSUE_WINDOW_SECS >= now
+						});
+						let paid = match payment.status {
+							HTLCStatus::Succeeded => true,
+							_ => false,
+						};
+						// A payment held for an external system to settle did arrive, it just
+						// hasn't been claimed yet.
+						let held = match payment.status {
+							HTLCStatus::Pending => payment.preimage.is_some(),
+							_ => false,
+						};
+						// Invoices without an amount are left to the merchant, who knows what to
+						// charge.
+						recently_expired
+							&& !paid && !held && payment.amt_msat.0.is_some()
+							&& payment.invoice.is_some()
+							&& !reissued.contains(payment_hash)
+							&& times_reissued(payment_hash) < max_reissues
+					})
+					.map(|(payment_hash, _)| *payment_hash)
+					.collect()
+			};
+
+			for payment_hash in expired {
+				match cli::reissue_invoice(
+					&payment_hash,
+					&inbound_payments,
+					&invoice_bundles,
+					&channel_manager,
+					&keys_manager,
+					network,
+					min_final_cltv_expiry,
+				) {
+					Ok(invoice) => {
+						if failing {
+							eprintln!("Expired invoices are being re-issued again");
+							failing = false;
+						}
+						if let Some(webhook) = &settlement_webhook {
+							let successor_hash =
+								PaymentHash(invoice.payment_hash().clone().into_inner());
+							webhook.reissued(
+								successor_hash,
+								payment_hash,
+								invoice.to_string(),
+								&inbound_payments,
+							);
+						}
+					}
+					Err(e) => {
+						if !failing {
+							eprintln!(
+								"Warning: Failed to re-issue expired invoice {}: {}",
+								hex_utils::hex_str(&payment_hash.0),
+								e
+							);
+							failing = true;
+						}
+					}
+				}
+			}
+		}
+	});
+}
@@ -0,0 +1,195 @@
+use crate::hex_utils;
+use crate::time_now_secs;
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::ln::msgs::{ErrorAction, LightningError};
+use lightning::routing::network_graph::NodeId;
+use lightning::routing::router::{Route, RouteHop};
+use lightning::routing::Score;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// More than any route could cost, so the router only uses an excluded channel if there's no other
+/// way to reach the payee, in which case `check_route` refuses the route. It's small enough that
+/// the penalties of a few hops can't overflow.
+const EXCLUDED_PENALTY_MSAT: u64 = 21_000_000 * 100_000_000 * 1000;
+
+/// Nodes and channels the operator told us not to route payments through, e.g. because they're
+/// known to be unreliable, each either until the given time or, if `None`, until it's removed.
+/// They're written to disk whenever they change.
+pub(crate) struct RouteExclusions {
+	path: String,
+	pub(crate) nodes: HashMap<PublicKey, Option<u64>>,
+	pub(crate) channels: HashMap<u64, Option<u64>>,
+}
+
+pub(crate) type RouteExclusionsStorage = Arc<Mutex<RouteExclusions>>;
+
+impl RouteExclusions {
+	pub(crate) fn new(path: String) -> Self {
+		let (nodes, channels) = read_exclusions(Path::new(&path));
+		Self { path, nodes, channels }
+	}
+
+	/// Excludes `node` from our routes, for `duration_secs` if given.
+	pub(crate) fn exclude_node(&mut self, node: PublicKey, duration_secs: Option<u64>) {
+		self.nodes.insert(node, duration_secs.map(|secs| time_now_secs() + secs));
+		self.persist();
+	}
+
+	/// Excludes the channel with the given short channel id from our routes, for `duration_secs`
+	/// if given.
+	pub(crate) fn exclude_channel(&mut self, short_channel_id: u64, duration_secs: Option<u64>) {
+		self.channels.insert(short_channel_id, duration_secs.map(|secs| time_now_secs() + secs));
+		self.persist();
+	}
+
+	/// Lifts a node's exclusion, returning whether there was one.
+	pub(crate) fn remove_node(&mut self, node: &PublicKey) -> bool {
+		let removed = self.nodes.remove(node).is_some();
+		self.persist();
+		removed
+	}
+
+	/// Lifts a channel's exclusion, returning whether there was one.
+	pub(crate) fn remove_channel(&mut self, short_channel_id: u64) -> bool {
+		let removed = self.channels.remove(&short_channel_id).is_some();
+		self.persist();
+		removed
+	}
+
+	/// Forgets exclusions which have run out.
+	pub(crate) fn remove_expired(&mut self) {
+		let now = time_now_secs();
+		let (num_nodes, num_channels) = (self.nodes.len(), self.channels.len());
+		self.nodes.retain(|_, until| until.map_or(true, |until| until > now));
+		self.channels.retain(|_, until| until.map_or(true, |until| until > now));
+		if self.nodes.len() != num_nodes || self.channels.len() != num_channels {
+			self.persist();
+		}
+	}
+
+	fn is_excluded(&self, hop: &RouteHop, now: u64) -> bool {
+		let active = |until: &Option<u64>| until.map_or(true, |until| until > now);
+		self.nodes.get(&hop.pubkey).map_or(false, active)
+			|| self.channels.get(&hop.short_channel_id).map_or(false, active)
+	}
+
+	/// Fails if `route` goes through an excluded node or channel, which the router only picks when
+	/// there's no other route. A payee we've excluded can't be paid at all.
+	pub(crate) fn check_route(&self, route: &Route) -> Result<(), LightningError> {
+		let now = time_now_secs();
+		for hop in route.paths.iter().flatten() {
+			if self.is_excluded(hop, now) {
+				return Err(LightningError {
+					err: format!(
+						"the only route found goes through excluded node {} or channel {}",
+						hop.pubkey, hop.short_channel_id
+					),
+					action: ErrorAction::IgnoreError,
+				});
+			}
+		}
+		Ok(())
+	}
+
+	fn persist(&self) {
+		if persist_exclusions(Path::new(&self.path), self).is_err() {
+			eprintln!(
+				"Warning: Failed to persist route exclusions, check your disk and permissions"
+			);
+		}
+	}
+}
+
+/// Steers the router away from excluded nodes and channels by penalizing them on top of the
+/// penalties of the scorer it wraps, for the duration of one route search.
+pub(crate) struct ExcludingScorer<'a, S: Score> {
+	pub(crate) inner: &'a S,
+	pub(crate) exclusions: &'a RouteExclusions,
+	pub(crate) now: u64,
+}
+
+impl<'a, S: Score> ExcludingScorer<'a, S> {
+	fn is_excluded_node(&self, node: &NodeId) -> bool {
+		self.exclusions.nodes.iter().any(|(excluded, until)| {
+			until.map_or(true, |until| until > self.now) && NodeId::from_pubkey(excluded) == *node
+		})
+	}
+}
+
+impl<'a, S: Score> Score for ExcludingScorer<'a, S> {
+	fn channel_penalty_msat(&self, short_channel_id: u64, source: &NodeId, target: &NodeId) -> u64 {
+		let excluded_channel = self
+			.exclusions
+			.channels
+			.get(&short_channel_id)
+			.map_or(false, |until| until.map_or(true, |until| until > self.now));
+		if excluded_channel || self.is_excluded_node(source) || self.is_excluded_node(target) {
+			return EXCLUDED_PENALTY_MSAT;
+		}
+		self.inner.channel_penalty_msat(short_channel_id, source, target)
+	}
+
+	// Failures are reported to the scorer we wrap once the payment is under way, not to us.
+	fn payment_path_failed(&mut self, _path: &[&RouteHop], _short_channel_id: u64) {}
+}
+
+fn until_to_str(until: &Option<u64>) -> String {
+	until.map_or("-".to_string(), |until| until.to_string())
+}
+
+/// Exclusions are stored one per line, as `node <pubkey> <until|->` or
+/// `channel <short_channel_id> <until|->`.
+fn persist_exclusions(path: &Path, exclusions: &RouteExclusions) -> std::io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for (node, until) in exclusions.nodes.iter() {
+		file.write_all(format!("node {} {}\n", node, until_to_str(until)).as_bytes())?;
+	}
+	for (short_channel_id, until) in exclusions.channels.iter() {
+		file.write_all(
+			format!("channel {} {}\n", short_channel_id, until_to_str(until)).as_bytes(),
+		)?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+fn read_exclusions(path: &Path) -> (HashMap<PublicKey, Option<u64>>, HashMap<u64, Option<u64>>) {
+	let mut nodes = HashMap::new();
+	let mut channels = HashMap::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return (nodes, channels),
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		let parts: Vec<&str> = line.split(' ').collect();
+		let until = match parts.get(2) {
+			Some(&"-") => Some(None),
+			Some(until) => until.parse().ok().map(Some),
+			None => None,
+		};
+		match (parts.as_slice(), until) {
+			(["node", node, _], Some(until)) if hex_utils::to_compressed_pubkey(node).is_some() => {
+				nodes.insert(hex_utils::to_compressed_pubkey(node).unwrap(), until);
+			}
+			(["channel", short_channel_id, _], Some(until))
+				if short_channel_id.parse::<u64>().is_ok() =>
+			{
+				channels.insert(short_channel_id.parse().unwrap(), until);
+			}
+			_ => eprintln!("Warning: Skipping malformed route exclusion in {:?}", path),
+		}
+	}
+	(nodes, channels)
+}
@@ -0,0 +1,721 @@
+use crate::bitcoind_client::BitcoindClient;
+use crate::bundles::BundleStorage;
+use crate::cli;
+use crate::cli::{InvoiceDescription, InvoiceOptions};
+use crate::connection_guard;
+use crate::connection_guard::ConnectionGuard;
+use crate::disk;
+use crate::disk_space::DiskMonitor;
+use crate::event_stream;
+use crate::event_stream::EventStream;
+use crate::hex_utils;
+use crate::hold_invoices::{HoldInvoices, DEFAULT_HOLD_TIMEOUT_SECS};
+use crate::notifier::SettlementWebhook;
+use crate::path_privacy::PrivacyOverrides;
+use crate::pause::PauseSwitch;
+use crate::payer::{RetryLimits, RouteRecordingPayer};
+use crate::peer_limits::PeerLimitsStorage;
+use crate::storage::Storage;
+use crate::uptime::PeerUptimeStorage;
+use crate::{
+	balances, time_now_secs, ChannelManager, HTLCStatus, InvoicePayer, PaymentInfoStorage,
+	PeerManager,
+};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::Network;
+use lightning::chain::keysinterface::KeysManager;
+use lightning::ln::PaymentHash;
+use lightning::util::config::UserConfig;
+use lightning::util::events::EventHandler;
+use lightning_invoice::Invoice;
+use rand::{OsRng, Rng};
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Requests with larger bodies than this are rejected, none of our endpoints need anywhere near it.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Requests with longer request or header lines, or more headers, than these are rejected.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Where the token clients authenticate with is written, in the data directory.
+pub(crate) const AUTH_COOKIE_FILE: &str = "rpc_cookie";
+
+/// Writes a fresh random token to `path`, readable only by the user running the node, returning
+/// it. Like bitcoind's cookie, a new token is made every time the node starts.
+pub(crate) fn write_auth_cookie(path: &str) -> io::Result<String> {
+	let mut token = [0; 32];
+	OsRng::new()
+		.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+		.fill_bytes(&mut token);
+	let token = hex_utils::hex_str(&token);
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+	// The mode only applies to new files, so also tighten one left by an earlier version.
+	file.set_permissions(fs::Permissions::from_mode(0o600))?;
+	file.write_all(token.as_bytes())?;
+	file.sync_all()?;
+	Ok(token)
+}
+
+/// Compares in constant time, so that the token can't be guessed byte by byte from timings.
+fn token_matches(given: &str, token: &str) -> bool {
+	given.len() == token.len()
+		&& given.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Everything the RPC endpoints need to drive the node.
+pub(crate) struct RpcServer<E: EventHandler> {
+	pub(crate) invoice_payer: Arc<InvoicePayer<E>>,
+	pub(crate) payer: Arc<RouteRecordingPayer>,
+	pub(crate) peer_manager: Arc<PeerManager>,
+	pub(crate) channel_manager: Arc<ChannelManager>,
+	pub(crate) keys_manager: Arc<KeysManager>,
+	pub(crate) bitcoind_client: Arc<BitcoindClient>,
+	pub(crate) inbound_payments: PaymentInfoStorage,
+	pub(crate) outbound_payments: PaymentInfoStorage,
+	pub(crate) invoice_bundles: BundleStorage,
+	pub(crate) peer_uptime: PeerUptimeStorage,
+	pub(crate) storage: Arc<dyn Storage>,
+	pub(crate) network: Network,
+	pub(crate) user_config: UserConfig,
+	pub(crate) min_final_cltv_expiry: u32,
+	pub(crate) proxy_addr: Option<SocketAddr>,
+	pub(crate) pause_switch: Arc<PauseSwitch>,
+	pub(crate) disk_monitor: Arc<DiskMonitor>,
+	pub(crate) peer_limits: PeerLimitsStorage,
+	pub(crate) hold_invoices: Arc<HoldInvoices>,
+	pub(crate) settlement_webhook: Option<Arc<SettlementWebhook>>,
+	pub(crate) connection_guard: Arc<ConnectionGuard>,
+	pub(crate) event_stream: Arc<EventStream>,
+	/// The token clients must send as `Authorization: Bearer <token>`.
+	pub(crate) auth_token: String,
+}
+
+/// An error response: the HTTP status code and a message for the caller.
+type RpcError = (u16, String);
+
+fn bad_request(msg: &str) -> RpcError {
+	(400, msg.to_string())
+}
+
+fn parse_payment_hash(params: &Value) -> Result<PaymentHash, RpcError> {
+	params["payment_hash"]
+		.as_str()
+		.and_then(hex_utils::to_vec)
+		.filter(|hash| hash.len() == 32)
+		.map(|hash| {
+			let mut payment_hash = [0; 32];
+			payment_hash.copy_from_slice(&hash);
+			PaymentHash(payment_hash)
+		})
+		.ok_or_else(|| bad_request("`payment_hash` must be 32 hex-encoded bytes"))
+}
+
+impl<E: EventHandler> RpcServer<E> {
+	async fn handle_request(
+		&self, method: &str, path: &str, params: Value,
+	) -> Result<Value, RpcError> {
+		match (method, path) {
+			("GET", "/nodeinfo") => Ok(self.node_info()),
+			("GET", "/health") => self.health(),
+			("GET", "/listchannels") => Ok(self.list_channels()),
+			("GET", "/listpeers") => Ok(self.list_peers()),
+			("POST", "/openchannel") => self.open_channel(params).await,
+			("POST", "/sendpayment") => self.send_payment(params),
+			("POST", "/getinvoice") => self.get_invoice(params),
+			("POST", "/reissueinvoice") => self.reissue_invoice(params),
+			("POST", "/settleinvoice") => self.settle_invoice(params),
+			("POST", "/cancelinvoice") => self.cancel_invoice(params),
+			("POST", "/createbundle") => self.create_bundle(params),
+			("POST", "/bundlestatus") => self.bundle_status(params),
+			(_, "/nodeinfo")
+			| (_, "/health")
+			| (_, "/listchannels")
+			| (_, "/listpeers")
+			| (_, "/openchannel")
+			| (_, "/sendpayment")
+			| (_, "/getinvoice")
+			| (_, "/reissueinvoice")
+			| (_, "/settleinvoice")
+			| (_, "/cancelinvoice")
+			| (_, "/createbundle")
+			| (_, "/bundlestatus") => Err((405, "method not allowed".to_string())),
+			_ => Err((404, format!("unknown endpoint {}", path))),
+		}
+	}
+
+	fn check_not_paused(&self) -> Result<(), RpcError> {
+		if self.pause_switch.is_held_for_disk() {
+			return Err((
+				503,
+				format!("the node's disk is {}", self.disk_monitor.status().as_str()),
+			));
+		}
+		if self.pause_switch.is_paused() {
+			return Err((503, "the node is paused".to_string()));
+		}
+		Ok(())
+	}
+
+	/// Responds with 503 while the node is held because of its disk, so that monitoring notices.
+	fn health(&self) -> Result<Value, RpcError> {
+		if self.pause_switch.is_held_for_disk() {
+			return Err((
+				503,
+				format!("the node's disk is {}", self.disk_monitor.status().as_str()),
+			));
+		}
+		Ok(json!({
+			"paused": self.pause_switch.is_paused(),
+			"disk_status": self.disk_monitor.status().as_str(),
+			"disk_free_bytes": self.disk_monitor.free_bytes(),
+		}))
+	}
+
+	fn node_info(&self) -> Value {
+		let fd_usage = connection_guard::fd_usage();
+		let (channel_peers, gossip_peers) = self.connection_guard.connected_peers();
+		let (evicted_peers, refused_inbound) = self.connection_guard.counters();
+		json!({
+			"node_pubkey": self.channel_manager.get_our_node_id().to_string(),
+			"num_channels": self.channel_manager.list_channels().len(),
+			"num_usable_channels": self.channel_manager.list_usable_channels().len(),
+			"num_peers": self.peer_manager.get_peer_node_ids().len(),
+			"paused": self.pause_switch.is_paused(),
+			"disk_status": self.disk_monitor.status().as_str(),
+			"disk_free_bytes": self.disk_monitor.free_bytes(),
+			"open_fds": fd_usage.as_ref().map(|usage| usage.open),
+			"fd_limit": fd_usage.as_ref().map(|usage| usage.limit),
+			"connected_channel_peers": channel_peers,
+			"connected_gossip_peers": gossip_peers,
+			"evicted_gossip_peers": evicted_peers,
+			"refused_inbound_connections": refused_inbound,
+		})
+	}
+
+	fn list_channels(&self) -> Value {
+		let best_block_height = self.channel_manager.current_best_block().height();
+		let commitment_feerate = balances::commitment_feerate(&self.bitcoind_client);
+		let channels: Vec<Value> = self
+			.channel_manager
+			.list_channels()
+			.iter()
+			.map(|chan_info| {
+				json!({
+					"channel_id": hex_utils::hex_str(&chan_info.channel_id[..]),
+					"funding_txid": chan_info.funding_txo.map(|txo| txo.txid.to_string()),
+					"funding_outpoint": chan_info
+						.funding_txo
+						.map(|txo| format!("{}:{}", txo.txid, txo.index)),
+					"peer_pubkey": chan_info.counterparty.node_id.to_string(),
+					"is_outbound": chan_info.is_outbound,
+					"short_channel_id": chan_info.short_channel_id,
+					"is_confirmed_onchain": chan_info.is_funding_locked,
+					"confirmations":
+						cli::funding_confirmations(chan_info, best_block_height).unwrap_or(0),
+					"confirmations_required": chan_info.confirmations_required,
+					"channel_value_satoshis": chan_info.channel_value_satoshis,
+					"local_balance_msat": balances::channel_local_balance_msat(chan_info),
+					"remote_balance_msat": balances::channel_remote_balance_msat(chan_info),
+					"available_balance_for_send_msat": chan_info.outbound_capacity_msat,
+					"available_balance_for_recv_msat": chan_info.inbound_capacity_msat,
+					"spendable_now_msat":
+						balances::channel_spendable_msat(chan_info, commitment_feerate),
+					"channel_can_send_payments": chan_info.is_usable,
+					"public": chan_info.is_public,
+				})
+			})
+			.collect();
+		Value::Array(channels)
+	}
+
+	fn list_peers(&self) -> Value {
+		let connected_peers = self.peer_manager.get_peer_node_ids();
+		let peer_uptime = self.peer_uptime.lock().unwrap();
+		let mut pubkeys = connected_peers.clone();
+		// Also list channel counterparties we're tracking but which are currently offline.
+		for pubkey in peer_uptime.keys() {
+			if !pubkeys.contains(pubkey) {
+				pubkeys.push(*pubkey);
+			}
+		}
+		let peers: Vec<Value> = pubkeys
+			.iter()
+			.map(|pubkey| {
+				let uptime = peer_uptime.get(pubkey);
+				json!({
+					"pubkey": pubkey.to_string(),
+					"connected": connected_peers.contains(pubkey),
+					"uptime_24h_pct": uptime.and_then(|u| u.uptime_pct(24)),
+					"uptime_7d_pct": uptime.and_then(|u| u.uptime_pct(24 * 7)),
+				})
+			})
+			.collect();
+		Value::Array(peers)
+	}
+
+	/// Expects `{"peer": "pubkey@host:port", "amount_sat": <u64>, "public": <bool>}`, where
+	/// `public` is optional. Unlike the CLI, this doesn't ask for confirmation before spending from
+	/// a dedicated funding wallet, as the caller explicitly asked for the channel.
+	async fn open_channel(&self, params: Value) -> Result<Value, RpcError> {
+		self.check_not_paused()?;
+		let peer = params["peer"].as_str().ok_or_else(|| bad_request("missing `peer`"))?;
+		let amount_sat =
+			params["amount_sat"].as_u64().ok_or_else(|| bad_request("missing `amount_sat`"))?;
+		let announce_channel = match &params["public"] {
+			Value::Null => self.user_config.channel_options.announced_channel,
+			Value::Bool(public) => *public,
+			_ => return Err(bad_request("`public` must be a boolean")),
+		};
+		let (pubkey, peer_addr) =
+			cli::parse_peer_info(peer.to_string()).map_err(|e| bad_request(&e.to_string()))?;
+
+		if cli::connect_peer_if_necessary(
+			pubkey,
+			peer_addr,
+			self.peer_manager.clone(),
+			self.proxy_addr,
+		)
+		.await
+		.is_err()
+		{
+			return Err((502, "failed to connect to peer".to_string()));
+		}
+		if cli::open_channel(
+			pubkey,
+			amount_sat,
+			announce_channel,
+			self.user_config,
+			self.channel_manager.clone(),
+			&self.peer_limits,
+			None,
+		)
+		.is_err()
+		{
+			return Err((500, "failed to open channel".to_string()));
+		}
+		let _ = disk::persist_channel_peer(&*self.storage, peer);
+		Ok(json!({ "peer_pubkey": pubkey.to_string() }))
+	}
+
+	/// Expects `{"invoice": "<bolt11 invoice>"}`, optionally with `max_retries`, `max_fee_msat`,
+	/// `max_fee_percent`, `timeout_secs` and `deadline_secs` limits, and `shadow_route` and
+	/// `randomize_final_cltv` booleans overriding the node's privacy defaults. Invoices without an
+	/// amount also need an `amount_msat`.
+	fn send_payment(&self, params: Value) -> Result<Value, RpcError> {
+		self.check_not_paused()?;
+		let invoice_str =
+			params["invoice"].as_str().ok_or_else(|| bad_request("missing `invoice`"))?;
+		let invoice = Invoice::from_str(invoice_str)
+			.map_err(|e| bad_request(&format!("invalid invoice: {:?}", e)))?;
+		cli::check_invoice_network(&invoice, self.network).map_err(|e| bad_request(&e))?;
+		let optional_u64 = |key: &str| match &params[key] {
+			Value::Null => Ok(None),
+			value => value
+				.as_u64()
+				.map(Some)
+				.ok_or_else(|| bad_request(&format!("`{}` must be a number", key))),
+		};
+		let amt_msat = cli::invoice_amount_msat(&invoice, optional_u64("amount_msat")?)
+			.map_err(|e| bad_request(&e))?;
+		let max_fee_percent = match &params["max_fee_percent"] {
+			Value::Null => None,
+			value => match value.as_f64() {
+				Some(percent) if percent >= 0.0 => Some((amt_msat as f64 * percent / 100.0) as u64),
+				_ => return Err(bad_request("`max_fee_percent` must be a non-negative number")),
+			},
+		};
+		let max_fee_msat = match (optional_u64("max_fee_msat")?, max_fee_percent) {
+			(Some(msat), Some(percent_msat)) => Some(msat.min(percent_msat)),
+			(msat, percent_msat) => msat.or(percent_msat),
+		};
+		let limits = RetryLimits {
+			max_retries: optional_u64("max_retries")?.map(|n| n as usize),
+			max_fee_msat,
+			retry_until: optional_u64("timeout_secs")?.map(|secs| time_now_secs() + secs),
+			deadline: optional_u64("deadline_secs")?.map(|secs| time_now_secs() + secs),
+		};
+		let optional_bool = |key: &str| match &params[key] {
+			Value::Null => Ok(None),
+			value => value
+				.as_bool()
+				.map(Some)
+				.ok_or_else(|| bad_request(&format!("`{}` must be a boolean", key))),
+		};
+		let privacy = PrivacyOverrides {
+			shadow_route: optional_bool("shadow_route")?,
+			randomize_final_cltv: optional_bool("randomize_final_cltv")?,
+		};
+		let payment_hash = cli::send_payment(
+			&*self.invoice_payer,
+			&self.payer,
+			&invoice,
+			amt_msat,
+			limits,
+			privacy,
+			self.outbound_payments.clone(),
+		)
+		.map_err(|e| (500, e))?;
+		Ok(json!({ "payment_hash": hex_utils::hex_str(&payment_hash.0) }))
+	}
+
+	/// Expects `{"amount_msat": <u64>}`, optionally with `"expiry_secs": <u32>` and one of
+	/// `"description": <string>` or `"description_hash": <hex>`. With `"hold": true` the payment
+	/// isn't claimed when it arrives, but held for up to `hold_timeout_secs` until it's settled or
+	/// cancelled.
+	fn get_invoice(&self, params: Value) -> Result<Value, RpcError> {
+		let amount_msat =
+			params["amount_msat"].as_u64().ok_or_else(|| bad_request("missing `amount_msat`"))?;
+		let mut options = InvoiceOptions::default();
+		if !params["expiry_secs"].is_null() {
+			options.expiry_secs = params["expiry_secs"]
+				.as_u64()
+				.filter(|secs| *secs > 0 && *secs <= u32::max_value() as u64)
+				.ok_or_else(|| bad_request("`expiry_secs` must be a positive number of seconds"))?
+				as u32;
+		}
+		match (&params["description"], &params["description_hash"]) {
+			(Value::Null, Value::Null) => {}
+			(Value::String(description), Value::Null) => {
+				options.description = InvoiceDescription::Direct(description.clone())
+			}
+			(Value::Null, Value::String(hash)) => {
+				let hash = hex_utils::to_vec(hash).filter(|h| h.len() == 32).ok_or_else(|| {
+					bad_request("`description_hash` must be 32 hex-encoded bytes")
+				})?;
+				options.description = InvoiceDescription::Hash(Sha256::from_slice(&hash).unwrap());
+			}
+			_ => {
+				return Err(bad_request(
+					"give one of `description` and `description_hash`, as a string",
+				))
+			}
+		}
+		let hold = match &params["hold"] {
+			Value::Null => false,
+			Value::Bool(hold) => *hold,
+			_ => return Err(bad_request("`hold` must be a boolean")),
+		};
+		let hold_timeout_secs = match &params["hold_timeout_secs"] {
+			Value::Null => DEFAULT_HOLD_TIMEOUT_SECS,
+			value => value.as_u64().filter(|secs| *secs > 0).ok_or_else(|| {
+				bad_request("`hold_timeout_secs` must be a positive number of seconds")
+			})?,
+		};
+		let invoice = cli::get_invoice(
+			amount_msat,
+			options,
+			self.inbound_payments.clone(),
+			self.channel_manager.clone(),
+			self.keys_manager.clone(),
+			self.network,
+			self.min_final_cltv_expiry,
+		)
+		.map_err(|e| (500, e))?;
+		if hold {
+			self.hold_invoices
+				.add(PaymentHash(invoice.payment_hash().clone().into_inner()), hold_timeout_secs);
+		}
+		Ok(json!({
+			"invoice": invoice.to_string(),
+			"payment_hash": invoice.payment_hash().to_string(),
+			"hold": hold,
+		}))
+	}
+
+	/// Expects `{"payment_hash": <hex>}` of a held payment, which is claimed.
+	fn settle_invoice(&self, params: Value) -> Result<Value, RpcError> {
+		self.check_not_paused()?;
+		let payment_hash = parse_payment_hash(&params)?;
+		let amount_msat = self
+			.hold_invoices
+			.settle(
+				&payment_hash,
+				&self.channel_manager,
+				&self.inbound_payments,
+				self.settlement_webhook.as_deref(),
+			)
+			.map_err(|e| bad_request(&e))?;
+		Ok(json!({
+			"payment_hash": hex_utils::hex_str(&payment_hash.0),
+			"amount_msat": amount_msat,
+		}))
+	}
+
+	/// Expects `{"payment_hash": <hex>}` of a held payment, which is failed back to the payer.
+	fn cancel_invoice(&self, params: Value) -> Result<Value, RpcError> {
+		let payment_hash = parse_payment_hash(&params)?;
+		self.hold_invoices
+			.cancel(&payment_hash, &self.channel_manager, &self.inbound_payments)
+			.map_err(|e| bad_request(&e))?;
+		Ok(json!({ "payment_hash": hex_utils::hex_str(&payment_hash.0) }))
+	}
+
+	/// Expects `{"payment_hash": <hex>}` of an invoice which expired unpaid.
+	fn reissue_invoice(&self, params: Value) -> Result<Value, RpcError> {
+		let payment_hash = parse_payment_hash(&params)?;
+		let invoice = cli::reissue_invoice(
+			&payment_hash,
+			&self.inbound_payments,
+			&self.invoice_bundles,
+			&self.channel_manager,
+			&self.keys_manager,
+			self.network,
+			self.min_final_cltv_expiry,
+		)
+		.map_err(|e| bad_request(&e))?;
+		Ok(json!({
+			"invoice": invoice.to_string(),
+			"payment_hash": invoice.payment_hash().to_string(),
+			"reissue_of": hex_utils::hex_str(&payment_hash.0),
+		}))
+	}
+
+	/// Expects `{"amounts_msat": [<u64>, ...]}`, creating one invoice per amount.
+	fn create_bundle(&self, params: Value) -> Result<Value, RpcError> {
+		let amounts_msat = params["amounts_msat"]
+			.as_array()
+			.and_then(|amounts| amounts.iter().map(Value::as_u64).collect::<Option<Vec<u64>>>())
+			.filter(|amounts| !amounts.is_empty())
+			.ok_or_else(|| bad_request("`amounts_msat` must be a non-empty list of amounts"))?;
+		let (bundle_id, invoices) = cli::create_bundle(
+			&amounts_msat,
+			self.invoice_bundles.clone(),
+			self.inbound_payments.clone(),
+			self.channel_manager.clone(),
+			self.keys_manager.clone(),
+			self.network,
+			self.min_final_cltv_expiry,
+		)
+		.map_err(|e| (500, e))?;
+		let invoices: Vec<String> = invoices.iter().map(|invoice| invoice.to_string()).collect();
+		Ok(json!({ "bundle_id": bundle_id, "invoices": invoices }))
+	}
+
+	/// Expects `{"bundle_id": <u64>}`.
+	fn bundle_status(&self, params: Value) -> Result<Value, RpcError> {
+		let bundle_id =
+			params["bundle_id"].as_u64().ok_or_else(|| bad_request("missing `bundle_id`"))?;
+		let bundles = self.invoice_bundles.lock().unwrap();
+		let bundle = bundles
+			.get(bundle_id)
+			.ok_or_else(|| (404, format!("unknown invoice bundle {}", bundle_id)))?;
+		let inbound = self.inbound_payments.lock().unwrap();
+		let invoices: Vec<Value> = bundle
+			.payment_hashes
+			.iter()
+			.map(|payment_hash| {
+				let payment = inbound.get(payment_hash);
+				json!({
+					"payment_hash": hex_utils::hex_str(&payment_hash.0),
+					"amount_msat": payment.and_then(|p| p.amt_msat.0),
+					"status": match payment.map(|p| &p.status) {
+						Some(HTLCStatus::Pending) => "pending",
+						Some(HTLCStatus::Succeeded) => "succeeded",
+						Some(HTLCStatus::Failed) => "failed",
+						Some(HTLCStatus::TimedOut) => "timed_out",
+						None => "unknown",
+					},
+				})
+			})
+			.collect();
+		Ok(json!({
+			"bundle_id": bundle_id,
+			"status": bundle.status(&inbound).as_str(),
+			"created_at": bundle.created_at,
+			"invoices": invoices,
+		}))
+	}
+}
+
+fn status_text(status: u16) -> &'static str {
+	match status {
+		200 => "OK",
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		403 => "Forbidden",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		413 => "Payload Too Large",
+		415 => "Unsupported Media Type",
+		431 => "Request Header Fields Too Large",
+		502 => "Bad Gateway",
+		503 => "Service Unavailable",
+		_ => "Internal Server Error",
+	}
+}
+
+/// What we look at of a request's head.
+#[derive(Default)]
+struct RequestHead {
+	method: String,
+	path: String,
+	content_len: usize,
+	content_type: Option<String>,
+	authorization: Option<String>,
+	origin: Option<String>,
+	host: Option<String>,
+}
+
+/// Reads a line of at most `MAX_LINE_LEN` bytes, returning `None` if it's longer.
+async fn read_line_capped(
+	reader: &mut BufReader<OwnedReadHalf>, line: &mut String,
+) -> io::Result<Option<usize>> {
+	let len = reader.take(MAX_LINE_LEN + 1).read_line(line).await?;
+	if len as u64 > MAX_LINE_LEN {
+		return Ok(None);
+	}
+	Ok(Some(len))
+}
+
+async fn read_head(
+	reader: &mut BufReader<OwnedReadHalf>,
+) -> io::Result<Result<RequestHead, RpcError>> {
+	let too_large = || Err((431, "request line or headers too large".to_string()));
+	let mut request_line = String::new();
+	if read_line_capped(reader, &mut request_line).await?.is_none() {
+		return Ok(too_large());
+	}
+	let mut request_parts = request_line.split_whitespace();
+	let mut head = RequestHead {
+		method: request_parts.next().unwrap_or("").to_string(),
+		path: request_parts.next().unwrap_or("").to_string(),
+		..Default::default()
+	};
+
+	let mut num_headers = 0;
+	loop {
+		let mut header = String::new();
+		match read_line_capped(reader, &mut header).await? {
+			None => return Ok(too_large()),
+			Some(0) => break,
+			Some(_) if header.trim().is_empty() => break,
+			Some(_) => {}
+		}
+		num_headers += 1;
+		if num_headers > MAX_HEADERS {
+			return Ok(too_large());
+		}
+		let mut header_parts = header.splitn(2, ':');
+		let name = header_parts.next().unwrap_or("").trim().to_ascii_lowercase();
+		let value = header_parts.next().unwrap_or("").trim().to_string();
+		match name.as_str() {
+			"content-length" => head.content_len = value.parse().unwrap_or(0),
+			"content-type" => head.content_type = Some(value),
+			"authorization" => head.authorization = Some(value),
+			"origin" => head.origin = Some(value),
+			"host" => head.host = Some(value),
+			_ => {}
+		}
+	}
+	Ok(Ok(head))
+}
+
+/// Checks that a request comes from a client holding our token, rather than e.g. from a web page
+/// the operator visits making a cross-site request to a local port.
+fn check_request(head: &RequestHead, auth_token: &str) -> Result<(), RpcError> {
+	// Browsers send an `Origin` with cross-site requests, and our clients have no reason to send
+	// one for any other site than us.
+	if let Some(origin) = &head.origin {
+		let own_origin = head.host.as_ref().map(|host| format!("http://{}", host));
+		if own_origin.as_ref() != Some(origin) {
+			return Err((403, "cross-origin requests aren't allowed".to_string()));
+		}
+	}
+	let given_token = head.authorization.as_ref().and_then(|auth| auth.strip_prefix("Bearer "));
+	if !given_token.map_or(false, |given| token_matches(given.trim(), auth_token)) {
+		return Err((401, format!("missing or wrong bearer token, see {}", AUTH_COOKIE_FILE)));
+	}
+	// A page can only send other content types cross-site after a CORS preflight, which we
+	// never answer.
+	let is_json = head.content_type.as_ref().map_or(false, |content_type| {
+		content_type.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/json")
+	});
+	if (head.method == "POST" || head.content_len > 0) && !is_json {
+		return Err((415, "requests must have `Content-Type: application/json`".to_string()));
+	}
+	Ok(())
+}
+
+async fn handle_connection<E: EventHandler>(
+	stream: TcpStream, server: Arc<RpcServer<E>>,
+) -> io::Result<()> {
+	let (reader, mut writer) = stream.into_split();
+	let mut reader = BufReader::new(reader);
+
+	let head = read_head(&mut reader).await?;
+	let head = head.and_then(|head| check_request(&head, &server.auth_token).map(|_| head));
+	if let Ok(head) = &head {
+		if head.method == "GET" && head.path == "/events" {
+			return event_stream::serve(writer, server.event_stream.subscribe()).await;
+		}
+	}
+
+	let res = match head {
+		Err(e) => Err(e),
+		Ok(head) if head.content_len > MAX_BODY_LEN => {
+			Err((413, "request body too large".to_string()))
+		}
+		Ok(head) => {
+			let (method, path, content_len) = (head.method, head.path, head.content_len);
+			let mut body = vec![0; content_len];
+			reader.read_exact(&mut body).await?;
+			let params =
+				if body.is_empty() { Ok(Value::Null) } else { serde_json::from_slice(&body) };
+			match params {
+				Ok(params) => server.handle_request(&method, &path, params).await,
+				Err(e) => Err(bad_request(&format!("invalid JSON body: {}", e))),
+			}
+		}
+	};
+	let (status, body) = match res {
+		Ok(result) => (200, result.to_string()),
+		Err((status, msg)) => (status, json!({ "error": msg }).to_string()),
+	};
+	let response = format!(
+		"HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status,
+		status_text(status),
+		body.len(),
+		body
+	);
+	writer.write_all(response.as_bytes()).await?;
+	writer.shutdown().await
+}
+
+/// Serves the JSON control API on `listener`. Requests must carry the token from the auth cookie,
+/// but it's sent in the clear, so this should still only be bound to a local or otherwise trusted
+/// interface.
+pub(crate) fn start_rpc_server<E: EventHandler + Send + Sync + 'static>(
+	listener: std::net::TcpListener, server: RpcServer<E>,
+) {
+	let server = Arc::new(server);
+	let listener = listener
+		.set_nonblocking(true)
+		.and_then(|_| TcpListener::from_std(listener))
+		.expect("Failed to use RPC listen socket");
+	tokio::spawn(async move {
+		loop {
+			let stream = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(_) => continue,
+			};
+			let server = server.clone();
+			tokio::spawn(async move {
+				let _ = handle_connection(stream, server).await;
+			});
+		}
+	});
+}
@@ -0,0 +1,198 @@
+use crate::hex_utils;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{
+	Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+/// How we check the certificate of a bitcoind RPC server we reach over TLS.
+#[derive(Clone)]
+pub struct RpcTls {
+	/// If set, the server's certificate must be signed by one of the CAs in this PEM file, rather
+	/// than by one of the usual public CAs.
+	pub ca_file: Option<PathBuf>,
+	/// If set, the SHA256 fingerprint the server's certificate must have, in which case it isn't
+	/// otherwise checked, e.g. for a self-signed certificate.
+	pub cert_sha256: Option<[u8; 32]>,
+	/// How long we wait to connect and complete the TLS handshake before giving up.
+	pub connect_timeout: Duration,
+}
+
+/// Parses a certificate fingerprint as printed by `openssl x509 -noout -fingerprint -sha256`,
+/// with or without the colons.
+pub fn parse_fingerprint(s: &str) -> Option<[u8; 32]> {
+	let hex: String = s.chars().filter(|c| *c != ':').collect();
+	let bytes = hex_utils::to_vec(&hex.to_ascii_lowercase())?;
+	if bytes.len() != 32 {
+		return None;
+	}
+	let mut fingerprint = [0; 32];
+	fingerprint.copy_from_slice(&bytes);
+	Some(fingerprint)
+}
+
+/// Accepts exactly the certificate with the pinned fingerprint.
+struct PinnedCertVerifier {
+	cert_sha256: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+	fn verify_server_cert(
+		&self, _roots: &RootCertStore, presented_certs: &[Certificate], _dns_name: DNSNameRef,
+		_ocsp_response: &[u8],
+	) -> Result<ServerCertVerified, TLSError> {
+		match presented_certs.first() {
+			Some(cert) if Sha256::hash(&cert.0).into_inner() == self.cert_sha256 => {
+				Ok(ServerCertVerified::assertion())
+			}
+			Some(cert) => Err(TLSError::General(format!(
+				"bitcoind's certificate has fingerprint {}, not the pinned one",
+				Sha256::hash(&cert.0)
+			))),
+			None => Err(TLSError::NoCertificatesPresented),
+		}
+	}
+}
+
+struct TlsTarget {
+	host: String,
+	port: u16,
+	connector: TlsConnector,
+	/// The name we check the server's certificate against. Without a pinned certificate this must
+	/// be the host we connect to, which therefore can't be an IP address, as certificates are only
+	/// checked against DNS names.
+	server_name: String,
+	connect_timeout: Duration,
+}
+
+impl TlsTarget {
+	fn new(host: &str, port: u16, tls: &RpcTls) -> Result<Self, String> {
+		let mut config = ClientConfig::new();
+		let host_is_ip = host.parse::<IpAddr>().is_ok();
+		let server_name = match tls.cert_sha256 {
+			Some(cert_sha256) => {
+				config
+					.dangerous()
+					.set_certificate_verifier(Arc::new(PinnedCertVerifier { cert_sha256 }));
+				// The name isn't checked, and an IP address isn't a valid SNI name.
+				config.enable_sni = !host_is_ip;
+				if host_is_ip {
+					"bitcoind".to_string()
+				} else {
+					host.to_string()
+				}
+			}
+			None => {
+				if host_is_ip {
+					return Err(format!("bitcoind's certificate can only be checked against a host name, not {} - connect by name or pin its certificate", host));
+				}
+				match &tls.ca_file {
+					Some(path) => {
+						let file = File::open(path).map_err(|e| {
+							format!("failed to read CA file {}: {}", path.display(), e)
+						})?;
+						match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+							Ok((added, _)) if added > 0 => {}
+							_ => {
+								return Err(format!(
+									"no CA certificates found in {}",
+									path.display()
+								))
+							}
+						}
+					}
+					None => {
+						config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS)
+					}
+				}
+				host.to_string()
+			}
+		};
+		if DNSNameRef::try_from_ascii_str(&server_name).is_err() {
+			return Err(format!("{} isn't a valid host name", server_name));
+		}
+		Ok(Self {
+			host: host.to_string(),
+			port,
+			connector: TlsConnector::from(Arc::new(config)),
+			server_name,
+			connect_timeout: tls.connect_timeout,
+		})
+	}
+
+	async fn connect(&self) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+		let server_name = DNSNameRef::try_from_ascii_str(&self.server_name).unwrap();
+		let connect = async {
+			let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+			self.connector.connect(server_name, stream).await
+		};
+		match tokio::time::timeout(self.connect_timeout, connect).await {
+			Ok(res) => res,
+			Err(_) => Err(io::Error::new(
+				io::ErrorKind::TimedOut,
+				format!(
+					"couldn't connect to bitcoind at {}:{} within {:?}",
+					self.host, self.port, self.connect_timeout
+				),
+			)),
+		}
+	}
+}
+
+/// Starts forwarding connections made to a local port on to bitcoind's RPC server at `host:port`
+/// over TLS, like stunnel does, returning the local address to make RPC calls to. We check that we
+/// can complete a handshake first, so a bad certificate is reported up front rather than as
+/// bitcoind dropping our connections.
+///
+/// The local port only accepts connections from this machine, and bitcoind still requires our
+/// RPC credentials over it.
+pub async fn start_tunnel(host: &str, port: u16, tls: &RpcTls) -> Result<SocketAddr, String> {
+	let target = Arc::new(TlsTarget::new(host, port, tls)?);
+	target.connect().await.map_err(|e| format!("TLS connection to bitcoind failed: {}", e))?;
+	let listener = TcpListener::bind("127.0.0.1:0")
+		.await
+		.map_err(|e| format!("failed to listen for bitcoind RPC connections: {}", e))?;
+	let local_addr = listener.local_addr().map_err(|e| e.to_string())?;
+
+	tokio::spawn(async move {
+		let failing = Arc::new(AtomicBool::new(false));
+		loop {
+			let mut local = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(_) => continue,
+			};
+			let target = target.clone();
+			let failing = failing.clone();
+			tokio::spawn(async move {
+				let mut remote = match target.connect().await {
+					Ok(remote) => {
+						if failing.swap(false, Ordering::AcqRel) {
+							eprintln!("TLS connections to bitcoind are working again");
+						}
+						remote
+					}
+					Err(e) => {
+						// Dropping the local connection fails the RPC call, which is retried.
+						if !failing.swap(true, Ordering::AcqRel) {
+							eprintln!("Warning: TLS connection to bitcoind failed: {}", e);
+						}
+						return;
+					}
+				};
+				let _ = tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+			});
+		}
+	});
+	Ok(local_addr)
+}
@@ -0,0 +1,198 @@
+use crate::cli;
+use crate::disk::FilesystemLogger;
+use crate::fee_budget::FeeBudget;
+use crate::hex_utils;
+use crate::pause::PauseSwitch;
+use crate::route_exclusions::RouteExclusionsStorage;
+use crate::{time_now_secs, ChannelManager, PaymentInfoStorage};
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::routing::network_graph::NetworkGraph;
+use lightning::routing::scorer::Scorer;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often we check for scheduled payments which are due.
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A standing order to keysend `amt_msat` to `payee` every `interval_secs` seconds. BOLT 12 offers
+/// aren't supported by LDK 0.0.103 and invoices can only be paid once, so keysend is the only way
+/// to pay the same recipient repeatedly without them handing us a new invoice each time.
+pub(crate) struct ScheduledPayment {
+	pub(crate) payee: PublicKey,
+	pub(crate) amt_msat: u64,
+	pub(crate) interval_secs: u64,
+	/// When the payment is next due, in seconds since the UNIX epoch.
+	pub(crate) next_run: u64,
+}
+
+/// Scheduled payments by id, written to disk whenever they change.
+pub(crate) struct ScheduleStore {
+	path: String,
+	schedules: HashMap<u64, ScheduledPayment>,
+}
+
+pub(crate) type ScheduleStorage = Arc<Mutex<ScheduleStore>>;
+
+impl ScheduleStore {
+	pub(crate) fn new(path: String) -> Self {
+		let schedules = read_schedules(Path::new(&path));
+		Self { path, schedules }
+	}
+
+	pub(crate) fn schedules(&self) -> &HashMap<u64, ScheduledPayment> {
+		&self.schedules
+	}
+
+	/// Schedules a new payment, first due one interval from now, returning its id.
+	pub(crate) fn insert(&mut self, payee: PublicKey, amt_msat: u64, interval_secs: u64) -> u64 {
+		let schedule_id = self.schedules.keys().max().map_or(1, |id| id + 1);
+		let next_run = time_now_secs() + interval_secs;
+		self.schedules
+			.insert(schedule_id, ScheduledPayment { payee, amt_msat, interval_secs, next_run });
+		self.persist();
+		schedule_id
+	}
+
+	pub(crate) fn remove(&mut self, schedule_id: u64) -> bool {
+		let removed = self.schedules.remove(&schedule_id).is_some();
+		self.persist();
+		removed
+	}
+
+	fn persist(&self) {
+		if persist_schedules(Path::new(&self.path), &self.schedules).is_err() {
+			eprintln!(
+				"Warning: Failed to persist scheduled payments, check your disk and permissions"
+			);
+		}
+	}
+}
+
+/// Each schedule is stored as a line of the form
+/// `<id> <payee_pubkey> <amt_msat> <interval_secs> <next_run>`.
+fn persist_schedules(
+	path: &Path, schedules: &HashMap<u64, ScheduledPayment>,
+) -> std::io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for (schedule_id, schedule) in schedules.iter() {
+		file.write_all(
+			format!(
+				"{} {} {} {} {}\n",
+				schedule_id,
+				schedule.payee,
+				schedule.amt_msat,
+				schedule.interval_secs,
+				schedule.next_run
+			)
+			.as_bytes(),
+		)?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+fn read_schedules(path: &Path) -> HashMap<u64, ScheduledPayment> {
+	let mut schedules = HashMap::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return schedules,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		let parts: Vec<&str> = line.split(' ').collect();
+		if parts.len() != 5 {
+			eprintln!("Warning: Skipping malformed scheduled payment entry in {:?}", path);
+			continue;
+		}
+		match (
+			parts[0].parse(),
+			hex_utils::to_compressed_pubkey(parts[1]),
+			parts[2].parse(),
+			parts[3].parse(),
+			parts[4].parse(),
+		) {
+			(Ok(schedule_id), Some(payee), Ok(amt_msat), Ok(interval_secs), Ok(next_run)) => {
+				schedules.insert(
+					schedule_id,
+					ScheduledPayment { payee, amt_msat, interval_secs, next_run },
+				);
+			}
+			_ => eprintln!("Warning: Skipping malformed scheduled payment entry in {:?}", path),
+		}
+	}
+	schedules
+}
+
+/// Regularly sends any scheduled payments which are due. If we were offline for several intervals,
+/// a payment is only sent once when we come back rather than once per missed interval.
+pub(crate) fn start_payment_scheduler(
+	schedules: ScheduleStorage, network_graph: Arc<NetworkGraph>,
+	channel_manager: Arc<ChannelManager>, outbound_payments: PaymentInfoStorage,
+	logger: Arc<FilesystemLogger>, scorer: Arc<Mutex<Scorer>>, fee_budget: FeeBudget,
+	payer_secret: Option<[u8; 32]>, pause_switch: Arc<PauseSwitch>,
+	route_exclusions: RouteExclusionsStorage,
+) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+		loop {
+			interval.tick().await;
+			// Payments which come due while we're paused are sent once we resume.
+			if pause_switch.is_paused() {
+				continue;
+			}
+			let now = time_now_secs();
+			let mut store = schedules.lock().unwrap();
+			let due: Vec<u64> = store
+				.schedules
+				.iter()
+				.filter(|(_, schedule)| schedule.next_run <= now)
+				.map(|(schedule_id, _)| *schedule_id)
+				.collect();
+			if due.is_empty() {
+				continue;
+			}
+			for schedule_id in due {
+				let schedule = store.schedules.get_mut(&schedule_id).unwrap();
+				println!(
+					"\nEVENT: running scheduled payment {} of {} msats to {}",
+					schedule_id, schedule.amt_msat, schedule.payee
+				);
+				let payment_hash = cli::keysend(
+					schedule.payee,
+					schedule.amt_msat,
+					network_graph.clone(),
+					channel_manager.clone(),
+					outbound_payments.clone(),
+					logger.clone(),
+					scorer.clone(),
+					fee_budget,
+					payer_secret,
+					route_exclusions.clone(),
+				);
+				if let Some(payment_hash) = payment_hash {
+					println!(
+						"EVENT: scheduled payment {} sent with payment hash {}",
+						schedule_id,
+						hex_utils::hex_str(&payment_hash.0)
+					);
+				}
+				schedule.next_run = now + schedule.interval_secs;
+				print!("> ");
+				io::stdout().flush().unwrap();
+			}
+			store.persist();
+		}
+	});
+}
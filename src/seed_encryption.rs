@@ -0,0 +1,136 @@
+use rand::{thread_rng, Rng};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Starts every encrypted seed file, followed by the parameters it was encrypted with. Bump the
+/// version whenever they change, so that older files can still be decrypted.
+const MAGIC: &[u8; 8] = b"ldkseed1";
+
+/// PBKDF2-HMAC-SHA256 rounds for new files, as OWASP recommends for SHA-256.
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+const SALT_LEN: usize = 16;
+
+/// The magic, the number of rounds, the salt and the nonce.
+const HEADER_LEN: usize = MAGIC.len() + 4 + SALT_LEN + NONCE_LEN;
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: NonZeroU32) -> LessSafeKey {
+	let mut key = [0; 32];
+	pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, rounds, salt, passphrase.as_bytes(), &mut key);
+	LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+}
+
+/// Encrypts the seed under a key derived from `passphrase`. The header is authenticated along
+/// with the seed, so its parameters can't be tampered with either.
+fn encrypt_seed(seed: &[u8; 32], passphrase: &str) -> Vec<u8> {
+	let mut salt = [0; SALT_LEN];
+	thread_rng().fill_bytes(&mut salt);
+	let mut nonce = [0; NONCE_LEN];
+	thread_rng().fill_bytes(&mut nonce);
+	let mut file = Vec::with_capacity(HEADER_LEN + seed.len() + CHACHA20_POLY1305.tag_len());
+	file.extend_from_slice(MAGIC);
+	file.extend_from_slice(&PBKDF2_ROUNDS.to_be_bytes());
+	file.extend_from_slice(&salt);
+	file.extend_from_slice(&nonce);
+
+	let key = derive_key(passphrase, &salt, NonZeroU32::new(PBKDF2_ROUNDS).unwrap());
+	let mut ciphertext = seed.to_vec();
+	key.seal_in_place_append_tag(
+		Nonce::assume_unique_for_key(nonce),
+		Aad::from(&file[..]),
+		&mut ciphertext,
+	)
+	.unwrap();
+	file.extend_from_slice(&ciphertext);
+	file
+}
+
+fn decrypt_seed(file: &[u8], passphrase: &str) -> Result<[u8; 32], String> {
+	if file.len() != HEADER_LEN + 32 + CHACHA20_POLY1305.tag_len() || !file.starts_with(MAGIC) {
+		return Err("the encrypted seed file is malformed".to_string());
+	}
+	let (header, ciphertext) = file.split_at(HEADER_LEN);
+	let mut rounds = [0; 4];
+	rounds.copy_from_slice(&header[MAGIC.len()..MAGIC.len() + 4]);
+	let rounds = NonZeroU32::new(u32::from_be_bytes(rounds))
+		.ok_or_else(|| "the encrypted seed file is malformed".to_string())?;
+	let salt = &header[MAGIC.len() + 4..MAGIC.len() + 4 + SALT_LEN];
+	let mut nonce = [0; NONCE_LEN];
+	nonce.copy_from_slice(&header[HEADER_LEN - NONCE_LEN..]);
+
+	let key = derive_key(passphrase, salt, rounds);
+	let mut ciphertext = ciphertext.to_vec();
+	let plaintext = key
+		.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(header), &mut ciphertext)
+		.map_err(|_| "wrong passphrase".to_string())?;
+	let mut seed = [0; 32];
+	seed.copy_from_slice(plaintext);
+	Ok(seed)
+}
+
+/// Reads the passphrase from the first line of `password_file` if given, or else prompts for it
+/// on the terminal, twice if it's a new one so that a typo doesn't lock the node out.
+pub(crate) fn read_passphrase(password_file: Option<&str>, new: bool) -> Result<String, String> {
+	let passphrase = match password_file {
+		Some(path) => fs::read_to_string(path)
+			.map_err(|e| format!("failed to read password file {}: {}", path, e))?
+			.lines()
+			.next()
+			.unwrap_or("")
+			.to_string(),
+		None => {
+			let passphrase = prompt("Seed passphrase: ")?;
+			if new && prompt("Repeat the seed passphrase: ")? != passphrase {
+				return Err("the passphrases don't match".to_string());
+			}
+			passphrase
+		}
+	};
+	if passphrase.is_empty() {
+		return Err("the seed passphrase is empty".to_string());
+	}
+	Ok(passphrase)
+}
+
+/// Reads a line from stdin, hiding what's typed if stdin is a terminal `stty` can control.
+fn prompt(message: &str) -> Result<String, String> {
+	print!("{}", message);
+	io::stdout().flush().unwrap();
+	let set_echo = |echo: &str| {
+		Command::new("stty").arg(echo).stdin(Stdio::inherit()).stderr(Stdio::null()).status()
+	};
+	let hidden = set_echo("-echo").map_or(false, |status| status.success());
+	let mut line = String::new();
+	let read = io::stdin().lock().read_line(&mut line);
+	if hidden {
+		let _ = set_echo("echo");
+		println!();
+	}
+	read.map_err(|e| format!("failed to read the seed passphrase: {}", e))?;
+	Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Decrypts the seed stored at `path`.
+pub(crate) fn unlock_seed(path: &str, passphrase: &str) -> Result<[u8; 32], String> {
+	let file = fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+	decrypt_seed(&file, passphrase)
+}
+
+/// Encrypts the seed to `path`, replacing the file only once the new one is fully on disk.
+pub(crate) fn write_encrypted_seed(
+	path: &str, seed: &[u8; 32], passphrase: &str,
+) -> io::Result<()> {
+	let mut tmp_path = Path::new(path).to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	file.write_all(&encrypt_seed(seed, passphrase))?;
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
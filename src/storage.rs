@@ -0,0 +1,542 @@
+use crate::disk;
+use crate::hex_utils;
+use crate::{MillisatAmount, PaymentInfo};
+use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
+use lightning::routing::router::Route;
+use lightning::util::ser::{Readable, Writeable};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row, Transaction};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub(crate) const MANAGER: &str = "manager";
+pub(crate) const CHANNEL_PEER_DATA: &str = "channel_peer_data";
+pub(crate) const INBOUND_PAYMENTS: &str = "inbound_payments";
+pub(crate) const OUTBOUND_PAYMENTS: &str = "outbound_payments";
+pub(crate) const NETWORK_GRAPH: &str = "network_graph";
+pub(crate) const PAYER_PROOFS: &str = "payer_proofs";
+
+/// Everything kept in a `Storage`, which are also the names of their files in the data directory.
+const KEYS: [&str; 6] =
+	[MANAGER, CHANNEL_PEER_DATA, INBOUND_PAYMENTS, OUTBOUND_PAYMENTS, NETWORK_GRAPH, PAYER_PROOFS];
+
+/// The database in the data directory holding the node's state when run with `--storage=sqlite`.
+pub(crate) const SQLITE_FILE: &str = "ldk.sqlite";
+
+/// Where the node keeps its ChannelManager, the addresses of its channel peers, its payments and
+/// the network graph. Channel monitors are always stored as files, see `WalPersister`.
+pub(crate) trait Storage: Send + Sync {
+	/// Reads the serialized object stored under `key`, i.e. `MANAGER`, `NETWORK_GRAPH` or
+	/// `PAYER_PROOFS`, `None` if there is none.
+	fn read(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+	/// Replaces the object under `key`, only returning once the new one is durably stored. A crash
+	/// leaves either the old or the new value, never a mix of both.
+	fn write(&self, key: &str, value: &[u8]) -> io::Result<()>;
+	/// Records the address of a channel peer, as `<pubkey>@<host>:<port>`.
+	fn add_channel_peer(&self, peer_info: &str) -> io::Result<()>;
+	/// The addresses recorded for our channel peers, oldest first. A peer's latest address is the
+	/// one that counts.
+	fn channel_peers(&self) -> io::Result<Vec<String>>;
+	/// Reads the payments kept under `key`, i.e. `INBOUND_PAYMENTS` or `OUTBOUND_PAYMENTS`,
+	/// skipping any which can't be read.
+	fn read_payments(&self, key: &str) -> io::Result<HashMap<PaymentHash, PaymentInfo>>;
+	/// Replaces the payments kept under `key` with `payments`.
+	fn write_payments(
+		&self, key: &str, payments: &HashMap<PaymentHash, PaymentInfo>,
+	) -> io::Result<()>;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum StorageBackend {
+	/// A file per key in the data directory.
+	Files,
+	/// A single SQLite database in the data directory.
+	Sqlite,
+}
+
+impl StorageBackend {
+	pub(crate) fn parse(backend: &str) -> Result<Self, String> {
+		match backend {
+			"files" => Ok(StorageBackend::Files),
+			"sqlite" => Ok(StorageBackend::Sqlite),
+			_ => Err(format!("unknown storage backend {}, expected files or sqlite", backend)),
+		}
+	}
+}
+
+pub(crate) struct FilesystemStorage {
+	data_dir: String,
+}
+
+impl FilesystemStorage {
+	pub(crate) fn new(data_dir: String) -> Self {
+		Self { data_dir }
+	}
+}
+
+impl Storage for FilesystemStorage {
+	fn read(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+		match fs::read(format!("{}/{}", self.data_dir, key)) {
+			Ok(value) => Ok(Some(value)),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn write(&self, key: &str, value: &[u8]) -> io::Result<()> {
+		let path = format!("{}/{}", self.data_dir, key);
+		let tmp_path = format!("{}.tmp", path);
+		let write_res = File::create(&tmp_path)
+			.and_then(|mut file| file.write_all(value).and_then(|_| file.sync_all()))
+			.and_then(|_| fs::rename(&tmp_path, &path));
+		if let Err(e) = write_res {
+			let _ = fs::remove_file(&tmp_path);
+			return Err(e);
+		}
+		File::open(&self.data_dir)?.sync_all()
+	}
+
+	fn add_channel_peer(&self, peer_info: &str) -> io::Result<()> {
+		let path = format!("{}/{}", self.data_dir, CHANNEL_PEER_DATA);
+		let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+		file.write_all(format!("{}\n", peer_info).as_bytes())?;
+		file.sync_all()
+	}
+
+	fn channel_peers(&self) -> io::Result<Vec<String>> {
+		let peer_data = self.read(CHANNEL_PEER_DATA)?.unwrap_or_default();
+		Ok(String::from_utf8_lossy(&peer_data).lines().map(str::to_string).collect())
+	}
+
+	fn read_payments(&self, key: &str) -> io::Result<HashMap<PaymentHash, PaymentInfo>> {
+		let lines = self.read(key)?.unwrap_or_default();
+		Ok(payments_from_lines(key, &lines))
+	}
+
+	fn write_payments(
+		&self, key: &str, payments: &HashMap<PaymentHash, PaymentInfo>,
+	) -> io::Result<()> {
+		let mut lines = String::new();
+		for (payment_hash, payment) in payments.iter() {
+			lines.push_str(&disk::payment_info_to_line(payment_hash, payment));
+			lines.push('\n');
+		}
+		self.write(key, lines.as_bytes())
+	}
+}
+
+/// Parses payments in the line format of our payment files.
+fn payments_from_lines(key: &str, lines: &[u8]) -> HashMap<PaymentHash, PaymentInfo> {
+	let mut payments = HashMap::new();
+	for line in String::from_utf8_lossy(lines).lines() {
+		match disk::payment_info_from_line(line) {
+			Some((payment_hash, payment)) => {
+				payments.insert(payment_hash, payment);
+			}
+			None => eprintln!("Warning: Skipping malformed payment entry in {}", key),
+		}
+	}
+	payments
+}
+
+fn sqlite_error(e: rusqlite::Error) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Keeps payments and channel peers in tables of their own, with a row per payment or peer and a
+/// column per field, and the ChannelManager and network graph, which LDK serializes as a whole,
+/// as rows of the `state` table. Every update is an atomic SQLite transaction, and the whole state
+/// can be backed up from a running node with `sqlite3 ldk.sqlite .backup`.
+pub(crate) struct SqliteStorage {
+	connection: Mutex<Connection>,
+	/// The rows we last wrote for each payment table, so that only payments which changed are
+	/// written again.
+	written_payments: Mutex<HashMap<String, HashMap<PaymentHash, String>>>,
+}
+
+const CREATE_TABLES: &str = "
+	CREATE TABLE IF NOT EXISTS state (key TEXT PRIMARY KEY, value BLOB NOT NULL);
+	CREATE TABLE IF NOT EXISTS peers (
+		pubkey TEXT PRIMARY KEY,
+		address TEXT NOT NULL,
+		added_at INTEGER NOT NULL
+	);
+	CREATE TABLE IF NOT EXISTS inbound_payments (
+		payment_hash TEXT PRIMARY KEY,
+		preimage TEXT,
+		secret TEXT,
+		status TEXT NOT NULL,
+		amt_msat INTEGER,
+		created_at INTEGER NOT NULL,
+		updated_at INTEGER NOT NULL,
+		expires_at INTEGER,
+		route BLOB,
+		failure TEXT,
+		failed_parts INTEGER NOT NULL,
+		fee_paid_msat INTEGER,
+		invoice TEXT,
+		refund_of TEXT,
+		reissue_of TEXT
+	);
+	CREATE TABLE IF NOT EXISTS outbound_payments AS SELECT * FROM inbound_payments WHERE 0;
+";
+
+const PAYMENT_COLUMNS: &str = "payment_hash, preimage, secret, status, amt_msat, created_at, \
+	updated_at, expires_at, route, failure, failed_parts, fee_paid_msat, invoice, refund_of, reissue_of";
+
+fn payment_table(key: &str) -> io::Result<&'static str> {
+	match key {
+		INBOUND_PAYMENTS => Ok(INBOUND_PAYMENTS),
+		OUTBOUND_PAYMENTS => Ok(OUTBOUND_PAYMENTS),
+		_ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no payments under {}", key))),
+	}
+}
+
+fn hash_from_hex(hex: Option<String>) -> Option<Option<[u8; 32]>> {
+	match hex {
+		None => Some(None),
+		Some(hex) => {
+			let bytes = hex_utils::to_vec(&hex)?;
+			if bytes.len() != 32 {
+				return None;
+			}
+			let mut res = [0; 32];
+			res.copy_from_slice(&bytes);
+			Some(Some(res))
+		}
+	}
+}
+
+/// Reads a row of a payments table, `None` if it doesn't hold a valid payment. SQLite integers
+/// are signed, so amounts and times are stored as their two's complement.
+fn payment_from_row(row: &Row) -> rusqlite::Result<Option<(PaymentHash, PaymentInfo)>> {
+	let route: Option<Vec<u8>> = row.get(8)?;
+	let payment = (|| {
+		let payment_hash = PaymentHash(hash_from_hex(row.get(0).ok()?)??);
+		let route = match &route {
+			None => None,
+			Some(route) => Some(Route::read(&mut Cursor::new(route)).ok()?),
+		};
+		let payment = PaymentInfo {
+			preimage: hash_from_hex(row.get(1).ok()?)?.map(PaymentPreimage),
+			secret: hash_from_hex(row.get(2).ok()?)?.map(PaymentSecret),
+			status: disk::parse_status(&row.get::<_, String>(3).ok()?)?,
+			amt_msat: MillisatAmount(row.get::<_, Option<i64>>(4).ok()?.map(|amt| amt as u64)),
+			created_at: row.get::<_, i64>(5).ok()? as u64,
+			updated_at: row.get::<_, i64>(6).ok()? as u64,
+			expires_at: row.get::<_, Option<i64>>(7).ok()?.map(|expiry| expiry as u64),
+			route,
+			failure: row.get(9).ok()?,
+			failed_parts: row.get(10).ok()?,
+			fee_paid_msat: row.get::<_, Option<i64>>(11).ok()?.map(|fee| fee as u64),
+			invoice: row.get(12).ok()?,
+			refund_of: hash_from_hex(row.get(13).ok()?)?.map(PaymentHash),
+			reissue_of: hash_from_hex(row.get(14).ok()?)?.map(PaymentHash),
+		};
+		Some((payment_hash, payment))
+	})();
+	Ok(payment)
+}
+
+fn upsert_payment(
+	tx: &Transaction, table: &str, payment_hash: &PaymentHash, payment: &PaymentInfo,
+) -> rusqlite::Result<()> {
+	tx.execute(
+		&format!(
+			"INSERT OR REPLACE INTO {} ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+			table, PAYMENT_COLUMNS
+		),
+		params![
+			hex_utils::hex_str(&payment_hash.0),
+			payment.preimage.map(|p| hex_utils::hex_str(&p.0)),
+			payment.secret.map(|s| hex_utils::hex_str(&s.0)),
+			disk::status_str(&payment.status),
+			payment.amt_msat.0.map(|amt| amt as i64),
+			payment.created_at as i64,
+			payment.updated_at as i64,
+			payment.expires_at.map(|expiry| expiry as i64),
+			payment.route.as_ref().map(|route| route.encode()),
+			payment.failure,
+			payment.failed_parts,
+			payment.fee_paid_msat.map(|fee| fee as i64),
+			payment.invoice,
+			payment.refund_of.map(|hash| hex_utils::hex_str(&hash.0)),
+			payment.reissue_of.map(|hash| hex_utils::hex_str(&hash.0)),
+		],
+	)
+	.map(|_| ())
+}
+
+fn upsert_peer(tx: &Transaction, peer_info: &str) -> rusqlite::Result<()> {
+	let pubkey = peer_info.splitn(2, '@').next().unwrap_or_default();
+	let address = peer_info.splitn(2, '@').nth(1).unwrap_or_default();
+	tx.execute(
+		"INSERT OR REPLACE INTO peers (pubkey, address, added_at) \
+			VALUES (?1, ?2, (SELECT IFNULL(MAX(added_at), 0) + 1 FROM peers))",
+		params![pubkey, address],
+	)
+	.map(|_| ())
+}
+
+impl SqliteStorage {
+	pub(crate) fn open(path: &str) -> io::Result<Self> {
+		let connection = Connection::open(path).map_err(sqlite_error)?;
+		connection.execute_batch(CREATE_TABLES).map_err(sqlite_error)?;
+		let storage = Self {
+			connection: Mutex::new(connection),
+			written_payments: Mutex::new(HashMap::new()),
+		};
+		storage.split_state()?;
+		Ok(storage)
+	}
+
+	/// Opens the database without the ability to change it, e.g. to inspect a node which is
+	/// running.
+	pub(crate) fn open_read_only(path: &str) -> io::Result<Self> {
+		let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+			.map_err(sqlite_error)?;
+		Ok(Self {
+			connection: Mutex::new(connection),
+			written_payments: Mutex::new(HashMap::new()),
+		})
+	}
+
+	/// Adds the payments or channel peers of a data file, in its format, to their table, unless
+	/// the table already has some. Anything else is stored as it is under `key`, unless there's
+	/// already something there. Returns whether anything was imported.
+	fn import(&self, key: &str, value: &[u8]) -> io::Result<bool> {
+		let mut connection = self.connection.lock().unwrap();
+		let tx = connection.transaction().map_err(sqlite_error)?;
+		let table = match key {
+			CHANNEL_PEER_DATA => "peers",
+			INBOUND_PAYMENTS | OUTBOUND_PAYMENTS => key,
+			_ => "state",
+		};
+		let existing = match table {
+			"state" => {
+				tx.query_row("SELECT COUNT(*) FROM state WHERE key = ?1", params![key], |row| {
+					row.get::<_, i64>(0)
+				})
+			}
+			_ => tx.query_row(&format!("SELECT COUNT(*) FROM {}", table), params![], |row| {
+				row.get::<_, i64>(0)
+			}),
+		}
+		.map_err(sqlite_error)?;
+		if existing > 0 {
+			return Ok(false);
+		}
+		match key {
+			INBOUND_PAYMENTS | OUTBOUND_PAYMENTS => {
+				for (payment_hash, payment) in payments_from_lines(key, value).iter() {
+					upsert_payment(&tx, key, payment_hash, payment).map_err(sqlite_error)?;
+				}
+			}
+			CHANNEL_PEER_DATA => {
+				for line in String::from_utf8_lossy(value).lines().filter(|l| !l.is_empty()) {
+					upsert_peer(&tx, line).map_err(sqlite_error)?;
+				}
+			}
+			_ => {
+				tx.execute("INSERT INTO state (key, value) VALUES (?1, ?2)", params![key, value])
+					.map_err(sqlite_error)?;
+			}
+		}
+		tx.commit().map_err(sqlite_error)?;
+		Ok(true)
+	}
+
+	/// Databases created by earlier versions kept payments and channel peers as rows of the
+	/// `state` table, in the format of our data files, which are moved to their own tables.
+	fn split_state(&self) -> io::Result<()> {
+		for key in [CHANNEL_PEER_DATA, INBOUND_PAYMENTS, OUTBOUND_PAYMENTS].iter() {
+			let value: Option<Vec<u8>> = {
+				let connection = self.connection.lock().unwrap();
+				connection
+					.query_row("SELECT value FROM state WHERE key = ?1", params![key], |row| {
+						row.get(0)
+					})
+					.optional()
+					.map_err(sqlite_error)?
+			};
+			if let Some(value) = value {
+				self.import(key, &value)?;
+				let connection = self.connection.lock().unwrap();
+				connection
+					.execute("DELETE FROM state WHERE key = ?1", params![key])
+					.map_err(sqlite_error)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Moves the state of a node which kept it in files into the database. Files are imported only
+	/// if the database doesn't have their contents yet, then renamed to `<key>.migrated` so that
+	/// nothing mistakes them for the node's current state.
+	fn import_files(&self, data_dir: &str) -> io::Result<()> {
+		for key in KEYS.iter() {
+			let path = format!("{}/{}", data_dir, key);
+			let value = match fs::read(&path) {
+				Ok(value) => value,
+				Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(e),
+			};
+			if self.import(key, &value)? {
+				println!("Imported {} into {}", path, SQLITE_FILE);
+			}
+			fs::rename(&path, format!("{}.migrated", path))?;
+		}
+		Ok(())
+	}
+
+	/// The rows of `table` as we last wrote them, serialized in the line format of our payment
+	/// files to compare against.
+	fn payment_lines(&self, table: &str) -> io::Result<HashMap<PaymentHash, String>> {
+		let payments = self.read_payments(table)?;
+		Ok(payments
+			.iter()
+			.map(|(payment_hash, payment)| {
+				(*payment_hash, disk::payment_info_to_line(payment_hash, payment))
+			})
+			.collect())
+	}
+}
+
+impl Storage for SqliteStorage {
+	fn read(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+		let connection = self.connection.lock().unwrap();
+		connection
+			.query_row("SELECT value FROM state WHERE key = ?1", params![key], |row| row.get(0))
+			.optional()
+			.map_err(sqlite_error)
+	}
+
+	fn write(&self, key: &str, value: &[u8]) -> io::Result<()> {
+		let connection = self.connection.lock().unwrap();
+		connection
+			.execute(
+				"INSERT OR REPLACE INTO state (key, value) VALUES (?1, ?2)",
+				params![key, value],
+			)
+			.map(|_| ())
+			.map_err(sqlite_error)
+	}
+
+	fn add_channel_peer(&self, peer_info: &str) -> io::Result<()> {
+		let mut connection = self.connection.lock().unwrap();
+		let tx = connection.transaction().map_err(sqlite_error)?;
+		upsert_peer(&tx, peer_info).map_err(sqlite_error)?;
+		tx.commit().map_err(sqlite_error)
+	}
+
+	fn channel_peers(&self) -> io::Result<Vec<String>> {
+		let connection = self.connection.lock().unwrap();
+		let mut stmt = connection
+			.prepare("SELECT pubkey, address FROM peers ORDER BY added_at")
+			.map_err(sqlite_error)?;
+		let peers = stmt
+			.query_map(params![], |row| {
+				Ok(format!("{}@{}", row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+			})
+			.map_err(sqlite_error)?;
+		peers.collect::<rusqlite::Result<Vec<_>>>().map_err(sqlite_error)
+	}
+
+	fn read_payments(&self, key: &str) -> io::Result<HashMap<PaymentHash, PaymentInfo>> {
+		let table = payment_table(key)?;
+		let connection = self.connection.lock().unwrap();
+		let mut stmt = connection
+			.prepare(&format!("SELECT {} FROM {}", PAYMENT_COLUMNS, table))
+			.map_err(sqlite_error)?;
+		let rows = stmt.query_map(params![], payment_from_row).map_err(sqlite_error)?;
+		let mut payments = HashMap::new();
+		for row in rows {
+			match row.map_err(sqlite_error)? {
+				Some((payment_hash, payment)) => {
+					payments.insert(payment_hash, payment);
+				}
+				None => eprintln!("Warning: Skipping malformed payment entry in {}", table),
+			}
+		}
+		Ok(payments)
+	}
+
+	/// Only writes the payments which changed since we last wrote them, and deletes those which
+	/// are gone, so a payment's update doesn't rewrite the whole payment history.
+	fn write_payments(
+		&self, key: &str, payments: &HashMap<PaymentHash, PaymentInfo>,
+	) -> io::Result<()> {
+		let table = payment_table(key)?;
+		let mut written_payments = self.written_payments.lock().unwrap();
+		if !written_payments.contains_key(table) {
+			written_payments.insert(table.to_string(), self.payment_lines(table)?);
+		}
+		let written = written_payments.get_mut(table).unwrap();
+		let mut connection = self.connection.lock().unwrap();
+		let tx = connection.transaction().map_err(sqlite_error)?;
+		let mut changed = Vec::new();
+		for (payment_hash, payment) in payments.iter() {
+			let line = disk::payment_info_to_line(payment_hash, payment);
+			if written.get(payment_hash) != Some(&line) {
+				upsert_payment(&tx, table, payment_hash, payment).map_err(sqlite_error)?;
+				changed.push((*payment_hash, line));
+			}
+		}
+		let removed: Vec<PaymentHash> =
+			written.keys().filter(|hash| !payments.contains_key(hash)).cloned().collect();
+		for payment_hash in removed.iter() {
+			tx.execute(
+				&format!("DELETE FROM {} WHERE payment_hash = ?1", table),
+				params![hex_utils::hex_str(&payment_hash.0)],
+			)
+			.map_err(sqlite_error)?;
+		}
+		tx.commit().map_err(sqlite_error)?;
+		// Only once the transaction is in do we know what's in the database.
+		for payment_hash in removed.iter() {
+			written.remove(payment_hash);
+		}
+		written.extend(changed);
+		Ok(())
+	}
+}
+
+/// Opens the node's storage in `data_dir`. Once a node has moved to SQLite its old files are
+/// stale, so it may not be started from them again.
+pub(crate) fn open_storage(
+	data_dir: &str, backend: StorageBackend,
+) -> Result<Arc<dyn Storage>, String> {
+	let sqlite_path = format!("{}/{}", data_dir, SQLITE_FILE);
+	match backend {
+		StorageBackend::Files => {
+			if Path::new(&sqlite_path).exists() {
+				return Err(format!(
+					"This node keeps its state in {}, start it with --storage=sqlite",
+					sqlite_path
+				));
+			}
+			Ok(Arc::new(FilesystemStorage::new(data_dir.to_string())))
+		}
+		StorageBackend::Sqlite => {
+			let storage = SqliteStorage::open(&sqlite_path)
+				.map_err(|e| format!("Failed to open {}: {}", sqlite_path, e))?;
+			storage
+				.import_files(data_dir)
+				.map_err(|e| format!("Failed to import the node's files into SQLite: {}", e))?;
+			Ok(Arc::new(storage))
+		}
+	}
+}
+
+/// Opens whichever storage the node in `data_dir` uses, without changing it.
+pub(crate) fn open_storage_read_only(data_dir: &str) -> io::Result<Arc<dyn Storage>> {
+	let sqlite_path = format!("{}/{}", data_dir, SQLITE_FILE);
+	if Path::new(&sqlite_path).exists() {
+		return Ok(Arc::new(SqliteStorage::open_read_only(&sqlite_path)?));
+	}
+	Ok(Arc::new(FilesystemStorage::new(data_dir.to_string())))
+}
@@ -0,0 +1,122 @@
+use bitcoin::blockdata::script::Script;
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::address::Address;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use lightning::ln::script::ShutdownScript;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+enum Destination {
+	/// Fresh P2WPKH addresses on the receive chain of an account-level xpub.
+	Xpub {
+		xpub: ExtendedPubKey,
+		index_path: String,
+		/// The index of the next address to hand out.
+		next_index: Mutex<u32>,
+	},
+	/// A single address, e.g. of a cold storage wallet, which every sweep pays to.
+	Address(Address),
+}
+
+/// An externally controlled wallet, such as a hardware wallet, which our on-chain funds are swept
+/// to instead of bitcoind's. We either know its account-level extended public key, from which we
+/// derive P2WPKH addresses on its receive chain (`<xpub>/0/<index>`), or a single static address,
+/// so the node never holds the keys to its swept funds.
+pub(crate) struct SweepWallet {
+	destination: Destination,
+	network: Network,
+}
+
+/// Accepts either a bare xpub or a `wpkh(<xpub>/0/*)` descriptor, optionally with key origin
+/// information and a checksum, as exported by most hardware wallet software.
+fn xpub_from_descriptor(descriptor: &str) -> Result<&str, String> {
+	let descriptor = descriptor.split('#').next().unwrap();
+	if !descriptor.starts_with("wpkh(") {
+		return Ok(descriptor);
+	}
+	let key = descriptor
+		.strip_prefix("wpkh(")
+		.and_then(|d| d.strip_suffix(")"))
+		.and_then(|d| d.strip_suffix("/0/*"))
+		.ok_or("only descriptors of the form wpkh(<xpub>/0/*) are supported")?;
+	// Drop the key origin, e.g. `[d34db33f/84'/0'/0']`.
+	match key.strip_prefix('[') {
+		Some(key) => key
+			.splitn(2, ']')
+			.nth(1)
+			.ok_or_else(|| "invalid key origin in sweep wallet descriptor".to_string()),
+		None => Ok(key),
+	}
+}
+
+impl SweepWallet {
+	pub(crate) fn new(xpub: &str, network: Network, index_path: String) -> Result<Self, String> {
+		let xpub = xpub_from_descriptor(xpub)?;
+		let xpub = ExtendedPubKey::from_str(xpub).map_err(|e| format!("invalid xpub: {}", e))?;
+		// Extended keys only distinguish mainnet from the test networks.
+		if (xpub.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+			return Err(format!("the sweep wallet's xpub isn't for {}", network));
+		}
+		let next_index = fs::read_to_string(&index_path)
+			.ok()
+			.and_then(|index| index.trim().parse().ok())
+			.unwrap_or(0);
+		let destination =
+			Destination::Xpub { xpub, index_path, next_index: Mutex::new(next_index) };
+		Ok(Self { destination, network })
+	}
+
+	pub(crate) fn from_address(address: &str, network: Network) -> Result<Self, String> {
+		let address =
+			Address::from_str(address).map_err(|e| format!("invalid sweep address: {}", e))?;
+		// Testnet addresses are also valid on signet and regtest (other than bech32 ones).
+		if (address.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+			return Err(format!("the sweep address isn't for {}", network));
+		}
+		// Cooperative closes pay to it too, which restricts the scripts we can use.
+		if ShutdownScript::try_from(address.script_pubkey()).is_err() {
+			return Err("the sweep address can't be used to close channels to".to_string());
+		}
+		Ok(Self { destination: Destination::Address(address), network })
+	}
+
+	/// Derives the next unused address, or returns the static address. The index is persisted
+	/// before the address is handed out so that we never reuse one, even across restarts.
+	pub(crate) fn next_address(&self) -> Address {
+		let (xpub, index_path, next_index) = match &self.destination {
+			Destination::Xpub { xpub, index_path, next_index } => (xpub, index_path, next_index),
+			Destination::Address(address) => return address.clone(),
+		};
+		let mut next_index = next_index.lock().unwrap();
+		let index = *next_index;
+		*next_index += 1;
+		if persist_index(Path::new(index_path), *next_index).is_err() {
+			eprintln!("Warning: Failed to persist the sweep wallet's address index, check your disk and permissions");
+		}
+		let path = [ChildNumber::Normal { index: 0 }, ChildNumber::Normal { index }];
+		let pubkey = xpub
+			.derive_pub(&Secp256k1::verification_only(), &path)
+			.expect("normal derivation from an xpub can't fail")
+			.public_key;
+		Address::p2wpkh(&pubkey, self.network).expect("keys derived from an xpub are compressed")
+	}
+
+	pub(crate) fn next_script(&self) -> Script {
+		self.next_address().script_pubkey()
+	}
+}
+
+fn persist_index(path: &Path, index: u32) -> std::io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	file.write_all(format!("{}\n", index).as_bytes())?;
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,345 @@
+use crate::bitcoind_client::{BitcoindClient, Target, WalletRole};
+use crate::fee_snapshots::BroadcastPurpose;
+use crate::hex_utils;
+use crate::sweep_wallet::SweepWallet;
+use bitcoin::blockdata::script::Script;
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::secp256k1::Secp256k1;
+use lightning::chain::chaininterface::{ConfirmationTarget, FeeEstimator};
+use lightning::chain::keysinterface::{KeysManager, SpendableOutputDescriptor};
+use lightning::chain::transaction::OutPoint;
+use lightning::util::ser::{Readable, Writeable};
+use std::fs;
+use std::io;
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often we retry sweeping outputs which haven't been swept yet.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+struct TrackedOutput {
+	descriptor: SpendableOutputDescriptor,
+	/// Our most recent sweep of the output which bitcoind accepted, if any.
+	sweep_txid: Option<Txid>,
+	/// How many times we've tried to sweep the output.
+	attempts: u32,
+}
+
+/// A summary of an output we've yet to see a confirmed sweep of, for display.
+pub(crate) struct UnsweptOutput {
+	pub(crate) outpoint: OutPoint,
+	pub(crate) value_sat: u64,
+	pub(crate) kind: &'static str,
+	pub(crate) sweep_txid: Option<Txid>,
+	pub(crate) attempts: u32,
+}
+
+fn outpoint_and_value(descriptor: &SpendableOutputDescriptor) -> (OutPoint, u64) {
+	match descriptor {
+		SpendableOutputDescriptor::StaticOutput { outpoint, output } => (*outpoint, output.value),
+		SpendableOutputDescriptor::DelayedPaymentOutput(d) => (d.outpoint, d.output.value),
+		SpendableOutputDescriptor::StaticPaymentOutput(d) => (d.outpoint, d.output.value),
+	}
+}
+
+fn kind(descriptor: &SpendableOutputDescriptor) -> &'static str {
+	match descriptor {
+		SpendableOutputDescriptor::StaticOutput { .. } => "static_output",
+		SpendableOutputDescriptor::DelayedPaymentOutput(_) => "delayed_payment",
+		SpendableOutputDescriptor::StaticPaymentOutput(_) => "static_payment",
+	}
+}
+
+/// Sweeps the on-chain outputs LDK hands us to our wallet, retrying until a sweep confirms.
+///
+/// Outputs are written to disk as soon as we learn of them, so that if bitcoind is down or rejects
+/// the sweep, or we restart before it confirms, we don't lose track of the funds. Each retry builds
+/// the sweep anew at the current feerate.
+pub(crate) struct OutputSweeper {
+	path: String,
+	outputs: Mutex<Vec<TrackedOutput>>,
+	/// Where sweeps pay to, picked once so that retries don't use up addresses.
+	destination: Mutex<Option<Script>>,
+	keys_manager: Arc<KeysManager>,
+	bitcoind_client: Arc<BitcoindClient>,
+	sweep_wallet: Option<Arc<SweepWallet>>,
+	/// How long to wait after new outputs come in before sweeping, so that outputs which become
+	/// spendable around the same time are swept in one transaction.
+	batch_window: Duration,
+	/// Whether a batched sweep is already waiting for its window to pass.
+	batch_pending: AtomicBool,
+}
+
+impl OutputSweeper {
+	pub(crate) fn new(
+		path: String, keys_manager: Arc<KeysManager>, bitcoind_client: Arc<BitcoindClient>,
+		sweep_wallet: Option<Arc<SweepWallet>>, batch_window: Duration,
+	) -> Self {
+		let outputs = read_tracked_outputs(Path::new(&path));
+		Self {
+			path,
+			outputs: Mutex::new(outputs),
+			destination: Mutex::new(None),
+			keys_manager,
+			bitcoind_client,
+			sweep_wallet,
+			batch_window,
+			batch_pending: AtomicBool::new(false),
+		}
+	}
+
+	/// Starts tracking outputs from a `SpendableOutputs` event.
+	pub(crate) fn track_outputs(&self, descriptors: &[SpendableOutputDescriptor]) {
+		let mut outputs = self.outputs.lock().unwrap();
+		for descriptor in descriptors {
+			let outpoint = outpoint_and_value(descriptor).0;
+			// LDK may hand us the same output again after a restart.
+			if outputs.iter().any(|o| outpoint_and_value(&o.descriptor).0 == outpoint) {
+				continue;
+			}
+			outputs.push(TrackedOutput {
+				descriptor: descriptor.clone(),
+				sweep_txid: None,
+				attempts: 0,
+			});
+		}
+		self.persist(&outputs);
+	}
+
+	/// Sweeps newly tracked outputs, either right away or, if batching, once the batch window has
+	/// passed along with any others which came in meanwhile.
+	pub(crate) async fn outputs_added(sweeper: &Arc<Self>) {
+		if sweeper.batch_window.as_secs() == 0 {
+			sweeper.sweep().await;
+			return;
+		}
+		if sweeper.batch_pending.swap(true, Ordering::AcqRel) {
+			return;
+		}
+		let sweeper = Arc::clone(sweeper);
+		tokio::spawn(async move {
+			tokio::time::sleep(sweeper.batch_window).await;
+			sweeper.batch_pending.store(false, Ordering::Release);
+			sweeper.sweep().await;
+		});
+	}
+
+	pub(crate) fn unswept_outputs(&self) -> Vec<UnsweptOutput> {
+		let outputs = self.outputs.lock().unwrap();
+		outputs
+			.iter()
+			.map(|o| {
+				let (outpoint, value_sat) = outpoint_and_value(&o.descriptor);
+				UnsweptOutput {
+					outpoint,
+					value_sat,
+					kind: kind(&o.descriptor),
+					sweep_txid: o.sweep_txid,
+					attempts: o.attempts,
+				}
+			})
+			.collect()
+	}
+
+	/// Forgets outputs whose sweep has confirmed, then (re)broadcasts a sweep of the rest.
+	pub(crate) async fn sweep(&self) {
+		let outpoints: Vec<OutPoint> = self
+			.outputs
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|o| outpoint_and_value(&o.descriptor).0)
+			.collect();
+		if outpoints.is_empty() {
+			return;
+		}
+		let mut spent = Vec::new();
+		for outpoint in outpoints {
+			match self.bitcoind_client.is_spent_on_chain(&outpoint.into_bitcoin_outpoint()).await {
+				Ok(true) => spent.push(outpoint),
+				Ok(false) => {}
+				// bitcoind is likely down, so try again later.
+				Err(_) => return,
+			}
+		}
+
+		let destination = match self.destination().await {
+			Some(destination) => destination,
+			None => return,
+		};
+		// Don't hold the lock over the broadcast, as new outputs may come in meanwhile.
+		let (sweep_tx, previously_broadcast) = {
+			let mut outputs = self.outputs.lock().unwrap();
+			if !spent.is_empty() {
+				outputs.retain(|o| {
+					let (outpoint, value_sat) = outpoint_and_value(&o.descriptor);
+					if !spent.contains(&outpoint) {
+						return true;
+					}
+					println!(
+						"\nEVENT: Swept {} sat from {}:{}{}",
+						value_sat,
+						outpoint.txid,
+						outpoint.index,
+						match o.sweep_txid {
+							Some(txid) => format!(" in {}", txid),
+							None => String::new(),
+						}
+					);
+					print!("> ");
+					io::stdout().flush().unwrap();
+					false
+				});
+				self.persist(&outputs);
+			}
+			if outputs.is_empty() {
+				return;
+			}
+
+			let descriptors: Vec<&SpendableOutputDescriptor> =
+				outputs.iter().map(|o| &o.descriptor).collect();
+			let feerate =
+				self.bitcoind_client.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
+			let sweep_res = self.keys_manager.spend_spendable_outputs(
+				&descriptors,
+				Vec::new(),
+				destination,
+				feerate,
+				&Secp256k1::new(),
+			);
+			let sweep_tx = match sweep_res {
+				Ok(tx) => tx,
+				Err(()) => {
+					// Most likely the outputs are worth less than the fee to sweep them at this
+					// feerate, so wait for fees to drop.
+					eprintln!(
+						"Warning: Failed to build a sweep of our spendable outputs, will retry"
+					);
+					return;
+				}
+			};
+			for output in outputs.iter_mut() {
+				output.attempts += 1;
+			}
+			self.persist(&outputs);
+			(sweep_tx, outputs.iter().all(|o| o.sweep_txid.is_some()))
+		};
+
+		let sent = self
+			.bitcoind_client
+			.send_transaction(&sweep_tx, BroadcastPurpose::Sweep, Some(Target::Normal))
+			.await;
+		match sent {
+			Ok(txid) => {
+				let swept: Vec<_> =
+					sweep_tx.input.iter().map(|input| input.previous_output).collect();
+				let mut outputs = self.outputs.lock().unwrap();
+				for output in outputs.iter_mut() {
+					let outpoint = outpoint_and_value(&output.descriptor).0;
+					if swept.contains(&outpoint.into_bitcoin_outpoint()) {
+						output.sweep_txid = Some(txid);
+					}
+				}
+				self.persist(&outputs);
+			}
+			// If an earlier sweep is still in the mempool, a new one at the same feerate can't
+			// replace it, so there's nothing to report.
+			Err(_) if previously_broadcast => {}
+			Err(e) => eprintln!(
+				"Warning: Failed to broadcast sweep of our spendable outputs, will retry: {}",
+				e
+			),
+		}
+	}
+
+	async fn destination(&self) -> Option<Script> {
+		if let Some(destination) = self.destination.lock().unwrap().clone() {
+			return Some(destination);
+		}
+		let destination = match &self.sweep_wallet {
+			// Our funds never go to the hot bitcoind wallet if we have somewhere else to sweep them.
+			Some(sweep_wallet) => sweep_wallet.next_script(),
+			None => match self.bitcoind_client.get_wallet_address(WalletRole::Operating).await {
+				Ok(address) => address.script_pubkey(),
+				Err(_) => return None,
+			},
+		};
+		*self.destination.lock().unwrap() = Some(destination.clone());
+		Some(destination)
+	}
+
+	fn persist(&self, outputs: &[TrackedOutput]) {
+		if persist_tracked_outputs(Path::new(&self.path), outputs).is_err() {
+			eprintln!(
+				"Warning: Failed to persist spendable outputs, check your disk and permissions"
+			);
+		}
+	}
+
+	/// Regularly retries sweeping any outputs which haven't been swept yet.
+	pub(crate) fn start_sweeping(sweeper: Arc<Self>) {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+			loop {
+				interval.tick().await;
+				// A pending batch sweeps everything once its window passes.
+				if !sweeper.batch_pending.load(Ordering::Acquire) {
+					sweeper.sweep().await;
+				}
+			}
+		});
+	}
+}
+
+/// Writes each output as `<descriptor_hex> <sweep_txid or -> <attempts>` on its own line.
+fn persist_tracked_outputs(path: &Path, outputs: &[TrackedOutput]) -> io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for output in outputs {
+		let sweep_txid = output.sweep_txid.map_or("-".to_string(), |txid| txid.to_string());
+		let line = format!(
+			"{} {} {}\n",
+			hex_utils::hex_str(&output.descriptor.encode()),
+			sweep_txid,
+			output.attempts
+		);
+		file.write_all(line.as_bytes())?;
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+fn tracked_output_from_line(line: &str) -> Option<TrackedOutput> {
+	let mut parts = line.split(' ');
+	let descriptor_bytes = hex_utils::to_vec(parts.next()?)?;
+	let descriptor = Readable::read(&mut Cursor::new(descriptor_bytes)).ok()?;
+	let sweep_txid = match parts.next()? {
+		"-" => None,
+		txid => Some(Txid::from_hex(txid).ok()?),
+	};
+	let attempts = parts.next()?.parse().ok()?;
+	Some(TrackedOutput { descriptor, sweep_txid, attempts })
+}
+
+fn read_tracked_outputs(path: &Path) -> Vec<TrackedOutput> {
+	let contents = match fs::read_to_string(path) {
+		Ok(contents) => contents,
+		Err(_) => return Vec::new(),
+	};
+	contents
+		.lines()
+		.filter_map(|line| {
+			let output = tracked_output_from_line(line);
+			if output.is_none() {
+				eprintln!("Warning: Skipping unreadable spendable output in {}", path.display());
+			}
+			output
+		})
+		.collect()
+}
@@ -0,0 +1,91 @@
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::time::Duration;
+
+/// The first file descriptor systemd passes to a socket-activated service.
+const LISTEN_FDS_START: i32 = 3;
+
+/// Whether we're running as a systemd service.
+pub(crate) fn is_service() -> bool {
+	env::var_os("INVOCATION_ID").is_some()
+}
+
+/// Tells systemd about the node's state, e.g. `READY=1` once it's up, if it runs as a
+/// `Type=notify` service. Does nothing otherwise, e.g. when not started by systemd.
+pub(crate) fn notify(state: &str) {
+	let socket_path = match env::var("NOTIFY_SOCKET") {
+		Ok(path) => path,
+		Err(_) => return,
+	};
+	// Sockets in the abstract namespace can't be addressed with the standard library's unix
+	// sockets, so only filesystem sockets are supported, which is what systemd itself uses.
+	if socket_path.starts_with('@') {
+		return;
+	}
+	let sent =
+		UnixDatagram::unbound().and_then(|socket| socket.send_to(state.as_bytes(), &socket_path));
+	if let Err(e) = sent {
+		eprintln!("Warning: Failed to notify systemd of {}: {}", state, e);
+	}
+}
+
+/// Keeps pinging systemd's watchdog if the service has `WatchdogSec=` set, at half the interval
+/// it expects, as systemd recommends. Pings come from the tokio runtime, so a wedged runtime gets
+/// the node restarted.
+pub(crate) fn start_watchdog() {
+	let watchdog_usec: u64 = match env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse().ok())
+	{
+		Some(usec) if usec > 0 => usec,
+		_ => return,
+	};
+	// The watchdog may be meant for another process of the service.
+	if let Ok(pid) = env::var("WATCHDOG_PID") {
+		if pid != process::id().to_string() {
+			return;
+		}
+	}
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(Duration::from_micros(watchdog_usec / 2));
+		loop {
+			interval.tick().await;
+			notify("WATCHDOG=1");
+		}
+	});
+}
+
+/// The sockets systemd opened for us under socket activation, told apart by their
+/// `FileDescriptorName=`: `rpc` for the RPC server, anything else for the peer listener.
+#[derive(Default)]
+pub(crate) struct ActivatedSockets {
+	pub(crate) peer_listeners: Vec<TcpListener>,
+	pub(crate) rpc_listener: Option<TcpListener>,
+}
+
+/// Takes the sockets systemd passed us, as `sd_listen_fds` would, so that the node's ports keep
+/// accepting connections while it restarts, and it needs no privileges to bind them.
+pub(crate) fn take_activated_sockets() -> ActivatedSockets {
+	let mut sockets = ActivatedSockets::default();
+	let for_us = env::var("LISTEN_PID").map_or(false, |pid| pid == process::id().to_string());
+	let num_fds: i32 = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse().ok()).unwrap_or(0);
+	let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+	// Child processes, e.g. monitor replica commands, mustn't think the sockets are theirs.
+	env::remove_var("LISTEN_PID");
+	env::remove_var("LISTEN_FDS");
+	env::remove_var("LISTEN_FDNAMES");
+	if !for_us {
+		return sockets;
+	}
+	let mut names = names.split(':');
+	for fd in LISTEN_FDS_START..LISTEN_FDS_START + num_fds {
+		// Safe as systemd handed the descriptor to us, and nothing else in the process uses it.
+		let listener = unsafe { TcpListener::from_raw_fd(fd) };
+		match names.next() {
+			Some("rpc") => sockets.rpc_listener = Some(listener),
+			_ => sockets.peer_listeners.push(listener),
+		}
+	}
+	sockets
+}
@@ -0,0 +1,95 @@
+use crate::ChannelManager;
+use bitcoin::network::constants::Network;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// How often we report statistics to the telemetry endpoint.
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Buckets the channel count so that the reported value can't be used to fingerprint a node.
+fn channel_count_bucket(num_channels: usize) -> &'static str {
+	match num_channels {
+		0 => "0",
+		1..=5 => "1-5",
+		6..=20 => "6-20",
+		21..=100 => "21-100",
+		_ => "100+",
+	}
+}
+
+/// Splits an endpoint of the form `host:port[/path]` into its address and request path.
+fn parse_endpoint(endpoint: &str) -> (&str, &str) {
+	match endpoint.find('/') {
+		Some(idx) => (&endpoint[..idx], &endpoint[idx..]),
+		None => (endpoint, "/"),
+	}
+}
+
+fn json_request(addr: &str, path: &str, headers: &[(&str, String)], body: &str) -> String {
+	let extra_headers: String =
+		headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+	format!(
+		"POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+		path,
+		addr,
+		body.len(),
+		extra_headers,
+		body
+	)
+}
+
+/// Posts a JSON `body` to an endpoint of the form `host:port[/path]`.
+pub(crate) async fn post_json(endpoint: &str, body: &str) -> std::io::Result<()> {
+	let (addr, path) = parse_endpoint(endpoint);
+	let mut stream = TcpStream::connect(addr).await?;
+	stream.write_all(json_request(addr, path, &[], body).as_bytes()).await?;
+	stream.shutdown().await
+}
+
+/// Posts a JSON `body` with extra `headers` to an endpoint of the form `host:port[/path]`, and
+/// waits for the response. Unlike `post_json`, this only succeeds if the endpoint answers with a
+/// 2xx status, so callers can tell that the body was actually accepted.
+pub(crate) async fn post_json_acknowledged(
+	endpoint: &str, body: &str, headers: &[(&str, String)],
+) -> std::io::Result<()> {
+	let (addr, path) = parse_endpoint(endpoint);
+	let mut stream = TcpStream::connect(addr).await?;
+	stream.write_all(json_request(addr, path, headers, body).as_bytes()).await?;
+	let mut status_line = String::new();
+	BufReader::new(stream).read_line(&mut status_line).await?;
+	// e.g. `HTTP/1.1 200 OK`
+	match status_line.split(' ').nth(1) {
+		Some(status) if status.starts_with('2') && status.len() == 3 => Ok(()),
+		_ => Err(std::io::Error::new(
+			std::io::ErrorKind::Other,
+			format!("endpoint responded with {:?}", status_line.trim_end()),
+		)),
+	}
+}
+
+/// Periodically posts anonymized node statistics to `endpoint`. No identifying information (node
+/// id, channel ids, balances or peer addresses) is ever included.
+pub(crate) fn start_telemetry(
+	endpoint: String, channel_manager: Arc<ChannelManager>, network: Network,
+) {
+	let started_at = Instant::now();
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(TELEMETRY_INTERVAL);
+		loop {
+			interval.tick().await;
+			let stats = serde_json::json!({
+				"version": env!("CARGO_PKG_VERSION"),
+				"network": network.to_string(),
+				// Round uptime to the hour to avoid leaking the exact node start time.
+				"uptime_hours": started_at.elapsed().as_secs() / 3600,
+				"channel_count": channel_count_bucket(channel_manager.list_channels().len()),
+			});
+			if post_json(&endpoint, &stats.to_string()).await.is_err() {
+				// Telemetry is best-effort, a failure to report should never affect the node.
+				eprintln!("Warning: Failed to post telemetry to {}", endpoint);
+			}
+		}
+	});
+}
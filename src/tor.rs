@@ -0,0 +1,226 @@
+use crate::hex_utils;
+use lightning::ln::msgs::NetAddress;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Connections through Tor take a few round trips across the world, so give them longer than the
+/// usual direct connection timeout.
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where to reach a peer. Onion addresses can't be resolved locally, so they're kept as a hostname
+/// for the proxy to resolve.
+#[derive(Clone)]
+pub(crate) enum PeerAddr {
+	Socket(SocketAddr),
+	Onion(String, u16),
+}
+
+fn other_err(msg: String) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, msg)
+}
+
+async fn socks5_handshake(stream: &mut TcpStream, peer_addr: &PeerAddr) -> io::Result<()> {
+	// Offer only the "no authentication" method, which is what Tor's SocksPort expects by default.
+	stream.write_all(&[5, 1, 0]).await?;
+	let mut method = [0; 2];
+	stream.read_exact(&mut method).await?;
+	if method != [5, 0] {
+		return Err(other_err("SOCKS5 proxy requires authentication".to_string()));
+	}
+
+	let mut request = vec![5, 1, 0];
+	let port = match peer_addr {
+		PeerAddr::Socket(addr) => {
+			match addr.ip() {
+				IpAddr::V4(ip) => {
+					request.push(1);
+					request.extend_from_slice(&ip.octets());
+				}
+				IpAddr::V6(ip) => {
+					request.push(4);
+					request.extend_from_slice(&ip.octets());
+				}
+			}
+			addr.port()
+		}
+		PeerAddr::Onion(host, port) => {
+			request.push(3);
+			request.push(host.len() as u8);
+			request.extend_from_slice(host.as_bytes());
+			*port
+		}
+	};
+	request.extend_from_slice(&port.to_be_bytes());
+	stream.write_all(&request).await?;
+
+	let mut reply = [0; 4];
+	stream.read_exact(&mut reply).await?;
+	if reply[1] != 0 {
+		return Err(other_err(format!("SOCKS5 proxy failed to connect (reply code {})", reply[1])));
+	}
+	// Skip over the address the proxy bound to, which we have no use for.
+	let bound_addr_len = match reply[3] {
+		1 => 4,
+		4 => 16,
+		3 => stream.read_u8().await? as usize,
+		_ => return Err(other_err("SOCKS5 proxy sent an invalid reply".to_string())),
+	};
+	let mut bound_addr = vec![0; bound_addr_len + 2];
+	stream.read_exact(&mut bound_addr).await?;
+	Ok(())
+}
+
+/// Opens a connection to `peer_addr` through the SOCKS5 proxy at `proxy_addr`.
+pub(crate) async fn socks5_connect(
+	proxy_addr: SocketAddr, peer_addr: &PeerAddr,
+) -> io::Result<TcpStream> {
+	let connect = async {
+		let mut stream = TcpStream::connect(proxy_addr).await?;
+		socks5_handshake(&mut stream, peer_addr).await?;
+		Ok(stream)
+	};
+	match tokio::time::timeout(PROXY_CONNECT_TIMEOUT, connect).await {
+		Ok(res) => res,
+		Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out connecting via proxy")),
+	}
+}
+
+/// Decodes the RFC 4648 base32 used by onion addresses.
+fn decode_base32(s: &str) -> Option<Vec<u8>> {
+	let mut bytes = Vec::new();
+	let mut buffer: u32 = 0;
+	let mut bits = 0;
+	for c in s.bytes() {
+		let value = match c {
+			b'a'..=b'z' => c - b'a',
+			b'A'..=b'Z' => c - b'A',
+			b'2'..=b'7' => c - b'2' + 26,
+			_ => return None,
+		};
+		buffer = (buffer << 5) | value as u32;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			bytes.push((buffer >> bits) as u8);
+		}
+	}
+	Some(bytes)
+}
+
+/// The address to announce for a v3 onion service, given its service id (the onion address without
+/// the `.onion` suffix).
+fn onion_v3_net_address(service_id: &str, port: u16) -> Option<NetAddress> {
+	let bytes = decode_base32(service_id)?;
+	if bytes.len() != 35 {
+		return None;
+	}
+	let mut ed25519_pubkey = [0; 32];
+	ed25519_pubkey.copy_from_slice(&bytes[..32]);
+	Some(NetAddress::OnionV3 {
+		ed25519_pubkey,
+		checksum: u16::from_be_bytes([bytes[32], bytes[33]]),
+		version: bytes[34],
+		port,
+	})
+}
+
+/// Sends a command over the Tor control connection, returning the reply lines on success.
+async fn control_command<R: AsyncBufReadExt + Unpin, W: AsyncWriteExt + Unpin>(
+	reader: &mut R, writer: &mut W, command: &str,
+) -> io::Result<Vec<String>> {
+	writer.write_all(format!("{}\r\n", command).as_bytes()).await?;
+	let mut lines = Vec::new();
+	loop {
+		let mut line = String::new();
+		if reader.read_line(&mut line).await? == 0 {
+			return Err(other_err("Tor closed the control connection".to_string()));
+		}
+		let line = line.trim_end().to_string();
+		if !line.starts_with("250") {
+			return Err(other_err(format!("Tor control command failed: {}", line)));
+		}
+		// The final line of a reply has a space after the status code, the others a dash.
+		let is_last = line.as_bytes().get(3) == Some(&b' ');
+		lines.push(line);
+		if is_last {
+			return Ok(lines);
+		}
+	}
+}
+
+/// Exposes our peer listening port as a v3 onion service via the Tor control port at
+/// `control_addr`, returning the address to announce. The service's key is kept at `key_path` so
+/// that our onion address is the same across restarts. Tor removes the service when the control
+/// connection closes, so we hold it open for as long as we run.
+pub(crate) async fn create_onion_service(
+	control_addr: SocketAddr, key_path: &Path, listening_port: u16,
+) -> io::Result<(String, NetAddress)> {
+	let stream = TcpStream::connect(control_addr).await?;
+	let (reader, mut writer) = stream.into_split();
+	let mut reader = BufReader::new(reader);
+
+	// Authenticate with the cookie file if Tor wants one, which is the default for the control
+	// port. Password authentication isn't supported.
+	let protocol_info = control_command(&mut reader, &mut writer, "PROTOCOLINFO 1").await?;
+	let auth_line = protocol_info
+		.iter()
+		.find(|line| line.starts_with("250-AUTH "))
+		.ok_or_else(|| other_err("Tor didn't tell us how to authenticate".to_string()))?;
+	let auth_command = if auth_line.contains("NULL") {
+		"AUTHENTICATE".to_string()
+	} else if let Some(cookie_path) = auth_line.split("COOKIEFILE=\"").nth(1) {
+		let cookie_path = cookie_path.trim_end_matches('"');
+		let cookie = fs::read(cookie_path)?;
+		format!("AUTHENTICATE {}", hex_utils::hex_str(&cookie))
+	} else {
+		return Err(other_err(
+			"Tor's control port requires a password, which isn't supported".to_string(),
+		));
+	};
+	control_command(&mut reader, &mut writer, &auth_command).await?;
+
+	let existing_key = fs::read_to_string(key_path).ok().map(|key| key.trim().to_string());
+	let key = existing_key.clone().unwrap_or_else(|| "NEW:ED25519-V3".to_string());
+	let reply = control_command(
+		&mut reader,
+		&mut writer,
+		&format!("ADD_ONION {} Port={},127.0.0.1:{}", key, listening_port, listening_port),
+	)
+	.await?;
+	let service_id = reply
+		.iter()
+		.find_map(|line| line.strip_prefix("250-ServiceID="))
+		.ok_or_else(|| other_err("Tor didn't return an onion service id".to_string()))?
+		.to_string();
+	if existing_key.is_none() {
+		let private_key = reply
+			.iter()
+			.find_map(|line| line.strip_prefix("250-PrivateKey="))
+			.ok_or_else(|| other_err("Tor didn't return the onion service key".to_string()))?;
+		let mut key_file = fs::File::create(key_path)?;
+		key_file.write_all(private_key.as_bytes())?;
+		key_file.sync_all()?;
+	}
+	let net_address = onion_v3_net_address(&service_id, listening_port)
+		.ok_or_else(|| other_err(format!("Tor returned an invalid service id {}", service_id)))?;
+
+	tokio::spawn(async move {
+		// Dropping the write half would shut down our side of the connection.
+		let _writer = writer;
+		let mut line = String::new();
+		while let Ok(n) = reader.read_line(&mut line).await {
+			if n == 0 {
+				eprintln!("Warning: Lost the Tor control connection, our onion service is offline");
+				break;
+			}
+			line.clear();
+		}
+	});
+	Ok((format!("{}.onion:{}", service_id, listening_port), net_address))
+}
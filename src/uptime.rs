@@ -0,0 +1,153 @@
+use crate::hex_utils;
+use crate::{ChannelManager, PeerManager};
+use bitcoin::secp256k1::key::PublicKey;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How often we check whether each channel peer is connected.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The longest window we track uptime over, in hourly buckets.
+const MAX_BUCKETS: usize = 24 * 7;
+
+/// Per-hour liveness samples for a single counterparty.
+struct UptimeBucket {
+	hour: u64,
+	online: u32,
+	total: u32,
+}
+
+#[derive(Default)]
+pub(crate) struct PeerUptime {
+	buckets: VecDeque<UptimeBucket>,
+}
+
+impl PeerUptime {
+	fn record(&mut self, hour: u64, online: bool) {
+		match self.buckets.back_mut() {
+			Some(bucket) if bucket.hour == hour => {
+				bucket.total += 1;
+				bucket.online += online as u32;
+			}
+			_ => self.buckets.push_back(UptimeBucket { hour, online: online as u32, total: 1 }),
+		}
+		while self.buckets.len() > MAX_BUCKETS {
+			self.buckets.pop_front();
+		}
+	}
+
+	/// Returns the percentage of samples in the last `hours` hours in which the peer was
+	/// connected, or `None` if we have no samples in that window.
+	pub(crate) fn uptime_pct(&self, hours: u64) -> Option<f64> {
+		let cutoff = current_hour().saturating_sub(hours);
+		let (online, total) = self
+			.buckets
+			.iter()
+			.filter(|bucket| bucket.hour > cutoff)
+			.fold((0, 0), |(online, total), bucket| (online + bucket.online, total + bucket.total));
+		if total == 0 {
+			return None;
+		}
+		Some(online as f64 * 100.0 / total as f64)
+	}
+}
+
+pub(crate) type PeerUptimeStorage = Arc<Mutex<HashMap<PublicKey, PeerUptime>>>;
+
+fn current_hour() -> u64 {
+	SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() / 3600
+}
+
+pub(crate) fn format_uptime(uptime: Option<f64>) -> String {
+	match uptime {
+		Some(pct) => format!("{:.1}%", pct),
+		None => "unknown".to_string(),
+	}
+}
+
+fn persist_uptime(path: &Path, uptime: &HashMap<PublicKey, PeerUptime>) -> std::io::Result<()> {
+	let mut tmp_path = path.to_path_buf().into_os_string();
+	tmp_path.push(".tmp");
+	let mut file =
+		fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+	for (pubkey, peer_uptime) in uptime.iter() {
+		for bucket in peer_uptime.buckets.iter() {
+			file.write_all(
+				format!("{} {} {} {}\n", pubkey, bucket.hour, bucket.online, bucket.total)
+					.as_bytes(),
+			)?;
+		}
+	}
+	file.sync_all()?;
+	fs::rename(&tmp_path, path)
+}
+
+pub(crate) fn read_uptime(path: &Path) -> HashMap<PublicKey, PeerUptime> {
+	let mut uptime: HashMap<PublicKey, PeerUptime> = HashMap::new();
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(_) => return uptime,
+	};
+	for line in BufReader::new(file).lines() {
+		let line = match line {
+			Ok(l) => l,
+			Err(_) => break,
+		};
+		let parts: Vec<&str> = line.split_whitespace().collect();
+		if parts.len() != 4 || parts[0].len() != 66 {
+			continue;
+		}
+		let pubkey = match hex_utils::to_compressed_pubkey(parts[0]) {
+			Some(pk) => pk,
+			None => continue,
+		};
+		if let (Ok(hour), Ok(online), Ok(total)) =
+			(parts[1].parse(), parts[2].parse(), parts[3].parse())
+		{
+			uptime.entry(pubkey).or_default().buckets.push_back(UptimeBucket {
+				hour,
+				online,
+				total,
+			});
+		}
+	}
+	uptime
+}
+
+/// Regularly samples whether each of our channel counterparties is connected. The PeerManager
+/// already pings connected peers and disconnects any which fail to respond, so being connected is
+/// a good proxy for liveness.
+pub(crate) fn start_uptime_tracking(
+	path: String, uptime: PeerUptimeStorage, channel_manager: Arc<ChannelManager>,
+	peer_manager: Arc<PeerManager>,
+) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+		loop {
+			interval.tick().await;
+			let connected: HashSet<PublicKey> =
+				peer_manager.get_peer_node_ids().into_iter().collect();
+			// Only sample each counterparty once even if we have several channels with it.
+			let counterparties: HashSet<PublicKey> = channel_manager
+				.list_channels()
+				.iter()
+				.map(|chan_info| chan_info.counterparty.node_id)
+				.collect();
+			let hour = current_hour();
+			let mut uptime = uptime.lock().unwrap();
+			for node_id in counterparties {
+				uptime.entry(node_id).or_default().record(hour, connected.contains(&node_id));
+			}
+			if persist_uptime(Path::new(&path), &uptime).is_err() {
+				eprintln!(
+					"Warning: Failed to persist peer uptime, check your disk and permissions"
+				);
+			}
+		}
+	});
+}
@@ -0,0 +1,373 @@
+use crate::disk_space::DiskMonitor;
+use crate::monitor_replica::MonitorReplica;
+use crate::ChannelManager;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::BlockHash;
+use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::chainmonitor::Persist;
+use lightning::chain::channelmonitor::{
+	ChannelMonitor, ChannelMonitorUpdate, CLOSED_CHANNEL_UPDATE_ID,
+};
+use lightning::chain::keysinterface::InMemorySigner;
+use lightning::chain::transaction::OutPoint;
+use lightning::chain::ChannelMonitorUpdateErr;
+use lightning::util::logger::Logger;
+use lightning::util::ser::{Readable, Writeable};
+use lightning_persister::FilesystemPersister;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long we wait to batch up monitor updates before syncing the WAL.
+const WAL_SYNC_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Once the WAL grows past this many bytes we write out full monitors and truncate it.
+const WAL_COMPACT_LEN: u64 = 32 * 1024 * 1024;
+
+/// How long we wait before retrying a replica, or writing out monitors, which failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+struct WalState {
+	wal: File,
+	wal_len: u64,
+	/// The highest update id per channel which has been written to the WAL but not yet synced.
+	unsynced_updates: HashMap<OutPoint, u64>,
+	/// The latest serialized monitor of each channel with updates in the WAL, written out in full
+	/// when we compact the WAL.
+	dirty_monitors: HashMap<OutPoint, Vec<u8>>,
+}
+
+/// Persists ChannelMonitor updates by appending them to a write-ahead log rather than rewriting
+/// the whole monitor, returning `TemporaryFailure` until a background thread has synced a batch of
+/// updates to disk and told the ChannelManager they're complete. This keeps monitor persistence,
+/// which every HTLC waits on, from being bounded by one fsync per update on slow disks.
+///
+/// New channels are still persisted synchronously by the inner `FilesystemPersister`.
+///
+/// If replicas are configured, every monitor is copied to each of them too, and an update is only
+/// reported as durable once every replica has the monitor it produced. A replica which fails holds
+/// up all channel updates until it works again.
+pub(crate) struct WalPersister {
+	inner: Arc<FilesystemPersister>,
+	monitors_dir: String,
+	tmp_path: String,
+	wal_path: String,
+	state: Mutex<WalState>,
+	disk_monitor: Arc<DiskMonitor>,
+	/// The latest update id of each channel's monitor, which counts the channel's commitment
+	/// updates since it was opened.
+	update_ids: Mutex<HashMap<OutPoint, u64>>,
+	replicas: Vec<MonitorReplica>,
+}
+
+fn monitor_name(funding_txo: &OutPoint) -> String {
+	format!("{}_{}", funding_txo.txid.to_hex(), funding_txo.index)
+}
+
+impl WalPersister {
+	pub(crate) fn new(
+		inner: Arc<FilesystemPersister>, ldk_data_dir: &str, disk_monitor: Arc<DiskMonitor>,
+		replicas: Vec<MonitorReplica>,
+	) -> std::io::Result<Self> {
+		let wal_path = format!("{}/monitor_wal", ldk_data_dir);
+		let wal = fs::OpenOptions::new().create(true).append(true).open(&wal_path)?;
+		let wal_len = wal.metadata()?.len();
+		Ok(Self {
+			inner,
+			monitors_dir: format!("{}/monitors", ldk_data_dir),
+			tmp_path: format!("{}/monitor_wal_tmp", ldk_data_dir),
+			wal_path,
+			state: Mutex::new(WalState {
+				wal,
+				wal_len,
+				unsynced_updates: HashMap::new(),
+				dirty_monitors: HashMap::new(),
+			}),
+			disk_monitor,
+			update_ids: Mutex::new(HashMap::new()),
+			replicas,
+		})
+	}
+
+	/// Copies a monitor to every replica, failing if any of them fails.
+	fn replicate(&self, funding_txo: &OutPoint, monitor: &[u8]) -> std::io::Result<()> {
+		for replica in self.replicas.iter() {
+			replica.write_monitor(&monitor_name(funding_txo), monitor).map_err(|e| {
+				std::io::Error::new(
+					e.kind(),
+					format!("failed to replicate monitor to {}: {}", replica.describe(), e),
+				)
+			})?;
+		}
+		Ok(())
+	}
+
+	/// Notes the update ids of the monitors we read at startup.
+	pub(crate) fn note_update_ids(&self, monitors: &[(BlockHash, ChannelMonitor<InMemorySigner>)]) {
+		let mut update_ids = self.update_ids.lock().unwrap();
+		for (_, monitor) in monitors {
+			update_ids.insert(monitor.get_funding_txo().0, monitor.get_latest_update_id());
+		}
+	}
+
+	/// How many commitment updates the channel with the given funding outpoint has taken.
+	pub(crate) fn update_count(&self, funding_txo: &OutPoint) -> Option<u64> {
+		self.update_ids.lock().unwrap().get(funding_txo).cloned()
+	}
+
+	/// Writes a full monitor over its copy in the monitors directory, in the same layout as
+	/// `FilesystemPersister` so that it's read back by `read_channelmonitors`.
+	fn write_monitor(&self, funding_txo: &OutPoint, monitor: &[u8]) -> std::io::Result<()> {
+		fs::create_dir_all(&self.monitors_dir)?;
+		let mut tmp_file = File::create(&self.tmp_path)?;
+		tmp_file.write_all(monitor)?;
+		tmp_file.sync_all()?;
+		let path = format!("{}/{}", self.monitors_dir, monitor_name(funding_txo));
+		fs::rename(&self.tmp_path, path)?;
+		File::open(&self.monitors_dir)?.sync_all()
+	}
+
+	/// Writes out the latest version of every monitor with updates in the WAL, after which the WAL
+	/// is no longer needed and is truncated.
+	fn compact(&self, state: &mut WalState) -> std::io::Result<()> {
+		for (funding_txo, monitor) in state.dirty_monitors.iter() {
+			self.write_monitor(funding_txo, monitor)?;
+		}
+		state.wal.set_len(0)?;
+		state.wal.sync_all()?;
+		state.wal_len = 0;
+		state.dirty_monitors.clear();
+		Ok(())
+	}
+
+	/// Makes every update written to the WAL so far durable. A failed fsync can't just be retried,
+	/// as the kernel may have dropped the pages it failed to write, so instead every monitor with
+	/// updates in the WAL is written out in full, until that works. Channel updates are held in the
+	/// meantime, as they're only reported complete once this returns.
+	fn sync(&self) {
+		// Writers can keep appending to the WAL while we sync our own handle to it.
+		let wal = self.state.lock().unwrap().wal.try_clone();
+		let synced = wal.and_then(|wal| wal.sync_data());
+		if let Err(e) = synced {
+			eprintln!(
+				"Warning: Failed to sync channel monitor WAL, writing out full monitors instead: {}",
+				e
+			);
+			self.disk_monitor.write_failed();
+			let mut failing = false;
+			loop {
+				// The state is unlocked before we sleep, so that writers aren't held up too.
+				let compacted = self.compact(&mut self.state.lock().unwrap());
+				match compacted {
+					Ok(()) => break,
+					// Only warn when writing starts failing, rather than on every retry.
+					Err(e) if !failing => {
+						failing = true;
+						eprintln!(
+							"Warning: Holding channel updates until monitors can be written: {}",
+							e
+						);
+					}
+					Err(_) => {}
+				}
+				thread::sleep(RETRY_INTERVAL);
+			}
+			if failing {
+				eprintln!("Writing channel monitors again");
+			}
+		}
+	}
+
+	/// Applies any updates left in the WAL by a previous run to the monitors we read from disk,
+	/// then writes those monitors out in full. Every monitor is copied to the replicas too, which
+	/// may have missed updates before we last stopped. Must be called before the monitors are
+	/// handed to the ChainMonitor.
+	pub(crate) fn replay_wal<B: Deref, F: Deref, L: Deref>(
+		&self, monitors: &mut Vec<(BlockHash, ChannelMonitor<InMemorySigner>)>, broadcaster: &B,
+		fee_estimator: &F, logger: &L,
+	) -> std::io::Result<()>
+	where
+		B::Target: BroadcasterInterface,
+		F::Target: FeeEstimator,
+		L::Target: Logger,
+	{
+		let replayed =
+			apply_wal(Path::new(&self.wal_path), monitors, broadcaster, fee_estimator, logger)?;
+		if replayed > 0 {
+			println!("Replayed {} channel monitor updates from the WAL", replayed);
+			for (_, monitor) in monitors.iter() {
+				self.write_monitor(&monitor.get_funding_txo().0, &monitor.encode())?;
+			}
+		}
+		for (_, monitor) in monitors.iter() {
+			self.replicate(&monitor.get_funding_txo().0, &monitor.encode())?;
+		}
+		let mut state = self.state.lock().unwrap();
+		state.wal.set_len(0)?;
+		state.wal.sync_all()?;
+		state.wal_len = 0;
+		Ok(())
+	}
+
+	/// Regularly syncs the WAL and tells the ChannelManager which monitor updates are now durable.
+	/// Runs on its own thread so that fsyncs never block the async runtime.
+	pub(crate) fn start_wal_sync(persister: Arc<Self>, channel_manager: Arc<ChannelManager>) {
+		thread::spawn(move || loop {
+			thread::sleep(WAL_SYNC_INTERVAL);
+			let (completed_updates, updated_monitors) = {
+				let mut state = persister.state.lock().unwrap();
+				if state.unsynced_updates.is_empty() {
+					continue;
+				}
+				let completed_updates: Vec<(OutPoint, u64)> =
+					state.unsynced_updates.drain().collect();
+				// The monitors to replicate are taken before compacting forgets them.
+				let updated_monitors: Vec<(OutPoint, Vec<u8>)> = if persister.replicas.is_empty() {
+					Vec::new()
+				} else {
+					completed_updates
+						.iter()
+						.filter_map(|(funding_txo, _)| {
+							Some((*funding_txo, state.dirty_monitors.get(funding_txo)?.clone()))
+						})
+						.collect()
+				};
+				if state.wal_len > WAL_COMPACT_LEN {
+					// The WAL still holds every update if this fails, so we can go on with it and
+					// compact next time.
+					if let Err(e) = persister.compact(&mut state) {
+						eprintln!("Warning: Failed to compact channel monitor WAL: {}", e);
+					}
+				}
+				(completed_updates, updated_monitors)
+			};
+			// Any updates written before we drained them above are covered by this sync, while
+			// writers can keep appending to the WAL in the meantime.
+			persister.sync();
+			for (funding_txo, monitor) in updated_monitors {
+				let mut failing = false;
+				while let Err(e) = persister.replicate(&funding_txo, &monitor) {
+					if !failing {
+						failing = true;
+						eprintln!("Warning: Holding channel updates until replicas work: {}", e);
+					}
+					thread::sleep(RETRY_INTERVAL);
+				}
+				if failing {
+					eprintln!("Replicating channel monitors again");
+				}
+			}
+			for (funding_txo, update_id) in completed_updates {
+				channel_manager.channel_monitor_updated(&funding_txo, update_id);
+			}
+		});
+	}
+}
+
+/// Applies the updates in the WAL at `wal_path` to `monitors` in memory, returning how many were
+/// applied.
+pub(crate) fn apply_wal<B: Deref, F: Deref, L: Deref>(
+	wal_path: &Path, monitors: &mut Vec<(BlockHash, ChannelMonitor<InMemorySigner>)>,
+	broadcaster: &B, fee_estimator: &F, logger: &L,
+) -> std::io::Result<usize>
+where
+	B::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	L::Target: Logger,
+{
+	let wal = fs::read(wal_path)?;
+	let mut replayed = 0;
+	let mut pos = 0;
+	while pos + 4 <= wal.len() {
+		let mut len_bytes = [0; 4];
+		len_bytes.copy_from_slice(&wal[pos..pos + 4]);
+		let len = u32::from_be_bytes(len_bytes) as usize;
+		// A partially written record at the end of the WAL was never acknowledged, so we can
+		// safely drop it.
+		if pos + 4 + len > wal.len() {
+			break;
+		}
+		let mut record = Cursor::new(&wal[pos + 4..pos + 4 + len]);
+		pos += 4 + len;
+		let (funding_txo, update): (OutPoint, ChannelMonitorUpdate) =
+			match (Readable::read(&mut record), Readable::read(&mut record)) {
+				(Ok(funding_txo), Ok(update)) => (funding_txo, update),
+				_ => {
+					return Err(std::io::Error::new(
+						std::io::ErrorKind::InvalidData,
+						"Failed to decode channel monitor WAL record",
+					))
+				}
+			};
+		let monitor = match monitors.iter().find(|(_, m)| m.get_funding_txo().0 == funding_txo) {
+			Some((_, monitor)) => monitor,
+			None => continue,
+		};
+		if update.update_id <= monitor.get_latest_update_id() {
+			continue;
+		}
+		if monitor.update_monitor(&update, broadcaster, fee_estimator, logger).is_err() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"Failed to apply channel monitor WAL record",
+			));
+		}
+		replayed += 1;
+	}
+	Ok(replayed)
+}
+
+impl Persist<InMemorySigner> for WalPersister {
+	fn persist_new_channel(
+		&self, funding_txo: OutPoint, monitor: &ChannelMonitor<InMemorySigner>,
+	) -> Result<(), ChannelMonitorUpdateErr> {
+		self.update_ids.lock().unwrap().insert(funding_txo, monitor.get_latest_update_id());
+		let res = self.inner.persist_new_channel(funding_txo, monitor);
+		if res.is_err() {
+			self.disk_monitor.write_failed();
+			return res;
+		}
+		// The channel isn't funded yet, so we can give up on it rather than go on without a copy.
+		if let Err(e) = self.replicate(&funding_txo, &monitor.encode()) {
+			eprintln!("Warning: Refusing new channel: {}", e);
+			return Err(ChannelMonitorUpdateErr::PermanentFailure);
+		}
+		res
+	}
+
+	fn update_persisted_channel(
+		&self, funding_txo: OutPoint, update: &ChannelMonitorUpdate,
+		monitor: &ChannelMonitor<InMemorySigner>,
+	) -> Result<(), ChannelMonitorUpdateErr> {
+		// Updates made after a channel closed don't count towards its commitment updates.
+		if update.update_id != CLOSED_CHANNEL_UPDATE_ID {
+			self.update_ids.lock().unwrap().insert(funding_txo, update.update_id);
+		}
+		let mut record = Vec::new();
+		funding_txo.write(&mut record).unwrap();
+		update.write(&mut record).unwrap();
+
+		let mut state = self.state.lock().unwrap();
+		let mut entry = Vec::with_capacity(4 + record.len());
+		entry.extend_from_slice(&(record.len() as u32).to_be_bytes());
+		entry.extend_from_slice(&record);
+		if state.wal.write_all(&entry).is_err() {
+			// The channel is force-closed with its last persisted state, and we stop taking on
+			// new HTLCs which could meet the same fate.
+			self.disk_monitor.write_failed();
+			return Err(ChannelMonitorUpdateErr::PermanentFailure);
+		}
+		state.wal_len += entry.len() as u64;
+		state.dirty_monitors.insert(funding_txo, monitor.encode());
+		let highest_update_id = state.unsynced_updates.entry(funding_txo).or_insert(0);
+		*highest_update_id = std::cmp::max(*highest_update_id, update.update_id);
+		// The ChannelManager holds off on the channel until we report the update as durable.
+		Err(ChannelMonitorUpdateErr::TemporaryFailure)
+	}
+}
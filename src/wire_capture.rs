@@ -0,0 +1,508 @@
+use crate::forwarding::HtlcTracker;
+use crate::hex_utils;
+use crate::{time_now_secs, ChannelManager};
+use bitcoin::secp256k1::key::PublicKey;
+use lightning::ln::features::InitFeatures;
+use lightning::ln::msgs::{
+	AcceptChannel, AnnouncementSignatures, ChannelMessageHandler, ChannelReestablish,
+	ChannelUpdate, ClosingSigned, CommitmentSigned, ErrorAction, ErrorMessage, FundingCreated,
+	FundingLocked, FundingSigned, Init, OpenChannel, RevokeAndACK, Shutdown, UpdateAddHTLC,
+	UpdateFailHTLC, UpdateFailMalformedHTLC, UpdateFee, UpdateFulfillHTLC,
+};
+use lightning::util::events::{MessageSendEvent, MessageSendEventsProvider};
+use lightning::util::ser::Writeable;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// How many messages we keep per peer, dropping the oldest first.
+const MAX_RECORDS_PER_PEER: usize = 1000;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+	Received,
+	Sent,
+}
+
+impl Direction {
+	pub(crate) fn as_str(&self) -> &'static str {
+		match self {
+			Direction::Received => "received",
+			Direction::Sent => "sent",
+		}
+	}
+}
+
+/// A channel message exchanged with a peer, with only the fields useful for debugging kept.
+/// Signatures, keys, onions, payment hashes and preimages are left out.
+pub(crate) struct WireRecord {
+	pub(crate) timestamp: u64,
+	pub(crate) direction: Direction,
+	pub(crate) msg_type: &'static str,
+	/// The length of the message's serialization, without the type prefix.
+	pub(crate) len: usize,
+	pub(crate) fields: String,
+}
+
+/// A message we know how to summarize.
+trait WireMessage: Writeable {
+	const TYPE: &'static str;
+	fn key_fields(&self) -> String;
+}
+
+fn channel_id_field(channel_id: &[u8; 32]) -> String {
+	format!("channel_id={}", hex_utils::hex_str(channel_id))
+}
+
+impl WireMessage for OpenChannel {
+	const TYPE: &'static str = "open_channel";
+	fn key_fields(&self) -> String {
+		format!(
+			"temporary_channel_id={} funding_satoshis={} push_msat={} feerate_per_kw={} to_self_delay={} max_accepted_htlcs={}",
+			hex_utils::hex_str(&self.temporary_channel_id),
+			self.funding_satoshis,
+			self.push_msat,
+			self.feerate_per_kw,
+			self.to_self_delay,
+			self.max_accepted_htlcs
+		)
+	}
+}
+
+impl WireMessage for AcceptChannel {
+	const TYPE: &'static str = "accept_channel";
+	fn key_fields(&self) -> String {
+		format!(
+			"temporary_channel_id={} minimum_depth={} to_self_delay={}",
+			hex_utils::hex_str(&self.temporary_channel_id),
+			self.minimum_depth,
+			self.to_self_delay
+		)
+	}
+}
+
+impl WireMessage for FundingCreated {
+	const TYPE: &'static str = "funding_created";
+	fn key_fields(&self) -> String {
+		format!(
+			"temporary_channel_id={} funding_outpoint={}:{}",
+			hex_utils::hex_str(&self.temporary_channel_id),
+			self.funding_txid,
+			self.funding_output_index
+		)
+	}
+}
+
+impl WireMessage for FundingSigned {
+	const TYPE: &'static str = "funding_signed";
+	fn key_fields(&self) -> String {
+		channel_id_field(&self.channel_id)
+	}
+}
+
+impl WireMessage for FundingLocked {
+	const TYPE: &'static str = "funding_locked";
+	fn key_fields(&self) -> String {
+		channel_id_field(&self.channel_id)
+	}
+}
+
+impl WireMessage for Shutdown {
+	const TYPE: &'static str = "shutdown";
+	fn key_fields(&self) -> String {
+		channel_id_field(&self.channel_id)
+	}
+}
+
+impl WireMessage for ClosingSigned {
+	const TYPE: &'static str = "closing_signed";
+	fn key_fields(&self) -> String {
+		format!("{} fee_satoshis={}", channel_id_field(&self.channel_id), self.fee_satoshis)
+	}
+}
+
+impl WireMessage for UpdateAddHTLC {
+	const TYPE: &'static str = "update_add_htlc";
+	fn key_fields(&self) -> String {
+		format!(
+			"{} htlc_id={} amount_msat={} cltv_expiry={}",
+			channel_id_field(&self.channel_id),
+			self.htlc_id,
+			self.amount_msat,
+			self.cltv_expiry
+		)
+	}
+}
+
+impl WireMessage for UpdateFulfillHTLC {
+	const TYPE: &'static str = "update_fulfill_htlc";
+	fn key_fields(&self) -> String {
+		format!("{} htlc_id={}", channel_id_field(&self.channel_id), self.htlc_id)
+	}
+}
+
+impl WireMessage for UpdateFailHTLC {
+	const TYPE: &'static str = "update_fail_htlc";
+	fn key_fields(&self) -> String {
+		format!("{} htlc_id={}", channel_id_field(&self.channel_id), self.htlc_id)
+	}
+}
+
+impl WireMessage for UpdateFailMalformedHTLC {
+	const TYPE: &'static str = "update_fail_malformed_htlc";
+	fn key_fields(&self) -> String {
+		format!(
+			"{} htlc_id={} failure_code={}",
+			channel_id_field(&self.channel_id),
+			self.htlc_id,
+			self.failure_code
+		)
+	}
+}
+
+impl WireMessage for CommitmentSigned {
+	const TYPE: &'static str = "commitment_signed";
+	fn key_fields(&self) -> String {
+		format!(
+			"{} htlc_signatures={}",
+			channel_id_field(&self.channel_id),
+			self.htlc_signatures.len()
+		)
+	}
+}
+
+impl WireMessage for RevokeAndACK {
+	const TYPE: &'static str = "revoke_and_ack";
+	fn key_fields(&self) -> String {
+		channel_id_field(&self.channel_id)
+	}
+}
+
+impl WireMessage for UpdateFee {
+	const TYPE: &'static str = "update_fee";
+	fn key_fields(&self) -> String {
+		format!("{} feerate_per_kw={}", channel_id_field(&self.channel_id), self.feerate_per_kw)
+	}
+}
+
+impl WireMessage for AnnouncementSignatures {
+	const TYPE: &'static str = "announcement_signatures";
+	fn key_fields(&self) -> String {
+		format!("{} short_channel_id={}", channel_id_field(&self.channel_id), self.short_channel_id)
+	}
+}
+
+impl WireMessage for ChannelReestablish {
+	const TYPE: &'static str = "channel_reestablish";
+	fn key_fields(&self) -> String {
+		format!(
+			"{} next_local_commitment_number={} next_remote_commitment_number={}",
+			channel_id_field(&self.channel_id),
+			self.next_local_commitment_number,
+			self.next_remote_commitment_number
+		)
+	}
+}
+
+impl WireMessage for ChannelUpdate {
+	const TYPE: &'static str = "channel_update";
+	fn key_fields(&self) -> String {
+		format!("short_channel_id={} flags={}", self.contents.short_channel_id, self.contents.flags)
+	}
+}
+
+impl WireMessage for ErrorMessage {
+	const TYPE: &'static str = "error";
+	fn key_fields(&self) -> String {
+		// The error's text is what interop debugging is usually after.
+		format!("{} data={:?}", channel_id_field(&self.channel_id), self.data)
+	}
+}
+
+impl WireMessage for Init {
+	const TYPE: &'static str = "init";
+	fn key_fields(&self) -> String {
+		String::new()
+	}
+}
+
+/// Sits between the peer manager and the channel manager, recording the channel messages we
+/// exchange with each peer into a per-peer ring buffer when enabled, for debugging interop issues
+/// with other implementations. Gossip isn't recorded, as LDK doesn't tell us which peer sent it.
+/// HTLC messages are always passed to the `HtlcTracker`, to tell which channels we forward between.
+pub(crate) struct WireCapture {
+	channel_manager: Arc<ChannelManager>,
+	htlc_tracker: Arc<HtlcTracker>,
+	enabled: bool,
+	records: Mutex<HashMap<PublicKey, VecDeque<WireRecord>>>,
+	/// Errors we were asked to send, which go out along with the channel manager's messages.
+	pending_errors: Mutex<Vec<MessageSendEvent>>,
+}
+
+impl WireCapture {
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, htlc_tracker: Arc<HtlcTracker>, enabled: bool,
+	) -> Self {
+		Self {
+			channel_manager,
+			htlc_tracker,
+			enabled,
+			records: Mutex::new(HashMap::new()),
+			pending_errors: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Sends the peer an error about a channel, which it takes as a request to force-close it.
+	/// This is how we get our funds back from channels we lost the state of, which the channel
+	/// manager doesn't know about.
+	pub(crate) fn send_error(&self, node_id: PublicKey, channel_id: [u8; 32], data: String) {
+		self.pending_errors.lock().unwrap().push(MessageSendEvent::HandleError {
+			node_id,
+			action: ErrorAction::SendErrorMessage { msg: ErrorMessage { channel_id, data } },
+		});
+	}
+
+	pub(crate) fn enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn record<M: WireMessage>(&self, node_id: &PublicKey, direction: Direction, msg: &M) {
+		if !self.enabled {
+			return;
+		}
+		let record = WireRecord {
+			timestamp: time_now_secs(),
+			direction,
+			msg_type: M::TYPE,
+			len: msg.encode().len(),
+			fields: msg.key_fields(),
+		};
+		let mut records = self.records.lock().unwrap();
+		let peer_records = records.entry(*node_id).or_default();
+		if peer_records.len() >= MAX_RECORDS_PER_PEER {
+			peer_records.pop_front();
+		}
+		peer_records.push_back(record);
+	}
+
+	fn record_sent(&self, event: &MessageSendEvent) {
+		match event {
+			MessageSendEvent::SendAcceptChannel { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendOpenChannel { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendFundingCreated { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendFundingSigned { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendFundingLocked { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendAnnouncementSignatures { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::UpdateHTLCs { node_id, updates } => {
+				for msg in updates.update_add_htlcs.iter() {
+					self.record(node_id, Direction::Sent, msg);
+				}
+				for msg in updates.update_fulfill_htlcs.iter() {
+					self.record(node_id, Direction::Sent, msg);
+				}
+				for msg in updates.update_fail_htlcs.iter() {
+					self.record(node_id, Direction::Sent, msg);
+				}
+				for msg in updates.update_fail_malformed_htlcs.iter() {
+					self.record(node_id, Direction::Sent, msg);
+				}
+				if let Some(msg) = &updates.update_fee {
+					self.record(node_id, Direction::Sent, msg);
+				}
+				self.record(node_id, Direction::Sent, &updates.commitment_signed);
+			}
+			MessageSendEvent::SendRevokeAndACK { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendClosingSigned { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendShutdown { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendChannelReestablish { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::SendChannelUpdate { node_id, msg } => {
+				self.record(node_id, Direction::Sent, msg)
+			}
+			MessageSendEvent::HandleError { node_id, action } => match action {
+				ErrorAction::SendErrorMessage { msg } => self.record(node_id, Direction::Sent, msg),
+				ErrorAction::DisconnectPeer { msg: Some(msg) } => {
+					self.record(node_id, Direction::Sent, msg)
+				}
+				_ => {}
+			},
+			// Broadcasts and gossip queries aren't for any one channel.
+			_ => {}
+		}
+	}
+
+	fn track_sent_htlcs(&self, event: &MessageSendEvent) {
+		if let MessageSendEvent::UpdateHTLCs { updates, .. } = event {
+			for msg in updates.update_add_htlcs.iter() {
+				self.htlc_tracker.add_sent(msg);
+			}
+			for msg in updates.update_fulfill_htlcs.iter() {
+				self.htlc_tracker.resolved_sent(&msg.channel_id, msg.htlc_id);
+			}
+			for msg in updates.update_fail_htlcs.iter() {
+				self.htlc_tracker.resolved_sent(&msg.channel_id, msg.htlc_id);
+			}
+			for msg in updates.update_fail_malformed_htlcs.iter() {
+				self.htlc_tracker.resolved_sent(&msg.channel_id, msg.htlc_id);
+			}
+		}
+	}
+
+	/// Calls `f` with the messages recorded for a peer, oldest first, if we've recorded any.
+	pub(crate) fn with_records<F: FnOnce(&VecDeque<WireRecord>)>(
+		&self, node_id: &PublicKey, f: F,
+	) -> bool {
+		match self.records.lock().unwrap().get(node_id) {
+			Some(records) => {
+				f(records);
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+impl MessageSendEventsProvider for WireCapture {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+		let mut events = std::mem::take(&mut *self.pending_errors.lock().unwrap());
+		events.extend(self.channel_manager.get_and_clear_pending_msg_events());
+		for event in events.iter() {
+			self.track_sent_htlcs(event);
+			self.record_sent(event);
+		}
+		events
+	}
+}
+
+impl ChannelMessageHandler for WireCapture {
+	fn handle_open_channel(
+		&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &OpenChannel,
+	) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_open_channel(their_node_id, their_features, msg)
+	}
+
+	fn handle_accept_channel(
+		&self, their_node_id: &PublicKey, their_features: InitFeatures, msg: &AcceptChannel,
+	) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_accept_channel(their_node_id, their_features, msg)
+	}
+
+	fn handle_funding_created(&self, their_node_id: &PublicKey, msg: &FundingCreated) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_funding_created(their_node_id, msg)
+	}
+
+	fn handle_funding_signed(&self, their_node_id: &PublicKey, msg: &FundingSigned) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_funding_signed(their_node_id, msg)
+	}
+
+	fn handle_funding_locked(&self, their_node_id: &PublicKey, msg: &FundingLocked) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_funding_locked(their_node_id, msg)
+	}
+
+	fn handle_shutdown(
+		&self, their_node_id: &PublicKey, their_features: &InitFeatures, msg: &Shutdown,
+	) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_shutdown(their_node_id, their_features, msg)
+	}
+
+	fn handle_closing_signed(&self, their_node_id: &PublicKey, msg: &ClosingSigned) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_closing_signed(their_node_id, msg)
+	}
+
+	fn handle_update_add_htlc(&self, their_node_id: &PublicKey, msg: &UpdateAddHTLC) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.htlc_tracker.add_received(msg);
+		self.channel_manager.handle_update_add_htlc(their_node_id, msg)
+	}
+
+	fn handle_update_fulfill_htlc(&self, their_node_id: &PublicKey, msg: &UpdateFulfillHTLC) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.htlc_tracker.fulfill_received(msg);
+		self.channel_manager.handle_update_fulfill_htlc(their_node_id, msg)
+	}
+
+	fn handle_update_fail_htlc(&self, their_node_id: &PublicKey, msg: &UpdateFailHTLC) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.htlc_tracker.fail_received(msg);
+		self.channel_manager.handle_update_fail_htlc(their_node_id, msg)
+	}
+
+	fn handle_update_fail_malformed_htlc(
+		&self, their_node_id: &PublicKey, msg: &UpdateFailMalformedHTLC,
+	) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.htlc_tracker.fail_malformed_received(msg);
+		self.channel_manager.handle_update_fail_malformed_htlc(their_node_id, msg)
+	}
+
+	fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &CommitmentSigned) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_commitment_signed(their_node_id, msg)
+	}
+
+	fn handle_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &RevokeAndACK) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_revoke_and_ack(their_node_id, msg)
+	}
+
+	fn handle_update_fee(&self, their_node_id: &PublicKey, msg: &UpdateFee) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_update_fee(their_node_id, msg)
+	}
+
+	fn handle_announcement_signatures(
+		&self, their_node_id: &PublicKey, msg: &AnnouncementSignatures,
+	) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_announcement_signatures(their_node_id, msg)
+	}
+
+	fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool) {
+		self.channel_manager.peer_disconnected(their_node_id, no_connection_possible)
+	}
+
+	fn peer_connected(&self, their_node_id: &PublicKey, msg: &Init) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.peer_connected(their_node_id, msg)
+	}
+
+	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &ChannelReestablish) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_channel_reestablish(their_node_id, msg)
+	}
+
+	fn handle_channel_update(&self, their_node_id: &PublicKey, msg: &ChannelUpdate) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_channel_update(their_node_id, msg)
+	}
+
+	fn handle_error(&self, their_node_id: &PublicKey, msg: &ErrorMessage) {
+		self.record(their_node_id, Direction::Received, msg);
+		self.channel_manager.handle_error(their_node_id, msg)
+	}
+}